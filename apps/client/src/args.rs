@@ -0,0 +1,144 @@
+//! Command line of `tunnl`.
+//!
+//! Arguments are parsed by hand like the server's subcommands: a forward kind
+//! and the local port, then options in any order.
+
+use std::path::PathBuf;
+
+/// Environment variable naming the default server
+pub const SERVER_ENV: &str = "TUNNL_SERVER";
+
+pub const USAGE: &str = "\
+usage: tunnl http <local port> [options]
+       tunnl tcp <local port> [--remote-port <port>] [options]
+       tunnl udp-agent <listen port> <service host:port>
+
+options:
+  --subdomain <name>    subdomain to ask for (default: a random one)
+  --server <host>       tunnel server (default: $TUNNL_SERVER or localhost)
+  --ssh-port <port>     SSH port of the server (default: 2222)
+  --local-host <host>   where the local service listens (default: localhost)
+  --profile <name>      performance profile: interactive, streaming or bulk
+  --identity <path>     SSH key (default: ~/.tunnl/id_ed25519, created if missing)
+  --remote-port <port>  public port of a tcp forward (default: any free one)
+  --secret-path         only answer visitors who have the tunnel's secret URL
+  --json                print the server's JSON events instead of messages
+  --no-reconnect        exit when the connection drops";
+
+const DEFAULT_SERVER: &str = "localhost";
+const DEFAULT_SSH_PORT: u16 = 2222;
+
+/// What to forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// An HTTP tunnel on a subdomain
+    Http,
+    /// A dedicated public TCP port (0 = any free one)
+    Tcp { remote_port: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub mode: Mode,
+    pub local_port: u16,
+    pub local_host: String,
+    pub subdomain: Option<String>,
+    pub server: String,
+    pub ssh_port: u16,
+    pub profile: Option<String>,
+    pub identity: Option<PathBuf>,
+    /// Hide an http tunnel behind a secret path token
+    pub secret_path: bool,
+    pub json: bool,
+    pub reconnect: bool,
+}
+
+/// Parse the arguments after the program name; `default_server` is the value
+/// of `TUNNL_SERVER`, if set
+pub fn parse(args: &[String], default_server: Option<String>) -> Result<Options, String> {
+    let mut args = args.iter();
+    let http = match args.next().map(String::as_str) {
+        Some("http") => true,
+        Some("tcp") => false,
+        Some(other) => return Err(format!("unknown command '{}'", other)),
+        None => return Err("missing command".to_string()),
+    };
+    let local_port = port(args.next(), "local port")?;
+
+    let mut options = Options {
+        mode: if http { Mode::Http } else { Mode::Tcp { remote_port: 0 } },
+        local_port,
+        local_host: "localhost".to_string(),
+        subdomain: None,
+        server: default_server.unwrap_or_else(|| DEFAULT_SERVER.to_string()),
+        ssh_port: DEFAULT_SSH_PORT,
+        profile: None,
+        identity: None,
+        secret_path: false,
+        json: false,
+        reconnect: true,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--subdomain" => options.subdomain = Some(value(args.next(), arg)?),
+            "--server" => options.server = value(args.next(), arg)?,
+            "--ssh-port" => options.ssh_port = port(args.next(), arg)?,
+            "--local-host" => options.local_host = value(args.next(), arg)?,
+            "--profile" => options.profile = Some(value(args.next(), arg)?),
+            "--identity" => options.identity = Some(PathBuf::from(value(args.next(), arg)?)),
+            "--remote-port" if !http => {
+                // 0 asks for any free port
+                let remote_port = value(args.next(), arg)?;
+                let remote_port = remote_port.parse().map_err(|_| format!("invalid {} '{}'", arg, remote_port))?;
+                options.mode = Mode::Tcp { remote_port };
+            }
+            "--secret-path" if http => options.secret_path = true,
+            "--json" => options.json = true,
+            "--no-reconnect" => options.reconnect = false,
+            other => return Err(format!("unexpected '{}'", other)),
+        }
+    }
+    Ok(options)
+}
+
+fn value(arg: Option<&String>, name: &str) -> Result<String, String> {
+    arg.cloned().ok_or_else(|| format!("{} needs a value", name))
+}
+
+fn port(arg: Option<&String>, name: &str) -> Result<u16, String> {
+    let value = value(arg, name)?;
+    value
+        .parse()
+        .ok()
+        .filter(|port| *port > 0)
+        .ok_or_else(|| format!("invalid {} '{}'", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = parse(&args("http 3000 --subdomain demo --ssh-port 22 --json"), None).unwrap();
+        assert_eq!(options.mode, Mode::Http);
+        assert_eq!(options.local_port, 3000);
+        assert_eq!(options.subdomain.as_deref(), Some("demo"));
+        assert_eq!((options.server.as_str(), options.ssh_port), ("localhost", 22));
+        assert!(options.json && options.reconnect);
+
+        let options = parse(&args("tcp 5432 --remote-port 20001"), Some("exlo.dev".to_string())).unwrap();
+        assert_eq!(options.mode, Mode::Tcp { remote_port: 20001 });
+        assert_eq!(options.server, "exlo.dev");
+
+        assert!(parse(&args("http 3000 --remote-port 1"), None).is_err());
+        assert!(parse(&args("tcp 5432 --secret-path"), None).is_err());
+        assert!(parse(&args("http 0"), None).is_err());
+        assert!(parse(&args("ftp 21"), None).is_err());
+        assert!(parse(&args("http 3000 --subdomain"), None).is_err());
+    }
+}
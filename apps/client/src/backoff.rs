@@ -0,0 +1,51 @@
+//! Delays between reconnection attempts.
+//!
+//! The delay doubles after each failed attempt, up to a minute, and starts
+//! over once a connection stayed up for a while, so a server restart is
+//! picked up within seconds while an unreachable server isn't hammered.
+
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A connection lasting this long resets the delay
+pub const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: INITIAL_DELAY }
+    }
+}
+
+impl Backoff {
+    /// The delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_DELAY);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = INITIAL_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), INITIAL_DELAY);
+    }
+}
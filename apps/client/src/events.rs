@@ -0,0 +1,163 @@
+//! The server's JSON session events, as messages for the user.
+//!
+//! With `EXLO_OUTPUT=json` every notice of the session is one JSON object
+//! per line with an `event` field (see "Scripted clients" in the server's
+//! README). Each is turned into a line of text, and tells whether
+//! reconnecting could help: a refused forward or a taken subdomain stays
+//! that way, a dropped connection doesn't.
+
+use serde_json::Value;
+
+/// Error codes that reconnecting doesn't fix
+const FATAL_CODES: [&str; 6] = [
+    "auth_failed",
+    "subdomain_taken",
+    "subdomain_reserved",
+    "forward_name_taken",
+    "quota_exceeded",
+    "verification_timeout",
+];
+
+/// What an event means for the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notice {
+    pub text: String,
+    /// The tunnel is up
+    pub ready: bool,
+    /// Stop instead of reconnecting
+    pub fatal: bool,
+}
+
+impl Notice {
+    fn info(text: String) -> Self {
+        Self { text, ready: false, fatal: false }
+    }
+}
+
+/// Describe one line of the session's output. Lines that aren't events (an
+/// older server) are passed on as they are; unknown events are skipped.
+pub fn describe(line: &str) -> Option<Notice> {
+    let Ok(event) = serde_json::from_str::<Value>(line) else {
+        return Some(Notice::info(line.to_string())).filter(|_| !line.trim().is_empty());
+    };
+    let str = |field: &str| event[field].as_str().unwrap_or_default().to_string();
+    let port = event["port"].as_u64().unwrap_or_default();
+    let notice = match event["event"].as_str()? {
+        "activation" => Notice::info(format!(
+            "Open {} and enter code {} to authorize this key (only needed once)",
+            str("url"),
+            str("code")
+        )),
+        "ready" => {
+            let tunnels: Vec<String> = event["tunnels"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|tunnel| {
+                    format!(
+                        "Forwarding {} -> port {}",
+                        tunnel["url"].as_str().unwrap_or_default(),
+                        tunnel["port"].as_u64().unwrap_or_default()
+                    )
+                })
+                .collect();
+            Notice {
+                text: tunnels.join("\n"),
+                ready: true,
+                fatal: false,
+            }
+        }
+        kind @ ("tcp_port" | "udp_port") => Notice {
+            text: format!("Forwarding {}://{}", kind.trim_end_matches("_port"), str("endpoint")),
+            ready: true,
+            fatal: false,
+        },
+        "waiting_for_service" => Notice::info(format!("Waiting for something to listen on port {}", port)),
+        "refused" => Notice {
+            text: format!("Refused: port {}: {}", port, str("reason")),
+            ready: false,
+            fatal: true,
+        },
+        "error" => {
+            let code = str("code");
+            Notice {
+                text: match code.as_str() {
+                    "" => format!("Error: {}", str("reason")),
+                    code => format!("Error: {} [{}]", str("reason"), code),
+                },
+                ready: false,
+                fatal: FATAL_CODES.contains(&code.as_str()),
+            }
+        }
+        "renamed" => Notice::info(format!("Renamed {} -> {}", str("from"), str("url"))),
+        "took_over" => Notice::info(format!("Took over {} from an earlier connection ({})", str("url"), str("from"))),
+        // Reconnecting would take it back and start a tug of war
+        "taken_over" => Notice {
+            text: format!("{} was taken over by a connection from {}", str("url"), str("by")),
+            ready: false,
+            fatal: true,
+        },
+        "secret_path" => {
+            Notice::info(format!("Secret URL {} (or send {}: {})", str("url"), str("header"), str("token")))
+        }
+        "message" => Notice::info(format!("Message: {}", str("text"))),
+        // The server closed the idle tunnel on purpose
+        "idle_disconnect" => Notice {
+            text: format!("Closed after {}s without requests", event["idle_secs"].as_u64().unwrap_or_default()),
+            ready: false,
+            fatal: true,
+        },
+        "status_alert" => Notice::info(format!(
+            "Alert {}: {} ({:.1}% 5xx)",
+            str("state"),
+            str("subdomain"),
+            event["error_percent"].as_f64().unwrap_or_default()
+        )),
+        "local_service" => Notice::info(format!(
+            "Local service on port {} is {} ({})",
+            port,
+            str("state"),
+            str("subdomain")
+        )),
+        _ => return None,
+    };
+    Some(notice)
+}
+
+/// The token of a `resume_token` event, to reconnect with
+pub fn resume_token(line: &str) -> Option<String> {
+    let event = serde_json::from_str::<Value>(line).ok()?;
+    if event["event"] != "resume_token" {
+        return None;
+    }
+    event["token"].as_str().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let ready = describe(
+            r#"{"event":"ready","tunnels":[{"subdomain":"demo","url":"https://demo.exlo.dev","port":3000}]}"#,
+        )
+        .unwrap();
+        assert_eq!(ready.text, "Forwarding https://demo.exlo.dev -> port 3000");
+        assert!(ready.ready && !ready.fatal);
+
+        let taken = describe(r#"{"event":"error","reason":"taken","code":"subdomain_taken"}"#).unwrap();
+        assert!(taken.fatal);
+        let dropped = describe(r#"{"event":"error","reason":"io","code":"io_error"}"#).unwrap();
+        assert!(!dropped.fatal);
+        let taken_over = describe(r#"{"event":"taken_over","url":"https://demo.exlo.dev","by":"10.0.0.2"}"#).unwrap();
+        assert!(taken_over.fatal);
+
+        let tcp = describe(r#"{"event":"tcp_port","port":20001,"endpoint":"exlo.dev:20001"}"#).unwrap();
+        assert_eq!(tcp.text, "Forwarding tcp://exlo.dev:20001");
+        assert_eq!(describe(r#"{"event":"future"}"#), None);
+        let resume = r#"{"event":"resume_token","token":"ab12","expires_in_secs":300}"#;
+        assert_eq!((describe(resume), resume_token(resume)), (None, Some("ab12".to_string())));
+        assert_eq!(describe("ready: plain").unwrap().text, "ready: plain");
+    }
+}
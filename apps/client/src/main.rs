@@ -0,0 +1,154 @@
+//! `tunnl`: command line client for EXLO tunnels.
+//!
+//! Replaces remembering `ssh -R` flags: it generates a key on first use,
+//! runs the system ssh with JSON session output, shows the events (the
+//! device flow prompt, the tunnel URLs, refusals) as plain messages and
+//! reconnects with backoff when the connection drops, presenting the
+//! session's resumption token so the tunnels come back at once.
+//!
+//! ```bash
+//! # HTTP tunnel to localhost:3000 on demo.<domain>
+//! tunnl http 3000 --subdomain demo --server exlo.dev
+//!
+//! # Dedicated public TCP port for a local database
+//! tunnl tcp 5432 --remote-port 20001
+//!
+//! # Local end of `ssh -R udp:0:localhost:7000`: relay to a local UDP service
+//! tunnl udp-agent 7000 localhost:5353
+//! ```
+
+mod args;
+mod backoff;
+mod events;
+mod ssh;
+mod udp_agent;
+
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, ExitCode, ExitStatus, Stdio};
+use std::time::Instant;
+
+use args::{Options, SERVER_ENV, USAGE};
+use backoff::{Backoff, STABLE_AFTER};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if matches!(args.first().map(String::as_str), Some("help" | "-h" | "--help")) {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    // `tunnl udp-agent ...` is the local end of a UDP forward
+    if args.first().map(String::as_str) == Some("udp-agent") {
+        let (listen, service) = match udp_agent::parse(&args[1..]) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("tunnl: {}\n\n{}", e, USAGE);
+                return ExitCode::from(2);
+            }
+        };
+        return match udp_agent::run(listen, service) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("tunnl: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let options = match args::parse(&args, std::env::var(SERVER_ENV).ok()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("tunnl: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tunnl: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let identity = match &options.identity {
+        Some(identity) => identity.clone(),
+        None => {
+            let identity = ssh::default_identity().ok_or("no home directory for the key, pass --identity")?;
+            ssh::ensure_key(&identity)?;
+            identity
+        }
+    };
+
+    let mut backoff = Backoff::default();
+    let mut resume = None;
+    loop {
+        let started = Instant::now();
+        let session = run_session(options, &identity, resume.as_deref())?;
+        if session.fatal {
+            return Err("stopped, reconnecting wouldn't help".to_string());
+        }
+        if !options.reconnect {
+            if session.status.success() {
+                return Ok(());
+            }
+            return Err(format!("ssh exited ({})", session.status));
+        }
+        // Tokens are single-use: only one from this connection is worth trying
+        resume = session.resume_token;
+        // A tunnel that stayed up means the server works: retry quickly
+        if session.ready && started.elapsed() >= STABLE_AFTER {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        eprintln!("Connection lost, reconnecting in {}s...", delay.as_secs());
+        std::thread::sleep(delay);
+    }
+}
+
+/// How a connection ended
+struct SessionEnd {
+    status: ExitStatus,
+    /// A tunnel came up
+    ready: bool,
+    /// An event said reconnecting wouldn't help
+    fatal: bool,
+    /// Latest resumption token the server sent
+    resume_token: Option<String>,
+}
+
+/// Run ssh until the connection ends, showing the session's events
+fn run_session(options: &Options, identity: &Path, resume: Option<&str>) -> Result<SessionEnd, String> {
+    let mut child = Command::new("ssh")
+        .args(ssh::ssh_args(options, identity, resume))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run ssh: {}", e))?;
+    let stdout = child.stdout.take().ok_or("ssh has no output")?;
+
+    let (mut ready, mut fatal, mut resume_token) = (false, false, None);
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if options.json {
+            println!("{}", line);
+        }
+        resume_token = events::resume_token(&line).or(resume_token);
+        if let Some(notice) = events::describe(&line) {
+            ready |= notice.ready;
+            fatal |= notice.fatal;
+            if !options.json {
+                println!("{}", notice.text);
+            }
+        }
+    }
+    let status = child.wait().map_err(|e| format!("failed to wait for ssh: {}", e))?;
+    Ok(SessionEnd {
+        status,
+        ready,
+        fatal,
+        resume_token,
+    })
+}
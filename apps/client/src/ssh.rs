@@ -0,0 +1,124 @@
+//! The `ssh` invocation behind a tunnel.
+//!
+//! `tunnl` drives the system OpenSSH client rather than speaking SSH itself,
+//! so host key checks, agents and proxies work as the user configured them.
+//! It uses a key of its own (generated with `ssh-keygen` on first use), which
+//! the device flow then ties to the user's account; later connections with
+//! the same key skip the browser.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::args::{Mode, Options};
+
+/// Where the generated key lives, under the home directory
+const KEY_PATH: &str = ".tunnl/id_ed25519";
+
+/// How often ssh checks the server is still there (seconds), and how many
+/// missed answers end the connection
+const ALIVE_INTERVAL: u32 = 15;
+const ALIVE_COUNT_MAX: u32 = 3;
+
+pub fn default_identity() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(KEY_PATH))
+}
+
+/// Generate the key at `path` unless it exists
+pub fn ensure_key(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    }
+    eprintln!("Generating a key for tunnl at {}", path.display());
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C", "tunnl", "-f"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| format!("failed to run ssh-keygen: {}", e))?;
+    if !status.success() {
+        return Err(format!("ssh-keygen failed ({})", status));
+    }
+    Ok(())
+}
+
+/// The SSH username: the subdomain ("." for a random one) plus the profile,
+/// or the resumption token of the previous connection (which brings both back)
+fn username(options: &Options, resume: Option<&str>) -> String {
+    if let Some(token) = resume {
+        return format!("resume-{}", token);
+    }
+    let name = options.subdomain.as_deref().unwrap_or(".");
+    match &options.profile {
+        Some(profile) => format!("{}+{}", name, profile),
+        None => name.to_string(),
+    }
+}
+
+/// The `-R` forward
+fn forward(options: &Options) -> String {
+    let local = format!("{}:{}", options.local_host, options.local_port);
+    match options.mode {
+        Mode::Http => format!("{}:{}", options.local_port, local),
+        Mode::Tcp { remote_port } => format!("tcp:{}:{}", remote_port, local),
+    }
+}
+
+/// Arguments of the ssh command for a tunnel
+pub fn ssh_args(options: &Options, identity: &Path, resume: Option<&str>) -> Vec<String> {
+    let mut args: Vec<String> = vec!["-T".into(), "-i".into(), identity.display().to_string()];
+    for option in [
+        "IdentitiesOnly=yes".to_string(),
+        "StrictHostKeyChecking=accept-new".to_string(),
+        "ExitOnForwardFailure=yes".to_string(),
+        format!("ServerAliveInterval={}", ALIVE_INTERVAL),
+        format!("ServerAliveCountMax={}", ALIVE_COUNT_MAX),
+        // Session notices as JSON lines ; one SetEnv carries every variable
+        match options.secret_path {
+            true => "SetEnv=EXLO_OUTPUT=json EXLO_SECRET_PATH=on".to_string(),
+            false => "SetEnv=EXLO_OUTPUT=json".to_string(),
+        },
+    ] {
+        args.push("-o".into());
+        args.push(option);
+    }
+    args.extend([
+        "-p".into(),
+        options.ssh_port.to_string(),
+        "-R".into(),
+        forward(options),
+        format!("{}@{}", username(options, resume), options.server),
+    ]);
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::parse;
+
+    fn options(line: &str) -> Options {
+        let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        parse(&args, None).unwrap()
+    }
+
+    #[test]
+    fn test_ssh_args() {
+        let args = ssh_args(&options("http 3000 --subdomain demo --profile streaming"), Path::new("/k"), None);
+        assert_eq!(&args[..3], ["-T", "-i", "/k"]);
+        assert!(args.contains(&"SetEnv=EXLO_OUTPUT=json".to_string()));
+        assert_eq!(&args[args.len() - 5..], ["-p", "2222", "-R", "3000:localhost:3000", "demo+streaming@localhost"]);
+
+        let args = ssh_args(&options("http 3000 --secret-path"), Path::new("/k"), None);
+        assert!(args.contains(&"SetEnv=EXLO_OUTPUT=json EXLO_SECRET_PATH=on".to_string()));
+
+        let args = ssh_args(&options("tcp 5432 --local-host 10.0.0.2"), Path::new("/k"), None);
+        assert_eq!(&args[args.len() - 2..], ["tcp:0:10.0.0.2:5432", ".@localhost"]);
+        let args = ssh_args(&options("http 3000 --subdomain demo"), Path::new("/k"), Some("ab12"));
+        assert_eq!(args.last().unwrap(), "resume-ab12@localhost");
+    }
+}
@@ -0,0 +1,151 @@
+//! Local end of UDP forwards.
+//!
+//! `ssh -R udp:<port>:localhost:7000` makes the server relay the datagrams of
+//! each remote peer over a channel of its own, framed as a 2-byte big-endian
+//! length plus payload. ssh connects those channels to local TCP port 7000,
+//! where `tunnl udp-agent 7000 localhost:5353` unwraps them: every connection
+//! gets a UDP socket of its own facing the local service, so replies go back
+//! to the peer that asked.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Largest datagram a frame can carry
+const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+/// How often a channel's reply thread checks whether the channel closed
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Parse `<listen port> <service host:port>`
+pub fn parse(args: &[String]) -> Result<(u16, SocketAddr), String> {
+    let [listen, service] = args else {
+        return Err("udp-agent needs a listen port and a service address".to_string());
+    };
+    let listen = listen.parse().map_err(|_| format!("invalid listen port '{}'", listen))?;
+    let service = service
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("cannot resolve service address '{}'", service))?;
+    Ok((listen, service))
+}
+
+/// Unwrap the framed datagrams ssh delivers to `listen` and relay them to the
+/// UDP service. Runs until interrupted.
+pub fn run(listen: u16, service: SocketAddr) -> Result<(), String> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, listen))
+        .map_err(|e| format!("failed to listen on 127.0.0.1:{}: {}", listen, e))?;
+    println!("UDP agent on 127.0.0.1:{}, relaying to {}", listen, service);
+    serve(listener, service)
+}
+
+fn serve(listener: TcpListener, service: SocketAddr) -> Result<(), String> {
+    loop {
+        let (stream, _) = listener.accept().map_err(|e| format!("failed to accept: {}", e))?;
+        thread::spawn(move || {
+            if let Err(e) = relay_channel(stream, service) {
+                eprintln!("UDP agent: channel closed: {}", e);
+            }
+        });
+    }
+}
+
+/// Frame a datagram for the channel
+fn encode_frame(datagram: &[u8]) -> Vec<u8> {
+    let len = datagram.len().min(MAX_DATAGRAM);
+    let mut frame = Vec::with_capacity(2 + len);
+    frame.extend_from_slice(&(len as u16).to_be_bytes());
+    frame.extend_from_slice(&datagram[..len]);
+    frame
+}
+
+/// Relay one channel (one remote peer) until ssh closes it; the service's
+/// replies are framed back by a thread of their own
+fn relay_channel(mut stream: TcpStream, service: SocketAddr) -> io::Result<()> {
+    let unspecified = match service.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect(service)?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let replies = {
+        let (socket, mut writer, closed) = (socket.try_clone()?, stream.try_clone()?, closed.clone());
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; MAX_DATAGRAM];
+            while !closed.load(Ordering::Relaxed) {
+                // Errors are timeouts or an unreachable service: no reply yet
+                if let Ok(len) = socket.recv(&mut buffer) {
+                    if writer.write_all(&encode_frame(&buffer[..len])).is_err() {
+                        break;
+                    }
+                }
+            }
+        })
+    };
+    let result = forward_datagrams(&mut stream, &socket);
+    closed.store(true, Ordering::Relaxed);
+    let _ = replies.join();
+    result
+}
+
+/// Send the datagrams framed on the channel to the service until it ends
+fn forward_datagrams(channel: &mut impl Read, socket: &UdpSocket) -> io::Result<()> {
+    let mut datagram = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let mut header = [0u8; 2];
+        match channel.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let len = u16::from_be_bytes(header) as usize;
+        channel.read_exact(&mut datagram[..len])?;
+        // An unreachable service only loses the datagram
+        let _ = socket.send(&datagram[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relays_datagrams_to_the_service() {
+        // A UDP echo service
+        let service = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let service_addr = service.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            while let Ok((len, from)) = service.recv_from(&mut buffer) {
+                let _ = service.send_to(&buffer[..len], from);
+            }
+        });
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let agent_addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, service_addr));
+
+        // What ssh does with a forwarded channel
+        let mut channel = TcpStream::connect(agent_addr).unwrap();
+        channel.write_all(&encode_frame(b"ping")).unwrap();
+        let mut reply = [0u8; 6];
+        channel.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"\x00\x04ping");
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = |line: &str| line.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        let (listen, service) = parse(&args("7000 127.0.0.1:5353")).unwrap();
+        assert_eq!(listen, 7000);
+        assert_eq!(service, "127.0.0.1:5353".parse().unwrap());
+        assert!(parse(&args("7000")).is_err());
+        assert!(parse(&args("port 127.0.0.1:5353")).is_err());
+    }
+}
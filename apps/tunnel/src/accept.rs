@@ -0,0 +1,294 @@
+//! Accept-loop backpressure for the public listeners.
+//!
+//! Each listener holds a fixed number of connection permits. When all permits
+//! are in use the loop stops calling `accept()`, so new connections wait in the
+//! kernel's bounded backlog (and are dropped by the kernel once it fills)
+//! instead of piling up as tasks in memory.
+//!
+//! `PerIpLimiter` additionally caps how many of those connections one client
+//! IP may hold, so a single visitor can't take all the slots. The proxy
+//! listeners share one limiter, so the cap counts a visitor's connections
+//! across all tunnels and both the HTTP and TLS ports; trusted networks
+//! (monitoring, office NAT) can be exempted.
+//!
+//! Listeners can be handed over between processes so an upgrade never
+//! refuses connections: sockets passed by systemd socket activation (or any
+//! parent following its `LISTEN_FDS` protocol) are used instead of binding,
+//! and with `REUSE_PORT` a new process binds next to the old one before the
+//! old one is stopped.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+
+use crate::reputation::Cidr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Pause after a failed accept (e.g. file descriptor exhaustion) before retrying
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// First descriptor passed by socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Inherited listeners not yet claimed by a bind
+static INHERITED: OnceLock<Mutex<Vec<std::net::TcpListener>>> = OnceLock::new();
+
+/// Listening sockets passed to this process via `LISTEN_PID`/`LISTEN_FDS`
+fn inherited_listeners() -> Vec<std::net::TcpListener> {
+    let for_us = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+    if !for_us || count <= 0 {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            // SAFETY: the descriptors were passed to this process to own, and
+            // are only wrapped once (the result is cached)
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(addr) => {
+                    debug!("Inherited listener {} (fd {})", addr, fd);
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("Ignoring inherited fd {}, not a TCP listener: {}", fd, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Whether a socket listening on `listening` serves a bind to `wanted`
+fn serves(listening: SocketAddr, wanted: SocketAddr) -> bool {
+    listening.port() == wanted.port() && (listening.ip() == wanted.ip() || wanted.ip().is_unspecified())
+}
+
+/// Take the inherited listener for `addr`, if one was passed
+fn take_inherited(addr: SocketAddr) -> anyhow::Result<Option<TcpListener>> {
+    let mut inherited = INHERITED.get_or_init(|| Mutex::new(inherited_listeners())).lock().unwrap();
+    let Some(index) = inherited
+        .iter()
+        .position(|listener| listener.local_addr().is_ok_and(|local| serves(local, addr)))
+    else {
+        return Ok(None);
+    };
+    let listener = inherited.remove(index);
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+/// Bind a TCP listener with an explicit accept backlog, or take over the
+/// inherited socket for the address. `reuse_port` lets another process
+/// bind the same address (for upgrades).
+pub async fn bind_listener(addr: &str, backlog: u32, reuse_port: bool) -> anyhow::Result<TcpListener> {
+    let addr: SocketAddr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Could not resolve listen address {}", addr))?;
+
+    if let Some(listener) = take_inherited(addr)? {
+        info!("Using inherited listener for {}", addr);
+        return Ok(listener);
+    }
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(reuse_port)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(backlog)?)
+}
+
+/// Caps the number of in-flight connections for one listener.
+pub struct ConnectionLimiter {
+    name: &'static str,
+    max: usize,
+    permits: Arc<Semaphore>,
+    /// Whether we've already warned about the current saturation episode
+    saturated: AtomicBool,
+}
+
+impl ConnectionLimiter {
+    pub fn new(name: &'static str, max: usize) -> Self {
+        Self {
+            name,
+            max,
+            permits: Arc::new(Semaphore::new(max)),
+            saturated: AtomicBool::new(false),
+        }
+    }
+
+    /// Number of connections currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    /// Wait until a connection slot is free. Hold the permit for the lifetime
+    /// of the connection; dropping it frees the slot.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            if self.saturated.swap(false, Ordering::Relaxed) {
+                info!("{} listener below connection limit again, accepting", self.name);
+            }
+            return permit;
+        }
+
+        if !self.saturated.swap(true, Ordering::Relaxed) {
+            warn!(
+                "{} listener at connection limit ({}), pausing accept",
+                self.name, self.max
+            );
+        }
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("connection semaphore is never closed")
+    }
+
+    /// Accept the next connection once a slot is free.
+    /// Transient accept errors are logged and retried rather than ending the loop.
+    pub async fn accept(&self, listener: &TcpListener) -> (TcpStream, SocketAddr, OwnedSemaphorePermit) {
+        let permit = self.acquire().await;
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => return (stream, addr, permit),
+                Err(e) => {
+                    error!("{} accept failed: {}", self.name, e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+/// Caps the number of concurrent connections per client IP (0 = unlimited).
+pub struct PerIpLimiter {
+    max: usize,
+    /// Networks whose addresses are never limited
+    exempt: Vec<Cidr>,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// A connection slot of one IP; dropping it frees the slot.
+pub struct IpPermit {
+    ip: IpAddr,
+    counts: Option<Arc<Mutex<HashMap<IpAddr, usize>>>>,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let Some(counts) = &self.counts else { return };
+        let mut counts = counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl PerIpLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            exempt: Vec::new(),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Leave addresses in these networks unlimited
+    pub fn with_exemptions(mut self, exempt: Vec<Cidr>) -> Self {
+        self.exempt = exempt;
+        self
+    }
+
+    /// Take a slot for `ip`, or None if it already holds the maximum
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        if self.max == 0 || self.exempt.iter().any(|cidr| cidr.contains(ip)) {
+            return Some(IpPermit { ip, counts: None });
+        }
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit {
+            ip,
+            counts: Some(self.counts.clone()),
+        })
+    }
+
+    /// Connections `ip` currently holds
+    pub fn connections(&self, ip: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limiter_blocks_at_capacity() {
+        let limiter = ConnectionLimiter::new("test", 2);
+        let first = limiter.acquire().await;
+        let _second = limiter.acquire().await;
+        assert_eq!(limiter.in_flight(), 2);
+
+        // No slot free: acquiring must wait
+        let blocked = tokio::time::timeout(Duration::from_millis(20), limiter.acquire()).await;
+        assert!(blocked.is_err());
+
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_millis(20), limiter.acquire()).await;
+        assert!(third.is_ok());
+        assert_eq!(limiter.in_flight(), 2);
+    }
+
+    #[test]
+    fn test_inherited_listener_match() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(serves(addr("0.0.0.0:2222"), addr("0.0.0.0:2222")));
+        assert!(serves(addr("127.0.0.1:8080"), addr("0.0.0.0:8080")));
+        assert!(!serves(addr("0.0.0.0:8080"), addr("0.0.0.0:2222")));
+        assert!(!serves(addr("127.0.0.1:8080"), addr("10.0.0.1:8080")));
+    }
+
+    #[test]
+    fn test_per_ip_limiter() {
+        let limiter = PerIpLimiter::new(2);
+        let ip: IpAddr = "203.0.113.5".parse().unwrap();
+        let other: IpAddr = "203.0.113.6".parse().unwrap();
+        let first = limiter.try_acquire(ip).unwrap();
+        let _second = limiter.try_acquire(ip).unwrap();
+        assert!(limiter.try_acquire(ip).is_none());
+        assert!(limiter.try_acquire(other).is_some());
+
+        drop(first);
+        assert_eq!(limiter.connections(ip), 1);
+        assert!(limiter.try_acquire(ip).is_some());
+
+        let unlimited = PerIpLimiter::new(0);
+        let _permits: Vec<_> = (0..10).map(|_| unlimited.try_acquire(ip).unwrap()).collect();
+
+        let exempting = PerIpLimiter::new(1).with_exemptions(vec![Cidr::parse("203.0.113.0/24").unwrap()]);
+        let _permits: Vec<_> = (0..10).map(|_| exempting.try_acquire(ip).unwrap()).collect();
+        assert_eq!(exempting.connections(ip), 0);
+        let outside: IpAddr = "198.51.100.1".parse().unwrap();
+        let _permit = exempting.try_acquire(outside).unwrap();
+        assert!(exempting.try_acquire(outside).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+//! Per-tier access control for advanced tunnel features.
+//!
+//! The auth backend reports a tier for each user (the web app sends the
+//! user's role). `ACL_TIERS` maps tiers to the capabilities they grant, e.g.
+//! `user=custom_subdomain;admin=*`. Users whose tier isn't listed get
+//! `ACL_DEFAULT_TIER`'s capabilities, or none. Without `ACL_TIERS` every user
+//! may use every feature.
+
+use std::collections::HashMap;
+
+/// A gated feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Pick the subdomain via the SSH username instead of a random one
+    CustomSubdomain,
+    /// Hold more than one forward in a session
+    MultipleTunnels,
+    /// Attach custom domains to a tunnel
+    CustomDomains,
+    /// Be reachable through the TLS passthrough listener
+    TlsPassthrough,
+    /// Bind a dedicated public TCP port (`-R tcp:<port>:...`)
+    TcpPorts,
+    /// Bind a dedicated public UDP port (`-R udp:<port>:...`)
+    UdpPorts,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Self::CustomSubdomain,
+        Self::MultipleTunnels,
+        Self::CustomDomains,
+        Self::TlsPassthrough,
+        Self::TcpPorts,
+        Self::UdpPorts,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CustomSubdomain => "custom_subdomain",
+            Self::MultipleTunnels => "multiple_tunnels",
+            Self::CustomDomains => "custom_domains",
+            Self::TlsPassthrough => "tls_passthrough",
+            Self::TcpPorts => "tcp_ports",
+            Self::UdpPorts => "udp_ports",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.as_str() == value)
+    }
+
+    /// What the capability allows, for error messages
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::CustomSubdomain => "Custom subdomains",
+            Self::MultipleTunnels => "Multiple tunnels per session",
+            Self::CustomDomains => "Custom domains",
+            Self::TlsPassthrough => "TLS passthrough",
+            Self::TcpPorts => "Dedicated TCP ports",
+            Self::UdpPorts => "Dedicated UDP ports",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A set of capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub fn all() -> Self {
+        Self(Capability::ALL.iter().fold(0, |bits, c| bits | c.bit()))
+    }
+
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    pub fn with(self, capability: Capability) -> Self {
+        Self(self.0 | capability.bit())
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Names of the granted capabilities
+    pub fn names(&self) -> Vec<&'static str> {
+        Capability::ALL
+            .iter()
+            .filter(|c| self.allows(**c))
+            .map(Capability::as_str)
+            .collect()
+    }
+}
+
+/// Everything is allowed unless an ACL says otherwise
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// Which capabilities each tier grants
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AclPolicy {
+    tiers: HashMap<String, Capabilities>,
+    default_tier: Option<String>,
+}
+
+impl AclPolicy {
+    /// Parse `tier=cap,cap;tier=*` (an empty list grants nothing). The
+    /// default tier, if any, must be one of the listed tiers.
+    pub fn parse(value: &str, default_tier: Option<String>) -> Option<Self> {
+        let tiers = value
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (tier, list) = entry.split_once('=')?;
+                let tier = tier.trim();
+                if tier.is_empty() {
+                    return None;
+                }
+                let capabilities = list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .try_fold(Capabilities::none(), |caps, name| match name {
+                        "*" => Some(Capabilities::all()),
+                        name => Some(caps.with(Capability::parse(name)?)),
+                    })?;
+                Some((tier.to_string(), capabilities))
+            })
+            .collect::<Option<HashMap<_, _>>>()?;
+        if default_tier.as_ref().is_some_and(|t| !tiers.is_empty() && !tiers.contains_key(t)) {
+            return None;
+        }
+        Some(Self { tiers, default_tier })
+    }
+
+    /// Whether any tiers are configured
+    pub fn is_enabled(&self) -> bool {
+        !self.tiers.is_empty()
+    }
+
+    /// Capabilities of a user in `tier`
+    pub fn capabilities(&self, tier: Option<&str>) -> Capabilities {
+        if !self.is_enabled() {
+            return Capabilities::all();
+        }
+        tier.and_then(|t| self.tiers.get(t))
+            .or_else(|| self.default_tier.as_ref().and_then(|t| self.tiers.get(t)))
+            .copied()
+            .unwrap_or_else(Capabilities::none)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = AclPolicy::parse(
+            "user=custom_subdomain; pro=custom_subdomain,multiple_tunnels,custom_domains; admin=*; guest=",
+            Some("guest".to_string()),
+        )
+        .unwrap();
+        let user = policy.capabilities(Some("user"));
+        assert!(user.allows(Capability::CustomSubdomain));
+        assert!(!user.allows(Capability::MultipleTunnels));
+        assert_eq!(
+            policy.capabilities(Some("pro")).names(),
+            vec!["custom_subdomain", "multiple_tunnels", "custom_domains"]
+        );
+        assert_eq!(policy.capabilities(Some("admin")), Capabilities::all());
+        // Unknown and missing tiers fall back to the default tier
+        assert_eq!(policy.capabilities(Some("intern")), Capabilities::none());
+        assert_eq!(policy.capabilities(None), Capabilities::none());
+
+        assert_eq!(AclPolicy::parse("user=teleport", None), None);
+        assert_eq!(AclPolicy::parse("=custom_subdomain", None), None);
+        assert_eq!(AclPolicy::parse("admin=*", Some("guest".to_string())), None);
+    }
+
+    #[test]
+    fn test_disabled_policy_allows_everything() {
+        let policy = AclPolicy::parse("", None).unwrap();
+        assert!(!policy.is_enabled());
+        assert_eq!(policy.capabilities(None), Capabilities::all());
+
+        // Configured, but no default tier: unknown tiers get nothing
+        let policy = AclPolicy::parse("admin=*", None).unwrap();
+        assert_eq!(policy.capabilities(Some("user")), Capabilities::none());
+    }
+}
@@ -2,27 +2,426 @@
 //!
 //! All configuration must be provided via environment variables.
 //! Missing required variables will cause a panic at startup.
+//!
+//! Most settings are fixed for the life of the process (`get()`). Timeouts,
+//! rate and bandwidth limits, reserved subdomains and the log filter live in
+//! `Reloadable` (`reloadable()`) and are re-read by `reload()`.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
 
-use std::sync::OnceLock;
+use crate::acl::AclPolicy;
+use crate::reputation::Cidr;
+use crate::ssh::parse_trusted_ca_keys;
+use crate::state::bandwidth::parse_tier_limits;
+use crate::state::cleanup::DEFAULT_CLEANUP_CONCURRENCY;
+use crate::state::oauth::OAuthClient;
+use crate::state::static_routes::{parse_static_routes, StaticRoute};
+use crate::state::tunnel_limits::TunnelRateLimit;
 
 // ============================================================================
 // Environment variable names
 // ============================================================================
 
-mod env {
+pub(crate) mod env {
     pub const TUNNEL_URL: &str = "TUNNEL_URL";
     pub const API_BASE_URL: &str = "API_BASE_URL";
     pub const INTERNAL_API_SECRET: &str = "INTERNAL_API_SECRET";
+    pub const NODE_ID: &str = "NODE_ID";
+    pub const CLUSTER_PEERS: &str = "CLUSTER_PEERS";
+    pub const CLUSTER_MODE: &str = "CLUSTER_MODE";
+    pub const CLUSTER_PROXY_ADDR: &str = "CLUSTER_PROXY_ADDR";
+    pub const CLUSTER_REDIRECT_BASE: &str = "CLUSTER_REDIRECT_BASE";
+    pub const PROXY_PROTOCOL: &str = "PROXY_PROTOCOL";
+    pub const ACCESS_LOG: &str = "ACCESS_LOG";
+    pub const ACCESS_LOG_FORMAT: &str = "ACCESS_LOG_FORMAT";
+    pub const MAX_HTTP_CONNECTIONS: &str = "MAX_HTTP_CONNECTIONS";
+    pub const MAX_SSH_CONNECTIONS: &str = "MAX_SSH_CONNECTIONS";
+    pub const ACCEPT_BACKLOG: &str = "ACCEPT_BACKLOG";
+    pub const REUSE_PORT: &str = "REUSE_PORT";
+    pub const RESPONSE_COMPRESSION: &str = "RESPONSE_COMPRESSION";
+    pub const OSC52_COPY: &str = "OSC52_COPY";
+    pub const TCP_PORT_RANGE: &str = "TCP_PORT_RANGE";
+    pub const UDP_PORT_RANGE: &str = "UDP_PORT_RANGE";
+    pub const UDP_IDLE_TIMEOUT: &str = "UDP_IDLE_TIMEOUT";
+    pub const RESUME_TOKEN_TTL: &str = "RESUME_TOKEN_TTL";
+    pub const SESSION_TAKEOVER: &str = "SESSION_TAKEOVER";
+    pub const RESPONSE_CACHE_MEMORY: &str = "RESPONSE_CACHE_MEMORY";
+    pub const RESPONSE_CACHE_DIR: &str = "RESPONSE_CACHE_DIR";
+    pub const RESPONSE_CACHE_DISK: &str = "RESPONSE_CACHE_DISK";
+    pub const PORT_PROBE: &str = "PORT_PROBE";
+    pub const IDLE_TUNNEL_TIMEOUT: &str = "IDLE_TUNNEL_TIMEOUT";
+    pub const SSH_KEEPALIVE_INTERVAL: &str = "SSH_KEEPALIVE_INTERVAL";
+    pub const SSH_KEEPALIVE_MAX: &str = "SSH_KEEPALIVE_MAX";
+    pub const HEALTH_CHECK_INTERVAL: &str = "HEALTH_CHECK_INTERVAL";
+    pub const CRASH_REPORT_DIR: &str = "CRASH_REPORT_DIR";
+    pub const CRASH_WEBHOOK_URL: &str = "CRASH_WEBHOOK_URL";
+    pub const STATUS_ALERT_WEBHOOK_URL: &str = "STATUS_ALERT_WEBHOOK_URL";
+    pub const TUNNEL_SKIP_AUTH: &str = "TUNNEL_SKIP_AUTH";
+    pub const SUBDOMAIN_CLAIMS_PATH: &str = "SUBDOMAIN_CLAIMS_PATH";
+    pub const VERIFIED_KEYS_PATH: &str = "VERIFIED_KEYS_PATH";
+    pub const VERIFIED_KEY_MAX_AGE: &str = "VERIFIED_KEY_MAX_AGE";
+    pub const AUDIT_LOG_PATH: &str = "AUDIT_LOG_PATH";
+    pub const STATIC_ROUTES: &str = "STATIC_ROUTES";
+    pub const HOST_KEY_ROTATION_GRACE: &str = "HOST_KEY_ROTATION_GRACE";
+    pub const TRUSTED_USER_CA_KEYS: &str = "TRUSTED_USER_CA_KEYS";
+    pub const OAUTH_GITHUB_CLIENT_ID: &str = "OAUTH_GITHUB_CLIENT_ID";
+    pub const OAUTH_GITHUB_CLIENT_SECRET: &str = "OAUTH_GITHUB_CLIENT_SECRET";
+    pub const OAUTH_GOOGLE_CLIENT_ID: &str = "OAUTH_GOOGLE_CLIENT_ID";
+    pub const OAUTH_GOOGLE_CLIENT_SECRET: &str = "OAUTH_GOOGLE_CLIENT_SECRET";
+    pub const OAUTH_CALLBACK_URL: &str = "OAUTH_CALLBACK_URL";
+    pub const OAUTH_SESSION_TTL: &str = "OAUTH_SESSION_TTL";
+    pub const IP_REPUTATION_FILE: &str = "IP_REPUTATION_FILE";
+    pub const IP_REPUTATION_URL: &str = "IP_REPUTATION_URL";
+    pub const IP_REPUTATION_API_KEY: &str = "IP_REPUTATION_API_KEY";
+    pub const IP_REPUTATION_CACHE_TTL: &str = "IP_REPUTATION_CACHE_TTL";
+    pub const IP_REPUTATION_ACTIONS: &str = "IP_REPUTATION_ACTIONS";
+    pub const CUSTOM_DOMAIN_CERT_RESOLVER: &str = "CUSTOM_DOMAIN_CERT_RESOLVER";
+    pub const CUSTOM_DOMAIN_TRAEFIK_SERVICE: &str = "CUSTOM_DOMAIN_TRAEFIK_SERVICE";
+    pub const ROUTING_MODE: &str = "ROUTING_MODE";
+    pub const TLS_ON_HTTP_PORT: &str = "TLS_ON_HTTP_PORT";
+    pub const TUNNEL_RATE_LIMIT: &str = "TUNNEL_RATE_LIMIT";
+    pub const TUNNEL_RATE_BURST: &str = "TUNNEL_RATE_BURST";
+    pub const TUNNEL_MAX_CONNECTIONS: &str = "TUNNEL_MAX_CONNECTIONS";
+    pub const BANDWIDTH_LIMIT: &str = "BANDWIDTH_LIMIT";
+    pub const BANDWIDTH_TIERS: &str = "BANDWIDTH_TIERS";
+    pub const AUTO_BAN_THRESHOLD: &str = "AUTO_BAN_THRESHOLD";
+    pub const AUTO_BAN_DURATION: &str = "AUTO_BAN_DURATION";
+    pub const CLEANUP_CONCURRENCY: &str = "CLEANUP_CONCURRENCY";
+    pub const MAX_REQUEST_HEADER_BYTES: &str = "MAX_REQUEST_HEADER_BYTES";
+    pub const REQUEST_HEADER_TIMEOUT: &str = "REQUEST_HEADER_TIMEOUT";
+    pub const MAX_REQUEST_BODY_BYTES: &str = "MAX_REQUEST_BODY_BYTES";
+    pub const MAX_CONNECTIONS_PER_IP: &str = "MAX_CONNECTIONS_PER_IP";
+    pub const CONNECTION_LIMIT_EXEMPT: &str = "CONNECTION_LIMIT_EXEMPT";
+    pub const CHANNEL_POOL_SIZE: &str = "CHANNEL_POOL_SIZE";
+    pub const CHANNEL_POOL_IDLE_TIMEOUT: &str = "CHANNEL_POOL_IDLE_TIMEOUT";
+    pub const MAX_CHANNELS_PER_TUNNEL: &str = "MAX_CHANNELS_PER_TUNNEL";
+    pub const CHANNEL_QUEUE_TIMEOUT: &str = "CHANNEL_QUEUE_TIMEOUT";
+    pub const MAINTENANCE_MODE: &str = "MAINTENANCE_MODE";
+    pub const MAINTENANCE_MESSAGE: &str = "MAINTENANCE_MESSAGE";
+    pub const SYSTEMD_NOTIFY: &str = "SYSTEMD_NOTIFY";
+    pub const FORWARD_ADDRESS_POLICY: &str = "FORWARD_ADDRESS_POLICY";
+    pub const FORWARD_DNS_CACHE_TTL: &str = "FORWARD_DNS_CACHE_TTL";
+    pub const PROXY_IDLE_TIMEOUT: &str = "PROXY_IDLE_TIMEOUT";
+    pub const PROXY_WRITE_TIMEOUT: &str = "PROXY_WRITE_TIMEOUT";
+    pub const BACKEND_RECONCILE: &str = "BACKEND_RECONCILE";
+    pub const VERIFICATION_MODE: &str = "VERIFICATION_MODE";
+    pub const CODE_EXPIRY_SECS: &str = "CODE_EXPIRY_SECS";
+    pub const BACKEND_RECONCILE_INTERVAL: &str = "BACKEND_RECONCILE_INTERVAL";
+    pub const ACL_TIERS: &str = "ACL_TIERS";
+    pub const ACL_DEFAULT_TIER: &str = "ACL_DEFAULT_TIER";
+    pub const RESERVED_SUBDOMAINS: &str = "RESERVED_SUBDOMAINS";
+    pub const RUST_LOG: &str = "RUST_LOG";
 }
 
 /// Minimum length for INTERNAL_API_SECRET
 const MIN_SECRET_LENGTH: usize = 32;
 
+/// Default in-flight connection limits and listen backlog
+const DEFAULT_MAX_HTTP_CONNECTIONS: usize = 1024;
+const DEFAULT_MAX_SSH_CONNECTIONS: usize = 256;
+const DEFAULT_ACCEPT_BACKLOG: u32 = 128;
+
+/// Default limit on the request head the proxy buffers for routing
+const DEFAULT_MAX_REQUEST_HEADER_BYTES: usize = 16 * 1024;
+
+/// Default time (seconds) a visitor has to send the complete request head
+const DEFAULT_REQUEST_HEADER_TIMEOUT: u64 = 10;
+
+/// Default time (seconds) an idle pooled channel is kept
+const DEFAULT_CHANNEL_POOL_IDLE_TIMEOUT: u64 = 30;
+
+/// Default time (seconds) a request waits for a free channel of a busy tunnel
+const DEFAULT_CHANNEL_QUEUE_TIMEOUT: u64 = 10;
+
+/// Default time (seconds) a UDP peer's channel is kept without datagrams
+const DEFAULT_UDP_IDLE_TIMEOUT: u64 = 60;
+
+/// Default lifetime (seconds) of a session resumption token
+const DEFAULT_RESUME_TOKEN_TTL: u64 = 300;
+
+/// Default response cache budget per tunnel (bytes): in memory, on disk
+const DEFAULT_RESPONSE_CACHE_MEMORY: u64 = 16 * 1024 * 1024;
+const DEFAULT_RESPONSE_CACHE_DISK: u64 = 256 * 1024 * 1024;
+
+/// Default proxied connection timeouts (seconds): no traffic either way, one stalled write
+const DEFAULT_PROXY_IDLE_TIMEOUT: u64 = 300;
+const DEFAULT_PROXY_WRITE_TIMEOUT: u64 = 30;
+
+/// Default SSH keepalive: seconds between probes, unanswered probes before disconnecting
+const DEFAULT_SSH_KEEPALIVE_INTERVAL: u64 = 30;
+const DEFAULT_SSH_KEEPALIVE_MAX: usize = 3;
+
+/// Default interval (seconds) between health checks of local services
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 30;
+
+/// Default limit (seconds) on renewing a key verification without the Device Flow
+const DEFAULT_VERIFIED_KEY_MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Default interval (seconds) between backend registration reconciliations
+const DEFAULT_BACKEND_RECONCILE_INTERVAL: u64 = 300;
+
+/// Default activation code lifetime (seconds), shared with the Device Flow client
+const DEFAULT_CODE_EXPIRY_SECS: u64 = 300;
+
+/// Default automatic ban policy: strikes within 10 minutes, ban length (seconds)
+const DEFAULT_AUTO_BAN_THRESHOLD: u32 = 10;
+const DEFAULT_AUTO_BAN_DURATION: u64 = 3600;
+
+/// Default IP reputation cache lifetime (seconds) and score thresholds
+const DEFAULT_IP_REPUTATION_CACHE_TTL: u64 = 3600;
+const DEFAULT_IP_REPUTATION_ACTIONS: &str = "block:90,throttle:75,log:50";
+
+/// Default lifetime (seconds) of cached forward address lookups
+const DEFAULT_FORWARD_DNS_CACHE_TTL: u64 = 60;
+
+/// Default file subdomain claims are persisted to
+const DEFAULT_SUBDOMAIN_CLAIMS_PATH: &str = "subdomain_claims.json";
+
+/// Default file verified keys are persisted to
+const DEFAULT_VERIFIED_KEYS_PATH: &str = "verified_keys.json";
+
+/// Default file the audit trail is appended to
+const DEFAULT_AUDIT_LOG_PATH: &str = "audit.jsonl";
+
+/// Default time (seconds) a rotated host key is published before it is used
+const DEFAULT_HOST_KEY_ROTATION_GRACE: u64 = 7 * 24 * 60 * 60;
+
+/// Default lifetime (seconds) of a visitor's sign-in to an OAuth-protected tunnel
+const DEFAULT_OAUTH_SESSION_TTL: u64 = 12 * 60 * 60;
+
+/// Default log filter when `RUST_LOG` is unset
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
 // ============================================================================
 // Global configuration (loaded once at startup)
 // ============================================================================
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
+static RELOADABLE: OnceLock<ArcSwap<Reloadable>> = OnceLock::new();
+
+/// How a node handles requests for tunnels owned by another cluster node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterMode {
+    /// Pipe the connection through to the owning node's HTTP proxy
+    Relay,
+    /// Answer with a 307 pointing at the owning node's redirect base
+    Redirect,
+}
+
+impl ClusterMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "relay" => Some(Self::Relay),
+            "redirect" => Some(Self::Redirect),
+            _ => None,
+        }
+    }
+}
+
+/// Which bind addresses clients may request (`-R <address>:80:...`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardAddressPolicy {
+    /// Any well-formed hostname or IP
+    Any,
+    /// Only addresses (or hostnames resolving only to addresses) in private ranges
+    Private,
+    /// Only labels, IPs, `localhost` and wildcards; no hostnames
+    Labels,
+}
+
+impl ForwardAddressPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "any" => Some(Self::Any),
+            "private" => Some(Self::Private),
+            "labels" => Some(Self::Labels),
+            _ => None,
+        }
+    }
+}
+
+/// Output format of the HTTP proxy access log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// One JSON object per line
+    Json,
+    /// Apache combined log format
+    Combined,
+}
+
+impl AccessLogFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "combined" | "apache" => Some(Self::Combined),
+            _ => None,
+        }
+    }
+}
+
+/// How visitors address a tunnel on the HTTP proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingMode {
+    /// `<subdomain>.TUNNEL_URL` (needs wildcard DNS)
+    Subdomain,
+    /// `TUNNEL_URL/t/<subdomain>/` (single hostname)
+    Path,
+}
+
+impl RoutingMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "subdomain" => Some(Self::Subdomain),
+            "path" => Some(Self::Path),
+            _ => None,
+        }
+    }
+}
+
+/// What the HTTP proxy does with a TLS handshake (`https://` to the plain port)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsOnHttpPort {
+    /// Refuse it with a TLS alert instead of an HTTP error the client can't read
+    Close,
+    /// Route it on SNI like the TLS passthrough listener
+    Passthrough,
+}
+
+impl TlsOnHttpPort {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "close" => Some(Self::Close),
+            "passthrough" => Some(Self::Passthrough),
+            _ => None,
+        }
+    }
+}
+
+/// Whether backend tunnel registrations without a live tunnel are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconcileMode {
+    Off,
+    /// Log and count orphans without unregistering them
+    DryRun,
+    /// Unregister orphans
+    On,
+}
+
+impl ReconcileMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "dry-run" => Some(Self::DryRun),
+            "on" => Some(Self::On),
+            _ => None,
+        }
+    }
+}
+
+/// How a session learns that its activation code was approved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationMode {
+    /// Poll the web app's check-code endpoint
+    Poll,
+    /// Wait for the web app to POST the result to the management API
+    Callback,
+}
+
+impl VerificationMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "poll" => Some(Self::Poll),
+            "callback" => Some(Self::Callback),
+            _ => None,
+        }
+    }
+}
+
+/// What to do when the local service doesn't answer the pre-registration probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortProbeMode {
+    /// Disconnect the session (the local service must be up before connecting)
+    Strict,
+    /// Register anyway and mark the tunnel as waiting for the local service
+    Wait,
+    /// Skip the probe entirely
+    Off,
+}
+
+impl PortProbeMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "strict" => Some(Self::Strict),
+            "wait" => Some(Self::Wait),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// What happens when a key reconnects while its earlier session still holds
+/// the subdomain (e.g. a laptop waking up before the old connection timed out)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionTakeover {
+    /// Move the subdomain to the new session and disconnect the old one
+    Takeover,
+    /// Refuse the new forward until the old session ends (resumed sessions
+    /// still take over the session they resume)
+    Reject,
+}
+
+impl SessionTakeover {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "takeover" => Some(Self::Takeover),
+            "reject" => Some(Self::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with a connection from an IP with a bad reputation score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReputationAction {
+    /// Log a warning and carry on
+    Log,
+    /// Delay the connection before serving it
+    Throttle,
+    /// Drop the connection
+    Block,
+}
+
+impl ReputationAction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "log" => Some(Self::Log),
+            "throttle" => Some(Self::Throttle),
+            "block" => Some(Self::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Parse `action:min_score` pairs (e.g. "block:90,log:50"), highest threshold first
+pub fn parse_reputation_actions(value: &str) -> Option<Vec<(u8, ReputationAction)>> {
+    let mut rules = value
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (action, score) = rule.split_once(':')?;
+            let score: u8 = score.trim().parse().ok().filter(|s| *s <= 100)?;
+            Some((score, ReputationAction::parse(action.trim())?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    rules.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    Some(rules)
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -30,6 +429,142 @@ pub struct Config {
     pub tunnel_url: String,
     pub api_base_url: String,
     pub internal_api_secret: String,
+    /// Identifier of this instance within a cluster
+    pub node_id: String,
+    /// Management API base URLs of the other cluster nodes (empty = standalone)
+    pub cluster_peers: Vec<String>,
+    pub cluster_mode: ClusterMode,
+    /// Address other nodes use to reach this node's HTTP proxy (e.g., "10.0.0.5:8080")
+    pub cluster_proxy_addr: String,
+    /// Node-specific base domain used as the redirect target (e.g., "node-a.tunnel.example.com")
+    pub cluster_redirect_base: Option<String>,
+    /// Expect a PROXY protocol (v1/v2) header on HTTP proxy connections
+    pub proxy_protocol: bool,
+    /// Access log destination: "stdout", "-" or a file path (None = disabled)
+    pub access_log: Option<String>,
+    pub access_log_format: AccessLogFormat,
+    /// Maximum in-flight HTTP proxy connections before accept pauses
+    pub max_http_connections: usize,
+    /// Maximum in-flight SSH connections before accept pauses
+    pub max_ssh_connections: usize,
+    /// Kernel accept backlog for the public listeners
+    pub accept_backlog: u32,
+    /// Bind listeners with SO_REUSEPORT so an upgraded process can start
+    /// next to the running one
+    pub reuse_port: bool,
+    /// Compress compressible responses for visitors that accept gzip or brotli
+    pub response_compression: bool,
+    /// Put new tunnels' URLs on the client's clipboard (OSC 52)
+    pub osc52_copy: bool,
+    /// Public ports given out for `-R tcp:<port>:...` forwards (None = off)
+    pub tcp_port_range: Option<RangeInclusive<u16>>,
+    /// Public ports given out for `-R udp:<port>:...` forwards (None = off)
+    pub udp_port_range: Option<RangeInclusive<u16>>,
+    /// Close a UDP peer's channel after this long without datagrams
+    pub udp_idle_timeout: Duration,
+    /// Lifetime of session resumption tokens (None = none are issued)
+    pub resume_token_ttl: Option<Duration>,
+    pub session_takeover: SessionTakeover,
+    /// Bytes each tunnel's response cache keeps in memory
+    pub response_cache_memory: u64,
+    /// Directory entries pushed out of memory move to (None = dropped instead)
+    pub response_cache_dir: Option<String>,
+    /// Bytes each tunnel's response cache keeps on disk
+    pub response_cache_disk: u64,
+    /// Default port probe behaviour (sessions may override via EXLO_PORT_PROBE)
+    pub port_probe: PortProbeMode,
+    /// Interval of SSH keepalives and session pings (None = disabled)
+    pub ssh_keepalive_interval: Option<Duration>,
+    /// Unanswered keepalives before a session is dropped
+    pub ssh_keepalive_max: usize,
+    /// Interval of local service health checks over the SSH channel (None = disabled)
+    pub health_check_interval: Option<Duration>,
+    /// Directory crash reports are written to (None = log only)
+    pub crash_report_dir: Option<String>,
+    /// URL crash reports are POSTed to as JSON
+    pub crash_webhook_url: Option<String>,
+    /// URL tunnel status alerts are POSTed to as JSON
+    pub status_alert_webhook_url: Option<String>,
+    /// Accept sessions without the Device Flow (only honoured by builds with
+    /// the `dangerous-dev-auth` feature)
+    pub skip_auth: bool,
+    /// File subdomain claims are persisted to
+    pub subdomain_claims_path: String,
+    /// File verified keys are persisted to
+    pub verified_keys_path: String,
+    /// How long after the Device Flow a key's verification may be renewed
+    /// by activity (None = as long as it stays in use)
+    pub verified_key_max_age: Option<Duration>,
+    /// File the audit trail is appended to (JSON lines)
+    pub audit_log_path: String,
+    /// Subdomains served by fixed upstreams instead of SSH sessions
+    pub static_routes: Vec<StaticRoute>,
+    /// How long the old host keys keep being served after a rotation
+    pub host_key_rotation_grace: Duration,
+    /// CAs whose user certificates verify sessions without the Device Flow
+    pub trusted_user_ca_keys: Vec<russh_keys::PublicKey>,
+    /// GitHub OAuth app for protected tunnels (None = GitHub sign-in unavailable)
+    pub oauth_github: Option<OAuthClient>,
+    /// Google OAuth client for protected tunnels (None = Google sign-in unavailable)
+    pub oauth_google: Option<OAuthClient>,
+    /// Redirect URI registered with the providers, served on the base domain
+    pub oauth_callback_url: String,
+    /// How long a visitor stays signed in to a protected tunnel
+    pub oauth_session_ttl: Duration,
+    /// File of CIDRs (optionally followed by a score) with a bad reputation
+    pub ip_reputation_file: Option<String>,
+    /// AbuseIPDB-style reputation check endpoint (None = no HTTP lookups)
+    pub ip_reputation_url: Option<String>,
+    pub ip_reputation_api_key: Option<String>,
+    /// How long HTTP reputation lookups are cached
+    pub ip_reputation_cache_ttl: Duration,
+    /// Score thresholds and their actions, highest threshold first
+    pub ip_reputation_actions: Vec<(u8, ReputationAction)>,
+    /// Traefik certificate resolver used for custom domains (HTTP-01 challenge)
+    pub custom_domain_cert_resolver: String,
+    /// Traefik service that custom domain routers point at
+    pub custom_domain_traefik_service: String,
+    /// Subdomain hosts or `/t/<subdomain>/` paths on a single host
+    pub routing_mode: RoutingMode,
+    pub tls_on_http_port: TlsOnHttpPort,
+    /// Background cleanup tasks allowed to run at once
+    pub cleanup_concurrency: usize,
+    /// Largest request head the proxy buffers for routing (larger gets 431)
+    pub max_request_header_bytes: usize,
+    /// Time allowed for the complete request head (slower visitors get 408)
+    pub request_header_timeout: Duration,
+    /// Largest request body, declared or counted as it is relayed (see `proxy::body_limit`; None = unlimited)
+    pub max_request_body_bytes: Option<u64>,
+    /// Concurrent proxy connections per visitor IP, HTTP and TLS together (0 = unlimited)
+    pub max_connections_per_ip: usize,
+    /// Visitor networks not subject to `max_connections_per_ip`
+    pub connection_limit_exempt: Vec<Cidr>,
+    /// Idle forwarded channels kept per forward for reuse (0 = no pooling)
+    pub channel_pool_size: usize,
+    /// How long an idle pooled channel is kept
+    pub channel_pool_idle_timeout: Duration,
+    /// Forwarded channels busy at once per tunnel (0 = unlimited)
+    pub max_channels_per_tunnel: usize,
+    /// How long a request waits for a channel of a busy tunnel before a 503
+    pub channel_queue_timeout: Duration,
+    /// Start in maintenance mode (no new sessions or tunnels)
+    pub maintenance_mode: bool,
+    /// Shown to refused sessions instead of the default notice
+    pub maintenance_message: Option<String>,
+    /// Send sd_notify readiness, status and watchdog messages when
+    /// `NOTIFY_SOCKET` is set
+    pub systemd_notify: bool,
+    pub forward_address_policy: ForwardAddressPolicy,
+    /// How long hostname lookups for `forward_address_policy` are cached
+    pub forward_dns_cache_ttl: Duration,
+    /// Garbage collection of orphaned backend registrations
+    pub backend_reconcile: ReconcileMode,
+    pub backend_reconcile_interval: Duration,
+    pub verification_mode: VerificationMode,
+    /// How long an activation code can be approved
+    pub code_expiry: Duration,
+    /// Capabilities granted per user tier (allows everything when no tiers are set)
+    pub acl: AclPolicy,
 }
 
 impl Config {
@@ -47,10 +582,271 @@ impl Config {
             )
         });
 
+        let skip_auth = env_flag(env::TUNNEL_SKIP_AUTH);
+        if skip_auth && !cfg!(feature = "dangerous-dev-auth") {
+            log::warn!(
+                "{} is ignored: this build lacks the dangerous-dev-auth feature",
+                env::TUNNEL_SKIP_AUTH
+            );
+        }
+
+        let node_id = env_opt(env::NODE_ID)
+            .or_else(|| env_opt("HOSTNAME"))
+            .unwrap_or_else(|| "node-1".to_string());
+
+        let cluster_mode = match env_opt(env::CLUSTER_MODE) {
+            Some(value) => ClusterMode::parse(&value).unwrap_or_else(|| {
+                panic!("{} must be 'relay' or 'redirect', got '{}'", env::CLUSTER_MODE, value)
+            }),
+            None => ClusterMode::Relay,
+        };
+
+        let cluster_proxy_addr = env_opt(env::CLUSTER_PROXY_ADDR).unwrap_or_else(|| {
+            let http_port = std::env::var("HTTP_PORT").unwrap_or_else(|_| "8080".to_string());
+            format!("127.0.0.1:{}", http_port)
+        });
+
+        let forward_address_policy = match env_opt(env::FORWARD_ADDRESS_POLICY) {
+            Some(value) => ForwardAddressPolicy::parse(&value).unwrap_or_else(|| {
+                panic!(
+                    "{} must be 'any', 'private' or 'labels', got '{}'",
+                    env::FORWARD_ADDRESS_POLICY, value
+                )
+            }),
+            None => ForwardAddressPolicy::Any,
+        };
+
+        let access_log_format = match env_opt(env::ACCESS_LOG_FORMAT) {
+            Some(value) => AccessLogFormat::parse(&value).unwrap_or_else(|| {
+                panic!("{} must be 'json' or 'combined', got '{}'", env::ACCESS_LOG_FORMAT, value)
+            }),
+            None => AccessLogFormat::Json,
+        };
+
+        let port_probe = match env_opt(env::PORT_PROBE) {
+            Some(value) => PortProbeMode::parse(&value).unwrap_or_else(|| {
+                panic!("{} must be 'strict', 'wait' or 'off', got '{}'", env::PORT_PROBE, value)
+            }),
+            None => PortProbeMode::Strict,
+        };
+
+        let routing_mode = match env_opt(env::ROUTING_MODE) {
+            Some(value) => RoutingMode::parse(&value).unwrap_or_else(|| {
+                panic!("{} must be 'subdomain' or 'path', got '{}'", env::ROUTING_MODE, value)
+            }),
+            None => RoutingMode::Subdomain,
+        };
+
+        let tls_on_http_port = match env_opt(env::TLS_ON_HTTP_PORT) {
+            Some(value) => TlsOnHttpPort::parse(&value).unwrap_or_else(|| {
+                panic!("{} must be 'close' or 'passthrough', got '{}'", env::TLS_ON_HTTP_PORT, value)
+            }),
+            None => TlsOnHttpPort::Close,
+        };
+
+        let backend_reconcile = match env_opt(env::BACKEND_RECONCILE) {
+            Some(value) => ReconcileMode::parse(&value).unwrap_or_else(|| {
+                panic!(
+                    "{} must be 'off', 'dry-run' or 'on', got '{}'",
+                    env::BACKEND_RECONCILE, value
+                )
+            }),
+            None => ReconcileMode::Off,
+        };
+
+        let connection_limit_exempt = env_list(env::CONNECTION_LIMIT_EXEMPT)
+            .iter()
+            .map(|value| {
+                Cidr::parse(value).unwrap_or_else(|| {
+                    panic!("{} must list CIDRs or addresses, got '{}'", env::CONNECTION_LIMIT_EXEMPT, value)
+                })
+            })
+            .collect();
+
+        let session_takeover = match env_opt(env::SESSION_TAKEOVER) {
+            Some(value) => SessionTakeover::parse(&value).unwrap_or_else(|| {
+                panic!("{} must be 'takeover' or 'reject', got '{}'", env::SESSION_TAKEOVER, value)
+            }),
+            None => SessionTakeover::Takeover,
+        };
+
+        let verification_mode = match env_opt(env::VERIFICATION_MODE) {
+            Some(value) => VerificationMode::parse(&value).unwrap_or_else(|| {
+                panic!(
+                    "{} must be 'poll' or 'callback', got '{}'",
+                    env::VERIFICATION_MODE, value
+                )
+            }),
+            None => VerificationMode::Poll,
+        };
+
+        let actions = env_opt(env::IP_REPUTATION_ACTIONS)
+            .unwrap_or_else(|| DEFAULT_IP_REPUTATION_ACTIONS.to_string());
+        let ip_reputation_actions = parse_reputation_actions(&actions).unwrap_or_else(|| {
+            panic!(
+                "{} must be a list of action:score pairs (log, throttle, block; score 0-100), got '{}'",
+                env::IP_REPUTATION_ACTIONS, actions
+            )
+        });
+
+        let static_routes = parse_static_routes(&env_opt(env::STATIC_ROUTES).unwrap_or_default())
+            .unwrap_or_else(|e| panic!("{} must be a list of subdomain=host:port: {}", env::STATIC_ROUTES, e));
+
+        let trusted_user_ca_keys = env_opt(env::TRUSTED_USER_CA_KEYS)
+            .map(|path| {
+                let contents = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("{} ({}) can't be read: {}", env::TRUSTED_USER_CA_KEYS, path, e));
+                parse_trusted_ca_keys(&contents).unwrap_or_else(|e| {
+                    panic!("{} ({}) is not a list of public keys: {}", env::TRUSTED_USER_CA_KEYS, path, e)
+                })
+            })
+            .unwrap_or_default();
+
+        let oauth_client = |id_var: &str, secret_var: &str| match (env_opt(id_var), env_opt(secret_var)) {
+            (Some(client_id), Some(client_secret)) => Some(OAuthClient {
+                client_id,
+                client_secret,
+            }),
+            (None, None) => None,
+            _ => panic!("{} and {} must be set together", id_var, secret_var),
+        };
+        let oauth_github = oauth_client(env::OAUTH_GITHUB_CLIENT_ID, env::OAUTH_GITHUB_CLIENT_SECRET);
+        let oauth_google = oauth_client(env::OAUTH_GOOGLE_CLIENT_ID, env::OAUTH_GOOGLE_CLIENT_SECRET);
+        let oauth_callback_url = env_opt(env::OAUTH_CALLBACK_URL)
+            .unwrap_or_else(|| format!("https://{}/_exlo/oauth/callback", tunnel_url));
+
+        let acl_tiers = env_opt(env::ACL_TIERS).unwrap_or_default();
+        let acl = AclPolicy::parse(&acl_tiers, env_opt(env::ACL_DEFAULT_TIER)).unwrap_or_else(|| {
+            panic!(
+                "{} must be a list of tier=capability,... entries separated by ';' (capabilities: {}) \
+                 including the {} tier, got '{}'",
+                env::ACL_TIERS,
+                crate::acl::Capability::ALL.map(|c| c.as_str()).join(", "),
+                env::ACL_DEFAULT_TIER,
+                acl_tiers
+            )
+        });
+
         let config = Self {
             tunnel_url,
             api_base_url,
             internal_api_secret,
+            node_id,
+            cluster_peers: env_list(env::CLUSTER_PEERS),
+            cluster_mode,
+            cluster_proxy_addr,
+            cluster_redirect_base: env_opt(env::CLUSTER_REDIRECT_BASE),
+            proxy_protocol: env_flag(env::PROXY_PROTOCOL),
+            access_log: env_opt(env::ACCESS_LOG),
+            access_log_format,
+            max_http_connections: env_parse(env::MAX_HTTP_CONNECTIONS, DEFAULT_MAX_HTTP_CONNECTIONS),
+            max_ssh_connections: env_parse(env::MAX_SSH_CONNECTIONS, DEFAULT_MAX_SSH_CONNECTIONS),
+            accept_backlog: env_parse(env::ACCEPT_BACKLOG, DEFAULT_ACCEPT_BACKLOG),
+            reuse_port: env_flag(env::REUSE_PORT),
+            response_compression: env_flag(env::RESPONSE_COMPRESSION),
+            osc52_copy: env_flag(env::OSC52_COPY),
+            tcp_port_range: env_opt(env::TCP_PORT_RANGE).map(|value| {
+                parse_port_range(&value).unwrap_or_else(|| {
+                    panic!("{} must be a port range like '20000-20999', got '{}'", env::TCP_PORT_RANGE, value)
+                })
+            }),
+            udp_port_range: env_opt(env::UDP_PORT_RANGE).map(|value| {
+                parse_port_range(&value).unwrap_or_else(|| {
+                    panic!("{} must be a port range like '20000-20999', got '{}'", env::UDP_PORT_RANGE, value)
+                })
+            }),
+            udp_idle_timeout: Duration::from_secs(env_parse(env::UDP_IDLE_TIMEOUT, DEFAULT_UDP_IDLE_TIMEOUT)),
+            resume_token_ttl: Some(env_parse(env::RESUME_TOKEN_TTL, DEFAULT_RESUME_TOKEN_TTL))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            session_takeover,
+            response_cache_memory: env_parse(env::RESPONSE_CACHE_MEMORY, DEFAULT_RESPONSE_CACHE_MEMORY),
+            response_cache_dir: env_opt(env::RESPONSE_CACHE_DIR),
+            response_cache_disk: env_parse(env::RESPONSE_CACHE_DISK, DEFAULT_RESPONSE_CACHE_DISK),
+            port_probe,
+            ssh_keepalive_interval: Some(env_parse(env::SSH_KEEPALIVE_INTERVAL, DEFAULT_SSH_KEEPALIVE_INTERVAL))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            ssh_keepalive_max: env_parse(env::SSH_KEEPALIVE_MAX, DEFAULT_SSH_KEEPALIVE_MAX),
+            health_check_interval: Some(env_parse(env::HEALTH_CHECK_INTERVAL, DEFAULT_HEALTH_CHECK_INTERVAL))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            crash_report_dir: env_opt(env::CRASH_REPORT_DIR),
+            crash_webhook_url: env_opt(env::CRASH_WEBHOOK_URL),
+            status_alert_webhook_url: env_opt(env::STATUS_ALERT_WEBHOOK_URL),
+            skip_auth: skip_auth && cfg!(feature = "dangerous-dev-auth"),
+            subdomain_claims_path: env_opt(env::SUBDOMAIN_CLAIMS_PATH)
+                .unwrap_or_else(|| DEFAULT_SUBDOMAIN_CLAIMS_PATH.to_string()),
+            verified_keys_path: env_opt(env::VERIFIED_KEYS_PATH)
+                .unwrap_or_else(|| DEFAULT_VERIFIED_KEYS_PATH.to_string()),
+            verified_key_max_age: Some(env_parse(env::VERIFIED_KEY_MAX_AGE, DEFAULT_VERIFIED_KEY_MAX_AGE))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            audit_log_path: env_opt(env::AUDIT_LOG_PATH).unwrap_or_else(|| DEFAULT_AUDIT_LOG_PATH.to_string()),
+            static_routes,
+            host_key_rotation_grace: Duration::from_secs(env_parse(
+                env::HOST_KEY_ROTATION_GRACE,
+                DEFAULT_HOST_KEY_ROTATION_GRACE,
+            )),
+            trusted_user_ca_keys,
+            oauth_github,
+            oauth_google,
+            oauth_callback_url,
+            oauth_session_ttl: Duration::from_secs(env_parse(env::OAUTH_SESSION_TTL, DEFAULT_OAUTH_SESSION_TTL)),
+            ip_reputation_file: env_opt(env::IP_REPUTATION_FILE),
+            ip_reputation_url: env_opt(env::IP_REPUTATION_URL),
+            ip_reputation_api_key: env_opt(env::IP_REPUTATION_API_KEY),
+            ip_reputation_cache_ttl: Duration::from_secs(env_parse(
+                env::IP_REPUTATION_CACHE_TTL,
+                DEFAULT_IP_REPUTATION_CACHE_TTL,
+            )),
+            ip_reputation_actions,
+            custom_domain_cert_resolver: env_opt(env::CUSTOM_DOMAIN_CERT_RESOLVER)
+                .unwrap_or_else(|| "letsencrypt-http".to_string()),
+            custom_domain_traefik_service: env_opt(env::CUSTOM_DOMAIN_TRAEFIK_SERVICE)
+                .unwrap_or_else(|| "tunnel@docker".to_string()),
+            routing_mode,
+            tls_on_http_port,
+            cleanup_concurrency: env_parse(env::CLEANUP_CONCURRENCY, DEFAULT_CLEANUP_CONCURRENCY),
+            max_request_header_bytes: env_parse(
+                env::MAX_REQUEST_HEADER_BYTES,
+                DEFAULT_MAX_REQUEST_HEADER_BYTES,
+            ),
+            request_header_timeout: Duration::from_secs(env_parse(
+                env::REQUEST_HEADER_TIMEOUT,
+                DEFAULT_REQUEST_HEADER_TIMEOUT,
+            )),
+            max_request_body_bytes: Some(env_parse(env::MAX_REQUEST_BODY_BYTES, 0u64)).filter(|bytes| *bytes > 0),
+            max_connections_per_ip: env_parse(env::MAX_CONNECTIONS_PER_IP, 0),
+            connection_limit_exempt,
+            channel_pool_size: env_parse(env::CHANNEL_POOL_SIZE, 0),
+            channel_pool_idle_timeout: Duration::from_secs(env_parse(
+                env::CHANNEL_POOL_IDLE_TIMEOUT,
+                DEFAULT_CHANNEL_POOL_IDLE_TIMEOUT,
+            )),
+            max_channels_per_tunnel: env_parse(env::MAX_CHANNELS_PER_TUNNEL, 0),
+            channel_queue_timeout: Duration::from_secs(env_parse(
+                env::CHANNEL_QUEUE_TIMEOUT,
+                DEFAULT_CHANNEL_QUEUE_TIMEOUT,
+            )),
+            maintenance_mode: env_flag(env::MAINTENANCE_MODE),
+            maintenance_message: env_opt(env::MAINTENANCE_MESSAGE),
+            // On unless explicitly disabled
+            systemd_notify: env_opt(env::SYSTEMD_NOTIFY)
+                .is_none_or(|v| !matches!(v.to_lowercase().as_str(), "false" | "0" | "no" | "off")),
+            forward_address_policy,
+            forward_dns_cache_ttl: Duration::from_secs(env_parse(
+                env::FORWARD_DNS_CACHE_TTL,
+                DEFAULT_FORWARD_DNS_CACHE_TTL,
+            )),
+            backend_reconcile,
+            verification_mode,
+            code_expiry: Duration::from_secs(env_parse(env::CODE_EXPIRY_SECS, DEFAULT_CODE_EXPIRY_SECS)),
+            backend_reconcile_interval: Duration::from_secs(env_parse(
+                env::BACKEND_RECONCILE_INTERVAL,
+                DEFAULT_BACKEND_RECONCILE_INTERVAL,
+            )),
+            acl,
         };
 
         config.validate();
@@ -64,7 +860,197 @@ impl Config {
                 env::INTERNAL_API_SECRET, MIN_SECRET_LENGTH
             );
         }
+        if self.max_http_connections == 0 || self.max_ssh_connections == 0 {
+            panic!(
+                "{} and {} must be greater than 0",
+                env::MAX_HTTP_CONNECTIONS, env::MAX_SSH_CONNECTIONS
+            );
+        }
+        if self.max_request_header_bytes == 0 {
+            panic!("{} must be greater than 0", env::MAX_REQUEST_HEADER_BYTES);
+        }
+        if self.request_header_timeout.is_zero() {
+            panic!("{} must be greater than 0", env::REQUEST_HEADER_TIMEOUT);
+        }
+        if self.channel_pool_size > 0 && self.channel_pool_idle_timeout.is_zero() {
+            panic!("{} must be greater than 0", env::CHANNEL_POOL_IDLE_TIMEOUT);
+        }
+        if self.udp_idle_timeout.is_zero() {
+            panic!("{} must be greater than 0", env::UDP_IDLE_TIMEOUT);
+        }
+        if self.code_expiry.is_zero() {
+            panic!("{} must be greater than 0", env::CODE_EXPIRY_SECS);
+        }
+        if self.backend_reconcile != ReconcileMode::Off && self.backend_reconcile_interval.is_zero() {
+            panic!("{} must be greater than 0", env::BACKEND_RECONCILE_INTERVAL);
+        }
+        if self.ssh_keepalive_interval.is_some() && self.ssh_keepalive_max == 0 {
+            panic!("{} must be greater than 0", env::SSH_KEEPALIVE_MAX);
+        }
+    }
+}
+
+/// Settings that can change at runtime (see `reload()`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloadable {
+    /// Disconnect tunnels without proxied traffic for this long (None = never)
+    pub idle_tunnel_timeout: Option<Duration>,
+    /// Close a proxied connection after this long without traffic in either direction
+    pub proxy_idle_timeout: Duration,
+    /// Close a proxied connection when one side stops accepting data for this long
+    pub proxy_write_timeout: Duration,
+    /// Default per-tunnel proxy limits (the management API can override them)
+    pub tunnel_rate_limit: TunnelRateLimit,
+    /// Default bandwidth per tunnel and direction in bytes/s (0 = unlimited)
+    pub bandwidth_limit: u64,
+    /// Bandwidth per user and direction in bytes/s by tier, shared by the user's tunnels
+    pub bandwidth_tiers: HashMap<String, u64>,
+    /// Strikes (Device Flow rate-limit hits, rejected SSH auth) that trigger a ban (0 = off)
+    pub auto_ban_threshold: u32,
+    /// How long automatic bans last
+    pub auto_ban_duration: Duration,
+    /// Subdomains nobody may register (lowercase)
+    pub reserved_subdomains: Vec<String>,
+    /// `env_logger` filter (`RUST_LOG` syntax)
+    pub log_filter: String,
+}
+
+impl Reloadable {
+    fn load() -> Result<Self, String> {
+        let reloadable = Self {
+            idle_tunnel_timeout: Some(env_try_parse(env::IDLE_TUNNEL_TIMEOUT, 0u64)?)
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            proxy_idle_timeout: Duration::from_secs(env_try_parse(
+                env::PROXY_IDLE_TIMEOUT,
+                DEFAULT_PROXY_IDLE_TIMEOUT,
+            )?),
+            proxy_write_timeout: Duration::from_secs(env_try_parse(
+                env::PROXY_WRITE_TIMEOUT,
+                DEFAULT_PROXY_WRITE_TIMEOUT,
+            )?),
+            tunnel_rate_limit: TunnelRateLimit {
+                requests_per_second: env_try_parse(env::TUNNEL_RATE_LIMIT, 0)?,
+                burst: env_try_parse(env::TUNNEL_RATE_BURST, 0)?,
+                max_connections: env_try_parse(env::TUNNEL_MAX_CONNECTIONS, 0)?,
+            },
+            bandwidth_limit: env_try_parse(env::BANDWIDTH_LIMIT, 0)?,
+            bandwidth_tiers: parse_tier_limits(&env_opt(env::BANDWIDTH_TIERS).unwrap_or_default())
+                .map_err(|e| format!("{}: {}", env::BANDWIDTH_TIERS, e))?,
+            auto_ban_threshold: env_try_parse(env::AUTO_BAN_THRESHOLD, DEFAULT_AUTO_BAN_THRESHOLD)?,
+            auto_ban_duration: Duration::from_secs(env_try_parse(
+                env::AUTO_BAN_DURATION,
+                DEFAULT_AUTO_BAN_DURATION,
+            )?),
+            reserved_subdomains: env_list(env::RESERVED_SUBDOMAINS)
+                .into_iter()
+                .map(|s| s.to_lowercase())
+                .collect(),
+            log_filter: env_opt(env::RUST_LOG).unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string()),
+        };
+        reloadable.validate()?;
+        Ok(reloadable)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.proxy_idle_timeout.is_zero() || self.proxy_write_timeout.is_zero() {
+            return Err(format!(
+                "{} and {} must be greater than 0",
+                env::PROXY_IDLE_TIMEOUT, env::PROXY_WRITE_TIMEOUT
+            ));
+        }
+        Ok(())
     }
+
+    /// Whether nobody may register `subdomain`
+    pub fn is_reserved(&self, subdomain: &str) -> bool {
+        self.reserved_subdomains.iter().any(|s| s == subdomain)
+    }
+
+    /// Environment variables whose values differ between `self` and `other`
+    pub fn changes(&self, other: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.idle_tunnel_timeout != other.idle_tunnel_timeout {
+            changed.push(env::IDLE_TUNNEL_TIMEOUT);
+        }
+        if self.proxy_idle_timeout != other.proxy_idle_timeout {
+            changed.push(env::PROXY_IDLE_TIMEOUT);
+        }
+        if self.proxy_write_timeout != other.proxy_write_timeout {
+            changed.push(env::PROXY_WRITE_TIMEOUT);
+        }
+        if self.tunnel_rate_limit != other.tunnel_rate_limit {
+            changed.push(env::TUNNEL_RATE_LIMIT);
+        }
+        if self.bandwidth_limit != other.bandwidth_limit {
+            changed.push(env::BANDWIDTH_LIMIT);
+        }
+        if self.bandwidth_tiers != other.bandwidth_tiers {
+            changed.push(env::BANDWIDTH_TIERS);
+        }
+        if self.auto_ban_threshold != other.auto_ban_threshold {
+            changed.push(env::AUTO_BAN_THRESHOLD);
+        }
+        if self.auto_ban_duration != other.auto_ban_duration {
+            changed.push(env::AUTO_BAN_DURATION);
+        }
+        if self.reserved_subdomains != other.reserved_subdomains {
+            changed.push(env::RESERVED_SUBDOMAINS);
+        }
+        if self.log_filter != other.log_filter {
+            changed.push(env::RUST_LOG);
+        }
+        changed
+    }
+}
+
+/// Read an optional environment variable, treating empty values as unset
+fn env_opt(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Read a boolean flag ("true"/"1"/"yes"/"on"), defaulting to false
+fn env_flag(name: &str) -> bool {
+    env_opt(name)
+        .map(|v| matches!(v.to_lowercase().as_str(), "true" | "1" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Parse an optional environment variable, panicking on invalid values
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env_try_parse(name, default).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Parse an optional environment variable
+fn env_try_parse<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match env_opt(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("{} has an invalid value: '{}'", name, value)),
+        None => Ok(default),
+    }
+}
+
+/// Parse an inclusive port range (`20000-20999`, or a single port)
+fn parse_port_range(value: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    let (start, end) = (start.trim().parse::<u16>().ok()?, end.trim().parse::<u16>().ok()?);
+    (start > 0 && start <= end).then_some(start..=end)
+}
+
+/// Read a comma-separated list from an environment variable
+fn env_list(name: &str) -> Vec<String> {
+    env_opt(name)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 // ============================================================================
@@ -75,6 +1061,12 @@ impl Config {
 /// Panics if required environment variables are missing.
 pub fn init() {
     CONFIG.get_or_init(Config::load);
+    RELOADABLE.get_or_init(|| ArcSwap::from_pointee(Reloadable::load().unwrap_or_else(|e| panic!("{}", e))));
+}
+
+/// Whether configuration has been loaded
+pub fn is_loaded() -> bool {
+    CONFIG.get().is_some()
 }
 
 /// Get the global configuration. Panics if not initialized.
@@ -82,8 +1074,92 @@ pub fn get() -> &'static Config {
     CONFIG.get().expect("Config not initialized. Call config::init() first.")
 }
 
+/// The current runtime-adjustable settings. Panics if not initialized.
+pub fn reloadable() -> Arc<Reloadable> {
+    RELOADABLE
+        .get()
+        .expect("Config not initialized. Call config::init() first.")
+        .load_full()
+}
+
+/// Re-read the runtime-adjustable settings from the environment. Invalid
+/// values leave the current settings in place. Returns the new settings and
+/// the variables that changed.
+pub fn reload() -> Result<(Arc<Reloadable>, Vec<&'static str>), String> {
+    let current = RELOADABLE
+        .get()
+        .ok_or_else(|| "Config not initialized".to_string())?;
+    let reloaded = Arc::new(Reloadable::load()?);
+    let changed = current.load().changes(&reloaded);
+    current.store(reloaded.clone());
+    Ok((reloaded, changed))
+}
+
+/// Whether this instance participates in a multi-node cluster
+pub fn is_clustered() -> bool {
+    !get().cluster_peers.is_empty()
+}
+
 /// Construct a tunnel address from subdomain (without protocol)
 pub fn get_tunnel_url(subdomain: &str) -> String {
     let config = get();
-    format!("{}.{}", subdomain, config.tunnel_url)
+    match config.routing_mode {
+        RoutingMode::Subdomain => format!("{}.{}", subdomain, config.tunnel_url),
+        RoutingMode::Path => format!("{}/t/{}", config.tunnel_url, subdomain),
+    }
+}
+
+/// Public endpoint of a dedicated TCP or UDP port (`host:port`)
+pub fn get_port_endpoint(port: u32) -> String {
+    let host = get().tunnel_url.split(':').next().unwrap_or_default();
+    format!("{}:{}", host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reloadable_changes() {
+        let current = Reloadable {
+            idle_tunnel_timeout: None,
+            proxy_idle_timeout: Duration::from_secs(DEFAULT_PROXY_IDLE_TIMEOUT),
+            proxy_write_timeout: Duration::from_secs(DEFAULT_PROXY_WRITE_TIMEOUT),
+            tunnel_rate_limit: TunnelRateLimit::default(),
+            bandwidth_limit: 0,
+            bandwidth_tiers: HashMap::new(),
+            auto_ban_threshold: DEFAULT_AUTO_BAN_THRESHOLD,
+            auto_ban_duration: Duration::from_secs(DEFAULT_AUTO_BAN_DURATION),
+            reserved_subdomains: vec!["www".to_string()],
+            log_filter: DEFAULT_LOG_FILTER.to_string(),
+        };
+        assert!(current.changes(&current.clone()).is_empty());
+        assert!(current.is_reserved("www"));
+        assert!(!current.is_reserved("myapp"));
+
+        let reloaded = Reloadable {
+            tunnel_rate_limit: TunnelRateLimit {
+                requests_per_second: 20,
+                ..Default::default()
+            },
+            log_filter: "debug".to_string(),
+            ..current.clone()
+        };
+        assert_eq!(current.changes(&reloaded), vec![env::TUNNEL_RATE_LIMIT, env::RUST_LOG]);
+        assert!(Reloadable {
+            proxy_write_timeout: Duration::ZERO,
+            ..current
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("20000-20999"), Some(20000..=20999));
+        assert_eq!(parse_port_range("5432"), Some(5432..=5432));
+        assert_eq!(parse_port_range("20999-20000"), None);
+        assert_eq!(parse_port_range("0-10"), None);
+        assert_eq!(parse_port_range("ssh"), None);
+    }
 }
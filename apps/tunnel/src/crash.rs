@@ -0,0 +1,240 @@
+//! Structured crash reports.
+//!
+//! The panic hook turns every panic into a JSON report carrying the panicking
+//! task's context (module, session ID, subdomain). Reports are counted, the
+//! most recent ones are kept for the management API, and each is logged,
+//! optionally written to `CRASH_REPORT_DIR` and posted to `CRASH_WEBHOOK_URL`.
+//! Tasks started with `spawn_with_context` carry their context; for other
+//! panics (e.g. inside russh's session task) the module is taken from the
+//! panic location.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Reports kept for the management API
+const RECENT_REPORTS: usize = 20;
+
+/// Timeout for posting a report to the webhook
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    static CONTEXT: CrashContext;
+}
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+static RECENT: Mutex<VecDeque<CrashReport>> = Mutex::new(VecDeque::new());
+static WEBHOOK: OnceLock<mpsc::UnboundedSender<CrashReport>> = OnceLock::new();
+
+/// What a task is doing, attached to reports of panics inside it
+#[derive(Debug, Clone)]
+pub struct CrashContext {
+    module: &'static str,
+    session_id: Option<String>,
+    /// Filled in once known (e.g. after the proxy routed the request)
+    subdomain: Arc<Mutex<Option<String>>>,
+}
+
+impl CrashContext {
+    pub fn new(module: &'static str) -> Self {
+        Self {
+            module,
+            session_id: None,
+            subdomain: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn session(mut self, session_id: &str) -> Self {
+        self.session_id = Some(session_id.to_string());
+        self
+    }
+
+    pub fn subdomain(self, subdomain: &str) -> Self {
+        *self.subdomain.lock().unwrap() = Some(subdomain.to_string());
+        self
+    }
+}
+
+/// A captured panic
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CrashReport {
+    pub time: String,
+    pub module: String,
+    pub session_id: Option<String>,
+    pub subdomain: Option<String>,
+    pub message: String,
+    /// `file:line:column` of the panic
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    pub backtrace: String,
+}
+
+/// Run `future` on a new task whose panics are reported with `context`
+pub fn spawn_with_context<F>(context: CrashContext, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(CONTEXT.scope(context, future))
+}
+
+/// Record the subdomain the current task is working on (no-op outside
+/// `spawn_with_context`)
+pub fn set_subdomain(subdomain: &str) {
+    let _ = CONTEXT.try_with(|context| {
+        *context.subdomain.lock().unwrap() = Some(subdomain.to_string());
+    });
+}
+
+/// Rust module path of a source file, e.g. `src/ssh/handler.rs` -> `ssh::handler`
+fn module_from_location(file: &str) -> String {
+    let path = file.rsplit_once("src/").map_or(file, |(_, rest)| rest);
+    let path = path.strip_suffix(".rs").unwrap_or(path);
+    let path = path.strip_suffix("/mod").unwrap_or(path);
+    path.replace('/', "::")
+}
+
+fn build_report(message: String, location: Option<(&str, u32, u32)>, context: Option<CrashContext>) -> CrashReport {
+    let module = match (&context, location) {
+        (Some(context), _) => context.module.to_string(),
+        (None, Some((file, _, _))) => module_from_location(file),
+        (None, None) => "unknown".to_string(),
+    };
+    CrashReport {
+        time: Utc::now().to_rfc3339(),
+        module,
+        session_id: context.as_ref().and_then(|c| c.session_id.clone()),
+        subdomain: context.and_then(|c| c.subdomain.lock().unwrap().clone()),
+        message,
+        location: location.map(|(file, line, column)| format!("{}:{}:{}", file, line, column)),
+        thread: std::thread::current().name().map(str::to_string),
+        backtrace: std::backtrace::Backtrace::force_capture().to_string(),
+    }
+}
+
+fn write_report(dir: &Path, report: &CrashReport) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let name = format!(
+        "crash-{}-{}.json",
+        Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+        PANICS.load(Ordering::SeqCst)
+    );
+    let path = dir.join(name);
+    std::fs::write(&path, serde_json::to_vec_pretty(report).unwrap_or_default())?;
+    Ok(path)
+}
+
+fn record(report: CrashReport, report_dir: Option<&Path>) {
+    PANICS.fetch_add(1, Ordering::SeqCst);
+    error!(
+        "Crash report: {}",
+        serde_json::to_string(&CrashReport {
+            backtrace: String::new(),
+            ..report.clone()
+        })
+        .unwrap_or_default()
+    );
+    if let Some(dir) = report_dir {
+        match write_report(dir, &report) {
+            Ok(path) => error!("Crash report written to {}", path.display()),
+            Err(e) => warn!("Failed to write crash report to {}: {}", dir.display(), e),
+        }
+    }
+    if let Some(webhook) = WEBHOOK.get() {
+        let _ = webhook.send(report.clone());
+    }
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_REPORTS {
+        recent.pop_front();
+    }
+    recent.push_back(report);
+}
+
+/// Report every panic (the previous hook still prints to stderr)
+pub fn install_panic_hook(report_dir: Option<PathBuf>) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panic with a non-string payload".to_string());
+        let location = info.location().map(|l| (l.file(), l.line(), l.column()));
+        let context = CONTEXT.try_with(CrashContext::clone).ok();
+        record(build_report(message, location, context), report_dir.as_deref());
+        previous(info);
+    }));
+}
+
+/// Post reports to `url` as JSON. Only the first notifier started is used.
+pub fn spawn_crash_notifier(url: String) -> JoinHandle<()> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<CrashReport>();
+    let _ = WEBHOOK.set(sender);
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        while let Some(report) = receiver.recv().await {
+            let result = client
+                .post(&url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .json(&report)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                warn!("Failed to post crash report: {}", e);
+            }
+        }
+    })
+}
+
+/// Panics since startup
+pub fn panic_count() -> u64 {
+    PANICS.load(Ordering::SeqCst)
+}
+
+/// Most recent reports, oldest first
+pub fn recent_reports() -> Vec<CrashReport> {
+    RECENT.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_from_location() {
+        assert_eq!(module_from_location("src/ssh/handler_impl.rs"), "ssh::handler_impl");
+        assert_eq!(module_from_location("apps/tunnel/src/proxy/mod.rs"), "proxy");
+        assert_eq!(module_from_location("/rustc/abc/library/core/src/option.rs"), "option");
+    }
+
+    #[tokio::test]
+    async fn test_report_carries_task_context() {
+        let context = CrashContext::new("proxy").session("session1");
+        let report = spawn_with_context(context, async {
+            set_subdomain("app");
+            let context = CONTEXT.try_with(CrashContext::clone).ok();
+            build_report("boom".to_string(), Some(("src/proxy/mod.rs", 10, 5)), context)
+        })
+        .await
+        .unwrap();
+        assert_eq!(report.module, "proxy");
+        assert_eq!(report.session_id.as_deref(), Some("session1"));
+        assert_eq!(report.subdomain.as_deref(), Some("app"));
+        assert_eq!(report.location.as_deref(), Some("src/proxy/mod.rs:10:5"));
+
+        // Outside a scoped task only the location is known
+        let report = build_report("boom".to_string(), Some(("src/ssh/handler.rs", 1, 1)), None);
+        assert_eq!((report.module.as_str(), report.session_id), ("ssh::handler", None));
+    }
+}
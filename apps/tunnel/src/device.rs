@@ -5,9 +5,13 @@
 
 use std::time::Duration;
 
+use async_trait::async_trait;
 use log::{debug, info, warn};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+use crate::error::TunnelError;
+
 /// Configuration for the Device Flow
 #[derive(Clone)]
 pub struct DeviceFlowConfig {
@@ -20,12 +24,42 @@ pub struct DeviceFlowConfig {
     pub internal_secret: String,
     /// How long codes are valid (in seconds)
     pub code_expiry_secs: u64,
-    /// How often to poll for verification (in seconds)
+    /// Delay before the first poll (in seconds); later delays grow by
+    /// `poll_backoff_multiplier` up to `max_poll_interval_secs`
     pub poll_interval_secs: u64,
-    /// Maximum poll attempts before giving up
+    /// Longest delay between polls (in seconds)
+    pub max_poll_interval_secs: u64,
+    /// Factor each delay grows by
+    pub poll_backoff_multiplier: f64,
+    /// Random spread applied to each delay (0.2 = ±20%)
+    pub poll_jitter: f64,
+    /// Maximum poll attempts before giving up (polling also stops once the
+    /// code has expired)
     pub max_poll_attempts: u32,
 }
 
+impl DeviceFlowConfig {
+    /// Delay before poll `attempt` (0-based), without jitter
+    pub fn poll_delay(&self, attempt: u32) -> Duration {
+        let initial = Duration::from_secs(self.poll_interval_secs);
+        let max = Duration::from_secs(self.max_poll_interval_secs).max(initial);
+        let factor = self.poll_backoff_multiplier.max(1.0).powi(attempt.min(64) as i32);
+        Duration::try_from_secs_f64(initial.as_secs_f64() * factor)
+            .unwrap_or(max)
+            .min(max)
+    }
+
+    /// Delay before poll `attempt` with jitter applied
+    fn jittered_poll_delay(&self, attempt: u32) -> Duration {
+        let delay = self.poll_delay(attempt);
+        let jitter = self.poll_jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..=jitter))
+    }
+}
+
 impl Default for DeviceFlowConfig {
     fn default() -> Self {
         Self {
@@ -42,11 +76,23 @@ impl Default for DeviceFlowConfig {
             poll_interval_secs: std::env::var("POLL_INTERVAL_SECS")
                 .ok()
                 .and_then(|v| v.parse().ok())
-                .unwrap_or(2),
+                .unwrap_or(1),
+            max_poll_interval_secs: std::env::var("POLL_MAX_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            poll_backoff_multiplier: std::env::var("POLL_BACKOFF_MULTIPLIER")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2.0),
+            poll_jitter: std::env::var("POLL_JITTER")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0.2),
             max_poll_attempts: std::env::var("MAX_POLL_ATTEMPTS")
                 .ok()
                 .and_then(|v| v.parse().ok())
-                .unwrap_or(150), // 5 minutes at 2 sec intervals
+                .unwrap_or(150),
         }
     }
 }
@@ -76,14 +122,107 @@ pub struct CheckCodeResponse {
     pub user_id: Option<String>,
     #[serde(rename = "userName")]
     pub user_name: Option<String>,
+    /// The user's tier, for `ACL_TIERS`
+    pub tier: Option<String>,
+    pub error: Option<String>,
+}
+
+impl CheckCodeResponse {
+    /// The approved user, an error once the code can no longer be approved,
+    /// or None while it is pending
+    pub fn outcome(self) -> Option<Result<VerifiedUser, anyhow::Error>> {
+        match self.status.as_str() {
+            "verified" => match self.user_id {
+                Some(user_id) if !user_id.is_empty() => Some(Ok(VerifiedUser {
+                    user_id,
+                    user_name: self.user_name,
+                    tier: self.tier,
+                })),
+                _ => {
+                    warn!("Received empty user_id from API");
+                    None
+                }
+            },
+            "expired" => Some(Err(TunnelError::VerificationTimeout.into())),
+            "not_found" => Some(Err(anyhow::anyhow!("Activation code not found"))),
+            "pending" => None,
+            other => {
+                warn!("Unknown status: {}", other);
+                None
+            }
+        }
+    }
+}
+
+/// Activation result the web app POSTs to the management API in callback
+/// mode (same fields as the check-code response, plus the code)
+#[derive(Debug, Deserialize)]
+pub struct ActivationCallback {
+    pub code: String,
+    #[serde(flatten)]
+    pub result: CheckCodeResponse,
+}
+
+/// Request to validate an API token
+#[derive(Debug, Serialize)]
+pub struct VerifyTokenRequest {
+    pub token: String,
+}
+
+/// Response from validating an API token
+#[derive(Debug, Deserialize)]
+pub struct VerifyTokenResponse {
+    pub valid: bool,
+    #[serde(rename = "userId")]
+    pub user_id: Option<String>,
+    #[serde(rename = "userName")]
+    pub user_name: Option<String>,
+    /// The user's tier, for `ACL_TIERS`
+    pub tier: Option<String>,
     pub error: Option<String>,
 }
 
+impl VerifyTokenResponse {
+    /// The token's user, or None if the token was rejected
+    pub fn user(self) -> Option<VerifiedUser> {
+        if let Some(error) = &self.error {
+            debug!("Token rejected: {}", error);
+        }
+        match self.user_id {
+            Some(user_id) if self.valid && !user_id.is_empty() => Some(VerifiedUser {
+                user_id,
+                user_name: self.user_name,
+                tier: self.tier,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A tunnel registration as stored by the web backend
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendTunnel {
+    pub subdomain: String,
+    pub session_id: String,
+    pub user_id: String,
+    /// RFC 3339 timestamp of the registration
+    pub created_at: Option<String>,
+}
+
+/// Response from listing registered tunnels
+#[derive(Debug, Deserialize)]
+pub struct ListTunnelsResponse {
+    pub tunnels: Vec<BackendTunnel>,
+}
+
 /// Verified user information returned from Device Flow
 #[derive(Debug, Clone)]
 pub struct VerifiedUser {
     pub user_id: String,
     pub user_name: Option<String>,
+    /// Tier deciding which capabilities the user has (see `acl`)
+    pub tier: Option<String>,
 }
 
 impl VerifiedUser {
@@ -113,6 +252,40 @@ pub fn generate_activation_code() -> String {
     format!("{:04X}-{:04X}", part1, part2)
 }
 
+/// Authorizes SSH sessions and keeps the web backend informed of tunnels.
+///
+/// `DeviceFlowClient` implements it against the web app's internal API;
+/// programs embedding the server can supply their own (see `TunnlService`).
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Make an activation code known before showing it to the user
+    async fn register_code(&self, code: &str, session_id: &str) -> Result<(), anyhow::Error>;
+
+    /// Wait until the code is approved (or fail when it expires)
+    async fn poll_until_verified(&self, code: &str) -> Result<VerifiedUser, anyhow::Error>;
+
+    /// URL where the user approves the code
+    fn get_activation_url(&self, code: &str) -> String;
+
+    /// The user a long-lived API token was issued to (None = invalid token).
+    /// Providers without tokens reject every token.
+    async fn verify_token(&self, _token: &str) -> Result<Option<VerifiedUser>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Record a new tunnel
+    async fn register_tunnel(&self, tunnel: &RegisterTunnelRequest) -> Result<(), anyhow::Error>;
+
+    /// Record that a tunnel is gone
+    async fn unregister_tunnel(&self, subdomain: &str) -> Result<(), anyhow::Error>;
+
+    /// Every tunnel registration the backend holds (for orphan cleanup).
+    /// Providers that don't keep registrations have nothing to reconcile.
+    async fn list_tunnels(&self) -> Result<Vec<BackendTunnel>, anyhow::Error> {
+        Ok(Vec::new())
+    }
+}
+
 /// Device Flow API client
 pub struct DeviceFlowClient {
     config: DeviceFlowConfig,
@@ -187,63 +360,51 @@ impl DeviceFlowClient {
         if !response.status().is_success() {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
-            anyhow::bail!("Failed to check code: {} - {}", status, body);
+            return Err(ApiStatusError { status, body }.into());
         }
 
         let result: CheckCodeResponse = response.json().await?;
         Ok(result)
     }
 
-    /// Poll until the code is verified or times out
+    /// Poll with exponential backoff until the code is verified, expires or
+    /// the API fails permanently
     pub async fn poll_until_verified(
         &self,
         code: &str,
     ) -> Result<VerifiedUser, anyhow::Error> {
-        let interval = Duration::from_secs(self.config.poll_interval_secs);
-        
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(self.config.code_expiry_secs);
+
         for attempt in 0..self.config.max_poll_attempts {
-            tokio::time::sleep(interval).await;
+            let delay = self.config.jittered_poll_delay(attempt);
+            if tokio::time::Instant::now() + delay > deadline {
+                break;
+            }
+            tokio::time::sleep(delay).await;
 
             match self.check_code(code).await {
                 Ok(response) => {
                     debug!("Poll attempt {}: status={}", attempt + 1, response.status);
-                    
-                    match response.status.as_str() {
-                        "verified" => {
-                            if let Some(user_id) = response.user_id {
-                                if user_id.is_empty() {
-                                    warn!("Received empty user_id from API");
-                                    continue;
-                                }
-                                info!("Code {} verified by user {}", code, user_id);
-                                return Ok(VerifiedUser {
-                                    user_id,
-                                    user_name: response.user_name,
-                                });
-                            }
-                        }
-                        "expired" => {
-                            anyhow::bail!("Activation code expired");
-                        }
-                        "not_found" => {
-                            anyhow::bail!("Activation code not found");
-                        }
-                        "pending" => {
-                            // Continue polling
-                        }
-                        other => {
-                            warn!("Unknown status: {}", other);
+
+                    // None = still pending, continue polling
+                    if let Some(outcome) = response.outcome() {
+                        if let Ok(ref user) = outcome {
+                            info!("Code {} verified by user {}", code, user.user_id);
                         }
+                        return outcome;
                     }
                 }
+                Err(e) if is_transient(&e) => {
+                    warn!("Poll error (attempt {}), retrying: {}", attempt + 1, e);
+                }
                 Err(e) => {
-                    warn!("Poll error (attempt {}): {}", attempt + 1, e);
-                    // Continue polling on transient errors
+                    warn!("Poll error (attempt {}), giving up: {}", attempt + 1, e);
+                    return Err(e);
                 }
             }
         }
 
-        anyhow::bail!("Timeout waiting for activation")
+        Err(TunnelError::VerificationTimeout.into())
     }
 
     /// Get the activation URL for display to the user
@@ -251,6 +412,31 @@ impl DeviceFlowClient {
         format!("{}/activate?code={}", self.config.homepage_url, code)
     }
 
+    /// Validate an API token issued by the web app (headless clients use it
+    /// instead of the browser flow)
+    pub async fn verify_token(&self, token: &str) -> Result<Option<VerifiedUser>, anyhow::Error> {
+        let url = format!("{}/api/internal/verify-token", self.config.api_base_url);
+
+        let response = self
+            .http_client
+            .post(&url)
+            .header("X-Internal-Secret", &self.config.internal_secret)
+            .json(&VerifyTokenRequest {
+                token: token.to_string(),
+            })
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Failed to verify token: {} - {}", status, body);
+        }
+
+        let result: VerifyTokenResponse = response.json().await?;
+        Ok(result.user())
+    }
+
     /// Register a tunnel with the web server (for tracking purposes)
     pub async fn register_tunnel(&self, tunnel: &RegisterTunnelRequest) -> Result<(), anyhow::Error> {
         let url = format!("{}/api/internal/register-tunnel", self.config.api_base_url);
@@ -296,12 +482,94 @@ impl DeviceFlowClient {
         info!("Unregistered tunnel from web server: {}", subdomain);
         Ok(())
     }
+
+    /// List every tunnel registered with the web server
+    pub async fn list_tunnels(&self) -> Result<Vec<BackendTunnel>, anyhow::Error> {
+        let url = format!("{}/api/internal/list-tunnels", self.config.api_base_url);
+
+        let response = self
+            .http_client
+            .get(&url)
+            .header("X-Internal-Secret", &self.config.internal_secret)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Failed to list tunnels: {} - {}", status, body);
+        }
+
+        let result: ListTunnelsResponse = response.json().await?;
+        Ok(result.tunnels)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for DeviceFlowClient {
+    async fn register_code(&self, code: &str, session_id: &str) -> Result<(), anyhow::Error> {
+        DeviceFlowClient::register_code(self, code, session_id).await
+    }
+
+    async fn poll_until_verified(&self, code: &str) -> Result<VerifiedUser, anyhow::Error> {
+        DeviceFlowClient::poll_until_verified(self, code).await
+    }
+
+    fn get_activation_url(&self, code: &str) -> String {
+        DeviceFlowClient::get_activation_url(self, code)
+    }
+
+    async fn verify_token(&self, token: &str) -> Result<Option<VerifiedUser>, anyhow::Error> {
+        DeviceFlowClient::verify_token(self, token).await
+    }
+
+    async fn register_tunnel(&self, tunnel: &RegisterTunnelRequest) -> Result<(), anyhow::Error> {
+        DeviceFlowClient::register_tunnel(self, tunnel).await
+    }
+
+    async fn unregister_tunnel(&self, subdomain: &str) -> Result<(), anyhow::Error> {
+        DeviceFlowClient::unregister_tunnel(self, subdomain).await
+    }
+
+    async fn list_tunnels(&self) -> Result<Vec<BackendTunnel>, anyhow::Error> {
+        DeviceFlowClient::list_tunnels(self).await
+    }
+}
+
+/// The web API answered with an error status
+#[derive(Debug)]
+struct ApiStatusError {
+    status: reqwest::StatusCode,
+    body: String,
+}
+
+impl std::fmt::Display for ApiStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to check code: {} - {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for ApiStatusError {}
+
+/// Whether a failed API call is worth retrying: network trouble, timeouts,
+/// overload and server errors are; rejected requests and bad responses aren't
+fn is_transient(error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<ApiStatusError>() {
+        return e.status.is_server_error() || e.status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+    }
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return !e.is_decode() && !e.is_builder();
+    }
+    false
 }
 
 /// Request to register a tunnel
 #[derive(Debug, Serialize)]
 pub struct RegisterTunnelRequest {
     pub subdomain: String,
+    /// Also stamped on the proxy's access log entries for this tunnel
+    #[serde(rename = "correlationId")]
+    pub correlation_id: String,
     #[serde(rename = "userId")]
     pub user_id: String,
     #[serde(rename = "sessionId")]
@@ -384,3 +652,60 @@ mod chrono_lite {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DeviceFlowConfig {
+        DeviceFlowConfig {
+            api_base_url: String::new(),
+            homepage_url: String::new(),
+            internal_secret: String::new(),
+            code_expiry_secs: 300,
+            poll_interval_secs: 1,
+            max_poll_interval_secs: 10,
+            poll_backoff_multiplier: 2.0,
+            poll_jitter: 0.2,
+            max_poll_attempts: 150,
+        }
+    }
+
+    #[test]
+    fn test_poll_delay_backs_off_to_cap() {
+        let config = config();
+        let delays: Vec<u64> = (0..6).map(|attempt| config.poll_delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(config.poll_delay(u32::MAX), Duration::from_secs(10));
+
+        for attempt in 0..6 {
+            let delay = config.jittered_poll_delay(attempt);
+            let base = config.poll_delay(attempt);
+            assert!(delay >= base.mul_f64(0.8) && delay <= base.mul_f64(1.2));
+        }
+    }
+
+    #[test]
+    fn test_verify_token_response() {
+        let parse = |json: &str| serde_json::from_str::<VerifyTokenResponse>(json).unwrap().user();
+
+        let user = parse(r#"{"valid":true,"userId":"u1","userName":"Alice","tier":"pro"}"#).unwrap();
+        assert_eq!(user.user_id, "u1");
+        assert_eq!(user.user_name.as_deref(), Some("Alice"));
+        assert_eq!(user.tier.as_deref(), Some("pro"));
+
+        assert!(parse(r#"{"valid":false,"error":"Unknown token"}"#).is_none());
+        assert!(parse(r#"{"valid":false,"userId":"u1"}"#).is_none());
+        assert!(parse(r#"{"valid":true,"userId":""}"#).is_none());
+        assert!(parse(r#"{"valid":true}"#).is_none());
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let status = |status| anyhow::Error::new(ApiStatusError { status, body: String::new() });
+        assert!(is_transient(&status(reqwest::StatusCode::BAD_GATEWAY)));
+        assert!(is_transient(&status(reqwest::StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_transient(&status(reqwest::StatusCode::UNAUTHORIZED)));
+        assert!(!is_transient(&anyhow::anyhow!("API error")));
+    }
+}
@@ -1,6 +1,10 @@
 //! Error types for the tunnel server.
 
 /// Custom error types for tunnel-related operations.
+///
+/// Each variant has a stable code (`code`) that clients can match on; it is
+/// shown in terminal notices, management API error bodies and proxy error
+/// pages, and doesn't change when the message wording does.
 #[derive(Debug, thiserror::Error)]
 pub enum TunnelError {
     #[error("Authentication failed: {0}")]
@@ -9,12 +13,80 @@ pub enum TunnelError {
     #[error("Subdomain '{0}' is already taken")]
     SubdomainTaken(String),
 
+    /// Reserved by the operator, served by a static route or claimed by
+    /// another account
+    #[error("Subdomain '{0}' is reserved")]
+    SubdomainReserved(String),
+
     #[error("Tunnel not found for subdomain '{0}'")]
     TunnelNotFound(String),
 
+    #[error("Forward name '{0}' is already used by this tunnel")]
+    ForwardNameTaken(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+
+    #[error("Activation code expired")]
+    VerificationTimeout,
+
+    #[error("Upstream unreachable: {0}")]
+    UpstreamUnreachable(String),
+
     #[error("SSH protocol error: {0}")]
     SshError(#[from] russh::Error),
 
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
 }
+
+impl TunnelError {
+    /// Stable code identifying the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::AuthFailed(_) => "auth_failed",
+            Self::SubdomainTaken(_) => "subdomain_taken",
+            Self::SubdomainReserved(_) => "subdomain_reserved",
+            Self::TunnelNotFound(_) => "tunnel_not_found",
+            Self::ForwardNameTaken(_) => "forward_name_taken",
+            Self::QuotaExceeded(_) => "quota_exceeded",
+            Self::RateLimited(_) => "rate_limited",
+            Self::VerificationTimeout => "verification_timeout",
+            Self::UpstreamUnreachable(_) => "upstream_unreachable",
+            Self::SshError(_) => "ssh_error",
+            Self::IoError(_) => "io_error",
+        }
+    }
+
+    /// HTTP status for the error in API responses and error pages
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::AuthFailed(_) => 401,
+            Self::SubdomainTaken(_) | Self::SubdomainReserved(_) | Self::ForwardNameTaken(_) => 409,
+            Self::TunnelNotFound(_) => 404,
+            Self::QuotaExceeded(_) => 403,
+            Self::RateLimited(_) => 429,
+            Self::VerificationTimeout => 408,
+            Self::UpstreamUnreachable(_) => 502,
+            Self::SshError(_) | Self::IoError(_) => 500,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let error = TunnelError::SubdomainReserved("www".to_string());
+        assert_eq!((error.code(), error.http_status()), ("subdomain_reserved", 409));
+        assert_eq!(error.to_string(), "Subdomain 'www' is reserved");
+        // Errors passed around as anyhow keep their code
+        let error = anyhow::Error::from(TunnelError::VerificationTimeout);
+        assert_eq!(error.downcast_ref::<TunnelError>().map(TunnelError::code), Some("verification_timeout"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Signed subdomain grants.
+//!
+//! An operator mints a grant offline (`tunnel grant <subdomain> <user_id>`)
+//! and hands it to a user. The grant is the subdomain, the user_id and an
+//! expiry, signed with `INTERNAL_API_SECRET`, so any node sharing the secret
+//! can check it without a database. Presenting it as the SSH username
+//! (`grant-<grant>@server`) or in a control channel `redeem` message claims
+//! the subdomain for that user_id until the grant expires and requests it for
+//! the session. The claim is what holds the subdomain: a session verified as
+//! another user still can't register it.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::{env, get as get_config};
+use crate::ssh::is_valid_subdomain;
+use crate::state::claims::SubdomainClaim;
+use crate::state::AppState;
+
+/// SSH username prefix carrying a grant (`ssh -R ... grant-XXXX@server`)
+pub const GRANT_USER_PREFIX: &str = "grant-";
+
+/// How long a minted grant lasts unless `--days` is given
+pub const DEFAULT_GRANT_DAYS: u32 = 365;
+
+/// A subdomain reserved for a user by the operator
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedGrant {
+    pub subdomain: String,
+    pub user_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Bytes of the HMAC kept in a grant's signature
+const SIGNATURE_LEN: usize = 16;
+
+fn mac(secret: &str, payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"grant:");
+    mac.update(payload);
+    mac
+}
+
+fn signature(secret: &str, payload: &[u8]) -> String {
+    hex::encode(&mac(secret, payload).finalize().into_bytes()[..SIGNATURE_LEN])
+}
+
+impl SignedGrant {
+    /// The grant as `<base64url payload>.<signature>`
+    pub fn encode(&self, secret: &str) -> String {
+        let payload = format!("{}\n{}\n{}", self.subdomain, self.user_id, self.expires_at.timestamp());
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload.as_bytes()),
+            signature(secret, payload.as_bytes())
+        )
+    }
+
+    /// Check a grant's signature and expiry
+    pub fn decode(grant: &str, secret: &str, now: DateTime<Utc>) -> Result<Self, String> {
+        let invalid = || "Invalid grant".to_string();
+        let (encoded, sig) = grant.trim().rsplit_once('.').ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(encoded).map_err(|_| invalid())?;
+        // Compared in constant time
+        let sig = hex::decode(sig).ok().filter(|sig| sig.len() == SIGNATURE_LEN).ok_or_else(invalid)?;
+        mac(secret, &payload).verify_truncated_left(&sig).map_err(|_| invalid())?;
+        let payload = String::from_utf8(payload).map_err(|_| invalid())?;
+        let mut fields = payload.splitn(3, '\n');
+        let (Some(subdomain), Some(user_id), Some(expires_at)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let expires_at = expires_at
+            .parse()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .ok_or_else(invalid)?;
+        if expires_at <= now {
+            return Err(format!("The grant for '{}' has expired", subdomain));
+        }
+        Ok(Self {
+            subdomain: subdomain.to_string(),
+            user_id: user_id.to_string(),
+            expires_at,
+        })
+    }
+}
+
+/// Claim the granted subdomain for its user until the grant expires. Unlike
+/// the claims API, the user needs no verified key yet.
+pub async fn redeem(state: &AppState, grant: &str) -> Result<SubdomainClaim, String> {
+    let now = Utc::now();
+    let grant = SignedGrant::decode(grant, &get_config().internal_api_secret, now)?;
+    if state
+        .get_tunnel(&grant.subdomain)
+        .await
+        .is_some_and(|tunnel| tunnel.is_connected && tunnel.username != grant.user_id)
+    {
+        return Err(format!("Subdomain '{}' is in use by another user", grant.subdomain));
+    }
+    let ttl = (grant.expires_at - now).to_std().unwrap_or(Duration::ZERO);
+    state.claims.claim(&grant.subdomain, &grant.user_id, Some(ttl)).await
+}
+
+/// `tunnel grant <subdomain> <user_id> [--days N]`: print a signed grant
+pub fn run_grant_command(args: &[String]) -> anyhow::Result<String> {
+    let mut positional = Vec::new();
+    let mut days = DEFAULT_GRANT_DAYS;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--days=") {
+            days = value.parse().context("--days must be a number")?;
+        } else if arg == "--days" {
+            days = args.next().context("--days needs a value")?.parse().context("--days must be a number")?;
+        } else if arg == "--profile" {
+            args.next();
+        } else if !arg.starts_with("--profile=") {
+            positional.push(arg.as_str());
+        }
+    }
+    let [subdomain, user_id] = positional[..] else {
+        bail!("usage: tunnel grant <subdomain> <user_id> [--days N]");
+    };
+    let subdomain = subdomain.to_lowercase();
+    if !is_valid_subdomain(&subdomain) {
+        bail!("'{}' is not a valid subdomain", subdomain);
+    }
+    if user_id.is_empty() || user_id.contains('\n') {
+        bail!("'{}' is not a valid user_id", user_id);
+    }
+    if days == 0 {
+        bail!("--days must be at least 1");
+    }
+    let secret = std::env::var(env::INTERNAL_API_SECRET)
+        .with_context(|| format!("{} is required to sign grants", env::INTERNAL_API_SECRET))?;
+    let grant = SignedGrant {
+        subdomain,
+        user_id: user_id.to_string(),
+        expires_at: Utc::now() + chrono::Duration::days(days.into()),
+    };
+    Ok(grant.encode(&secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant() -> SignedGrant {
+        SignedGrant {
+            subdomain: "myapp".to_string(),
+            user_id: "user1".to_string(),
+            expires_at: Utc.timestamp_opt(2_000_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_grant_round_trip() {
+        let now = Utc.timestamp_opt(1_900_000_000, 0).unwrap();
+        let encoded = grant().encode("secret");
+        assert!(!encoded.contains('+') && !encoded.contains('@'));
+        assert_eq!(SignedGrant::decode(&encoded, "secret", now), Ok(grant()));
+
+        assert!(SignedGrant::decode(&encoded, "other", now).is_err());
+        let tampered = SignedGrant {
+            subdomain: "other".to_string(),
+            ..grant()
+        }
+        .encode("other");
+        let (payload, _) = tampered.rsplit_once('.').unwrap();
+        let (_, sig) = encoded.rsplit_once('.').unwrap();
+        assert!(SignedGrant::decode(&format!("{}.{}", payload, sig), "secret", now).is_err());
+        let (payload, sig) = encoded.rsplit_once('.').unwrap();
+        assert!(SignedGrant::decode(&format!("{}.{}", payload, &sig[..2]), "secret", now).is_err());
+
+        let later = Utc.timestamp_opt(2_000_000_000, 0).unwrap();
+        assert!(SignedGrant::decode(&encoded, "secret", later).unwrap_err().contains("expired"));
+    }
+
+    #[test]
+    fn test_grant_command_arguments() {
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(run_grant_command(&args(&["myapp"])).is_err());
+        assert!(run_grant_command(&args(&["-bad-", "user1"])).is_err());
+        assert!(run_grant_command(&args(&["myapp", "user1", "--days", "x"])).is_err());
+    }
+}
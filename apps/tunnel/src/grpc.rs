@@ -0,0 +1,474 @@
+//! gRPC management service.
+//!
+//! Mirrors the parts of the REST management API that control planes poll
+//! (`ListTunnels`, `KickTunnel`, `StreamEvents`, `GetStats`) over tonic, on
+//! the same `AppState`. Calls carry the internal API secret as
+//! `x-internal-secret` metadata or a bearer token; `StreamEvents` also takes
+//! the per-user event tokens the WebSocket stream accepts.
+//!
+//! The contract is `proto/management.proto`. The message types and routing
+//! below are written out by hand from it, so building the server doesn't
+//! need `protoc`; keep the two in sync.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+
+use crate::accept::bind_listener;
+use crate::config::get as get_config;
+use crate::crash::panic_count;
+use crate::management::{kick, EventMessage};
+use crate::state::events::{EventScope, Replay};
+use crate::state::AppState;
+
+/// Messages of `proto/management.proto`
+pub mod pb {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListTunnelsRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Tunnel {
+        #[prost(string, tag = "1")]
+        pub subdomain: String,
+        #[prost(string, optional, tag = "2")]
+        pub user_id: Option<String>,
+        #[prost(string, tag = "3")]
+        pub client_ip: String,
+        #[prost(string, tag = "4")]
+        pub connected_at: String,
+        #[prost(bool, tag = "5")]
+        pub is_connected: bool,
+        #[prost(string, tag = "6")]
+        pub node_id: String,
+        #[prost(string, tag = "7")]
+        pub correlation_id: String,
+        #[prost(uint64, tag = "8")]
+        pub bytes_in: u64,
+        #[prost(uint64, tag = "9")]
+        pub bytes_out: u64,
+        #[prost(uint32, tag = "10")]
+        pub active_connections: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListTunnelsResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub tunnels: Vec<Tunnel>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KickTunnelRequest {
+        #[prost(string, tag = "1")]
+        pub subdomain: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KickTunnelResponse {
+        #[prost(string, tag = "1")]
+        pub message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamEventsRequest {
+        #[prost(string, optional, tag = "1")]
+        pub resume: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TunnelEvent {
+        #[prost(string, tag = "1")]
+        pub kind: String,
+        #[prost(string, tag = "2")]
+        pub subdomain: String,
+        #[prost(string, optional, tag = "3")]
+        pub previous_subdomain: Option<String>,
+        #[prost(string, tag = "4")]
+        pub user_id: String,
+        #[prost(string, tag = "5")]
+        pub at: String,
+        #[prost(string, tag = "6")]
+        pub token: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamMarker {
+        #[prost(string, tag = "1")]
+        pub token: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EventMessage {
+        #[prost(oneof = "event_message::Message", tags = "1, 2, 3")]
+        pub message: Option<event_message::Message>,
+    }
+
+    pub mod event_message {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Message {
+            #[prost(message, tag = "1")]
+            Event(super::TunnelEvent),
+            #[prost(message, tag = "2")]
+            ResyncRequired(super::StreamMarker),
+            #[prost(message, tag = "3")]
+            Ready(super::StreamMarker),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetStatsRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Stats {
+        #[prost(string, tag = "1")]
+        pub node_id: String,
+        #[prost(uint32, tag = "2")]
+        pub tunnels: u32,
+        #[prost(uint32, tag = "3")]
+        pub connected_tunnels: u32,
+        #[prost(uint32, tag = "4")]
+        pub active_connections: u32,
+        #[prost(uint64, tag = "5")]
+        pub bytes_in: u64,
+        #[prost(uint64, tag = "6")]
+        pub bytes_out: u64,
+        #[prost(uint32, tag = "7")]
+        pub verified_keys: u32,
+        #[prost(uint32, tag = "8")]
+        pub banned_ips: u32,
+        #[prost(uint64, tag = "9")]
+        pub panics: u64,
+        #[prost(bool, tag = "10")]
+        pub maintenance_mode: bool,
+    }
+}
+
+/// Fully qualified service name from the proto package
+const SERVICE_NAME: &str = "exlo.management.v1.Management";
+
+/// Events buffered per subscriber before the stream applies backpressure
+const EVENT_STREAM_BUFFER: usize = 64;
+
+type EventStream = ReceiverStream<Result<pb::EventMessage, Status>>;
+
+impl From<EventMessage> for pb::EventMessage {
+    fn from(message: EventMessage) -> Self {
+        use pb::event_message::Message;
+        let message = match message {
+            EventMessage::Event {
+                event,
+                subdomain,
+                previous_subdomain,
+                user_id,
+                at,
+                token,
+            } => Message::Event(pb::TunnelEvent {
+                kind: event.to_string(),
+                subdomain,
+                previous_subdomain,
+                user_id,
+                at,
+                token,
+            }),
+            EventMessage::ResyncRequired { token } => Message::ResyncRequired(pb::StreamMarker { token }),
+            EventMessage::Ready { token } => Message::Ready(pb::StreamMarker { token }),
+        };
+        Self { message: Some(message) }
+    }
+}
+
+/// Scope granted by the call's secret or event token
+fn authorize<T>(request: &Request<T>) -> Result<EventScope, Status> {
+    let metadata = request.metadata();
+    let token = metadata
+        .get("x-internal-secret")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            metadata
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+        .unwrap_or_default();
+    EventScope::from_token(token, &get_config().internal_api_secret)
+        .ok_or_else(|| Status::unauthenticated("Invalid internal secret"))
+}
+
+/// Operator calls need the internal secret itself
+fn authorize_admin<T>(request: &Request<T>) -> Result<(), Status> {
+    match authorize(request)? {
+        EventScope::Global => Ok(()),
+        EventScope::User(_) => Err(Status::permission_denied("Event tokens only grant StreamEvents")),
+    }
+}
+
+async fn list_tunnels(
+    state: Arc<AppState>,
+    request: Request<pb::ListTunnelsRequest>,
+) -> Result<Response<pb::ListTunnelsResponse>, Status> {
+    authorize_admin(&request)?;
+    let tunnels = state
+        .list_tunnels()
+        .await
+        .into_iter()
+        .map(|t| {
+            let traffic = t.traffic.snapshot();
+            pb::Tunnel {
+                user_id: (!t.username.is_empty() && t.username != "anonymous").then(|| t.username.clone()),
+                subdomain: t.subdomain.clone(),
+                client_ip: t.client_ip.clone(),
+                connected_at: DateTime::<Utc>::from(t.created_at).to_rfc3339(),
+                is_connected: t.is_connected,
+                node_id: t.node_id.clone(),
+                correlation_id: t.correlation_id.clone(),
+                bytes_in: traffic.bytes_in,
+                bytes_out: traffic.bytes_out,
+                active_connections: traffic.active_connections,
+            }
+        })
+        .collect();
+    Ok(Response::new(pb::ListTunnelsResponse { tunnels }))
+}
+
+async fn kick_tunnel(
+    state: Arc<AppState>,
+    request: Request<pb::KickTunnelRequest>,
+) -> Result<Response<pb::KickTunnelResponse>, Status> {
+    authorize_admin(&request)?;
+    let subdomain = request.into_inner().subdomain;
+    info!("gRPC: Kick request for tunnel '{}'", subdomain);
+    kick(&state, &subdomain)
+        .await
+        .map_err(|_| Status::not_found(format!("Tunnel not found: {}", subdomain)))?;
+    Ok(Response::new(pb::KickTunnelResponse {
+        message: format!("Tunnel '{}' disconnected", subdomain),
+    }))
+}
+
+async fn get_stats(state: Arc<AppState>, request: Request<pb::GetStatsRequest>) -> Result<Response<pb::Stats>, Status> {
+    authorize_admin(&request)?;
+    let tunnels = state.list_tunnels().await;
+    let traffic: Vec<_> = tunnels.iter().map(|t| t.traffic.snapshot()).collect();
+    Ok(Response::new(pb::Stats {
+        node_id: get_config().node_id.clone(),
+        tunnels: tunnels.len() as u32,
+        connected_tunnels: tunnels.iter().filter(|t| t.is_connected).count() as u32,
+        active_connections: traffic.iter().map(|t| t.active_connections).sum(),
+        bytes_in: traffic.iter().map(|t| t.bytes_in).sum(),
+        bytes_out: traffic.iter().map(|t| t.bytes_out).sum(),
+        verified_keys: state.verified_keys.read().await.len() as u32,
+        banned_ips: state.bans.list().await.len() as u32,
+        panics: panic_count(),
+        maintenance_mode: state.maintenance_mode.current().await.is_some(),
+    }))
+}
+
+async fn stream_events(
+    state: Arc<AppState>,
+    request: Request<pb::StreamEventsRequest>,
+) -> Result<Response<EventStream>, Status> {
+    let scope = authorize(&request)?;
+    info!("gRPC: event stream opened ({:?})", scope);
+    let (sender, receiver) = mpsc::channel(EVENT_STREAM_BUFFER);
+    tokio::spawn(forward_events(state, scope, request.into_inner().resume, sender));
+    Ok(Response::new(ReceiverStream::new(receiver)))
+}
+
+/// Replay missed events (if resuming), then forward live events in scope,
+/// like the WebSocket stream
+async fn forward_events(
+    state: Arc<AppState>,
+    scope: EventScope,
+    resume: Option<String>,
+    sender: mpsc::Sender<Result<pb::EventMessage, Status>>,
+) {
+    let send = |message: EventMessage| sender.send(Ok(message.into()));
+    let (mut events, mut token) = state.events.subscribe();
+    // Highest sequence number delivered, so live events don't repeat the replay
+    let mut last_seq = 0;
+
+    if let Some(resume) = resume {
+        match state.events.replay(&resume) {
+            Replay::Events(missed) => {
+                for event in missed {
+                    last_seq = event.seq;
+                    token = state.events.token(event.seq);
+                    if scope.allows(&event) && send(EventMessage::event(&event, token.clone())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Replay::ResyncRequired => {
+                if send(EventMessage::ResyncRequired { token: token.clone() }).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+    if send(EventMessage::Ready { token }).await.is_err() {
+        return;
+    }
+
+    loop {
+        let message = tokio::select! {
+            received = events.recv() => match received {
+                Ok(event) if event.seq <= last_seq || !scope.allows(&event) => {
+                    last_seq = last_seq.max(event.seq);
+                    continue;
+                }
+                Ok(event) => {
+                    last_seq = event.seq;
+                    EventMessage::event(&event, state.events.token(event.seq))
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("gRPC event stream subscriber lagged by {} events", skipped);
+                    let (resubscribed, token) = state.events.subscribe();
+                    events = resubscribed;
+                    EventMessage::ResyncRequired { token }
+                }
+                Err(RecvError::Closed) => return,
+            },
+            // The client went away
+            _ = sender.closed() => return,
+        };
+        if send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Adapts a handler to tonic's unary service
+struct Unary<F>(F);
+
+impl<Req, Res, F, Fut> UnaryService<Req> for Unary<F>
+where
+    F: FnMut(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Res>, Status>> + Send + 'static,
+{
+    type Response = Res;
+    type Future = BoxFuture<Response<Res>, Status>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        Box::pin((self.0)(request))
+    }
+}
+
+/// Adapts the event stream handler to tonic's server streaming service
+struct Streaming<F>(F);
+
+impl<Req, F, Fut> ServerStreamingService<Req> for Streaming<F>
+where
+    F: FnMut(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<EventStream>, Status>> + Send + 'static,
+{
+    type Response = pb::EventMessage;
+    type ResponseStream = EventStream;
+    type Future = BoxFuture<Response<EventStream>, Status>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        Box::pin((self.0)(request))
+    }
+}
+
+/// The management service, routed by method path
+#[derive(Clone)]
+pub struct ManagementService {
+    state: Arc<AppState>,
+}
+
+impl ManagementService {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+}
+
+impl NamedService for ManagementService {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl<B> Service<http::Request<B>> for ManagementService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let state = self.state.clone();
+        let method = request.uri().path().strip_prefix(SERVICE_NAME).unwrap_or_default().to_string();
+        Box::pin(async move {
+            let response = match method.as_str() {
+                "/ListTunnels" => {
+                    let handler = Unary(move |r| list_tunnels(state.clone(), r));
+                    Grpc::new(ProstCodec::default()).unary(handler, request).await
+                }
+                "/KickTunnel" => {
+                    let handler = Unary(move |r| kick_tunnel(state.clone(), r));
+                    Grpc::new(ProstCodec::default()).unary(handler, request).await
+                }
+                "/GetStats" => {
+                    let handler = Unary(move |r| get_stats(state.clone(), r));
+                    Grpc::new(ProstCodec::default()).unary(handler, request).await
+                }
+                "/StreamEvents" => {
+                    let handler = Streaming(move |r| stream_events(state.clone(), r));
+                    Grpc::new(ProstCodec::default()).server_streaming(handler, request).await
+                }
+                _ => Status::unimplemented(format!("Unknown method {}", method)).into_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+/// Run the gRPC management server
+pub async fn run_grpc_api(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
+    let config = get_config();
+    let listener = bind_listener(addr, config.accept_backlog, config.reuse_port).await?;
+    info!("gRPC management API listening on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(ManagementService::new(state))
+        .serve_with_incoming(TcpIncoming::from(listener))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn test_event_message_encoding() {
+        let message: pb::EventMessage = EventMessage::Ready { token: "abc".to_string() }.into();
+        let decoded = pb::EventMessage::decode(message.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(
+            decoded.message,
+            Some(pb::event_message::Message::Ready(pb::StreamMarker {
+                token: "abc".to_string()
+            }))
+        );
+
+        // Field 3 of TunnelEvent is the optional previous subdomain
+        let event = pb::TunnelEvent {
+            previous_subdomain: Some("old".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(event.encode_to_vec(), b"\x1a\x03old");
+    }
+}
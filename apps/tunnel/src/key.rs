@@ -1,43 +1,473 @@
 //! Server key management.
+//!
+//! The server offers one host key per algorithm so clients that predate
+//! Ed25519 (or only accept RSA / ECDSA) can still connect.
+//! `SERVER_KEY_ALGORITHMS` picks which ones (default `ed25519,ecdsa,rsa`, in
+//! order of preference). The Ed25519 key lives at `SERVER_KEY_PATH`, the
+//! others next to it with the algorithm in the name (`server_key_rsa.pem`).
+//! Missing keys are generated and saved on first start.
+//!
+//! With `SERVER_KEY_PASSPHRASE` set (or `SERVER_KEY_PASSPHRASE_PROMPT=true`
+//! to type it at startup) keys are encrypted at rest: new keys are written
+//! encrypted, existing ones are decrypted when loaded, and an unencrypted key
+//! on disk is refused rather than used. An encrypted key found without a
+//! configured passphrase is asked for on the terminal, if there is one.
+//!
+//! Rotation (`POST /host-keys/rotate` or `tunnel rotate-key`) generates the
+//! replacement keys at `<path>.next` while the current ones keep being served,
+//! so clients can learn the new fingerprints before they are presented. When
+//! the grace period is over the `.next` files replace the keys (the old ones
+//! are kept as `<path>.old`).
 
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Context;
 use log::info;
-use russh_keys::HashAlg;
+use russh_keys::{Algorithm, EcdsaCurve, HashAlg};
 
-/// Path to store the server key
-pub fn load_or_generate_server_key() -> anyhow::Result<russh_keys::PrivateKey> {
-    use russh_keys::Algorithm;
-    use std::env;
-    use std::fs;
-    use std::path::Path;
+use crate::config::env;
 
-    let key_path_str = env::var("SERVER_KEY_PATH").unwrap_or_else(|_| "server_key.pem".to_string());
-    let key_path = Path::new(&key_path_str);
+/// Where the Ed25519 key is kept
+const SERVER_KEY_PATH: &str = "SERVER_KEY_PATH";
 
-    if key_path.exists() {
-        info!("Loading server key from {}...", key_path.display());
-        let key_data = fs::read_to_string(key_path)?;
-        let key = russh_keys::PrivateKey::from_openssh(&key_data)?;
-        info!(
-            "Server key fingerprint: {}",
-            key.public_key().fingerprint(HashAlg::Sha256)
+/// Which host keys are offered
+const SERVER_KEY_ALGORITHMS: &str = "SERVER_KEY_ALGORITHMS";
+
+/// Passphrase the keys are encrypted with
+const SERVER_KEY_PASSPHRASE: &str = "SERVER_KEY_PASSPHRASE";
+
+/// Ask for the passphrase on the terminal at startup
+const SERVER_KEY_PASSPHRASE_PROMPT: &str = "SERVER_KEY_PASSPHRASE_PROMPT";
+
+const DEFAULT_SERVER_KEY_PATH: &str = "server_key.pem";
+
+const DEFAULT_SERVER_KEY_ALGORITHMS: &str = "ed25519,ecdsa,rsa";
+
+/// Passphrase resolved at startup, reused to write rotated keys
+static PASSPHRASE: OnceLock<Option<String>> = OnceLock::new();
+
+/// A host key algorithm the server can offer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyAlgorithm {
+    Ed25519,
+    /// NIST P-256
+    Ecdsa,
+    /// 3072-bit
+    Rsa,
+}
+
+impl HostKeyAlgorithm {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "ed25519" => Some(Self::Ed25519),
+            "ecdsa" => Some(Self::Ecdsa),
+            "rsa" => Some(Self::Rsa),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ed25519 => "ed25519",
+            Self::Ecdsa => "ecdsa",
+            Self::Rsa => "rsa",
+        }
+    }
+
+    /// The algorithm of `key`, if the server can offer it
+    pub fn of(key: &russh_keys::PrivateKey) -> Option<Self> {
+        match key.algorithm() {
+            Algorithm::Ed25519 => Some(Self::Ed25519),
+            Algorithm::Ecdsa { .. } => Some(Self::Ecdsa),
+            Algorithm::Rsa { .. } => Some(Self::Rsa),
+            _ => None,
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Ed25519 => Algorithm::Ed25519,
+            Self::Ecdsa => Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+            Self::Rsa => Algorithm::Rsa { hash: None },
+        }
+    }
+
+    /// Where this algorithm's key lives, given the Ed25519 key's path
+    pub fn key_path(&self, base: &Path) -> PathBuf {
+        if *self == Self::Ed25519 {
+            return base.to_path_buf();
+        }
+        let stem = base.file_stem().and_then(|s| s.to_str()).unwrap_or("server_key");
+        let name = match base.extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("{}_{}.{}", stem, self.as_str(), extension),
+            None => format!("{}_{}", stem, self.as_str()),
+        };
+        base.with_file_name(name)
+    }
+}
+
+/// Parse a comma-separated algorithm list, without duplicates
+pub fn parse_host_key_algorithms(value: &str) -> Option<Vec<HostKeyAlgorithm>> {
+    let mut algorithms = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let algorithm = HostKeyAlgorithm::parse(name)?;
+        if !algorithms.contains(&algorithm) {
+            algorithms.push(algorithm);
+        }
+    }
+    (!algorithms.is_empty()).then_some(algorithms)
+}
+
+/// Whether the key file at `path` exists and is encrypted
+fn is_encrypted_file(path: &Path) -> bool {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|data| russh_keys::PrivateKey::from_openssh(&data).ok())
+        .is_some_and(|key| key.is_encrypted())
+}
+
+/// Read a passphrase from the terminal without echoing it
+fn prompt_passphrase(confirm: bool) -> anyhow::Result<String> {
+    let term = console::Term::stderr();
+    if !term.is_term() {
+        anyhow::bail!(
+            "server keys are encrypted and there is no terminal to ask for the passphrase; set {}",
+            SERVER_KEY_PASSPHRASE
         );
-        Ok(key)
+    }
+    term.write_str("Server key passphrase: ")?;
+    let passphrase = term.read_secure_line()?;
+    if confirm {
+        term.write_str("Repeat passphrase: ")?;
+        if term.read_secure_line()? != passphrase {
+            anyhow::bail!("passphrases don't match");
+        }
+    }
+    if passphrase.is_empty() {
+        anyhow::bail!("the server key passphrase can't be empty");
+    }
+    Ok(passphrase)
+}
+
+/// The configured passphrase, prompting when asked to or when an encrypted
+/// key is found without one. `creating` asks twice (a new key is written
+/// with it).
+fn server_key_passphrase(encrypted_found: bool, creating: bool) -> anyhow::Result<Option<String>> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase.clone());
+    }
+    let passphrase = resolve_passphrase(encrypted_found, creating)?;
+    let _ = PASSPHRASE.set(passphrase.clone());
+    Ok(passphrase)
+}
+
+fn resolve_passphrase(encrypted_found: bool, creating: bool) -> anyhow::Result<Option<String>> {
+    if let Some(passphrase) = std::env::var(SERVER_KEY_PASSPHRASE).ok().filter(|p| !p.is_empty()) {
+        return Ok(Some(passphrase));
+    }
+    let prompt = std::env::var(SERVER_KEY_PASSPHRASE_PROMPT)
+        .is_ok_and(|v| matches!(v.trim().to_lowercase().as_str(), "true" | "1" | "yes" | "on"));
+    if prompt || encrypted_found {
+        return prompt_passphrase(creating && !encrypted_found).map(Some);
+    }
+    Ok(None)
+}
+
+/// The key in OpenSSH format for writing to disk; encrypted with the
+/// passphrase, if there is one (never written in the clear then)
+fn encode_for_disk(key: &russh_keys::PrivateKey, passphrase: Option<&str>) -> anyhow::Result<String> {
+    let encoded = match passphrase {
+        Some(passphrase) => {
+            let encrypted = key.encrypt(&mut rand::thread_rng(), passphrase)?;
+            if !encrypted.is_encrypted() {
+                anyhow::bail!("refusing to write an unencrypted server key while a passphrase is configured");
+            }
+            encrypted.to_openssh(russh_keys::ssh_key::LineEnding::LF)?
+        }
+        None => key.to_openssh(russh_keys::ssh_key::LineEnding::LF)?,
+    };
+    Ok(encoded.to_string())
+}
+
+/// Load the key at `path`, or generate and save one of `algorithm`. With a
+/// passphrase the key on disk must be (and is written) encrypted.
+pub fn load_or_generate_key(
+    path: &Path,
+    algorithm: HostKeyAlgorithm,
+    passphrase: Option<&str>,
+) -> anyhow::Result<russh_keys::PrivateKey> {
+    let key = if path.exists() {
+        info!("Loading {} server key from {}...", algorithm.as_str(), path.display());
+        let key_data = fs::read_to_string(path)?;
+        let key = russh_keys::PrivateKey::from_openssh(&key_data)?;
+        let key = match (key.is_encrypted(), passphrase) {
+            (true, Some(passphrase)) => key
+                .decrypt(passphrase)
+                .map_err(|_| anyhow::anyhow!("wrong passphrase for {}", path.display()))?,
+            (true, None) => anyhow::bail!("{} is encrypted and no passphrase is configured", path.display()),
+            (false, Some(_)) => anyhow::bail!(
+                "{} is not encrypted but {} is configured; encrypt it (ssh-keygen -p -f {}) or remove it",
+                path.display(),
+                SERVER_KEY_PASSPHRASE,
+                path.display()
+            ),
+            (false, None) => key,
+        };
+        if HostKeyAlgorithm::of(&key) != Some(algorithm) {
+            anyhow::bail!(
+                "{} holds a {} key, expected {}",
+                path.display(),
+                key.algorithm(),
+                algorithm.as_str()
+            );
+        }
+        key
     } else {
-        info!("Generating new Ed25519 server key...");
-        let key = russh_keys::PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)?;
+        info!("Generating new {} server key...", algorithm.as_str());
+        let key = russh_keys::PrivateKey::random(&mut rand::thread_rng(), algorithm.algorithm())?;
 
-        if let Some(parent) = key_path.parent() {
+        if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        let key_data = key.to_openssh(russh_keys::ssh_key::LineEnding::LF)?;
-        fs::write(key_path, key_data.as_bytes())?;
-        info!("Server key saved to {}", key_path.display());
+        let key_data = encode_for_disk(&key, passphrase)?;
+        fs::write(path, key_data.as_bytes())?;
         info!(
-            "Server key fingerprint: {}",
-            key.public_key().fingerprint(HashAlg::Sha256)
+            "Server key saved to {}{}",
+            path.display(),
+            if passphrase.is_some() { " (encrypted)" } else { "" }
+        );
+        key
+    };
+    info!(
+        "Server key fingerprint ({}): {}",
+        algorithm.as_str(),
+        key.public_key().fingerprint(HashAlg::Sha256)
+    );
+    Ok(key)
+}
+
+/// The Ed25519 server key at `SERVER_KEY_PATH`
+pub fn load_or_generate_server_key() -> anyhow::Result<russh_keys::PrivateKey> {
+    let path = PathBuf::from(std::env::var(SERVER_KEY_PATH).unwrap_or_else(|_| DEFAULT_SERVER_KEY_PATH.to_string()));
+    let passphrase = server_key_passphrase(is_encrypted_file(&path), !path.exists())?;
+    load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, passphrase.as_deref())
+}
+
+/// Every configured host key, in order of preference
+pub fn load_or_generate_server_keys() -> anyhow::Result<Vec<russh_keys::PrivateKey>> {
+    Ok(load_or_generate_server_key_files()?.into_iter().map(|(_, key)| key).collect())
+}
+
+/// Every configured host key with the file it is kept in
+pub fn load_or_generate_server_key_files() -> anyhow::Result<Vec<(PathBuf, russh_keys::PrivateKey)>> {
+    let path = std::env::var(SERVER_KEY_PATH).unwrap_or_else(|_| DEFAULT_SERVER_KEY_PATH.to_string());
+    let list = std::env::var(SERVER_KEY_ALGORITHMS).unwrap_or_else(|_| DEFAULT_SERVER_KEY_ALGORITHMS.to_string());
+    let Some(algorithms) = parse_host_key_algorithms(&list) else {
+        anyhow::bail!(
+            "{} must list ed25519, ecdsa and/or rsa, got '{}'",
+            SERVER_KEY_ALGORITHMS,
+            list
         );
+    };
+    let keys: Vec<_> = algorithms
+        .into_iter()
+        .map(|algorithm| (algorithm.key_path(Path::new(&path)), algorithm))
+        .collect();
+    // One passphrase for all keys
+    let passphrase = server_key_passphrase(
+        keys.iter().any(|(path, _)| is_encrypted_file(path)),
+        keys.iter().any(|(path, _)| !path.exists()),
+    )?;
+    keys.into_iter()
+        .map(|(path, algorithm)| {
+            let key = load_or_generate_key(&path, algorithm, passphrase.as_deref())?;
+            Ok((path, key))
+        })
+        .collect()
+}
+
+/// Where the replacement for the key at `path` waits during a rotation
+pub fn next_key_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".next");
+    PathBuf::from(name)
+}
+
+/// Where the key at `path` is kept after it was replaced
+fn old_key_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".old");
+    PathBuf::from(name)
+}
+
+/// Generate a replacement for the key at `path` and save it at
+/// `<path>.next`, encrypted like the keys loaded at startup
+pub fn rotate(path: &Path, algorithm: HostKeyAlgorithm) -> anyhow::Result<russh_keys::PrivateKey> {
+    let passphrase = match PASSPHRASE.get() {
+        Some(passphrase) => passphrase.clone(),
+        None => std::env::var(SERVER_KEY_PASSPHRASE).ok().filter(|p| !p.is_empty()),
+    };
+    if passphrase.is_none() && is_encrypted_file(path) {
+        anyhow::bail!("{} is encrypted but no passphrase is known to encrypt its replacement", path.display());
+    }
+    let next = next_key_path(path);
+    if next.exists() {
+        anyhow::bail!("{} already exists; a rotation is in progress", next.display());
+    }
+    load_or_generate_key(&next, algorithm, passphrase.as_deref())
+}
+
+/// The rotated-in key for `path`, if a rotation is in progress
+pub fn load_rotated(path: &Path, algorithm: HostKeyAlgorithm) -> anyhow::Result<Option<russh_keys::PrivateKey>> {
+    let next = next_key_path(path);
+    if !next.exists() {
+        return Ok(None);
+    }
+    let passphrase = PASSPHRASE.get().cloned().flatten();
+    load_or_generate_key(&next, algorithm, passphrase.as_deref()).map(Some)
+}
+
+/// Replace the key at `path` with its rotated-in successor, keeping the old
+/// one as `<path>.old`
+pub fn promote(path: &Path) -> anyhow::Result<()> {
+    let next = next_key_path(path);
+    fs::rename(path, old_key_path(path)).with_context(|| format!("failed to retire {}", path.display()))?;
+    fs::rename(&next, path).with_context(|| format!("failed to move {} into place", next.display()))?;
+    info!("Rotated server key {} is now in use", path.display());
+    Ok(())
+}
+
+/// `tunnel rotate-key [--grace-secs N] [--url URL]`: ask the running server
+/// (its management API, default `http://127.0.0.1:$MGMT_PORT`) to rotate its
+/// host keys; returns the published fingerprints
+pub async fn run_rotate_command(args: &[String]) -> anyhow::Result<String> {
+    let mut grace_secs: Option<u64> = None;
+    let mut url = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--grace-secs" => {
+                let value = args.next().context("--grace-secs needs a value")?;
+                grace_secs = Some(value.parse().context("--grace-secs must be a number")?);
+            }
+            "--url" => url = Some(args.next().context("--url needs a value")?.clone()),
+            "--profile" => {
+                args.next();
+            }
+            other if other.starts_with("--profile=") => {}
+            other => anyhow::bail!("usage: tunnel rotate-key [--grace-secs N] [--url URL] (unexpected '{}')", other),
+        }
+    }
+    let url = url.unwrap_or_else(|| {
+        let port = std::env::var("MGMT_PORT").unwrap_or_else(|_| "9090".to_string());
+        format!("http://127.0.0.1:{}", port)
+    });
+    let secret = std::env::var(env::INTERNAL_API_SECRET)
+        .with_context(|| format!("{} is required to rotate keys", env::INTERNAL_API_SECRET))?;
+
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/host-keys/rotate", url.trim_end_matches('/')))
+        .header("X-Internal-Secret", secret);
+    if let Some(grace_secs) = grace_secs {
+        request = request.query(&[("grace_secs", grace_secs)]);
+    }
+    let response = request.send().await.with_context(|| format!("failed to reach {}", url))?;
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    if !status.is_success() {
+        anyhow::bail!("rotation refused ({}): {}", status, body["error"].as_str().unwrap_or("no details"));
+    }
+
+    let mut lines = Vec::new();
+    if let Some(at) = body["rotation"]["promote_at"].as_str() {
+        lines.push(format!("New host keys are used from {}", at));
+    }
+    for key in body["keys"].as_array().into_iter().flatten() {
+        lines.push(format!(
+            "{:<8} {:<20} {}",
+            key["status"].as_str().unwrap_or_default(),
+            key["algorithm"].as_str().unwrap_or_default(),
+            key["fingerprint"].as_str().unwrap_or_default()
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_key_algorithms() {
+        assert_eq!(
+            parse_host_key_algorithms("ed25519, RSA,ed25519"),
+            Some(vec![HostKeyAlgorithm::Ed25519, HostKeyAlgorithm::Rsa])
+        );
+        assert_eq!(parse_host_key_algorithms(""), None);
+        assert_eq!(parse_host_key_algorithms("dsa"), None);
+
+        let base = Path::new("/keys/server_key.pem");
+        assert_eq!(HostKeyAlgorithm::Ed25519.key_path(base), base);
+        assert_eq!(HostKeyAlgorithm::Rsa.key_path(base), Path::new("/keys/server_key_rsa.pem"));
+        assert_eq!(HostKeyAlgorithm::Ecdsa.key_path(Path::new("host")), Path::new("host_ecdsa"));
+    }
+
+    #[test]
+    fn test_generated_key_is_reloaded() {
+        let path = std::env::temp_dir().join(format!("exlo-host-key-{}.pem", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let generated = load_or_generate_key(&path, HostKeyAlgorithm::Ecdsa, None).unwrap();
+        let loaded = load_or_generate_key(&path, HostKeyAlgorithm::Ecdsa, None).unwrap();
+        assert_eq!(generated.public_key(), loaded.public_key());
+        assert!(load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, None).is_err());
+        // A plaintext key isn't used once a passphrase is configured
+        assert!(load_or_generate_key(&path, HostKeyAlgorithm::Ecdsa, Some("secret")).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rotate_and_promote() {
+        let path = std::env::temp_dir().join(format!("exlo-host-key-rot-{}.pem", std::process::id()));
+        let cleanup = || {
+            for file in [path.clone(), next_key_path(&path), old_key_path(&path)] {
+                let _ = fs::remove_file(file);
+            }
+        };
+        cleanup();
+        let current = load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, None).unwrap();
+        assert!(load_rotated(&path, HostKeyAlgorithm::Ed25519).unwrap().is_none());
+        let next = rotate(&path, HostKeyAlgorithm::Ed25519).unwrap();
+        assert_ne!(current.public_key(), next.public_key());
+        assert_eq!(
+            load_rotated(&path, HostKeyAlgorithm::Ed25519).unwrap().map(|key| key.public_key().clone()),
+            Some(next.public_key().clone())
+        );
+        // One rotation at a time
+        assert!(rotate(&path, HostKeyAlgorithm::Ed25519).is_err());
+
+        promote(&path).unwrap();
+        let loaded = load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, None).unwrap();
+        assert_eq!(loaded.public_key(), next.public_key());
+        assert!(!next_key_path(&path).exists());
+        assert!(old_key_path(&path).exists());
+        cleanup();
+    }
 
-        Ok(key)
+    #[test]
+    fn test_encrypted_key_at_rest() {
+        let path = std::env::temp_dir().join(format!("exlo-host-key-enc-{}.pem", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let generated = load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, Some("secret")).unwrap();
+        assert!(is_encrypted_file(&path));
+        let loaded = load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, Some("secret")).unwrap();
+        assert_eq!(generated.public_key(), loaded.public_key());
+        assert!(load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, Some("wrong")).is_err());
+        assert!(load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, None).is_err());
+        let _ = fs::remove_file(&path);
     }
 }
@@ -2,21 +2,42 @@
 //!
 //! Provides components for building a tunnel service.
 
+// The auth bypass must never ship in an optimized (release) binary
+#[cfg(all(feature = "dangerous-dev-auth", not(debug_assertions)))]
+compile_error!("the dangerous-dev-auth feature is only allowed in debug builds");
+
+pub mod accept;
+pub mod acl;
 pub mod config;
+pub mod crash;
 pub mod device;
 pub mod error;
+pub mod grant;
+pub mod grpc;
 pub mod key;
+pub mod logging;
+pub mod maintenance;
 pub mod management;
+pub mod profile;
 pub mod proxy;
+pub mod reload;
+pub mod reputation;
+pub mod service;
 pub mod ssh;
 pub mod state;
+pub mod systemd;
 pub mod terminal_ui;
 
-pub use config::{get, get_tunnel_url, init as init_config, Config};
-pub use device::{generate_activation_code, truncate_user_id, DeviceFlowClient, DeviceFlowConfig, VerifiedUser};
+pub use config::{get, get_tunnel_url, init as init_config, is_clustered, ClusterMode, Config};
+pub use device::{
+    generate_activation_code, truncate_user_id, AuthProvider, DeviceFlowClient, DeviceFlowConfig, VerifiedUser,
+};
 pub use error::TunnelError;
-pub use key::load_or_generate_server_key;
+pub use grpc::run_grpc_api;
+pub use key::{load_or_generate_server_key, load_or_generate_server_keys};
 pub use management::run_management_api;
-pub use proxy::run_http_proxy;
+pub use proxy::{run_http_proxy, run_tls_proxy};
+pub use service::{Storage, TunnlHandle, TunnlService, TunnlServiceBuilder};
 pub use ssh::{SshHandler, TunnelServer};
+pub use state::hooks::{TunnelHooks, VerificationEvent, VerificationMethod};
 pub use state::{AppState, TunnelInfo, VerifiedKey};
@@ -0,0 +1,59 @@
+//! Process logger whose filter can be replaced at runtime.
+//!
+//! Wraps an `env_logger` logger so a configuration reload can apply a new
+//! `RUST_LOG` filter without restarting the server.
+
+use std::sync::OnceLock;
+
+use arc_swap::ArcSwap;
+use log::{Log, Metadata, Record};
+
+use crate::config::DEFAULT_LOG_FILTER;
+
+static LOGGER: ReloadableLogger = ReloadableLogger { inner: OnceLock::new() };
+
+struct ReloadableLogger {
+    inner: OnceLock<ArcSwap<env_logger::Logger>>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.get().is_some_and(|logger| logger.load().enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = self.inner.get() {
+            logger.load().log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = self.inner.get() {
+            logger.load().flush();
+        }
+    }
+}
+
+fn build(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+/// Install the logger, filtered by `RUST_LOG` (default `info`)
+pub fn init() {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string());
+    let logger = build(&filter);
+    let max_level = logger.filter();
+    if LOGGER.inner.set(ArcSwap::from_pointee(logger)).is_ok() && log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Apply a new filter (no-op if `init()` was not called)
+pub fn set_filter(filter: &str) {
+    let Some(current) = LOGGER.inner.get() else {
+        return;
+    };
+    let logger = build(filter);
+    log::set_max_level(logger.filter());
+    current.store(logger.into());
+}
@@ -11,56 +11,70 @@
 //! # You will see an activation URL - visit it in your browser to authorize
 //! # After authorization, access via HTTP proxy
 //! curl -H "Host: tunnel-xxx.localhost" http://localhost:8080/
+//!
+//! # Start with a named profile from exlo.toml
+//! cargo run -- --profile dev
+//!
+//! # Mint a grant reserving "myapp" for user_123 (ssh ... grant-<grant>@server)
+//! cargo run -- grant myapp user_123 --days 30
+//!
+//! # Rotate the host keys of the running server, switching after a day
+//! cargo run -- rotate-key --grace-secs 86400
 //! ```
 
-use std::sync::Arc;
+use std::path::PathBuf;
 
 use log::info;
-use russh::server::Server;
 
-use tunnel::{
-    init_config, load_or_generate_server_key, run_http_proxy, run_management_api, AppState,
-    DeviceFlowClient, DeviceFlowConfig, TunnelServer,
-};
+use tunnel::crash::install_panic_hook;
+use tunnel::grant::run_grant_command;
+use tunnel::key::run_rotate_command;
+use tunnel::profile::apply_profile;
+use tunnel::service::shutdown_signal;
+use tunnel::{logging, reload};
+use tunnel::{init_config, DeviceFlowClient, DeviceFlowConfig, TunnlService};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Reloads keep variables from the real environment ahead of .env and the profile
+    reload::capture_process_env();
+
     // Load .env file (optional, won't fail if not found)
     dotenvy::dotenv().ok();
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // Profile values only fill in variables not set by the environment or .env
+    let profile = apply_profile(std::env::args().skip(1))?;
+
+    // `tunnel grant ...` prints a signed subdomain grant and exits
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("grant") {
+        println!("{}", run_grant_command(&args[1..])?);
+        return Ok(());
+    }
+    // `tunnel rotate-key ...` asks the running server to rotate its host keys
+    if args.first().map(String::as_str) == Some("rotate-key") {
+        println!("{}", run_rotate_command(&args[1..]).await?);
+        return Ok(());
+    }
+
+    // RUST_LOG can be changed by a configuration reload
+    logging::init();
 
     info!("🚀 Starting SSH Reverse Tunnel Server with Device Flow...");
+    if let Some(ref profile) = profile {
+        info!("✓ Using config profile '{}'", profile);
+    }
 
     // Initialize configuration (panics if required env vars are missing)
     init_config();
     info!("✓ Configuration loaded");
 
-    // Initialize shared state
-    let state = Arc::new(AppState::new());
-    info!("✓ Application state initialized");
+    // Panics become structured crash reports
+    install_panic_hook(tunnel::get().crash_report_dir.clone().map(PathBuf::from));
 
     // Initialize Device Flow client
     let device_flow_config = DeviceFlowConfig::default();
     info!("✓ Device Flow API: {}", device_flow_config.api_base_url);
-    let device_flow_client = Arc::new(DeviceFlowClient::new(device_flow_config));
-
-    // Load or generate SSH server key
-    let key = load_or_generate_server_key()?;
-
-    // Configure SSH server
-    let config = russh::server::Config {
-        methods: russh::MethodSet::PUBLICKEY,
-        server_id: russh::SshId::Standard(format!("SSH-2.0-EXLO_{}", env!("CARGO_PKG_VERSION"))),
-        keys: vec![key],
-        inactivity_timeout: Some(std::time::Duration::from_secs(1800)),
-        auth_rejection_time: std::time::Duration::from_secs(3),
-        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        ..Default::default()
-    };
-
-    let config = Arc::new(config);
-    let mut server = TunnelServer::new(state.clone(), device_flow_client);
 
     let ssh_port = std::env::var("SSH_PORT").unwrap_or_else(|_| "2222".to_string());
     let ssh_addr = format!("0.0.0.0:{}", ssh_port);
@@ -68,11 +82,21 @@ async fn main() -> anyhow::Result<()> {
     let http_addr = format!("0.0.0.0:{}", http_port);
     let mgmt_port = std::env::var("MGMT_PORT").unwrap_or_else(|_| "9090".to_string());
     let mgmt_addr = format!("0.0.0.0:{}", mgmt_port);
+    // TLS passthrough is only started when a port is configured
+    let tls_addr = std::env::var("TLS_PORT").ok().map(|port| format!("0.0.0.0:{}", port));
+    // Likewise the gRPC management API
+    let grpc_addr = std::env::var("GRPC_PORT").ok().map(|port| format!("0.0.0.0:{}", port));
 
     info!("═══════════════════════════════════════════════════════════════");
     info!("SSH server:     {}", ssh_addr);
     info!("HTTP proxy:     {}", http_addr);
+    if let Some(ref tls_addr) = tls_addr {
+        info!("TLS passthrough: {}", tls_addr);
+    }
     info!("Inner Management API: {}", mgmt_addr);
+    if let Some(ref grpc_addr) = grpc_addr {
+        info!("gRPC Management API: {}", grpc_addr);
+    }
     info!("═══════════════════════════════════════════════════════════════");
     info!("To create a tunnel:");
     info!("  ssh -N -R 3000:localhost:3000 -p {} user@yourserver.com", ssh_port);
@@ -80,32 +104,16 @@ async fn main() -> anyhow::Result<()> {
     info!("You will see an activation URL - visit it to authorize.");
     info!("═══════════════════════════════════════════════════════════════");
 
-    let http_state = state.clone();
-    let mgmt_state = state.clone();
-    let cleanup_state = state.clone();
-
-    // Spawn a background task to periodically clean up expired tunnels and keys
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            cleanup_state.cleanup_expired_tunnels().await;
-            cleanup_state.cleanup_expired_keys().await;
-            cleanup_state.cleanup_rate_limits().await;
-        }
-    });
-
-    tokio::select! {
-        result = server.run_on_address(config, ssh_addr) => {
-            result?;
-        }
-        result = run_http_proxy(http_state, &http_addr) => {
-            result?;
-        }
-        result = run_management_api(mgmt_state, &mgmt_addr) => {
-            result?;
-        }
-    }
+    // Load or generate the SSH server key and wire up the listeners
+    let service = TunnlService::builder()
+        .ssh_addr(ssh_addr)
+        .proxy_addr(http_addr)
+        .management_addr(Some(mgmt_addr))
+        .tls_addr(tls_addr)
+        .grpc_addr(grpc_addr)
+        .auth(DeviceFlowClient::new(device_flow_config))
+        .build()?;
+    info!("✓ Application state initialized");
 
-    Ok(())
+    service.run(shutdown_signal()).await
 }
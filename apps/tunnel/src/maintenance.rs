@@ -0,0 +1,268 @@
+//! Supervised periodic maintenance.
+//!
+//! Each cleanup runs in its own loop with its own interval plus random jitter,
+//! so a slow or panicking task can't stall the others. Every pass runs in a
+//! separate tokio task; a panic is logged and counted, and the loop carries on.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, error, info};
+use rand::Rng;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::config::{get as get_config, reloadable};
+use crate::crash::{spawn_with_context, CrashContext};
+use crate::ssh::{check_local_services, ping_sessions, reap_idle_tunnels};
+use crate::state::audit::{AuditEvent, SYSTEM_ACTOR};
+use crate::state::AppState;
+
+type TaskFn = Arc<dyn Fn(Arc<AppState>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// A periodic maintenance job
+#[derive(Clone)]
+pub struct MaintenanceTask {
+    pub name: &'static str,
+    pub interval: Duration,
+    /// Upper bound of the random delay added to each interval
+    pub jitter: Duration,
+    run: TaskFn,
+}
+
+impl MaintenanceTask {
+    pub fn new<F, Fut>(name: &'static str, interval: Duration, run: F) -> Self
+    where
+        F: Fn(Arc<AppState>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            name,
+            interval,
+            jitter: interval / 10,
+            run: Arc::new(move |state| Box::pin(run(state))),
+        }
+    }
+
+    /// Interval for the next pass, including jitter
+    fn next_delay(&self) -> Duration {
+        let jitter_ms = self.jitter.as_millis() as u64;
+        let extra = if jitter_ms > 0 {
+            rand::thread_rng().gen_range(0..=jitter_ms)
+        } else {
+            0
+        };
+        self.interval + Duration::from_millis(extra)
+    }
+}
+
+/// Counters for one maintenance task
+#[derive(Debug, Clone, Default)]
+pub struct TaskStats {
+    pub runs: u64,
+    pub panics: u64,
+    pub last_run: Option<SystemTime>,
+    pub last_duration: Duration,
+}
+
+/// Per-task pass statistics, exposed on the management API
+#[derive(Debug, Default)]
+pub struct MaintenanceStats {
+    tasks: RwLock<HashMap<&'static str, TaskStats>>,
+}
+
+impl MaintenanceStats {
+    async fn record(&self, name: &'static str, duration: Duration, panicked: bool) {
+        let mut tasks = self.tasks.write().await;
+        let stats = tasks.entry(name).or_default();
+        stats.runs += 1;
+        if panicked {
+            stats.panics += 1;
+        }
+        stats.last_run = Some(SystemTime::now());
+        stats.last_duration = duration;
+    }
+
+    /// Snapshot of all task statistics, sorted by task name
+    pub async fn snapshot(&self) -> Vec<(&'static str, TaskStats)> {
+        let tasks = self.tasks.read().await;
+        let mut list: Vec<_> = tasks.iter().map(|(name, stats)| (*name, stats.clone())).collect();
+        list.sort_by_key(|(name, _)| *name);
+        list
+    }
+}
+
+/// Run a single pass in its own task so a panic stays contained
+async fn run_pass(task: &MaintenanceTask, state: &Arc<AppState>) {
+    let started = Instant::now();
+    let result = spawn_with_context(CrashContext::new("maintenance"), (task.run)(state.clone())).await;
+    let elapsed = started.elapsed();
+
+    let panicked = match result {
+        Ok(()) => {
+            debug!("Maintenance task '{}' finished in {:?}", task.name, elapsed);
+            false
+        }
+        Err(e) => {
+            error!("Maintenance task '{}' failed: {}", task.name, e);
+            true
+        }
+    };
+    state.maintenance.record(task.name, elapsed, panicked).await;
+}
+
+/// Run a task forever on its own interval
+async fn supervise(task: MaintenanceTask, state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(task.next_delay()).await;
+        run_pass(&task, &state).await;
+    }
+}
+
+/// The server's standard maintenance tasks
+pub fn default_tasks() -> Vec<MaintenanceTask> {
+    let mut tasks = vec![
+        MaintenanceTask::new("expired_tunnels", Duration::from_secs(10), |state| async move {
+            state.cleanup_expired_tunnels().await
+        }),
+        MaintenanceTask::new("expired_keys", Duration::from_secs(60), |state| async move {
+            state.renew_verified_keys().await;
+            state.cleanup_expired_keys().await
+        }),
+        MaintenanceTask::new("rate_limits", Duration::from_secs(30), |state| async move {
+            state.cleanup_rate_limits().await
+        }),
+        MaintenanceTask::new("bans", Duration::from_secs(60), |state| async move {
+            state.bans.cleanup().await
+        }),
+        MaintenanceTask::new("expired_claims", Duration::from_secs(60), |state| async move {
+            state.claims.cleanup().await
+        }),
+        MaintenanceTask::new("subdomain_pool", Duration::from_secs(2), |state| async move {
+            state.subdomain_pool.refill(&state).await
+        }),
+        MaintenanceTask::new("channel_pool", Duration::from_secs(10), |state| async move {
+            let sessions: Vec<String> = state
+                .list_tunnels()
+                .await
+                .into_iter()
+                .filter(|t| t.is_connected)
+                .map(|t| t.session_id.clone())
+                .collect();
+            state.channel_pool.prune(&sessions).await
+        }),
+        MaintenanceTask::new("channel_slots", Duration::from_secs(60), |state| async move {
+            let subdomains: Vec<String> = state.tunnels.iter().map(|t| t.key().clone()).collect();
+            state.channel_slots.prune(&subdomains)
+        }),
+        MaintenanceTask::new("bandwidth", Duration::from_secs(60), |state| async move {
+            let (subdomains, users): (Vec<String>, Vec<String>) = state
+                .tunnels
+                .iter()
+                .map(|t| (t.subdomain.clone(), t.username.clone()))
+                .unzip();
+            state.bandwidth.prune(&subdomains, &users)
+        }),
+        MaintenanceTask::new("response_cache", Duration::from_secs(60), |state| async move {
+            let caching: Vec<(String, String)> = state
+                .tunnels
+                .iter()
+                .filter(|t| t.response_cache)
+                .map(|t| (t.subdomain.clone(), t.correlation_id.clone()))
+                .collect();
+            state.response_cache.prune(&caching).await
+        }),
+        MaintenanceTask::new("har", Duration::from_secs(60), |state| async move {
+            let subdomains: Vec<String> = state.tunnels.iter().map(|t| t.key().clone()).collect();
+            state.har.prune(&subdomains)
+        }),
+        MaintenanceTask::new("health_check_failures", Duration::from_secs(60), |state| async move {
+            let subdomains: Vec<String> = state.tunnels.iter().map(|t| t.key().clone()).collect();
+            state.health_checks.prune(&subdomains)
+        }),
+        MaintenanceTask::new("latency_stats", Duration::from_secs(60), |state| async move {
+            let subdomains: Vec<String> = state.tunnels.iter().map(|t| t.key().clone()).collect();
+            state.latency.prune(&subdomains)
+        }),
+        MaintenanceTask::new("resume_tokens", Duration::from_secs(60), |state| async move {
+            state.resume_tokens.prune()
+        }),
+    ];
+
+    if let Some(interval) = get_config().ssh_keepalive_interval {
+        tasks.push(MaintenanceTask::new("session_pings", interval, |state| async move {
+            ping_sessions(&state).await
+        }));
+    }
+
+    if let Some(interval) = get_config().health_check_interval {
+        tasks.push(MaintenanceTask::new("health_checks", interval, |state| async move {
+            check_local_services(&state).await
+        }));
+    }
+
+    // Switch to rotated host keys once their grace period is over
+    tasks.push(MaintenanceTask::new("host_key_rotation", Duration::from_secs(60), |state| async move {
+        if state.host_keys.finish_due_rotation(chrono::Utc::now()) {
+            state.audit.record(AuditEvent::new(SYSTEM_ACTOR, "host_key.promoted"));
+        }
+    }));
+
+    // IDLE_TUNNEL_TIMEOUT can be changed by a reload, so it is read on every pass
+    tasks.push(MaintenanceTask::new("idle_tunnels", Duration::from_secs(10), |state| async move {
+        if let Some(timeout) = reloadable().idle_tunnel_timeout {
+            reap_idle_tunnels(&state, timeout).await
+        }
+    }));
+
+    tasks
+}
+
+/// Spawn a supervised loop for each task
+pub fn spawn_maintenance(state: Arc<AppState>, tasks: Vec<MaintenanceTask>) -> Vec<JoinHandle<()>> {
+    tasks
+        .into_iter()
+        .map(|task| {
+            info!(
+                "Maintenance task '{}' every {:?} (+ up to {:?} jitter)",
+                task.name, task.interval, task.jitter
+            );
+            tokio::spawn(supervise(task, state.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_panicking_pass_is_recorded() {
+        let state = Arc::new(AppState::new());
+        let task = MaintenanceTask::new("boom", Duration::from_secs(1), |_| async {
+            panic!("cleanup exploded");
+        });
+
+        run_pass(&task, &state).await;
+        run_pass(&task, &state).await;
+
+        let stats = state.maintenance.snapshot().await;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].0, "boom");
+        assert_eq!(stats[0].1.runs, 2);
+        assert_eq!(stats[0].1.panics, 2);
+    }
+
+    #[test]
+    fn test_next_delay_within_jitter() {
+        let task = MaintenanceTask::new("noop", Duration::from_secs(10), |_| async {});
+        for _ in 0..20 {
+            let delay = task.next_delay();
+            assert!(delay >= Duration::from_secs(10));
+            assert!(delay <= Duration::from_secs(11));
+        }
+    }
+}
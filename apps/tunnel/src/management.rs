@@ -2,20 +2,57 @@
 //!
 //! Provides HTTP endpoints for listing and managing active tunnels.
 
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{delete, get},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use chrono::{DateTime, Utc};
-use log::{error, info};
-use serde::Serialize;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::state::AppState;
+use crate::accept::bind_listener;
+use crate::acl::Capability;
+use crate::config::{get as get_config, is_loaded as config_loaded, ReconcileMode};
+use crate::crash::{panic_count, recent_reports, CrashReport};
+use crate::device::ActivationCallback;
+use crate::error::TunnelError;
+use crate::proxy::oauth::is_configured as oauth_configured;
+use crate::proxy::share_secret::secrets_match;
+use crate::reload::reload;
+use crate::ssh::is_valid_subdomain;
+use crate::state::audit::{AuditEvent, AuditQuery};
+use crate::state::bandwidth::BandwidthUsage;
+use crate::state::bans::Ban;
+use crate::state::claims::SubdomainClaim;
+use crate::state::cluster::{local_report, ClusterTunnelsResponse};
+use crate::state::dedicated_ports::{PortInfo, Transport};
+use crate::state::domains::{normalize_host, validate_custom_domain, CustomDomain};
+use crate::state::events::{EventScope, Replay, TunnelEvent};
+use crate::state::har::Har;
+use crate::state::header_rules::HeaderRules;
+use crate::state::host_keys::{HostKeyInfo, RotationInfo};
+use crate::state::oauth::OAuthPolicy;
+use crate::state::perf_profiles::PerfProfile;
+use crate::state::webhooks::{validate_rules, WebhookRule};
+use crate::state::response_cache::CacheStats;
+use crate::state::stats::LatencyReport;
+use crate::state::status_alerts::StatusCounts;
+use crate::state::tunnel_limits::TunnelRateLimit;
+use crate::state::{AppState, TunnelInfo, DEVICE_FLOW_IP_BUDGET, DEVICE_FLOW_KEY_BUDGET};
 
 /// JSON response for a single tunnel.
 #[derive(Debug, Serialize)]
@@ -26,6 +63,29 @@ pub struct TunnelResponse {
     pub connected_at: String,
     /// Whether the SSH connection is still active (not closed)
     pub is_connected: bool,
+    /// Cluster node holding the SSH session
+    pub node_id: String,
+    /// Registered before the local service answered; no successful request yet
+    pub awaiting_local_service: bool,
+    /// RFC 3339 time the local service started failing health checks
+    /// (None while it answers)
+    pub degraded_since: Option<String>,
+    /// Joins the web backend record with the proxy's access logs
+    pub correlation_id: String,
+    /// HTML responses carry the preview banner
+    pub preview_banner: bool,
+    /// Cacheable requests are answered from the response cache
+    pub response_cache: bool,
+    /// Responses of the tunneled service per status class
+    pub statuses: StatusCounts,
+    /// Closed proxied connections per close reason
+    pub close_reasons: BTreeMap<String, u64>,
+    /// Performance profile (None = global settings)
+    pub perf_profile: Option<PerfProfile>,
+    /// Headers added to and removed from responses
+    pub response_headers: HeaderRules,
+    /// Bandwidth limit and current throughput
+    pub bandwidth: BandwidthUsage,
 }
 
 /// JSON response for list of tunnels.
@@ -41,10 +101,436 @@ pub struct SuccessResponse {
     pub message: String,
 }
 
+/// JSON response for disconnecting several tunnels at once.
+#[derive(Debug, Serialize)]
+pub struct KickedTunnelsResponse {
+    pub success: bool,
+    pub message: String,
+    /// Subdomains that were removed, sorted
+    pub kicked: Vec<String>,
+}
+
+/// JSON request body for setting the message of the day.
+#[derive(Debug, Deserialize)]
+pub struct SetMotdRequest {
+    pub message: String,
+}
+
+/// JSON response for the current message of the day.
+#[derive(Debug, Serialize)]
+pub struct MotdResponse {
+    pub message: Option<String>,
+    pub set_at: Option<String>,
+    /// Number of users who have already been shown the message
+    pub seen_by: usize,
+}
+
+/// Request body for turning maintenance mode on.
+#[derive(Debug, Default, Deserialize)]
+pub struct SetMaintenanceModeRequest {
+    /// Shown to refused sessions (default notice if omitted)
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// JSON response for the maintenance mode switch.
+#[derive(Debug, Serialize)]
+pub struct MaintenanceModeResponse {
+    pub enabled: bool,
+    pub message: Option<String>,
+    pub since: Option<String>,
+}
+
+/// JSON response for a configuration reload.
+#[derive(Debug, Serialize)]
+pub struct ConfigReloadResponse {
+    /// Environment variables whose values changed
+    pub changed: Vec<String>,
+}
+
+/// JSON response for the liveness probe.
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+}
+
+/// Individual readiness checks.
+#[derive(Debug, Serialize)]
+pub struct ReadinessChecks {
+    pub config_loaded: bool,
+    pub ssh_listener: bool,
+    pub http_listener: bool,
+    /// Whether the Device Flow web API answered (any HTTP status counts)
+    pub web_api: bool,
+}
+
+/// JSON response for the readiness probe.
+#[derive(Debug, Serialize)]
+pub struct ReadinessResponse {
+    /// "ready", "degraded" (web API unreachable) or "not_ready"
+    pub status: &'static str,
+    pub checks: ReadinessChecks,
+}
+
+/// JSON response for one maintenance task.
+#[derive(Debug, Serialize)]
+pub struct MaintenanceTaskResponse {
+    pub name: String,
+    pub runs: u64,
+    pub panics: u64,
+    pub last_run: Option<String>,
+    pub last_duration_ms: u64,
+}
+
+/// JSON response for the maintenance task list.
+#[derive(Debug, Serialize)]
+pub struct MaintenanceResponse {
+    pub tasks: Vec<MaintenanceTaskResponse>,
+}
+
+/// Query parameters of the audit log.
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    /// RFC 3339 time; only events at or after it
+    pub since: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    /// Most recent events returned (default 100, at most 1000)
+    pub limit: Option<usize>,
+}
+
+/// Response for the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditLogResponse {
+    pub events: Vec<AuditEvent>,
+}
+
+/// Response for the host keys.
+#[derive(Debug, Serialize)]
+pub struct HostKeysResponse {
+    /// Active keys, then the next ones during a rotation, then retired ones
+    pub keys: Vec<HostKeyInfo>,
+    pub rotation: Option<RotationInfo>,
+}
+
+/// Query parameters of a host key rotation.
+#[derive(Debug, Deserialize)]
+pub struct RotateHostKeysQuery {
+    /// How long the current keys stay in use (default `HOST_KEY_ROTATION_GRACE`)
+    pub grace_secs: Option<u64>,
+}
+
+/// Query parameters of the event stream.
+#[derive(Debug, Deserialize)]
+pub struct EventStreamQuery {
+    /// Admin secret or user token (browsers can't set WebSocket headers)
+    pub token: Option<String>,
+    /// Token of the last event seen, to replay what was missed
+    pub resume: Option<String>,
+}
+
+/// Message sent on the event stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventMessage {
+    /// A tunnel lifecycle event; `token` resumes after it
+    Event {
+        event: &'static str,
+        subdomain: String,
+        previous_subdomain: Option<String>,
+        user_id: String,
+        at: String,
+        token: String,
+    },
+    /// Missed events can't be replayed; reload the tunnel list
+    ResyncRequired { token: String },
+    /// Replay finished; live events follow
+    Ready { token: String },
+}
+
+impl EventMessage {
+    pub(crate) fn event(event: &TunnelEvent, token: String) -> Self {
+        Self::Event {
+            event: event.kind.as_str(),
+            subdomain: event.subdomain.clone(),
+            previous_subdomain: event.previous_subdomain.clone(),
+            user_id: event.user_id.clone(),
+            at: DateTime::<Utc>::from(event.at).to_rfc3339(),
+            token,
+        }
+    }
+}
+
+/// JSON request body for attaching a custom domain.
+#[derive(Debug, Deserialize)]
+pub struct AttachDomainRequest {
+    pub subdomain: String,
+    /// User the web app verified as the domain's owner; must own the tunnel
+    pub user_id: String,
+}
+
+/// JSON response for a custom domain.
+#[derive(Debug, Serialize)]
+pub struct CustomDomainResponse {
+    pub domain: String,
+    pub subdomain: String,
+    pub user_id: String,
+    pub created_at: String,
+}
+
+impl From<CustomDomain> for CustomDomainResponse {
+    fn from(d: CustomDomain) -> Self {
+        Self {
+            domain: d.domain,
+            subdomain: d.subdomain,
+            user_id: d.user_id,
+            created_at: DateTime::<Utc>::from(d.created_at).to_rfc3339(),
+        }
+    }
+}
+
+/// JSON response for the custom domain list.
+#[derive(Debug, Serialize)]
+pub struct CustomDomainsResponse {
+    pub domains: Vec<CustomDomainResponse>,
+}
+
+/// JSON request body for claiming a subdomain.
+#[derive(Debug, Deserialize)]
+pub struct ClaimSubdomainRequest {
+    /// Verified user the subdomain is reserved for
+    pub user_id: String,
+    /// Claim period in seconds (omitted = until released)
+    pub ttl_secs: Option<u64>,
+}
+
+/// JSON response for a subdomain claim.
+#[derive(Debug, Serialize)]
+pub struct SubdomainClaimResponse {
+    pub subdomain: String,
+    pub user_id: String,
+    pub claimed_at: String,
+    pub expires_at: Option<String>,
+}
+
+impl From<SubdomainClaim> for SubdomainClaimResponse {
+    fn from(c: SubdomainClaim) -> Self {
+        Self {
+            subdomain: c.subdomain,
+            user_id: c.user_id,
+            claimed_at: c.claimed_at.to_rfc3339(),
+            expires_at: c.expires_at.map(|t| t.to_rfc3339()),
+        }
+    }
+}
+
+/// JSON response for the subdomain claim list.
+#[derive(Debug, Serialize)]
+pub struct SubdomainClaimsResponse {
+    pub claims: Vec<SubdomainClaimResponse>,
+}
+
+/// JSON response for a tunnel's proxy limits.
+#[derive(Debug, Serialize)]
+pub struct RateLimitResponse {
+    pub subdomain: String,
+    #[serde(flatten)]
+    pub limit: TunnelRateLimit,
+    /// Set through the API (false = configured defaults)
+    pub custom: bool,
+}
+
+/// Body of PUT /tunnels/:subdomain/bandwidth.
+#[derive(Debug, Deserialize)]
+pub struct BandwidthLimitRequest {
+    /// Bytes per second in each direction (0 = unlimited)
+    pub bytes_per_second: u64,
+}
+
+/// JSON response for a tunnel's bandwidth limit and throughput.
+#[derive(Debug, Serialize)]
+pub struct BandwidthResponse {
+    pub subdomain: String,
+    /// Set through the API (false = configured default)
+    pub custom: bool,
+    #[serde(flatten)]
+    pub usage: BandwidthUsage,
+}
+
+/// JSON response for a tunnel's OAuth protection.
+#[derive(Debug, Serialize)]
+pub struct OAuthPolicyResponse {
+    pub subdomain: String,
+    /// None = visitors don't need to sign in
+    pub policy: Option<OAuthPolicy>,
+}
+
+/// JSON request body for banning an IP.
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    pub ip: IpAddr,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Ban length in seconds (omit for a ban until removed)
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+/// JSON response for a ban.
+#[derive(Debug, Serialize)]
+pub struct BanResponse {
+    pub ip: String,
+    pub reason: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub automatic: bool,
+}
+
+impl From<Ban> for BanResponse {
+    fn from(b: Ban) -> Self {
+        Self {
+            ip: b.ip.to_string(),
+            reason: b.reason,
+            created_at: DateTime::<Utc>::from(b.created_at).to_rfc3339(),
+            expires_at: b.expires_at.map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+            automatic: b.automatic,
+        }
+    }
+}
+
+/// JSON response for a cached key verification.
+#[derive(Debug, Serialize)]
+pub struct VerifiedKeyResponse {
+    pub fingerprint: String,
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub tier: Option<String>,
+    /// Last verification, or renewal by a reconnect or connected tunnel
+    pub verified_at: String,
+    /// After this the key goes through the Device Flow again
+    pub expires_at: String,
+    /// Activity doesn't renew the verification past this (None = no limit)
+    pub renewable_until: Option<String>,
+    /// Subdomain kept for reconnects, by client port
+    pub subdomains: BTreeMap<u32, String>,
+}
+
+/// JSON response for the verified key list.
+#[derive(Debug, Serialize)]
+pub struct VerifiedKeysResponse {
+    pub keys: Vec<VerifiedKeyResponse>,
+}
+
+/// Device Flow rate limit state of a key behind an IP.
+#[derive(Debug, Serialize)]
+pub struct KeyDeviceFlowLimitResponse {
+    /// None for sessions without a public key
+    pub fingerprint: Option<String>,
+    /// Requests in the current window
+    pub attempts: u32,
+    pub last_request: String,
+    /// Whether the key's next request would be refused
+    pub limited: bool,
+}
+
+/// JSON response for the Device Flow rate limit state of an IP.
+#[derive(Debug, Serialize)]
+pub struct DeviceFlowLimitResponse {
+    pub ip: IpAddr,
+    /// Requests in the current window, across keys
+    pub attempts: u32,
+    pub window_start: String,
+    pub last_request: String,
+    /// Whether the IP's shared budget is used up
+    pub limited: bool,
+    pub keys: Vec<KeyDeviceFlowLimitResponse>,
+}
+
+/// JSON response for the ban list.
+#[derive(Debug, Serialize)]
+pub struct BansResponse {
+    pub bans: Vec<BanResponse>,
+}
+
+/// JSON response for the dedicated TCP or UDP ports.
+#[derive(Debug, Serialize)]
+pub struct PortsResponse {
+    pub ports: Vec<PortInfo>,
+}
+
+/// JSON response for the cleanup task backlog.
+#[derive(Debug, Serialize)]
+pub struct CleanupResponse {
+    /// Cleanup tasks allowed to run at once
+    pub limit: usize,
+    pub queued: usize,
+    pub running: usize,
+    pub completed: u64,
+    pub peak: usize,
+    /// Queued + running tasks by kind
+    pub by_kind: std::collections::HashMap<&'static str, usize>,
+}
+
+/// JSON response for backend registration reconciliation.
+#[derive(Debug, Serialize)]
+pub struct ReconcileResponse {
+    /// "off", "dry-run" or "on"
+    pub mode: &'static str,
+    pub runs: u64,
+    pub failed_runs: u64,
+    pub orphans_found: u64,
+    pub unregistered: u64,
+    pub unregister_failures: u64,
+    pub last_run: Option<String>,
+    pub last_orphans: Vec<String>,
+}
+
+/// JSON response for captured panics.
+#[derive(Debug, Serialize)]
+pub struct CrashesResponse {
+    /// Panics since startup
+    pub panics: u64,
+    /// Most recent reports, oldest first
+    pub recent: Vec<CrashReport>,
+}
+
+/// JSON response for a tunnel's webhook signature rules (secrets left out).
+#[derive(Debug, Serialize)]
+pub struct WebhookRulesResponse {
+    pub subdomain: String,
+    pub rules: Vec<WebhookRule>,
+}
+
+/// JSON request body for toggling the preview banner, the response cache or
+/// HAR capture.
+#[derive(Debug, Deserialize)]
+pub struct BannerRequest {
+    pub enabled: bool,
+}
+
+/// JSON response for a tunnel's response cache.
+#[derive(Debug, Serialize)]
+pub struct CacheResponse {
+    pub subdomain: String,
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub stats: CacheStats,
+}
+
+/// JSON response for a tunnel's latency percentiles.
+#[derive(Debug, Serialize)]
+pub struct LatencyResponse {
+    pub subdomain: String,
+    #[serde(flatten)]
+    pub latency: LatencyReport,
+}
+
 /// JSON response for errors.
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
     pub error: String,
+    /// Stable code of tunnel errors (see `TunnelError::code`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
 }
 
 /// GET /tunnels - List all active tunnels
@@ -56,10 +542,13 @@ async fn list_tunnels(
     let tunnel_responses: Vec<TunnelResponse> = tunnels
         .into_iter()
         .map(|t| {
+            let t = Arc::unwrap_or_clone(t);
+            let traffic = t.traffic.snapshot();
             // Convert SystemTime to DateTime<Utc>
             let connected_at: DateTime<Utc> = t.created_at.into();
 
             TunnelResponse {
+                bandwidth: state.bandwidth.usage(&t.subdomain),
                 subdomain: t.subdomain,
                 user_id: if t.username.is_empty() || t.username == "anonymous" {
                     None
@@ -69,6 +558,16 @@ async fn list_tunnels(
                 client_ip: t.client_ip,
                 connected_at: connected_at.to_rfc3339(),
                 is_connected: t.is_connected,
+                node_id: t.node_id,
+                awaiting_local_service: t.awaiting_local_service,
+                degraded_since: t.degraded_since.map(|since| DateTime::<Utc>::from(since).to_rfc3339()),
+                correlation_id: t.correlation_id,
+                preview_banner: t.preview_banner,
+                response_cache: t.response_cache,
+                statuses: traffic.statuses,
+                close_reasons: traffic.close_reasons,
+                perf_profile: t.perf_profile,
+                response_headers: t.response_headers,
             }
         })
         .collect();
@@ -76,6 +575,31 @@ async fn list_tunnels(
     Json(TunnelsListResponse { tunnels: tunnel_responses })
 }
 
+/// Remove a tunnel and disconnect its SSH session (REST and gRPC)
+pub(crate) async fn kick(state: &AppState, subdomain: &str) -> Result<(), TunnelError> {
+    // Any future requests to this tunnel will fail with "tunnel not found"
+    let removed = state.remove_tunnel(subdomain).await?;
+    // The kicked session can't come back with its resume token
+    state.resume_tokens.revoke_session(&removed.session_id);
+    state
+        .audit
+        .record(AuditEvent::new("admin", "tunnel.kicked").target(subdomain).detail(&removed.username));
+    let handle = removed.handle.clone();
+
+    // Spawn a task to disconnect the session without blocking
+    state.cleanup.spawn("kick", async move {
+        // disconnect() gracefully closes the SSH connection
+        if let Err(e) = handle.disconnect(
+            russh::Disconnect::ByApplication,
+            "Tunnel terminated by administrator".to_string(),
+            "en".to_string(),
+        ).await {
+            log::debug!("Disconnect result: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
 /// DELETE /tunnels/:subdomain - Force disconnect a tunnel
 async fn kick_tunnel(
     State(state): State<Arc<AppState>>,
@@ -83,25 +607,8 @@ async fn kick_tunnel(
 ) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
     info!("Management API: Kick request for tunnel '{}'", subdomain);
 
-    match state.remove_tunnel(&subdomain).await {
-        Ok(tunnel_info) => {
-            // Send disconnect to the SSH session
-            // This will cause the SSH session handle to be dropped when not used
-            // Any future requests to this tunnel will fail with "tunnel not found"
-            let handle = tunnel_info.handle;
-
-            // Spawn a task to disconnect the session without blocking
-            tokio::spawn(async move {
-                // disconnect() gracefully closes the SSH connection
-                if let Err(e) = handle.disconnect(
-                    russh::Disconnect::ByApplication,
-                    "Tunnel terminated by administrator".to_string(),
-                    "en".to_string(),
-                ).await {
-                    log::debug!("Disconnect result: {:?}", e);
-                }
-            });
-
+    match kick(&state, &subdomain).await {
+        Ok(()) => {
             info!("Management API: Tunnel '{}' kicked successfully", subdomain);
             Ok(Json(SuccessResponse {
                 success: true,
@@ -110,13 +617,1130 @@ async fn kick_tunnel(
         }
         Err(e) => {
             error!("Management API: Failed to kick tunnel '{}': {}", subdomain, e);
-            Err((
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: format!("Tunnel not found: {}", subdomain),
-                }),
+            Err(tunnel_error(e))
+        }
+    }
+}
+
+/// Kick every tunnel `matches` selects; returns the subdomains removed
+async fn kick_matching(state: &AppState, matches: impl Fn(&TunnelInfo) -> bool) -> Vec<String> {
+    let mut kicked = Vec::new();
+    for tunnel in state.list_tunnels().await.iter().filter(|tunnel| matches(tunnel)) {
+        // A tunnel removed meanwhile is already gone
+        if kick(state, &tunnel.subdomain).await.is_ok() {
+            kicked.push(tunnel.subdomain.clone());
+        }
+    }
+    kicked.sort();
+    kicked
+}
+
+/// DELETE /users/:user_id/tunnels - Force disconnect every tunnel of a user
+async fn kick_user_tunnels(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> Json<KickedTunnelsResponse> {
+    info!("Management API: Kick request for all tunnels of user '{}'", user_id);
+    state.resume_tokens.revoke_user(&user_id);
+    let kicked = kick_matching(&state, |tunnel| tunnel.username == user_id).await;
+    info!("Management API: Kicked {} tunnel(s) of user '{}'", kicked.len(), user_id);
+    Json(KickedTunnelsResponse {
+        success: true,
+        message: format!("{} tunnel(s) of user '{}' disconnected", kicked.len(), user_id),
+        kicked,
+    })
+}
+
+/// POST /tunnels/kick-all - Force disconnect every tunnel on this node
+async fn kick_all_tunnels(State(state): State<Arc<AppState>>) -> Json<KickedTunnelsResponse> {
+    warn!("Management API: Kicking all tunnels");
+    state.resume_tokens.revoke_all();
+    let kicked = kick_matching(&state, |_| true).await;
+    info!("Management API: Kicked {} tunnel(s)", kicked.len());
+    Json(KickedTunnelsResponse {
+        success: true,
+        message: format!("{} tunnel(s) disconnected", kicked.len()),
+        kicked,
+    })
+}
+
+/// GET /tunnels/:subdomain/rate-limit - Show the limits in effect for a tunnel
+async fn get_rate_limit(State(state): State<Arc<AppState>>, Path(subdomain): Path<String>) -> Json<RateLimitResponse> {
+    let (limit, custom) = state.tunnel_limits.limit_for(&subdomain).await;
+    Json(RateLimitResponse {
+        subdomain,
+        limit,
+        custom,
+    })
+}
+
+/// PUT /tunnels/:subdomain/rate-limit - Override a tunnel's limits (0 = unlimited)
+async fn set_rate_limit(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(limit): Json<TunnelRateLimit>,
+) -> Json<RateLimitResponse> {
+    info!(
+        "Management API: rate limit for '{}' set to {} req/s (burst {}), {} connections",
+        subdomain, limit.requests_per_second, limit.burst, limit.max_connections
+    );
+    state.tunnel_limits.set(&subdomain, limit).await;
+    Json(RateLimitResponse {
+        subdomain,
+        limit,
+        custom: true,
+    })
+}
+
+/// DELETE /tunnels/:subdomain/rate-limit - Revert a tunnel to the configured limits
+async fn clear_rate_limit(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Json<SuccessResponse> {
+    let message = if state.tunnel_limits.clear(&subdomain).await {
+        info!("Management API: rate limit for '{}' cleared", subdomain);
+        format!("Tunnel '{}' uses the default limits", subdomain)
+    } else {
+        format!("Tunnel '{}' had no custom limits", subdomain)
+    };
+    Json(SuccessResponse {
+        success: true,
+        message,
+    })
+}
+
+/// GET /tunnels/:subdomain/bandwidth - Show a tunnel's bandwidth limit and throughput
+async fn get_bandwidth(State(state): State<Arc<AppState>>, Path(subdomain): Path<String>) -> Json<BandwidthResponse> {
+    let (_, custom) = state.bandwidth.limit_for(&subdomain);
+    Json(BandwidthResponse {
+        usage: state.bandwidth.usage(&subdomain),
+        subdomain,
+        custom,
+    })
+}
+
+/// PUT /tunnels/:subdomain/bandwidth - Override a tunnel's bandwidth limit (0 = unlimited)
+async fn set_bandwidth(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(request): Json<BandwidthLimitRequest>,
+) -> Json<BandwidthResponse> {
+    info!(
+        "Management API: bandwidth of '{}' set to {} bytes/s",
+        subdomain, request.bytes_per_second
+    );
+    state.bandwidth.set(&subdomain, request.bytes_per_second);
+    Json(BandwidthResponse {
+        usage: state.bandwidth.usage(&subdomain),
+        subdomain,
+        custom: true,
+    })
+}
+
+/// DELETE /tunnels/:subdomain/bandwidth - Revert a tunnel to the configured bandwidth limit
+async fn clear_bandwidth(State(state): State<Arc<AppState>>, Path(subdomain): Path<String>) -> Json<SuccessResponse> {
+    let message = if state.bandwidth.clear(&subdomain) {
+        info!("Management API: bandwidth of '{}' cleared", subdomain);
+        format!("Tunnel '{}' uses the default bandwidth limit", subdomain)
+    } else {
+        format!("Tunnel '{}' had no custom bandwidth limit", subdomain)
+    };
+    Json(SuccessResponse {
+        success: true,
+        message,
+    })
+}
+
+/// GET /tunnels/:subdomain/oauth - Show who must sign in to visit a tunnel
+async fn get_oauth_policy(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Json<OAuthPolicyResponse> {
+    let policy = state.oauth.get(&subdomain).await;
+    Json(OAuthPolicyResponse { subdomain, policy })
+}
+
+/// PUT /tunnels/:subdomain/oauth - Require visitors to sign in with a provider
+async fn set_oauth_policy(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(policy): Json<OAuthPolicy>,
+) -> Result<Json<OAuthPolicyResponse>, (StatusCode, Json<ErrorResponse>)> {
+    policy.validate().map_err(|e| domain_error(StatusCode::BAD_REQUEST, e))?;
+    if !oauth_configured(policy.provider) {
+        let error = format!("No {} OAuth client is configured on this server", policy.provider.as_str());
+        return Err(domain_error(StatusCode::BAD_REQUEST, error));
+    }
+    info!(
+        "Management API: '{}' now requires {} sign-in ({} allow entries)",
+        subdomain,
+        policy.provider.as_str(),
+        policy.allow.len()
+    );
+    state.oauth.set(&subdomain, policy.clone()).await;
+    Ok(Json(OAuthPolicyResponse {
+        subdomain,
+        policy: Some(policy),
+    }))
+}
+
+/// DELETE /tunnels/:subdomain/oauth - Let visitors in without signing in
+async fn clear_oauth_policy(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Json<SuccessResponse> {
+    let message = if state.oauth.clear(&subdomain).await {
+        info!("Management API: OAuth protection for '{}' removed", subdomain);
+        format!("Tunnel '{}' no longer requires signing in", subdomain)
+    } else {
+        format!("Tunnel '{}' did not require signing in", subdomain)
+    };
+    Json(SuccessResponse {
+        success: true,
+        message,
+    })
+}
+
+/// GET /tunnels/:subdomain/webhooks - List a tunnel's webhook signature rules
+async fn get_webhook_rules(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Json<WebhookRulesResponse> {
+    let rules = state.webhooks.get(&subdomain).await;
+    Json(WebhookRulesResponse { subdomain, rules })
+}
+
+/// PUT /tunnels/:subdomain/webhooks - Replace a tunnel's webhook signature rules
+async fn set_webhook_rules(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(rules): Json<Vec<WebhookRule>>,
+) -> Result<Json<WebhookRulesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    validate_rules(&rules).map_err(|e| domain_error(StatusCode::BAD_REQUEST, e))?;
+    info!("Management API: '{}' now checks {} webhook paths", subdomain, rules.len());
+    state.webhooks.set(&subdomain, rules.clone()).await;
+    Ok(Json(WebhookRulesResponse { subdomain, rules }))
+}
+
+/// DELETE /tunnels/:subdomain/webhooks - Stop checking webhook signatures
+async fn clear_webhook_rules(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Json<SuccessResponse> {
+    let cleared = state.webhooks.clear(&subdomain).await;
+    if cleared > 0 {
+        info!("Management API: webhook rules for '{}' removed", subdomain);
+    }
+    Json(SuccessResponse {
+        success: true,
+        message: format!("Removed {} webhook rules of '{}'", cleared, subdomain),
+    })
+}
+
+/// PUT /tunnels/:subdomain/banner - Toggle the preview banner on HTML responses
+async fn set_banner(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(request): Json<BannerRequest>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    state
+        .set_preview_banner(&subdomain, request.enabled)
+        .await
+        .map_err(tunnel_error)?;
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!(
+            "Preview banner {} for '{}'",
+            if request.enabled { "enabled" } else { "disabled" },
+            subdomain
+        ),
+    }))
+}
+
+/// GET /tunnels/:subdomain/cache - Show whether a tunnel caches responses and its cache counters
+async fn get_cache(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Result<Json<CacheResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let tunnel = state
+        .get_tunnel(&subdomain)
+        .await
+        .ok_or_else(|| tunnel_error(TunnelError::TunnelNotFound(subdomain.clone())))?;
+    Ok(Json(CacheResponse {
+        stats: state.response_cache.stats(&subdomain).await,
+        enabled: tunnel.response_cache,
+        subdomain,
+    }))
+}
+
+/// PUT /tunnels/:subdomain/cache - Turn the response cache on or off
+async fn set_cache(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(request): Json<BannerRequest>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    state
+        .set_response_cache(&subdomain, request.enabled)
+        .await
+        .map_err(tunnel_error)?;
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!(
+            "Response cache {} for '{}'",
+            if request.enabled { "enabled" } else { "disabled" },
+            subdomain
+        ),
+    }))
+}
+
+/// DELETE /tunnels/:subdomain/cache - Drop everything a tunnel has cached
+async fn purge_cache(State(state): State<Arc<AppState>>, Path(subdomain): Path<String>) -> Json<SuccessResponse> {
+    let purged = state.response_cache.purge(&subdomain).await;
+    info!("Management API: purged {} cached responses of '{}'", purged, subdomain);
+    Json(SuccessResponse {
+        success: true,
+        message: format!("Purged {} cached responses of '{}'", purged, subdomain),
+    })
+}
+
+/// GET /tunnels/:subdomain/latency - Show p50/p95/p99 of a tunnel's time to first byte and request duration
+async fn get_latency(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Result<Json<LatencyResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if state.get_tunnel(&subdomain).await.is_none() {
+        return Err(tunnel_error(TunnelError::TunnelNotFound(subdomain)));
+    }
+    Ok(Json(LatencyResponse {
+        latency: state.latency.report(std::slice::from_ref(&subdomain)),
+        subdomain,
+    }))
+}
+
+/// GET /tunnels/:subdomain/har - Download the exchanges captured for a tunnel as a HAR file
+async fn get_har(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Result<([(header::HeaderName, String); 1], Json<Har>), (StatusCode, Json<ErrorResponse>)> {
+    let har = state
+        .har
+        .export(&subdomain)
+        .ok_or_else(|| domain_error(StatusCode::NOT_FOUND, format!("No HAR capture for '{}'", subdomain)))?;
+    let disposition = format!("attachment; filename=\"{}.har\"", subdomain);
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(har)))
+}
+
+/// PUT /tunnels/:subdomain/har - Start or pause capturing a tunnel's exchanges
+async fn set_har(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(request): Json<BannerRequest>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if request.enabled && state.get_tunnel(&subdomain).await.is_none() {
+        return Err(tunnel_error(TunnelError::TunnelNotFound(subdomain)));
+    }
+    state.har.set_recording(&subdomain, request.enabled);
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!(
+            "HAR capture {} for '{}'",
+            if request.enabled { "started" } else { "paused" },
+            subdomain
+        ),
+    }))
+}
+
+/// DELETE /tunnels/:subdomain/har - Stop capturing and drop the captured exchanges
+async fn discard_har(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let dropped = state
+        .har
+        .discard(&subdomain)
+        .ok_or_else(|| domain_error(StatusCode::NOT_FOUND, format!("No HAR capture for '{}'", subdomain)))?;
+    info!("Management API: discarded HAR capture of '{}' ({} exchanges)", subdomain, dropped);
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!("Discarded {} captured exchanges of '{}'", dropped, subdomain),
+    }))
+}
+
+/// PUT /tunnels/:subdomain/headers - Replace the headers added to and removed
+/// from a tunnel's responses (`{}` clears them)
+async fn set_response_headers(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(rules): Json<HeaderRules>,
+) -> Result<Json<HeaderRules>, (StatusCode, Json<ErrorResponse>)> {
+    rules
+        .validate()
+        .map_err(|e| domain_error(StatusCode::BAD_REQUEST, e))?;
+    state
+        .set_response_headers(&subdomain, rules.clone())
+        .await
+        .map_err(tunnel_error)?;
+    Ok(Json(rules))
+}
+
+/// GET /verified-keys - Cached key verifications that skip the Device Flow
+async fn list_verified_keys(State(state): State<Arc<AppState>>) -> Json<VerifiedKeysResponse> {
+    let keys = state
+        .list_verified_keys()
+        .await
+        .into_iter()
+        .map(|(fingerprint, key)| VerifiedKeyResponse {
+            fingerprint,
+            verified_at: DateTime::<Utc>::from(key.verified_at).to_rfc3339(),
+            expires_at: DateTime::<Utc>::from(key.expires_at()).to_rfc3339(),
+            renewable_until: key.renewable_until.map(|until| DateTime::<Utc>::from(until).to_rfc3339()),
+            subdomains: key.subdomains.into_iter().collect(),
+            user_id: key.user_id,
+            display_name: key.display_name,
+            tier: key.tier,
+        })
+        .collect();
+    Json(VerifiedKeysResponse { keys })
+}
+
+/// DELETE /verified-keys/:fingerprint - Send the key's next connection back
+/// through the Device Flow (open sessions stay connected)
+async fn revoke_verified_key(
+    State(state): State<Arc<AppState>>,
+    Path(fingerprint): Path<String>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    match state.revoke_verified_key(&fingerprint).await {
+        Some(key) => {
+            info!("Management API: verified key {} of user {} revoked", fingerprint, key.user_id);
+            Ok(Json(SuccessResponse {
+                success: true,
+                message: format!("Verified key {} revoked", fingerprint),
+            }))
+        }
+        None => Err(domain_error(
+            StatusCode::NOT_FOUND,
+            format!("Verified key not found: {}", fingerprint),
+        )),
+    }
+}
+
+/// GET /bans - List active bans
+async fn list_bans(State(state): State<Arc<AppState>>) -> Json<BansResponse> {
+    let bans = state.bans.list().await.into_iter().map(Into::into).collect();
+    Json(BansResponse { bans })
+}
+
+/// GET /tcp-ports - List the public TCP ports bound for `-R tcp:<port>:...` forwards
+async fn list_tcp_ports(State(state): State<Arc<AppState>>) -> Json<PortsResponse> {
+    Json(PortsResponse {
+        ports: state.dedicated_ports.list(Transport::Tcp),
+    })
+}
+
+/// GET /udp-ports - List the public UDP ports bound for `-R udp:<port>:...` forwards
+async fn list_udp_ports(State(state): State<Arc<AppState>>) -> Json<PortsResponse> {
+    Json(PortsResponse {
+        ports: state.dedicated_ports.list(Transport::Udp),
+    })
+}
+
+/// POST /bans - Ban an IP from SSH and the HTTP proxy
+async fn create_ban(State(state): State<Arc<AppState>>, Json(request): Json<BanRequest>) -> Json<BanResponse> {
+    let reason = request.reason.unwrap_or_else(|| "banned by administrator".to_string());
+    let ban = state
+        .bans
+        .ban(request.ip, &reason, request.duration_secs.map(Duration::from_secs))
+        .await;
+    info!("Management API: banned {} ({})", request.ip, reason);
+    Json(ban.into())
+}
+
+/// DELETE /bans/:ip - Lift a ban
+async fn delete_ban(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ip: IpAddr = ip.parse().map_err(|_| {
+        domain_error(StatusCode::BAD_REQUEST, format!("Invalid IP address: {}", ip))
+    })?;
+    if !state.bans.unban(ip).await {
+        return Err(domain_error(StatusCode::NOT_FOUND, format!("{} is not banned", ip)));
+    }
+    info!("Management API: unbanned {}", ip);
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!("{} unbanned", ip),
+    }))
+}
+
+/// Parse the `{ip}` of a Device Flow limit route
+fn parse_ip(ip: &str) -> Result<IpAddr, (StatusCode, Json<ErrorResponse>)> {
+    ip.parse()
+        .map_err(|_| domain_error(StatusCode::BAD_REQUEST, format!("Invalid IP address: {}", ip)))
+}
+
+/// GET /device-flow-limits/:ip - Device Flow rate limit state of an IP and its keys
+async fn get_device_flow_limits(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<DeviceFlowLimitResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = parse_ip(&ip)?;
+    let Some((entry, keys)) = state.device_flow_rate_limits(ip).await else {
+        return Err(domain_error(
+            StatusCode::NOT_FOUND,
+            format!("No recent Device Flow requests from {}", ip),
+        ));
+    };
+    let keys = keys
+        .into_iter()
+        .map(|(fingerprint, key)| KeyDeviceFlowLimitResponse {
+            fingerprint: (!fingerprint.is_empty()).then_some(fingerprint),
+            attempts: key.attempts,
+            last_request: DateTime::<Utc>::from(key.last_request).to_rfc3339(),
+            limited: key.exceeds(&DEVICE_FLOW_KEY_BUDGET),
+        })
+        .collect();
+    Ok(Json(DeviceFlowLimitResponse {
+        ip,
+        attempts: entry.attempts,
+        window_start: DateTime::<Utc>::from(entry.window_start).to_rfc3339(),
+        last_request: DateTime::<Utc>::from(entry.last_request).to_rfc3339(),
+        limited: entry.exceeds(&DEVICE_FLOW_IP_BUDGET),
+        keys,
+    }))
+}
+
+/// DELETE /device-flow-limits/:ip - Let an IP start the Device Flow again right away
+async fn clear_device_flow_limits(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = parse_ip(&ip)?;
+    if !state.clear_device_flow_rate_limits(ip).await {
+        return Err(domain_error(
+            StatusCode::NOT_FOUND,
+            format!("No recent Device Flow requests from {}", ip),
+        ));
+    }
+    info!("Management API: cleared Device Flow rate limits of {}", ip);
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!("Rate limits of {} cleared", ip),
+    }))
+}
+
+/// GET /cluster/tunnels - Report this node's connected tunnels to cluster peers
+async fn cluster_tunnels(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<ClusterTunnelsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    require_internal_secret(&headers)
+        .map_err(|status| domain_error(status, "Invalid internal secret".to_string()))?;
+
+    Ok(Json(local_report(&state).await))
+}
+
+/// POST /activations - Activation result from the web app
+/// (`VERIFICATION_MODE=callback`)
+async fn activation_callback(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(callback): Json<ActivationCallback>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    require_internal_secret(&headers)
+        .map_err(|status| domain_error(status, "Invalid internal secret".to_string()))?;
+
+    let status = callback.result.status.clone();
+    if !state.activations.resolve(&callback.code, callback.result) {
+        return Err(domain_error(
+            StatusCode::NOT_FOUND,
+            format!("No session is waiting for code {}", callback.code),
+        ));
+    }
+    info!("Management API: activation code {} reported {}", callback.code, status);
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: format!("Code {} {}", callback.code, status),
+    }))
+}
+
+/// POST /config/reload - Re-read TTLs, rate limits, reserved subdomains and
+/// the log filter (same as SIGHUP)
+async fn reload_config(headers: HeaderMap) -> Result<Json<ConfigReloadResponse>, (StatusCode, Json<ErrorResponse>)> {
+    require_internal_secret(&headers)
+        .map_err(|status| domain_error(status, "Invalid internal secret".to_string()))?;
+
+    // Reading .env and the profile file is blocking I/O
+    let result = tokio::task::spawn_blocking(reload)
+        .await
+        .map_err(|e| domain_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let changed = result.map_err(|e| domain_error(StatusCode::BAD_REQUEST, e))?;
+    info!("Management API: configuration reloaded");
+    Ok(Json(ConfigReloadResponse {
+        changed: changed.into_iter().map(str::to_string).collect(),
+    }))
+}
+
+/// GET /motd - Show the current message of the day
+async fn get_motd(State(state): State<Arc<AppState>>) -> Json<MotdResponse> {
+    match state.motd.current().await {
+        Some((motd, seen_by)) => {
+            let set_at: DateTime<Utc> = motd.set_at.into();
+            Json(MotdResponse {
+                message: Some(motd.message),
+                set_at: Some(set_at.to_rfc3339()),
+                seen_by,
+            })
+        }
+        None => Json(MotdResponse {
+            message: None,
+            set_at: None,
+            seen_by: 0,
+        }),
+    }
+}
+
+/// PUT /motd - Set a message shown once to each user on their next connect
+async fn set_motd(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SetMotdRequest>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let message = request.message.trim().to_string();
+    if message.is_empty() {
+        return Err(domain_error(StatusCode::BAD_REQUEST, "Message must not be empty".to_string()));
+    }
+
+    info!("Management API: MOTD set ({} chars)", message.len());
+    state.motd.set(message).await;
+    Ok(Json(SuccessResponse {
+        success: true,
+        message: "MOTD set".to_string(),
+    }))
+}
+
+/// DELETE /motd - Clear the message of the day
+async fn clear_motd(State(state): State<Arc<AppState>>) -> Json<SuccessResponse> {
+    info!("Management API: MOTD cleared");
+    state.motd.clear().await;
+    Json(SuccessResponse {
+        success: true,
+        message: "MOTD cleared".to_string(),
+    })
+}
+
+/// Current maintenance mode state
+async fn maintenance_mode_response(state: &AppState) -> MaintenanceModeResponse {
+    match state.maintenance_mode.current().await {
+        Some(notice) => {
+            let since: DateTime<Utc> = notice.since.into();
+            MaintenanceModeResponse {
+                enabled: true,
+                message: Some(notice.message),
+                since: Some(since.to_rfc3339()),
+            }
+        }
+        None => MaintenanceModeResponse {
+            enabled: false,
+            message: None,
+            since: None,
+        },
+    }
+}
+
+/// GET /maintenance/mode - Whether new sessions and tunnels are refused
+async fn get_maintenance_mode(State(state): State<Arc<AppState>>) -> Json<MaintenanceModeResponse> {
+    Json(maintenance_mode_response(&state).await)
+}
+
+/// PUT /maintenance/mode - Refuse new sessions and tunnels; existing tunnels keep serving
+async fn enable_maintenance_mode(
+    State(state): State<Arc<AppState>>,
+    request: Option<Json<SetMaintenanceModeRequest>>,
+) -> Json<MaintenanceModeResponse> {
+    let Json(request) = request.unwrap_or_default();
+    info!("Management API: maintenance mode enabled");
+    state.maintenance_mode.enable(request.message).await;
+    Json(maintenance_mode_response(&state).await)
+}
+
+/// DELETE /maintenance/mode - Accept new sessions and tunnels again
+async fn disable_maintenance_mode(State(state): State<Arc<AppState>>) -> Json<MaintenanceModeResponse> {
+    if state.maintenance_mode.disable().await {
+        info!("Management API: maintenance mode disabled");
+    }
+    Json(maintenance_mode_response(&state).await)
+}
+
+/// Timeout for the web API reachability check
+const WEB_API_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// GET /healthz - Liveness probe (process is up and serving)
+async fn healthz() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "ok" })
+}
+
+/// Check that the Device Flow web API answers at all
+async fn web_api_reachable() -> bool {
+    let Ok(client) = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(WEB_API_PROBE_TIMEOUT)
+        .build()
+    else {
+        return false;
+    };
+    client.get(&get_config().api_base_url).send().await.is_ok()
+}
+
+/// GET /readyz - Readiness probe.
+/// Returns 503 until both listeners are bound; a web API outage only degrades
+/// readiness because existing tunnels keep working without it.
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadinessResponse>) {
+    let config_loaded = config_loaded();
+    let checks = ReadinessChecks {
+        config_loaded,
+        ssh_listener: state.readiness.ssh_listening(),
+        http_listener: state.readiness.http_listening(),
+        web_api: config_loaded && web_api_reachable().await,
+    };
+
+    let (code, status) = if !checks.config_loaded || !checks.ssh_listener || !checks.http_listener {
+        (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+    } else if !checks.web_api {
+        (StatusCode::OK, "degraded")
+    } else {
+        (StatusCode::OK, "ready")
+    };
+
+    (code, Json(ReadinessResponse { status, checks }))
+}
+
+/// GET /maintenance - Statistics of the periodic maintenance tasks
+async fn maintenance_stats(State(state): State<Arc<AppState>>) -> Json<MaintenanceResponse> {
+    let tasks = state
+        .maintenance
+        .snapshot()
+        .await
+        .into_iter()
+        .map(|(name, stats)| MaintenanceTaskResponse {
+            name: name.to_string(),
+            runs: stats.runs,
+            panics: stats.panics,
+            last_run: stats.last_run.map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+            last_duration_ms: stats.last_duration.as_millis() as u64,
+        })
+        .collect();
+
+    Json(MaintenanceResponse { tasks })
+}
+
+/// GET /debug/cleanup - Background cleanup backlog
+async fn cleanup_stats(State(state): State<Arc<AppState>>) -> Json<CleanupResponse> {
+    let stats = state.cleanup.stats();
+    Json(CleanupResponse {
+        limit: stats.limit,
+        queued: stats.queued,
+        running: stats.running,
+        completed: stats.completed,
+        peak: stats.peak,
+        by_kind: stats.by_kind,
+    })
+}
+
+/// GET /debug/reconcile - Orphaned backend registration cleanup
+async fn reconcile_stats(State(state): State<Arc<AppState>>) -> Json<ReconcileResponse> {
+    let stats = state.reconcile.stats();
+    let mode = match get_config().backend_reconcile {
+        ReconcileMode::Off => "off",
+        ReconcileMode::DryRun => "dry-run",
+        ReconcileMode::On => "on",
+    };
+    Json(ReconcileResponse {
+        mode,
+        runs: stats.runs,
+        failed_runs: stats.failed_runs,
+        orphans_found: stats.orphans_found,
+        unregistered: stats.unregistered,
+        unregister_failures: stats.unregister_failures,
+        last_run: stats.last_run.map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+        last_orphans: stats.last_orphans,
+    })
+}
+
+/// GET /debug/crashes - Panic count and the most recent crash reports
+async fn crash_reports() -> Json<CrashesResponse> {
+    Json(CrashesResponse {
+        panics: panic_count(),
+        recent: recent_reports(),
+    })
+}
+
+fn domain_error(status: StatusCode, error: String) -> (StatusCode, Json<ErrorResponse>) {
+    (status, Json(ErrorResponse { error, code: None }))
+}
+
+/// Check the `X-Internal-Secret` of an internal call (cluster peers, the web app)
+fn require_internal_secret(headers: &HeaderMap) -> Result<(), StatusCode> {
+    let provided = headers
+        .get("X-Internal-Secret")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if secrets_match(provided, &get_config().internal_api_secret) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Error body of a `TunnelError`, with its code and status
+pub(crate) fn tunnel_error(e: TunnelError) -> (StatusCode, Json<ErrorResponse>) {
+    let status = StatusCode::from_u16(e.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let body = ErrorResponse {
+        error: e.to_string(),
+        code: Some(e.code()),
+    };
+    (status, Json(body))
+}
+
+/// GET /domains - List custom domains
+async fn list_domains(State(state): State<Arc<AppState>>) -> Json<CustomDomainsResponse> {
+    let domains = state.domains.list().await.into_iter().map(Into::into).collect();
+    Json(CustomDomainsResponse { domains })
+}
+
+/// PUT /domains/:domain - Attach a custom domain to one of the user's tunnels
+async fn attach_domain(
+    State(state): State<Arc<AppState>>,
+    Path(domain): Path<String>,
+    Json(request): Json<AttachDomainRequest>,
+) -> Result<Json<CustomDomainResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let domain = normalize_host(&domain);
+    validate_custom_domain(&domain, &get_config().tunnel_url)
+        .map_err(|e| domain_error(StatusCode::BAD_REQUEST, e))?;
+
+    match state.get_tunnel(&request.subdomain).await {
+        Some(tunnel) if tunnel.username != request.user_id => {
+            return Err(domain_error(
+                StatusCode::FORBIDDEN,
+                format!("Tunnel '{}' belongs to another user", request.subdomain),
             ))
         }
+        Some(tunnel) if !tunnel.capabilities.allows(Capability::CustomDomains) => {
+            return Err(domain_error(
+                StatusCode::FORBIDDEN,
+                format!("Custom domains are not enabled for user '{}'", request.user_id),
+            ))
+        }
+        Some(_) => {}
+        None => {
+            return Err(tunnel_error(TunnelError::TunnelNotFound(request.subdomain)))
+        }
+    }
+
+    let attached = state
+        .domains
+        .attach(&domain, &request.subdomain, &request.user_id)
+        .await
+        .map_err(|e| domain_error(StatusCode::CONFLICT, e))?;
+    info!("Management API: custom domain {} -> {}", domain, request.subdomain);
+    Ok(Json(attached.into()))
+}
+
+/// DELETE /domains/:domain - Detach a custom domain
+async fn detach_domain(
+    State(state): State<Arc<AppState>>,
+    Path(domain): Path<String>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let domain = normalize_host(&domain);
+    match state.domains.detach(&domain).await {
+        Some(_) => {
+            info!("Management API: custom domain {} detached", domain);
+            Ok(Json(SuccessResponse {
+                success: true,
+                message: format!("Domain '{}' detached", domain),
+            }))
+        }
+        None => Err(domain_error(
+            StatusCode::NOT_FOUND,
+            format!("Domain not found: {}", domain),
+        )),
+    }
+}
+
+/// GET /claims - List active subdomain claims
+async fn list_claims(State(state): State<Arc<AppState>>) -> Json<SubdomainClaimsResponse> {
+    let claims = state.claims.list().await.into_iter().map(Into::into).collect();
+    Json(SubdomainClaimsResponse { claims })
+}
+
+/// PUT /claims/:subdomain - Reserve a subdomain for a verified user
+async fn claim_subdomain(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+    Json(request): Json<ClaimSubdomainRequest>,
+) -> Result<Json<SubdomainClaimResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if !is_valid_subdomain(&subdomain) {
+        return Err(domain_error(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid subdomain: {}", subdomain),
+        ));
+    }
+
+    let verified = state
+        .verified_keys
+        .read()
+        .await
+        .values()
+        .any(|key| key.user_id == request.user_id);
+    if !verified {
+        return Err(domain_error(
+            StatusCode::FORBIDDEN,
+            format!("User '{}' has no verified keys", request.user_id),
+        ));
+    }
+
+    if state
+        .get_tunnel(&subdomain)
+        .await
+        .is_some_and(|tunnel| tunnel.is_connected && tunnel.username != request.user_id)
+    {
+        return Err(tunnel_error(TunnelError::SubdomainTaken(subdomain)));
+    }
+
+    let claim = state
+        .claims
+        .claim(&subdomain, &request.user_id, request.ttl_secs.map(Duration::from_secs))
+        .await
+        .map_err(|e| domain_error(StatusCode::CONFLICT, e))?;
+    info!("Management API: subdomain {} claimed by {}", subdomain, request.user_id);
+    Ok(Json(claim.into()))
+}
+
+/// DELETE /claims/:subdomain - Release a subdomain claim
+async fn release_claim(
+    State(state): State<Arc<AppState>>,
+    Path(subdomain): Path<String>,
+) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
+    match state.claims.release(&subdomain).await {
+        Some(_) => {
+            info!("Management API: subdomain claim {} released", subdomain);
+            Ok(Json(SuccessResponse {
+                success: true,
+                message: format!("Claim on '{}' released", subdomain),
+            }))
+        }
+        None => Err(domain_error(
+            StatusCode::NOT_FOUND,
+            format!("Claim not found: {}", subdomain),
+        )),
+    }
+}
+
+/// GET /traefik/config - Traefik dynamic configuration (HTTP provider) with a
+/// router per custom domain, so certificates are issued for custom hosts too
+async fn traefik_config(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    let config = get_config();
+    let routers: serde_json::Map<String, serde_json::Value> = state
+        .domains
+        .list()
+        .await
+        .into_iter()
+        .map(|d| {
+            let name = format!("exlo-custom-{}", d.domain.replace('.', "-"));
+            let router = serde_json::json!({
+                "rule": format!("Host(`{}`)", d.domain),
+                "entryPoints": ["websecure"],
+                "service": config.custom_domain_traefik_service,
+                "tls": { "certResolver": config.custom_domain_cert_resolver },
+            });
+            (name, router)
+        })
+        .collect();
+
+    if routers.is_empty() {
+        // Traefik rejects an empty routers table
+        return Json(serde_json::json!({}));
+    }
+    Json(serde_json::json!({ "http": { "routers": routers } }))
+}
+
+/// Who is calling the management API: with the internal secret the `X-Actor`
+/// the caller names (e.g. the dashboard passing its signed-in operator), else
+/// `admin`; without it the caller's address (`anonymous` if unknown), since
+/// an unauthenticated `X-Actor` could name anyone
+fn caller_identity(headers: &HeaderMap, remote: Option<SocketAddr>) -> String {
+    if !config_loaded() || require_internal_secret(headers).is_err() {
+        return remote.map_or_else(|| "anonymous".to_string(), |addr| addr.ip().to_string());
+    }
+    headers
+        .get("X-Actor")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map_or_else(|| "admin".to_string(), |actor| actor.chars().take(128).collect())
+}
+
+/// Record every state-changing management API call in the audit log
+async fn audit_api_calls(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    if matches!(method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(request).await;
+    }
+    let remote = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let actor = caller_identity(request.headers(), remote);
+    let path = request.uri().path().to_string();
+
+    let response = next.run(request).await;
+
+    let mut event = AuditEvent::new(&actor, "api.call")
+        .target(&format!("{} {}", method, path))
+        .detail(response.status().as_u16().to_string());
+    if let Some(remote) = remote {
+        event = event.remote(remote);
+    }
+    state.audit.record(event);
+    response
+}
+
+/// GET /audit - Audit trail, filtered by `since` and `actor`
+async fn audit_log(State(state): State<Arc<AppState>>, Query(query): Query<AuditLogQuery>) -> Json<AuditLogResponse> {
+    let query = AuditQuery {
+        since: query.since,
+        actor: query.actor,
+        limit: query.limit.unwrap_or(100).clamp(1, 1000),
+    };
+    Json(AuditLogResponse {
+        events: state.audit.query(&query),
+    })
+}
+
+fn host_keys_response(state: &AppState) -> HostKeysResponse {
+    let (keys, rotation) = state.host_keys.published();
+    HostKeysResponse { keys, rotation }
+}
+
+/// GET /host-keys - Host key fingerprints, including the next ones during a rotation
+async fn list_host_keys(State(state): State<Arc<AppState>>) -> Json<HostKeysResponse> {
+    Json(host_keys_response(&state))
+}
+
+/// POST /host-keys/rotate - Generate new host keys, presented once the grace
+/// period is over
+async fn rotate_host_keys(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<RotateHostKeysQuery>,
+) -> Result<Json<HostKeysResponse>, (StatusCode, Json<ErrorResponse>)> {
+    require_internal_secret(&headers)
+        .map_err(|status| domain_error(status, "Invalid internal secret".to_string()))?;
+
+    let grace = query
+        .grace_secs
+        .map(Duration::from_secs)
+        .unwrap_or(get_config().host_key_rotation_grace);
+    // Key generation (RSA in particular) is slow, blocking work
+    let rotating = state.clone();
+    let rotation = tokio::task::spawn_blocking(move || rotating.host_keys.rotate(grace))
+        .await
+        .map_err(|e| domain_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| domain_error(StatusCode::CONFLICT, format!("{:#}", e)))?;
+    info!("Management API: host key rotation started, keys switch at {}", rotation.promote_at);
+    state.audit.record(
+        AuditEvent::new(&caller_identity(&headers, None), "host_key.rotated").detail(rotation.promote_at.to_rfc3339()),
+    );
+    Ok(Json(host_keys_response(&state)))
+}
+
+/// GET /events - WebSocket stream of tunnel lifecycle events.
+/// Admins (internal secret) see all tunnels, user tokens only their own.
+async fn event_stream(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventStreamQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.to_string());
+    let token = query.token.or(bearer).unwrap_or_default();
+
+    let Some(scope) = EventScope::from_token(&token, &get_config().internal_api_secret) else {
+        return Err(domain_error(StatusCode::UNAUTHORIZED, "Invalid event stream token".to_string()));
+    };
+
+    info!("Management API: event stream opened ({:?})", scope);
+    Ok(ws.on_upgrade(move |socket| stream_events(socket, state, scope, query.resume)))
+}
+
+async fn send_message(socket: &mut WebSocket, message: &EventMessage) -> bool {
+    let text = serde_json::to_string(message).unwrap_or_default();
+    socket.send(Message::Text(text.into())).await.is_ok()
+}
+
+/// Replay missed events (if resuming), then forward live events in scope
+async fn stream_events(mut socket: WebSocket, state: Arc<AppState>, scope: EventScope, resume: Option<String>) {
+    let (mut events, mut token) = state.events.subscribe();
+    // Highest sequence number delivered, so live events don't repeat the replay
+    let mut last_seq = 0;
+
+    if let Some(resume) = resume {
+        match state.events.replay(&resume) {
+            Replay::Events(missed) => {
+                for event in missed {
+                    last_seq = event.seq;
+                    token = state.events.token(event.seq);
+                    if scope.allows(&event) && !send_message(&mut socket, &EventMessage::event(&event, token.clone())).await {
+                        return;
+                    }
+                }
+            }
+            Replay::ResyncRequired => {
+                if !send_message(&mut socket, &EventMessage::ResyncRequired { token: token.clone() }).await {
+                    return;
+                }
+            }
+        }
+    }
+    if !send_message(&mut socket, &EventMessage::Ready { token }).await {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            received = events.recv() => {
+                let message = match received {
+                    Ok(event) if event.seq <= last_seq => continue,
+                    Ok(event) => {
+                        last_seq = event.seq;
+                        if !scope.allows(&event) {
+                            continue;
+                        }
+                        EventMessage::event(&event, state.events.token(event.seq))
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Event stream subscriber lagged by {} events", skipped);
+                        let (resubscribed, token) = state.events.subscribe();
+                        events = resubscribed;
+                        EventMessage::ResyncRequired { token }
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if !send_message(&mut socket, &message).await {
+                    return;
+                }
+            }
+            incoming = socket.recv() => {
+                // Clients only listen; stop on close or error
+                match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
     }
 }
 
@@ -130,7 +1754,67 @@ pub fn create_management_router(state: Arc<AppState>) -> Router {
 
     Router::new()
         .route("/tunnels", get(list_tunnels))
+        .route("/tunnels/kick-all", post(kick_all_tunnels))
         .route("/tunnels/{subdomain}", delete(kick_tunnel))
+        .route("/users/{user_id}/tunnels", delete(kick_user_tunnels))
+        .route("/tunnels/{subdomain}/banner", put(set_banner))
+        .route(
+            "/tunnels/{subdomain}/cache",
+            get(get_cache).put(set_cache).delete(purge_cache),
+        )
+        .route(
+            "/tunnels/{subdomain}/har",
+            get(get_har).put(set_har).delete(discard_har),
+        )
+        .route("/tunnels/{subdomain}/headers", put(set_response_headers))
+        .route("/tunnels/{subdomain}/latency", get(get_latency))
+        .route(
+            "/tunnels/{subdomain}/rate-limit",
+            get(get_rate_limit).put(set_rate_limit).delete(clear_rate_limit),
+        )
+        .route(
+            "/tunnels/{subdomain}/bandwidth",
+            get(get_bandwidth).put(set_bandwidth).delete(clear_bandwidth),
+        )
+        .route(
+            "/tunnels/{subdomain}/oauth",
+            get(get_oauth_policy).put(set_oauth_policy).delete(clear_oauth_policy),
+        )
+        .route(
+            "/tunnels/{subdomain}/webhooks",
+            get(get_webhook_rules).put(set_webhook_rules).delete(clear_webhook_rules),
+        )
+        .route("/bans", get(list_bans).post(create_ban))
+        .route("/tcp-ports", get(list_tcp_ports))
+        .route("/udp-ports", get(list_udp_ports))
+        .route("/bans/{ip}", delete(delete_ban))
+        .route("/device-flow-limits/{ip}", get(get_device_flow_limits).delete(clear_device_flow_limits))
+        .route("/verified-keys", get(list_verified_keys))
+        .route("/verified-keys/{fingerprint}", delete(revoke_verified_key))
+        .route("/cluster/tunnels", get(cluster_tunnels))
+        .route("/activations", post(activation_callback))
+        .route("/config/reload", post(reload_config))
+        .route("/motd", get(get_motd).put(set_motd).delete(clear_motd))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/maintenance", get(maintenance_stats))
+        .route(
+            "/maintenance/mode",
+            get(get_maintenance_mode).put(enable_maintenance_mode).delete(disable_maintenance_mode),
+        )
+        .route("/debug/cleanup", get(cleanup_stats))
+        .route("/debug/reconcile", get(reconcile_stats))
+        .route("/debug/crashes", get(crash_reports))
+        .route("/events", get(event_stream))
+        .route("/domains", get(list_domains))
+        .route("/domains/{domain}", put(attach_domain).delete(detach_domain))
+        .route("/claims", get(list_claims))
+        .route("/claims/{subdomain}", put(claim_subdomain).delete(release_claim))
+        .route("/traefik/config", get(traefik_config))
+        .route("/audit", get(audit_log))
+        .route("/host-keys", get(list_host_keys))
+        .route("/host-keys/rotate", post(rotate_host_keys))
+        .route_layer(middleware::from_fn_with_state(state.clone(), audit_api_calls))
         .layer(cors)
         .with_state(state)
 }
@@ -139,10 +1823,12 @@ pub fn create_management_router(state: Arc<AppState>) -> Router {
 pub async fn run_management_api(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
     let router = create_management_router(state);
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let config = get_config();
+    let listener = bind_listener(addr, config.accept_backlog, config.reuse_port).await?;
     info!("Management API listening on {}", addr);
 
-    axum::serve(listener, router).await?;
+    // Peer addresses go into the audit log
+    axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
@@ -0,0 +1,155 @@
+//! Named configuration profiles (e.g. dev, staging, prod).
+//!
+//! Profiles live in a TOML file (`exlo.toml`, or the path in `CONFIG_FILE`):
+//!
+//! ```toml
+//! [profiles.dev]
+//! ssh_port = 2222
+//! tunnel_url = "localhost"
+//! port_probe = "wait"
+//!
+//! [profiles.prod]
+//! tunnel_url = "tunnel.example.com"
+//! idle_tunnel_timeout = 3600
+//! ```
+//!
+//! The profile is selected with `--profile <name>` or `TUNNL_PROFILE`. Keys are
+//! environment variable names (case-insensitive) and only fill in variables
+//! that are not already set, so the environment and `.env` always win.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+
+/// Environment variable selecting the profile
+pub const PROFILE_ENV: &str = "TUNNL_PROFILE";
+
+/// Environment variable overriding the profile file path
+pub const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+
+/// Profile file used when `CONFIG_FILE` is unset
+const DEFAULT_CONFIG_FILE: &str = "exlo.toml";
+
+/// Profile named by `--profile <name>` / `--profile=<name>`, else by `TUNNL_PROFILE`
+pub fn selected_profile(args: impl IntoIterator<Item = String>) -> Option<String> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            return args.next().filter(|name| !name.is_empty());
+        }
+        if let Some(name) = arg.strip_prefix("--profile=") {
+            return Some(name.to_string()).filter(|name| !name.is_empty());
+        }
+    }
+    std::env::var(PROFILE_ENV).ok().filter(|name| !name.trim().is_empty())
+}
+
+/// Environment variables defined by a profile, as (NAME, value) pairs
+pub type ProfileVars = Vec<(String, String)>;
+
+/// Environment variables defined by a profile
+pub fn profile_vars(contents: &str, profile: &str) -> anyhow::Result<ProfileVars> {
+    let table: toml::Table = contents.parse().context("invalid TOML")?;
+    let profiles = table
+        .get("profiles")
+        .and_then(|p| p.as_table())
+        .ok_or_else(|| anyhow!("no [profiles] table"))?;
+    let selected = profiles.get(profile).and_then(|p| p.as_table()).ok_or_else(|| {
+        let available: Vec<_> = profiles.keys().map(String::as_str).collect();
+        anyhow!("unknown profile '{}' (available: {})", profile, available.join(", "))
+    })?;
+
+    let mut vars = Vec::new();
+    for (key, value) in selected {
+        let value = match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Float(f) => f.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            // Lists become comma-separated values (e.g. cluster_peers)
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => bail!("profile '{}': unsupported value for '{}'", profile, key),
+        };
+        vars.push((key.to_uppercase(), value));
+    }
+    Ok(vars)
+}
+
+/// Read the selected profile from the profile file: its name and variables
+pub fn load_profile(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<(String, ProfileVars)>> {
+    let Some(profile) = selected_profile(args) else {
+        return Ok(None);
+    };
+    let path = std::env::var(CONFIG_FILE_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string());
+    let contents = std::fs::read_to_string(Path::new(&path))
+        .with_context(|| format!("profile '{}' selected but {} could not be read", profile, path))?;
+    let vars = profile_vars(&contents, &profile).with_context(|| format!("in {}", path))?;
+    Ok(Some((profile, vars)))
+}
+
+/// Apply the selected profile's values as environment defaults.
+/// Must run before the configuration is loaded. Returns the profile name, if any.
+pub fn apply_profile(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<String>> {
+    let Some((profile, vars)) = load_profile(args)? else {
+        return Ok(None);
+    };
+    for (name, value) in vars {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(Some(profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = r#"
+[profiles.dev]
+ssh_port = 2222
+tunnel_url = "localhost"
+proxy_protocol = false
+cluster_peers = ["http://a:9090", "http://b:9090"]
+
+[profiles.prod]
+tunnel_url = "tunnel.example.com"
+"#;
+
+    #[test]
+    fn test_selected_profile_from_args() {
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(selected_profile(args(&["tunnel", "--profile", "dev"])), Some("dev".to_string()));
+        assert_eq!(selected_profile(args(&["tunnel", "--profile=prod"])), Some("prod".to_string()));
+    }
+
+    #[test]
+    fn test_profile_vars() {
+        let mut vars = profile_vars(PROFILES, "dev").unwrap();
+        vars.sort();
+        assert_eq!(
+            vars,
+            vec![
+                ("CLUSTER_PEERS".to_string(), "http://a:9090,http://b:9090".to_string()),
+                ("PROXY_PROTOCOL".to_string(), "false".to_string()),
+                ("SSH_PORT".to_string(), "2222".to_string()),
+                ("TUNNEL_URL".to_string(), "localhost".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        let err = profile_vars(PROFILES, "staging").unwrap_err().to_string();
+        assert!(err.contains("unknown profile 'staging'"));
+        assert!(err.contains("dev"));
+        assert!(profile_vars("not = [toml", "dev").is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! Per-request access log for the HTTP proxy.
+//!
+//! Entries are written as JSON lines or in Apache combined format to a file or
+//! stdout, separate from the application log. Since the proxy passes bytes
+//! through untouched, the response status is sniffed from the first bytes the
+//! tunnel sends back.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::{get as get_config, AccessLogFormat};
+
+use super::close_reason::CloseReason;
+use super::{extract_header_from_raw, extract_request_target};
+
+/// A single proxied request
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    pub client_ip: String,
+    pub subdomain: Option<String>,
+    /// The tunnel's correlation ID, as registered with the web backend
+    pub correlation_id: Option<String>,
+    /// Identifies this visitor connection in the proxy's logs
+    pub connection_id: String,
+    pub method: String,
+    pub path: String,
+    /// Response status (None if the tunnel never answered with an HTTP status line)
+    pub status: Option<u16>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration_ms: u64,
+    /// Time until the tunnel's first response bytes (None if it never answered)
+    pub first_byte_ms: Option<u64>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// How the connection ended
+    pub close_reason: CloseReason,
+}
+
+fn serialize_timestamp<S: serde::Serializer>(ts: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&ts.to_rfc3339())
+}
+
+impl AccessLogEntry {
+    /// Start an entry from the buffered request bytes
+    pub fn new(client_addr: SocketAddr, request: &[u8]) -> Self {
+        let method = std::str::from_utf8(request)
+            .ok()
+            .and_then(|text| text.split_whitespace().next())
+            .unwrap_or("-")
+            .to_string();
+
+        Self {
+            timestamp: Utc::now(),
+            client_ip: client_addr.ip().to_string(),
+            subdomain: None,
+            correlation_id: None,
+            connection_id: format!("{:08x}", rand::random::<u32>()),
+            method,
+            path: extract_request_target(request).unwrap_or_else(|| "-".to_string()),
+            status: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            duration_ms: 0,
+            first_byte_ms: None,
+            user_agent: extract_header_from_raw(request, "user-agent"),
+            referer: extract_header_from_raw(request, "referer"),
+            // Until the connection reaches a tunnel
+            close_reason: CloseReason::Rejected,
+        }
+    }
+
+    /// Render as a JSON line
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Render in Apache combined format (subdomain, close reason, duration and
+    /// correlation ID appended)
+    pub fn to_combined(&self) -> String {
+        let quoted = |v: &Option<String>| v.as_deref().unwrap_or("-").replace('"', "\\\"");
+        format!(
+            "{} - - [{}] \"{} {} HTTP/1.1\" {} {} \"{}\" \"{}\" {} {} {}ms {}",
+            self.client_ip,
+            self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.status.map(|s| s.to_string()).unwrap_or_else(|| "-".to_string()),
+            self.bytes_out,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            self.subdomain.as_deref().unwrap_or("-"),
+            self.close_reason,
+            self.duration_ms,
+            self.correlation_id.as_deref().unwrap_or("-")
+        )
+    }
+
+    /// Note when the tunnel's first response bytes came in
+    pub fn set_first_byte(&mut self, started: Instant, at: Instant) {
+        self.first_byte_ms = Some(at.saturating_duration_since(started).as_millis() as u64);
+    }
+
+    /// Fill in timing and write the entry to the access log (no-op if disabled).
+    /// Returns the completed entry for the session's live view.
+    pub fn finish(mut self, started: Instant) -> Self {
+        self.duration_ms = started.elapsed().as_millis() as u64;
+        write_entry(&self);
+        self
+    }
+}
+
+static SINK: OnceLock<Option<Mutex<Box<dyn Write + Send>>>> = OnceLock::new();
+
+/// Open the configured access log destination on first use
+fn sink() -> Option<&'static Mutex<Box<dyn Write + Send>>> {
+    SINK.get_or_init(|| {
+        let target = get_config().access_log.as_deref()?;
+        let writer: Box<dyn Write + Send> = match target {
+            "stdout" | "-" => Box::new(std::io::stdout()),
+            path => match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    error!("Failed to open access log {}: {}", path, e);
+                    return None;
+                }
+            },
+        };
+        Some(Mutex::new(writer))
+    })
+    .as_ref()
+}
+
+fn write_entry(entry: &AccessLogEntry) {
+    let Some(sink) = sink() else {
+        return;
+    };
+    let line = match get_config().access_log_format {
+        AccessLogFormat::Json => entry.to_json(),
+        AccessLogFormat::Combined => entry.to_combined(),
+    };
+    if let Ok(mut writer) = sink.lock() {
+        let _ = writeln!(writer, "{}", line);
+        let _ = writer.flush();
+    }
+}
+
+/// Parse the status code from the start of an HTTP response
+fn parse_status(data: &[u8]) -> Option<u16> {
+    let text = std::str::from_utf8(data.get(..12)?).ok()?;
+    if !text.starts_with("HTTP/") {
+        return None;
+    }
+    text.split(' ').nth(1)?.parse().ok()
+}
+
+/// Stream wrapper that records the response status from the first bytes read
+/// and when they came in
+pub struct StatusSniffer<S> {
+    inner: S,
+    head: Vec<u8>,
+    status: Option<u16>,
+    first_read: Option<Instant>,
+}
+
+impl<S> StatusSniffer<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            head: Vec::with_capacity(12),
+            status: None,
+            first_read: None,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    pub fn first_read(&self) -> Option<Instant> {
+        self.first_read
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for StatusSniffer<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if self.first_read.is_none() && buf.filled().len() > before {
+            self.first_read = Some(Instant::now());
+        }
+        if self.head.len() < 12 {
+            let read = &buf.filled()[before..];
+            let take = read.len().min(12 - self.head.len());
+            let chunk = read[..take].to_vec();
+            self.head.extend_from_slice(&chunk);
+            if self.head.len() == 12 {
+                self.status = parse_status(&self.head);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StatusSniffer<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn entry() -> AccessLogEntry {
+        let request = b"GET /api/users?id=1 HTTP/1.1\r\nHost: app.localhost\r\nUser-Agent: curl/8.0\r\n\r\n";
+        let mut entry = AccessLogEntry::new("203.0.113.5:40000".parse().unwrap(), request);
+        entry.subdomain = Some("app".to_string());
+        entry.correlation_id = Some("9f2c4e1a7b3d5f60".to_string());
+        entry.status = Some(200);
+        entry.bytes_out = 512;
+        entry.close_reason = CloseReason::UpstreamEof;
+        entry
+    }
+
+    #[test]
+    fn test_entry_from_request() {
+        let entry = entry();
+        assert_eq!(entry.method, "GET");
+        assert_eq!(entry.path, "/api/users?id=1");
+        assert_eq!(entry.client_ip, "203.0.113.5");
+        assert_eq!(entry.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(entry.referer, None);
+    }
+
+    #[test]
+    fn test_entry_formats() {
+        let entry = entry();
+        let json: serde_json::Value = serde_json::from_str(&entry.to_json()).unwrap();
+        assert_eq!(json["subdomain"], "app");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes_out"], 512);
+        assert_eq!(json["correlation_id"], "9f2c4e1a7b3d5f60");
+        assert_eq!(json["connection_id"].as_str().map(str::len), Some(8));
+        assert_eq!(json["close_reason"], "upstream_eof");
+
+        let combined = entry.to_combined();
+        assert!(combined.starts_with("203.0.113.5 - - ["));
+        assert!(combined.contains("\"GET /api/users?id=1 HTTP/1.1\" 200 512 \"-\" \"curl/8.0\" app upstream_eof "));
+        assert!(combined.ends_with("ms 9f2c4e1a7b3d5f60"));
+    }
+
+    #[tokio::test]
+    async fn test_status_sniffer() {
+        assert_eq!(parse_status(b"HTTP/1.1 404 Not Found"), Some(404));
+        assert_eq!(parse_status(b"SSH-2.0-OpenSSH"), None);
+
+        let mut sniffer = StatusSniffer::new(&b"HTTP/1.1 201 Created\r\n\r\n"[..]);
+        assert_eq!(sniffer.first_read(), None);
+        let mut out = Vec::new();
+        sniffer.read_to_end(&mut out).await.unwrap();
+        assert_eq!(sniffer.status(), Some(201));
+        assert!(sniffer.first_read().is_some());
+    }
+}
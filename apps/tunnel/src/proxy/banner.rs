@@ -0,0 +1,151 @@
+//! Preview banner injection.
+//!
+//! Tunnels with the banner enabled get a small fixed bar inserted before
+//! `</body>` in HTML responses, so a tunnel preview can't be mistaken for
+//! production. The request is sent without `Accept-Encoding` so the page comes
+//! back uncompressed, and `Content-Length` is fixed up after injection.
+//! Responses that aren't plain, length-delimited HTML pass through untouched.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::extract_header_from_raw;
+use super::rewrite::read_response_head;
+
+/// Request headers dropped so the page comes back uncompressed
+pub const DROPPED_REQUEST_HEADERS: &[&str] = &["accept-encoding"];
+
+/// The injected snippet
+pub const BANNER_HTML: &str = concat!(
+    "<div id=\"exlo-preview-banner\" style=\"position:fixed;bottom:0;left:0;right:0;z-index:2147483647;",
+    "padding:4px 8px;background:#f59e0b;color:#111;font:12px/1.4 sans-serif;text-align:center\">",
+    "Preview served through an EXLO tunnel &mdash; not production</div>"
+);
+
+/// Largest HTML body that gets a banner; bigger pages pass through
+const MAX_INJECT_BODY: usize = 2 * 1024 * 1024;
+
+/// Body length of a response the banner can be injected into
+fn injectable_length(head: &[u8]) -> Option<usize> {
+    let content_type = extract_header_from_raw(head, "content-type")?;
+    if !content_type.to_lowercase().starts_with("text/html") {
+        return None;
+    }
+    let encoding = extract_header_from_raw(head, "content-encoding");
+    if encoding.is_some_and(|e| !e.eq_ignore_ascii_case("identity")) {
+        return None;
+    }
+    let length: usize = extract_header_from_raw(head, "content-length")?.parse().ok()?;
+    (length <= MAX_INJECT_BODY).then_some(length)
+}
+
+/// Insert the banner before the last `</body>`, if there is one
+pub fn inject_banner(body: &[u8]) -> Option<Vec<u8>> {
+    let lower = body.to_ascii_lowercase();
+    let pos = lower.windows(7).rposition(|w| w == b"</body>")?;
+    let mut injected = Vec::with_capacity(body.len() + BANNER_HTML.len());
+    injected.extend_from_slice(&body[..pos]);
+    injected.extend_from_slice(BANNER_HTML.as_bytes());
+    injected.extend_from_slice(&body[pos..]);
+    Some(injected)
+}
+
+/// Replace the `Content-Length` value in a response head
+fn set_content_length(head: &[u8], length: usize) -> Vec<u8> {
+    let text = String::from_utf8_lossy(head);
+    let mut rewritten = String::with_capacity(head.len());
+    for line in text.split_inclusive("\r\n") {
+        if line.to_lowercase().starts_with("content-length:") {
+            rewritten.push_str(&format!("Content-Length: {}\r\n", length));
+        } else {
+            rewritten.push_str(line);
+        }
+    }
+    rewritten.into_bytes()
+}
+
+/// Forward one response from the tunnel to the visitor, injecting the banner
+/// when possible. Returns the bytes written to the visitor.
+pub async fn forward_response<R, W>(upstream: &mut R, client: &mut W) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    let head_end = read_response_head(upstream, &mut buf).await?;
+
+    if let Some(head_end) = head_end {
+        if let Some(length) = injectable_length(&buf[..head_end]) {
+            while buf.len() < head_end + length {
+                let n = upstream.read(&mut chunk).await?;
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let body_end = (head_end + length).min(buf.len());
+            if body_end == head_end + length {
+                if let Some(body) = inject_banner(&buf[head_end..body_end]) {
+                    let mut response = set_content_length(&buf[..head_end], body.len());
+                    response.extend_from_slice(&body);
+                    response.extend_from_slice(&buf[body_end..]);
+                    client.write_all(&response).await?;
+                    let rest = tokio::io::copy(upstream, client).await?;
+                    return Ok(response.len() as u64 + rest);
+                }
+            }
+        }
+    }
+
+    client.write_all(&buf).await?;
+    let rest = tokio::io::copy(upstream, client).await?;
+    Ok(buf.len() as u64 + rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_banner() {
+        let injected = inject_banner(b"<html><BODY><p>hi</p></BODY></html>").unwrap();
+        let text = String::from_utf8(injected).unwrap();
+        assert!(text.starts_with("<html><BODY><p>hi</p><div id=\"exlo-preview-banner\""));
+        assert!(text.ends_with("</div></BODY></html>"));
+        assert!(inject_banner(b"{\"json\": true}").is_none());
+    }
+
+    #[test]
+    fn test_injectable_length() {
+        let html = b"HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: 20\r\n\r\n";
+        assert_eq!(injectable_length(html), Some(20));
+        let gzip = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: gzip\r\nContent-Length: 20\r\n\r\n";
+        assert_eq!(injectable_length(gzip), None);
+        let chunked = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(injectable_length(chunked), None);
+        let json = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n";
+        assert_eq!(injectable_length(json), None);
+    }
+
+    #[tokio::test]
+    async fn test_forward_response() {
+        let body = "<html><body>hello</body></html>";
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut out = Vec::new();
+        let written = forward_response(&mut response.as_bytes(), &mut out).await.unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(written as usize, text.len());
+        assert!(text.contains(&format!("Content-Length: {}\r\n", body.len() + BANNER_HTML.len())));
+        assert!(text.contains("exlo-preview-banner"));
+
+        // Non-HTML passes through byte for byte
+        let json = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        let mut out = Vec::new();
+        forward_response(&mut json.as_bytes(), &mut out).await.unwrap();
+        assert_eq!(out, json.as_bytes());
+    }
+}
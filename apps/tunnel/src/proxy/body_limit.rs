@@ -0,0 +1,166 @@
+//! `MAX_REQUEST_BODY_BYTES` for bodies whose size isn't known up front.
+//!
+//! A declared `Content-Length` over the limit is refused before anything is
+//! forwarded. Everything else (chunked bodies, and body bytes arriving after
+//! the request head) is counted as it is relayed: the visitor's stream is
+//! wrapped so that reading past the limit fails with `FileTooLarge`, which
+//! ends the connection. Requests are sent with `Connection: close` while a
+//! limit is set, so each body is counted on a connection of its own. Bytes
+//! after the body (an upgraded WebSocket connection) are not counted.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::framing::{parse_request_head, BodyFraming};
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, "Request body too large")
+}
+
+/// Payload bytes a request body may still carry
+#[derive(Debug)]
+pub struct BodyLimit {
+    framing: BodyFraming,
+    remaining: u64,
+    payload: Vec<u8>,
+    upgrade: bool,
+}
+
+impl BodyLimit {
+    /// Limit for the body of `request` (whose head is `head_len` bytes long if
+    /// complete), counting what was already read of it. Fails if the body is
+    /// declared or already known to be larger than `max`. A body that can't
+    /// be framed counts everything after the head.
+    pub fn new(request: &[u8], head_len: Option<usize>, max: u64) -> io::Result<Self> {
+        let head_len = head_len.unwrap_or(request.len());
+        let head = parse_request_head(&request[..head_len]);
+        let upgrade = head.as_ref().is_some_and(|head| head.upgrade);
+        let framing = head.and_then(|head| head.framing).unwrap_or(BodyFraming::Close);
+        if matches!(framing, BodyFraming::Length(len) if len > max) {
+            return Err(too_large());
+        }
+        let mut limit = Self {
+            framing,
+            remaining: max,
+            payload: Vec::new(),
+            upgrade,
+        };
+        limit.count(&request[head_len..])?;
+        Ok(limit)
+    }
+
+    /// Whether the request has to be sent with `Connection: close`, so that a
+    /// next request arrives on a new connection with a limit of its own
+    /// (an upgrade request is the last HTTP message on its connection)
+    pub fn needs_close(&self) -> bool {
+        !self.upgrade
+    }
+
+    /// Count body bytes read from the visitor
+    fn count(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() || self.framing.is_complete() {
+            return Ok(());
+        }
+        self.payload.clear();
+        let (_, done) = self
+            .framing
+            .decode(data, &mut self.payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.remaining = self
+            .remaining
+            .checked_sub(self.payload.len() as u64)
+            .ok_or_else(too_large)?;
+        if done {
+            self.framing = BodyFraming::Length(0);
+        }
+        Ok(())
+    }
+}
+
+/// The visitor's stream, failing reads once its request body is too large
+pub struct BodyLimited<S> {
+    inner: S,
+    limit: Option<BodyLimit>,
+}
+
+impl<S> BodyLimited<S> {
+    /// Without a limit the stream is passed through untouched
+    pub fn new(inner: S, limit: Option<BodyLimit>) -> Self {
+        Self { inner, limit }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for BodyLimited<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(limit) = this.limit.as_mut() {
+            if let Err(e) = limit.count(&buf.filled()[before..]) {
+                buf.set_filled(before);
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for BodyLimited<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, data)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CHUNKED: &[u8] = b"POST /upload HTTP/1.1\r\nHost: app\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[test]
+    fn test_declared_length() {
+        let request = b"POST / HTTP/1.1\r\nHost: app\r\nContent-Length: 11\r\n\r\nhello";
+        assert!(BodyLimit::new(request, Some(request.len() - 5), 10).is_err());
+        let limit = BodyLimit::new(request, Some(request.len() - 5), 11).unwrap();
+        assert_eq!(limit.remaining, 6);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_is_counted() {
+        // Chunk framing doesn't count, the payload does
+        let limit = BodyLimit::new(CHUNKED, Some(CHUNKED.len()), 8).unwrap();
+        let (mut visitor, client) = tokio::io::duplex(1024);
+        let mut limited = BodyLimited::new(client, Some(limit));
+        visitor.write_all(b"4\r\nabcd\r\n4\r\nefgh\r\n").await.unwrap();
+        let mut buf = [0u8; 64];
+        assert!(limited.read(&mut buf).await.unwrap() > 0);
+
+        visitor.write_all(b"1\r\ni\r\n").await.unwrap();
+        let error = limited.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[tokio::test]
+    async fn test_bytes_after_the_body_pass() {
+        let request = b"GET /ws HTTP/1.1\r\nHost: app\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let limit = BodyLimit::new(request, Some(request.len()), 4).unwrap();
+        assert!(!limit.needs_close());
+        let (mut visitor, client) = tokio::io::duplex(1024);
+        let mut limited = BodyLimited::new(client, Some(limit));
+        visitor.write_all(&[0u8; 100]).await.unwrap();
+        let mut buf = [0u8; 128];
+        assert_eq!(limited.read(&mut buf).await.unwrap(), 100);
+    }
+}
@@ -0,0 +1,226 @@
+//! HTTP caching rules for the tunnel response cache.
+//!
+//! Only plain `GET` requests without credentials take part. A stored copy is
+//! served while it is fresh by the origin's `Cache-Control` (`s-maxage`,
+//! `max-age`) or `Expires`; visitors asking for `no-cache` go to the origin
+//! and refresh it. Responses are stored only when the origin says they may be
+//! shared: a `200` with an explicit lifetime, no `no-store`, `private` or
+//! `no-cache`, no `Set-Cookie` and no `Vary: *`. Cacheable requests go out
+//! with `Connection: close` so the recorded stream (see `recording`) holds
+//! exactly their response.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::DateTime;
+
+use super::framing::{parse_request_head, parse_response_head, BodyFraming};
+use super::rewrite::head_len;
+use super::{extract_header_from_raw, extract_header_values, extract_request_target, UPSTREAM_HEADER};
+use crate::state::header_rules::HeaderRules;
+use crate::state::response_cache::{CacheEntry, CacheHit};
+
+/// A request the cache may answer or learn from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheRequest {
+    /// Method and target, as forwarded to the tunnel
+    pub key: String,
+    /// The visitor accepts a stored response (false for `no-cache`)
+    pub lookup: bool,
+}
+
+/// `Cache-Control` directives of a message, lowercased
+fn cache_control(head: &[u8]) -> Vec<String> {
+    extract_header_values(head, "cache-control")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_lowercase())
+        .filter(|directive| !directive.is_empty())
+        .collect()
+}
+
+/// Seconds of a `name=N` directive
+fn directive_secs(directives: &[String], name: &str) -> Option<u64> {
+    directives.iter().find_map(|directive| {
+        let (key, value) = directive.split_once('=')?;
+        (key.trim() == name).then(|| value.trim().trim_matches('"').parse().ok())?
+    })
+}
+
+/// Whether the cache handles `request`, whose head is `head_len` bytes and
+/// whose target after path routing is `routed`
+pub fn cache_request(request: &[u8], head_len: Option<usize>, routed: Option<&str>) -> Option<CacheRequest> {
+    let head = &request[..head_len?];
+    // Credentials, and upstream overrides that could send the same target to another service
+    let skipped = ["authorization", UPSTREAM_HEADER];
+    if !head.starts_with(b"GET ") || skipped.iter().any(|name| extract_header_from_raw(head, name).is_some()) {
+        return None;
+    }
+    let parsed = parse_request_head(head)?;
+    if parsed.upgrade || parsed.framing != Some(BodyFraming::Length(0)) {
+        return None;
+    }
+    let directives = cache_control(head);
+    if directives.iter().any(|d| d == "no-store") {
+        return None;
+    }
+    let pragma_no_cache = extract_header_from_raw(head, "pragma").is_some_and(|p| p.eq_ignore_ascii_case("no-cache"));
+    let no_cache = directives.iter().any(|d| d == "no-cache") || directive_secs(&directives, "max-age") == Some(0);
+    let target = routed.map(str::to_string).or_else(|| extract_request_target(head))?;
+    Some(CacheRequest {
+        key: format!("GET {}", target),
+        lookup: !no_cache && !pragma_no_cache,
+    })
+}
+
+/// How long a response may be served from the cache (None = not storable)
+fn freshness(head: &[u8]) -> Option<Duration> {
+    let directives = cache_control(head);
+    let forbidden = ["no-store", "private", "no-cache"];
+    if directives.iter().any(|d| forbidden.contains(&d.split('=').next().unwrap_or(""))) {
+        return None;
+    }
+    let secs = directive_secs(&directives, "s-maxage")
+        .or_else(|| directive_secs(&directives, "max-age"))
+        .or_else(|| {
+            let expires = DateTime::parse_from_rfc2822(&extract_header_from_raw(head, "expires")?).ok()?;
+            let date = DateTime::parse_from_rfc2822(&extract_header_from_raw(head, "date")?).ok()?;
+            u64::try_from((expires - date).num_seconds()).ok()
+        })?;
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// The entry to keep for the response recorded for `request`, if it may be
+/// stored
+pub fn storable(recorded: &[u8], request: &[u8]) -> Option<CacheEntry> {
+    let head_end = head_len(recorded)?;
+    let head = &recorded[..head_end];
+    let parsed = parse_response_head(head, "GET")?;
+    if parsed.status != Some(200) || extract_header_from_raw(head, "set-cookie").is_some() {
+        return None;
+    }
+    let fresh_for = freshness(head)?;
+    let mut vary = Vec::new();
+    for name in extract_header_values(head, "vary").iter().flat_map(|value| value.split(',')) {
+        let name = name.trim().to_lowercase();
+        if name == "*" {
+            return None;
+        }
+        if !name.is_empty() {
+            let value = extract_header_from_raw(request, &name);
+            vary.push((name, value));
+        }
+    }
+
+    // Only bodies known to be complete; a close-delimited one may have been cut short
+    let mut framing = parsed.framing.filter(|framing| *framing != BodyFraming::Close)?;
+    let mut body = Vec::new();
+    let (_, done) = framing.decode(&recorded[head_end..], &mut body).ok()?;
+    if !done {
+        return None;
+    }
+    let age = extract_header_from_raw(head, "age")
+        .and_then(|age| age.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    Some(CacheEntry {
+        head: head.to_vec(),
+        body: Bytes::from(body),
+        vary,
+        fresh_for,
+        age,
+    })
+}
+
+/// The response sent for a cache hit: length-delimited, with its age and the
+/// tunnel's current header rules
+pub fn cached_response(hit: &CacheHit, rules: &HeaderRules) -> Vec<u8> {
+    let text = String::from_utf8_lossy(&hit.head);
+    let mut head = String::with_capacity(hit.head.len() + 96);
+    for line in text.split_inclusive("\r\n").filter(|line| *line != "\r\n") {
+        let name = line.split(':').next().unwrap_or("").trim().to_lowercase();
+        let framing = ["content-length", "transfer-encoding", "connection", "keep-alive", "age"];
+        if !framing.contains(&name.as_str()) {
+            head.push_str(line);
+        }
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nAge: {}\r\nX-Cache: HIT\r\nConnection: close\r\n\r\n",
+        hit.body.len(),
+        hit.age.as_secs()
+    ));
+    let mut response = if rules.is_empty() {
+        head.into_bytes()
+    } else {
+        rules.apply(head.as_bytes())
+    };
+    response.extend_from_slice(&hit.body);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> Option<CacheRequest> {
+        let head = head.as_bytes();
+        cache_request(head, head_len(head), None)
+    }
+
+    #[test]
+    fn test_cache_request() {
+        let plain = request("GET /app.js?v=2 HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(plain.key, "GET /app.js?v=2");
+        assert!(plain.lookup);
+        let reload = request("GET /app.js HTTP/1.1\r\nCache-Control: no-cache\r\n\r\n").unwrap();
+        assert!(!reload.lookup);
+
+        assert!(request("POST /api HTTP/1.1\r\nContent-Length: 2\r\n\r\n").is_none());
+        assert!(request("GET /me HTTP/1.1\r\nAuthorization: Bearer x\r\n\r\n").is_none());
+        assert!(request("GET / HTTP/1.1\r\nCache-Control: no-store\r\n\r\n").is_none());
+        assert!(request("GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_freshness() {
+        assert_eq!(
+            freshness(b"HTTP/1.1 200 OK\r\nCache-Control: public, max-age=60\r\n\r\n"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            freshness(b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60, s-maxage=600\r\n\r\n"),
+            Some(Duration::from_secs(600))
+        );
+        let expires = b"HTTP/1.1 200 OK\r\nDate: Wed, 21 Oct 2015 07:28:00 GMT\r\nExpires: Wed, 21 Oct 2015 08:28:00 GMT\r\n\r\n";
+        assert_eq!(freshness(expires), Some(Duration::from_secs(3600)));
+        assert_eq!(freshness(b"HTTP/1.1 200 OK\r\nCache-Control: private, max-age=60\r\n\r\n"), None);
+        assert_eq!(freshness(b"HTTP/1.1 200 OK\r\nCache-Control: max-age=0\r\n\r\n"), None);
+        assert_eq!(freshness(b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_store_and_serve() {
+        let request = b"GET /app.css HTTP/1.1\r\nHost: a\r\nAccept-Encoding: gzip\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nCache-Control: max-age=300\r\nVary: Accept-Encoding\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n0\r\n\r\n";
+        let entry = storable(response, request).unwrap();
+        assert_eq!(entry.body, Bytes::from("body"));
+        assert_eq!(entry.vary, vec![("accept-encoding".to_string(), Some("gzip".to_string()))]);
+
+        let hit = CacheHit {
+            head: entry.head,
+            body: entry.body,
+            age: Duration::from_secs(7),
+        };
+        let served = String::from_utf8(cached_response(&hit, &HeaderRules::default())).unwrap();
+        assert!(served.contains("Content-Length: 4\r\nAge: 7\r\n"));
+        assert!(!served.contains("Transfer-Encoding"));
+        assert!(served.ends_with("\r\n\r\nbody"));
+
+        // Cut short, cookies and wildcard variance aren't stored
+        assert!(storable(&response[..response.len() - 5], request).is_none());
+        let cookie = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nSet-Cookie: a=1\r\nContent-Length: 0\r\n\r\n";
+        assert!(storable(cookie, request).is_none());
+        let any = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nVary: *\r\nContent-Length: 0\r\n\r\n";
+        assert!(storable(any, request).is_none());
+    }
+}
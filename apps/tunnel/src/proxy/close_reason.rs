@@ -0,0 +1,118 @@
+//! Why a proxied connection closed.
+//!
+//! Every visitor connection ends with one reason. It goes into the access
+//! log entry, is counted per tunnel (`close_reasons` in the tunnel list and
+//! `stats`), and the counts are kept in the user's tunnel history.
+
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+use super::relay::RelayEnd;
+
+/// How a proxied connection ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The visitor finished first
+    ClientEof,
+    /// The tunneled service finished first
+    UpstreamEof,
+    /// Nothing moved either way for the idle timeout
+    IdleTimeout,
+    /// Refused by the tunnel's rate or connection limit, or cut off by the
+    /// request body limit
+    LimitExceeded,
+    /// The tunnel was removed (kicked, or its session ended) mid-connection
+    Kicked,
+    /// Answered or dropped by the proxy without reaching a tunnel
+    Rejected,
+    /// Answered from the tunnel's response cache
+    CacheHit,
+    /// Failed; the kind is a short snake_case label (e.g. `connection_reset`)
+    Error(String),
+}
+
+impl CloseReason {
+    /// `error:<kind>` for an I/O error
+    pub fn from_io(e: &std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::FileTooLarge {
+            // The request body went over `MAX_REQUEST_BODY_BYTES`
+            return Self::LimitExceeded;
+        }
+        Self::error(&format!("{:?}", e.kind()))
+    }
+
+    /// `error:<kind>`, with `kind` converted to snake_case
+    pub fn error(kind: &str) -> Self {
+        let mut label = String::with_capacity(kind.len() + 4);
+        for (i, c) in kind.chars().enumerate() {
+            if c.is_ascii_uppercase() {
+                if i > 0 {
+                    label.push('_');
+                }
+                label.push(c.to_ascii_lowercase());
+            } else {
+                label.push(c);
+            }
+        }
+        Self::Error(label)
+    }
+
+    /// How a relay ended
+    pub fn from_relay(end: &RelayEnd) -> Self {
+        match end {
+            RelayEnd::ClientEof => Self::ClientEof,
+            RelayEnd::UpstreamEof => Self::UpstreamEof,
+            RelayEnd::IdleTimeout => Self::IdleTimeout,
+            RelayEnd::WriteTimeout => Self::error("WriteTimeout"),
+            RelayEnd::Error(e) => Self::from_io(e),
+        }
+    }
+
+    /// Whether the end says nothing about the tunnel going away
+    pub fn is_orderly(&self) -> bool {
+        matches!(
+            self,
+            Self::ClientEof | Self::IdleTimeout | Self::LimitExceeded | Self::Rejected | Self::CacheHit
+        )
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ClientEof => f.write_str("client_eof"),
+            Self::UpstreamEof => f.write_str("upstream_eof"),
+            Self::IdleTimeout => f.write_str("idle_timeout"),
+            Self::LimitExceeded => f.write_str("limit_exceeded"),
+            Self::Kicked => f.write_str("kicked"),
+            Self::Rejected => f.write_str("rejected"),
+            Self::CacheHit => f.write_str("cache_hit"),
+            Self::Error(kind) => write!(f, "error:{}", kind),
+        }
+    }
+}
+
+impl Serialize for CloseReason {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(CloseReason::ClientEof.to_string(), "client_eof");
+        assert_eq!(CloseReason::LimitExceeded.to_string(), "limit_exceeded");
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(CloseReason::from_io(&reset).to_string(), "error:connection_reset");
+        assert_eq!(
+            CloseReason::from_relay(&RelayEnd::WriteTimeout).to_string(),
+            "error:write_timeout"
+        );
+        assert_eq!(serde_json::to_string(&CloseReason::Kicked).unwrap(), "\"kicked\"");
+    }
+}
@@ -0,0 +1,320 @@
+//! Response compression at the edge.
+//!
+//! With `RESPONSE_COMPRESSION` on, compressible responses (text, JSON,
+//! JavaScript, SVG, ...) are brotli or gzip encoded for visitors that accept
+//! it, so a dev server that doesn't compress still loads quickly over a slow
+//! link. The request keeps its `Accept-Encoding`: a service that compresses
+//! by itself is passed through untouched. The compressed body is sent chunked
+//! and flushed after every read, so streamed responses aren't held back.
+
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::framing::{parse_response_head, BodyFraming};
+use super::rewrite::read_response_head;
+use super::{extract_header_from_raw, extract_header_values};
+
+/// Bodies declared smaller than this aren't worth compressing
+const MIN_COMPRESS_BODY: u64 = 1024;
+
+/// Brotli quality and window for on-the-fly compression
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// Content encodings the proxy can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// Quality the visitor gave `coding` in Accept-Encoding (0 = not acceptable)
+fn quality(accepted: &[String], coding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for entry in accepted.iter().flat_map(|value| value.split(',')) {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+/// The encoding to compress the response to `request` with, if the visitor
+/// accepts one (HEAD responses have no body to compress)
+pub fn negotiate(request: &[u8]) -> Option<Encoding> {
+    if request.starts_with(b"HEAD ") {
+        return None;
+    }
+    let accepted = extract_header_values(request, "accept-encoding");
+    let brotli = quality(&accepted, Encoding::Brotli.as_str());
+    let gzip = quality(&accepted, Encoding::Gzip.as_str());
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Media types that shrink when compressed (event streams are left alone so
+/// events aren't delayed)
+fn compressible_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    if mime == "text/event-stream" {
+        return false;
+    }
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm" | "image/svg+xml"
+        )
+}
+
+/// Body framing of a response the proxy should compress
+fn compressible_response(head: &[u8]) -> Option<BodyFraming> {
+    // HEAD requests are never compressed, so any method with a body will do
+    let parsed = parse_response_head(head, "GET")?;
+    if parsed.status != Some(200) {
+        return None;
+    }
+    let content_type = extract_header_from_raw(head, "content-type")?;
+    if !compressible_type(&content_type) {
+        return None;
+    }
+    let encoding = extract_header_from_raw(head, "content-encoding");
+    if encoding.is_some_and(|e| !e.eq_ignore_ascii_case("identity")) {
+        return None;
+    }
+    let no_transform = extract_header_values(head, "cache-control")
+        .iter()
+        .any(|value| value.to_lowercase().contains("no-transform"));
+    match parsed.framing? {
+        _ if no_transform => None,
+        BodyFraming::Length(length) if length < MIN_COMPRESS_BODY => None,
+        framing => Some(framing),
+    }
+}
+
+/// The response head for the compressed body: chunked instead of the
+/// original framing, and a weak ETag since the bytes differ
+fn compressed_head(head: &[u8], encoding: Encoding) -> Vec<u8> {
+    let text = String::from_utf8_lossy(head);
+    let mut rewritten = String::with_capacity(head.len() + 96);
+    for line in text.split_inclusive("\r\n").filter(|line| *line != "\r\n") {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        match name.trim().to_lowercase().as_str() {
+            "content-length" | "transfer-encoding" | "content-encoding" => {}
+            "etag" if !value.trim().starts_with("W/") => {
+                rewritten.push_str(&format!("ETag: W/{}\r\n", value.trim()));
+            }
+            _ => rewritten.push_str(line),
+        }
+    }
+    rewritten.push_str(&format!(
+        "Content-Encoding: {}\r\nVary: Accept-Encoding\r\nTransfer-Encoding: chunked\r\n\r\n",
+        encoding.as_str()
+    ));
+    rewritten.into_bytes()
+}
+
+/// Streaming compressor writing into a buffer
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        }
+    }
+
+    /// Compress `data` and return everything encoded so far
+    fn push(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// End the stream, returning the remaining output
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli(encoder) => Ok(encoder.into_inner()),
+            Self::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Write `data` as one chunk (nothing for no data: that would end the body)
+async fn write_chunk<W: AsyncWrite + Unpin>(client: &mut W, data: &[u8]) -> std::io::Result<u64> {
+    if data.is_empty() {
+        return Ok(0);
+    }
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    client.write_all(&chunk).await?;
+    Ok(chunk.len() as u64)
+}
+
+/// Forward one response from the tunnel to the visitor, compressed with
+/// `encoding` when worthwhile. Returns the bytes written to the visitor.
+pub async fn forward_response<R, W>(upstream: &mut R, client: &mut W, encoding: Encoding) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(4096);
+    let head_end = read_response_head(upstream, &mut buf).await?;
+    let framing = head_end.and_then(|end| Some((end, compressible_response(&buf[..end])?)));
+    let Some((head_end, mut framing)) = framing else {
+        client.write_all(&buf).await?;
+        let rest = tokio::io::copy(upstream, client).await?;
+        return Ok(buf.len() as u64 + rest);
+    };
+
+    let head = compressed_head(&buf[..head_end], encoding);
+    client.write_all(&head).await?;
+    let mut written = head.len() as u64;
+
+    let mut encoder = Encoder::new(encoding);
+    let mut payload = Vec::new();
+    let invalid = |e: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string());
+    let (_, mut done) = framing.decode(&buf[head_end..], &mut payload).map_err(invalid)?;
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        written += write_chunk(client, &encoder.push(&payload)?).await?;
+        payload.clear();
+        if done {
+            break;
+        }
+        let n = upstream.read(&mut chunk).await?;
+        if n == 0 {
+            if framing != BodyFraming::Close {
+                // Leave the chunked body unterminated so the visitor sees the truncation
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            break;
+        }
+        (_, done) = framing.decode(&chunk[..n], &mut payload).map_err(invalid)?;
+    }
+    written += write_chunk(client, &encoder.finish()?).await?;
+    client.write_all(b"0\r\n\r\n").await?;
+    Ok(written + 5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        let request = |accept: &str| format!("GET / HTTP/1.1\r\nHost: a\r\nAccept-Encoding: {}\r\n\r\n", accept);
+        assert_eq!(negotiate(request("gzip, deflate, br").as_bytes()), Some(Encoding::Brotli));
+        assert_eq!(negotiate(request("gzip, br;q=0.5").as_bytes()), Some(Encoding::Gzip));
+        assert_eq!(negotiate(request("br;q=0, *").as_bytes()), Some(Encoding::Gzip));
+        assert_eq!(negotiate(request("identity").as_bytes()), None);
+        assert_eq!(negotiate(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"), None);
+        assert_eq!(negotiate(b"HEAD / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_compressible_response() {
+        let chunked = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(compressible_response(chunked), Some(BodyFraming::chunked()));
+        let json = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 4096\r\n\r\n";
+        assert_eq!(compressible_response(json), Some(BodyFraming::Length(4096)));
+
+        let small = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 12\r\n\r\n";
+        assert_eq!(compressible_response(small), None);
+        let image = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 4096\r\n\r\n";
+        assert_eq!(compressible_response(image), None);
+        let gzip = b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nContent-Encoding: gzip\r\n\r\n";
+        assert_eq!(compressible_response(gzip), None);
+        let events = b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n";
+        assert_eq!(compressible_response(events), None);
+        let no_transform = b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nCache-Control: no-transform\r\n\r\n";
+        assert_eq!(compressible_response(no_transform), None);
+        let partial = b"HTTP/1.1 206 Partial Content\r\nContent-Type: text/plain\r\nContent-Length: 4096\r\n\r\n";
+        assert_eq!(compressible_response(partial), None);
+    }
+
+    #[tokio::test]
+    async fn test_forward_response() {
+        let body = "<p>hello, compressed world</p>\n".repeat(100);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        for encoding in [Encoding::Gzip, Encoding::Brotli] {
+            let mut out = Vec::new();
+            let written = forward_response(&mut response.as_bytes(), &mut out, encoding).await.unwrap();
+            assert_eq!(written as usize, out.len());
+
+            let head_end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let head = String::from_utf8_lossy(&out[..head_end]).to_string();
+            assert!(head.contains(&format!("Content-Encoding: {}\r\n", encoding.as_str())));
+            assert!(head.contains("ETag: W/\"v1\"\r\n"));
+            assert!(!head.contains("Content-Length"));
+
+            let mut framing = BodyFraming::chunked();
+            let mut compressed = Vec::new();
+            assert!(framing.decode(&out[head_end..], &mut compressed).unwrap().1);
+            assert!(compressed.len() < body.len());
+            let mut decoded = String::new();
+            match encoding {
+                Encoding::Gzip => flate2::read::GzDecoder::new(compressed.as_slice()).read_to_string(&mut decoded),
+                Encoding::Brotli => brotli::Decompressor::new(compressed.as_slice(), 4096).read_to_string(&mut decoded),
+            }
+            .unwrap();
+            assert_eq!(decoded, body);
+        }
+
+        // Images pass through byte for byte
+        let png = "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 4\r\n\r\n\x00PNG";
+        let mut out = Vec::new();
+        forward_response(&mut png.as_bytes(), &mut out, Encoding::Gzip).await.unwrap();
+        assert_eq!(out, png.as_bytes());
+    }
+}
@@ -0,0 +1,293 @@
+//! HTTP/1.1 message framing.
+//!
+//! Pooled channels carry one exchange after another, so the proxy has to know
+//! where each request and response body ends (RFC 9112 section 6): a
+//! `Content-Length`, chunked transfer coding, or (responses only) the end of
+//! the connection. Messages it can't frame are relayed the usual way.
+//! Response compression also needs the payload itself, without chunk framing.
+
+/// Where a message body ends
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyFraming {
+    /// Exactly this many more bytes
+    Length(u64),
+    /// Chunked transfer coding
+    Chunked(ChunkState),
+    /// Until the sender closes the connection (responses only)
+    Close,
+}
+
+/// Position within a chunked body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkState {
+    /// Reading the hex chunk size
+    Size(u64),
+    /// Skipping chunk extensions up to the line end
+    Extension(u64),
+    /// Inside chunk data
+    Data(u64),
+    /// Expecting the CRLF after chunk data
+    DataEnd,
+    /// At the start of a trailer line (or the final blank line)
+    TrailerStart,
+    /// Inside a trailer line
+    Trailer,
+    Done,
+}
+
+impl BodyFraming {
+    pub fn chunked() -> Self {
+        Self::Chunked(ChunkState::Size(0))
+    }
+
+    /// Consume body bytes from the start of `data`. Returns how many belong to
+    /// this body and whether it is complete; anything after belongs to the
+    /// next message.
+    pub fn consume(&mut self, data: &[u8]) -> Result<(usize, bool), &'static str> {
+        match self {
+            Self::Length(remaining) => {
+                let n = (*remaining).min(data.len() as u64);
+                *remaining -= n;
+                Ok((n as usize, *remaining == 0))
+            }
+            Self::Close => Ok((data.len(), false)),
+            Self::Chunked(state) => {
+                let mut used = 0;
+                while used < data.len() && *state != ChunkState::Done {
+                    used += step(state, &data[used..])?;
+                }
+                Ok((used, *state == ChunkState::Done))
+            }
+        }
+    }
+
+    /// Like `consume`, also appending the payload (the body without chunk
+    /// framing) to `payload`
+    pub fn decode(&mut self, data: &[u8], payload: &mut Vec<u8>) -> Result<(usize, bool), &'static str> {
+        let Self::Chunked(state) = self else {
+            let (used, done) = self.consume(data)?;
+            payload.extend_from_slice(&data[..used]);
+            return Ok((used, done));
+        };
+        let mut used = 0;
+        while used < data.len() && *state != ChunkState::Done {
+            let in_data = matches!(state, ChunkState::Data(_));
+            let n = step(state, &data[used..])?;
+            if in_data {
+                payload.extend_from_slice(&data[used..used + n]);
+            }
+            used += n;
+        }
+        Ok((used, *state == ChunkState::Done))
+    }
+
+    /// The body is complete before any bytes are read
+    pub fn is_complete(&self) -> bool {
+        matches!(self, Self::Length(0) | Self::Chunked(ChunkState::Done))
+    }
+}
+
+/// Advance the chunked decoder, returning how many bytes were used
+fn step(state: &mut ChunkState, data: &[u8]) -> Result<usize, &'static str> {
+    let byte = data[0];
+    match *state {
+        ChunkState::Size(size) => match byte {
+            b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                let digit = (byte as char).to_digit(16).unwrap_or(0) as u64;
+                let size = size
+                    .checked_mul(16)
+                    .and_then(|s| s.checked_add(digit))
+                    .ok_or("Chunk size too large")?;
+                *state = ChunkState::Size(size);
+            }
+            b';' | b' ' | b'\t' => *state = ChunkState::Extension(size),
+            b'\r' => {}
+            b'\n' => *state = chunk_start(size),
+            _ => return Err("Invalid chunk size"),
+        },
+        ChunkState::Extension(size) => {
+            if byte == b'\n' {
+                *state = chunk_start(size);
+            }
+        }
+        ChunkState::Data(remaining) => {
+            let n = remaining.min(data.len() as u64);
+            *state = if n == remaining {
+                ChunkState::DataEnd
+            } else {
+                ChunkState::Data(remaining - n)
+            };
+            return Ok(n as usize);
+        }
+        ChunkState::DataEnd => match byte {
+            b'\r' => {}
+            b'\n' => *state = ChunkState::Size(0),
+            _ => return Err("Missing CRLF after chunk data"),
+        },
+        ChunkState::TrailerStart => match byte {
+            b'\r' => {}
+            b'\n' => *state = ChunkState::Done,
+            _ => *state = ChunkState::Trailer,
+        },
+        ChunkState::Trailer => {
+            if byte == b'\n' {
+                *state = ChunkState::TrailerStart;
+            }
+        }
+        ChunkState::Done => return Ok(0),
+    }
+    Ok(1)
+}
+
+fn chunk_start(size: u64) -> ChunkState {
+    if size == 0 {
+        ChunkState::TrailerStart
+    } else {
+        ChunkState::Data(size)
+    }
+}
+
+/// Parsed framing of a request or response head
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageHead {
+    /// HTTP/1.1 (rather than 1.0 or unknown)
+    pub http11: bool,
+    /// `Connection: close` was sent
+    pub close: bool,
+    /// `Upgrade` was requested or a 101 returned
+    pub upgrade: bool,
+    /// Response status code (None for requests)
+    pub status: Option<u16>,
+    /// Body framing (None if it can't be determined)
+    pub framing: Option<BodyFraming>,
+}
+
+impl MessageHead {
+    /// Both sides may send another message on the connection
+    pub fn keeps_alive(&self) -> bool {
+        self.http11 && !self.close && !self.upgrade
+    }
+}
+
+/// Header values for `name` (case-insensitive), skipping the start line
+fn header_values<'a>(head: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> {
+    head.split("\r\n").skip(1).filter_map(move |line| {
+        let (header, value) = line.split_once(':')?;
+        header.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+fn has_token(head: &str, name: &str, token: &str) -> bool {
+    header_values(head, name).any(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Body framing from Transfer-Encoding / Content-Length (None if ambiguous)
+fn declared_framing(head: &str) -> Option<Option<BodyFraming>> {
+    let encodings: Vec<&str> = header_values(head, "transfer-encoding").collect();
+    let lengths: Vec<&str> = header_values(head, "content-length").collect();
+    if !encodings.is_empty() {
+        // Only plain `chunked`, and never alongside a length (smuggling risk)
+        let chunked = encodings.len() == 1 && encodings[0].eq_ignore_ascii_case("chunked");
+        return (chunked && lengths.is_empty()).then_some(Some(BodyFraming::chunked()));
+    }
+    match lengths.as_slice() {
+        [] => Some(None),
+        [length] => length.parse().ok().map(|len| Some(BodyFraming::Length(len))),
+        _ => None,
+    }
+}
+
+/// Framing of a request head (without a declared body there is none)
+pub fn parse_request_head(head: &[u8]) -> Option<MessageHead> {
+    let head = std::str::from_utf8(head).ok()?;
+    let request_line = head.split("\r\n").next()?;
+    let framing = declared_framing(head).map(|framing| framing.unwrap_or(BodyFraming::Length(0)));
+    Some(MessageHead {
+        http11: request_line.ends_with(" HTTP/1.1"),
+        close: has_token(head, "connection", "close"),
+        upgrade: header_values(head, "upgrade").next().is_some(),
+        status: None,
+        framing,
+    })
+}
+
+/// Framing of a response head to a request with `method`
+pub fn parse_response_head(head: &[u8], method: &str) -> Option<MessageHead> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut status_line = head.split("\r\n").next()?.split(' ');
+    let version = status_line.next()?;
+    let status: u16 = status_line.next()?.parse().ok()?;
+    let framing = if method.eq_ignore_ascii_case("HEAD") || (100..200).contains(&status) || status == 204 || status == 304 {
+        Some(BodyFraming::Length(0))
+    } else {
+        declared_framing(head).map(|framing| framing.unwrap_or(BodyFraming::Close))
+    };
+    Some(MessageHead {
+        http11: version == "HTTP/1.1",
+        close: has_token(head, "connection", "close"),
+        upgrade: status == 101,
+        status: Some(status),
+        framing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_across_reads() {
+        let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET /next";
+        let mut framing = BodyFraming::chunked();
+        let (used, done) = framing.consume(&body[..12]).unwrap();
+        assert_eq!((used, done), (12, false));
+        let (used, done) = framing.consume(&body[12..]).unwrap();
+        assert!(done);
+        assert_eq!(&body[12 + used..], b"GET /next");
+
+        assert!(BodyFraming::chunked().consume(b"zz\r\n").is_err());
+        assert!(BodyFraming::chunked().consume(b"ffffffffffffffffff\r\n").is_err());
+    }
+
+    #[test]
+    fn test_decode_payload() {
+        let body = b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\nrest";
+        let mut framing = BodyFraming::chunked();
+        let mut payload = Vec::new();
+        let (used, done) = framing.decode(&body[..8], &mut payload).unwrap();
+        assert_eq!((used, done), (8, false));
+        let (used, done) = framing.decode(&body[8..], &mut payload).unwrap();
+        assert!(done);
+        assert_eq!(&body[8 + used..], b"rest");
+        assert_eq!(payload, b"Wikipedia");
+
+        let mut payload = Vec::new();
+        assert_eq!(BodyFraming::Length(3).decode(b"abcd", &mut payload), Ok((3, true)));
+        assert_eq!(payload, b"abc");
+    }
+
+    #[test]
+    fn test_parse_heads() {
+        let request = parse_request_head(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert!(request.keeps_alive());
+        assert_eq!(request.framing, Some(BodyFraming::Length(3)));
+
+        let request = parse_request_head(b"GET / HTTP/1.0\r\nHost: a\r\n\r\n").unwrap();
+        assert!(!request.keeps_alive());
+        assert_eq!(request.framing, Some(BodyFraming::Length(0)));
+
+        // A length alongside chunked coding is refused
+        let request =
+            parse_request_head(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert_eq!(request.framing, None);
+
+        let response = parse_response_head(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n", "GET").unwrap();
+        assert_eq!(response.status, Some(200));
+        assert!(!response.keeps_alive());
+        assert_eq!(response.framing, Some(BodyFraming::Close));
+
+        let response = parse_response_head(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n", "HEAD").unwrap();
+        assert_eq!(response.framing, Some(BodyFraming::Length(0)));
+        assert!(response.keeps_alive());
+    }
+}
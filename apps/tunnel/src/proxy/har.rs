@@ -0,0 +1,238 @@
+//! HAR entries for captured exchanges.
+//!
+//! Built from the raw bytes of one exchange: the visitor's request as it
+//! arrived and the response as the tunnel sent it, before header rules, the
+//! preview banner or edge compression touch it. Chunked bodies are de-chunked;
+//! bodies that aren't UTF-8 are kept base64-encoded.
+
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+
+use super::extract_header_values;
+use super::framing::{parse_request_head, parse_response_head, BodyFraming};
+use super::recording::Recording;
+use super::rewrite::head_len;
+use crate::state::har::{
+    HarCache, HarContent, HarEntry, HarPair, HarPostData, HarRequest, HarResponse, HarTimings, MAX_MESSAGE_BYTES,
+};
+
+/// What was seen of one exchange
+pub struct Exchange<'a> {
+    /// The request as buffered before forwarding
+    pub request: &'a [u8],
+    /// The rest of the request body, sent to the tunnel afterwards
+    pub sent: Option<&'a Recording>,
+    /// The response from the tunnel
+    pub received: &'a Recording,
+    /// Scheme and host the visitor used, e.g. `https://app.example.com`
+    pub origin: String,
+    pub connection_id: String,
+    pub started_at: DateTime<Utc>,
+    /// Time to forward the request and until the response began
+    pub send: Duration,
+    pub wait: Duration,
+    pub total: Duration,
+}
+
+/// Name/value pairs of the header lines of `head`
+fn headers(head: &str) -> Vec<HarPair> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| HarPair {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+        .collect()
+}
+
+/// `name=value` pairs separated by `separator`
+fn pairs(text: &str, separator: char) -> Vec<HarPair> {
+    text.split(separator)
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            HarPair {
+                name: name.to_string(),
+                value: value.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// The payload of a body framed by `framing`, from whatever was recorded
+fn payload(framing: Option<BodyFraming>, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    match framing.map(|mut framing| framing.decode(body, &mut payload)) {
+        Some(Ok(_)) => payload,
+        _ => body.to_vec(),
+    }
+}
+
+/// The body as text, or base64 with its encoding
+fn body_text(payload: &[u8]) -> (String, Option<String>) {
+    match std::str::from_utf8(payload) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (STANDARD.encode(payload), Some("base64".to_string())),
+    }
+}
+
+fn truncation_comment(truncated: bool) -> Option<String> {
+    truncated.then(|| format!("Message truncated to {} bytes", MAX_MESSAGE_BYTES))
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+/// The HAR entry for an exchange (None if either head can't be parsed)
+pub fn har_entry(exchange: &Exchange<'_>) -> Option<HarEntry> {
+    // The request
+    let mut request = exchange.request.to_vec();
+    if let Some(sent) = exchange.sent {
+        request.extend_from_slice(&sent.bytes);
+    }
+    let request_truncated = request.len() > MAX_MESSAGE_BYTES || exchange.sent.is_some_and(|sent| sent.truncated);
+    request.truncate(MAX_MESSAGE_BYTES);
+    let request_head_len = head_len(&request)?;
+    let request_head = std::str::from_utf8(&request[..request_head_len]).ok()?;
+    let mut request_line = request_head.split("\r\n").next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let request_version = request_line.next().unwrap_or("HTTP/1.1").to_string();
+    let request_framing = parse_request_head(request_head.as_bytes())?.framing;
+    let request_body = payload(request_framing, &request[request_head_len..]);
+    let request_mime = extract_header_values(request_head.as_bytes(), "content-type").into_iter().next();
+    let post_data = (!request_body.is_empty() || request_mime.is_some()).then(|| HarPostData {
+        mime_type: request_mime.unwrap_or_default(),
+        text: String::from_utf8_lossy(&request_body).into_owned(),
+        comment: truncation_comment(request_truncated),
+    });
+    let query_string = pairs(target.split_once('?').map_or("", |(_, query)| query), '&');
+    let cookies = extract_header_values(request_head.as_bytes(), "cookie")
+        .iter()
+        .flat_map(|cookie| pairs(cookie, ';'))
+        .collect();
+
+    // The response
+    let received = &exchange.received.bytes[..exchange.received.bytes.len().min(MAX_MESSAGE_BYTES)];
+    let response_truncated = exchange.received.truncated || received.len() < exchange.received.bytes.len();
+    let response_head_len = head_len(received)?;
+    let response_head = std::str::from_utf8(&received[..response_head_len]).ok()?;
+    let mut status_line = response_head.split("\r\n").next()?.splitn(3, ' ');
+    let response_version = status_line.next()?.to_string();
+    let status = status_line.next()?.parse().ok()?;
+    let status_text = status_line.next().unwrap_or("").to_string();
+    let response_framing = parse_response_head(response_head.as_bytes(), &method)?.framing;
+    let response_body = payload(response_framing, &received[response_head_len..]);
+    let (text, encoding) = body_text(&response_body);
+    let response_value = |name: &str| extract_header_values(response_head.as_bytes(), name).into_iter().next();
+    let set_cookies = extract_header_values(response_head.as_bytes(), "set-cookie")
+        .iter()
+        .filter_map(|cookie| pairs(cookie.split(';').next()?, ';').into_iter().next())
+        .collect();
+
+    let receive = exchange.total.saturating_sub(exchange.send + exchange.wait);
+    Some(HarEntry {
+        started_date_time: exchange.started_at.to_rfc3339(),
+        time: millis(exchange.total),
+        request: HarRequest {
+            method,
+            url: match target.starts_with('/') {
+                true => format!("{}{}", exchange.origin, target),
+                false => target,
+            },
+            http_version: request_version,
+            cookies,
+            headers: headers(request_head),
+            query_string,
+            post_data,
+            headers_size: request_head_len as i64,
+            body_size: (request.len() - request_head_len) as i64,
+        },
+        response: HarResponse {
+            status,
+            status_text,
+            http_version: response_version,
+            cookies: set_cookies,
+            headers: headers(response_head),
+            content: HarContent {
+                size: response_body.len() as i64,
+                mime_type: response_value("content-type").unwrap_or_default(),
+                text,
+                encoding,
+                comment: truncation_comment(response_truncated),
+            },
+            redirect_url: response_value("location").unwrap_or_default(),
+            headers_size: response_head_len as i64,
+            body_size: (received.len() - response_head_len) as i64,
+        },
+        cache: HarCache::default(),
+        timings: HarTimings {
+            send: millis(exchange.send),
+            wait: millis(exchange.wait),
+            receive: millis(receive),
+        },
+        connection: exchange.connection_id.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_har_entry() {
+        let request = b"POST /hooks/github?delivery=1&retry HTTP/1.1\r\nHost: app.example.com\r\nContent-Type: application/json\r\nCookie: a=1; b=2\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n{\"ok\":1";
+        let sent = Recording {
+            bytes: b"}\r\n0\r\n\r\n".to_vec(),
+            ..Default::default()
+        };
+        let received = Recording {
+            bytes: b"HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nSet-Cookie: s=x; Path=/\r\nContent-Length: 2\r\n\r\nok".to_vec(),
+            ..Default::default()
+        };
+        let exchange = Exchange {
+            request,
+            sent: Some(&sent),
+            received: &received,
+            origin: "https://app.example.com".to_string(),
+            connection_id: "0000beef".to_string(),
+            started_at: Utc::now(),
+            send: Duration::from_millis(2),
+            wait: Duration::from_millis(30),
+            total: Duration::from_millis(40),
+        };
+        let entry = har_entry(&exchange).unwrap();
+        assert_eq!(entry.request.url, "https://app.example.com/hooks/github?delivery=1&retry");
+        assert_eq!(entry.request.query_string[1].name, "retry");
+        assert_eq!(entry.request.cookies.len(), 2);
+        let post_data = entry.request.post_data.unwrap();
+        assert_eq!(post_data.mime_type, "application/json");
+        assert_eq!(post_data.text, "{\"ok\":1}");
+        assert_eq!(entry.response.status, 201);
+        assert_eq!(entry.response.status_text, "Created");
+        assert_eq!(entry.response.cookies[0].value, "x");
+        assert_eq!(entry.response.content.text, "ok");
+        assert_eq!(entry.timings.receive, 8.0);
+
+        // Binary bodies are base64-encoded
+        let binary = Recording {
+            bytes: b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n\x00\xff".to_vec(),
+            ..Default::default()
+        };
+        let exchange = Exchange {
+            request: b"GET /logo HTTP/1.1\r\n\r\n",
+            sent: None,
+            received: &binary,
+            ..exchange
+        };
+        let content = har_entry(&exchange).unwrap().response.content;
+        assert_eq!(content.text, "AP8=");
+        assert_eq!(content.encoding.as_deref(), Some("base64"));
+    }
+}
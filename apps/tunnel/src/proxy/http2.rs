@@ -0,0 +1,781 @@
+//! HTTP/2 on the public proxy port.
+//!
+//! A connection that opens with the HTTP/2 connection preface (h2c with prior
+//! knowledge, as sent by a TLS-terminating edge such as Traefik with an `h2c`
+//! backend) is served by hyper instead of the raw pass-through. Each stream
+//! is routed on its `:authority` like an HTTP/1.1 request (static routes and
+//! tunnels held by cluster peers included), sent on as HTTP/1.1 (to a tunnel
+//! over its own forwarded channel), and the response is translated back.
+//! Share secrets, rate limits, upstream selection, path routing and response
+//! header rules apply as for HTTP/1.1. The preview banner, edge compression,
+//! the response cache and HAR capture need the HTTP/1.1 exchange itself, so
+//! streams they would apply to are reset with `HTTP_1_1_REQUIRED` and retried
+//! by the client over HTTP/1.1. Every other connection keeps the raw mode.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, COOKIE, HOST};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::config::{get as get_config, ClusterMode, RoutingMode};
+use crate::error::TunnelError;
+use crate::state::channel_slots::ChannelSlot;
+use crate::state::cluster::{connect_to_node, RemoteTunnel};
+use crate::state::header_rules::HeaderRules;
+use crate::state::perf_profiles::PerfProfile;
+use crate::state::static_routes::StaticRoute;
+use crate::state::{AppState, TunnelInfo};
+
+use super::access_log::AccessLogEntry;
+use super::close_reason::CloseReason;
+use super::path_routing::{self, PathRoute};
+use super::secret_path::SecretGate;
+use super::shaping::Shaped;
+use super::{
+    caching, channel_open_failed, channels_busy_response, classify_close, compression, error_response,
+    extract_header_from_raw, extract_subdomain, oauth, offline, open_channel, rate_limited_response, record_status,
+    redirect_response, relay_options, secret_path, share_secret, tunnel_error_response, usage_hint,
+    STATIC_ROUTE_CONNECT_TIMEOUT, UPSTREAM_HEADER,
+};
+
+/// Start of the HTTP/2 connection preface
+const PREFACE_START: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+/// Streams a visitor may have open at once (each holds a forwarded channel)
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Headers that only apply to one HTTP/1.1 hop and are invalid in HTTP/2
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+type ProxyBody = UnsyncBoxBody<Bytes, hyper::Error>;
+
+/// Whether the buffered bytes start an HTTP/2 connection
+pub fn is_preface(data: &[u8]) -> bool {
+    data.starts_with(PREFACE_START)
+}
+
+/// The visitor's stream with the bytes already read put back in front
+struct Rewind {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: TcpStream,
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = (self.prefix.len() - self.pos).min(buf.remaining());
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serve an HTTP/2 connection whose first bytes were already read
+/// (`allow_cluster`: streams for tunnels of cluster peers may go on to them)
+pub async fn serve_connection(
+    stream: TcpStream,
+    buffered: Vec<u8>,
+    client_addr: SocketAddr,
+    allow_cluster: bool,
+    state: Arc<AppState>,
+) {
+    debug!("HTTP/2 connection from {}", client_addr);
+    let io = TokioIo::new(Rewind {
+        prefix: buffered,
+        pos: 0,
+        inner: stream,
+    });
+    let service = service_fn(move |request| {
+        let state = state.clone();
+        async move { handle_request(request, client_addr, allow_cluster, state).await }
+    });
+    let result = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .serve_connection(io, service)
+        .await;
+    if let Err(e) = result {
+        debug!("HTTP/2 connection from {} ended: {}", client_addr, e);
+    }
+}
+
+/// One stream's request and response, logged once both bodies are done
+struct Exchange {
+    state: Arc<AppState>,
+    subdomain: String,
+    /// Session of the tunnel; None for static routes and peer nodes, whose
+    /// exchanges only go to the access log
+    session_id: Option<String>,
+    access: Mutex<Option<AccessLogEntry>>,
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Stays `client_eof` if the visitor resets the stream first
+    close_reason: Mutex<CloseReason>,
+    /// The tunnel's channel slot, freed once both bodies are done
+    _slot: Option<ChannelSlot>,
+}
+
+impl Exchange {
+    /// `access` already counts the request head
+    fn new(
+        state: &Arc<AppState>,
+        subdomain: &str,
+        session_id: Option<String>,
+        access: AccessLogEntry,
+        started: Instant,
+        slot: Option<ChannelSlot>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            state: state.clone(),
+            subdomain: subdomain.to_string(),
+            session_id,
+            bytes_in: AtomicU64::new(access.bytes_in),
+            access: Mutex::new(Some(access)),
+            started,
+            bytes_out: AtomicU64::new(0),
+            close_reason: Mutex::new(CloseReason::ClientEof),
+            _slot: slot,
+        })
+    }
+
+    fn set_status(&self, status: u16) {
+        if let Some(access) = self.access.lock().unwrap().as_mut() {
+            access.status = Some(status);
+        }
+    }
+
+    fn set_first_byte(&self) {
+        if let Some(access) = self.access.lock().unwrap().as_mut() {
+            access.set_first_byte(self.started, Instant::now());
+        }
+    }
+
+    fn set_close_reason(&self, reason: CloseReason) {
+        *self.close_reason.lock().unwrap() = reason;
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        let Some(mut access) = self.access.lock().unwrap().take() else {
+            return;
+        };
+        let started = self.started;
+        access.bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        access.bytes_out = self.bytes_out.load(Ordering::Relaxed);
+        let reason = self.close_reason.lock().unwrap().clone();
+        let Some(session_id) = self.session_id.take() else {
+            access.close_reason = reason;
+            access.finish(started);
+            return;
+        };
+        let state = self.state.clone();
+        let subdomain = std::mem::take(&mut self.subdomain);
+        tokio::spawn(async move {
+            access.close_reason = classify_close(&state, &subdomain, &session_id, reason).await;
+            // Also releases the tunnel's connection slot
+            state
+                .record_traffic(&subdomain, access.bytes_in, access.bytes_out, &access.close_reason)
+                .await;
+            state.request_finished(&session_id, &access.finish(started));
+        });
+    }
+}
+
+/// Body wrapper counting the bytes that pass through
+struct Counted<B> {
+    inner: B,
+    exchange: Arc<Exchange>,
+    outgoing: bool,
+}
+
+impl<B: Body<Data = Bytes> + Unpin> Body for Counted<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let counter = if self.outgoing {
+                        &self.exchange.bytes_out
+                    } else {
+                        &self.exchange.bytes_in
+                    };
+                    counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+            }
+            // The response ended: the service finished the exchange
+            Poll::Ready(None) if self.outgoing => self.exchange.set_close_reason(CloseReason::UpstreamEof),
+            Poll::Ready(Some(Err(_))) if self.outgoing => {
+                self.exchange.set_close_reason(CloseReason::error("ResponseBodyFailed"))
+            }
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Whether sending a request failed because its body went over the limit
+fn is_body_too_large(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// The request head as HTTP/1.1 bytes, for the helpers that read raw heads
+fn http1_head<B>(request: &Request<B>, host: &str) -> Vec<u8> {
+    let target = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), target);
+    if !request.headers().contains_key(HOST) {
+        head.push_str(&format!("host: {}\r\n", host));
+    }
+    for (name, value) in request.headers() {
+        head.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+/// Convert one of the proxy's canned HTTP/1.1 responses
+fn from_raw(raw: Vec<u8>) -> Response<ProxyBody> {
+    let text = String::from_utf8_lossy(&raw);
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut response = Response::new(full(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.trim().as_bytes()), HeaderValue::from_str(value.trim())) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    strip_hop_by_hop(response.headers_mut());
+    response
+}
+
+fn full(body: Bytes) -> ProxyBody {
+    Full::new(body).map_err(|never| match never {}).boxed_unsync()
+}
+
+/// Answer without reaching the tunnel
+fn reject(mut access: AccessLogEntry, started: Instant, raw: Vec<u8>) -> (Response<ProxyBody>, AccessLogEntry) {
+    let response = from_raw(raw);
+    access.status = Some(response.status().as_u16());
+    access.bytes_out = response.body().size_hint().exact().unwrap_or(0);
+    (response, access.finish(started))
+}
+
+/// Remove HTTP/1.1 connection headers (and the ones `Connection` names)
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in HOP_BY_HOP_HEADERS.iter().copied().chain(named.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+}
+
+/// Remove a secret-path tunnel's token header and cookie from a stream's
+/// request, as `secret_path::strip_token` does for HTTP/1.1 heads
+fn strip_secret_token(headers: &mut HeaderMap) {
+    headers.remove(secret_path::TOKEN_HEADER);
+    let cookies: Vec<HeaderValue> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| match value.to_str() {
+            Ok(text) => secret_path::without_token_cookie(text).and_then(|kept| HeaderValue::from_str(&kept).ok()),
+            Err(_) => Some(value.clone()),
+        })
+        .collect();
+    headers.remove(COOKIE);
+    for value in cookies {
+        headers.append(COOKIE, value);
+    }
+}
+
+/// Apply a tunnel's response header rules
+fn apply_header_rules(rules: &HeaderRules, headers: &mut HeaderMap) {
+    for name in rules.set.keys().chain(&rules.remove) {
+        headers.remove(name.to_ascii_lowercase().as_str());
+    }
+    for (name, value) in &rules.set {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Route one stream as HTTP/1.1 requests are routed (to its tunnel, a static
+/// route or the node holding the tunnel) and translate the response. A
+/// stream needing what only HTTP/1.1 exchanges get (see `needs_http1`) is
+/// reset with `HTTP_1_1_REQUIRED`, so the client retries it over HTTP/1.1.
+async fn handle_request(
+    mut request: Request<Incoming>,
+    client_addr: SocketAddr,
+    allow_cluster: bool,
+    state: Arc<AppState>,
+) -> Result<Response<ProxyBody>, h2::Error> {
+    let started = Instant::now();
+    let host = request
+        .uri()
+        .authority()
+        .map(|authority| authority.to_string())
+        .or_else(|| request.headers().get(HOST).and_then(|h| h.to_str().ok()).map(str::to_string));
+    let head = http1_head(&request, host.as_deref().unwrap_or_default());
+    let mut access = AccessLogEntry::new(client_addr, &head);
+
+    let Some(host) = host else {
+        return Ok(reject(access, started, error_response(400, "Missing :authority")).0);
+    };
+    if let Some(max) = get_config().max_request_body_bytes {
+        if request.body().size_hint().exact().is_some_and(|len| len > max) {
+            return Ok(reject(access, started, error_response(413, "Request body too large")).0);
+        }
+    }
+
+    // Same routing as HTTP/1.1: custom domain, path prefix, subdomain
+    let custom_domain = state.domains.resolve(&host).await;
+    let target = request.uri().path_and_query().map_or("/", |p| p.as_str()).to_string();
+    let path_route = match get_config().routing_mode {
+        RoutingMode::Path if custom_domain.is_none() => path_routing::route(&target),
+        _ => None,
+    };
+    let path_target = match path_route {
+        Some(PathRoute::Redirect { location }) => return Ok(reject(access, started, redirect_response(&location)).0),
+        Some(PathRoute::Forward { subdomain, target }) => Some((subdomain, target)),
+        None => None,
+    };
+    let subdomain = match custom_domain
+        .as_ref()
+        .map(|d| d.subdomain.clone())
+        .or_else(|| path_target.as_ref().map(|(subdomain, _)| subdomain.clone()))
+        .or_else(|| extract_subdomain(&host))
+    {
+        Some(subdomain) => subdomain,
+        None => return Ok(reject(access, started, error_response(400, &usage_hint())).0),
+    };
+    access.subdomain = Some(subdomain.clone());
+    access.bytes_in = head.len() as u64;
+    let request_target = path_target.as_ref().map_or(target.clone(), |(_, target)| target.clone());
+
+    // Static routes own their subdomains outright (tunnels can't register them)
+    if let Some(route) = state.static_routes.get(&subdomain).filter(|_| custom_domain.is_none()) {
+        return Ok(forward_to_static_route(request, route, &host, &request_target, access, started, &state).await);
+    }
+
+    let tunnel = match state.get_tunnel(&subdomain).await {
+        Some(t) if custom_domain.as_ref().is_none_or(|d| d.user_id == t.username) => t,
+        Some(_) => {
+            return Ok(reject(access, started, tunnel_error_response(&TunnelError::TunnelNotFound(subdomain))).0);
+        }
+        None => {
+            if allow_cluster && custom_domain.is_none() {
+                if let Some(remote) = state.cluster.lookup(&subdomain).await {
+                    let forwarded =
+                        forward_to_cluster_node(request, client_addr, &remote, &host, &target, access, started, &state);
+                    return Ok(forwarded.await);
+                }
+            }
+            return Ok(reject(access, started, tunnel_error_response(&TunnelError::TunnelNotFound(subdomain))).0);
+        }
+    };
+    let span = format!("{} cid={} conn={} h2", subdomain, tunnel.correlation_id, access.connection_id);
+    access.correlation_id = Some(tunnel.correlation_id.clone());
+
+    // Errors from here on show up in the owner's live view
+    let publish = |(response, access): (Response<ProxyBody>, AccessLogEntry)| {
+        state.request_finished(&tunnel.session_id, &access);
+        Ok(response)
+    };
+    if let Some(ref token) = tunnel.secret_path {
+        match secret_path::gate(&head, token, &target, &request_target) {
+            SecretGate::Forward => {}
+            SecretGate::Redirect { location, cookie } => {
+                let response = secret_path::redirect_response(&location, &cookie);
+                return publish(reject(access, started, response));
+            }
+            SecretGate::NotFound => {
+                let response = tunnel_error_response(&TunnelError::TunnelNotFound(subdomain.clone()));
+                return publish(reject(access, started, response));
+            }
+        }
+        // The token is for the proxy only
+        strip_secret_token(request.headers_mut());
+    }
+    if !tunnel.is_connected {
+        return publish(reject(access, started, offline::offline_response(&subdomain, &head)));
+    }
+    if let Some(ref secret) = tunnel.share_secret {
+        if !share_secret::is_authorized(&head, secret) {
+            debug!("[{}] Missing or wrong share secret from {}", span, client_addr);
+            return publish(reject(access, started, share_secret::unauthorized_response()));
+        }
+    }
+    // Streams can't be redirected through the sign-in; a browser session's cookie still works
+    if let Some(policy) = state.oauth.get(&subdomain).await {
+        if !oauth::is_admitted(&head, &subdomain, &policy) {
+            debug!("[{}] No OAuth session from {}", span, client_addr);
+            let message = "This tunnel requires signing in; open it in a browser";
+            return publish(reject(access, started, error_response(401, message)));
+        }
+    }
+
+    // Stream bodies aren't buffered for signature checks; webhooks have to use HTTP/1.1
+    let path = request_target.split('?').next().unwrap_or_default();
+    if let Some(rule) = state.webhooks.matching(&subdomain, path).await {
+        debug!("[{}] HTTP/2 webhook delivery to {} refused", span, rule.path);
+        let message = "Webhook deliveries to this path must use HTTP/1.1";
+        return publish(reject(access, started, error_response(401, message)));
+    }
+    let routed = path_target.as_ref().map(|(_, target)| target.as_str());
+    if needs_http1(&state, &tunnel, &head, routed) {
+        debug!("[{}] Asking {} to retry over HTTP/1.1", span, client_addr);
+        access.close_reason = CloseReason::Rejected;
+        state.request_finished(&tunnel.session_id, &access.finish(started));
+        return Err(h2::Error::from(h2::Reason::HTTP_1_1_REQUIRED));
+    }
+    let upstream_override = extract_header_from_raw(&head, UPSTREAM_HEADER);
+    let Some(upstream) = tunnel.select_upstream(upstream_override.as_deref(), Some(&request_target)) else {
+        let message = format!(
+            "Unknown upstream '{}' for tunnel '{}'",
+            upstream_override.unwrap_or_default(),
+            subdomain
+        );
+        return publish(reject(access, started, error_response(404, &message)));
+    };
+
+    if let Err(exceeded) = state.connection_opened(&subdomain).await {
+        debug!("[{}] Limit exceeded: {:?}", span, exceeded);
+        access.close_reason = CloseReason::LimitExceeded;
+        return publish(reject(access, started, rate_limited_response(exceeded)));
+    }
+    let Ok(slot) = state.channel_slots.acquire(&subdomain).await else {
+        debug!("[{}] All channels of the tunnel stayed busy", span);
+        access.close_reason = CloseReason::LimitExceeded;
+        state.record_traffic(&subdomain, 0, 0, &access.close_reason).await;
+        return publish(reject(access, started, channels_busy_response()));
+    };
+    let throttle = state.bandwidth.throttle(&subdomain, &tunnel.username, tunnel.tier.as_deref());
+
+    let channel = match open_channel(&tunnel, &upstream, client_addr).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            let (_, response) = channel_open_failed(&state, &tunnel, &upstream, &span, e, &mut access, &head).await;
+            return publish(reject(access, started, response));
+        }
+    };
+    if tunnel.awaiting_local_service && state.mark_local_service_ready(&subdomain).await {
+        info!("[{}] Local service is up, tunnel is healthy", span);
+    }
+
+    // From here the exchange logs itself once both bodies are done
+    let exchange = Exchange::new(&state, &subdomain, Some(tunnel.session_id.clone()), access, started, slot);
+    let upstream = Shaped::new(channel, throttle);
+    let idle = relay_options(tunnel.perf_profile).idle;
+    let response = match send_http1(upstream, request, &host, &request_target, &exchange, idle, &span).await {
+        Ok(response) => response,
+        Err(failed) => return Ok(failed),
+    };
+    record_status(&state, &subdomain, response.status().as_u16()).await;
+    Ok(translate_response(response, Some(&tunnel.response_headers), exchange))
+}
+
+/// Whether a stream to `tunnel` needs what only HTTP/1.1 exchanges get: the
+/// preview banner, edge compression, the response cache or HAR capture
+fn needs_http1(state: &AppState, tunnel: &TunnelInfo, head: &[u8], routed: Option<&str>) -> bool {
+    let uncompressed = tunnel.preview_banner || !tunnel.perf_profile.is_none_or(PerfProfile::compression);
+    let compress = !uncompressed && get_config().response_compression && compression::negotiate(head).is_some();
+    let cacheable = tunnel.response_cache && caching::cache_request(head, Some(head.len()), routed).is_some();
+    tunnel.preview_banner || compress || cacheable || state.har.is_recording(&tunnel.subdomain)
+}
+
+/// Forward a stream to the upstream of an operator-defined static route
+async fn forward_to_static_route(
+    request: Request<Incoming>,
+    route: &StaticRoute,
+    host: &str,
+    target: &str,
+    mut access: AccessLogEntry,
+    started: Instant,
+    state: &Arc<AppState>,
+) -> Response<ProxyBody> {
+    let connected = tokio::time::timeout(STATIC_ROUTE_CONNECT_TIMEOUT, TcpStream::connect(&route.target)).await;
+    let upstream = match connected {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            warn!("[{}] Static route to {} failed: {}", route.subdomain, route.target, e);
+            access.close_reason = CloseReason::from_io(&e);
+            return reject(access, started, error_response(502, "Failed to reach the upstream of this route")).0;
+        }
+        Err(_) => {
+            warn!("[{}] Static route to {} timed out", route.subdomain, route.target);
+            access.close_reason = CloseReason::error("ConnectTimeout");
+            return reject(access, started, error_response(504, "The upstream of this route did not answer")).0;
+        }
+    };
+    let span = format!("{} conn={} h2", route.subdomain, access.connection_id);
+    let exchange = Exchange::new(state, &route.subdomain, None, access, started, None);
+    let idle = relay_options(None).idle;
+    match send_http1(upstream, request, host, target, &exchange, idle, &span).await {
+        Ok(response) => translate_response(response, None, exchange),
+        Err(failed) => failed,
+    }
+}
+
+/// Forward a stream for a tunnel held by another cluster node: relayed to it
+/// as HTTP/1.1 (the node routes it again), or redirected there
+#[allow(clippy::too_many_arguments)]
+async fn forward_to_cluster_node(
+    request: Request<Incoming>,
+    client_addr: SocketAddr,
+    remote: &RemoteTunnel,
+    host: &str,
+    target: &str,
+    mut access: AccessLogEntry,
+    started: Instant,
+    state: &Arc<AppState>,
+) -> Response<ProxyBody> {
+    match get_config().cluster_mode {
+        ClusterMode::Relay => {
+            debug!(
+                "[{}] Relaying stream to node {} ({})",
+                remote.subdomain, remote.node_id, remote.proxy_addr
+            );
+            let upstream = match connect_to_node(remote, client_addr).await {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("[{}] Relay to node {} failed: {:?}", remote.subdomain, remote.node_id, e);
+                    access.close_reason = CloseReason::from_io(&e);
+                    let message = "Failed to reach the node holding this tunnel";
+                    return reject(access, started, error_response(502, message)).0;
+                }
+            };
+            let span = format!("{} node={} conn={} h2", remote.subdomain, remote.node_id, access.connection_id);
+            let exchange = Exchange::new(state, &remote.subdomain, None, access, started, None);
+            let idle = relay_options(None).idle;
+            match send_http1(upstream, request, host, target, &exchange, idle, &span).await {
+                Ok(response) => translate_response(response, None, exchange),
+                Err(failed) => failed,
+            }
+        }
+        ClusterMode::Redirect => {
+            let Some(ref redirect_base) = remote.redirect_base else {
+                warn!("[{}] Node {} has no redirect base configured", remote.subdomain, remote.node_id);
+                return reject(access, started, error_response(502, "Tunnel is held by another node")).0;
+            };
+            // Scheme-relative so visitors keep whatever scheme they arrived with
+            let location = format!("//{}.{}{}", remote.subdomain, redirect_base, target);
+            debug!("[{}] Redirecting to node {}: {}", remote.subdomain, remote.node_id, location);
+            reject(access, started, redirect_response(&location)).0
+        }
+    }
+}
+
+/// Send the stream's request as HTTP/1.1 (with `host` and `target`) over
+/// `upstream`: a tunnel's channel, a static route's upstream or a peer node.
+/// Failures are answered in place of the response.
+async fn send_http1<IO>(
+    upstream: IO,
+    request: Request<Incoming>,
+    host: &str,
+    target: &str,
+    exchange: &Arc<Exchange>,
+    idle: Duration,
+    span: &str,
+) -> Result<Response<Incoming>, Response<ProxyBody>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let failed = |status: u16, message: &str, reason: CloseReason| {
+        let response = from_raw(error_response(status, message));
+        exchange.set_status(status);
+        exchange.set_close_reason(reason);
+        response
+    };
+
+    let (mut sender, connection) = match hyper::client::conn::http1::handshake(TokioIo::new(upstream)).await {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            return Err(failed(
+                502,
+                &format!("Failed to connect to tunnel: {}", e),
+                CloseReason::error("HandshakeFailed"),
+            ))
+        }
+    };
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("HTTP/1.1 connection to tunnel ended: {}", e);
+        }
+    });
+
+    // HTTP/1.1 toward the local service: Host instead of :authority
+    let (mut parts, body) = request.into_parts();
+    parts.version = Version::HTTP_11;
+    parts.uri = match target.parse() {
+        Ok(uri) => uri,
+        Err(_) => return Err(failed(400, "Malformed request target", CloseReason::Rejected)),
+    };
+    if let Ok(value) = HeaderValue::from_str(host) {
+        parts.headers.insert(HOST, value);
+    }
+    // Bodies without a declared length are cut off at the limit as they stream
+    let max_body = get_config()
+        .max_request_body_bytes
+        .map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(usize::MAX));
+    let body = Counted {
+        inner: Limited::new(body, max_body),
+        exchange: exchange.clone(),
+        outgoing: false,
+    };
+    match tokio::time::timeout(idle, sender.send_request(Request::from_parts(parts, body))).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) if is_body_too_large(&e) => Err(failed(413, "Request body too large", CloseReason::LimitExceeded)),
+        Ok(Err(e)) => {
+            debug!("[{}] Request to tunnel failed: {}", span, e);
+            Err(failed(
+                502,
+                "The tunnel closed the connection without a response",
+                CloseReason::UpstreamEof,
+            ))
+        }
+        Err(_) => Err(failed(504, "The tunnel did not respond in time", CloseReason::IdleTimeout)),
+    }
+}
+
+/// The upstream's HTTP/1.1 response as the stream's, with the tunnel's
+/// header `rules` applied; its body counts toward `exchange`
+fn translate_response(
+    response: Response<Incoming>,
+    rules: Option<&HeaderRules>,
+    exchange: Arc<Exchange>,
+) -> Response<ProxyBody> {
+    let (mut parts, body) = response.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    if let Some(rules) = rules {
+        apply_header_rules(rules, &mut parts.headers);
+    }
+    parts.version = Version::HTTP_2;
+    exchange.set_status(parts.status.as_u16());
+    exchange.set_first_byte();
+    let body = Counted {
+        inner: body,
+        exchange,
+        outgoing: true,
+    };
+    Response::from_parts(parts, body.boxed_unsync())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http1_head_and_hop_by_hop() {
+        let request = Request::builder()
+            .uri("http://myapp.localhost/a?b=1")
+            .header("authorization", "Basic eDpzZWNyZXQ=")
+            .body(())
+            .unwrap();
+        let head = http1_head(&request, "myapp.localhost");
+        assert_eq!(
+            String::from_utf8(head.clone()).unwrap(),
+            "GET /a?b=1 HTTP/1.1\r\nhost: myapp.localhost\r\nauthorization: Basic eDpzZWNyZXQ=\r\n\r\n"
+        );
+        assert!(share_secret::is_authorized(&head, "secret"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("close, x-hop"));
+        headers.insert("x-hop", HeaderValue::from_static("1"));
+        headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        headers.insert("content-type", HeaderValue::from_static("text/html"));
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("content-type"));
+    }
+
+    #[test]
+    fn test_strip_secret_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-exlo-token", HeaderValue::from_static("abc"));
+        headers.append("cookie", HeaderValue::from_static("a=1; exlo_token=abc"));
+        headers.append("cookie", HeaderValue::from_static("exlo_token=abc"));
+        headers.append("cookie", HeaderValue::from_static("b=2"));
+        strip_secret_token(&mut headers);
+        assert!(!headers.contains_key("x-exlo-token"));
+        let cookies: Vec<_> = headers.get_all("cookie").iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn test_from_raw() {
+        let response = from_raw(rate_limited_response(crate::state::tunnel_limits::LimitExceeded::Connections));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "1");
+        assert!(!response.headers().contains_key("connection"));
+        assert!(is_preface(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"));
+    }
+}
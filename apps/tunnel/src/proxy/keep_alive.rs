@@ -0,0 +1,402 @@
+//! Persistent HTTP/1.1 exchanges over pooled channels.
+//!
+//! Instead of relaying raw bytes, the proxy forwards one request at a time,
+//! frames the response (see `framing`) and, once it is complete, waits for
+//! the visitor's next request on the same channel. When the visitor leaves
+//! between exchanges the channel is clean and can go back to the pool.
+//! Anything the framing can't follow (upgrades, pipelined or unframed
+//! messages, interim responses) falls back to the raw relay for the rest of
+//! the connection, and that channel is not reused.
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::close_reason::CloseReason;
+use super::framing::{parse_request_head, parse_response_head, BodyFraming, MessageHead};
+use super::relay::{relay, RelayOptions};
+use super::request_head::read_request_head;
+
+/// Largest response head that is parsed; bigger heads are relayed raw
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// A buffered request that can be forwarded over a pooled channel
+#[derive(Debug, Clone)]
+pub struct PoolableRequest {
+    head: MessageHead,
+    /// The whole request (including its body) was buffered, so it can be
+    /// sent again if a pooled channel turns out to be closed
+    pub fully_buffered: bool,
+}
+
+/// Check a buffered request: persistent HTTP/1.1 without an upgrade, a body
+/// the framing can follow, and nothing pipelined after it
+pub fn poolable_request(data: &[u8], head_len: Option<usize>) -> Option<PoolableRequest> {
+    let head_len = head_len?;
+    let head = parse_request_head(&data[..head_len])?;
+    if !head.keeps_alive() {
+        return None;
+    }
+    let mut framing = head.framing.clone()?;
+    let body = &data[head_len..];
+    let (used, done) = framing.consume(body).ok()?;
+    if used < body.len() {
+        return None;
+    }
+    Some(PoolableRequest {
+        head,
+        fully_buffered: done || framing.is_complete(),
+    })
+}
+
+/// What happened to the visitor's requests
+#[derive(Debug, Default)]
+pub struct Served {
+    pub to_upstream: u64,
+    pub to_client: u64,
+    /// Status of the first response
+    pub status: Option<u16>,
+    /// When the head of the first response came in
+    pub responded_at: Option<Instant>,
+    /// The channel ended at a message boundary and can be pooled
+    pub reusable: bool,
+    /// The channel closed before answering the first request and nothing
+    /// was sent to the visitor
+    pub stale: bool,
+    /// How the connection ended (None = the service finished a response
+    /// that doesn't allow another)
+    pub close_reason: Option<CloseReason>,
+}
+
+async fn write_timed<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8], timeout: Duration) -> std::io::Result<()> {
+    let write = async {
+        writer.write_all(data).await?;
+        writer.flush().await
+    };
+    match tokio::time::timeout(timeout, write).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// Copy a body until its framing completes. Returns the bytes copied and
+/// whether the sender went on past the end of the body.
+async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    framing: &mut BodyFraming,
+    options: RelayOptions,
+) -> std::io::Result<(u64, bool)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; options.buffer_size.max(1)];
+    let mut copied = 0;
+    while !framing.is_complete() {
+        let n = match tokio::time::timeout(options.idle, reader.read(&mut buf)).await {
+            Ok(read) => read?,
+            Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+        };
+        if n == 0 {
+            if *framing == BodyFraming::Close {
+                return Ok((copied, false));
+            }
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let (used, done) = framing
+            .consume(&buf[..n])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        write_timed(writer, &buf[..used], options.write).await?;
+        copied += used as u64;
+        if used < n {
+            return Ok((copied, true));
+        }
+        if done {
+            break;
+        }
+    }
+    Ok((copied, false))
+}
+
+/// How one exchange ended
+enum ExchangeEnd {
+    /// Complete, and both sides allow another exchange
+    KeepAlive,
+    /// Complete (or relayed raw), but the connection can't carry another
+    Done,
+    /// No response and nothing sent to the visitor
+    Stale,
+}
+
+/// Hand what was read to the visitor and relay the rest of the connection raw
+async fn fall_back_to_relay<C, U>(client: &mut C, upstream: &mut U, data: &[u8], options: RelayOptions, served: &mut Served)
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(e) = write_timed(client, data, options.write).await {
+        served.close_reason = Some(CloseReason::from_io(&e));
+        return;
+    }
+    served.to_client += data.len() as u64;
+    let relayed = relay(client, upstream, options).await;
+    served.to_upstream += relayed.to_upstream;
+    served.to_client += relayed.to_client;
+    served.close_reason = Some(CloseReason::from_relay(&relayed.end));
+}
+
+/// Forward one request and its response
+async fn exchange<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    request: &[u8],
+    plan: &PoolableRequest,
+    options: RelayOptions,
+    served: &mut Served,
+) -> ExchangeEnd
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = request.split(|b| *b == b' ').next().unwrap_or_default();
+    let method = String::from_utf8_lossy(method).into_owned();
+
+    // The request, then any body still to come from the visitor
+    if let Err(e) = write_timed(upstream, request, options.write).await {
+        served.close_reason = Some(CloseReason::from_io(&e));
+        return ExchangeEnd::Stale;
+    }
+    served.to_upstream += request.len() as u64;
+    if !plan.fully_buffered {
+        let Some(mut framing) = plan.head.framing.clone() else {
+            return ExchangeEnd::Done;
+        };
+        if framing.consume(&request[request_body_start(request)..]).is_err() {
+            return ExchangeEnd::Done;
+        }
+        match copy_body(client, upstream, &mut framing, options).await {
+            Ok((copied, pipelined)) => {
+                served.to_upstream += copied;
+                if pipelined {
+                    return ExchangeEnd::Done;
+                }
+            }
+            Err(e) => {
+                served.close_reason = Some(CloseReason::from_io(&e));
+                return ExchangeEnd::Done;
+            }
+        }
+    }
+
+    // The response head
+    let response = match read_request_head(upstream, MAX_RESPONSE_HEAD, options.idle).await {
+        Ok(response) => response,
+        Err(e) => {
+            served.close_reason = Some(CloseReason::from_io(&e));
+            return ExchangeEnd::Stale;
+        }
+    };
+    if response.data.is_empty() {
+        return if response.timed_out {
+            served.close_reason = Some(CloseReason::IdleTimeout);
+            ExchangeEnd::Done
+        } else {
+            served.close_reason = Some(CloseReason::UpstreamEof);
+            ExchangeEnd::Stale
+        };
+    }
+    served.responded_at.get_or_insert_with(Instant::now);
+    let parsed = response
+        .head_len
+        .and_then(|len| Some((len, parse_response_head(&response.data[..len], &method)?)));
+    served.status = served.status.or_else(|| parsed.as_ref().and_then(|(_, head)| head.status));
+    let (head_len, head) = match parsed {
+        Some((len, head)) if head.framing.is_some() && head.status.is_none_or(|s| s >= 200) => (len, head),
+        _ => {
+            // Interim, upgraded, oversized or unframed responses go raw
+            fall_back_to_relay(client, upstream, &response.data, options, served).await;
+            return ExchangeEnd::Done;
+        }
+    };
+
+    // The response body, up to the end of its framing
+    let Some(mut framing) = head.framing.clone() else {
+        return ExchangeEnd::Done;
+    };
+    let prefix = &response.data[head_len..];
+    let Ok((used, done)) = framing.consume(prefix) else {
+        fall_back_to_relay(client, upstream, &response.data, options, served).await;
+        return ExchangeEnd::Done;
+    };
+    if let Err(e) = write_timed(client, &response.data[..head_len + used], options.write).await {
+        served.close_reason = Some(CloseReason::from_io(&e));
+        return ExchangeEnd::Done;
+    }
+    served.to_client += (head_len + used) as u64;
+    let mut clean = used == prefix.len();
+    if !done && !framing.is_complete() {
+        match copy_body(upstream, client, &mut framing, options).await {
+            Ok((copied, extra)) => {
+                served.to_client += copied;
+                clean &= !extra;
+            }
+            Err(e) => {
+                served.close_reason = Some(CloseReason::from_io(&e));
+                return ExchangeEnd::Done;
+            }
+        }
+    }
+
+    let framed = framing != BodyFraming::Close;
+    if clean && framed && plan.head.keeps_alive() && head.keeps_alive() {
+        ExchangeEnd::KeepAlive
+    } else {
+        ExchangeEnd::Done
+    }
+}
+
+/// Where the body starts in a buffered request
+fn request_body_start(request: &[u8]) -> usize {
+    request
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(request.len(), |pos| pos + 4)
+}
+
+/// Serve the visitor's requests over `upstream` until either side is done.
+/// `first` is the buffered first request, already checked with
+/// `poolable_request`.
+pub async fn serve<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    first: &[u8],
+    plan: &PoolableRequest,
+    options: RelayOptions,
+    head_limit: usize,
+) -> Served
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut served = Served::default();
+    let mut request = first.to_vec();
+    let mut plan = plan.clone();
+    let mut first_exchange = true;
+    loop {
+        match exchange(client, upstream, &request, &plan, options, &mut served).await {
+            ExchangeEnd::KeepAlive => {}
+            ExchangeEnd::Stale if first_exchange => {
+                served.stale = true;
+                return served;
+            }
+            ExchangeEnd::Stale | ExchangeEnd::Done => return served,
+        }
+        first_exchange = false;
+
+        // Wait for the visitor's next request; leaving here keeps the channel
+        let next = match read_request_head(client, head_limit, options.idle).await {
+            Ok(next) => next,
+            Err(e) => {
+                served.reusable = true;
+                served.close_reason = Some(CloseReason::from_io(&e));
+                return served;
+            }
+        };
+        if next.head_len.is_none() {
+            served.reusable = true;
+            served.close_reason = Some(if next.timed_out {
+                CloseReason::IdleTimeout
+            } else {
+                CloseReason::ClientEof
+            });
+            return served;
+        }
+        match poolable_request(&next.data, next.head_len) {
+            Some(next_plan) => {
+                request = next.data;
+                plan = next_plan;
+            }
+            None => {
+                // Not something the framing can follow: relay it raw
+                match write_timed(upstream, &next.data, options.write).await {
+                    Ok(()) => {
+                        served.to_upstream += next.data.len() as u64;
+                        let relayed = relay(client, upstream, options).await;
+                        served.to_upstream += relayed.to_upstream;
+                        served.to_client += relayed.to_client;
+                        served.close_reason = Some(CloseReason::from_relay(&relayed.end));
+                    }
+                    Err(e) => served.close_reason = Some(CloseReason::from_io(&e)),
+                }
+                return served;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> RelayOptions {
+        RelayOptions {
+            idle: Duration::from_secs(5),
+            write: Duration::from_secs(5),
+            buffer_size: 1024,
+        }
+    }
+
+    #[test]
+    fn test_poolable_request() {
+        let get = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert!(poolable_request(get, Some(get.len())).is_some_and(|r| r.fully_buffered));
+
+        let post = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nab";
+        assert!(poolable_request(post, Some(post.len() - 2)).is_some_and(|r| !r.fully_buffered));
+
+        // Pipelined, upgraded and HTTP/1.0 requests are relayed raw
+        let pipelined = b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\n";
+        assert!(poolable_request(pipelined, Some(get.len())).is_none());
+        let upgrade = b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\n\r\n";
+        assert!(poolable_request(upgrade, Some(upgrade.len())).is_none());
+        let old = b"GET / HTTP/1.0\r\nHost: a\r\n\r\n";
+        assert!(poolable_request(old, Some(old.len())).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serves_two_requests_then_keeps_channel() {
+        let first = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n";
+        let second = b"POST /b HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhi";
+        let mut client = tokio_test::io::Builder::new()
+            .write(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .read(second)
+            .write(b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+            .build();
+        let mut upstream = tokio_test::io::Builder::new()
+            .write(first)
+            .read(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .write(second)
+            .read(b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+            .build();
+
+        let plan = poolable_request(first, Some(first.len())).unwrap();
+        let served = serve(&mut client, &mut upstream, first, &plan, options(), 16 * 1024).await;
+        assert_eq!(served.status, Some(200));
+        assert!(served.reusable);
+        assert!(!served.stale);
+        assert_eq!(served.close_reason, Some(CloseReason::ClientEof));
+    }
+
+    #[tokio::test]
+    async fn test_closed_channel_is_stale() {
+        let request = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut client = tokio_test::io::Builder::new().build();
+        let mut upstream = tokio_test::io::Builder::new().write(request).build();
+        let plan = poolable_request(request, Some(request.len())).unwrap();
+        let served = serve(&mut client, &mut upstream, request, &plan, options(), 16 * 1024).await;
+        assert!(served.stale);
+        assert!(!served.reusable);
+        assert_eq!(served.close_reason, Some(CloseReason::UpstreamEof));
+    }
+}
@@ -0,0 +1,1766 @@
+//! HTTP proxy layer for forwarding traffic through SSH tunnels.
+//! Reads the request head for subdomain routing, then passes the TCP stream through.
+
+pub mod access_log;
+pub mod banner;
+pub mod body_limit;
+pub mod caching;
+pub mod close_reason;
+pub mod compression;
+pub mod framing;
+pub mod har;
+pub mod http2;
+pub mod keep_alive;
+pub mod oauth;
+pub mod offline;
+pub mod path_routing;
+pub mod share_secret;
+pub mod proxy_protocol;
+pub mod recording;
+pub mod relay;
+pub mod request_head;
+pub mod response_headers;
+pub mod rewrite;
+pub mod secret_path;
+pub mod shaping;
+pub mod sni;
+pub mod tcp;
+pub mod udp;
+pub mod webhooks;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use russh::server::Msg;
+use russh::ChannelStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::accept::{bind_listener, ConnectionLimiter, PerIpLimiter};
+use crate::acl::Capability;
+use crate::config::{
+    get as get_config, get_tunnel_url, is_clustered, reloadable, ClusterMode, RoutingMode, TlsOnHttpPort,
+};
+use crate::crash::{set_subdomain, spawn_with_context, CrashContext};
+use crate::error::TunnelError;
+use crate::ssh::{is_session_gone, mark_session_dead, notify_local_health, notify_status_alert};
+use crate::state::channel_pool::PoolKey;
+use crate::state::cluster::{consume_relay_marker, relay_to_node, starts_with_relay_marker, RemoteTunnel};
+use crate::state::har::MAX_MESSAGE_BYTES;
+use crate::state::perf_profiles::{PerfProfile, DEFAULT_BUFFER_SIZE};
+use crate::state::response_cache::MAX_ENTRY_BYTES;
+use crate::state::static_routes::StaticRoute;
+use crate::state::tunnel_limits::LimitExceeded;
+use crate::state::{AppState, NamedForward, TunnelInfo};
+
+use self::access_log::{AccessLogEntry, StatusSniffer};
+use self::body_limit::{BodyLimit, BodyLimited};
+use self::close_reason::CloseReason;
+use self::framing::parse_request_head;
+use self::keep_alive::poolable_request;
+use self::path_routing::PathRoute;
+use self::proxy_protocol::read_proxy_header;
+use self::recording::Recorder;
+use self::relay::{relay, RelayEnd, RelayOptions, Relayed};
+use self::rewrite::ResponseEdit;
+use self::secret_path::SecretGate;
+use self::request_head::read_request_head;
+use self::response_headers::ResponseHeaderRewriter;
+use self::shaping::Shaped;
+use self::sni::{
+    looks_like_client_hello, read_client_hello, BufferedHello, Sni, HANDSHAKE_FAILURE_ALERT, MAX_CLIENT_HELLO,
+};
+
+/// Extract subdomain from Host header based on a given base domain.
+/// e.g., base_domain="localhost", host="test.localhost:8080" -> "test"
+/// e.g., base_domain="example.com", host="test.example.com" -> "test"
+/// 
+/// Validates subdomain length (max 63 chars) and characters (alphanumeric + hyphen).
+fn extract_subdomain_with_base(host: &str, base_domain: &str) -> Option<String> {
+    // Host header might have port, remove it for comparison
+    let host_without_port = host.split(':').next().unwrap_or(host);
+    
+    // Check if host ends with ".base_domain" (e.g., "test.localhost" ends with ".localhost")
+    let suffix = format!(".{}", base_domain);
+    if host_without_port.ends_with(&suffix) {
+        // Extract subdomain (everything before the suffix)
+        let subdomain = &host_without_port[..host_without_port.len() - suffix.len()];
+        
+        // Validate: not empty, no dots (single-level subdomain only)
+        if subdomain.is_empty() || subdomain.contains('.') {
+            return None;
+        }
+        
+        // Validate length (DNS label limit is 63 characters)
+        if subdomain.len() > 63 {
+            warn!("Subdomain too long (max 63 chars): {} chars", subdomain.len());
+            return None;
+        }
+        
+        // Validate characters (alphanumeric and hyphens only, case-insensitive)
+        let subdomain_lower = subdomain.to_lowercase();
+        if !subdomain_lower.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            warn!("Subdomain contains invalid characters: {}", subdomain);
+            return None;
+        }
+        
+        // Cannot start or end with hyphen
+        if subdomain_lower.starts_with('-') || subdomain_lower.ends_with('-') {
+            warn!("Subdomain cannot start or end with hyphen: {}", subdomain);
+            return None;
+        }
+        
+        return Some(subdomain_lower);
+    }
+    
+    None
+}
+
+/// Extract subdomain from Host header based on TUNNEL_URL configuration.
+/// If TUNNEL_URL is "localhost:8080", then "test.localhost:8080" -> "test"
+/// If TUNNEL_URL is "example.com", then "test.example.com" -> "test"
+fn extract_subdomain(host: &str) -> Option<String> {
+    let tunnel_url = &get_config().tunnel_url;
+    
+    // Remove port from tunnel_url for comparison (e.g., "localhost:8080" -> "localhost")
+    let base_domain = tunnel_url.split(':').next().unwrap_or(tunnel_url);
+    
+    extract_subdomain_with_base(host, base_domain).or_else(|| {
+        // Visitors redirected by another cluster node arrive on this node's own base domain
+        let redirect_base = get_config().cluster_redirect_base.as_deref()?;
+        extract_subdomain_with_base(host, redirect_base)
+    })
+}
+
+/// Header that forces routing to a named forward in multi-port sessions
+const UPSTREAM_HEADER: &str = "x-exlo-upstream";
+
+/// Header carrying the code of the proxy's error pages (see `TunnelError::code`)
+const ERROR_CODE_HEADER: &str = "x-exlo-error-code";
+
+/// How long connecting to a static route's upstream may take
+const STATIC_ROUTE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Extract a header value (case-insensitive name) from raw HTTP request bytes.
+fn extract_header_from_raw(data: &[u8], name: &str) -> Option<String> {
+    extract_header_values(data, name).into_iter().next()
+}
+
+/// Every value of a header (case-insensitive name), in order.
+fn extract_header_values(data: &[u8], name: &str) -> Vec<String> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}:", name.to_lowercase());
+
+    // Skip the request line; an empty line means end of headers
+    text.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter(|line| line.to_lowercase().starts_with(&prefix))
+        .map(|line| line[prefix.len()..].trim().to_string())
+        .collect()
+}
+
+/// Split an absolute-form request target (`http://app.example.com/path`)
+/// into its authority (without userinfo) and origin-form path.
+fn split_absolute_target(target: &str) -> Option<(&str, String)> {
+    let scheme_len = ["http://", "https://"]
+        .iter()
+        .find(|scheme| target.get(..scheme.len()).is_some_and(|s| s.eq_ignore_ascii_case(scheme)))?
+        .len();
+    let rest = &target[scheme_len..];
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let authority = rest[..end].rsplit('@').next().unwrap_or_default();
+    let path = match &rest[end..] {
+        "" => "/".to_string(),
+        path if path.starts_with('?') => format!("/{}", path),
+        path => path.to_string(),
+    };
+    Some((authority, path))
+}
+
+/// The raw request target from the request line.
+fn raw_request_target(data: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(data).ok()?;
+    text.lines().next()?.split_whitespace().nth(1)
+}
+
+/// Host a request is addressed to. The authority of an absolute-form target
+/// wins over the Host header (RFC 9112 §3.2.2); more than one Host header is
+/// rejected, since proxies and backends may each pick a different one.
+fn extract_request_host(data: &[u8]) -> Result<Option<String>, &'static str> {
+    let hosts = extract_header_values(data, "host");
+    if hosts.len() > 1 {
+        return Err("Multiple Host headers");
+    }
+    let authority = raw_request_target(data)
+        .and_then(split_absolute_target)
+        .map(|(authority, _)| authority.to_string());
+    match authority {
+        Some(authority) if authority.is_empty() => Err("Missing host in request target"),
+        Some(authority) => Ok(Some(authority)),
+        None => Ok(hosts.into_iter().next()),
+    }
+}
+
+/// Extract the request target (path) from the request line of raw HTTP bytes.
+/// An absolute-form target yields its path.
+fn extract_request_target(data: &[u8]) -> Option<String> {
+    let target = raw_request_target(data)?;
+    if target.starts_with('/') {
+        Some(target.to_string())
+    } else {
+        split_absolute_target(target).map(|(_, path)| path)
+    }
+}
+
+/// Generate a 307 redirect response.
+fn redirect_response(location: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 307 Temporary Redirect\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    )
+    .into_bytes()
+}
+
+/// Reason phrase of the statuses the proxy answers with
+fn status_text(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+/// Generate error response HTML.
+fn error_response(status: u16, message: &str) -> Vec<u8> {
+    let body = message.as_bytes();
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        status_text(status),
+        body.len(),
+        message
+    )
+    .into_bytes()
+}
+
+/// Error page for `error` with `status`, naming its code in the body and
+/// the `x-exlo-error-code` header
+fn coded_response(status: u16, error: &TunnelError, extra_headers: &str) -> Vec<u8> {
+    let body = format!("{}\n\nError code: {}", error, error.code());
+    format!(
+        "HTTP/1.1 {} {}\r\n{}{}: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        status_text(status),
+        extra_headers,
+        ERROR_CODE_HEADER,
+        error.code(),
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// Error page for a `TunnelError`, with its status and code
+fn tunnel_error_response(error: &TunnelError) -> Vec<u8> {
+    coded_response(error.http_status(), error, "")
+}
+
+/// 429 response for a tunnel over its request rate or connection limit.
+fn rate_limited_response(exceeded: LimitExceeded) -> Vec<u8> {
+    let (retry_after, error) = match exceeded {
+        LimitExceeded::Rate { retry_after } => (
+            retry_after.as_secs_f64().ceil().max(1.0) as u64,
+            TunnelError::RateLimited("too many requests for this tunnel".to_string()),
+        ),
+        LimitExceeded::Connections => (
+            1,
+            TunnelError::QuotaExceeded("too many concurrent connections to this tunnel".to_string()),
+        ),
+    };
+    coded_response(429, &error, &format!("Retry-After: {}\r\n", retry_after))
+}
+
+/// 503 for a request that found all of its tunnel's channels busy
+fn channels_busy_response() -> Vec<u8> {
+    let body = "Tunnel is busy, try again shortly";
+    format!(
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// Relay settings from the configuration, tuned by the tunnel's profile
+fn relay_options(profile: Option<PerfProfile>) -> RelayOptions {
+    let settings = reloadable();
+    RelayOptions {
+        idle: profile.map_or(settings.proxy_idle_timeout, |p| p.idle_timeout(settings.proxy_idle_timeout)),
+        write: settings.proxy_write_timeout,
+        buffer_size: profile.map_or(DEFAULT_BUFFER_SIZE, PerfProfile::buffer_size),
+    }
+}
+
+/// Apply the tunnel's profile to the visitor's socket
+fn tune_stream(stream: &TcpStream, profile: Option<PerfProfile>) {
+    if profile.is_some_and(PerfProfile::nodelay) {
+        let _ = stream.set_nodelay(true);
+    }
+}
+
+/// Put an abnormal end down to the tunnel going away, if it has meanwhile
+async fn classify_close(state: &AppState, subdomain: &str, session_id: &str, reason: CloseReason) -> CloseReason {
+    if reason.is_orderly() {
+        return reason;
+    }
+    let gone = state
+        .get_tunnel(subdomain)
+        .await
+        .is_none_or(|current| current.session_id != session_id);
+    if gone {
+        CloseReason::Kicked
+    } else {
+        reason
+    }
+}
+
+/// Log how a relayed connection ended and count its bytes
+async fn finish_relay(
+    state: &AppState,
+    tunnel: &TunnelInfo,
+    span: &str,
+    relayed: Relayed,
+    options: RelayOptions,
+    head_bytes: u64,
+    access: &mut AccessLogEntry,
+) {
+    access.close_reason = classify_close(state, &tunnel.subdomain, &tunnel.session_id, CloseReason::from_relay(&relayed.end)).await;
+    match relayed.end {
+        RelayEnd::IdleTimeout => debug!("[{}] Closed after {:?} without traffic", span, options.idle),
+        RelayEnd::WriteTimeout => warn!("[{}] Closed: peer stopped reading for {:?}", span, options.write),
+        _ => {}
+    }
+    debug!(
+        "[{}] Connection closed ({}): {} bytes to SSH, {} bytes to TCP",
+        span, access.close_reason, relayed.to_upstream, relayed.to_client
+    );
+    access.bytes_in = head_bytes + relayed.to_upstream;
+    access.bytes_out = relayed.to_client;
+}
+
+/// Open a forwarded channel to one of the tunnel's forwards
+async fn open_channel(
+    tunnel: &TunnelInfo,
+    upstream: &NamedForward,
+    client_addr: SocketAddr,
+) -> Result<ChannelStream<Msg>, russh::Error> {
+    let channel = tunnel
+        .handle
+        .channel_open_forwarded_tcpip(
+            &upstream.address,
+            upstream.port,
+            client_addr.ip().to_string(),
+            client_addr.port() as u32,
+        )
+        .await?;
+    Ok(channel.into_stream())
+}
+
+/// Record a failed channel open and build the visitor's response (status
+/// and raw bytes)
+async fn channel_open_failed(
+    state: &AppState,
+    tunnel: &TunnelInfo,
+    upstream: &NamedForward,
+    span: &str,
+    e: russh::Error,
+    access: &mut AccessLogEntry,
+    request: &[u8],
+) -> (u16, Vec<u8>) {
+    error!("[{}] Failed to open forwarded channel: {:?}", span, e);
+    let session_gone = is_session_gone(&e);
+    access.close_reason = if session_gone {
+        mark_session_dead(state, &tunnel.session_id).await;
+        CloseReason::Kicked
+    } else {
+        CloseReason::error("ChannelOpenFailed")
+    };
+    state.record_traffic(&tunnel.subdomain, 0, 0, &access.close_reason).await;
+    if session_gone {
+        // The session dropped before the proxy noticed: the tunnel is offline now
+        (503, offline::offline_response(&tunnel.subdomain, request))
+    } else if tunnel.awaiting_local_service {
+        let error = TunnelError::UpstreamUnreachable(format!(
+            "tunnel '{}' is waiting for the local service on port {} to start",
+            tunnel.subdomain, upstream.port
+        ));
+        (502, tunnel_error_response(&error))
+    } else if tunnel.degraded_since.is_some() && upstream.port == tunnel.requested_port {
+        record_status(state, &tunnel.subdomain, 502).await;
+        let error = TunnelError::UpstreamUnreachable(format!(
+            "the local service of tunnel '{}' on port {} stopped answering health checks",
+            tunnel.subdomain, upstream.port
+        ));
+        (502, tunnel_error_response(&error))
+    } else {
+        record_status(state, &tunnel.subdomain, 502).await;
+        let error = TunnelError::UpstreamUnreachable(format!("failed to connect to tunnel: {:?}", e));
+        (502, tunnel_error_response(&error))
+    }
+}
+
+/// Write a prepared response and record it in the access log.
+async fn respond(
+    stream: &mut TcpStream,
+    mut access: AccessLogEntry,
+    started: Instant,
+    status: u16,
+    response: &[u8],
+) -> AccessLogEntry {
+    if stream.write_all(response).await.is_ok() {
+        access.bytes_out = response.len() as u64;
+    }
+    access.status = Some(status);
+    access.finish(started)
+}
+
+/// Write an error response and record it in the access log.
+async fn respond_error(
+    stream: &mut TcpStream,
+    access: AccessLogEntry,
+    started: Instant,
+    status: u16,
+    message: &str,
+) -> AccessLogEntry {
+    respond(stream, access, started, status, &error_response(status, message)).await
+}
+
+/// How to address a tunnel in the configured routing mode
+fn usage_hint() -> String {
+    let config = get_config();
+    match config.routing_mode {
+        RoutingMode::Subdomain => format!("Use: curl -H \"Host: SUBDOMAIN.{}\" <address>", config.tunnel_url),
+        RoutingMode::Path => format!("Use: curl http://{}{}SUBDOMAIN/", config.tunnel_url, path_routing::PATH_PREFIX),
+    }
+}
+
+/// Generate tunnel list response.
+fn tunnel_list_response() -> Vec<u8> {
+    let body = format!(
+        "Tunnel Proxy Server\n\n{}\n\nConnect with: ssh -R 8000:localhost:8000 -p 2222 <subdomain>@server",
+        usage_hint()
+    );
+
+    error_response(400, &body)
+}
+
+/// Forward a request for a tunnel held by another cluster node.
+async fn forward_to_cluster_node(
+    stream: &mut TcpStream,
+    client_addr: SocketAddr,
+    remote: &RemoteTunnel,
+    request: &[u8],
+    mut access: AccessLogEntry,
+    started: Instant,
+) {
+    match get_config().cluster_mode {
+        ClusterMode::Relay => {
+            debug!(
+                "[{}] Relaying to node {} ({})",
+                remote.subdomain, remote.node_id, remote.proxy_addr
+            );
+            match relay_to_node(stream, remote, client_addr, request).await {
+                Ok((to_node, to_client)) => {
+                    access.bytes_in = to_node;
+                    access.bytes_out = to_client;
+                    access.finish(started);
+                }
+                Err(e) => {
+                    warn!("[{}] Relay to node {} failed: {:?}", remote.subdomain, remote.node_id, e);
+                    respond_error(stream, access, started, 502, "Failed to reach the node holding this tunnel")
+                        .await;
+                }
+            }
+        }
+        ClusterMode::Redirect => {
+            let Some(ref redirect_base) = remote.redirect_base else {
+                warn!(
+                    "[{}] Node {} has no redirect base configured",
+                    remote.subdomain, remote.node_id
+                );
+                respond_error(stream, access, started, 502, "Tunnel is held by another node").await;
+                return;
+            };
+            let path = extract_request_target(request).unwrap_or_else(|| "/".to_string());
+            // Scheme-relative so visitors keep whatever scheme they arrived with
+            let location = format!("//{}.{}{}", remote.subdomain, redirect_base, path);
+            debug!("[{}] Redirecting to node {}: {}", remote.subdomain, remote.node_id, location);
+            let response = redirect_response(&location);
+            if stream.write_all(&response).await.is_ok() {
+                access.bytes_out = response.len() as u64;
+            }
+            access.status = Some(307);
+            access.finish(started);
+        }
+    }
+}
+
+/// Forward a request to the upstream of an operator-defined static route.
+/// `initial` is the buffered request (with a path-routed head rewritten).
+async fn forward_to_static_route(
+    stream: &mut TcpStream,
+    route: &StaticRoute,
+    initial: &[u8],
+    body_limit: Option<BodyLimit>,
+    mut access: AccessLogEntry,
+    started: Instant,
+) {
+    let connected = tokio::time::timeout(STATIC_ROUTE_CONNECT_TIMEOUT, TcpStream::connect(&route.target)).await;
+    let upstream = match connected {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            warn!("[{}] Static route to {} failed: {}", route.subdomain, route.target, e);
+            access.close_reason = CloseReason::from_io(&e);
+            respond_error(stream, access, started, 502, "Failed to reach the upstream of this route").await;
+            return;
+        }
+        Err(_) => {
+            warn!("[{}] Static route to {} timed out", route.subdomain, route.target);
+            access.close_reason = CloseReason::error("ConnectTimeout");
+            respond_error(stream, access, started, 504, "The upstream of this route did not answer").await;
+            return;
+        }
+    };
+
+    let mut upstream = StatusSniffer::new(upstream);
+    if let Err(e) = upstream.write_all(initial).await {
+        debug!("[{}] Failed to forward request head: {:?}", route.subdomain, e);
+        access.close_reason = CloseReason::from_io(&e);
+        access.finish(started);
+        return;
+    }
+    let relayed = relay(&mut BodyLimited::new(stream, body_limit), &mut upstream, relay_options(None)).await;
+    access.close_reason = CloseReason::from_relay(&relayed.end);
+    access.bytes_in = initial.len() as u64 + relayed.to_upstream;
+    access.bytes_out = relayed.to_client;
+    access.status = upstream.status();
+    debug!("[{}] Static route connection closed ({})", route.subdomain, access.close_reason);
+    access.finish(started);
+}
+
+/// Answer a connection from an address already at `MAX_CONNECTIONS_PER_IP`
+async fn refuse_busy_ip(stream: &mut TcpStream, client_addr: SocketAddr) {
+    debug!("Refusing connection from {}: too many connections from this IP", client_addr);
+    let _ = stream
+        .write_all(&error_response(429, "Too many connections from your address"))
+        .await;
+}
+
+/// Handle a single TCP connection, routing on its request head.
+/// `client_addr` is the visitor's address (from PROXY protocol when enabled,
+/// or from the relay marker of a connection relayed by another node).
+async fn handle_connection(mut stream: TcpStream, mut client_addr: SocketAddr, state: Arc<AppState>) {
+    let config = get_config();
+    // The relay marker and the request head share the header deadline
+    let header_deadline = Instant::now() + config.request_header_timeout;
+
+    // One visitor can't take all connection slots
+    let Some(mut ip_permit) = visitor_limiter().try_acquire(client_addr.ip()) else {
+        refuse_busy_ip(&mut stream, client_addr).await;
+        return;
+    };
+
+    // Connections relayed by another node are never relayed again, and count
+    // against their visitor rather than the relaying node
+    let marker = tokio::time::timeout_at(header_deadline.into(), consume_relay_marker(&mut stream)).await;
+    let allow_cluster = match marker {
+        Ok(Ok(Some(relayed))) => {
+            client_addr = relayed.client_addr;
+            ip_permit = match visitor_limiter().try_acquire(client_addr.ip()) {
+                Some(permit) => permit,
+                None => {
+                    refuse_busy_ip(&mut stream, client_addr).await;
+                    return;
+                }
+            };
+            false
+        }
+        Ok(Ok(None)) => is_clustered(),
+        Ok(Err(e)) => {
+            warn!("Rejecting connection from {}: {}", client_addr, e);
+            return;
+        }
+        Err(_) => {
+            debug!("Connection from {} sent nothing within {:?}", client_addr, config.request_header_timeout);
+            return;
+        }
+    };
+    let _ip_permit = ip_permit;
+
+    // Read the request head, however many segments it arrives in
+    let header_limit = config.max_request_header_bytes;
+    let header_timeout = header_deadline.saturating_duration_since(Instant::now());
+    let buffered = match read_request_head(&mut stream, header_limit, header_timeout).await {
+        Ok(buffered) if buffered.data.is_empty() => {
+            debug!("Connection closed before data received");
+            return;
+        }
+        Ok(buffered) => buffered,
+        Err(e) => {
+            error!("Failed to read request head: {:?}", e);
+            return;
+        }
+    };
+    let request = &buffered.data[..];
+
+    // `https://` to the plain port: an HTTP error would only garble the handshake
+    if looks_like_client_hello(request) {
+        handle_tls_on_http_port(stream, buffered.data, client_addr, state).await;
+        return;
+    }
+
+    let started = Instant::now();
+    let mut access = AccessLogEntry::new(client_addr, request);
+
+    if buffered.exceeds(header_limit) {
+        respond_error(&mut stream, access, started, 431, "Request headers too large").await;
+        return;
+    }
+
+    if buffered.timed_out {
+        debug!("Request head from {} not complete within {:?}", client_addr, config.request_header_timeout);
+        respond_error(&mut stream, access, started, 408, "Request header timeout").await;
+        return;
+    }
+
+    // The rest of the body is counted as it is relayed (see `body_limit`)
+    let body_limit = match config.max_request_body_bytes.map(|max| BodyLimit::new(request, buffered.head_len, max)) {
+        Some(Ok(limit)) => Some(limit),
+        Some(Err(e)) if e.kind() == std::io::ErrorKind::FileTooLarge => {
+            respond_error(&mut stream, access, started, 413, "Request body too large").await;
+            return;
+        }
+        Some(Err(_)) => {
+            respond_error(&mut stream, access, started, 400, "Malformed request body").await;
+            return;
+        }
+        None => None,
+    };
+    let close_for_body_limit = body_limit.as_ref().is_some_and(BodyLimit::needs_close);
+
+    if let Some(ban) = state.bans.is_banned(client_addr.ip()).await {
+        debug!("Refusing HTTP request from banned IP {} ({})", client_addr, ban.reason);
+        respond_error(&mut stream, access, started, 403, "Forbidden").await;
+        return;
+    }
+
+    if !state.reputation.admit(client_addr.ip(), "HTTP").await {
+        respond_error(&mut stream, access, started, 403, "Forbidden").await;
+        return;
+    }
+
+    // h2c with prior knowledge: every stream is routed on its own
+    if http2::is_preface(request) {
+        http2::serve_connection(stream, buffered.data, client_addr, allow_cluster, state).await;
+        return;
+    }
+
+    // Extract the Host from the buffered head (or an absolute-form target)
+    let host = match extract_request_host(request) {
+        Ok(Some(h)) => h,
+        Err(reason) => {
+            debug!("Rejecting request from {}: {}", client_addr, reason);
+            respond_error(&mut stream, access, started, 400, reason).await;
+            return;
+        }
+        Ok(None) => {
+            warn!("No Host header found in request");
+            let response = tunnel_list_response();
+            let _ = stream.write_all(&response).await;
+            access.status = Some(400);
+            access.bytes_out = response.len() as u64;
+            access.finish(started);
+            return;
+        }
+    };
+
+    // Custom domains match the whole Host before subdomain extraction
+    let custom_domain = state.domains.resolve(&host).await;
+
+    // In path mode the tunnel is named by a /t/<subdomain>/ prefix
+    let path_route = match get_config().routing_mode {
+        RoutingMode::Path if custom_domain.is_none() => {
+            extract_request_target(request).and_then(|target| path_routing::route(&target))
+        }
+        _ => None,
+    };
+    let path_target = match path_route {
+        Some(PathRoute::Redirect { location }) => {
+            let response = redirect_response(&location);
+            if stream.write_all(&response).await.is_ok() {
+                access.bytes_out = response.len() as u64;
+            }
+            access.status = Some(307);
+            access.finish(started);
+            return;
+        }
+        Some(PathRoute::Forward { subdomain, target }) => Some((subdomain, target)),
+        None => None,
+    };
+
+    // Extract subdomain from Host
+    let subdomain = match custom_domain
+        .as_ref()
+        .map(|d| d.subdomain.clone())
+        .or_else(|| path_target.as_ref().map(|(subdomain, _)| subdomain.clone()))
+        .or_else(|| extract_subdomain(&host))
+    {
+        Some(s) => s,
+        None => {
+            // Providers return to the base domain after an OAuth sign-in
+            let target = extract_request_target(request);
+            if let Some(target) = target.filter(|t| t.split('?').next() == Some(oauth::CALLBACK_PATH)) {
+                let (status, response) = oauth::handle_callback(&state, &target).await;
+                if stream.write_all(&response).await.is_ok() {
+                    access.bytes_out = response.len() as u64;
+                }
+                access.status = Some(status);
+                access.finish(started);
+                return;
+            }
+
+            // No valid subdomain, show available tunnels (but not those
+            // hidden behind a secret path)
+            let tunnels = state.list_tunnels().await;
+            let tunnel_list: Vec<String> = tunnels
+                .iter()
+                .filter(|t| t.secret_path.is_none())
+                .map(|t| format!("  - {}", get_tunnel_url(&t.subdomain)))
+                .collect();
+
+            let body = if tunnel_list.is_empty() {
+                "No tunnels registered.\n\nConnect with: ssh -R 8000:localhost:8000 -p 2222 <subdomain>@server".to_string()
+            } else {
+                format!("Available tunnels:\n{}\n\n{}", tunnel_list.join("\n"), usage_hint())
+            };
+
+            respond_error(&mut stream, access, started, 400, &body).await;
+            return;
+        }
+    };
+
+    debug!(
+        "HTTP request for subdomain: {} from {} (conn={})",
+        subdomain, client_addr, access.connection_id
+    );
+    access.subdomain = Some(subdomain.clone());
+    set_subdomain(&subdomain);
+
+    // Static routes own their subdomains outright (tunnels can't register them)
+    if let Some(route) = state.static_routes.get(&subdomain).filter(|_| custom_domain.is_none()) {
+        let initial = match (&path_target, buffered.head_len) {
+            (None, Some(len)) if close_for_body_limit => {
+                match rewrite::rewrite_request_head(&request[..len], None, &[]) {
+                    Some(head) => [head.as_slice(), &request[len..]].concat(),
+                    None => {
+                        respond_error(&mut stream, access, started, 400, "Malformed request head").await;
+                        return;
+                    }
+                }
+            }
+            (None, _) => request.to_vec(),
+            (Some((_, target)), Some(len)) => match rewrite::rewrite_request_head(&request[..len], Some(target.as_str()), &[]) {
+                Some(head) => [head.as_slice(), &request[len..]].concat(),
+                None => {
+                    respond_error(&mut stream, access, started, 400, "Malformed request head").await;
+                    return;
+                }
+            },
+            (Some(_), None) => {
+                respond_error(&mut stream, access, started, 400, "Malformed request head").await;
+                return;
+            }
+        };
+        forward_to_static_route(&mut stream, route, &initial, body_limit, access, started).await;
+        return;
+    }
+
+    // Look up tunnel (a custom domain only reaches its owner's tunnel)
+    let tunnel = match state.get_tunnel(&subdomain).await {
+        Some(t) if custom_domain.as_ref().is_none_or(|d| d.user_id == t.username) => t,
+        Some(_) => {
+            let response = tunnel_error_response(&TunnelError::TunnelNotFound(host));
+            respond(&mut stream, access, started, 404, &response).await;
+            return;
+        }
+        None => {
+            if allow_cluster && custom_domain.is_none() {
+                if let Some(remote) = state.cluster.lookup(&subdomain).await {
+                    forward_to_cluster_node(&mut stream, client_addr, &remote, request, access, started)
+                        .await;
+                    return;
+                }
+            }
+            let response = tunnel_error_response(&TunnelError::TunnelNotFound(subdomain));
+            respond(&mut stream, access, started, 404, &response).await;
+            return;
+        }
+    };
+
+    // Every log line for this connection carries the tunnel's correlation ID
+    let span = format!("{} cid={} conn={}", subdomain, tunnel.correlation_id, access.connection_id);
+    access.correlation_id = Some(tunnel.correlation_id.clone());
+
+    // Secret-path tunnels look like no tunnel at all without their token,
+    // even while offline
+    if let Some(ref token) = tunnel.secret_path {
+        let original = extract_request_target(request).unwrap_or_else(|| "/".to_string());
+        let routed = path_target.as_ref().map_or(original.as_str(), |(_, target)| target.as_str());
+        let answer = match secret_path::gate(request, token, &original, routed) {
+            SecretGate::Forward => None,
+            SecretGate::Redirect { location, cookie } => {
+                Some((302, secret_path::redirect_response(&location, &cookie)))
+            }
+            SecretGate::NotFound => {
+                Some((404, tunnel_error_response(&TunnelError::TunnelNotFound(subdomain.clone()))))
+            }
+        };
+        if let Some((status, response)) = answer {
+            debug!("[{}] Secret path gate answered {} to {}", span, status, client_addr);
+            if stream.write_all(&response).await.is_ok() {
+                access.bytes_out = response.len() as u64;
+            }
+            access.status = Some(status);
+            state.request_finished(&tunnel.session_id, &access.finish(started));
+            return;
+        }
+    }
+
+    // The session is gone; waiting for it to reconnect
+    if !tunnel.is_connected {
+        let response = offline::offline_response(&subdomain, request);
+        respond(&mut stream, access, started, 503, &response).await;
+        return;
+    }
+
+    // Password-protected tunnels need the current share secret
+    if let Some(ref secret) = tunnel.share_secret {
+        if !share_secret::is_authorized(request, secret) {
+            debug!("[{}] Missing or wrong share secret from {}", span, client_addr);
+            let response = share_secret::unauthorized_response();
+            if stream.write_all(&response).await.is_ok() {
+                access.bytes_out = response.len() as u64;
+            }
+            access.status = Some(401);
+            state.request_finished(&tunnel.session_id, &access.finish(started));
+            return;
+        }
+    }
+
+    // OAuth-protected tunnels only see signed-in visitors their policy admits
+    if let Some(policy) = state.oauth.get(&subdomain).await {
+        let original = extract_request_target(request).unwrap_or_else(|| "/".to_string());
+        let routed = path_target.as_ref().map_or(original.as_str(), |(_, target)| target.as_str());
+        let domain = custom_domain.as_ref().map(|d| d.domain.as_str());
+        let gate = oauth::gate(request, domain, &subdomain, &policy, &original, routed);
+        if let oauth::Gate::Respond(status, response) = gate {
+            debug!("[{}] OAuth sign-in required for {} ({})", span, client_addr, status);
+            if stream.write_all(&response).await.is_ok() {
+                access.bytes_out = response.len() as u64;
+            }
+            access.status = Some(status);
+            state.request_finished(&tunnel.session_id, &access.finish(started));
+            return;
+        }
+    }
+
+    // Webhook deliveries are read in full and their signature checked before
+    // anything reaches the service
+    let with_body;
+    let target = match path_target.as_ref() {
+        Some((_, target)) => Some(target.clone()),
+        None => extract_request_target(request),
+    };
+    let path = target.as_deref().map(|target| target.split('?').next().unwrap_or(target));
+    let rule = match path {
+        Some(path) => state.webhooks.matching(&subdomain, path).await,
+        None => None,
+    };
+    let request = match (rule, buffered.head_len) {
+        (Some(rule), Some(head_len)) => {
+            let mut data = request.to_vec();
+            let timeout = config.request_header_timeout;
+            let checked = match webhooks::read_body(&mut stream, &mut data, head_len, timeout).await {
+                Ok(body) => {
+                    let now = webhooks::unix_now();
+                    webhooks::verify(&rule, &data[..head_len], &body, now).map_err(|e| (401, e))
+                }
+                Err(refused) => Err(refused),
+            };
+            if let Err((status, reason)) = checked {
+                debug!("[{}] Webhook delivery to {} refused: {}", span, rule.path, reason);
+                let access = respond_error(&mut stream, access, started, status, reason).await;
+                state.request_finished(&tunnel.session_id, &access);
+                return;
+            }
+            with_body = data;
+            &with_body[..]
+        }
+        (Some(_), None) => {
+            let access = respond_error(&mut stream, access, started, 400, "Malformed request head").await;
+            state.request_finished(&tunnel.session_id, &access);
+            return;
+        }
+        (None, _) => request,
+    };
+
+    // Path-routed requests are forwarded with the prefix stripped; the preview
+    // banner and profiles without compression need an uncompressed response;
+    // header rules, edge compression, the response cache and HAR capture need
+    // one response per connection (Connection: close), and so do webhook
+    // rules, the body size limit, the request rate limit and secret paths:
+    // only the first request of a connection has its signature checked, its
+    // body counted, a token taken from the tunnel's bucket and its secret path
+    // token checked and removed (upgrades are the last request of their
+    // connection)
+    let uncompressed = tunnel.preview_banner || !tunnel.perf_profile.is_none_or(PerfProfile::compression);
+    let has_header_rules = !tunnel.response_headers.is_empty();
+    let compress = match uncompressed {
+        false if config.response_compression => compression::negotiate(request),
+        _ => None,
+    };
+    let routed = path_target.as_ref().map(|(_, target)| target.as_str());
+    let cacheable = match tunnel.response_cache {
+        true => caching::cache_request(request, buffered.head_len, routed),
+        false => None,
+    };
+    let capturing = state.har.is_recording(&subdomain);
+    let checked_per_request = state.webhooks.has_rules(&subdomain).await;
+    let upgrade = buffered
+        .head_len
+        .and_then(|len| parse_request_head(&request[..len]))
+        .is_some_and(|head| head.upgrade);
+    let rate_limited = state.tunnel_limits.limit_for(&subdomain).await.0.requests_per_second > 0 && !upgrade;
+    let secret_gated = tunnel.secret_path.is_some() && !upgrade;
+    let edited = has_header_rules
+        || compress.is_some()
+        || cacheable.is_some()
+        || capturing
+        || checked_per_request
+        || close_for_body_limit
+        || rate_limited
+        || secret_gated;
+    let rewritten_head = if path_target.is_some() || uncompressed || edited {
+        let dropped = if uncompressed {
+            banner::DROPPED_REQUEST_HEADERS
+        } else {
+            &[]
+        };
+        let rewritten = buffered
+            .head_len
+            .and_then(|len| Some((len, rewrite::rewrite_request_head(&request[..len], routed, dropped)?)));
+        if rewritten.is_none() && path_target.is_some() {
+            respond_error(&mut stream, access, started, 400, "Malformed request head").await;
+            return;
+        }
+        rewritten
+    } else {
+        None
+    };
+    // The secret path token never reaches the service (an upgrade request
+    // keeps its connection headers)
+    let rewritten_head = match tunnel.secret_path {
+        Some(_) => {
+            let stripped = match rewritten_head {
+                Some((len, head)) => secret_path::strip_token(&head).map(|head| (len, head)),
+                None => buffered
+                    .head_len
+                    .and_then(|len| Some((len, secret_path::strip_token(&request[..len])?))),
+            };
+            if stripped.is_none() {
+                respond_error(&mut stream, access, started, 400, "Malformed request head").await;
+                return;
+            }
+            stripped
+        }
+        None => rewritten_head,
+    };
+    let response_edit = match rewritten_head {
+        Some(_) if tunnel.preview_banner => Some(ResponseEdit::Banner),
+        Some(_) => compress.map(ResponseEdit::Compress),
+        None => None,
+    };
+    let header_rules = (has_header_rules && rewritten_head.is_some()).then(|| tunnel.response_headers.clone());
+    let request_target = match path_target {
+        Some((_, target)) => Some(target),
+        None => extract_request_target(request),
+    };
+
+    // Pick the upstream forward (only differs from the primary in multi-port sessions)
+    let upstream_override = extract_header_from_raw(request, UPSTREAM_HEADER);
+    let upstream = match tunnel.select_upstream(upstream_override.as_deref(), request_target.as_deref()) {
+        Some(forward) => forward,
+        None => {
+            let message = format!(
+                "Unknown upstream '{}' for tunnel '{}'",
+                upstream_override.unwrap_or_default(),
+                subdomain
+            );
+            let access = respond_error(&mut stream, access, started, 404, &message).await;
+            state.request_finished(&tunnel.session_id, &access);
+            return;
+        }
+    };
+
+    debug!(
+        "[{}] Forwarding to {} (localhost:{})",
+        span, upstream.name, upstream.port
+    );
+
+    // Per-tunnel request rate and concurrent connection limits (with a rate
+    // limit every request comes on a connection of its own, see above)
+    if let Err(exceeded) = state.connection_opened(&subdomain).await {
+        debug!("[{}] Limit exceeded: {:?}", span, exceeded);
+        let response = rate_limited_response(exceeded);
+        if stream.write_all(&response).await.is_ok() {
+            access.bytes_out = response.len() as u64;
+        }
+        access.status = Some(429);
+        access.close_reason = CloseReason::LimitExceeded;
+        state.request_finished(&tunnel.session_id, &access.finish(started));
+        return;
+    }
+
+    // Fresh copies of static responses are answered without reaching the tunnel
+    if let Some(cache) = cacheable.as_ref().filter(|cache| cache.lookup) {
+        let header = |name: &str| extract_header_from_raw(request, name);
+        let hit = state
+            .response_cache
+            .lookup(&subdomain, &tunnel.correlation_id, &cache.key, header)
+            .await;
+        if let Some(hit) = hit {
+            debug!("[{}] Answered from the response cache", span);
+            let response = caching::cached_response(&hit, &tunnel.response_headers);
+            let written = match response_edit {
+                Some(edit) => rewrite::forward_response(&mut response.as_slice(), &mut stream, edit).await,
+                None => stream.write_all(&response).await.map(|()| response.len() as u64),
+            };
+            access.bytes_out = written.unwrap_or(0);
+            access.status = Some(200);
+            access.close_reason = CloseReason::CacheHit;
+            state.record_traffic(&subdomain, 0, access.bytes_out, &access.close_reason).await;
+            state.request_finished(&tunnel.session_id, &access.finish(started));
+            return;
+        }
+    }
+
+    // A channel slot of the tunnel, held until the exchange is over
+    let _slot = match state.channel_slots.acquire(&subdomain).await {
+        Ok(slot) => slot,
+        Err(_) => {
+            debug!("[{}] All channels of the tunnel stayed busy", span);
+            access.close_reason = CloseReason::LimitExceeded;
+            state.record_traffic(&subdomain, 0, 0, &access.close_reason).await;
+            let access = respond(&mut stream, access, started, 503, &channels_busy_response()).await;
+            state.request_finished(&tunnel.session_id, &access);
+            return;
+        }
+    };
+    let throttle = state.bandwidth.throttle(&subdomain, &tunnel.username, tunnel.tier.as_deref());
+
+    // Plain HTTP/1.1 requests can reuse an idle channel of the same forward
+    let pooled = match rewritten_head {
+        None if state.channel_pool.is_enabled() => poolable_request(request, buffered.head_len),
+        _ => None,
+    };
+    let pool_key = PoolKey {
+        session_id: tunnel.session_id.clone(),
+        address: upstream.address.clone(),
+        port: upstream.port,
+    };
+    let pooled_channel = match pooled {
+        Some(_) => state.channel_pool.checkout(&pool_key).await,
+        None => None,
+    };
+    let reused = pooled_channel.is_some();
+
+    // Open SSH forwarded channel (or take the pooled one)
+    let mut channel = match pooled_channel {
+        Some(channel) => {
+            debug!("[{}] Reusing pooled channel", span);
+            channel
+        }
+        None => match open_channel(&tunnel, &upstream, client_addr).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                let (status, response) =
+                    channel_open_failed(&state, &tunnel, &upstream, &span, e, &mut access, request).await;
+                let access = respond(&mut stream, access, started, status, &response).await;
+                state.request_finished(&tunnel.session_id, &access);
+                return;
+            }
+        },
+    };
+
+    // An accepted channel means the client reached its local service, so a
+    // waiting or degraded tunnel is healthy without the next health check
+    if !reused && tunnel.awaiting_local_service && state.mark_local_service_ready(&subdomain).await {
+        info!("[{}] Local service is up, tunnel is healthy", span);
+    }
+    if !reused && upstream.port == tunnel.requested_port && tunnel.degraded_since.is_some() {
+        if let Some(recovered) = state.record_health_check(&subdomain, true).await {
+            notify_local_health(recovered);
+        }
+    }
+
+    debug!("[{}] Opened forwarded channel to client", span);
+
+    let options = relay_options(tunnel.perf_profile);
+    tune_stream(&stream, tunnel.perf_profile);
+
+    // Pooled exchanges: one request at a time, the channel parked afterwards
+    if let Some(plan) = pooled {
+        let header_limit = config.max_request_header_bytes;
+        let mut served = {
+            let mut shaped = Shaped::new(&mut channel, throttle.clone());
+            keep_alive::serve(&mut stream, &mut shaped, request, &plan, options, header_limit).await
+        };
+        if served.stale && reused && plan.fully_buffered {
+            // The service closed the pooled connection meanwhile; try a fresh one
+            debug!("[{}] Pooled channel was closed, opening a new one", span);
+            channel = match open_channel(&tunnel, &upstream, client_addr).await {
+                Ok(channel) => channel,
+                Err(e) => {
+                    let (status, response) =
+                        channel_open_failed(&state, &tunnel, &upstream, &span, e, &mut access, request).await;
+                    let access = respond(&mut stream, access, started, status, &response).await;
+                    state.request_finished(&tunnel.session_id, &access);
+                    return;
+                }
+            };
+            let mut shaped = Shaped::new(&mut channel, throttle);
+            served = keep_alive::serve(&mut stream, &mut shaped, request, &plan, options, header_limit).await;
+        }
+        let reason = served.close_reason.unwrap_or(CloseReason::UpstreamEof);
+        access.close_reason = classify_close(&state, &tunnel.subdomain, &tunnel.session_id, reason).await;
+        debug!(
+            "[{}] Pooled connection closed ({}): {} bytes to SSH, {} bytes to TCP",
+            span, access.close_reason, served.to_upstream, served.to_client
+        );
+        access.bytes_in = served.to_upstream;
+        access.bytes_out = served.to_client;
+        access.status = served.status;
+        if let Some(at) = served.responded_at {
+            access.set_first_byte(started, at);
+        }
+        state
+            .record_traffic(&subdomain, access.bytes_in, access.bytes_out, &access.close_reason)
+            .await;
+        if let Some(status) = served.status {
+            record_status(&state, &subdomain, status).await;
+        }
+        if served.reusable {
+            state.channel_pool.checkin(pool_key, channel).await;
+        }
+        state.request_finished(&tunnel.session_id, &access.finish(started));
+        return;
+    }
+
+    // Convert SSH channel to stream for bidirectional I/O
+    let mut channel_stream = StatusSniffer::new(Shaped::new(channel, throttle));
+
+    // Forward what was already read (with the rewritten head, if any)
+    let initial = match rewritten_head {
+        Some((len, head)) => [head.as_slice(), &request[len..]].concat(),
+        None => request.to_vec(),
+    };
+    if let Err(e) = channel_stream.write_all(&initial).await {
+        debug!("[{}] Failed to forward request head: {:?}", span, e);
+        access.close_reason = classify_close(&state, &tunnel.subdomain, &tunnel.session_id, CloseReason::from_io(&e)).await;
+        state.record_traffic(&subdomain, 0, 0, &access.close_reason).await;
+        state.request_finished(&tunnel.session_id, &access.finish(started));
+        return;
+    }
+    let head_bytes = initial.len() as u64;
+    let forwarded_at = Instant::now();
+
+    // Relay between the TCP stream and the SSH channel stream, keeping copies
+    // for the response cache and HAR capture
+    let read_limit = [
+        cacheable.as_ref().map(|_| MAX_ENTRY_BYTES),
+        capturing.then_some(MAX_MESSAGE_BYTES),
+    ];
+    let mut recorder = Recorder::new(&mut channel_stream)
+        .record_reads(read_limit.into_iter().flatten().max())
+        .record_writes(capturing.then_some(MAX_MESSAGE_BYTES));
+    let mut upstream_stream = ResponseHeaderRewriter::new(&mut recorder, header_rules.unwrap_or_default());
+    let mut visitor = BodyLimited::new(&mut stream, body_limit);
+    if let Some(edit) = response_edit {
+        // One exchange, capped at the idle timeout
+        let timeout = options.idle;
+        match tokio::time::timeout(timeout, rewrite::proxy_exchange(&mut visitor, &mut upstream_stream, edit)).await {
+            Ok(Ok((to_ssh, to_tcp))) => {
+                access.bytes_in = head_bytes + to_ssh;
+                access.bytes_out = to_tcp;
+                access.close_reason = CloseReason::UpstreamEof;
+            }
+            Ok(Err(e)) => {
+                access.close_reason = classify_close(&state, &tunnel.subdomain, &tunnel.session_id, CloseReason::from_io(&e)).await;
+                debug!("[{}] Connection closed ({})", span, access.close_reason);
+            }
+            Err(_) => {
+                warn!("[{}] Connection timeout after {:?}", span, timeout);
+                access.close_reason = CloseReason::IdleTimeout;
+            }
+        }
+    } else {
+        let relayed = relay(&mut visitor, &mut upstream_stream, options).await;
+        finish_relay(&state, &tunnel, &span, relayed, options, head_bytes, &mut access).await;
+    }
+    let (received, sent) = recorder.into_recordings();
+    if let (Some(cache), Some(received)) = (cacheable, received.as_ref().filter(|received| !received.truncated)) {
+        if let Some(entry) = caching::storable(&received.bytes, request) {
+            debug!("[{}] Response stored in the response cache", span);
+            state
+                .response_cache
+                .store(&subdomain, &tunnel.correlation_id, cache.key, entry)
+                .await;
+        }
+    }
+    if let Some(received) = received.filter(|_| capturing) {
+        let scheme = extract_header_from_raw(request, "x-forwarded-proto").unwrap_or_else(|| "http".to_string());
+        let exchange = har::Exchange {
+            request,
+            sent: sent.as_ref(),
+            received: &received,
+            origin: format!("{}://{}", scheme, host),
+            connection_id: access.connection_id.clone(),
+            started_at: access.timestamp,
+            send: forwarded_at.duration_since(started),
+            wait: received.first_at.map_or(Duration::ZERO, |at| at.duration_since(forwarded_at)),
+            total: started.elapsed(),
+        };
+        match har::har_entry(&exchange) {
+            Some(entry) => state.har.record(&subdomain, entry),
+            None => debug!("[{}] Exchange not captured: no complete HTTP response", span),
+        }
+    }
+
+    state
+        .record_traffic(&subdomain, access.bytes_in, access.bytes_out, &access.close_reason)
+        .await;
+    access.status = channel_stream.status();
+    if let Some(at) = channel_stream.first_read() {
+        access.set_first_byte(started, at);
+    }
+    if let Some(status) = access.status {
+        record_status(&state, &subdomain, status).await;
+    }
+    state.request_finished(&tunnel.session_id, &access.finish(started));
+}
+
+/// Count a response of the tunneled service, alerting its owner if needed
+async fn record_status(state: &AppState, subdomain: &str, status: u16) {
+    if let Some((tunnel, change)) = state.record_status(subdomain, status).await {
+        notify_status_alert(tunnel, change);
+    }
+}
+
+/// Per-visitor-IP connection cap shared by the HTTP and TLS listeners
+fn visitor_limiter() -> &'static PerIpLimiter {
+    static LIMITER: OnceLock<PerIpLimiter> = OnceLock::new();
+    LIMITER.get_or_init(|| {
+        let config = get_config();
+        PerIpLimiter::new(config.max_connections_per_ip).with_exemptions(config.connection_limit_exempt.clone())
+    })
+}
+
+/// Run the HTTP proxy server.
+pub async fn run_http_proxy(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
+    let config = get_config();
+    let listener = bind_listener(addr, config.accept_backlog, config.reuse_port).await?;
+    let limiter = ConnectionLimiter::new("HTTP", config.max_http_connections);
+    state.readiness.set_http_listening();
+    info!(
+        "HTTP proxy listening on {} (max {} connections)",
+        addr, config.max_http_connections
+    );
+
+    let proxy_protocol = config.proxy_protocol;
+    let header_timeout = config.request_header_timeout;
+    if proxy_protocol {
+        info!("PROXY protocol enabled on HTTP proxy listener");
+    }
+
+    loop {
+        let (mut stream, remote_addr, permit) = limiter.accept(&listener).await;
+        let state = state.clone();
+
+        spawn_with_context(CrashContext::new("proxy"), async move {
+            // Released when the connection finishes
+            let _permit = permit;
+            debug!("HTTP connection from {}", remote_addr);
+
+            // A relaying peer sends its marker instead of a PROXY header
+            let relayed = proxy_protocol
+                && matches!(
+                    tokio::time::timeout(header_timeout, starts_with_relay_marker(&stream)).await,
+                    Ok(Ok(true))
+                );
+            let client_addr = if proxy_protocol && !relayed {
+                match read_proxy_header(&mut stream).await {
+                    Ok(Some(addr)) => addr,
+                    Ok(None) => remote_addr,
+                    Err(e) => {
+                        warn!("Rejecting connection from {}: {}", remote_addr, e);
+                        return;
+                    }
+                }
+            } else {
+                remote_addr
+            };
+
+            handle_connection(stream, client_addr, state).await;
+        });
+    }
+}
+
+/// Handle a TLS passthrough connection, routing on the ClientHello's SNI.
+/// The encrypted stream is forwarded untouched, so HTTP-level features
+/// (share secrets, path routing, the preview banner) don't apply.
+async fn handle_tls_connection(mut stream: TcpStream, client_addr: SocketAddr, state: Arc<AppState>) {
+    let hello = match read_client_hello(&mut stream, MAX_CLIENT_HELLO).await {
+        Ok(hello) if hello.data.is_empty() => {
+            debug!("TLS connection closed before data received");
+            return;
+        }
+        Ok(hello) => hello,
+        Err(e) => {
+            error!("Failed to read ClientHello: {:?}", e);
+            return;
+        }
+    };
+    serve_tls_passthrough(stream, hello, client_addr, state).await;
+}
+
+/// A TLS handshake on the plain HTTP port (`data` is what was read of it).
+/// With `TLS_ON_HTTP_PORT=passthrough` it is routed like the TLS listener's
+/// connections; otherwise it is refused with a `handshake_failure` alert,
+/// which browsers report as a TLS error rather than a garbled response, and
+/// logged with the host the visitor meant.
+async fn handle_tls_on_http_port(mut stream: TcpStream, data: Vec<u8>, client_addr: SocketAddr, state: Arc<AppState>) {
+    let hello = match read_client_hello(&mut data.as_slice().chain(&mut stream), MAX_CLIENT_HELLO).await {
+        Ok(hello) => hello,
+        Err(e) => {
+            debug!("Failed to read ClientHello on the HTTP port: {:?}", e);
+            return;
+        }
+    };
+    if get_config().tls_on_http_port == TlsOnHttpPort::Passthrough {
+        serve_tls_passthrough(stream, hello, client_addr, state).await;
+        return;
+    }
+
+    let started = Instant::now();
+    let mut access = AccessLogEntry::new(client_addr, &[]);
+    access.method = "TLS".to_string();
+    let server_name = match &hello.sni {
+        Sni::Found(name) => {
+            let custom_domain = state.domains.resolve(name).await;
+            access.subdomain = custom_domain.map(|d| d.subdomain).or_else(|| extract_subdomain(name));
+            name.as_str()
+        }
+        _ => "(no SNI)",
+    };
+    info!(
+        "Refused TLS handshake from {} for {} on the HTTP port, which speaks plain HTTP (use http://, \
+         or TLS_ON_HTTP_PORT=passthrough)",
+        client_addr, server_name
+    );
+    access.close_reason = CloseReason::Rejected;
+    let _ = stream.write_all(&HANDSHAKE_FAILURE_ALERT).await;
+    let _ = stream.shutdown().await;
+    access.finish(started);
+}
+
+/// Route a TLS connection on its ClientHello's SNI and relay it untouched
+async fn serve_tls_passthrough(
+    mut stream: TcpStream,
+    hello: BufferedHello,
+    client_addr: SocketAddr,
+    state: Arc<AppState>,
+) {
+    let started = Instant::now();
+    let mut access = AccessLogEntry::new(client_addr, &[]);
+    access.method = "TLS".to_string();
+
+    if let Some(ban) = state.bans.is_banned(client_addr.ip()).await {
+        debug!("Refusing TLS connection from banned IP {} ({})", client_addr, ban.reason);
+        access.finish(started);
+        return;
+    }
+
+    if !state.reputation.admit(client_addr.ip(), "TLS").await {
+        access.finish(started);
+        return;
+    }
+
+    let server_name = match hello.sni {
+        Sni::Found(name) => name,
+        other => {
+            debug!("No usable SNI from {} ({:?})", client_addr, other);
+            access.finish(started);
+            return;
+        }
+    };
+
+    // Custom domains match the whole server name before subdomain extraction
+    let custom_domain = state.domains.resolve(&server_name).await;
+    let Some(subdomain) = custom_domain
+        .as_ref()
+        .map(|d| d.subdomain.clone())
+        .or_else(|| extract_subdomain(&server_name))
+    else {
+        debug!("SNI '{}' from {} does not name a tunnel", server_name, client_addr);
+        access.finish(started);
+        return;
+    };
+    access.subdomain = Some(subdomain.clone());
+    set_subdomain(&subdomain);
+
+    // Tunnels held by other cluster nodes aren't reachable over TLS passthrough
+    let tunnel = match state.get_tunnel(&subdomain).await {
+        Some(t) if custom_domain.as_ref().is_none_or(|d| d.user_id == t.username) => t,
+        _ => {
+            debug!("TLS tunnel '{}' not found", subdomain);
+            access.finish(started);
+            return;
+        }
+    };
+
+    let span = format!("{} cid={} conn={}", subdomain, tunnel.correlation_id, access.connection_id);
+    access.correlation_id = Some(tunnel.correlation_id.clone());
+
+    if !tunnel.is_connected {
+        debug!("[{}] Tunnel is disconnected", span);
+        access.finish(started);
+        return;
+    }
+
+    if !tunnel.capabilities.allows(Capability::TlsPassthrough) {
+        debug!("[{}] TLS passthrough not enabled for the tunnel's owner", span);
+        access.finish(started);
+        return;
+    }
+
+    // The share secret travels inside the encrypted stream, so it can't be checked
+    if tunnel.share_secret.is_some() {
+        debug!("[{}] Refusing TLS passthrough to a password-protected tunnel", span);
+        access.finish(started);
+        return;
+    }
+
+    // Same for the OAuth session cookie and the secret path token
+    if tunnel.secret_path.is_some() {
+        debug!("[{}] Refusing TLS passthrough to a secret-path tunnel", span);
+        access.finish(started);
+        return;
+    }
+    if state.oauth.get(&subdomain).await.is_some() {
+        debug!("[{}] Refusing TLS passthrough to an OAuth-protected tunnel", span);
+        access.finish(started);
+        return;
+    }
+
+    let Some(upstream) = tunnel.select_upstream(None, None) else {
+        access.finish(started);
+        return;
+    };
+
+    if let Err(exceeded) = state.connection_opened(&subdomain).await {
+        debug!("[{}] Limit exceeded: {:?}", span, exceeded);
+        access.close_reason = CloseReason::LimitExceeded;
+        access.finish(started);
+        return;
+    }
+    let Ok(_slot) = state.channel_slots.acquire(&subdomain).await else {
+        debug!("[{}] All channels of the tunnel stayed busy", span);
+        access.close_reason = CloseReason::LimitExceeded;
+        state.record_traffic(&subdomain, 0, 0, &access.close_reason).await;
+        access.finish(started);
+        return;
+    };
+
+    let channel = match tunnel
+        .handle
+        .channel_open_forwarded_tcpip(
+            &upstream.address,
+            upstream.port,
+            client_addr.ip().to_string(),
+            client_addr.port() as u32,
+        )
+        .await
+    {
+        Ok(ch) => ch,
+        Err(e) => {
+            error!("[{}] Failed to open forwarded channel: {:?}", span, e);
+            access.close_reason = if is_session_gone(&e) {
+                mark_session_dead(&state, &tunnel.session_id).await;
+                CloseReason::Kicked
+            } else {
+                CloseReason::error("ChannelOpenFailed")
+            };
+            state.record_traffic(&subdomain, 0, 0, &access.close_reason).await;
+            access.finish(started);
+            return;
+        }
+    };
+
+    debug!("[{}] TLS passthrough to {} (localhost:{})", span, upstream.name, upstream.port);
+
+    let throttle = state.bandwidth.throttle(&subdomain, &tunnel.username, tunnel.tier.as_deref());
+    let mut channel_stream = Shaped::new(channel.into_stream(), throttle);
+    if let Err(e) = channel_stream.write_all(&hello.data).await {
+        debug!("[{}] Failed to forward ClientHello: {:?}", span, e);
+        access.close_reason = classify_close(&state, &tunnel.subdomain, &tunnel.session_id, CloseReason::from_io(&e)).await;
+        state.record_traffic(&subdomain, 0, 0, &access.close_reason).await;
+        access.finish(started);
+        return;
+    }
+
+    let options = relay_options(tunnel.perf_profile);
+    tune_stream(&stream, tunnel.perf_profile);
+    let relayed = relay(&mut stream, &mut channel_stream, options).await;
+    finish_relay(&state, &tunnel, &span, relayed, options, hello.data.len() as u64, &mut access).await;
+
+    state
+        .record_traffic(&subdomain, access.bytes_in, access.bytes_out, &access.close_reason)
+        .await;
+    state.request_finished(&tunnel.session_id, &access.finish(started));
+}
+
+/// Run the TLS passthrough listener, routing by SNI.
+pub async fn run_tls_proxy(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
+    let config = get_config();
+    let listener = bind_listener(addr, config.accept_backlog, config.reuse_port).await?;
+    let limiter = ConnectionLimiter::new("TLS", config.max_http_connections);
+    info!(
+        "TLS passthrough listening on {} (max {} connections)",
+        addr, config.max_http_connections
+    );
+
+    let proxy_protocol = config.proxy_protocol;
+    loop {
+        let (mut stream, remote_addr, permit) = limiter.accept(&listener).await;
+        let state = state.clone();
+
+        spawn_with_context(CrashContext::new("proxy::tls"), async move {
+            let _permit = permit;
+            debug!("TLS connection from {}", remote_addr);
+
+            let client_addr = if proxy_protocol {
+                match read_proxy_header(&mut stream).await {
+                    Ok(Some(addr)) => addr,
+                    Ok(None) => remote_addr,
+                    Err(e) => {
+                        warn!("Rejecting connection from {}: {}", remote_addr, e);
+                        return;
+                    }
+                }
+            } else {
+                remote_addr
+            };
+
+            // Counted together with the visitor's HTTP connections
+            let Some(_ip_permit) = visitor_limiter().try_acquire(client_addr.ip()) else {
+                debug!("Refusing TLS connection from {}: too many connections from this IP", client_addr);
+                return;
+            };
+
+            handle_tls_connection(stream, client_addr, state).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_subdomain_with_localhost() {
+        // With base_domain = "localhost"
+        assert_eq!(
+            extract_subdomain_with_base("test.localhost:8080", "localhost"),
+            Some("test".to_string())
+        );
+        assert_eq!(
+            extract_subdomain_with_base("tunnel-abc123.localhost:8080", "localhost"),
+            Some("tunnel-abc123".to_string())
+        );
+        assert_eq!(
+            extract_subdomain_with_base("myapp.localhost", "localhost"),
+            Some("myapp".to_string())
+        );
+        // No subdomain
+        assert_eq!(extract_subdomain_with_base("localhost:8080", "localhost"), None);
+        assert_eq!(extract_subdomain_with_base("localhost", "localhost"), None);
+    }
+
+    #[test]
+    fn test_extract_subdomain_with_domain() {
+        // With base_domain = "example.com"
+        assert_eq!(
+            extract_subdomain_with_base("test.example.com", "example.com"),
+            Some("test".to_string())
+        );
+        assert_eq!(
+            extract_subdomain_with_base("tunnel-xyz.example.com:8080", "example.com"),
+            Some("tunnel-xyz".to_string())
+        );
+        // No subdomain
+        assert_eq!(extract_subdomain_with_base("example.com", "example.com"), None);
+        assert_eq!(extract_subdomain_with_base("example.com:8080", "example.com"), None);
+        // Different domain should not match
+        assert_eq!(extract_subdomain_with_base("test.other.com", "example.com"), None);
+    }
+
+    #[test]
+    fn test_extract_subdomain_rejects_nested() {
+        // Should reject nested subdomains (e.g., "a.b.localhost")
+        assert_eq!(extract_subdomain_with_base("a.b.localhost", "localhost"), None);
+        assert_eq!(extract_subdomain_with_base("sub.test.example.com", "example.com"), None);
+    }
+
+    #[test]
+    fn test_extract_subdomain_with_base_domain_containing_port() {
+        // When TUNNEL_URL is "localhost:8080", the base_domain passed should be "localhost"
+        // This tests that the port stripping logic works correctly
+        
+        // Host with same port as TUNNEL_URL
+        assert_eq!(
+            extract_subdomain_with_base("myapp.localhost:8080", "localhost"),
+            Some("myapp".to_string())
+        );
+        
+        // Host with different port (should still work, we only care about domain)
+        assert_eq!(
+            extract_subdomain_with_base("myapp.localhost:9000", "localhost"),
+            Some("myapp".to_string())
+        );
+        
+        // Host without port
+        assert_eq!(
+            extract_subdomain_with_base("myapp.localhost", "localhost"),
+            Some("myapp".to_string())
+        );
+        
+        // Base domain itself (no subdomain)
+        assert_eq!(extract_subdomain_with_base("localhost:8080", "localhost"), None);
+        
+        // Test with multi-level domain like "tunnel.example.com"
+        assert_eq!(
+            extract_subdomain_with_base("myapp.tunnel.example.com:8080", "tunnel.example.com"),
+            Some("myapp".to_string())
+        );
+        assert_eq!(
+            extract_subdomain_with_base("tunnel.example.com:8080", "tunnel.example.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_request_host() {
+        let request = b"GET / HTTP/1.1\r\nHost: tunnel-abc.localhost:8080\r\nUser-Agent: curl\r\n\r\n";
+        assert_eq!(
+            extract_request_host(request),
+            Ok(Some("tunnel-abc.localhost:8080".to_string()))
+        );
+
+        let request_lower = b"GET / HTTP/1.1\r\nhost: tunnel-xyz.example.com\r\n\r\n";
+        assert_eq!(
+            extract_request_host(request_lower),
+            Ok(Some("tunnel-xyz.example.com".to_string()))
+        );
+
+        let no_host = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\n\r\n";
+        assert_eq!(extract_request_host(no_host), Ok(None));
+    }
+
+    #[test]
+    fn test_extract_upstream_header() {
+        let request = b"GET / HTTP/1.1\r\nHost: app.localhost\r\nX-EXLO-Upstream: api\r\n\r\n";
+        assert_eq!(
+            extract_header_from_raw(request, UPSTREAM_HEADER),
+            Some("api".to_string())
+        );
+
+        // Headers after the blank line (body) are ignored
+        let in_body = b"POST / HTTP/1.1\r\nHost: a\r\n\r\nx-exlo-upstream: api";
+        assert_eq!(extract_header_from_raw(in_body, UPSTREAM_HEADER), None);
+    }
+
+    #[test]
+    fn test_extract_request_target() {
+        let request = b"GET /api/users?id=1 HTTP/1.1\r\nHost: app.localhost\r\n\r\n";
+        assert_eq!(
+            extract_request_target(request),
+            Some("/api/users?id=1".to_string())
+        );
+
+        let absolute = b"GET http://app.localhost/ HTTP/1.1\r\n\r\n";
+        assert_eq!(extract_request_target(absolute), Some("/".to_string()));
+
+        let absolute = b"GET HTTPS://user@app.localhost:8080?x=1 HTTP/1.1\r\n\r\n";
+        assert_eq!(extract_request_target(absolute), Some("/?x=1".to_string()));
+
+        let authority = b"CONNECT app.localhost:443 HTTP/1.1\r\n\r\n";
+        assert_eq!(extract_request_target(authority), None);
+    }
+
+    #[test]
+    fn test_absolute_form_and_duplicate_hosts() {
+        // The request-target authority wins over the Host header
+        let absolute = b"GET http://app.localhost:8080/api HTTP/1.1\r\nHost: other.localhost\r\n\r\n";
+        assert_eq!(extract_request_host(absolute), Ok(Some("app.localhost:8080".to_string())));
+
+        let no_authority = b"GET http:///api HTTP/1.1\r\nHost: app.localhost\r\n\r\n";
+        assert!(extract_request_host(no_authority).is_err());
+
+        let duplicate = b"GET / HTTP/1.1\r\nHost: app.localhost\r\nhost: evil.localhost\r\n\r\n";
+        assert!(extract_request_host(duplicate).is_err());
+        let duplicate = b"GET http://app.localhost/ HTTP/1.1\r\nHost: a\r\nHost: a\r\n\r\n";
+        assert!(extract_request_host(duplicate).is_err());
+    }
+
+    #[test]
+    fn test_rate_limited_response() {
+        let exceeded = LimitExceeded::Rate {
+            retry_after: std::time::Duration::from_millis(1500),
+        };
+        let response = String::from_utf8(rate_limited_response(exceeded)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(response.contains("Retry-After: 2\r\n"));
+
+        let response = String::from_utf8(rate_limited_response(LimitExceeded::Connections)).unwrap();
+        assert!(response.contains("Retry-After: 1\r\nx-exlo-error-code: quota_exceeded\r\n"));
+        assert!(response.ends_with("Error code: quota_exceeded"));
+
+        let response = String::from_utf8(channels_busy_response()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\n"));
+    }
+}
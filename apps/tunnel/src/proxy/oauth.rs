@@ -0,0 +1,534 @@
+//! Sign-in with GitHub or Google in front of protected tunnels.
+//!
+//! A tunnel with an OAuth policy (see `state::oauth`) only forwards requests
+//! from visitors holding its session cookie; anyone else is redirected to the
+//! provider. The provider returns to the one redirect URI registered for the
+//! server (`OAUTH_CALLBACK_URL`, on the base domain), which looks the visitor
+//! up, checks the policy and sends a short-lived handoff token back to the
+//! tunnel's own host, where it is exchanged for the cookie (a cookie set on
+//! the base domain would never reach the tunnel). Login state, handoff tokens
+//! and cookies are signed with `INTERNAL_API_SECRET`. The policy is checked
+//! again on every request, so taking someone off it locks them out at once.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::path_routing::PATH_PREFIX;
+use super::share_secret::secrets_match;
+use super::{error_response, extract_header_values};
+use crate::config::{get as get_config, RoutingMode};
+use crate::state::oauth::{OAuthClient, OAuthPolicy, OAuthProvider};
+use crate::state::AppState;
+
+/// Redirect URI path on the base domain
+pub const CALLBACK_PATH: &str = "/_exlo/oauth/callback";
+
+/// Path on the tunnel's host that turns a handoff token into the cookie
+pub const SESSION_PATH: &str = "/_exlo/oauth/session";
+
+const COOKIE_NAME: &str = "exlo_oauth";
+
+/// Time allowed to finish signing in at the provider (seconds)
+const LOGIN_TTL: u64 = 10 * 60;
+
+/// Lifetime of a handoff token (seconds)
+const HANDOFF_TTL: u64 = 60;
+
+/// Time allowed for each call to the provider
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const GITHUB_USER_AGENT: &str = "exlo-tunnel";
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn signature(secret: &str, kind: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"oauth:");
+    mac.update(kind.as_bytes());
+    mac.update(b":");
+    mac.update(payload);
+    hex::encode(&mac.finalize().into_bytes()[..16])
+}
+
+/// `fields` as `<base64url payload>.<signature>`, valid until `expires`
+fn seal(secret: &str, kind: &str, expires: u64, fields: &[&str]) -> String {
+    let mut payload = expires.to_string();
+    for field in fields {
+        payload.push('\n');
+        payload.push_str(field);
+    }
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload.as_bytes()),
+        signature(secret, kind, payload.as_bytes())
+    )
+}
+
+/// Fields of a token sealed as `kind`, if it is genuine and unexpired at `now`
+fn open(secret: &str, kind: &str, token: &str, now: u64) -> Option<Vec<String>> {
+    let (encoded, sig) = token.trim().rsplit_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+    if !secrets_match(&signature(secret, kind, &payload), sig) {
+        return None;
+    }
+    let payload = String::from_utf8(payload).ok()?;
+    let mut fields = payload.split('\n').map(str::to_string);
+    let expires: u64 = fields.next()?.parse().ok()?;
+    (expires > now).then(|| fields.collect())
+}
+
+/// A visitor who signed in for one tunnel
+#[derive(Debug, Clone, PartialEq)]
+struct Visitor {
+    subdomain: String,
+    provider: OAuthProvider,
+    /// Login and verified emails at the provider
+    identities: Vec<String>,
+}
+
+impl Visitor {
+    fn seal(&self, secret: &str, kind: &str, expires: u64) -> String {
+        let mut fields = vec![self.subdomain.as_str(), self.provider.as_str()];
+        fields.extend(self.identities.iter().map(String::as_str));
+        seal(secret, kind, expires, &fields)
+    }
+
+    fn open(secret: &str, kind: &str, token: &str, now: u64) -> Option<Self> {
+        let mut fields = open(secret, kind, token, now)?.into_iter();
+        let subdomain = fields.next()?;
+        let provider = OAuthProvider::parse(&fields.next()?)?;
+        Some(Self {
+            subdomain,
+            provider,
+            identities: fields.collect(),
+        })
+    }
+}
+
+/// The visitor a request's cookie signs in to `subdomain`, if any
+fn signed_in(request: &[u8], secret: &str, subdomain: &str, now: u64) -> Option<Visitor> {
+    extract_header_values(request, "cookie")
+        .iter()
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .filter(|(name, _)| *name == COOKIE_NAME)
+        .filter_map(|(_, value)| Visitor::open(secret, "session", value, now))
+        .find(|visitor| visitor.subdomain == subdomain)
+}
+
+/// Whether a request carries a session `policy` currently admits (for
+/// protocols that can't be redirected through the sign-in)
+pub fn is_admitted(request: &[u8], subdomain: &str, policy: &OAuthPolicy) -> bool {
+    signed_in(request, &get_config().internal_api_secret, subdomain, unix_now())
+        .is_some_and(|visitor| policy.allows(visitor.provider, &visitor.identities))
+}
+
+fn client(provider: OAuthProvider) -> Option<&'static OAuthClient> {
+    let config = get_config();
+    match provider {
+        OAuthProvider::Github => config.oauth_github.as_ref(),
+        OAuthProvider::Google => config.oauth_google.as_ref(),
+    }
+}
+
+/// Whether the server has credentials for `provider`
+pub fn is_configured(provider: OAuthProvider) -> bool {
+    client(provider).is_some()
+}
+
+fn provider_name(provider: OAuthProvider) -> &'static str {
+    match provider {
+        OAuthProvider::Github => "GitHub",
+        OAuthProvider::Google => "Google",
+    }
+}
+
+/// Scheme visitors reach the proxy with, taken from the callback URL
+fn public_scheme() -> &'static str {
+    get_config()
+        .oauth_callback_url
+        .split_once("://")
+        .map_or("https", |(scheme, _)| scheme)
+}
+
+/// Where the callback sends the handoff token for a tunnel: its custom
+/// domain, or its subdomain of `tunnel_url` (the `/t/<subdomain>` prefix of
+/// the base domain in path mode). Never taken from a request's Host header,
+/// which would let a crafted Host send the token elsewhere.
+fn session_url(
+    scheme: &str,
+    tunnel_url: &str,
+    routing_mode: RoutingMode,
+    subdomain: &str,
+    custom_domain: Option<&str>,
+) -> Option<Url> {
+    // Custom domains are served on the same public port as the base domain
+    let port = tunnel_url
+        .rsplit_once(':')
+        .map(|(_, port)| port)
+        .filter(|port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+    let (host, prefix) = match (custom_domain, routing_mode) {
+        (Some(domain), _) => (port.map_or(domain.to_string(), |port| format!("{}:{}", domain, port)), String::new()),
+        (None, RoutingMode::Subdomain) => (format!("{}.{}", subdomain, tunnel_url), String::new()),
+        (None, RoutingMode::Path) => (tunnel_url.to_string(), format!("{}{}", PATH_PREFIX, subdomain)),
+    };
+    Url::parse(&format!("{}://{}{}{}", scheme, host, prefix, SESSION_PATH)).ok()
+}
+
+/// Whether `return_to` is the session URL of `subdomain` or of one of its
+/// custom domains (ignoring the query)
+fn is_session_url(return_to: &Url, subdomain: &str, custom_domains: &[String]) -> bool {
+    let config = get_config();
+    let expected = |domain: Option<&str>| {
+        session_url(public_scheme(), &config.tunnel_url, config.routing_mode, subdomain, domain)
+    };
+    std::iter::once(None)
+        .chain(custom_domains.iter().map(|domain| Some(domain.as_str())))
+        .filter_map(expected)
+        .any(|url| url.origin() == return_to.origin() && url.path() == return_to.path())
+}
+
+/// Query parameter `name` of an origin-form target
+fn query_param(target: &str, name: &str) -> Option<String> {
+    let url = Url::parse(&format!("http://localhost{}", target)).ok()?;
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// A same-host path to return to, or `/`
+fn local_path(next: Option<String>) -> String {
+    next.filter(|next| next.starts_with('/') && !next.starts_with("//") && !next.contains('\\'))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+/// 302 response, optionally setting a cookie
+fn found_response(location: &str, cookie: Option<&str>) -> Vec<u8> {
+    let cookie = cookie.map(|c| format!("Set-Cookie: {}\r\n", c)).unwrap_or_default();
+    format!(
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\n{}Cache-Control: no-store\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location, cookie
+    )
+    .into_bytes()
+}
+
+fn authorize_url(provider: OAuthProvider, client: &OAuthClient, state: &str) -> Option<Url> {
+    let redirect_uri = get_config().oauth_callback_url.as_str();
+    let (endpoint, scope) = match provider {
+        OAuthProvider::Github => ("https://github.com/login/oauth/authorize", "read:user user:email"),
+        OAuthProvider::Google => ("https://accounts.google.com/o/oauth2/v2/auth", "openid email"),
+    };
+    Url::parse_with_params(
+        endpoint,
+        &[
+            ("client_id", client.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", scope),
+            ("state", state),
+        ],
+    )
+    .ok()
+}
+
+/// What the proxy does with a request for a protected tunnel
+#[derive(Debug)]
+pub enum Gate {
+    /// The visitor is signed in and allowed
+    Forward,
+    /// Answer with this response (status for the access log)
+    Respond(u16, Vec<u8>),
+}
+
+/// Check a request for a tunnel protected by `policy`, reached through
+/// `custom_domain` if any. `original` is the request target as received and
+/// `routed` the one the tunnel would see (they differ by the `/t/<subdomain>`
+/// prefix in path mode).
+pub fn gate(
+    request: &[u8],
+    custom_domain: Option<&str>,
+    subdomain: &str,
+    policy: &OAuthPolicy,
+    original: &str,
+    routed: &str,
+) -> Gate {
+    let config = get_config();
+    let secret = config.internal_api_secret.as_str();
+    let now = unix_now();
+    let prefix = original.strip_suffix(routed).unwrap_or_default();
+    let path = routed.split('?').next().unwrap_or_default();
+
+    // Back from the callback: exchange the handoff token for the cookie
+    if path == SESSION_PATH {
+        let handoff = query_param(routed, "token")
+            .and_then(|token| Visitor::open(secret, "handoff", &token, now))
+            .filter(|visitor| visitor.subdomain == subdomain);
+        let Some(visitor) = handoff else {
+            return Gate::Respond(400, error_response(400, "Sign-in link is invalid or has expired"));
+        };
+        let ttl = config.oauth_session_ttl.as_secs();
+        let cookie = format!(
+            "{}={}; Path={}/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            COOKIE_NAME,
+            visitor.seal(secret, "session", now + ttl),
+            prefix,
+            ttl,
+            if public_scheme() == "https" { "; Secure" } else { "" }
+        );
+        let next = local_path(query_param(routed, "next"));
+        return Gate::Respond(302, found_response(&next, Some(&cookie)));
+    }
+
+    match signed_in(request, secret, subdomain, now) {
+        Some(visitor) if policy.allows(visitor.provider, &visitor.identities) => Gate::Forward,
+        Some(visitor) => {
+            let message = format!(
+                "Signed in with {} as {}, which may not visit this tunnel",
+                provider_name(visitor.provider),
+                visitor.identities.join(", ")
+            );
+            Gate::Respond(403, error_response(403, &message))
+        }
+        None => {
+            let Some(client) = client(policy.provider) else {
+                let name = provider_name(policy.provider);
+                let message = format!("Sign-in with {} is not available on this server", name);
+                return Gate::Respond(503, error_response(503, &message));
+            };
+            let return_to =
+                session_url(public_scheme(), &config.tunnel_url, config.routing_mode, subdomain, custom_domain);
+            let Some(mut return_to) = return_to else {
+                return Gate::Respond(500, error_response(500, "Sign-in is misconfigured"));
+            };
+            return_to.query_pairs_mut().append_pair("next", original);
+            let state = seal(
+                secret,
+                "state",
+                now + LOGIN_TTL,
+                &[subdomain, policy.provider.as_str(), return_to.as_str()],
+            );
+            match authorize_url(policy.provider, client, &state) {
+                Some(url) => Gate::Respond(302, found_response(url.as_str(), None)),
+                None => Gate::Respond(500, error_response(500, "Sign-in is misconfigured")),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    verified: bool,
+}
+
+#[derive(Deserialize)]
+struct GoogleUser {
+    email: String,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+/// Redeem an authorization code for the visitor's login and verified emails
+async fn fetch_identities(provider: OAuthProvider, client: &OAuthClient, code: &str) -> anyhow::Result<Vec<String>> {
+    let http = reqwest::Client::builder()
+        .timeout(PROVIDER_TIMEOUT)
+        .user_agent(GITHUB_USER_AGENT)
+        .build()?;
+    let token_url = match provider {
+        OAuthProvider::Github => "https://github.com/login/oauth/access_token",
+        OAuthProvider::Google => "https://oauth2.googleapis.com/token",
+    };
+    let token: TokenResponse = http
+        .post(token_url)
+        .header("Accept", "application/json")
+        .form(&[
+            ("client_id", client.client_id.as_str()),
+            ("client_secret", client.client_secret.as_str()),
+            ("code", code),
+            ("redirect_uri", get_config().oauth_callback_url.as_str()),
+            ("grant_type", "authorization_code"),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    match provider {
+        OAuthProvider::Github => {
+            let user: GithubUser = http
+                .get("https://api.github.com/user")
+                .bearer_auth(&token.access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let emails: Vec<GithubEmail> = http
+                .get("https://api.github.com/user/emails")
+                .bearer_auth(&token.access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let mut identities = vec![user.login];
+            identities.extend(emails.into_iter().filter(|e| e.verified).map(|e| e.email));
+            Ok(identities)
+        }
+        OAuthProvider::Google => {
+            let user: GoogleUser = http
+                .get("https://openidconnect.googleapis.com/v1/userinfo")
+                .bearer_auth(&token.access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            if !user.email_verified {
+                anyhow::bail!("the Google account's email is not verified");
+            }
+            Ok(vec![user.email])
+        }
+    }
+}
+
+/// Handle the provider's redirect back to `CALLBACK_PATH`; returns the status
+/// and response
+pub async fn handle_callback(state: &AppState, target: &str) -> (u16, Vec<u8>) {
+    let secret = get_config().internal_api_secret.as_str();
+    if let Some(error) = query_param(target, "error") {
+        let message = format!("Sign-in was not completed ({})", error);
+        return (403, error_response(403, &message));
+    }
+    let login = query_param(target, "state").and_then(|token| open(secret, "state", &token, unix_now()));
+    let (Some([subdomain, provider, return_to]), Some(code)) =
+        (login.as_deref(), query_param(target, "code"))
+    else {
+        return (400, error_response(400, "Sign-in has expired, reload the page to try again"));
+    };
+    let (Some(provider), Ok(mut return_to)) = (OAuthProvider::parse(provider), Url::parse(return_to)) else {
+        return (400, error_response(400, "Invalid sign-in state"));
+    };
+
+    let custom_domains: Vec<String> = state
+        .domains
+        .list()
+        .await
+        .into_iter()
+        .filter(|domain| domain.subdomain == *subdomain)
+        .map(|domain| domain.domain)
+        .collect();
+    if !is_session_url(&return_to, subdomain, &custom_domains) {
+        warn!("Refusing OAuth handoff for '{}' to foreign URL {}", subdomain, return_to);
+        return (400, error_response(400, "Invalid sign-in state"));
+    }
+
+    let Some(policy) = state.oauth.get(subdomain).await.filter(|policy| policy.provider == provider) else {
+        let message = format!("Tunnel '{}' no longer asks for {} sign-in", subdomain, provider_name(provider));
+        return (400, error_response(400, &message));
+    };
+    let Some(client) = client(provider) else {
+        let message = format!("Sign-in with {} is not available on this server", provider_name(provider));
+        return (503, error_response(503, &message));
+    };
+    let identities = match fetch_identities(provider, client, &code).await {
+        Ok(identities) => identities,
+        Err(e) => {
+            warn!("{} sign-in for '{}' failed: {:#}", provider_name(provider), subdomain, e);
+            let message = format!("Sign-in with {} failed", provider_name(provider));
+            return (502, error_response(502, &message));
+        }
+    };
+    if !policy.allows(provider, &identities) {
+        debug!("{} sign-in as {:?} refused for '{}'", provider_name(provider), identities, subdomain);
+        let message = format!(
+            "Signed in with {} as {}, which may not visit this tunnel",
+            provider_name(provider),
+            identities.join(", ")
+        );
+        return (403, error_response(403, &message));
+    }
+
+    let visitor = Visitor {
+        subdomain: subdomain.clone(),
+        provider,
+        identities,
+    };
+    let handoff = visitor.seal(secret, "handoff", unix_now() + HANDOFF_TTL);
+    return_to.query_pairs_mut().append_pair("token", &handoff);
+    (302, found_response(return_to.as_str(), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let visitor = Visitor {
+            subdomain: "app".to_string(),
+            provider: OAuthProvider::Github,
+            identities: vec!["octocat".to_string(), "octo@example.com".to_string()],
+        };
+        let token = visitor.seal("secret", "session", 1_000);
+        assert_eq!(Visitor::open("secret", "session", &token, 999), Some(visitor.clone()));
+        // Expired, other key, other kind, tampered
+        assert_eq!(Visitor::open("secret", "session", &token, 1_000), None);
+        assert_eq!(Visitor::open("other", "session", &token, 999), None);
+        assert_eq!(Visitor::open("secret", "handoff", &token, 999), None);
+        let (_, sig) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(b"2000\napp\ngithub\nmallory");
+        assert_eq!(Visitor::open("secret", "session", &format!("{}.{}", forged, sig), 999), None);
+
+        let request = format!("GET / HTTP/1.1\r\nHost: app\r\nCookie: a=b; {}={}\r\n\r\n", COOKIE_NAME, token);
+        assert_eq!(signed_in(request.as_bytes(), "secret", "app", 999), Some(visitor));
+        assert_eq!(signed_in(request.as_bytes(), "secret", "other", 999), None);
+    }
+
+    #[test]
+    fn test_session_url() {
+        let url = |mode, domain| session_url("https", "exlo.dev:8443", mode, "app", domain).unwrap().to_string();
+        assert_eq!(url(RoutingMode::Subdomain, None), "https://app.exlo.dev:8443/_exlo/oauth/session");
+        assert_eq!(url(RoutingMode::Path, None), "https://exlo.dev:8443/t/app/_exlo/oauth/session");
+        assert_eq!(
+            url(RoutingMode::Subdomain, Some("demo.example.com")),
+            "https://demo.example.com:8443/_exlo/oauth/session"
+        );
+
+        // A Host like `app.exlo.dev:x@evil.com` once ended up as the handoff target
+        let forged = Url::parse("https://app.exlo.dev:x@evil.com/_exlo/oauth/session").unwrap();
+        let genuine = session_url("https", "exlo.dev:8443", RoutingMode::Subdomain, "app", None).unwrap();
+        assert_eq!(forged.host_str(), Some("evil.com"));
+        assert_ne!(forged.origin(), genuine.origin());
+    }
+
+    #[test]
+    fn test_local_path() {
+        assert_eq!(local_path(Some("/t/app/x?y=1".to_string())), "/t/app/x?y=1");
+        assert_eq!(local_path(Some("//evil.com".to_string())), "/");
+        assert_eq!(local_path(Some("https://evil.com".to_string())), "/");
+        assert_eq!(local_path(None), "/");
+        assert_eq!(query_param("/cb?code=a%20b&state=x", "code").as_deref(), Some("a b"));
+    }
+}
@@ -0,0 +1,73 @@
+//! "Tunnel offline" page for tunnels waiting for their session.
+//!
+//! Between a session dropping and its reconnect window closing, the tunnel
+//! still exists but nothing can be forwarded. Visitors get a 503 with
+//! `Retry-After` instead of a bare error; browsers get a page that reloads
+//! itself, so the app reappears once the developer's laptop wakes up.
+
+use super::extract_header_from_raw;
+
+/// Seconds visitors are asked to wait before trying again
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/// Whether the visitor is a browser asking for a page
+fn wants_html(request: &[u8]) -> bool {
+    extract_header_from_raw(request, "accept").is_some_and(|accept| accept.to_lowercase().contains("text/html"))
+}
+
+fn offline_page(subdomain: &str) -> String {
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">",
+            "<meta http-equiv=\"refresh\" content=\"{retry}\">",
+            "<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">",
+            "<title>{subdomain} is offline</title></head>\n",
+            "<body style=\"font:16px/1.5 sans-serif;max-width:36em;margin:15vh auto;padding:0 1em;color:#333\">",
+            "<h1 style=\"font-size:1.4em\">{subdomain} is offline</h1>",
+            "<p>The computer serving this tunnel lost its connection, for example because it went to sleep. ",
+            "The tunnel is kept for a while so it can come back at the same address.</p>",
+            "<p style=\"color:#777\">This page tries again every {retry} seconds.</p>",
+            "</body></html>\n"
+        ),
+        subdomain = subdomain,
+        retry = RETRY_AFTER_SECS
+    )
+}
+
+/// 503 response for a tunnel whose session is gone, as a self-refreshing
+/// page for browsers and plain text otherwise
+pub fn offline_response(subdomain: &str, request: &[u8]) -> Vec<u8> {
+    let (content_type, body) = if wants_html(request) {
+        ("text/html; charset=utf-8", offline_page(subdomain))
+    } else {
+        ("text/plain", format!("Tunnel '{}' is offline, waiting for it to reconnect", subdomain))
+    };
+    format!(
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\nCache-Control: no-store\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        RETRY_AFTER_SECS,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_response() {
+        let browser = b"GET / HTTP/1.1\r\nHost: app.localhost\r\nAccept: text/html,*/*;q=0.8\r\n\r\n";
+        let response = String::from_utf8(offline_response("app", browser)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: 5\r\n"));
+        assert!(response.contains("<meta http-equiv=\"refresh\" content=\"5\">"));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+
+        let curl = b"GET / HTTP/1.1\r\nHost: app.localhost\r\nAccept: */*\r\n\r\n";
+        let response = String::from_utf8(offline_response("app", curl)).unwrap();
+        assert!(response.contains("Content-Type: text/plain"));
+        assert!(response.ends_with("Tunnel 'app' is offline, waiting for it to reconnect"));
+    }
+}
@@ -0,0 +1,72 @@
+//! Path-based routing for deployments without wildcard DNS.
+//!
+//! With `ROUTING_MODE=path` tunnels are addressed as `TUNNEL_URL/t/<subdomain>/...`.
+//! The proxy strips the prefix from the request line before forwarding (see
+//! `rewrite`), so every request on a browser connection is routed again.
+
+/// Path prefix that addresses a tunnel
+pub const PATH_PREFIX: &str = "/t/";
+
+/// Where a path-routed request goes
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathRoute {
+    /// Forward to the tunnel with the prefix stripped from the target
+    Forward { subdomain: String, target: String },
+    /// `/t/<subdomain>` without the trailing slash; redirect so relative links resolve
+    Redirect { location: String },
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Route a request target of the form `/t/<subdomain>/<rest>`
+pub fn route(target: &str) -> Option<PathRoute> {
+    let after_prefix = target.strip_prefix(PATH_PREFIX)?;
+    let end = after_prefix.find(['/', '?']).unwrap_or(after_prefix.len());
+    let (label, rest) = after_prefix.split_at(end);
+    if !is_valid_label(label) {
+        return None;
+    }
+    let subdomain = label.to_lowercase();
+
+    if rest.starts_with('/') {
+        Some(PathRoute::Forward {
+            subdomain,
+            target: rest.to_string(),
+        })
+    } else {
+        Some(PathRoute::Redirect {
+            location: format!("{}{}/{}", PATH_PREFIX, subdomain, rest),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(
+            route("/t/MyApp/api/users?page=2"),
+            Some(PathRoute::Forward {
+                subdomain: "myapp".to_string(),
+                target: "/api/users?page=2".to_string()
+            })
+        );
+        assert_eq!(
+            route("/t/myapp?x=1"),
+            Some(PathRoute::Redirect {
+                location: "/t/myapp/?x=1".to_string()
+            })
+        );
+        assert_eq!(route("/api/users"), None);
+        assert_eq!(route("/t/"), None);
+        assert_eq!(route("/t/bad_name/"), None);
+    }
+}
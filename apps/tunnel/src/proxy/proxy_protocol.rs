@@ -0,0 +1,196 @@
+//! PROXY protocol (v1 text / v2 binary) header parsing.
+//!
+//! When the HTTP proxy runs behind a TCP load balancer, the balancer prepends a
+//! PROXY header carrying the original client address. The header is consumed
+//! before routing so the visitor's request bytes reach the tunnel untouched.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature that starts every v2 header
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Maximum length of a v1 header line including CRLF (per spec)
+const V1_MAX_LENGTH: usize = 107;
+
+/// Maximum time allowed for the load balancer to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyProtocolError {
+    #[error("Missing PROXY protocol header")]
+    Missing,
+    #[error("Malformed PROXY protocol header: {0}")]
+    Malformed(&'static str),
+    #[error("Timed out waiting for PROXY protocol header")]
+    Timeout,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Parse a v1 header line (without the trailing CRLF).
+/// Returns the source address, or None for "PROXY UNKNOWN".
+fn parse_v1_line(line: &str) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(ProxyProtocolError::Missing);
+    }
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(ProxyProtocolError::Malformed("unknown protocol family")),
+    }
+
+    let src_ip: IpAddr = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ProxyProtocolError::Malformed("invalid source address"))?;
+    let _dst_ip: IpAddr = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ProxyProtocolError::Malformed("invalid destination address"))?;
+    let src_port: u16 = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ProxyProtocolError::Malformed("invalid source port"))?;
+
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+/// Parse the address block of a v2 header.
+/// Returns None for LOCAL commands and unsupported families.
+fn parse_v2_addresses(
+    ver_cmd: u8,
+    family: u8,
+    block: &[u8],
+) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    if ver_cmd >> 4 != 0x2 {
+        return Err(ProxyProtocolError::Malformed("unsupported version"));
+    }
+
+    // LOCAL command: health checks from the balancer itself
+    if ver_cmd & 0x0F == 0x0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        // AF_INET: src(4) dst(4) sport(2) dport(2)
+        0x1 => {
+            if block.len() < 12 {
+                return Err(ProxyProtocolError::Malformed("short IPv4 address block"));
+            }
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6: src(16) dst(16) sport(2) dport(2)
+        0x2 => {
+            if block.len() < 36 {
+                return Err(ProxyProtocolError::Malformed("short IPv6 address block"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        let mut meta = [0u8; 4];
+        stream.read_exact(&mut meta).await?;
+        let len = u16::from_be_bytes([meta[2], meta[3]]) as usize;
+        let mut block = vec![0u8; len];
+        stream.read_exact(&mut block).await?;
+        return parse_v2_addresses(meta[0], meta[1], &block);
+    }
+
+    if !prefix.starts_with(b"PROXY ") {
+        return Err(ProxyProtocolError::Missing);
+    }
+
+    // v1: read the rest of the line byte by byte so nothing past CRLF is consumed
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(ProxyProtocolError::Malformed("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let text = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyProtocolError::Malformed("v1 header is not ASCII"))?;
+    parse_v1_line(text)
+}
+
+/// Consume a PROXY protocol header from the start of the stream.
+/// Returns the original client address, or None if the balancer didn't provide one.
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| ProxyProtocolError::Timeout)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1_tcp4() {
+        let addr = parse_v1_line("PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080").unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_v1_tcp6_and_unknown() {
+        let addr = parse_v1_line("PROXY TCP6 2001:db8::1 2001:db8::2 4000 80").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(parse_v1_line("PROXY UNKNOWN").unwrap(), None);
+        assert!(parse_v1_line("PROXY TCP4 bogus 10.0.0.1 1 2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_header_leaves_request_bytes() {
+        let data = b"PROXY TCP4 198.51.100.2 10.0.0.1 40000 80\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut reader = &data[..];
+        let addr = read_proxy_header(&mut reader).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.2:40000".parse().unwrap()));
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        data.extend_from_slice(&[192, 0, 2, 10, 10, 0, 0, 1, 0x1F, 0x90, 0x00, 0x50]);
+        data.extend_from_slice(b"GET /");
+
+        let mut reader = &data[..];
+        let addr = read_proxy_header(&mut reader).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.10:8080".parse().unwrap()));
+        assert_eq!(reader, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn test_missing_header_is_rejected() {
+        let mut reader = &b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"[..];
+        assert!(matches!(
+            read_proxy_header(&mut reader).await,
+            Err(ProxyProtocolError::Missing)
+        ));
+    }
+}
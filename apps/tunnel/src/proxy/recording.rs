@@ -0,0 +1,128 @@
+//! Copies of the bytes an exchange moves through the tunnel.
+//!
+//! The response cache keeps what the tunnel sends back, HAR capture also the
+//! request body going out to it. Both need a single exchange per connection,
+//! so the recorded streams hold exactly one request and its response.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes seen in one direction, up to a limit
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub bytes: Vec<u8>,
+    /// More bytes passed than were kept
+    pub truncated: bool,
+    /// When the first byte passed
+    pub first_at: Option<Instant>,
+}
+
+impl Recording {
+    fn push(&mut self, data: &[u8], limit: usize) {
+        if data.is_empty() {
+            return;
+        }
+        self.first_at.get_or_insert_with(Instant::now);
+        let room = limit.saturating_sub(self.bytes.len());
+        self.bytes.extend_from_slice(&data[..room.min(data.len())]);
+        self.truncated |= data.len() > room;
+    }
+}
+
+/// Stream wrapper to the tunnel keeping what is read (the response) and
+/// written (the request) when asked to
+pub struct Recorder<S> {
+    inner: S,
+    reads: Option<(Recording, usize)>,
+    writes: Option<(Recording, usize)>,
+}
+
+impl<S> Recorder<S> {
+    /// Without recording the stream passes through untouched
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            reads: None,
+            writes: None,
+        }
+    }
+
+    /// Keep up to `limit` bytes read from the tunnel
+    pub fn record_reads(mut self, limit: Option<usize>) -> Self {
+        self.reads = limit.map(|limit| (Recording::default(), limit));
+        self
+    }
+
+    /// Keep up to `limit` bytes written to the tunnel
+    pub fn record_writes(mut self, limit: Option<usize>) -> Self {
+        self.writes = limit.map(|limit| (Recording::default(), limit));
+        self
+    }
+
+    /// What was read and written, for the directions being recorded
+    pub fn into_recordings(self) -> (Option<Recording>, Option<Recording>) {
+        (self.reads.map(|(reads, _)| reads), self.writes.map(|(writes, _)| writes))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some((reads, limit)) = this.reads.as_mut() {
+            reads.push(&buf.filled()[before..], *limit);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_write(cx, data);
+        if let (Poll::Ready(Ok(written)), Some((writes, limit))) = (&result, this.writes.as_mut()) {
+            writes.push(&data[..*written], *limit);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_recorder_limits() {
+        let (tunnel, mut service) = tokio::io::duplex(1024);
+        let mut recorder = Recorder::new(tunnel).record_reads(Some(4)).record_writes(Some(64));
+        recorder.write_all(b"POST / HTTP/1.1\r\n").await.unwrap();
+        service.write_all(b"HTTP/1.1").await.unwrap();
+        let mut buf = [0; 8];
+        recorder.read_exact(&mut buf).await.unwrap();
+
+        let (reads, writes) = recorder.into_recordings();
+        let reads = reads.unwrap();
+        assert_eq!(reads.bytes, b"HTTP");
+        assert!(reads.truncated);
+        let writes = writes.unwrap();
+        assert_eq!(writes.bytes, b"POST / HTTP/1.1\r\n");
+        assert!(!writes.truncated && writes.first_at.is_some());
+
+        // Nothing is kept for directions that aren't recorded
+        let (reads, writes) = Recorder::new(tokio::io::empty()).into_recordings();
+        assert!(reads.is_none() && writes.is_none());
+    }
+}
@@ -0,0 +1,239 @@
+//! Bidirectional relay between a visitor and a tunnel channel.
+//!
+//! Each direction is copied on its own. When one side finishes sending, its
+//! EOF is passed on (a FIN to the visitor, an SSH EOF to the channel) and the
+//! other direction keeps running, so a visitor that half-closes after its
+//! request still gets a slow response. The connection is only cut when no
+//! bytes move in either direction for the idle timeout, or when a single
+//! write stalls past the write timeout. Timeouts and the copy buffer size
+//! come from the tunnel's performance profile.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Limits and tuning for one relayed connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayOptions {
+    /// Close when neither side has sent anything for this long
+    pub idle: Duration,
+    /// Close when a write (or EOF) can't be delivered within this long
+    pub write: Duration,
+    /// Bytes copied per read in each direction
+    pub buffer_size: usize,
+}
+
+/// Why the relay stopped
+#[derive(Debug)]
+pub enum RelayEnd {
+    /// Both directions reached EOF, the visitor's first
+    ClientEof,
+    /// Both directions reached EOF, the tunneled service's first
+    UpstreamEof,
+    /// Nothing moved for the idle timeout
+    IdleTimeout,
+    /// The receiving side stopped reading
+    WriteTimeout,
+    Error(std::io::Error),
+}
+
+/// Bytes moved in each direction and how the relay ended
+#[derive(Debug)]
+pub struct Relayed {
+    pub to_upstream: u64,
+    pub to_client: u64,
+    pub end: RelayEnd,
+}
+
+/// Last time any bytes moved
+struct Activity(Mutex<Instant>);
+
+impl Activity {
+    fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+enum PumpError {
+    WriteTimeout,
+    Io(std::io::Error),
+}
+
+/// Copy one direction until EOF, then pass the EOF on
+async fn pump<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    total: &Mutex<u64>,
+    activity: &Activity,
+    write_timeout: Duration,
+    buffer_size: usize,
+) -> Result<(), PumpError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buffer_size.max(1)];
+    loop {
+        let n = reader.read(&mut buf).await.map_err(PumpError::Io)?;
+        if n == 0 {
+            return match tokio::time::timeout(write_timeout, writer.shutdown()).await {
+                Ok(result) => result.map_err(PumpError::Io),
+                Err(_) => Err(PumpError::WriteTimeout),
+            };
+        }
+        activity.touch();
+        let write = async {
+            writer.write_all(&buf[..n]).await?;
+            writer.flush().await
+        };
+        match tokio::time::timeout(write_timeout, write).await {
+            Ok(result) => result.map_err(PumpError::Io)?,
+            Err(_) => return Err(PumpError::WriteTimeout),
+        }
+        *total.lock().unwrap() += n as u64;
+        activity.touch();
+    }
+}
+
+/// Relay between the visitor and the tunnel until both sides are done
+pub async fn relay<C, U>(client: &mut C, upstream: &mut U, options: RelayOptions) -> Relayed
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+
+    let activity = Activity(Mutex::new(Instant::now()));
+    let to_upstream = Mutex::new(0u64);
+    let to_client = Mutex::new(0u64);
+
+    let upload = pump(
+        &mut client_read,
+        &mut upstream_write,
+        &to_upstream,
+        &activity,
+        options.write,
+        options.buffer_size,
+    );
+    let download = pump(
+        &mut upstream_read,
+        &mut client_write,
+        &to_client,
+        &activity,
+        options.write,
+        options.buffer_size,
+    );
+    tokio::pin!(upload, download);
+
+    let mut upload_done = false;
+    let mut download_done = false;
+    let mut client_first = false;
+    let end = loop {
+        if upload_done && download_done {
+            break if client_first { RelayEnd::ClientEof } else { RelayEnd::UpstreamEof };
+        }
+        let idle_deadline = activity.last() + options.idle;
+        let result = tokio::select! {
+            result = &mut upload, if !upload_done => {
+                upload_done = true;
+                client_first = !download_done;
+                result
+            }
+            result = &mut download, if !download_done => {
+                download_done = true;
+                result
+            }
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                if activity.last() + options.idle <= Instant::now() {
+                    break RelayEnd::IdleTimeout;
+                }
+                continue;
+            }
+        };
+        match result {
+            Ok(()) => {}
+            Err(PumpError::WriteTimeout) => break RelayEnd::WriteTimeout,
+            Err(PumpError::Io(e)) => break RelayEnd::Error(e),
+        }
+    };
+
+    let to_upstream = *to_upstream.lock().unwrap();
+    let to_client = *to_client.lock().unwrap();
+    Relayed {
+        to_upstream,
+        to_client,
+        end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUTS: RelayOptions = RelayOptions {
+        idle: Duration::from_secs(60),
+        write: Duration::from_secs(10),
+        buffer_size: 16 * 1024,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_half_close_waits_for_response() {
+        let (mut client, mut visitor) = tokio::io::duplex(1024);
+        let (mut upstream, mut app) = tokio::io::duplex(1024);
+
+        let relay = tokio::spawn(async move { relay(&mut client, &mut upstream, TIMEOUTS).await });
+
+        // The visitor sends its request and half-closes
+        visitor.write_all(b"request").await.unwrap();
+        visitor.shutdown().await.unwrap();
+
+        // The app sees the request followed by EOF, then answers slowly
+        let mut request = Vec::new();
+        app.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        app.write_all(b"response").await.unwrap();
+        app.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        visitor.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let relayed = relay.await.unwrap();
+        assert!(matches!(relayed.end, RelayEnd::ClientEof));
+        assert_eq!((relayed.to_upstream, relayed.to_client), (7, 8));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let (mut client, mut visitor) = tokio::io::duplex(1024);
+        let (mut upstream, _app) = tokio::io::duplex(1024);
+
+        let relay = tokio::spawn(async move { relay(&mut client, &mut upstream, TIMEOUTS).await });
+        visitor.write_all(b"request").await.unwrap();
+
+        let relayed = relay.await.unwrap();
+        assert!(matches!(relayed.end, RelayEnd::IdleTimeout));
+        assert_eq!(relayed.to_upstream, 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_timeout() {
+        // The app never reads, so the channel's buffer fills up
+        let (mut client, mut visitor) = tokio::io::duplex(64 * 1024);
+        let (mut upstream, _app) = tokio::io::duplex(16);
+
+        let relay = tokio::spawn(async move { relay(&mut client, &mut upstream, TIMEOUTS).await });
+        visitor.write_all(&[0u8; 1024]).await.unwrap();
+
+        let relayed = relay.await.unwrap();
+        assert!(matches!(relayed.end, RelayEnd::WriteTimeout));
+    }
+}
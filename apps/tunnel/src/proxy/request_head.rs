@@ -0,0 +1,139 @@
+//! Incremental reading of the request head.
+//!
+//! The Host header can arrive in a later TCP segment than the request line,
+//! so the proxy reads until the blank line that ends the head (or the
+//! configured limit) instead of routing on a single peek. Everything read,
+//! including any body bytes that came along, is forwarded before the rest of
+//! the stream. A visitor gets a fixed time for the whole head, so one that
+//! trickles it in byte by byte (slow-loris) can't hold the connection open.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout_at, Instant};
+
+use super::rewrite::head_len;
+
+/// Bytes read from the visitor before routing
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferedRequest {
+    /// Everything read so far (the head and possibly the start of the body)
+    pub data: Vec<u8>,
+    /// Length of the head within `data` (None if it never completed)
+    pub head_len: Option<usize>,
+    /// The time allowed for the head ran out
+    pub timed_out: bool,
+}
+
+impl BufferedRequest {
+    /// The head ran into the size limit without completing
+    pub fn exceeds(&self, limit: usize) -> bool {
+        self.head_len.is_none() && self.data.len() >= limit
+    }
+}
+
+/// Whether the bytes so far can still be the start of an HTTP request line
+fn looks_like_http(data: &[u8]) -> bool {
+    data.first().is_none_or(|b| b.is_ascii_uppercase())
+}
+
+/// Read until the end of the request head, `limit` bytes, EOF, or `timeout`.
+/// Stops early on data that isn't HTTP (e.g. a TLS ClientHello) so it isn't
+/// left waiting for a blank line that never comes.
+pub async fn read_request_head<R>(stream: &mut R, limit: usize, timeout: Duration) -> std::io::Result<BufferedRequest>
+where
+    R: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + timeout;
+    let mut data = Vec::with_capacity(limit.min(4096));
+    let mut chunk = [0u8; 4096];
+    let mut timed_out = false;
+    loop {
+        if let Some(len) = head_len(&data) {
+            return Ok(BufferedRequest {
+                data,
+                head_len: Some(len),
+                timed_out: false,
+            });
+        }
+        if data.len() >= limit || !looks_like_http(&data) {
+            break;
+        }
+        let want = (limit - data.len()).min(chunk.len());
+        let Ok(read) = timeout_at(deadline, stream.read(&mut chunk[..want])).await else {
+            timed_out = true;
+            break;
+        };
+        let n = read?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&chunk[..n]);
+    }
+    Ok(BufferedRequest {
+        data,
+        head_len: None,
+        timed_out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn test_split_segments() {
+        // The Host header arrives in a later segment than the request line
+        let mut stream = tokio_test::io::Builder::new()
+            .read(b"GET / HTTP/1.1\r\n")
+            .read(b"Host: app.local")
+            .read(b"host\r\n\r\nbody")
+            .build();
+        let request = read_request_head(&mut stream, 16 * 1024, TIMEOUT).await.unwrap();
+        assert_eq!(request.head_len, Some(39));
+        assert_eq!(&request.data[39..], b"body");
+    }
+
+    #[tokio::test]
+    async fn test_limit_and_eof() {
+        let long = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(100));
+        let request = read_request_head(&mut long.as_bytes(), 64, TIMEOUT).await.unwrap();
+        assert_eq!(request.data.len(), 64);
+        assert!(request.exceeds(64));
+
+        let truncated = read_request_head(&mut &b"GET / HTTP/1.1\r\nHost"[..], 64, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(truncated.head_len, None);
+        assert!(!truncated.exceeds(64));
+
+        // Not HTTP: return after the first read
+        let tls = read_request_head(&mut &[0x16u8, 0x03, 0x01, 0x02, 0x00][..], 64, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(tls.data.len(), 5);
+        assert_eq!(tls.head_len, None);
+    }
+
+    #[tokio::test]
+    async fn test_slow_head_times_out() {
+        // A visitor trickling the head in gets cut off at the deadline
+        let mut stream = tokio_test::io::Builder::new()
+            .read(b"GET / HTTP/1.1\r\n")
+            .wait(Duration::from_millis(100))
+            .read(b"Host: app.localhost\r\n\r\n")
+            .build();
+        let request = read_request_head(&mut stream, 16 * 1024, Duration::from_millis(20))
+            .await
+            .unwrap();
+        assert!(request.timed_out);
+        assert_eq!(request.head_len, None);
+        assert_eq!(request.data, b"GET / HTTP/1.1\r\n");
+
+        // The mock insists on being drained
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! Response header rewriting for tunnels with header rules.
+//!
+//! Wraps the tunnel side of a connection: the response head is buffered until
+//! its blank line, rewritten with the tunnel's `HeaderRules`, and handed out
+//! before the rest of the stream passes through untouched. The request is
+//! forwarded with `Connection: close`, so each connection carries exactly one
+//! response and the relay keeps its usual idle/write timeouts.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::rewrite::head_len;
+use crate::state::header_rules::HeaderRules;
+
+/// Largest response head that gets rewritten; bigger heads pass through
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+enum Phase {
+    /// Collecting the response head
+    Reading(Vec<u8>),
+    /// Handing out the (rewritten) head and any body bytes read with it
+    Writing(Vec<u8>, usize),
+    Passthrough,
+}
+
+/// Stream wrapper that applies header rules to the first response head read
+pub struct ResponseHeaderRewriter<S> {
+    inner: S,
+    rules: HeaderRules,
+    phase: Phase,
+}
+
+impl<S> ResponseHeaderRewriter<S> {
+    /// Without rules the stream passes through untouched
+    pub fn new(inner: S, rules: HeaderRules) -> Self {
+        let phase = if rules.is_empty() {
+            Phase::Passthrough
+        } else {
+            Phase::Reading(Vec::with_capacity(4096))
+        };
+        Self { inner, rules, phase }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ResponseHeaderRewriter<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.phase {
+                Phase::Passthrough => return Pin::new(&mut this.inner).poll_read(cx, buf),
+                Phase::Writing(data, pos) => {
+                    let n = (data.len() - *pos).min(buf.remaining());
+                    buf.put_slice(&data[*pos..*pos + n]);
+                    *pos += n;
+                    if *pos == data.len() {
+                        this.phase = Phase::Passthrough;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Phase::Reading(head) => {
+                    let mut chunk = [0u8; 4096];
+                    let mut chunk_buf = ReadBuf::new(&mut chunk);
+                    match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Ready(Ok(())) => {}
+                    }
+                    let read = chunk_buf.filled();
+                    if read.is_empty() {
+                        // EOF before the head completed: hand out what came
+                        let head = std::mem::take(head);
+                        if head.is_empty() {
+                            this.phase = Phase::Passthrough;
+                            return Poll::Ready(Ok(()));
+                        }
+                        this.phase = Phase::Writing(head, 0);
+                        continue;
+                    }
+                    head.extend_from_slice(read);
+                    if let Some(len) = head_len(head) {
+                        let mut rewritten = this.rules.apply(&head[..len]);
+                        rewritten.extend_from_slice(&head[len..]);
+                        this.phase = Phase::Writing(rewritten, 0);
+                    } else if head.len() > MAX_RESPONSE_HEAD {
+                        this.phase = Phase::Writing(std::mem::take(head), 0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ResponseHeaderRewriter<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_rewrites_head_across_reads() {
+        let mut rules = HeaderRules::default();
+        rules.set_header("X-Robots-Tag", "noindex").unwrap();
+        rules.remove_header("Server").unwrap();
+        let upstream = tokio_test::io::Builder::new()
+            .read(b"HTTP/1.1 200 OK\r\nServer: ngi")
+            .read(b"nx\r\nContent-Length: 5\r\n\r\nhel")
+            .read(b"lo")
+            .build();
+        let mut rewriter = ResponseHeaderRewriter::new(upstream, rules);
+        let mut out = String::new();
+        rewriter.read_to_string(&mut out).await.unwrap();
+        assert_eq!(
+            out,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Robots-Tag: noindex\r\n\r\nhello"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_head_passes_through() {
+        let mut rules = HeaderRules::default();
+        rules.remove_header("Server").unwrap();
+        let mut rewriter = ResponseHeaderRewriter::new(&b"HTTP/1.1 200 OK\r\nServer: x"[..], rules);
+        let mut out = Vec::new();
+        rewriter.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nServer: x");
+    }
+}
@@ -0,0 +1,152 @@
+//! Request head rewriting for the HTTP proxy.
+//!
+//! The proxy is a TCP passthrough, so it only rewrites a request when a
+//! feature needs it (path routing, banner injection, compression). A rewritten
+//! request is sent with `Connection: close`: the next request on the visitor's
+//! connection then arrives on a new connection and is routed and rewritten
+//! again. That single exchange is also where a response can be edited.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::banner;
+use super::compression::{self, Encoding};
+
+/// Largest response head buffered for editing
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// How the response of a rewritten exchange is edited on its way back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseEdit {
+    /// Preview banner injection into HTML
+    Banner,
+    /// Compression for a visitor accepting `Encoding`
+    Compress(Encoding),
+}
+
+/// Length of the request head (through the blank line) if it is complete in `data`
+pub fn head_len(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+/// Read into `buf` until it holds a complete response head; returns its
+/// length, or None if the head is too large or the upstream closed first
+pub async fn read_response_head<R>(upstream: &mut R, buf: &mut Vec<u8>) -> std::io::Result<Option<usize>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(len) = head_len(buf) {
+            return Ok(Some(len));
+        }
+        if buf.len() > MAX_RESPONSE_HEAD {
+            return Ok(None);
+        }
+        let n = upstream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Rewrite a complete request head: optionally a new target, without the
+/// `drop_headers` (lowercase names), and with `Connection: close` in place of
+/// any connection headers the visitor sent
+pub fn rewrite_request_head(head: &[u8], target: Option<&str>, drop_headers: &[&str]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut lines = text.split("\r\n");
+    let request_line = lines.next()?;
+    let mut parts = request_line.splitn(3, ' ');
+    let (method, original_target, version) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let mut rewritten = format!("{} {} {}\r\n", method, target.unwrap_or(original_target), version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or("").trim().to_lowercase();
+        if name == "connection" || name == "keep-alive" || drop_headers.contains(&name.as_str()) {
+            continue;
+        }
+        rewritten.push_str(line);
+        rewritten.push_str("\r\n");
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Some(rewritten.into_bytes())
+}
+
+/// Forward one response to the visitor, edited. Returns the bytes written.
+pub async fn forward_response<R, W>(upstream: &mut R, client: &mut W, edit: ResponseEdit) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match edit {
+        ResponseEdit::Banner => banner::forward_response(upstream, client).await,
+        ResponseEdit::Compress(encoding) => compression::forward_response(upstream, client, encoding).await,
+    }
+}
+
+/// Proxy one request/response exchange, editing the response
+pub async fn proxy_exchange<C, U>(client: &mut C, upstream: &mut U, edit: ResponseEdit) -> std::io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+
+    let upload = async {
+        let n = tokio::io::copy(&mut client_read, &mut upstream_write).await?;
+        upstream_write.shutdown().await?;
+        Ok::<u64, std::io::Error>(n)
+    };
+    let download = async {
+        let n = forward_response(&mut upstream_read, &mut client_write, edit).await?;
+        client_write.shutdown().await?;
+        Ok::<u64, std::io::Error>(n)
+    };
+    tokio::pin!(upload, download);
+
+    // The exchange ends with the response; the request body may still be in flight
+    let mut uploaded = 0;
+    let mut upload_done = false;
+    loop {
+        tokio::select! {
+            result = &mut upload, if !upload_done => {
+                upload_done = true;
+                uploaded = match result {
+                    Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => return Err(e),
+                    result => result.unwrap_or(0),
+                };
+            }
+            result = &mut download => return Ok((uploaded, result?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &[u8] = b"GET /t/myapp/index.html HTTP/1.1\r\nHost: tunnel.example.com\r\nConnection: keep-alive\r\nAccept-Encoding: gzip\r\nAccept: */*\r\n\r\n";
+
+    #[test]
+    fn test_head_len() {
+        assert_eq!(head_len(HEAD), Some(HEAD.len()));
+        assert_eq!(head_len(b"GET / HTTP/1.1\r\nHost: a"), None);
+    }
+
+    #[test]
+    fn test_rewrite_request_head() {
+        let rewritten = rewrite_request_head(HEAD, Some("/index.html"), &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "GET /index.html HTTP/1.1\r\nHost: tunnel.example.com\r\nAccept-Encoding: gzip\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+
+        let rewritten = rewrite_request_head(HEAD, None, &["accept-encoding"]).unwrap();
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "GET /t/myapp/index.html HTTP/1.1\r\nHost: tunnel.example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
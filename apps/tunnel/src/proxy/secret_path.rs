@@ -0,0 +1,158 @@
+//! Secret paths: tunnels only visitors who know their token can find.
+//!
+//! A tunnel started with `EXLO_SECRET_PATH=on` gets a random token, shown
+//! with its URL. Requests must carry it: as the first path segment
+//! (`/<token>/...`, answered with a cookie and a redirect to the rest of the
+//! path so the app's own links keep working), in that cookie, or in an
+//! `X-Exlo-Token` header for scripts. Anyone else gets the same 404 as a
+//! subdomain without a tunnel, so scanners enumerating subdomains can't tell
+//! the tunnel exists (nor is it in the base domain's tunnel list). The token
+//! is for the proxy only: its header and cookie are removed before the
+//! request reaches the tunnel's service.
+
+use rand::RngCore;
+
+use super::share_secret::secrets_match;
+use super::{extract_header_from_raw, extract_header_values};
+
+/// Header carrying the token for clients that don't keep cookies
+pub const TOKEN_HEADER: &str = "X-Exlo-Token";
+
+const COOKIE_NAME: &str = "exlo_token";
+
+/// Generate a new random path token (96 bits, hex)
+pub fn generate_path_token() -> String {
+    let mut bytes = [0u8; 12];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// What the proxy does with a request for a secret-path tunnel
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretGate {
+    /// The request carries the token
+    Forward,
+    /// The path starts with the token: set the cookie and redirect to the rest
+    Redirect { location: String, cookie: String },
+    /// Answer as if there were no tunnel
+    NotFound,
+}
+
+/// Check a request for a tunnel with path token `token`. `original` is the
+/// request target as received and `routed` the one the tunnel would see
+/// (they differ by the `/t/<subdomain>` prefix in path mode).
+pub fn gate(request: &[u8], token: &str, original: &str, routed: &str) -> SecretGate {
+    let prefix = original.strip_suffix(routed).unwrap_or_default();
+    if let Some(path) = routed.strip_prefix('/') {
+        let end = path.find(['/', '?']).unwrap_or(path.len());
+        let (segment, rest) = path.split_at(end);
+        if secrets_match(segment, token) {
+            let rest = if rest.starts_with('/') { rest.to_string() } else { format!("/{}", rest) };
+            return SecretGate::Redirect {
+                location: format!("{}{}", prefix, rest),
+                cookie: format!("{}={}; Path={}/; HttpOnly; SameSite=Lax", COOKIE_NAME, token, prefix),
+            };
+        }
+    }
+
+    let header = extract_header_from_raw(request, TOKEN_HEADER)
+        .is_some_and(|value| secrets_match(value.trim(), token));
+    let cookie = extract_header_values(request, "cookie")
+        .iter()
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .any(|(name, value)| name == COOKIE_NAME && secrets_match(value, token));
+    if header || cookie {
+        SecretGate::Forward
+    } else {
+        SecretGate::NotFound
+    }
+}
+
+/// A request head without the token header and cookie (None if it isn't
+/// text). A `Cookie` header left empty is dropped.
+pub fn strip_token(head: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut stripped = String::with_capacity(text.len());
+    for line in text.split_inclusive("\r\n") {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        if name.trim().eq_ignore_ascii_case(TOKEN_HEADER) {
+            continue;
+        }
+        if name.trim().eq_ignore_ascii_case("cookie") {
+            if let Some(kept) = without_token_cookie(value) {
+                stripped.push_str(&format!("{}: {}\r\n", name, kept));
+            }
+            continue;
+        }
+        stripped.push_str(line);
+    }
+    Some(stripped.into_bytes())
+}
+
+/// A `Cookie` header value without the token cookie (None if nothing is left)
+pub fn without_token_cookie(value: &str) -> Option<String> {
+    let is_token = |cookie: &str| cookie.split_once('=').is_some_and(|(name, _)| name == COOKIE_NAME);
+    let kept: Vec<&str> = value
+        .split(';')
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty() && !is_token(cookie))
+        .collect();
+    (!kept.is_empty()).then(|| kept.join("; "))
+}
+
+/// 302 response setting the token cookie
+pub fn redirect_response(location: &str, cookie: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nSet-Cookie: {}\r\nCache-Control: no-store\r\n\
+         Referrer-Policy: no-referrer\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location, cookie
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gate() {
+        let token = "0123456789abcdef01234567";
+        let request = b"GET /x HTTP/1.1\r\nHost: app.local\r\n\r\n";
+        assert_eq!(gate(request, token, "/x", "/x"), SecretGate::NotFound);
+
+        let entered = format!("/{}/app?page=2", token);
+        assert_eq!(
+            gate(request, token, &entered, &entered),
+            SecretGate::Redirect {
+                location: "/app?page=2".to_string(),
+                cookie: format!("exlo_token={}; Path=/; HttpOnly; SameSite=Lax", token),
+            }
+        );
+        // Path mode keeps the /t/<subdomain> prefix
+        let routed = format!("/{}", token);
+        let original = format!("/t/app{}", routed);
+        let SecretGate::Redirect { location, cookie } = gate(request, token, &original, &routed) else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(location, "/t/app/");
+        assert!(cookie.contains("Path=/t/app/;"));
+
+        let with_cookie = format!("GET /x HTTP/1.1\r\nHost: app.local\r\nCookie: a=1; exlo_token={}\r\n\r\n", token);
+        assert_eq!(gate(with_cookie.as_bytes(), token, "/x", "/x"), SecretGate::Forward);
+        let with_header = format!("GET /x HTTP/1.1\r\nHost: app.local\r\nX-Exlo-Token: {}\r\n\r\n", token);
+        assert_eq!(gate(with_header.as_bytes(), token, "/x", "/x"), SecretGate::Forward);
+        let wrong = b"GET /x HTTP/1.1\r\nHost: app.local\r\nX-Exlo-Token: guess\r\n\r\n";
+        assert_eq!(gate(wrong, token, "/x", "/x"), SecretGate::NotFound);
+    }
+
+    #[test]
+    fn test_strip_token() {
+        let head = b"GET /x HTTP/1.1\r\nHost: app.local\r\nx-exlo-token: abc\r\n\
+                     Cookie: a=1; exlo_token=abc; b=2\r\nCookie: exlo_token=abc\r\n\r\n";
+        assert_eq!(
+            strip_token(head).unwrap(),
+            b"GET /x HTTP/1.1\r\nHost: app.local\r\nCookie: a=1; b=2\r\n\r\n"
+        );
+    }
+}
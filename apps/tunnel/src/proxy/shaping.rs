@@ -0,0 +1,107 @@
+//! Upstream streams paced by the tunnel's bandwidth budgets.
+//!
+//! Wraps the forwarded channel: reads are traffic back to the visitor, writes
+//! traffic towards the tunnel. Each read or write is counted against the
+//! budgets of `state::bandwidth`; an overdrawn direction waits before its next
+//! read or write, which backs up into the socket on the other side.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+use crate::state::bandwidth::Throttle;
+
+/// A stream to the tunnel that keeps to `Throttle`'s budgets
+pub struct Shaped<S> {
+    inner: S,
+    throttle: Throttle,
+    read_pause: Option<Pin<Box<Sleep>>>,
+    write_pause: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Shaped<S> {
+    pub fn new(inner: S, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            read_pause: None,
+            write_pause: None,
+        }
+    }
+}
+
+/// Wait out a pending pause, if any
+fn poll_pause(pause: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = pause.as_mut() {
+        ready!(sleep.as_mut().poll(cx));
+        *pause = None;
+    }
+    Poll::Ready(())
+}
+
+/// Pause the next operation for as long as the budget is overdrawn
+fn charge(throttle: &Throttle, inbound: bool, bytes: usize) -> Option<Pin<Box<Sleep>>> {
+    let pause = throttle.take(inbound, bytes);
+    (!pause.is_zero()).then(|| Box::pin(sleep(pause)))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shaped<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_pause(&mut this.read_pause, cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read_pause = charge(&this.throttle, false, buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shaped<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_pause(&mut this.write_pause, cx));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, data))?;
+        this.write_pause = charge(&this.throttle, true, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::bandwidth::Bandwidth;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shaped_stream() {
+        let bandwidth = Bandwidth::default();
+        bandwidth.set("app", 1000);
+        let (tunnel, mut visitor) = tokio::io::duplex(1 << 16);
+        let mut shaped = Shaped::new(tunnel, bandwidth.throttle("app", "user_1", None));
+
+        // The first write overdraws the budget, the next one waits for it
+        let started = tokio::time::Instant::now();
+        shaped.write_all(&[0; 1000]).await.unwrap();
+        shaped.write_all(&[0; 500]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // Reads draw from their own budget
+        visitor.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        shaped.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,103 @@
+//! Share secrets for password-protected tunnels.
+//!
+//! A protected tunnel only serves requests that carry its secret, either as the
+//! password of HTTP Basic auth (any username) or as an `exlo_secret` query
+//! parameter on a share URL. The secret is read from the tunnel on every
+//! request, so rotating it takes effect immediately.
+
+use base64::Engine;
+use rand::RngCore;
+
+use super::{extract_header_from_raw, extract_request_target};
+
+/// Query parameter carrying the secret in share URLs
+pub const SHARE_SECRET_PARAM: &str = "exlo_secret";
+
+/// Generate a new random share secret (128 bits, hex)
+pub fn generate_share_secret() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Compare without leaking the position of the first mismatch
+pub(crate) fn secrets_match(provided: &str, secret: &str) -> bool {
+    provided.len() == secret.len()
+        && provided
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Password from an `Authorization: Basic` header
+fn basic_auth_password(request: &[u8]) -> Option<String> {
+    let header = extract_header_from_raw(request, "authorization")?;
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// Secret from the share URL query parameter
+fn query_secret(request: &[u8]) -> Option<String> {
+    let target = extract_request_target(request)?;
+    let (_, query) = target.split_once('?')?;
+    query.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        (name == SHARE_SECRET_PARAM).then(|| value.to_string())
+    })
+}
+
+/// Whether a request carries the tunnel's secret
+pub fn is_authorized(request: &[u8], secret: &str) -> bool {
+    basic_auth_password(request).is_some_and(|p| secrets_match(&p, secret))
+        || query_secret(request).is_some_and(|s| secrets_match(&s, secret))
+}
+
+/// 401 response asking the browser for the password
+pub fn unauthorized_response() -> Vec<u8> {
+    let body = "This tunnel is password protected";
+    format!(
+        "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"exlo tunnel\"\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_auth() {
+        let secret = "s3cret";
+        let credentials = base64::engine::general_purpose::STANDARD.encode("anyone:s3cret");
+        let request = format!("GET / HTTP/1.1\r\nHost: app.localhost\r\nAuthorization: Basic {}\r\n\r\n", credentials);
+        assert!(is_authorized(request.as_bytes(), secret));
+
+        let wrong = base64::engine::general_purpose::STANDARD.encode("anyone:nope");
+        let request = format!("GET / HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n", wrong);
+        assert!(!is_authorized(request.as_bytes(), secret));
+        assert!(!is_authorized(b"GET / HTTP/1.1\r\nHost: app.localhost\r\n\r\n", secret));
+    }
+
+    #[test]
+    fn test_share_url() {
+        assert!(is_authorized(b"GET /hook?a=1&exlo_secret=abc HTTP/1.1\r\n\r\n", "abc"));
+        assert!(!is_authorized(b"GET /hook?exlo_secret=abd HTTP/1.1\r\n\r\n", "abc"));
+        assert!(!is_authorized(b"GET /hook?exlo_secret=ab HTTP/1.1\r\n\r\n", "abc"));
+    }
+
+    #[test]
+    fn test_generate_share_secret() {
+        let a = generate_share_secret();
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, generate_share_secret());
+    }
+}
@@ -0,0 +1,273 @@
+//! TLS ClientHello parsing for SNI passthrough routing.
+//!
+//! The TLS listener never terminates TLS: it reads the visitor's ClientHello,
+//! takes the tunnel name from the `server_name` extension, and forwards the
+//! bytes untouched so the certificate lives on the user's machine. Only the
+//! fields needed to reach the extensions are parsed.
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_ALERT: u8 = 0x15;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const ALERT_FATAL: u8 = 2;
+const ALERT_HANDSHAKE_FAILURE: u8 = 40;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Largest ClientHello the listener will buffer
+pub const MAX_CLIENT_HELLO: usize = 16 * 1024;
+
+/// Fatal `handshake_failure` alert, refusing a handshake the server won't
+/// complete (TLS 1.0 record version, which every client accepts here)
+pub const HANDSHAKE_FAILURE_ALERT: [u8; 7] =
+    [RECORD_ALERT, 0x03, 0x01, 0x00, 0x02, ALERT_FATAL, ALERT_HANDSHAKE_FAILURE];
+
+/// Whether the bytes so far can be the start of a ClientHello: a handshake
+/// record of TLS (major version 3) whose message is a ClientHello
+pub fn looks_like_client_hello(data: &[u8]) -> bool {
+    data.first() == Some(&RECORD_HANDSHAKE)
+        && data.get(1).is_none_or(|major| *major == 0x03)
+        && data.get(5).is_none_or(|kind| *kind == HANDSHAKE_CLIENT_HELLO)
+}
+
+/// Outcome of parsing the start of a TLS connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sni {
+    /// The ClientHello names this host (lowercased)
+    Found(String),
+    /// A complete ClientHello without a host name
+    Missing,
+    /// More bytes are needed
+    Incomplete,
+    /// Not a TLS ClientHello
+    Invalid,
+}
+
+/// Cursor over length-prefixed TLS fields
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+/// Reassemble the ClientHello handshake message, which may span several records
+fn client_hello_message(data: &[u8]) -> Result<Vec<u8>, Sni> {
+    let mut message = Vec::new();
+    let mut rest = data;
+    loop {
+        if rest.len() < 5 {
+            return Err(Sni::Incomplete);
+        }
+        if rest[0] != RECORD_HANDSHAKE || rest[1] != 0x03 {
+            return Err(Sni::Invalid);
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if rest.len() < 5 + len {
+            return Err(Sni::Incomplete);
+        }
+        message.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+
+        if message.len() >= 4 {
+            if message[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(Sni::Invalid);
+            }
+            let body_len = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= 4 + body_len {
+                message.truncate(4 + body_len);
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// Host name from a ClientHello body (None if malformed, Some(None) if absent)
+fn server_name(body: &[u8]) -> Option<Option<String>> {
+    let mut hello = Reader::new(body);
+    hello.take(2 + 32)?; // legacy_version, random
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compression methods
+    if hello.is_empty() {
+        return Some(None);
+    }
+
+    let mut extensions = Reader::new(hello.vec16()?);
+    while !extensions.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader::new(Reader::new(data).vec16()?);
+        while !names.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.to_ascii_lowercase()));
+            }
+        }
+    }
+    Some(None)
+}
+
+/// Parse the server name from the bytes read so far
+pub fn parse_sni(data: &[u8]) -> Sni {
+    let message = match client_hello_message(data) {
+        Ok(message) => message,
+        Err(outcome) => return outcome,
+    };
+    match server_name(&message[4..]) {
+        Some(Some(name)) => Sni::Found(name),
+        Some(None) => Sni::Missing,
+        None => Sni::Invalid,
+    }
+}
+
+/// Bytes read from the visitor before routing, with the parse result
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferedHello {
+    pub data: Vec<u8>,
+    pub sni: Sni,
+}
+
+/// Read until the ClientHello is complete, `limit` bytes, or EOF
+pub async fn read_client_hello<R>(stream: &mut R, limit: usize) -> std::io::Result<BufferedHello>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut data = Vec::with_capacity(limit.min(4096));
+    let mut chunk = [0u8; 4096];
+    loop {
+        let sni = parse_sni(&data);
+        if sni != Sni::Incomplete || data.len() >= limit {
+            return Ok(BufferedHello { data, sni });
+        }
+        let want = (limit - data.len()).min(chunk.len());
+        let n = stream.read(&mut chunk[..want]).await?;
+        if n == 0 {
+            return Ok(BufferedHello { data, sni });
+        }
+        data.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// A ClientHello handshake message with an optional server_name extension
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session id
+        body.extend(vec16(&[0x13, 0x01]));
+        body.extend_from_slice(&[1, 0]);
+
+        let mut extensions = Vec::new();
+        // An unrelated extension first (supported_versions)
+        extensions.extend_from_slice(&[0x00, 0x2b]);
+        extensions.extend(vec16(&[0x02, 0x03, 0x04]));
+        if let Some(host) = host {
+            let mut entry = vec![NAME_TYPE_HOST_NAME];
+            entry.extend(vec16(host.as_bytes()));
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend(vec16(&vec16(&entry)));
+        }
+        body.extend(vec16(&extensions));
+
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+        message
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let mut out = vec![RECORD_HANDSHAKE, 0x03, 0x01];
+        out.extend(vec16(fragment));
+        out
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let hello = record(&client_hello(Some("MyApp.Example.com")));
+        assert_eq!(parse_sni(&hello), Sni::Found("myapp.example.com".to_string()));
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), Sni::Incomplete);
+        assert_eq!(parse_sni(&record(&client_hello(None))), Sni::Missing);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), Sni::Invalid);
+
+        // The handshake message split across two records
+        let message = client_hello(Some("app.example.com"));
+        let split = [record(&message[..10]), record(&message[10..])].concat();
+        assert_eq!(parse_sni(&split), Sni::Found("app.example.com".to_string()));
+    }
+
+    #[test]
+    fn test_looks_like_client_hello() {
+        let hello = record(&client_hello(Some("app.example.com")));
+        assert!(looks_like_client_hello(&hello));
+        assert!(looks_like_client_hello(&hello[..1]));
+        assert!(!looks_like_client_hello(b"GET / HTTP/1.1\r\n"));
+        assert!(!looks_like_client_hello(&[]));
+        // A handshake record carrying something other than a ClientHello
+        assert!(!looks_like_client_hello(&[RECORD_HANDSHAKE, 0x03, 0x03, 0x00, 0x04, 0x02]));
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let hello = record(&client_hello(Some("app.example.com")));
+        let mut stream = tokio_test::io::Builder::new()
+            .read(&hello[..20])
+            .read(&hello[20..])
+            .build();
+        let buffered = read_client_hello(&mut stream, MAX_CLIENT_HELLO).await.unwrap();
+        assert_eq!(buffered.data, hello);
+        assert_eq!(buffered.sni, Sni::Found("app.example.com".to_string()));
+
+        let truncated = read_client_hello(&mut &hello[..30], MAX_CLIENT_HELLO).await.unwrap();
+        assert_eq!(truncated.sni, Sni::Incomplete);
+    }
+}
@@ -0,0 +1,93 @@
+//! Raw TCP forwarding on dedicated public ports.
+//!
+//! Each connection to a port bound in `state::dedicated_ports` gets a forwarded
+//! channel of its own to the session holding the port, opened with the bind
+//! address and port of the client's `-R tcp:<port>:...` forward, and bytes
+//! are relayed untouched. Bans, IP reputation and the per-IP connection cap
+//! apply as on the HTTP proxy; there is no routing, access log or response
+//! handling.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use russh::server::Handle;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::AbortHandle;
+
+use crate::crash::{spawn_with_context, CrashContext};
+use crate::state::AppState;
+
+use super::relay::relay;
+use super::{relay_options, visitor_limiter};
+
+/// Accept connections on a dedicated port until the task is aborted
+pub fn spawn_tcp_forward(
+    state: Arc<AppState>,
+    listener: TcpListener,
+    handle: Handle,
+    address: String,
+    port: u16,
+) -> AbortHandle {
+    let context = CrashContext::new("proxy::tcp");
+    let task = spawn_with_context(context, async move {
+        loop {
+            let (stream, client_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept on TCP port {}: {}", port, e);
+                    continue;
+                }
+            };
+            let state = state.clone();
+            let handle = handle.clone();
+            let address = address.clone();
+            spawn_with_context(CrashContext::new("proxy::tcp"), async move {
+                forward_connection(&state, stream, client_addr, &handle, &address, port).await;
+            });
+        }
+    });
+    task.abort_handle()
+}
+
+async fn forward_connection(
+    state: &AppState,
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    handle: &Handle,
+    address: &str,
+    port: u16,
+) {
+    let Some(_ip_permit) = visitor_limiter().try_acquire(client_addr.ip()) else {
+        debug!("Refusing TCP connection from {}: too many connections from this IP", client_addr);
+        return;
+    };
+    if let Some(ban) = state.bans.is_banned(client_addr.ip()).await {
+        debug!("Refusing TCP connection from banned IP {} ({})", client_addr, ban.reason);
+        return;
+    }
+    if !state.reputation.admit(client_addr.ip(), "TCP").await {
+        return;
+    }
+
+    let channel = handle
+        .channel_open_forwarded_tcpip(
+            address,
+            port as u32,
+            client_addr.ip().to_string(),
+            client_addr.port() as u32,
+        )
+        .await;
+    let mut channel = match channel {
+        Ok(channel) => channel.into_stream(),
+        Err(e) => {
+            debug!("TCP port {}: client refused the channel for {}: {:?}", port, client_addr, e);
+            return;
+        }
+    };
+    let relayed = relay(&mut stream, &mut channel, relay_options(None)).await;
+    debug!(
+        "TCP port {}: connection from {} closed, {} bytes in, {} bytes out",
+        port, client_addr, relayed.to_upstream, relayed.to_client
+    );
+}
@@ -0,0 +1,240 @@
+//! UDP relay on dedicated public ports.
+//!
+//! SSH channels carry byte streams, so datagrams are framed: each one is a
+//! 2-byte big-endian length followed by its payload, in both directions.
+//! Every remote peer (source address) of a port bound in
+//! `state::dedicated_ports` gets a forwarded channel of its own, opened with
+//! the forward's bind address and port and the peer's address as originator,
+//! so the agent on the client side (`tunnl udp-agent`) needs no addressing:
+//! it unwraps the frames of each channel to a UDP socket of its own facing
+//! the local service, and frames the replies. A peer's channel is closed
+//! after `UDP_IDLE_TIMEOUT` without datagrams either way. Bans, IP
+//! reputation and the per-IP connection cap apply per peer; refused peers
+//! are ignored until they go quiet.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use russh::server::Handle;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::accept::IpPermit;
+use crate::config::get as get_config;
+use crate::crash::{spawn_with_context, CrashContext};
+use crate::state::AppState;
+
+use super::visitor_limiter;
+
+/// Largest datagram a frame can carry
+pub const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+/// Datagrams queued for a peer's channel; more are dropped, as on a busy link
+const PEER_QUEUE: usize = 64;
+
+/// Peers served at once per port; datagrams of further peers are dropped
+const MAX_PEERS: usize = 1024;
+
+/// Frame a datagram for the channel
+pub fn encode_frame(datagram: &[u8]) -> Vec<u8> {
+    let len = datagram.len().min(MAX_DATAGRAM);
+    let mut frame = Vec::with_capacity(2 + len);
+    frame.extend_from_slice(&(len as u16).to_be_bytes());
+    frame.extend_from_slice(&datagram[..len]);
+    frame
+}
+
+/// Splits a channel's byte stream back into datagrams
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete datagram, if one has arrived
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let header: [u8; 2] = self.buffer.get(..2)?.try_into().ok()?;
+        let len = u16::from_be_bytes(header) as usize;
+        if self.buffer.len() < 2 + len {
+            return None;
+        }
+        let datagram = self.buffer[2..2 + len].to_vec();
+        self.buffer.drain(..2 + len);
+        Some(datagram)
+    }
+}
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// Relay datagrams on a dedicated port until the task is aborted
+pub fn spawn_udp_forward(
+    state: Arc<AppState>,
+    socket: UdpSocket,
+    handle: Handle,
+    address: String,
+    port: u16,
+) -> AbortHandle {
+    let socket = Arc::new(socket);
+    let peers: Peers = Arc::default();
+    let context = CrashContext::new("proxy::udp");
+    let task = spawn_with_context(context, async move {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive on UDP port {}: {}", port, e);
+                    continue;
+                }
+            };
+            let sender = {
+                let mut known = peers.lock().unwrap();
+                match known.get(&peer) {
+                    Some(sender) => Some(sender.clone()),
+                    None if known.len() >= MAX_PEERS => None,
+                    None => {
+                        let (sender, datagrams) = mpsc::channel(PEER_QUEUE);
+                        known.insert(peer, sender.clone());
+                        let peer_task = PeerTask {
+                            state: state.clone(),
+                            socket: socket.clone(),
+                            handle: handle.clone(),
+                            address: address.clone(),
+                            port,
+                            peer,
+                        };
+                        let peers = peers.clone();
+                        spawn_with_context(CrashContext::new("proxy::udp"), async move {
+                            peer_task.run(datagrams).await;
+                            peers.lock().unwrap().remove(&peer);
+                        });
+                        Some(sender)
+                    }
+                }
+            };
+            let Some(sender) = sender else {
+                debug!("UDP port {}: dropping datagram from {}, too many peers", port, peer);
+                continue;
+            };
+            // A full queue drops the datagram
+            let _ = sender.try_send(buffer[..len].to_vec());
+        }
+    });
+    task.abort_handle()
+}
+
+/// The channel of one remote peer
+struct PeerTask {
+    state: Arc<AppState>,
+    socket: Arc<UdpSocket>,
+    handle: Handle,
+    address: String,
+    port: u16,
+    peer: SocketAddr,
+}
+
+impl PeerTask {
+    async fn run(self, mut datagrams: mpsc::Receiver<Vec<u8>>) {
+        let idle = get_config().udp_idle_timeout;
+        match self.admit().await {
+            Some(_ip_permit) => self.relay(&mut datagrams, idle).await,
+            // Keep the refused peer's entry (and drop its datagrams) until it goes quiet
+            None => while let Ok(Some(_)) = tokio::time::timeout(idle, datagrams.recv()).await {},
+        }
+    }
+
+    async fn admit(&self) -> Option<IpPermit> {
+        let ip = self.peer.ip();
+        let Some(permit) = visitor_limiter().try_acquire(ip) else {
+            debug!("Refusing UDP peer {}: too many connections from this IP", self.peer);
+            return None;
+        };
+        if let Some(ban) = self.state.bans.is_banned(ip).await {
+            debug!("Refusing UDP peer {} (banned: {})", self.peer, ban.reason);
+            return None;
+        }
+        self.state.reputation.admit(ip, "UDP").await.then_some(permit)
+    }
+
+    async fn relay(&self, datagrams: &mut mpsc::Receiver<Vec<u8>>, idle: std::time::Duration) {
+        let (peer, port) = (self.peer, self.port);
+        let channel = self
+            .handle
+            .channel_open_forwarded_tcpip(&self.address, port as u32, peer.ip().to_string(), peer.port() as u32)
+            .await;
+        let channel = match channel {
+            Ok(channel) => channel.into_stream(),
+            Err(e) => {
+                debug!("UDP port {}: client refused the channel for {}: {:?}", port, peer, e);
+                return;
+            }
+        };
+        let (mut reader, mut writer) = tokio::io::split(channel);
+        let mut decoder = FrameDecoder::default();
+        let mut buffer = vec![0u8; 16 * 1024];
+        let (mut sent, mut received) = (0usize, 0usize);
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => {
+                    let Some(datagram) = datagram else { break };
+                    if writer.write_all(&encode_frame(&datagram)).await.is_err() {
+                        break;
+                    }
+                    sent += 1;
+                }
+                read = reader.read(&mut buffer) => {
+                    let n = match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => n,
+                    };
+                    decoder.push(&buffer[..n]);
+                    while let Some(datagram) = decoder.next_frame() {
+                        if let Err(e) = self.socket.send_to(&datagram, peer).await {
+                            debug!("UDP port {}: failed to send to {}: {}", port, peer, e);
+                        }
+                        received += 1;
+                    }
+                }
+                _ = tokio::time::sleep(idle) => break,
+            }
+        }
+        let _ = writer.shutdown().await;
+        debug!(
+            "UDP port {}: channel of {} closed, {} datagrams in, {} datagrams out",
+            port, peer, sent, received
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let mut stream = encode_frame(b"ping");
+        stream.extend(encode_frame(b""));
+        stream.extend(encode_frame(&[7; 300]));
+        assert_eq!(&stream[..6], b"\x00\x04ping");
+
+        let mut decoder = FrameDecoder::default();
+        // Frames may arrive split anywhere
+        decoder.push(&stream[..1]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&stream[1..10]);
+        assert_eq!(decoder.next_frame(), Some(b"ping".to_vec()));
+        assert_eq!(decoder.next_frame(), Some(Vec::new()));
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&stream[10..]);
+        assert_eq!(decoder.next_frame(), Some(vec![7; 300]));
+        assert_eq!(decoder.next_frame(), None);
+    }
+}
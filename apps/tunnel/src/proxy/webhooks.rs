@@ -0,0 +1,195 @@
+//! Webhook signature checks at the edge.
+//!
+//! Requests matching a tunnel's webhook rule (see `state::webhooks`) are read
+//! in full, up to `MAX_WEBHOOK_BODY`, and their signature is checked against
+//! the rule's secret before anything is forwarded. Deliveries with a missing
+//! or wrong signature, or (Stripe) a stale timestamp, get a 401 from the
+//! proxy. Signatures are compared in constant time.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::extract_header_values;
+use super::framing::parse_request_head;
+use crate::state::webhooks::{SignatureEncoding, WebhookProvider, WebhookRule, DEFAULT_TOLERANCE_SECS};
+
+/// Largest delivery body the proxy buffers to check its signature
+pub const MAX_WEBHOOK_BODY: usize = 1 << 20;
+
+/// Read the rest of the request body into `data` (whose head is `head_len`
+/// bytes); returns the payload, without chunk framing, or the status and
+/// reason to refuse the request with
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    data: &mut Vec<u8>,
+    head_len: usize,
+    timeout: Duration,
+) -> Result<Vec<u8>, (u16, &'static str)> {
+    let mut framing = parse_request_head(&data[..head_len])
+        .and_then(|head| head.framing)
+        .ok_or((400, "Malformed request head"))?;
+    let mut payload = Vec::new();
+    let (_, mut done) = framing.decode(&data[head_len..], &mut payload).map_err(|e| (400, e))?;
+    let read = async {
+        let mut buf = vec![0; 16 * 1024];
+        while !done {
+            if data.len() - head_len > MAX_WEBHOOK_BODY {
+                return Err((413, "Webhook body too large"));
+            }
+            let n = stream.read(&mut buf).await.map_err(|_| (400, "Request body not received"))?;
+            if n == 0 {
+                return Err((400, "Request body cut short"));
+            }
+            data.extend_from_slice(&buf[..n]);
+            (_, done) = framing.decode(&buf[..n], &mut payload).map_err(|e| (400, e))?;
+        }
+        Ok(payload)
+    };
+    tokio::time::timeout(timeout, read)
+        .await
+        .unwrap_or(Err((408, "Request body timeout")))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn mac(secret: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length")
+}
+
+/// Check a delivery with head `head` and payload `body` against `rule` at
+/// unix time `now`
+pub fn verify(rule: &WebhookRule, head: &[u8], body: &[u8], now: u64) -> Result<(), &'static str> {
+    match rule.provider {
+        WebhookProvider::Github => {
+            let header = extract_header_values(head, "x-hub-signature-256");
+            let signature = header
+                .first()
+                .and_then(|value| value.strip_prefix("sha256="))
+                .and_then(|hex_value| hex::decode(hex_value).ok())
+                .ok_or("Missing webhook signature")?;
+            let mut mac = mac(&rule.secret);
+            mac.update(body);
+            mac.verify_slice(&signature).map_err(|_| "Invalid webhook signature")
+        }
+        WebhookProvider::Stripe => {
+            let header = extract_header_values(head, "stripe-signature");
+            let fields: Vec<(&str, &str)> = header
+                .first()
+                .ok_or("Missing webhook signature")?
+                .split(',')
+                .filter_map(|field| field.trim().split_once('='))
+                .collect();
+            let timestamp: u64 = fields
+                .iter()
+                .find(|(key, _)| *key == "t")
+                .and_then(|(_, value)| value.parse().ok())
+                .ok_or("Missing webhook signature")?;
+            if now.abs_diff(timestamp) > rule.tolerance_secs.unwrap_or(DEFAULT_TOLERANCE_SECS) {
+                return Err("Webhook signature expired");
+            }
+            let valid = fields
+                .iter()
+                .filter(|(key, _)| *key == "v1")
+                .filter_map(|(_, value)| hex::decode(value).ok())
+                .any(|signature| {
+                    let mut mac = mac(&rule.secret);
+                    mac.update(format!("{}.", timestamp).as_bytes());
+                    mac.update(body);
+                    mac.verify_slice(&signature).is_ok()
+                });
+            valid.then_some(()).ok_or("Invalid webhook signature")
+        }
+        WebhookProvider::Hmac => {
+            let name = rule.header.as_deref().unwrap_or_default().to_lowercase();
+            let header = extract_header_values(head, &name);
+            let value = header.first().ok_or("Missing webhook signature")?;
+            let value = match rule.prefix.as_deref() {
+                Some(prefix) => value.strip_prefix(prefix).ok_or("Missing webhook signature")?,
+                None => value.as_str(),
+            };
+            let signature = match rule.encoding {
+                SignatureEncoding::Hex => hex::decode(value).ok(),
+                SignatureEncoding::Base64 => STANDARD.decode(value).ok(),
+            }
+            .ok_or("Invalid webhook signature")?;
+            let mut mac = mac(&rule.secret);
+            mac.update(body);
+            mac.verify_slice(&signature).map_err(|_| "Invalid webhook signature")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(provider: WebhookProvider) -> WebhookRule {
+        WebhookRule {
+            path: "/hooks".to_string(),
+            provider,
+            secret: "It's a Secret to Everybody".to_string(),
+            header: None,
+            prefix: None,
+            encoding: SignatureEncoding::Hex,
+            tolerance_secs: None,
+        }
+    }
+
+    fn sign(secret: &str, payload: &[u8]) -> Vec<u8> {
+        let mut mac = mac(secret);
+        mac.update(payload);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_verify_github() {
+        // Example from GitHub's documentation
+        let github = rule(WebhookProvider::Github);
+        let head = b"POST /hooks HTTP/1.1\r\nX-Hub-Signature-256: sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17\r\n\r\n";
+        assert_eq!(verify(&github, head, b"Hello, World!", 0), Ok(()));
+        assert_eq!(verify(&github, head, b"Hello, World?", 0), Err("Invalid webhook signature"));
+        assert_eq!(verify(&github, b"POST /hooks HTTP/1.1\r\n\r\n", b"", 0), Err("Missing webhook signature"));
+    }
+
+    #[test]
+    fn test_verify_stripe_and_hmac() {
+        let stripe = rule(WebhookProvider::Stripe);
+        let signature = hex::encode(sign(&stripe.secret, b"1700000000.{}"));
+        let head = format!("POST /hooks HTTP/1.1\r\nStripe-Signature: t=1700000000,v1=00,v1={}\r\n\r\n", signature);
+        assert_eq!(verify(&stripe, head.as_bytes(), b"{}", 1700000100), Ok(()));
+        assert_eq!(verify(&stripe, head.as_bytes(), b"{}", 1700001000), Err("Webhook signature expired"));
+
+        let shopify = WebhookRule {
+            header: Some("X-Shopify-Hmac-Sha256".to_string()),
+            encoding: SignatureEncoding::Base64,
+            ..rule(WebhookProvider::Hmac)
+        };
+        let signature = STANDARD.encode(sign(&shopify.secret, b"{}"));
+        let head = format!("POST /hooks HTTP/1.1\r\nX-Shopify-Hmac-Sha256: {}\r\n\r\n", signature);
+        assert_eq!(verify(&shopify, head.as_bytes(), b"{}", 0), Ok(()));
+        assert_eq!(verify(&shopify, head.as_bytes(), b"{ }", 0), Err("Invalid webhook signature"));
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let mut data = b"POST /hooks HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}".to_vec();
+        let head_len = data.len() - 5;
+        let mut rest: &[u8] = b"\r\n0\r\n\r\n";
+        let payload = read_body(&mut rest, &mut data, head_len, Duration::from_secs(1)).await;
+        assert_eq!(payload, Ok(b"{}".to_vec()));
+        assert!(data.ends_with(b"0\r\n\r\n"));
+
+        let mut data = b"POST /hooks HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}".to_vec();
+        let head_len = data.len() - 2;
+        let mut rest: &[u8] = b"";
+        let payload = read_body(&mut rest, &mut data, head_len, Duration::from_secs(1)).await;
+        assert_eq!(payload, Err((400, "Request body cut short")));
+    }
+}
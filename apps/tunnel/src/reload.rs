@@ -0,0 +1,95 @@
+//! Configuration reload without a restart.
+//!
+//! A reload (SIGHUP or `POST /config/reload`) re-reads `.env` and the selected
+//! profile, then applies the runtime-adjustable settings in
+//! `config::Reloadable`: timeouts, rate limits, auto-ban settings, reserved
+//! subdomains and the log filter. Everything else (ports, keys, URLs, routing)
+//! still needs a restart.
+//!
+//! Variables set in the process environment at startup keep their value, so
+//! the usual precedence (environment > `.env` > profile) holds across reloads.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::config;
+use crate::logging;
+use crate::profile::load_profile;
+
+/// Names of the variables the process was started with
+static PROCESS_ENV: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Remember which variables came from the process environment.
+/// Call at startup, before `.env` and the profile are applied.
+pub fn capture_process_env() {
+    PROCESS_ENV.get_or_init(|| std::env::vars_os().filter_map(|(name, _)| name.into_string().ok()).collect());
+}
+
+/// Re-read `.env` and the profile into the environment, without touching
+/// variables from the process environment
+fn refresh_env() -> Result<(), String> {
+    let mut vars: HashMap<String, String> = HashMap::new();
+    if let Some((_, profile_vars)) = load_profile(std::env::args().skip(1)).map_err(|e| format!("{:#}", e))? {
+        vars.extend(profile_vars);
+    }
+    if let Ok(dotenv) = dotenvy::dotenv_iter() {
+        for item in dotenv {
+            let (name, value) = item.map_err(|e| format!(".env: {}", e))?;
+            vars.insert(name, value);
+        }
+    }
+
+    let process_env = PROCESS_ENV.get();
+    for (name, value) in vars {
+        if !process_env.is_some_and(|env| env.contains(&name)) {
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Reload the runtime-adjustable settings. Returns the variables that changed;
+/// on error the current settings stay in effect.
+pub fn reload() -> Result<Vec<&'static str>, String> {
+    refresh_env()?;
+    let (settings, changed) = config::reload()?;
+    if changed.contains(&"RUST_LOG") {
+        logging::set_filter(&settings.log_filter);
+    }
+    if changed.is_empty() {
+        info!("Configuration reloaded (no changes)");
+    } else {
+        info!("Configuration reloaded: {}", changed.join(", "));
+    }
+    Ok(changed)
+}
+
+/// Reload on every SIGHUP
+#[cfg(unix)]
+pub fn spawn_sighup_listener() -> Option<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, configuration reload via signal disabled: {}", e);
+            return None;
+        }
+    };
+    Some(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration");
+            if let Err(e) = reload() {
+                warn!("Configuration reload failed: {}", e);
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_listener() -> Option<JoinHandle<()>> {
+    None
+}
@@ -0,0 +1,414 @@
+//! IP reputation checks for incoming SSH and HTTP connections.
+//!
+//! Providers score an address from 0 (clean) to 100 (known bad); the highest
+//! score across providers picks an action from the configured thresholds.
+//! Lookups fail open: an unreachable provider never blocks anyone.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use tokio::sync::RwLock;
+
+use crate::config::{get as get_config, ReputationAction};
+
+/// Delay applied to throttled connections
+const THROTTLE_DELAY: Duration = Duration::from_secs(3);
+
+/// Timeout for HTTP reputation lookups
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of cached HTTP lookups
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// Score given to list entries without an explicit score
+const DEFAULT_LIST_SCORE: u8 = 100;
+
+/// A source of IP reputation scores
+#[async_trait]
+pub trait ReputationProvider: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Score from 0 (clean) to 100 (malicious), or None if unknown
+    async fn score(&self, ip: IpAddr) -> Option<u8>;
+}
+
+/// An IPv4 or IPv6 network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse "10.0.0.0/8", "2001:db8::/32" or a bare address
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { network: addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Reputation from a local list of networks.
+/// One CIDR per line, optionally followed by a score; `#` starts a comment.
+#[derive(Debug, Default)]
+pub struct CidrListProvider {
+    entries: Vec<(Cidr, u8)>,
+}
+
+impl CidrListProvider {
+    pub fn parse(contents: &str) -> Self {
+        let mut entries = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let cidr = parts.next().and_then(Cidr::parse);
+            let score = match parts.next() {
+                Some(score) => score.parse::<u8>().ok().map(|s| s.min(100)),
+                None => Some(DEFAULT_LIST_SCORE),
+            };
+            match (cidr, score) {
+                (Some(cidr), Some(score)) => entries.push((cidr, score)),
+                _ => warn!("Ignoring invalid IP reputation entry: '{}'", line),
+            }
+        }
+        Self { entries }
+    }
+
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[async_trait]
+impl ReputationProvider for CidrListProvider {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn score(&self, ip: IpAddr) -> Option<u8> {
+        self.entries
+            .iter()
+            .filter(|(cidr, _)| cidr.contains(ip))
+            .map(|(_, score)| *score)
+            .max()
+    }
+}
+
+/// Reputation from an AbuseIPDB-style HTTP API, with cached results.
+/// Sends `GET <url>?ipAddress=<ip>` with the API key in a `Key` header and
+/// reads `data.abuseConfidenceScore` (or a top-level `score`) from the JSON.
+pub struct HttpReputationProvider {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    ttl: Duration,
+    cache: RwLock<HashMap<IpAddr, (Option<u8>, Instant)>>,
+}
+
+impl HttpReputationProvider {
+    pub fn new(url: String, api_key: Option<String>, ttl: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(LOOKUP_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            url,
+            api_key,
+            ttl,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    async fn lookup(&self, ip: IpAddr) -> Result<Option<u8>, reqwest::Error> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let mut request = self
+            .client
+            .get(format!("{}{}ipAddress={}", self.url, separator, ip))
+            .header("Accept", "application/json");
+        if let Some(ref key) = self.api_key {
+            request = request.header("Key", key);
+        }
+        let body: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        let score = body
+            .pointer("/data/abuseConfidenceScore")
+            .or_else(|| body.get("score"))
+            .and_then(|s| s.as_u64())
+            .map(|s| s.min(100) as u8);
+        Ok(score)
+    }
+
+    async fn cache_result(&self, ip: IpAddr, score: Option<u8>) {
+        let mut cache = self.cache.write().await;
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            let ttl = self.ttl;
+            cache.retain(|_, (_, at)| at.elapsed() < ttl);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        cache.insert(ip, (score, Instant::now()));
+    }
+}
+
+/// Addresses that public reputation services know nothing about
+fn is_local_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified(),
+        IpAddr::V6(v6) => v6.is_loopback() || v6.is_unspecified(),
+    }
+}
+
+#[async_trait]
+impl ReputationProvider for HttpReputationProvider {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn score(&self, ip: IpAddr) -> Option<u8> {
+        if is_local_address(ip) {
+            return None;
+        }
+        if let Some((score, at)) = self.cache.read().await.get(&ip) {
+            if at.elapsed() < self.ttl {
+                return *score;
+            }
+        }
+        match self.lookup(ip).await {
+            Ok(score) => {
+                self.cache_result(ip, score).await;
+                score
+            }
+            Err(e) => {
+                // Not cached, so the next connection retries
+                debug!("IP reputation lookup for {} failed: {}", ip, e);
+                None
+            }
+        }
+    }
+}
+
+/// Outcome of a reputation check
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub score: u8,
+    pub provider: &'static str,
+    /// None if the score is below every threshold
+    pub action: Option<ReputationAction>,
+}
+
+/// The action of the highest threshold the score reaches
+pub fn action_for(actions: &[(u8, ReputationAction)], score: u8) -> Option<ReputationAction> {
+    actions
+        .iter()
+        .filter(|(threshold, _)| score >= *threshold)
+        .map(|(_, action)| *action)
+        .max()
+}
+
+struct Checker {
+    providers: Vec<Arc<dyn ReputationProvider>>,
+    actions: Vec<(u8, ReputationAction)>,
+}
+
+/// Reputation checks consulted before serving a connection.
+/// Does nothing until providers are installed.
+#[derive(Default)]
+pub struct IpReputation {
+    checker: OnceLock<Checker>,
+}
+
+impl fmt::Debug for IpReputation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let providers: Vec<_> = self
+            .checker
+            .get()
+            .map(|c| c.providers.iter().map(|p| p.name()).collect())
+            .unwrap_or_default();
+        f.debug_struct("IpReputation").field("providers", &providers).finish()
+    }
+}
+
+impl IpReputation {
+    /// Install providers and thresholds (only the first call has an effect)
+    pub fn install(&self, providers: Vec<Arc<dyn ReputationProvider>>, actions: Vec<(u8, ReputationAction)>) {
+        if providers.is_empty() {
+            return;
+        }
+        let names: Vec<_> = providers.iter().map(|p| p.name()).collect();
+        if self.checker.set(Checker { providers, actions }).is_ok() {
+            info!("IP reputation providers: {}", names.join(", "));
+        }
+    }
+
+    /// Highest score across providers and the action it triggers
+    pub async fn check(&self, ip: IpAddr) -> Option<Verdict> {
+        let checker = self.checker.get()?;
+        let mut worst: Option<(u8, &'static str)> = None;
+        for provider in &checker.providers {
+            if let Some(score) = provider.score(ip).await {
+                if worst.is_none_or(|(s, _)| score > s) {
+                    worst = Some((score, provider.name()));
+                }
+            }
+        }
+        worst.map(|(score, provider)| Verdict {
+            score,
+            provider,
+            action: action_for(&checker.actions, score),
+        })
+    }
+
+    /// Apply the configured action for a connection.
+    /// Logs and throttles as needed; returns false if the connection must be dropped.
+    pub async fn admit(&self, ip: IpAddr, listener: &str) -> bool {
+        let Some(verdict) = self.check(ip).await else {
+            return true;
+        };
+        match verdict.action {
+            None => true,
+            Some(ReputationAction::Log) => {
+                warn!(
+                    "{} connection from {} has reputation score {} ({})",
+                    listener, ip, verdict.score, verdict.provider
+                );
+                true
+            }
+            Some(ReputationAction::Throttle) => {
+                warn!(
+                    "Throttling {} connection from {} (reputation score {}, {})",
+                    listener, ip, verdict.score, verdict.provider
+                );
+                tokio::time::sleep(THROTTLE_DELAY).await;
+                true
+            }
+            Some(ReputationAction::Block) => {
+                warn!(
+                    "Blocking {} connection from {} (reputation score {}, {})",
+                    listener, ip, verdict.score, verdict.provider
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Providers enabled by the configuration
+pub fn providers_from_config() -> Vec<Arc<dyn ReputationProvider>> {
+    let config = get_config();
+    let mut providers: Vec<Arc<dyn ReputationProvider>> = Vec::new();
+
+    if let Some(ref path) = config.ip_reputation_file {
+        match CidrListProvider::load(path) {
+            Ok(list) => {
+                info!("Loaded {} IP reputation entries from {}", list.len(), path);
+                providers.push(Arc::new(list));
+            }
+            Err(e) => warn!("Failed to read IP reputation file {}: {}", path, e),
+        }
+    }
+    if let Some(ref url) = config.ip_reputation_url {
+        providers.push(Arc::new(HttpReputationProvider::new(
+            url.clone(),
+            config.ip_reputation_api_key.clone(),
+            config.ip_reputation_cache_ttl,
+        )));
+    }
+
+    providers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_reputation_actions;
+
+    #[test]
+    fn test_cidr_contains() {
+        let v4 = Cidr::parse("203.0.113.0/24").unwrap();
+        assert!(v4.contains("203.0.113.77".parse().unwrap()));
+        assert!(!v4.contains("203.0.114.1".parse().unwrap()));
+        assert!(!v4.contains("2001:db8::1".parse().unwrap()));
+
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(Cidr::parse("198.51.100.7").unwrap().contains("198.51.100.7".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cidr_list_scores() {
+        let list = CidrListProvider::parse(
+            "# known scanners\n203.0.113.0/24 60\n203.0.113.8/29   # worst of them\nnot-a-cidr\n",
+        );
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.score("203.0.113.9".parse().unwrap()).await, Some(100));
+        assert_eq!(list.score("203.0.113.200".parse().unwrap()).await, Some(60));
+        assert_eq!(list.score("192.0.2.1".parse().unwrap()).await, None);
+    }
+
+    #[test]
+    fn test_action_thresholds() {
+        let actions = parse_reputation_actions("log:50, block:90 ,throttle:75").unwrap();
+        assert_eq!(actions[0], (90, ReputationAction::Block));
+        assert_eq!(action_for(&actions, 10), None);
+        assert_eq!(action_for(&actions, 50), Some(ReputationAction::Log));
+        assert_eq!(action_for(&actions, 80), Some(ReputationAction::Throttle));
+        assert_eq!(action_for(&actions, 100), Some(ReputationAction::Block));
+
+        assert!(parse_reputation_actions("ban:90").is_none());
+        assert!(parse_reputation_actions("block:101").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let reputation = IpReputation::default();
+        // Nothing installed: everyone is admitted
+        assert!(reputation.admit("203.0.113.9".parse().unwrap(), "HTTP").await);
+
+        let list = CidrListProvider::parse("203.0.113.0/24 95\n198.51.100.0/24 55\n");
+        reputation.install(vec![Arc::new(list)], parse_reputation_actions("block:90,log:50").unwrap());
+        assert!(!reputation.admit("203.0.113.9".parse().unwrap(), "HTTP").await);
+        assert!(reputation.admit("198.51.100.1".parse().unwrap(), "HTTP").await);
+        assert!(reputation.admit("192.0.2.1".parse().unwrap(), "HTTP").await);
+    }
+}
@@ -0,0 +1,495 @@
+//! Embedding API.
+//!
+//! `TunnlService` wires the SSH server, HTTP proxy, management API and
+//! background tasks together the same way the `tunnel` binary does, so other
+//! Rust programs can run the server in-process:
+//!
+//! ```ignore
+//! tunnel::init_config();
+//! TunnlService::builder()
+//!     .ssh_addr("0.0.0.0:2222")
+//!     .proxy_addr("0.0.0.0:8080")
+//!     .auth(my_auth_provider)
+//!     .on_event(|event| println!("{} {}", event.kind.as_str(), event.subdomain))
+//!     .hooks(MyHooks) // implements TunnelHooks
+//!     .build()?
+//!     .run(async {
+//!         let _ = tokio::signal::ctrl_c().await;
+//!     })
+//!     .await?;
+//! ```
+//!
+//! Programs that manage the server's lifetime themselves can `start()` it
+//! instead and keep the returned [`TunnlHandle`] to `shutdown()` it later.
+//! Settings not covered by the builder still come from the global
+//! configuration, which must be initialized first.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::config::{get as get_config, is_clustered, is_loaded as config_loaded, ReconcileMode};
+use crate::crash::spawn_crash_notifier;
+use crate::device::{AuthProvider, DeviceFlowClient, DeviceFlowConfig};
+use crate::key::load_or_generate_server_key_files;
+use crate::maintenance::{default_tasks, spawn_maintenance, MaintenanceTask};
+use crate::reload::spawn_sighup_listener;
+use crate::grpc::run_grpc_api;
+use crate::management::run_management_api;
+use crate::proxy::{run_http_proxy, run_tls_proxy};
+use crate::reputation::providers_from_config;
+use crate::ssh::TunnelServer;
+use crate::state::cluster::run_cluster_sync;
+use crate::state::events::{TunnelEvent, TunnelEventKind};
+use crate::state::hooks::TunnelHooks;
+use crate::state::host_keys::HostKey;
+use crate::state::AppState;
+use crate::systemd::{notify_stopping, spawn_systemd_notifier};
+
+/// Callback for tunnel lifecycle events
+pub type EventHook = Arc<dyn Fn(&TunnelEvent) + Send + Sync>;
+
+/// Where subdomain claims, the audit trail and verified keys are kept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Storage {
+    /// `SUBDOMAIN_CLAIMS_PATH`, `AUDIT_LOG_PATH` and `VERIFIED_KEYS_PATH`
+    #[default]
+    Config,
+    /// `subdomain_claims.json`, `audit.jsonl` and `verified_keys.json` in this directory
+    Directory(PathBuf),
+    /// Nothing is written; claims and verified keys are lost on restart
+    Memory,
+}
+
+impl Storage {
+    /// Files for the claims, the audit trail and verified keys (None = memory only)
+    fn paths(&self) -> (Option<PathBuf>, Option<PathBuf>, Option<PathBuf>) {
+        match self {
+            Self::Config => {
+                let config = get_config();
+                (
+                    Some(PathBuf::from(&config.subdomain_claims_path)),
+                    Some(PathBuf::from(&config.audit_log_path)),
+                    Some(PathBuf::from(&config.verified_keys_path)),
+                )
+            }
+            Self::Directory(dir) => (
+                Some(dir.join("subdomain_claims.json")),
+                Some(dir.join("audit.jsonl")),
+                Some(dir.join("verified_keys.json")),
+            ),
+            Self::Memory => (None, None, None),
+        }
+    }
+}
+
+/// Builder for [`TunnlService`]
+pub struct TunnlServiceBuilder {
+    ssh_addr: String,
+    proxy_addr: String,
+    management_addr: Option<String>,
+    tls_addr: Option<String>,
+    grpc_addr: Option<String>,
+    state: Option<Arc<AppState>>,
+    storage: Option<Storage>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    host_keys: Vec<russh_keys::PrivateKey>,
+    event_hooks: Vec<EventHook>,
+    tunnel_hooks: Vec<Arc<dyn TunnelHooks>>,
+    maintenance: bool,
+}
+
+impl Default for TunnlServiceBuilder {
+    fn default() -> Self {
+        Self {
+            ssh_addr: "0.0.0.0:2222".to_string(),
+            proxy_addr: "0.0.0.0:8080".to_string(),
+            management_addr: Some("0.0.0.0:9090".to_string()),
+            tls_addr: None,
+            grpc_addr: None,
+            state: None,
+            storage: None,
+            auth: None,
+            host_keys: Vec::new(),
+            event_hooks: Vec::new(),
+            tunnel_hooks: Vec::new(),
+            maintenance: true,
+        }
+    }
+}
+
+impl TunnlServiceBuilder {
+    /// SSH listen address (default `0.0.0.0:2222`)
+    pub fn ssh_addr(mut self, addr: impl Into<String>) -> Self {
+        self.ssh_addr = addr.into();
+        self
+    }
+
+    /// HTTP proxy listen address (default `0.0.0.0:8080`)
+    pub fn proxy_addr(mut self, addr: impl Into<String>) -> Self {
+        self.proxy_addr = addr.into();
+        self
+    }
+
+    /// Management API listen address (default `0.0.0.0:9090`, None = disabled)
+    pub fn management_addr(mut self, addr: Option<String>) -> Self {
+        self.management_addr = addr;
+        self
+    }
+
+    /// TLS passthrough listen address (default disabled)
+    pub fn tls_addr(mut self, addr: Option<String>) -> Self {
+        self.tls_addr = addr;
+        self
+    }
+
+    /// gRPC management API listen address (default disabled)
+    pub fn grpc_addr(mut self, addr: Option<String>) -> Self {
+        self.grpc_addr = addr;
+        self
+    }
+
+    /// Share state with the embedding program (default: a fresh `AppState`)
+    pub fn state(mut self, state: Arc<AppState>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Where the state the builder creates keeps claims, the audit trail and verified keys
+    /// (default [`Storage::Config`]; not combinable with [`state`](Self::state))
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// How sessions are authorized (default: Device Flow against `API_BASE_URL`)
+    pub fn auth(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.auth = Some(Arc::new(provider));
+        self
+    }
+
+    /// Add an SSH host key; keys are offered in the order added (default:
+    /// one per `SERVER_KEY_ALGORITHMS`, loaded from or generated next to
+    /// `SERVER_KEY_PATH`)
+    pub fn host_key(mut self, key: russh_keys::PrivateKey) -> Self {
+        self.host_keys.push(key);
+        self
+    }
+
+    /// Call `hook` for every tunnel lifecycle event
+    pub fn on_event(mut self, hook: impl Fn(&TunnelEvent) + Send + Sync + 'static) -> Self {
+        self.event_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Register lifecycle callbacks (tunnels created and closed,
+    /// verifications, finished requests)
+    pub fn hooks(mut self, hooks: impl TunnelHooks + 'static) -> Self {
+        self.tunnel_hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Run the periodic maintenance tasks (default true)
+    pub fn maintenance(mut self, enabled: bool) -> Self {
+        self.maintenance = enabled;
+        self
+    }
+
+    /// Resolve defaults. Fails if the global configuration isn't initialized
+    /// or the host key can't be loaded.
+    pub fn build(self) -> anyhow::Result<TunnlService> {
+        if !config_loaded() {
+            anyhow::bail!("configuration not initialized; call tunnel::init_config() first");
+        }
+        let state = match (self.state, self.storage) {
+            (Some(_), Some(_)) => anyhow::bail!("storage only applies to a state created by the builder"),
+            (Some(state), None) => state,
+            (None, storage) => {
+                let (claims_path, audit_path, verified_keys_path) = storage.unwrap_or_default().paths();
+                Arc::new(AppState::with_storage(claims_path, audit_path, verified_keys_path))
+            }
+        };
+        // Verifications and requests are reported through the state, tunnel
+        // events from the event log like `on_event` hooks
+        let mut event_hooks = self.event_hooks;
+        for hooks in self.tunnel_hooks {
+            state.hooks.add(hooks.clone());
+            event_hooks.push(Arc::new(move |event: &TunnelEvent| match event.kind {
+                TunnelEventKind::Connected => hooks.on_tunnel_created(event),
+                TunnelEventKind::Removed => hooks.on_tunnel_closed(event),
+                TunnelEventKind::Disconnected
+                | TunnelEventKind::Renamed
+                | TunnelEventKind::Degraded
+                | TunnelEventKind::Recovered => {}
+            }));
+        }
+        let auth = match self.auth {
+            Some(auth) => auth,
+            None => Arc::new(DeviceFlowClient::new(DeviceFlowConfig::default())),
+        };
+        // Keys loaded from files can be rotated later; supplied ones can't
+        let host_keys = if self.host_keys.is_empty() {
+            load_or_generate_server_key_files()?
+                .into_iter()
+                .map(|(path, key)| HostKey::new(key, Some(path)))
+                .collect()
+        } else {
+            self.host_keys.into_iter().map(|key| HostKey::new(key, None)).collect()
+        };
+
+        Ok(TunnlService {
+            ssh_addr: self.ssh_addr,
+            proxy_addr: self.proxy_addr,
+            management_addr: self.management_addr,
+            tls_addr: self.tls_addr,
+            grpc_addr: self.grpc_addr,
+            state,
+            auth,
+            host_keys,
+            event_hooks,
+            maintenance: self.maintenance,
+        })
+    }
+}
+
+/// A configured tunnel server, ready to run
+pub struct TunnlService {
+    ssh_addr: String,
+    proxy_addr: String,
+    management_addr: Option<String>,
+    tls_addr: Option<String>,
+    grpc_addr: Option<String>,
+    state: Arc<AppState>,
+    auth: Arc<dyn AuthProvider>,
+    host_keys: Vec<HostKey>,
+    event_hooks: Vec<EventHook>,
+    maintenance: bool,
+}
+
+/// Resolves on Ctrl-C or SIGTERM (sent by `systemctl stop` and `docker stop`)
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Run a listener, or wait forever if it is disabled
+async fn optional<F>(listener: Option<F>) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    match listener {
+        Some(listener) => listener.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Deliver events to the hooks until the event log goes away
+fn spawn_event_hooks(state: &AppState, hooks: Vec<EventHook>) -> JoinHandle<()> {
+    let (mut receiver, _) = state.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => hooks.iter().for_each(|hook| hook(&event)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Event hooks fell behind, {} events skipped", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// A server started with [`TunnlService::start`]
+pub struct TunnlHandle {
+    state: Arc<AppState>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TunnlHandle {
+    /// The shared state (tunnels, bans, events, ...)
+    pub fn state(&self) -> Arc<AppState> {
+        self.state.clone()
+    }
+
+    /// Whether the server has stopped on its own (a listener failed)
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stop the server and wait for it; returns the error it stopped with,
+    /// if it had already failed
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        let _ = self.shutdown.send(());
+        self.task.await?
+    }
+}
+
+impl TunnlService {
+    pub fn builder() -> TunnlServiceBuilder {
+        TunnlServiceBuilder::default()
+    }
+
+    /// The shared state (tunnels, bans, events, ...)
+    pub fn state(&self) -> Arc<AppState> {
+        self.state.clone()
+    }
+
+    /// Run on a background task until [`TunnlHandle::shutdown`] is called
+    pub fn start(self) -> TunnlHandle {
+        let state = self.state.clone();
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(self.run(async {
+            let _ = stop.await;
+        }));
+        TunnlHandle { state, shutdown, task }
+    }
+
+    /// Serve until a listener fails or `shutdown` completes. Background tasks
+    /// started here are stopped before returning.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let state = self.state;
+        state
+            .reputation
+            .install(providers_from_config(), get_config().ip_reputation_actions.clone());
+        state.subdomain_pool.refill(&state).await;
+        if get_config().maintenance_mode {
+            log::warn!("Starting in maintenance mode: new sessions and tunnels are refused");
+            state.maintenance_mode.enable(get_config().maintenance_message.clone()).await;
+        }
+
+        if get_config().skip_auth {
+            log::warn!("═══════════════════════════════════════════════════════════════");
+            log::warn!("!!! TUNNEL_SKIP_AUTH is active: SSH sessions skip the Device Flow !!!");
+            log::warn!("!!! Anyone who can reach the SSH port can open tunnels.         !!!");
+            log::warn!("!!! Development builds only; never expose this server.         !!!");
+            log::warn!("═══════════════════════════════════════════════════════════════");
+        }
+
+        // Rebuilt by the SSH server whenever a key rotation changes the keys
+        state.host_keys.install(self.host_keys, get_config().host_key_rotation_grace);
+        let ssh_config = |keys| russh::server::Config {
+            methods: russh::MethodSet::PUBLICKEY | russh::MethodSet::PASSWORD,
+            server_id: russh::SshId::Standard(format!("SSH-2.0-EXLO_{}", env!("CARGO_PKG_VERSION"))),
+            keys,
+            inactivity_timeout: Some(Duration::from_secs(1800)),
+            keepalive_interval: get_config().ssh_keepalive_interval,
+            keepalive_max: get_config().ssh_keepalive_max,
+            auth_rejection_time: Duration::from_secs(3),
+            auth_rejection_time_initial: Some(Duration::from_secs(0)),
+            ..Default::default()
+        };
+        let server = TunnelServer::new(state.clone(), self.auth.clone());
+
+        let mut background = Vec::new();
+        if self.maintenance {
+            // Periodically clean up expired tunnels, keys and rate limits
+            let mut tasks = default_tasks();
+            let mode = get_config().backend_reconcile;
+            if mode != ReconcileMode::Off {
+                let auth = self.auth.clone();
+                tasks.push(MaintenanceTask::new(
+                    "backend_reconcile",
+                    get_config().backend_reconcile_interval,
+                    move |state| {
+                        let auth = auth.clone();
+                        async move { state.reconcile.run(&state, auth.as_ref(), mode).await }
+                    },
+                ));
+            }
+            background.extend(spawn_maintenance(state.clone(), tasks));
+        }
+        // Keep the shared tunnel registry in sync with the other cluster nodes
+        if is_clustered() {
+            background.push(tokio::spawn(run_cluster_sync(state.clone())));
+        }
+        if let Some(url) = get_config().crash_webhook_url.clone() {
+            background.push(spawn_crash_notifier(url));
+        }
+        if !self.event_hooks.is_empty() {
+            background.push(spawn_event_hooks(&state, self.event_hooks));
+        }
+        // Reload TTLs, rate limits, reserved subdomains and the log filter on SIGHUP
+        background.extend(spawn_sighup_listener());
+        // READY/STATUS/WATCHDOG for systemd units
+        background.extend(spawn_systemd_notifier(state.clone()));
+
+        let management = self
+            .management_addr
+            .as_deref()
+            .map(|addr| run_management_api(state.clone(), addr));
+        let tls = self.tls_addr.as_deref().map(|addr| run_tls_proxy(state.clone(), addr));
+        let grpc = self.grpc_addr.as_deref().map(|addr| run_grpc_api(state.clone(), addr));
+
+        let result = tokio::select! {
+            result = server.run(ssh_config, &self.ssh_addr) => result,
+            result = run_http_proxy(state.clone(), &self.proxy_addr) => result,
+            result = optional(management) => result,
+            result = optional(tls) => result,
+            result = optional(grpc) => result,
+            _ = shutdown => {
+                info!("Shutting down");
+                Ok(())
+            }
+        };
+
+        notify_stopping();
+        for task in background {
+            task.abort();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_event_hooks() {
+        let state = AppState::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let hook: EventHook = Arc::new(move |event: &TunnelEvent| {
+            let _ = tx.send((event.kind, event.subdomain.clone()));
+        });
+        let task = spawn_event_hooks(&state, vec![hook]);
+
+        state.events.publish(TunnelEventKind::Connected, "app", None, "user1");
+        assert_eq!(rx.recv().await, Some((TunnelEventKind::Connected, "app".to_string())));
+        task.abort();
+    }
+
+    #[test]
+    fn test_storage_paths() {
+        let dir = PathBuf::from("/var/lib/exlo");
+        assert_eq!(
+            Storage::Directory(dir.clone()).paths(),
+            (
+                Some(dir.join("subdomain_claims.json")),
+                Some(dir.join("audit.jsonl")),
+                Some(dir.join("verified_keys.json"))
+            )
+        );
+        assert_eq!(Storage::Memory.paths(), (None, None, None));
+    }
+}
@@ -0,0 +1,139 @@
+//! OpenSSH user certificates.
+//!
+//! With `TRUSTED_USER_CA_KEYS` pointing at a file of CA public keys (the
+//! format of sshd's `TrustedUserCAKeys`), a client presenting a user
+//! certificate signed by one of them is verified without the Device Flow: the
+//! certificate's first principal becomes the session's user_id. The
+//! certificate must be within its validity window; a `source-address`
+//! critical option is enforced against the client's address, and any other
+//! critical option (e.g. `force-command`) makes the certificate unusable here.
+
+use std::net::IpAddr;
+
+use russh_keys::ssh_key::certificate::CertType;
+use russh_keys::{Certificate, HashAlg, PublicKey};
+
+use crate::reputation::Cidr;
+
+/// Parse CA public keys, one per line in OpenSSH format; blank lines and
+/// `#` comments are skipped
+pub fn parse_trusted_ca_keys(contents: &str) -> Result<Vec<PublicKey>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| PublicKey::from_openssh(line).map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// Who a valid certificate vouches for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertifiedUser {
+    /// First principal of the certificate
+    pub user_id: String,
+    /// Free-form key id the CA put in the certificate (for logs)
+    pub key_id: String,
+    pub ca_fingerprint: String,
+}
+
+/// Check `certificate` against the trusted CAs at `now` (Unix seconds) for a
+/// client connecting from `peer`
+pub fn verify_user_certificate(
+    certificate: &Certificate,
+    trusted: &[PublicKey],
+    peer: Option<IpAddr>,
+    now: u64,
+) -> Result<CertifiedUser, String> {
+    if trusted.is_empty() {
+        return Err("no certificate authority is trusted".to_string());
+    }
+    let fingerprints: Vec<_> = trusted.iter().map(|ca| ca.fingerprint(HashAlg::Sha256)).collect();
+    certificate
+        .validate_at(now, &fingerprints)
+        .map_err(|e| format!("certificate rejected: {}", e))?;
+    if certificate.cert_type() != CertType::User {
+        return Err("not a user certificate".to_string());
+    }
+
+    for (name, value) in certificate.critical_options().iter() {
+        match name.as_str() {
+            "source-address" => {
+                let allowed = peer.is_some_and(|ip| {
+                    value
+                        .split(',')
+                        .filter_map(|cidr| Cidr::parse(cidr.trim()))
+                        .any(|cidr| cidr.contains(ip))
+                });
+                if !allowed {
+                    return Err(format!("source address {:?} is not allowed by the certificate", peer));
+                }
+            }
+            other => return Err(format!("unsupported critical option '{}'", other)),
+        }
+    }
+
+    let Some(user_id) = certificate.valid_principals().first().filter(|p| !p.is_empty()) else {
+        return Err("certificate names no principal".to_string());
+    };
+    Ok(CertifiedUser {
+        user_id: user_id.clone(),
+        key_id: certificate.key_id().to_string(),
+        ca_fingerprint: certificate.signature_key().fingerprint(HashAlg::Sha256).to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh_keys::ssh_key::certificate::Builder;
+    use russh_keys::{Algorithm, PrivateKey};
+
+    fn certificate(ca: &PrivateKey, principals: &[&str], source_address: Option<&str>) -> Certificate {
+        let user = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+        let mut builder = Builder::new([7u8; 16], user.public_key(), 1_000, 2_000).unwrap();
+        builder.cert_type(CertType::User).unwrap();
+        builder.key_id("alice@laptop").unwrap();
+        for principal in principals {
+            builder.valid_principal(*principal).unwrap();
+        }
+        if principals.is_empty() {
+            // Valid for any user: says nothing about who the client is
+            builder.all_principals_valid().unwrap();
+        }
+        if let Some(source_address) = source_address {
+            builder.critical_option("source-address", source_address).unwrap();
+        }
+        builder.sign(ca).unwrap()
+    }
+
+    #[test]
+    fn test_verify_user_certificate() {
+        let ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+        let other_ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+        let trusted = vec![ca.public_key().clone()];
+        let peer: Option<IpAddr> = Some("10.1.2.3".parse().unwrap());
+
+        let user = verify_user_certificate(&certificate(&ca, &["user_123"], None), &trusted, peer, 1_500).unwrap();
+        assert_eq!(user.user_id, "user_123");
+        assert_eq!(user.key_id, "alice@laptop");
+
+        // Expired, untrusted CA, no principal
+        assert!(verify_user_certificate(&certificate(&ca, &["user_123"], None), &trusted, peer, 2_500).is_err());
+        assert!(verify_user_certificate(&certificate(&other_ca, &["user_123"], None), &trusted, peer, 1_500).is_err());
+        assert!(verify_user_certificate(&certificate(&ca, &[], None), &trusted, peer, 1_500).is_err());
+
+        let restricted = certificate(&ca, &["user_123"], Some("10.0.0.0/8,192.168.1.1"));
+        assert!(verify_user_certificate(&restricted, &trusted, peer, 1_500).is_ok());
+        assert!(verify_user_certificate(&restricted, &trusted, Some("203.0.113.9".parse().unwrap()), 1_500).is_err());
+    }
+
+    #[test]
+    fn test_parse_trusted_ca_keys() {
+        let ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+        let line = ca.public_key().to_openssh().unwrap();
+        let keys = parse_trusted_ca_keys(&format!("# team CA\n\n{}\n", line)).unwrap();
+        assert_eq!(keys, vec![ca.public_key().clone()]);
+        assert!(parse_trusted_ca_keys("ssh-ed25519 garbage").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Second-factor confirmation for destructive exec commands.
+//!
+//! `close` and `rotate-secret` don't run right away: the server writes a
+//! short code to stderr and waits for the user to type it back on the exec
+//! channel (`SshHandler::data`). A wrong code, Ctrl-C / Ctrl-D, or no answer
+//! within `CONFIRMATION_TIMEOUT` ends the command without running it, so a
+//! stray script or a copied command line can't close a tunnel by accident.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use russh::server::Handle;
+use russh::ChannelId;
+use tokio::sync::Mutex;
+
+use crate::device::generate_activation_code;
+
+use super::types::SharedHandlerState;
+
+/// How long the user has to type the code
+pub const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest answer accepted before giving up on a line
+const MAX_INPUT: usize = 64;
+
+/// A destructive command waiting for its code
+#[derive(Debug, Clone)]
+pub struct PendingConfirmation {
+    /// The exec command line to run once confirmed
+    pub command: String,
+    pub code: String,
+    input: Vec<u8>,
+}
+
+/// Result of feeding typed input to a pending confirmation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Confirmation {
+    /// No complete line yet
+    Waiting,
+    /// The code matched; run this command
+    Confirmed(String),
+    /// Wrong code or cancelled
+    Refused(&'static str),
+}
+
+impl PendingConfirmation {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            code: generate_activation_code(),
+            input: Vec::new(),
+        }
+    }
+
+    /// The prompt written to stderr
+    pub fn prompt(&self, action: &str) -> String {
+        format!(
+            "This will {}. Type {} and press Enter to confirm ({}s): ",
+            action,
+            self.code,
+            CONFIRMATION_TIMEOUT.as_secs()
+        )
+    }
+
+    /// Add typed bytes; decides once a line is complete
+    pub fn feed(&mut self, data: &[u8]) -> Confirmation {
+        for &byte in data {
+            match byte {
+                // Ctrl-C, Ctrl-D
+                3 | 4 => return Confirmation::Refused("Cancelled"),
+                b'\r' | b'\n' => {
+                    let typed: String = String::from_utf8_lossy(&self.input)
+                        .chars()
+                        .filter(|c| !c.is_whitespace())
+                        .collect();
+                    return if typed.eq_ignore_ascii_case(&self.code) {
+                        Confirmation::Confirmed(self.command.clone())
+                    } else {
+                        Confirmation::Refused("Confirmation code did not match")
+                    };
+                }
+                // Backspace / DEL when the client sends raw keystrokes
+                8 | 127 => {
+                    self.input.pop();
+                }
+                _ => self.input.push(byte),
+            }
+            if self.input.len() > MAX_INPUT {
+                return Confirmation::Refused("Confirmation code did not match");
+            }
+        }
+        Confirmation::Waiting
+    }
+}
+
+/// End the command if the code hasn't been typed in time
+pub fn spawn_confirmation_timeout(shared_state: Arc<Mutex<SharedHandlerState>>, handle: Handle, channel: ChannelId, code: String) {
+    tokio::spawn(async move {
+        tokio::time::sleep(CONFIRMATION_TIMEOUT).await;
+        {
+            let mut shared = shared_state.lock().await;
+            let still_pending = shared
+                .pending_confirmation
+                .as_ref()
+                .is_some_and(|(id, p)| *id == channel && p.code == code);
+            if !still_pending {
+                return;
+            }
+            shared.pending_confirmation = None;
+        }
+        info!("Confirmation on channel {:?} timed out", channel);
+        let _ = handle
+            .extended_data(channel, 1, "\nerror: Confirmation timed out\n".to_string().into_bytes().into())
+            .await;
+        let _ = handle.exit_status_request(channel, 1).await;
+        let _ = handle.eof(channel).await;
+        let _ = handle.close(channel).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feed() {
+        let mut pending = PendingConfirmation::new("close myapp");
+        let code = pending.code.clone();
+        assert_eq!(pending.feed(&code.as_bytes()[..4]), Confirmation::Waiting);
+        let rest = format!("{} \r\n", code[4..].to_lowercase());
+        assert_eq!(pending.feed(rest.as_bytes()), Confirmation::Confirmed("close myapp".to_string()));
+
+        let mut pending = PendingConfirmation::new("close myapp");
+        assert!(matches!(pending.feed(b"0000-0000\n"), Confirmation::Refused(_)));
+        let mut pending = PendingConfirmation::new("close myapp");
+        assert!(matches!(pending.feed(&[3]), Confirmation::Refused("Cancelled")));
+    }
+}
@@ -0,0 +1,408 @@
+//! Control channel for programmatic clients (`exlo-control` subsystem).
+//!
+//! A client opens a session channel and requests the `exlo-control`
+//! subsystem instead of a shell. Both sides then exchange frames: a 4-byte
+//! big-endian length followed by a JSON message carrying the protocol version
+//! (`{"v":1,"type":"status"}`). The client asks for status, renames tunnels,
+//! changes profiles and redeems grants; the server answers and pushes tunnel
+//! status updates, renames and config changes as they happen. Terminal notices
+//! are not written to a control channel.
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use russh::server::Handle;
+use russh::ChannelId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::get_tunnel_url;
+use crate::grant;
+use crate::state::perf_profiles::PerfProfile;
+use crate::state::{AppState, TunnelInfo};
+
+use super::handler::SshHandler;
+use super::types::{SharedHandlerState, VerificationStatus};
+
+/// Subsystem name clients request
+pub const CONTROL_SUBSYSTEM: &str = "exlo-control";
+
+/// Protocol version spoken by this server
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest frame either side may send
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// A message from the client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// First message; the server answers with the version both sides speak
+    Hello {
+        version: u32,
+        #[serde(default)]
+        client: Option<String>,
+    },
+    /// Ask for the session's tunnels
+    Status,
+    /// Move a tunnel to a new subdomain (current may be omitted with one tunnel)
+    Rename {
+        #[serde(default)]
+        subdomain: Option<String>,
+        new: String,
+    },
+    /// Change a tunnel's performance profile (None = global settings)
+    SetProfile {
+        subdomain: String,
+        #[serde(default)]
+        profile: Option<PerfProfile>,
+    },
+    /// Redeem a signed grant and request its subdomain for new tunnels
+    Redeem { grant: String },
+    Ping { id: u64 },
+}
+
+/// A tunnel as reported on the control channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlTunnel {
+    pub subdomain: String,
+    pub url: String,
+    pub local_port: u32,
+    pub connected: bool,
+    pub awaiting_local_service: bool,
+    /// The local service fails health checks
+    #[serde(default)]
+    pub degraded: bool,
+    pub profile: Option<PerfProfile>,
+}
+
+impl From<&TunnelInfo> for ControlTunnel {
+    fn from(tunnel: &TunnelInfo) -> Self {
+        Self {
+            subdomain: tunnel.subdomain.clone(),
+            url: get_tunnel_url(&tunnel.subdomain),
+            local_port: tunnel.requested_port,
+            connected: tunnel.is_connected,
+            awaiting_local_service: tunnel.awaiting_local_service,
+            degraded: tunnel.degraded_since.is_some(),
+            profile: tunnel.perf_profile,
+        }
+    }
+}
+
+/// A message from the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        version: u32,
+        server: String,
+        session_id: String,
+        /// Set while the session waits for its activation code to be approved
+        activation_url: Option<String>,
+    },
+    /// The session's tunnels (answer to `status`, and pushed on changes)
+    Status { tunnels: Vec<ControlTunnel> },
+    Renamed { from: String, to: String, url: String },
+    /// A tunnel's settings changed
+    Config {
+        subdomain: String,
+        profile: Option<PerfProfile>,
+    },
+    /// A grant was redeemed; the subdomain is claimed for `user_id`
+    Redeemed {
+        subdomain: String,
+        user_id: String,
+        expires_at: Option<DateTime<Utc>>,
+    },
+    Pong { id: u64 },
+    Error { message: String },
+}
+
+/// A message with the protocol version it was written for
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope<T> {
+    v: u32,
+    #[serde(flatten)]
+    message: T,
+}
+
+/// Encode a server message as one frame
+pub fn encode_frame(message: &ServerMessage) -> Vec<u8> {
+    let body = serde_json::to_vec(&Envelope {
+        v: PROTOCOL_VERSION,
+        message,
+    })
+    .unwrap_or_default();
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// A frame announced a length over `MAX_FRAME_LEN`; the stream can't be resynchronized
+#[derive(Debug, PartialEq)]
+pub struct FrameTooLarge(pub usize);
+
+/// Splits the client's byte stream into messages
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Add received bytes and take every complete frame. A frame that isn't a
+    /// known message yields an error for that frame only.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Result<ClientMessage, String>>, FrameTooLarge> {
+        self.buf.extend_from_slice(data);
+        let mut messages = Vec::new();
+        while let Some(header) = self.buf.first_chunk::<4>() {
+            let len = u32::from_be_bytes(*header) as usize;
+            if len > MAX_FRAME_LEN {
+                return Err(FrameTooLarge(len));
+            }
+            if self.buf.len() < 4 + len {
+                break;
+            }
+            let frame: Vec<u8> = self.buf.drain(..4 + len).skip(4).collect();
+            messages.push(decode_message(&frame));
+        }
+        Ok(messages)
+    }
+}
+
+fn decode_message(frame: &[u8]) -> Result<ClientMessage, String> {
+    let envelope: Envelope<ClientMessage> =
+        serde_json::from_slice(frame).map_err(|e| format!("Invalid message: {}", e))?;
+    if envelope.v == 0 || envelope.v > PROTOCOL_VERSION {
+        return Err(format!(
+            "Unsupported protocol version {} (server speaks 1..={})",
+            envelope.v, PROTOCOL_VERSION
+        ));
+    }
+    Ok(envelope.message)
+}
+
+/// Send a message on a control channel, if there is one
+pub async fn push(handle: &Handle, channel: Option<ChannelId>, message: &ServerMessage) {
+    let Some(channel) = channel else { return };
+    if handle.data(channel, encode_frame(message).into()).await.is_err() {
+        debug!("Failed to push control message to channel {:?}", channel);
+    }
+}
+
+/// Push the session's tunnels to its control channel
+pub async fn push_session_status(state: &AppState, shared_state: &Mutex<SharedHandlerState>) {
+    let (handle, channel, session_id) = {
+        let shared = shared_state.lock().await;
+        (shared.session_handle.clone(), shared.control_channel_id, shared.session_id.clone())
+    };
+    let (Some(handle), Some(channel)) = (handle, channel) else { return };
+    let message = ServerMessage::Status {
+        tunnels: session_tunnels(state, &session_id).await,
+    };
+    push(&handle, Some(channel), &message).await;
+}
+
+/// Connected tunnels of a session, sorted by subdomain
+async fn session_tunnels(state: &AppState, session_id: &str) -> Vec<ControlTunnel> {
+    let mut tunnels = Vec::new();
+    for subdomain in state.session_subdomains(session_id).await {
+        if let Some(tunnel) = state.get_tunnel(&subdomain).await {
+            tunnels.push(ControlTunnel::from(tunnel.as_ref()));
+        }
+    }
+    tunnels.sort_by(|a, b| a.subdomain.cmp(&b.subdomain));
+    tunnels
+}
+
+impl SshHandler {
+    /// Make `channel` the session's control channel
+    pub(super) async fn open_control_channel(&mut self, channel: ChannelId) {
+        info!("Control channel opened: id={:?}", channel);
+        self.control = Some((channel, FrameDecoder::default()));
+        self.open_channel = None;
+        // Notices meant for a terminal would corrupt the frames: they go back
+        // to the session channel opened before this one, if any
+        let terminal = if self.session_channel_id == Some(channel) {
+            self.session_channel_id = self.previous_session_channel_id.take();
+            self.session_channel_id
+        } else {
+            self.session_channel_id
+        };
+        let registered = {
+            let mut shared = self.shared_state.lock().await;
+            shared.control_channel_id = Some(channel);
+            shared.session_channel_id = terminal;
+            shared.registered_subdomains.clone()
+        };
+        for subdomain in &registered {
+            self.state.set_control_channel(subdomain, Some(channel)).await;
+            if let Some(terminal) = terminal {
+                self.state.set_session_channel(subdomain, terminal).await;
+            }
+        }
+    }
+
+    /// The session's control channel closed
+    pub(super) async fn close_control_channel(&mut self) {
+        self.control = None;
+        let registered = {
+            let mut shared = self.shared_state.lock().await;
+            shared.control_channel_id = None;
+            shared.registered_subdomains.clone()
+        };
+        for subdomain in &registered {
+            self.state.set_control_channel(subdomain, None).await;
+        }
+    }
+
+    /// Handle bytes received on the control channel; returns the frames to
+    /// send back, or None if the channel must be closed
+    pub(super) async fn control_data(&mut self, data: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let decoded = self.control.as_mut()?.1.push(data);
+        let messages = match decoded {
+            Ok(messages) => messages,
+            Err(FrameTooLarge(len)) => {
+                warn!("Closing control channel: {} byte frame exceeds {}", len, MAX_FRAME_LEN);
+                return None;
+            }
+        };
+        let mut replies = Vec::new();
+        for message in messages {
+            let reply = match message {
+                Ok(message) => self.control_message(message).await,
+                Err(message) => ServerMessage::Error { message },
+            };
+            replies.push(encode_frame(&reply));
+        }
+        Some(replies)
+    }
+
+    async fn control_message(&self, message: ClientMessage) -> ServerMessage {
+        debug!("Control message: {:?}", message);
+        let user_id = match self.get_verification_status().await {
+            VerificationStatus::Verified { user_id, .. } => Some(user_id),
+            _ => None,
+        };
+        match message {
+            ClientMessage::Hello { version, client } => {
+                info!("Control client {:?} speaks version {}", client, version);
+                let activation_url = match self.get_verification_status().await {
+                    VerificationStatus::Pending { code } => Some(self.device_flow_client.get_activation_url(&code)),
+                    _ => None,
+                };
+                ServerMessage::Hello {
+                    version: version.clamp(1, PROTOCOL_VERSION),
+                    server: env!("CARGO_PKG_VERSION").to_string(),
+                    session_id: self.session_id.clone(),
+                    activation_url,
+                }
+            }
+            ClientMessage::Status => ServerMessage::Status {
+                tunnels: session_tunnels(&self.state, &self.session_id).await,
+            },
+            ClientMessage::Ping { id } => ServerMessage::Pong { id },
+            ClientMessage::Redeem { grant } => match grant::redeem(&self.state, &grant).await {
+                Ok(claim) => {
+                    info!("Grant redeemed on control channel for '{}'", claim.subdomain);
+                    self.shared_state.lock().await.requested_subdomain = Some(claim.subdomain.clone());
+                    ServerMessage::Redeemed {
+                        subdomain: claim.subdomain,
+                        user_id: claim.user_id,
+                        expires_at: claim.expires_at,
+                    }
+                }
+                Err(message) => ServerMessage::Error { message },
+            },
+            ClientMessage::Rename { .. } | ClientMessage::SetProfile { .. } if user_id.is_none() => {
+                ServerMessage::Error {
+                    message: "This session is not activated yet".to_string(),
+                }
+            }
+            ClientMessage::Rename { subdomain, new } => {
+                let user_id = user_id.unwrap_or_default();
+                match self.rename_owned_tunnel(&user_id, subdomain, &new.to_lowercase()).await {
+                    Ok((from, tunnel)) => ServerMessage::Renamed {
+                        from,
+                        url: get_tunnel_url(&tunnel.subdomain),
+                        to: tunnel.subdomain.clone(),
+                    },
+                    Err(message) => ServerMessage::Error { message },
+                }
+            }
+            ClientMessage::SetProfile { subdomain, profile } => {
+                let user_id = user_id.unwrap_or_default();
+                match self.set_owned_profile(&user_id, &subdomain.to_lowercase(), profile).await {
+                    Ok(tunnel) => ServerMessage::Config {
+                        subdomain: tunnel.subdomain.clone(),
+                        profile: tunnel.perf_profile,
+                    },
+                    Err(message) => ServerMessage::Error { message },
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(json: &str) -> Vec<u8> {
+        let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(json.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_decoder_reassembles_frames() {
+        let mut bytes = frame(r#"{"v":1,"type":"hello","version":1,"client":"exlo/0.1"}"#);
+        bytes.extend(frame(r#"{"v":1,"type":"rename","new":"myapp"}"#));
+        bytes.extend(frame(r#"{"v":1,"type":"ping","id":7}"#));
+
+        let mut decoder = FrameDecoder::default();
+        let (first, rest) = bytes.split_at(10);
+        assert!(decoder.push(first).unwrap().is_empty());
+        let messages = decoder.push(rest).unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                Ok(ClientMessage::Hello {
+                    version: 1,
+                    client: Some("exlo/0.1".to_string()),
+                }),
+                Ok(ClientMessage::Rename {
+                    subdomain: None,
+                    new: "myapp".to_string(),
+                }),
+                Ok(ClientMessage::Ping { id: 7 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decoder_rejects_bad_frames() {
+        let mut decoder = FrameDecoder::default();
+        let messages = decoder
+            .push(&[frame(r#"{"v":2,"type":"status"}"#), frame(r#"{"v":1,"type":"reboot"}"#)].concat())
+            .unwrap();
+        assert!(messages.iter().all(Result::is_err));
+        // The stream stays usable after a bad message
+        assert_eq!(
+            decoder.push(&frame(r#"{"v":1,"type":"status"}"#)).unwrap(),
+            vec![Ok(ClientMessage::Status)]
+        );
+
+        let too_large = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        assert_eq!(decoder.push(&too_large), Err(FrameTooLarge(MAX_FRAME_LEN + 1)));
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let bytes = encode_frame(&ServerMessage::Pong { id: 7 });
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, bytes.len() - 4);
+        let json: serde_json::Value = serde_json::from_slice(&bytes[4..]).unwrap();
+        assert_eq!(json, serde_json::json!({"v": 1, "type": "pong", "id": 7}));
+    }
+}
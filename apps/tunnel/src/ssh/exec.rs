@@ -0,0 +1,674 @@
+//! One-shot commands run over an exec channel (`ssh -p 2222 server -- <command>`).
+//!
+//! `status`, `list`, `stats`, `alert`, `profile`, `headers`, `rename`, `close` and `rotate-secret` print a single JSON
+//! document so they can be scripted; errors go to stderr with a non-zero exit status.
+//! `speedtest` keeps the channel open while it measures (see `speedtest`);
+//! `close` and `rotate-secret` first wait for a confirmation code (see `confirm`).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use russh::{ChannelId, Disconnect};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::acl::Capability;
+use crate::config::{get as get_config, get_tunnel_url};
+use crate::device::RegisterTunnelRequest;
+use crate::proxy::share_secret::{generate_share_secret, SHARE_SECRET_PARAM};
+use crate::state::bandwidth::BandwidthUsage;
+use crate::state::header_rules::HeaderRules;
+use crate::state::perf_profiles::PerfProfile;
+use crate::state::status_alerts::{parse_threshold, parse_window, StatusAlert};
+use crate::state::TunnelInfo;
+use crate::terminal_ui::{self, OutputMode, SessionEvent};
+
+use super::confirm::{spawn_confirmation_timeout, Confirmation, PendingConfirmation};
+use super::control::{self, ServerMessage};
+use super::handler::SshHandler;
+use super::speedtest::run_speedtest;
+use super::types::{validate_subdomain, SubdomainValidation, VerificationStatus};
+
+/// Commands accepted on the exec channel (shown in usage errors)
+const USAGE: &str =
+    "Available: status, list, history, stats <subdomain>, alert <subdomain> <percent> <window>|off, profile <subdomain> interactive|streaming|bulk|default, headers <subdomain> [set <name> <value>|remove <name>|clear], rename [<subdomain>] <new-subdomain>, close <subdomain>, rotate-secret <subdomain>, speedtest";
+
+/// A parsed exec command
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecCommand {
+    /// Show who the key belongs to and how many tunnels are connected
+    Status,
+    /// List the user's tunnels
+    List,
+    /// Show the user's recent tunnels
+    History,
+    /// Move a tunnel to a new subdomain (current may be omitted if the user has one tunnel)
+    Rename {
+        current: Option<String>,
+        new: String,
+    },
+    /// Close a tunnel and disconnect its session
+    Close(String),
+    /// Show a tunnel's response status counts and alert
+    Stats(String),
+    /// Alert when the share of 5xx responses over `window` reaches `threshold_percent`
+    Alert {
+        subdomain: String,
+        threshold_percent: f64,
+        window: Duration,
+    },
+    /// Remove a tunnel's alert
+    ClearAlert(String),
+    /// Tune a tunnel's new connections (None = global settings)
+    Profile {
+        subdomain: String,
+        profile: Option<PerfProfile>,
+    },
+    /// Show or change the headers added to and removed from a tunnel's responses
+    Headers {
+        subdomain: String,
+        change: Option<HeaderChange>,
+    },
+    /// Generate a new share secret (password) for a tunnel, invalidating the old one
+    RotateSecret(String),
+    /// Measure round-trip time and throughput to the server
+    SpeedTest,
+}
+
+/// A change to a tunnel's response header rules
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderChange {
+    Set { name: String, value: String },
+    Remove(String),
+    Clear,
+}
+
+impl HeaderChange {
+    fn apply(self, rules: &mut HeaderRules) -> Result<(), String> {
+        match self {
+            Self::Set { name, value } => rules.set_header(&name, &value),
+            Self::Remove(name) => rules.remove_header(&name),
+            Self::Clear => {
+                *rules = HeaderRules::default();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ExecCommand {
+    pub fn parse(command: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        match parts.as_slice() {
+            ["status"] => Ok(Self::Status),
+            ["list"] => Ok(Self::List),
+            ["history"] => Ok(Self::History),
+            ["rename", new] => Ok(Self::Rename {
+                current: None,
+                new: new.to_lowercase(),
+            }),
+            ["rename", current, new] => Ok(Self::Rename {
+                current: Some(current.to_lowercase()),
+                new: new.to_lowercase(),
+            }),
+            ["close", subdomain] => Ok(Self::Close(subdomain.to_lowercase())),
+            ["rotate-secret", subdomain] => Ok(Self::RotateSecret(subdomain.to_lowercase())),
+            ["stats", subdomain] => Ok(Self::Stats(subdomain.to_lowercase())),
+            ["alert", subdomain, "off"] => Ok(Self::ClearAlert(subdomain.to_lowercase())),
+            ["alert", subdomain, threshold, window] => Ok(Self::Alert {
+                subdomain: subdomain.to_lowercase(),
+                threshold_percent: parse_threshold(threshold)
+                    .ok_or_else(|| format!("Invalid threshold '{}': use a percentage in (0, 100]", threshold))?,
+                window: parse_window(window)
+                    .ok_or_else(|| format!("Invalid window '{}': use e.g. 300, 30s, 5m or 1h (max 24h)", window))?,
+            }),
+            ["profile", subdomain, "default"] => Ok(Self::Profile {
+                subdomain: subdomain.to_lowercase(),
+                profile: None,
+            }),
+            ["profile", subdomain, profile] => Ok(Self::Profile {
+                subdomain: subdomain.to_lowercase(),
+                profile: Some(PerfProfile::parse(profile).ok_or_else(|| {
+                    format!("Invalid profile '{}': use interactive, streaming, bulk or default", profile)
+                })?),
+            }),
+            ["headers", subdomain] => Ok(Self::Headers {
+                subdomain: subdomain.to_lowercase(),
+                change: None,
+            }),
+            ["headers", subdomain, "clear"] => Ok(Self::Headers {
+                subdomain: subdomain.to_lowercase(),
+                change: Some(HeaderChange::Clear),
+            }),
+            ["headers", subdomain, "remove", name] => Ok(Self::Headers {
+                subdomain: subdomain.to_lowercase(),
+                change: Some(HeaderChange::Remove(name.to_string())),
+            }),
+            ["headers", subdomain, "set", name, value @ ..] if !value.is_empty() => Ok(Self::Headers {
+                subdomain: subdomain.to_lowercase(),
+                change: Some(HeaderChange::Set {
+                    name: name.to_string(),
+                    value: value.join(" "),
+                }),
+            }),
+            ["speedtest"] => Ok(Self::SpeedTest),
+            [] => Err(format!("No command given. {}", USAGE)),
+            [name, ..] => Err(format!("Invalid command '{}'. {}", name, USAGE)),
+        }
+    }
+
+    /// What a destructive command is about to do, for its confirmation
+    /// prompt (None = runs right away)
+    pub fn confirmation_action(&self) -> Option<String> {
+        match self {
+            Self::Close(subdomain) => Some(format!("close tunnel '{}' and disconnect its session", subdomain)),
+            Self::RotateSecret(subdomain) => Some(format!(
+                "replace the share secret of '{}' and invalidate its current share links",
+                subdomain
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Text written to the exec channel and the exit status reported to the client
+#[derive(Debug)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_status: u32,
+}
+
+impl ExecOutput {
+    fn ok(stdout: String) -> Self {
+        Self {
+            stdout,
+            stderr: String::new(),
+            exit_status: 0,
+        }
+    }
+
+    fn json(value: serde_json::Value) -> Self {
+        Self::ok(format!("{}\n", value))
+    }
+
+    pub(super) fn error(message: &str) -> Self {
+        Self {
+            stdout: String::new(),
+            stderr: format!("error: {}\n", message),
+            exit_status: 1,
+        }
+    }
+}
+
+/// JSON description of a tunnel
+fn tunnel_json(tunnel: &TunnelInfo) -> serde_json::Value {
+    let created_at: DateTime<Utc> = tunnel.created_at.into();
+    json!({
+        "subdomain": tunnel.subdomain,
+        "url": get_tunnel_url(&tunnel.subdomain),
+        "connected": tunnel.is_connected,
+        "local_port": tunnel.requested_port,
+        "created_at": created_at.to_rfc3339(),
+        "node_id": tunnel.node_id,
+        "protected": tunnel.share_secret.is_some(),
+        "profile": tunnel.perf_profile,
+    })
+}
+
+/// JSON response status counts, alert and throughput of a tunnel
+fn stats_json(tunnel: &TunnelInfo, bandwidth: BandwidthUsage) -> serde_json::Value {
+    let traffic = tunnel.traffic.snapshot();
+    json!({
+        "subdomain": tunnel.subdomain,
+        "statuses": traffic.statuses,
+        "close_reasons": traffic.close_reasons,
+        "alert": tunnel.status_alert.as_ref().map(|alert| json!({
+            "threshold_percent": alert.threshold_percent,
+            "window_secs": alert.window.as_secs(),
+            "firing": alert.firing,
+        })),
+        "bandwidth": bandwidth,
+    })
+}
+
+impl SshHandler {
+    /// Run an exec command on behalf of the authenticated user
+    pub(super) async fn run_exec(&self, command: &str) -> ExecOutput {
+        let command = match ExecCommand::parse(command) {
+            Ok(c) => c,
+            Err(e) => return ExecOutput::error(&e),
+        };
+
+        let (user_id, display_name) = match self.get_verification_status().await {
+            VerificationStatus::Verified {
+                user_id,
+                display_name,
+            } => (user_id, display_name),
+            _ => {
+                return ExecOutput::error(
+                    "This key is not activated. Connect once with -R to activate it.",
+                )
+            }
+        };
+
+        info!("Exec command {:?} for user {}", command, user_id);
+        match command {
+            ExecCommand::Status => {
+                let tunnels = self.state.user_tunnels(&user_id).await;
+                ExecOutput::json(json!({
+                    "user_id": user_id,
+                    "display_name": display_name,
+                    "node_id": get_config().node_id,
+                    "connected_tunnels": tunnels.iter().filter(|t| t.is_connected).count(),
+                }))
+            }
+            ExecCommand::List => {
+                let tunnels: Vec<_> = self.state.user_tunnels(&user_id).await.iter().map(|t| tunnel_json(t)).collect();
+                ExecOutput::json(json!({ "tunnels": tunnels }))
+            }
+            ExecCommand::History => {
+                let entries = self.state.history.for_user(&user_id).await;
+                ExecOutput::ok(terminal_ui::create_history_table(&entries))
+            }
+            ExecCommand::Rename { current, new } => self.exec_rename(&user_id, current, &new).await,
+            ExecCommand::Close(subdomain) => self.exec_close(&user_id, &subdomain).await,
+            ExecCommand::RotateSecret(subdomain) => self.exec_rotate_secret(&user_id, &subdomain).await,
+            ExecCommand::Stats(subdomain) => match self.owned_tunnel(&user_id, &subdomain).await {
+                Ok(tunnel) => ExecOutput::json(stats_json(&tunnel, self.state.bandwidth.usage(&subdomain))),
+                Err(e) => ExecOutput::error(&e),
+            },
+            ExecCommand::Alert {
+                subdomain,
+                threshold_percent,
+                window,
+            } => {
+                self.exec_set_alert(&user_id, &subdomain, Some(StatusAlert::new(threshold_percent, window)))
+                    .await
+            }
+            ExecCommand::ClearAlert(subdomain) => self.exec_set_alert(&user_id, &subdomain, None).await,
+            ExecCommand::Profile { subdomain, profile } => self.exec_set_profile(&user_id, &subdomain, profile).await,
+            ExecCommand::Headers { subdomain, change } => self.exec_headers(&user_id, &subdomain, change).await,
+            // Started by `start_speedtest`, which needs the channel itself
+            ExecCommand::SpeedTest => ExecOutput::error("speedtest must be the only command on the channel"),
+        }
+    }
+
+    /// Start `speedtest` on the exec channel; it writes the report and closes
+    /// the channel when done.
+    pub(super) async fn start_speedtest(&mut self, channel: ChannelId) -> Result<(), ExecOutput> {
+        if !self.is_verified().await {
+            return Err(ExecOutput::error(
+                "This key is not activated. Connect once with -R to activate it.",
+            ));
+        }
+        let handle = self.session_handle.clone();
+        let open = self.open_channel.take().filter(|c| c.id() == channel);
+        let (Some(handle), Some(open)) = (handle, open) else {
+            return Err(ExecOutput::error("speedtest is not available on this channel"));
+        };
+
+        info!("Starting speed test on channel {:?}", channel);
+        let (upload_tx, upload_rx) = mpsc::unbounded_channel();
+        self.speedtest_upload = Some((channel, upload_tx));
+        let ui = self.shared_state.lock().await.ui();
+        tokio::spawn(run_speedtest(handle, open, upload_rx, ui));
+        Ok(())
+    }
+
+    /// Hold back a destructive command until its confirmation code is typed.
+    /// Returns the prompt for stderr, or None if the command runs right away.
+    pub(super) async fn request_confirmation(&self, channel: ChannelId, command: &str) -> Option<String> {
+        let action = ExecCommand::parse(command).ok()?.confirmation_action()?;
+        // Unverified keys get the usual error from run_exec
+        if !self.is_verified().await {
+            return None;
+        }
+        let handle = self.session_handle.clone()?;
+
+        let pending = PendingConfirmation::new(command);
+        let prompt = pending.prompt(&action);
+        let code = pending.code.clone();
+        info!("Waiting for confirmation of '{}' on channel {:?}", command, channel);
+        self.shared_state.lock().await.pending_confirmation = Some((channel, pending));
+        spawn_confirmation_timeout(self.shared_state.clone(), handle, channel, code);
+        Some(prompt)
+    }
+
+    /// Pass input typed on an exec channel to its pending confirmation.
+    /// None if the channel isn't waiting for one.
+    pub(super) async fn confirmation_input(&self, channel: ChannelId, data: &[u8]) -> Option<Confirmation> {
+        let mut shared = self.shared_state.lock().await;
+        let (id, pending) = shared.pending_confirmation.as_mut()?;
+        if *id != channel {
+            return None;
+        }
+        let confirmation = pending.feed(data);
+        if confirmation != Confirmation::Waiting {
+            shared.pending_confirmation = None;
+        }
+        Some(confirmation)
+    }
+
+    /// Drop the channel's pending confirmation; true if there was one
+    pub(super) async fn cancel_confirmation(&self, channel: ChannelId) -> bool {
+        let mut shared = self.shared_state.lock().await;
+        if shared.pending_confirmation.as_ref().is_some_and(|(id, _)| *id == channel) {
+            shared.pending_confirmation = None;
+            return true;
+        }
+        false
+    }
+
+    /// Find a connected tunnel owned by the user
+    async fn owned_tunnel(&self, user_id: &str, subdomain: &str) -> Result<Arc<TunnelInfo>, String> {
+        match self.state.get_tunnel(subdomain).await {
+            Some(t) if t.username == user_id && t.is_connected => Ok(t),
+            _ => Err(format!("You have no connected tunnel '{}'", subdomain)),
+        }
+    }
+
+    async fn exec_rename(&self, user_id: &str, current: Option<String>, new: &str) -> ExecOutput {
+        match self.rename_owned_tunnel(user_id, current, new).await {
+            Ok((current, tunnel)) => ExecOutput::json(json!({
+                "renamed": current,
+                "tunnel": tunnel_json(&tunnel),
+            })),
+            Err(e) => ExecOutput::error(&e),
+        }
+    }
+
+    /// Move one of the user's tunnels to a new subdomain (also used by the
+    /// control channel); returns the old subdomain and the moved tunnel
+    pub(super) async fn rename_owned_tunnel(
+        &self,
+        user_id: &str,
+        current: Option<String>,
+        new: &str,
+    ) -> Result<(String, Arc<TunnelInfo>), String> {
+        if validate_subdomain(new) != SubdomainValidation::Valid {
+            return Err(format!("'{}' is not a valid subdomain", new));
+        }
+
+        let current = match current {
+            Some(c) => c,
+            None => {
+                let connected: Vec<_> = self
+                    .state
+                    .user_tunnels(user_id)
+                    .await
+                    .into_iter()
+                    .filter(|t| t.is_connected)
+                    .collect();
+                match connected.as_slice() {
+                    [only] => only.subdomain.clone(),
+                    [] => return Err("You have no connected tunnels".to_string()),
+                    _ => {
+                        return Err("You have several tunnels; use: rename <subdomain> <new-subdomain>".to_string())
+                    }
+                }
+            }
+        };
+
+        match self.owned_tunnel(user_id, &current).await {
+            Ok(t) if !t.capabilities.allows(Capability::CustomSubdomain) => {
+                return Err(terminal_ui::capability_denied_reason(Capability::CustomSubdomain))
+            }
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+
+        let tunnel = self.move_tunnel(user_id, &current, new).await?;
+        Ok((current, tunnel))
+    }
+
+    /// Move a tunnel to `new` and tell whoever shows its URL: the web
+    /// dashboard, the session holding it and its control channel
+    pub(super) async fn move_tunnel(&self, user_id: &str, current: &str, new: &str) -> Result<Arc<TunnelInfo>, String> {
+        let tunnel = self.state.rename_tunnel(current, new).await.map_err(|e| e.to_string())?;
+        self.state.rename_verified_key_subdomain(current, new).await;
+
+        // Keep the web dashboard in sync
+        if let Err(e) = self.device_flow_client.unregister_tunnel(current).await {
+            warn!("Failed to unregister renamed tunnel from web server: {}", e);
+        }
+        let register_req = RegisterTunnelRequest {
+            subdomain: tunnel.subdomain.clone(),
+            correlation_id: tunnel.correlation_id.clone(),
+            user_id: user_id.to_string(),
+            session_id: tunnel.session_id.clone(),
+            requested_address: tunnel.requested_address.clone(),
+            requested_port: tunnel.requested_port,
+            server_port: tunnel.server_port,
+            client_ip: tunnel.client_ip.clone(),
+        };
+        if let Err(e) = self.device_flow_client.register_tunnel(&register_req).await {
+            warn!("Failed to register renamed tunnel with web server: {}", e);
+        }
+
+        // Tell the session holding the tunnel about its new URL
+        if let Some(channel_id) = tunnel.session_channel_id {
+            let notice = match tunnel.output_mode {
+                OutputMode::Tty => terminal_ui::create_renamed_notice(tunnel.ui.lang, current, new),
+                mode => SessionEvent::Renamed { from: current, to: new }.render(mode),
+            };
+            let _ = tunnel.handle.data(channel_id, notice.into_bytes().into()).await;
+        }
+        let renamed = ServerMessage::Renamed {
+            from: current.to_string(),
+            to: tunnel.subdomain.clone(),
+            url: get_tunnel_url(&tunnel.subdomain),
+        };
+        control::push(&tunnel.handle, tunnel.control_channel_id, &renamed).await;
+
+        Ok(tunnel)
+    }
+
+    async fn exec_rotate_secret(&self, user_id: &str, subdomain: &str) -> ExecOutput {
+        if let Err(e) = self.owned_tunnel(user_id, subdomain).await {
+            return ExecOutput::error(&e);
+        }
+
+        let secret = generate_share_secret();
+        if let Err(e) = self.state.set_share_secret(subdomain, secret.clone()).await {
+            return ExecOutput::error(&e.to_string());
+        }
+
+        let url = get_tunnel_url(subdomain);
+        ExecOutput::json(json!({
+            "subdomain": subdomain,
+            "password": secret,
+            "share_url": format!("{}/?{}={}", url, SHARE_SECRET_PARAM, secret),
+        }))
+    }
+
+    async fn exec_set_alert(&self, user_id: &str, subdomain: &str, alert: Option<StatusAlert>) -> ExecOutput {
+        if let Err(e) = self.owned_tunnel(user_id, subdomain).await {
+            return ExecOutput::error(&e);
+        }
+        if let Err(e) = self.state.set_status_alert(subdomain, alert).await {
+            return ExecOutput::error(&e.to_string());
+        }
+        match self.owned_tunnel(user_id, subdomain).await {
+            Ok(tunnel) => ExecOutput::json(stats_json(&tunnel, self.state.bandwidth.usage(subdomain))),
+            Err(e) => ExecOutput::error(&e),
+        }
+    }
+
+    async fn exec_set_profile(&self, user_id: &str, subdomain: &str, profile: Option<PerfProfile>) -> ExecOutput {
+        match self.set_owned_profile(user_id, subdomain, profile).await {
+            Ok(tunnel) => ExecOutput::json(tunnel_json(&tunnel)),
+            Err(e) => ExecOutput::error(&e),
+        }
+    }
+
+    /// Change the profile of one of the user's tunnels (also used by the
+    /// control channel), telling the session holding it
+    pub(super) async fn set_owned_profile(
+        &self,
+        user_id: &str,
+        subdomain: &str,
+        profile: Option<PerfProfile>,
+    ) -> Result<Arc<TunnelInfo>, String> {
+        self.owned_tunnel(user_id, subdomain).await?;
+        self.state
+            .set_perf_profile(subdomain, profile)
+            .await
+            .map_err(|e| e.to_string())?;
+        let tunnel = self.owned_tunnel(user_id, subdomain).await?;
+        let config = ServerMessage::Config {
+            subdomain: tunnel.subdomain.clone(),
+            profile: tunnel.perf_profile,
+        };
+        control::push(&tunnel.handle, tunnel.control_channel_id, &config).await;
+        Ok(tunnel)
+    }
+
+    async fn exec_headers(&self, user_id: &str, subdomain: &str, change: Option<HeaderChange>) -> ExecOutput {
+        let tunnel = match self.owned_tunnel(user_id, subdomain).await {
+            Ok(tunnel) => tunnel,
+            Err(e) => return ExecOutput::error(&e),
+        };
+        let mut rules = tunnel.response_headers.clone();
+        if let Some(change) = change {
+            if let Err(e) = change.apply(&mut rules) {
+                return ExecOutput::error(&e);
+            }
+            if let Err(e) = self.state.set_response_headers(subdomain, rules.clone()).await {
+                return ExecOutput::error(&e.to_string());
+            }
+        }
+        ExecOutput::json(json!({
+            "subdomain": subdomain,
+            "headers": rules,
+        }))
+    }
+
+    async fn exec_close(&self, user_id: &str, subdomain: &str) -> ExecOutput {
+        if let Err(e) = self.owned_tunnel(user_id, subdomain).await {
+            return ExecOutput::error(&e);
+        }
+
+        let tunnel = match self.state.remove_tunnel(subdomain).await {
+            Ok(t) => t,
+            Err(e) => return ExecOutput::error(&e.to_string()),
+        };
+        if let Err(e) = self.device_flow_client.unregister_tunnel(subdomain).await {
+            warn!("Failed to unregister closed tunnel from web server: {}", e);
+        }
+
+        let handle = tunnel.handle.clone();
+        self.state.cleanup.spawn("close", async move {
+            let _ = handle
+                .disconnect(
+                    Disconnect::ByApplication,
+                    "Tunnel closed by its owner".to_string(),
+                    "en".to_string(),
+                )
+                .await;
+        });
+
+        ExecOutput::json(json!({ "closed": subdomain }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_exec_command() {
+        assert_eq!(ExecCommand::parse("history"), Ok(ExecCommand::History));
+        assert_eq!(ExecCommand::parse("  status  "), Ok(ExecCommand::Status));
+        assert_eq!(ExecCommand::parse("list"), Ok(ExecCommand::List));
+        assert_eq!(ExecCommand::parse("speedtest"), Ok(ExecCommand::SpeedTest));
+        assert!(ExecCommand::parse("").is_err());
+        assert!(ExecCommand::parse("rm -rf /").is_err());
+        assert!(ExecCommand::parse("list extra").is_err());
+    }
+
+    #[test]
+    fn test_parse_rename_and_close() {
+        assert_eq!(
+            ExecCommand::parse("rename MySub"),
+            Ok(ExecCommand::Rename {
+                current: None,
+                new: "mysub".to_string()
+            })
+        );
+        assert_eq!(
+            ExecCommand::parse("rename old new"),
+            Ok(ExecCommand::Rename {
+                current: Some("old".to_string()),
+                new: "new".to_string()
+            })
+        );
+        assert_eq!(
+            ExecCommand::parse("close mysub"),
+            Ok(ExecCommand::Close("mysub".to_string()))
+        );
+        assert!(ExecCommand::parse("close").is_err());
+        assert_eq!(
+            ExecCommand::parse("rotate-secret MySub"),
+            Ok(ExecCommand::RotateSecret("mysub".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_stats_and_alert() {
+        assert_eq!(ExecCommand::parse("stats MyApp"), Ok(ExecCommand::Stats("myapp".to_string())));
+        assert_eq!(
+            ExecCommand::parse("alert myapp 10% 5m"),
+            Ok(ExecCommand::Alert {
+                subdomain: "myapp".to_string(),
+                threshold_percent: 10.0,
+                window: Duration::from_secs(300),
+            })
+        );
+        assert_eq!(ExecCommand::parse("alert myapp off"), Ok(ExecCommand::ClearAlert("myapp".to_string())));
+        assert!(ExecCommand::parse("alert myapp 0 5m").is_err());
+        assert!(ExecCommand::parse("alert myapp 10 forever").is_err());
+    }
+    #[test]
+    fn test_parse_profile() {
+        assert_eq!(
+            ExecCommand::parse("profile MyApp Streaming"),
+            Ok(ExecCommand::Profile {
+                subdomain: "myapp".to_string(),
+                profile: Some(PerfProfile::Streaming),
+            })
+        );
+        assert_eq!(
+            ExecCommand::parse("profile myapp default"),
+            Ok(ExecCommand::Profile {
+                subdomain: "myapp".to_string(),
+                profile: None,
+            })
+        );
+        assert!(ExecCommand::parse("profile myapp turbo").is_err());
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        assert_eq!(
+            ExecCommand::parse("headers MyApp set Access-Control-Allow-Origin https://a.example, https://b.example"),
+            Ok(ExecCommand::Headers {
+                subdomain: "myapp".to_string(),
+                change: Some(HeaderChange::Set {
+                    name: "Access-Control-Allow-Origin".to_string(),
+                    value: "https://a.example, https://b.example".to_string(),
+                }),
+            })
+        );
+        assert_eq!(
+            ExecCommand::parse("headers myapp remove Server"),
+            Ok(ExecCommand::Headers {
+                subdomain: "myapp".to_string(),
+                change: Some(HeaderChange::Remove("Server".to_string())),
+            })
+        );
+        assert!(ExecCommand::parse("headers myapp set X-Robots-Tag").is_err());
+    }
+}
@@ -2,27 +2,48 @@
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use log::{debug, error, info, warn};
-use russh::server::Handle;
-use russh::ChannelId;
+use russh::server::{Handle, Msg, Session};
+use russh::{Channel, ChannelId, Disconnect};
 use tokio::sync::{oneshot, Mutex};
 
-use crate::device::{generate_activation_code, DeviceFlowClient};
+use crate::config::get_tunnel_url;
+use crate::device::{generate_activation_code, AuthProvider};
 use crate::error::TunnelError;
+use crate::grant::{self, GRANT_USER_PREFIX};
+use crate::state::hooks::{VerificationEvent, VerificationMethod};
+use crate::state::perf_profiles::split_username;
 use crate::state::AppState;
-use crate::terminal_ui;
+use crate::terminal_ui::i18n::split_lang;
+use crate::terminal_ui::{self, ActiveTunnelSummary, OutputMode, SessionEvent, Ui};
 
+use super::control::FrameDecoder;
+use super::live_view::spawn_live_view;
+use super::menu::KeyDecoder;
+use super::speedtest::UploadSender;
 use super::tunnel::{create_tunnel, CreateTunnelResult};
 use super::types::{
-    generate_secure_subdomain_id, generate_session_id, SharedHandlerState, VerificationStatus,
+    generate_session_id, validate_subdomain, SharedHandlerState, SubdomainValidation, VerificationStatus,
 };
 use super::verification::spawn_verification_polling;
 
+/// How long a session awaiting activation may go without a session channel
+const CHANNEL_LESS_ACTIVATION_GRACE: Duration = Duration::from_secs(15);
+
+/// Disconnect reason for a session that can't be shown its activation code
+const CHANNEL_LESS_ACTIVATION_MESSAGE: &str = "This key needs activation, which needs a session: \
+connect once without -N/-T (or as token-<API token>@host), then -N works";
+
+/// User every session belongs to while `TUNNEL_SKIP_AUTH` is active
+#[cfg(feature = "dangerous-dev-auth")]
+const DEV_USER_ID: &str = "dev-user";
+
 /// Handler for a single SSH connection.
 pub struct SshHandler {
     pub(super) state: Arc<AppState>,
-    pub(super) device_flow_client: Arc<DeviceFlowClient>,
+    pub(super) device_flow_client: Arc<dyn AuthProvider>,
     pub(super) peer_addr: Option<SocketAddr>,
     pub(super) username: Option<String>,
     pub(super) session_handle: Option<Handle>,
@@ -31,16 +52,31 @@ pub struct SshHandler {
     pub(super) poll_cancel: Option<oneshot::Sender<()>>,
     pub(super) shared_state: Arc<Mutex<SharedHandlerState>>,
     pub(super) public_key_fingerprint: Option<String>,
+    /// Most recently opened session channel, until its shell or exec request
+    /// (a speed test takes it over)
+    pub(super) open_channel: Option<Channel<Msg>>,
+    /// Channel running `speedtest` and where its upload data goes
+    pub(super) speedtest_upload: Option<(ChannelId, UploadSender)>,
+    /// Session channel replaced by the most recently opened one (restored if
+    /// that one becomes the control channel)
+    pub(super) previous_session_channel_id: Option<ChannelId>,
+    /// Control channel and its pending input
+    pub(super) control: Option<(ChannelId, FrameDecoder)>,
+    /// Keys typed in the session, for the keyboard menu
+    pub(super) keys: KeyDecoder,
 }
 
 impl SshHandler {
     pub fn new(
         state: Arc<AppState>,
-        device_flow_client: Arc<DeviceFlowClient>,
+        device_flow_client: Arc<dyn AuthProvider>,
         peer_addr: Option<SocketAddr>,
     ) -> Self {
         let session_id = generate_session_id();
-        let shared_state = Arc::new(Mutex::new(SharedHandlerState::new()));
+        let shared_state = Arc::new(Mutex::new(SharedHandlerState {
+            session_id: session_id.clone(),
+            ..SharedHandlerState::new()
+        }));
         Self {
             state,
             device_flow_client,
@@ -52,37 +88,37 @@ impl SshHandler {
             poll_cancel: None,
             shared_state,
             public_key_fingerprint: None,
+            open_channel: None,
+            speedtest_upload: None,
+            previous_session_channel_id: None,
+            control: None,
+            keys: KeyDecoder::default(),
         }
     }
 
+    /// Draw a random subdomain from the pre-generated pool
     pub(super) async fn generate_subdomain(&self) -> String {
-        let mut state = self.shared_state.lock().await;
-        state.subdomain_counter += 1;
-        let random_id = generate_secure_subdomain_id();
-        format!("tunnel-{}-{}", random_id, state.subdomain_counter)
+        self.shared_state.lock().await.subdomain_counter += 1;
+        self.state.subdomain_pool.take()
     }
 
-    pub(super) async fn send_tunnel_message(&self, port: u32) {
-        let (display_name, tunnels) = {
-            let state = self.shared_state.lock().await;
-            let display_name = match &state.verification_status {
-                VerificationStatus::Verified { display_name, .. } => display_name.clone(),
-                _ => "unknown".to_string(),
-            };
-            let tunnels: Vec<(String, u32)> = state
-                .registered_subdomains
-                .iter()
-                .map(|s| (s.clone(), port))
-                .collect();
-            (display_name, tunnels)
+    /// Display name and registered tunnels (subdomain, client port) for the success box
+    pub(super) async fn success_box_tunnels(&self) -> (String, Vec<(String, u32)>) {
+        let state = self.shared_state.lock().await;
+        let display_name = match &state.verification_status {
+            VerificationStatus::Verified { display_name, .. } => display_name.clone(),
+            _ => "unknown".to_string(),
         };
+        (display_name, state.registered_tunnels())
+    }
+
+    pub(super) async fn send_tunnel_message(&self) {
+        let (display_name, tunnels) = self.success_box_tunnels().await;
 
         if tunnels.is_empty() {
             return;
         }
 
-        let message = terminal_ui::create_success_box(&display_name, &tunnels);
-
         info!(
             "send_tunnel_message: session_handle={}, session_channel_id={:?}",
             self.session_handle.is_some(),
@@ -90,6 +126,14 @@ impl SshHandler {
         );
 
         if let (Some(handle), Some(channel_id)) = (&self.session_handle, self.session_channel_id) {
+            let copy = self.auto_copy(&tunnels).await;
+            if !copy.is_empty() {
+                let _ = handle.data(channel_id, copy.into_bytes().into()).await;
+            }
+            if self.show_live_view(handle, channel_id).await {
+                return;
+            }
+            let message = self.tunnel_message(&display_name, &tunnels).await;
             info!("Sending tunnel message to channel {:?}", channel_id);
             if let Err(e) = handle
                 .data(channel_id, message.into_bytes().into())
@@ -105,19 +149,229 @@ impl SshHandler {
         // Session channel not ready yet, save for later
         {
             let mut state = self.shared_state.lock().await;
-            state.pending_tunnel_port = Some(port);
-            info!(
-                "Session channel not ready, deferring tunnel message (port={})",
-                port
-            );
+            state.tunnel_message_pending = Some(Instant::now());
+            info!("Session channel not ready, deferring tunnel message");
         }
     }
 
-    pub(super) async fn cleanup_tunnels(&self) {
-        let subdomains: Vec<String> = {
-            let state = self.shared_state.lock().await;
-            state.registered_subdomains.clone()
+    /// Write a refusal or error notice to the session channel, or keep its
+    /// reason until one opens (`ssh -N` sessions may never open one)
+    pub(super) async fn send_notice(&self, session: &mut Session, notice: String, reason: &str) {
+        match self.session_channel_id {
+            Some(channel) => {
+                let _ = session.data(channel, notice.into_bytes().into());
+            }
+            None => {
+                info!("No session channel, queueing notice: {}", reason);
+                self.shared_state.lock().await.queue_notice(reason, Instant::now());
+            }
+        }
+    }
+
+    /// Queued notices, rendered for the session's output mode
+    pub(super) async fn pending_notices(&self, mode: OutputMode) -> String {
+        let (reasons, ui) = {
+            let mut shared = self.shared_state.lock().await;
+            (shared.take_pending_notices(Instant::now()), shared.ui())
+        };
+        reasons
+            .iter()
+            .map(|reason| match mode {
+                OutputMode::Tty => terminal_ui::create_error_box(ui, reason, None, 0),
+                mode => SessionEvent::Error { reason, code: None }.render(mode),
+            })
+            .collect()
+    }
+
+    /// Disconnect with an explanation if the session still has no session
+    /// channel (and so no way to show the activation code) after a grace
+    /// period, instead of leaving it waiting until the code expires
+    pub(super) fn watch_channel_less_activation(&self) {
+        let Some(handle) = self.session_handle.clone() else {
+            return;
+        };
+        let shared_state = self.shared_state.clone();
+        let session_id = self.session_id.clone();
+        self.state.cleanup.spawn("channel_less_activation", async move {
+            tokio::time::sleep(CHANNEL_LESS_ACTIVATION_GRACE).await;
+            let waiting = {
+                let state = shared_state.lock().await;
+                state.session_channel_id.is_none()
+                    && matches!(state.verification_status, VerificationStatus::Pending { .. })
+            };
+            if waiting {
+                info!("Session {} awaits activation without a session channel, disconnecting", session_id);
+                let _ = handle
+                    .disconnect(
+                        Disconnect::ByApplication,
+                        CHANNEL_LESS_ACTIVATION_MESSAGE.to_string(),
+                        "en".to_string(),
+                    )
+                    .await;
+            }
+        });
+    }
+
+    /// How notices are written to this session
+    pub(super) async fn output_mode(&self) -> OutputMode {
+        self.shared_state.lock().await.output_mode()
+    }
+
+    /// How boxes are drawn for this session's terminal
+    pub(super) async fn ui(&self) -> Ui {
+        self.shared_state.lock().await.ui()
+    }
+
+    /// OSC 52 sequence copying the URLs of tunnels that just came up, if
+    /// `OSC52_COPY` is on (terminal only)
+    pub(super) async fn auto_copy(&self, tunnels: &[(String, u32)]) -> String {
+        if !crate::config::get().osc52_copy || self.output_mode().await != OutputMode::Tty {
+            return String::new();
+        }
+        terminal_ui::create_clipboard_copy(&terminal_ui::clipboard_urls(tunnels))
+    }
+
+    /// Success box (or ready lines without a terminal) plus the user's other
+    /// tunnels and any unseen MOTD
+    pub(super) async fn tunnel_message(&self, display_name: &str, tunnels: &[(String, u32)]) -> String {
+        let mut message = match self.output_mode().await {
+            OutputMode::Tty => terminal_ui::create_success_box(self.ui().await, display_name, tunnels),
+            mode => SessionEvent::Ready { tunnels }.render(mode),
         };
+        self.append_takeovers(&mut message).await;
+        self.append_secret_paths(&mut message, tunnels).await;
+        self.append_tunnel_summary(&mut message).await;
+        self.append_motd(&mut message).await;
+        self.append_resume_token(&mut message).await;
+        message
+    }
+
+    /// Show the success box with the live request panel (or redraw it if it
+    /// is already up). Returns false if the client turned the live view off
+    /// or has no terminal.
+    pub(super) async fn show_live_view(&self, handle: &Handle, channel_id: ChannelId) -> bool {
+        {
+            let shared = self.shared_state.lock().await;
+            if !shared.live_view_enabled || shared.output_mode() != OutputMode::Tty {
+                return false;
+            }
+            if let Some(view) = &shared.live_view {
+                view.repaint();
+                return true;
+            }
+        }
+
+        let (display_name, tunnels) = self.success_box_tunnels().await;
+        let mut motd = String::new();
+        self.append_takeovers(&mut motd).await;
+        self.append_secret_paths(&mut motd, &tunnels).await;
+        self.append_tunnel_summary(&mut motd).await;
+        self.append_motd(&mut motd).await;
+        let view = spawn_live_view(
+            self.state.clone(),
+            self.shared_state.clone(),
+            handle.clone(),
+            channel_id,
+            self.session_id.clone(),
+            display_name,
+            motd,
+        );
+        info!("Live request view started for session {}", self.session_id);
+        if let Some(previous) = self.shared_state.lock().await.live_view.replace(view) {
+            previous.stop();
+        }
+        true
+    }
+
+    /// Build the activation box for the session's terminal, remembering its height
+    /// Activation box, or an activation line without a terminal
+    pub(super) async fn activation_box(&self, code: &str, url: &str) -> String {
+        let mut shared = self.shared_state.lock().await;
+        let mode = shared.output_mode();
+        if mode != OutputMode::Tty {
+            return SessionEvent::Activation { code, url }.render(mode);
+        }
+        shared.activation_box_lines = terminal_ui::activation_box_lines(shared.ui(), url);
+        terminal_ui::create_activation_box(shared.ui(), code, url)
+    }
+
+    /// What to draw again when the screen is stale (after a resize, or for
+    /// the keyboard menu): the activation box while it waits, else the
+    /// success box. None without boxes, or while the live view (which
+    /// repaints itself) or the ESC hint is up.
+    pub(super) async fn redrawn_screen(&self) -> Option<String> {
+        {
+            let shared = self.shared_state.lock().await;
+            if shared.output_mode() != OutputMode::Tty || shared.esc_pressed || shared.live_view.is_some() {
+                return None;
+            }
+        }
+        match self.get_verification_status().await {
+            VerificationStatus::Pending { code } => {
+                let url = self.device_flow_client.get_activation_url(&code);
+                Some(self.activation_box(&code, &url).await)
+            }
+            VerificationStatus::Verified { .. } => {
+                let (display_name, tunnels) = self.success_box_tunnels().await;
+                if tunnels.is_empty() {
+                    return None;
+                }
+                Some(self.tunnel_message(&display_name, &tunnels).await)
+            }
+            VerificationStatus::NotStarted | VerificationStatus::Failed { .. } => None,
+        }
+    }
+
+    /// Append the box listing all of the verified user's connected tunnels,
+    /// if some are held by other sessions (terminal only)
+    pub(super) async fn append_tunnel_summary(&self, message: &mut String) {
+        let user_id = match self.get_verification_status().await {
+            VerificationStatus::Verified { user_id, .. } => user_id,
+            _ => return,
+        };
+        if self.output_mode().await != OutputMode::Tty {
+            return;
+        }
+        let tunnels: Vec<_> = self
+            .state
+            .user_tunnels(&user_id)
+            .await
+            .into_iter()
+            .filter(|t| t.is_connected)
+            .collect();
+        if tunnels.iter().all(|t| t.session_id == self.session_id) {
+            return;
+        }
+        let now = SystemTime::now();
+        let summary: Vec<_> = tunnels
+            .into_iter()
+            .map(|t| ActiveTunnelSummary {
+                url: get_tunnel_url(&t.subdomain),
+                this_session: t.session_id == self.session_id,
+                client_ip: t.client_ip.clone(),
+                connected_for: now.duration_since(t.created_at).unwrap_or_default(),
+            })
+            .collect();
+        message.push_str(&terminal_ui::create_active_tunnels_box(self.ui().await, &summary));
+    }
+
+    /// Append the operator MOTD if the verified user hasn't seen it yet
+    pub(super) async fn append_motd(&self, message: &mut String) {
+        let user_id = match self.get_verification_status().await {
+            VerificationStatus::Verified { user_id, .. } => user_id,
+            _ => return,
+        };
+        if let Some(motd) = self.state.motd.take_for_user(&user_id).await {
+            info!("Showing MOTD to user {}", user_id);
+            match self.output_mode().await {
+                OutputMode::Tty => message.push_str(&terminal_ui::create_motd_box(self.ui().await, &motd)),
+                mode => message.push_str(&SessionEvent::Message { text: &motd }.render(mode)),
+            }
+        }
+    }
+
+    pub(super) async fn cleanup_tunnels(&self) {
+        let subdomains = session_subdomains(&self.state, &self.shared_state).await;
         for subdomain in &subdomains {
             // Mark tunnel as disconnected instead of removing it
             // This allows the dashboard to show the correct status while keeping
@@ -137,6 +391,149 @@ impl SshHandler {
             .clear();
     }
 
+    /// Count abuse from the peer towards an automatic ban
+    pub(super) async fn strike(&self, reason: &str) {
+        if let Some(peer) = self.peer_addr {
+            self.state.bans.strike(peer.ip(), reason).await;
+        }
+    }
+
+    /// Use the SSH username as the explicit subdomain (disconnect on
+    /// conflict); "." means a random one. A `+<profile>` suffix selects the
+    /// performance profile. Returns false, after a strike, if the username is
+    /// not a valid subdomain or names an unknown profile.
+    pub(super) async fn request_subdomain_from_username(&self, user: &str) -> bool {
+        let user = match split_lang(user) {
+            Ok((name, lang)) => {
+                if let Some(lang) = lang {
+                    info!("Username selects language {:?}", lang);
+                    self.shared_state.lock().await.requested_lang = Some(lang);
+                }
+                name
+            }
+            Err(e) => {
+                warn!("{}", e);
+                self.strike("rejected SSH auth").await;
+                return false;
+            }
+        };
+        let user = match split_username(user) {
+            Ok((name, profile)) => {
+                if let Some(profile) = profile {
+                    info!("Username selects the {} profile", profile);
+                    self.shared_state.lock().await.perf_profile = Some(profile);
+                }
+                name
+            }
+            Err(e) => {
+                warn!("{}", e);
+                self.strike("rejected SSH auth").await;
+                return false;
+            }
+        };
+
+        if let Some(grant) = user.strip_prefix(GRANT_USER_PREFIX) {
+            match grant::redeem(&self.state, grant).await {
+                Ok(claim) => {
+                    info!("Grant redeemed for subdomain '{}' (user {})", claim.subdomain, claim.user_id);
+                    self.shared_state.lock().await.requested_subdomain = Some(claim.subdomain);
+                    return true;
+                }
+                Err(e) => {
+                    warn!("Rejected grant in username: {}", e);
+                    self.strike("rejected SSH auth").await;
+                    return false;
+                }
+            }
+        }
+
+        if user == "." {
+            info!("Username is '.', will use random subdomain");
+            return true;
+        }
+
+        let subdomain = user.to_lowercase();
+        match validate_subdomain(&subdomain) {
+            SubdomainValidation::Valid => {
+                let mut state = self.shared_state.lock().await;
+                state.requested_subdomain = Some(subdomain.clone());
+                info!("Username set as explicit subdomain: {}", subdomain);
+                return true;
+            }
+            SubdomainValidation::TooLong => {
+                warn!("Username '{}' is too long for subdomain (max 63 chars)", user);
+            }
+            SubdomainValidation::TooShort => {
+                warn!("Username '{}' is too short for subdomain", user);
+            }
+            SubdomainValidation::InvalidCharacters => {
+                warn!("Username '{}' contains invalid characters for subdomain", user);
+            }
+            SubdomainValidation::StartsWithHyphen | SubdomainValidation::EndsWithHyphen => {
+                warn!("Username '{}' cannot start or end with hyphen", user);
+            }
+        }
+        self.strike("rejected SSH auth").await;
+        false
+    }
+
+    /// Tell the registered hooks how a verification attempt ended
+    pub(super) fn report_verification(&self, method: VerificationMethod, result: Result<String, String>) {
+        self.state.hooks.verification(&VerificationEvent {
+            method,
+            session_id: self.session_id.clone(),
+            peer_addr: self.peer_addr,
+            key_fingerprint: self.public_key_fingerprint.clone(),
+            result,
+        });
+    }
+
+    /// Verify the session with an API token instead of the Device Flow
+    pub(super) async fn verify_api_token(&self, token: &str) -> bool {
+        match self.device_flow_client.verify_token(token).await {
+            Ok(Some(user)) => {
+                info!("API token accepted for user '{}', skipping Device Flow", user.user_id);
+                self.report_verification(VerificationMethod::ApiToken, Ok(user.user_id.clone()));
+                let display_name = user.display_name();
+                let mut state = self.shared_state.lock().await;
+                state.verification_status = VerificationStatus::Verified {
+                    user_id: user.user_id,
+                    display_name,
+                };
+                state.tier = user.tier;
+                true
+            }
+            Ok(None) => {
+                warn!("Rejected invalid API token");
+                self.report_verification(VerificationMethod::ApiToken, Err("invalid API token".to_string()));
+                self.strike("invalid API token").await;
+                false
+            }
+            Err(e) => {
+                warn!("Failed to verify API token: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Verify the session without the Device Flow (`TUNNEL_SKIP_AUTH`)
+    #[cfg(feature = "dangerous-dev-auth")]
+    pub(super) async fn skip_auth(&self, user: &str, fingerprint: &str) {
+        warn!(
+            target: "audit",
+            "AUTH BYPASSED: session {} from {:?} (user '{}', key {}) accepted without Device Flow (TUNNEL_SKIP_AUTH)",
+            self.session_id,
+            self.peer_addr,
+            user,
+            fingerprint
+        );
+        let mut state = self.shared_state.lock().await;
+        state.verification_status = VerificationStatus::Verified {
+            user_id: DEV_USER_ID.to_string(),
+            display_name: "dev (auth skipped)".to_string(),
+        };
+    }
+
     pub(super) async fn is_verified(&self) -> bool {
         let state = self.shared_state.lock().await;
         matches!(
@@ -145,6 +542,14 @@ impl SshHandler {
         )
     }
 
+    /// The maintenance message if a forward for `port` would be a new tunnel
+    /// (reconnects of a verified user's recent tunnels are let through)
+    pub(super) async fn maintenance_refusal(&self, port: u32) -> Option<String> {
+        let notice = self.state.maintenance_mode.current().await?;
+        let reconnect = self.is_verified().await && self.shared_state.lock().await.last_subdomains.contains_key(&port);
+        (!reconnect).then_some(notice.message)
+    }
+
     pub(super) async fn get_verification_status(&self) -> VerificationStatus {
         self.shared_state.lock().await.verification_status.clone()
     }
@@ -153,9 +558,13 @@ impl SshHandler {
         // Check rate limiting atomically
         if let Some(peer) = self.peer_addr {
             let ip = peer.ip();
-            if self.state.check_and_record_device_flow(ip).await {
-                let reason = "Rate limited: too many Device Flow requests. Please wait before trying again.".to_string();
+            let fingerprint = self.public_key_fingerprint.as_deref();
+            if self.state.check_and_record_device_flow(ip, fingerprint).await {
+                let reason =
+                    TunnelError::RateLimited("too many Device Flow requests. Please wait before trying again.".into())
+                        .to_string();
                 warn!("Device Flow rate limited for IP: {}", ip);
+                self.state.bans.strike(ip, "Device Flow rate limit").await;
                 {
                     let mut state = self.shared_state.lock().await;
                     state.verification_status = VerificationStatus::Failed {
@@ -234,6 +643,27 @@ impl SshHandler {
     }
 }
 
+/// Subdomains held by a session: those it registered plus any renamed since,
+/// minus any a resumed session has taken over
+async fn session_subdomains(state: &AppState, shared_state: &Mutex<SharedHandlerState>) -> Vec<String> {
+    let (session_id, registered) = {
+        let shared = shared_state.lock().await;
+        (shared.session_id.clone(), shared.registered_subdomains.clone())
+    };
+    let mut subdomains = Vec::new();
+    for subdomain in registered {
+        if state.get_tunnel(&subdomain).await.is_none_or(|tunnel| tunnel.session_id == session_id) {
+            subdomains.push(subdomain);
+        }
+    }
+    for subdomain in state.session_subdomains(&session_id).await {
+        if !subdomains.contains(&subdomain) {
+            subdomains.push(subdomain);
+        }
+    }
+    subdomains
+}
+
 impl Drop for SshHandler {
     fn drop(&mut self) {
         // Cancel the polling task if it's still running
@@ -247,11 +677,16 @@ impl Drop for SshHandler {
         let device_flow_client = self.device_flow_client.clone();
         
         // Spawn a task to clean up since Drop can't be async
-        tokio::spawn(async move {
-            let subdomains: Vec<String> = {
-                let state = shared_state.lock().await;
-                state.registered_subdomains.clone()
+        self.state.cleanup.spawn("handler_drop", async move {
+            let session_id = {
+                let mut shared = shared_state.lock().await;
+                if let Some(view) = shared.live_view.take() {
+                    view.stop();
+                }
+                shared.session_id.clone()
             };
+            state.dedicated_ports.release_session(&session_id);
+            let subdomains = session_subdomains(&state, &shared_state).await;
             
             if subdomains.is_empty() {
                 return;
@@ -1,17 +1,33 @@
 //! Handler trait implementation for SshHandler.
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use async_trait::async_trait;
 use log::{debug, info, warn};
 use russh::keys::PublicKey;
 use russh::server::{Auth, Handler, Msg, Session};
 use russh::{Channel, ChannelId, Disconnect};
-use russh_keys::HashAlg;
+use russh_keys::{Certificate, HashAlg};
 
+use crate::config::get as get_config;
 use crate::error::TunnelError;
-use crate::terminal_ui;
-
+use crate::state::audit::AuditEvent;
+use crate::state::dedicated_ports::Transport;
+use crate::state::hooks::VerificationMethod;
+use crate::state::resume_tokens::RESUME_USER_PREFIX;
+use crate::terminal_ui::{self, Lang, OutputMode, SessionEvent};
+
+use super::certs::verify_user_certificate;
+use super::control::{push_session_status, CONTROL_SUBSYSTEM};
+use super::confirm::Confirmation;
+use super::exec::{ExecCommand, ExecOutput};
 use super::handler::SshHandler;
-use super::types::{PendingTunnel, VerificationStatus, validate_subdomain, SubdomainValidation};
+use crate::config::PortProbeMode;
+
+use super::types::{
+    redact_username, PendingTunnel, VerificationStatus, LANG_ENV, LIVE_VIEW_ENV, OUTPUT_ENV, PORT_PROBE_ENV,
+    SECRET_PATH_ENV, TOKEN_USER_PREFIX,
+};
 
 #[async_trait]
 impl Handler for SshHandler {
@@ -38,6 +54,13 @@ impl Handler for SshHandler {
             }
 
             self.cleanup_tunnels().await;
+        } else if self.speedtest_upload.as_ref().is_some_and(|(id, _)| *id == channel) {
+            self.speedtest_upload = None;
+        } else if self.control.as_ref().is_some_and(|(id, _)| *id == channel) {
+            info!("Control channel {:?} closed", channel);
+            self.close_control_channel().await;
+        } else if self.cancel_confirmation(channel).await {
+            info!("Exec channel {:?} closed before confirming", channel);
         } else {
             debug!("Forwarded channel {:?} closed", channel);
         }
@@ -54,41 +77,40 @@ impl Handler for SshHandler {
 
         info!(
             "Public key auth attempt: user='{}', fingerprint='{}'",
-            user, fingerprint
+            redact_username(user), fingerprint
         );
 
-        self.username = Some(user.to_string());
-        
-        // Username is used as explicit subdomain (disconnect on conflict)
-        // "." means use random subdomain
-        if user != "." {
-            // Validate the subdomain before accepting
-            let subdomain = user.to_lowercase();
-            match validate_subdomain(&subdomain) {
-                SubdomainValidation::Valid => {
-                    let mut state = self.shared_state.lock().await;
-                    state.requested_subdomain = Some(subdomain.clone());
-                    info!("Username set as explicit subdomain: {}", subdomain);
-                }
-                SubdomainValidation::TooLong => {
-                    warn!("Username '{}' is too long for subdomain (max 63 chars)", user);
-                    return Ok(Auth::Reject { proceed_with_methods: None });
-                }
-                SubdomainValidation::TooShort => {
-                    warn!("Username '{}' is too short for subdomain", user);
-                    return Ok(Auth::Reject { proceed_with_methods: None });
-                }
-                SubdomainValidation::InvalidCharacters => {
-                    warn!("Username '{}' contains invalid characters for subdomain", user);
-                    return Ok(Auth::Reject { proceed_with_methods: None });
-                }
-                SubdomainValidation::StartsWithHyphen | SubdomainValidation::EndsWithHyphen => {
-                    warn!("Username '{}' cannot start or end with hyphen", user);
-                    return Ok(Auth::Reject { proceed_with_methods: None });
-                }
+        if let Some(peer) = self.peer_addr {
+            if self.state.bans.is_banned(peer.ip()).await.is_some() {
+                warn!("Rejecting auth from banned IP {}", peer.ip());
+                return Ok(Auth::Reject { proceed_with_methods: None });
             }
-        } else {
-            info!("Username is '.', will use random subdomain");
+        }
+
+        self.username = Some(redact_username(user));
+
+        // A resumption token brings back the dropped session it was issued to
+        if let Some(token) = user.strip_prefix(RESUME_USER_PREFIX) {
+            let fingerprint = fingerprint.to_string();
+            self.public_key_fingerprint = Some(fingerprint.clone());
+            return Ok(if self.resume_session(token, &fingerprint).await {
+                Auth::Accept
+            } else {
+                Auth::Reject { proceed_with_methods: None }
+            });
+        }
+
+        // A token in the username replaces the Device Flow (random subdomain)
+        if let Some(token) = user.strip_prefix(TOKEN_USER_PREFIX) {
+            return Ok(if self.verify_api_token(token).await {
+                Auth::Accept
+            } else {
+                Auth::Reject { proceed_with_methods: None }
+            });
+        }
+
+        if !self.request_subdomain_from_username(user).await {
+            return Ok(Auth::Reject { proceed_with_methods: None });
         }
         
         let fingerprint_str = fingerprint.to_string();
@@ -99,24 +121,132 @@ impl Handler for SshHandler {
                 "Public key already verified for user '{}', subdomains={:?}, skipping Device Flow",
                 verified_key.user_id, verified_key.subdomains
             );
+            self.report_verification(VerificationMethod::VerifiedKey, Ok(verified_key.user_id.clone()));
             let display_name = verified_key.get_display_name();
             let mut state = self.shared_state.lock().await;
             state.verification_status = VerificationStatus::Verified {
                 user_id: verified_key.user_id,
                 display_name,
             };
+            state.tier = verified_key.tier;
             state.last_subdomains = verified_key.subdomains;
         }
 
+        #[cfg(feature = "dangerous-dev-auth")]
+        if crate::config::get().skip_auth && !self.is_verified().await {
+            self.skip_auth(user, &fingerprint_str).await;
+        }
+
         Ok(Auth::Accept)
     }
 
+    /// A user certificate from a trusted CA verifies the session without the
+    /// Device Flow; its principal is the user_id
+    async fn auth_openssh_certificate(
+        &mut self,
+        user: &str,
+        certificate: &Certificate,
+    ) -> Result<Auth, Self::Error> {
+        info!(
+            "Certificate auth attempt: user='{}', key id='{}'",
+            redact_username(user),
+            certificate.key_id()
+        );
+
+        if let Some(peer) = self.peer_addr {
+            if self.state.bans.is_banned(peer.ip()).await.is_some() {
+                warn!("Rejecting auth from banned IP {}", peer.ip());
+                return Ok(Auth::Reject { proceed_with_methods: None });
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let peer_ip = self.peer_addr.map(|peer| peer.ip());
+        let certified = match verify_user_certificate(certificate, &get_config().trusted_user_ca_keys, peer_ip, now) {
+            Ok(certified) => certified,
+            Err(reason) => {
+                // The client falls back to its plain key and the Device Flow
+                info!("Certificate not accepted: {}", reason);
+                return Ok(Auth::Reject { proceed_with_methods: None });
+            }
+        };
+
+        self.username = Some(redact_username(user));
+        if !self.request_subdomain_from_username(user).await {
+            return Ok(Auth::Reject { proceed_with_methods: None });
+        }
+        let fingerprint = certificate.public_key().fingerprint(HashAlg::Sha256).to_string();
+        self.public_key_fingerprint = Some(fingerprint.clone());
+
+        info!(
+            "Certificate '{}' from CA {} accepted for user '{}', skipping Device Flow",
+            certified.key_id, certified.ca_fingerprint, certified.user_id
+        );
+        let mut event = AuditEvent::new(&certified.user_id, "verification.succeeded")
+            .target(&fingerprint)
+            .detail(format!("certificate {} from CA {}", certified.key_id, certified.ca_fingerprint));
+        if let Some(peer) = self.peer_addr {
+            event = event.remote(peer);
+        }
+        self.state.audit.record(event);
+        self.report_verification(VerificationMethod::Certificate, Ok(certified.user_id.clone()));
+
+        let mut state = self.shared_state.lock().await;
+        state.verification_status = VerificationStatus::Verified {
+            display_name: certified.user_id.clone(),
+            user_id: certified.user_id,
+        };
+        Ok(Auth::Accept)
+    }
+
+    /// Password auth carries an API token for headless clients: either the
+    /// password is the token (username picks the subdomain as with keys) or
+    /// the username is `token-XXXX` and the password is ignored
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        info!("Password (API token) auth attempt: user='{}'", redact_username(user));
+
+        if let Some(peer) = self.peer_addr {
+            if self.state.bans.is_banned(peer.ip()).await.is_some() {
+                warn!("Rejecting auth from banned IP {}", peer.ip());
+                return Ok(Auth::Reject { proceed_with_methods: None });
+            }
+        }
+
+        self.username = Some(redact_username(user));
+
+        let token = match user.strip_prefix(TOKEN_USER_PREFIX) {
+            Some(token) => token,
+            None if self.request_subdomain_from_username(user).await => password,
+            None => return Ok(Auth::Reject { proceed_with_methods: None }),
+        };
+        Ok(if self.verify_api_token(token).await {
+            Auth::Accept
+        } else {
+            Auth::Reject { proceed_with_methods: None }
+        })
+    }
+
     async fn tcpip_forward(
         &mut self,
         address: &str,
         port: &mut u32,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        // The address is echoed on every forwarded channel and in logs
+        let normalized = match self.state.forward_addresses.check(address).await {
+            Ok(normalized) => normalized,
+            Err(reason) => {
+                warn!("Refusing forward for port {} with address {:?}: {}", port, address, reason);
+                let notice = match self.output_mode().await {
+                    OutputMode::Tty => terminal_ui::create_forward_refused_box(self.ui().await, *port, &reason),
+                    mode => SessionEvent::Refused { port: *port, reason: &reason }.render(mode),
+                };
+                self.send_notice(session, notice, &format!("port {} refused: {}", port, reason))
+                    .await;
+                return Ok(false);
+            }
+        };
+
         let status = self.get_verification_status().await;
         info!(
             "=== Tunnel Request ===\n\
@@ -124,31 +254,65 @@ impl Handler for SshHandler {
              Port: {}\n\
              User: {:?}\n\
              Status: {:?}",
-            address, port, self.username, status
+            normalized, port, self.username, status
         );
 
+        // Maintenance mode: existing tunnels only
+        if let Some(message) = self.maintenance_refusal(*port).await {
+            info!("Refusing forward for port {}: maintenance mode", port);
+            let notice = match self.output_mode().await {
+                OutputMode::Tty => terminal_ui::create_maintenance_box(self.ui().await, &message),
+                mode => SessionEvent::Refused { port: *port, reason: &message }.render(mode),
+            };
+            self.send_notice(session, notice, &format!("port {} refused: {}", port, message))
+                .await;
+            return Ok(false);
+        }
+
+        // Dedicated TCP / UDP ports are bound right away so the reply can name them
+        if let Some(transport) = Transport::of_forward(address) {
+            if !self.port_forward(address, transport, port, session).await {
+                return Ok(false);
+            }
+            if self.is_verified().await {
+                return Ok(true);
+            }
+        }
+
         // If already verified (reconnection or new port), create tunnel immediately
         if self.is_verified().await {
             let result = self.do_create_tunnel(address, *port).await?;
             if result.success {
-                self.send_tunnel_message(*port).await;
-            } else if let Some(ref conflicting) = result.conflicting_subdomain {
+                self.send_tunnel_message().await;
+                push_session_status(&self.state, &self.shared_state).await;
+            } else if let Some(capability) = result.missing_capability {
+                let reason = terminal_ui::capability_denied_reason(capability);
+                let notice = match self.output_mode().await {
+                    OutputMode::Tty => terminal_ui::create_capability_denied_box(self.ui().await, capability, *port),
+                    mode => SessionEvent::Refused { port: *port, reason: &reason }.render(mode),
+                };
+                self.send_notice(session, notice, &format!("port {} refused: {}", port, reason))
+                    .await;
+            } else if let Some(ref conflict) = result.conflict {
                 // Only disconnect if it's an explicit subdomain conflict
                 if result.is_explicit_conflict {
-                    let channel_id = self.session_channel_id;
-                    if let Some(channel) = channel_id {
-                        let error_msg = terminal_ui::create_subdomain_taken_error_box(conflicting, *port);
-                        let _ = session.data(channel, error_msg.into_bytes().into());
-                    }
-                    
-                    // Disconnect after a short delay
+                    let reason = conflict.to_string();
+                    let ui = self.ui().await;
+                    let error_msg = match self.output_mode().await {
+                        OutputMode::Tty => terminal_ui::create_subdomain_taken_error_box(ui, conflict, *port),
+                        mode => SessionEvent::Error { reason: &reason, code: Some(conflict.code()) }.render(mode),
+                    };
+                    self.send_notice(session, error_msg, &reason).await;
+
+                    // Disconnect after a short delay; the disconnect message is
+                    // all a session without a session channel gets to see
                     let handle = self.session_handle.clone();
-                    tokio::spawn(async move {
+                    self.state.cleanup.spawn("conflict_disconnect", async move {
                         tokio::time::sleep(std::time::Duration::from_secs(3)).await;
                         if let Some(h) = handle {
                             let _ = h.disconnect(
                                 Disconnect::ByApplication,
-                                "Subdomain already taken".to_string(),
+                                format!("Subdomain unavailable: {}", reason),
                                 "en".to_string(),
                             ).await;
                         }
@@ -179,6 +343,9 @@ impl Handler for SshHandler {
             match self.start_device_flow().await {
                 Ok(_code) => {
                     debug!("Device Flow started for pending tunnel");
+                    if self.session_channel_id.is_none() {
+                        self.watch_channel_less_activation();
+                    }
                 }
                 Err(reason) => {
                     warn!("Device Flow failed: {}", reason);
@@ -196,7 +363,13 @@ impl Handler for SshHandler {
         port: u32,
         _session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        info!("Cancel tcpip_forward: address='{}', port={}", address, port);
+        info!("Cancel tcpip_forward: address={:?}, port={}", address, port);
+
+        if let Some(transport) = Transport::of_forward(address) {
+            let released = u16::try_from(port)
+                .is_ok_and(|port| self.state.dedicated_ports.release(transport, &self.session_id, port));
+            return Ok(released);
+        }
 
         let tunnels_to_remove: Vec<String> = {
             let state = self.shared_state.lock().await;
@@ -204,14 +377,18 @@ impl Handler for SshHandler {
         };
 
         for subdomain in tunnels_to_remove {
-            if let Ok(info) = self.state.remove_tunnel(&subdomain).await {
-                if info.requested_address == address && info.requested_port == port {
-                    let mut state = self.shared_state.lock().await;
-                    state.registered_subdomains.retain(|s| s != &subdomain);
-                    info!("Removed tunnel: {}", subdomain);
-                }
+            // Only the tunnel of the cancelled forward; the session may hold others
+            let matches = self
+                .state
+                .get_tunnel(&subdomain)
+                .await
+                .is_some_and(|info| info.requested_address == address && info.requested_port == port);
+            if matches && self.state.remove_tunnel(&subdomain).await.is_ok() {
+                self.shared_state.lock().await.forget_tunnel(&subdomain);
+                info!("Removed tunnel: {}", subdomain);
             }
         }
+        push_session_status(&self.state, &self.shared_state).await;
 
         Ok(true)
     }
@@ -219,30 +396,40 @@ impl Handler for SshHandler {
     async fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
-        session: &mut Session,
+        _session: &mut Session,
     ) -> Result<bool, Self::Error> {
         let channel_id = channel.id();
         info!("Session channel opened: id={:?}", channel_id);
-        self.session_channel_id = Some(channel_id);
-        self.shared_state.lock().await.session_channel_id = Some(channel_id);
+        self.previous_session_channel_id = self.session_channel_id.replace(channel_id);
+        let registered = {
+            let mut state = self.shared_state.lock().await;
+            state.session_channel_id = Some(channel_id);
+            state.registered_subdomains.clone()
+        };
+        // Tunnels created before the session channel opened can now receive notices
+        for subdomain in &registered {
+            self.state.set_session_channel(subdomain, channel_id).await;
+        }
+        self.open_channel = Some(channel);
 
         // Check verification status for new connections
         let status = self.get_verification_status().await;
+        let maintenance = self.state.maintenance_mode.current().await.is_some();
 
         match status {
             VerificationStatus::Verified { .. } => {
                 // Already verified, tunnels will be created in tcpip_forward
             }
+            VerificationStatus::NotStarted if maintenance => {
+                // No new activations; shell_request shows the notice
+            }
             VerificationStatus::NotStarted => {
+                // The activation message is sent by shell_request, once the
+                // client has said whether it wants a PTY
                 match self.start_device_flow().await {
                     Ok(code) => {
                         let url = self.device_flow_client.get_activation_url(&code);
                         debug!("Device Flow started - URL: {}", url);
-
-                        let message = terminal_ui::create_activation_box(&code, &url);
-                        if let Err(e) = session.data(channel_id, message.into_bytes().into()) {
-                            warn!("Failed to send activation message: {:?}", e);
-                        }
                     }
                     Err(reason) => {
                         warn!("Device Flow failed to start: {}", reason);
@@ -267,64 +454,160 @@ impl Handler for SshHandler {
             data.len()
         );
 
-        if data.contains(&27) {
-            let mut state = self.shared_state.lock().await;
-            let now = std::time::Instant::now();
-
-            if state.esc_pressed {
-                if let Some(last_time) = state.last_esc_time {
-                    if now.duration_since(last_time).as_secs() < 2 {
-                        drop(state);
-                        info!("Double ESC detected, disconnecting...");
-                        if let Some(handle) = &self.session_handle {
-                            let _ = handle
-                                .disconnect(
-                                    Disconnect::ByApplication,
-                                    "Disconnected by user".to_string(),
-                                    "en".to_string(),
-                                )
-                                .await;
-                        }
-                        return Ok(());
+        // Control protocol frames, not keystrokes
+        if self.control.as_ref().is_some_and(|(id, _)| *id == channel) {
+            match self.control_data(data).await {
+                Some(replies) => {
+                    for reply in replies {
+                        session.data(channel, reply.into())?;
                     }
                 }
-            }
-
-            state.esc_pressed = true;
-            state.last_esc_time = Some(now);
-            drop(state);
-
-            let hint = terminal_ui::create_esc_hint();
-            session.data(channel, hint.into_bytes().into())?;
-
-            let shared_state = self.shared_state.clone();
-            let handle = self.session_handle.clone();
-            let channel_id = channel;
-            tokio::spawn(async move {
-                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-                let mut state = shared_state.lock().await;
-                if state.esc_pressed {
-                    state.esc_pressed = false;
-                    state.last_esc_time = None;
-                    if let Some(h) = handle {
-                        let clear = terminal_ui::clear_esc_hint();
-                        let _ = h.data(channel_id, clear.into_bytes().into()).await;
-                    }
+                None => {
+                    session.close(channel)?;
+                    self.close_control_channel().await;
                 }
-            });
+            }
+            return Ok(());
+        }
 
+        // The answer to a confirmation prompt
+        if let Some(confirmation) = self.confirmation_input(channel, data).await {
+            let output = match confirmation {
+                Confirmation::Waiting => return Ok(()),
+                Confirmation::Confirmed(command) => self.run_exec(&command).await,
+                Confirmation::Refused(reason) => ExecOutput::error(reason),
+            };
+            send_exec_output(session, channel, output)?;
             return Ok(());
         }
 
+        // Speed test upload payload, not keystrokes
+        if let Some((speedtest_channel, upload)) = &self.speedtest_upload {
+            if *speedtest_channel == channel {
+                let _ = upload.send((std::time::Instant::now(), data.len()));
+                return Ok(());
+            }
+        }
+
+        for key in self.keys.feed(data) {
+            self.menu_key(key, channel, session).await?;
+        }
         Ok(())
     }
 
     async fn channel_eof(
         &mut self,
         channel: ChannelId,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<(), Self::Error> {
         debug!("EOF on channel {:?}", channel);
+        // The speed test upload ends with the client's input
+        if self.speedtest_upload.as_ref().is_some_and(|(id, _)| *id == channel) {
+            self.speedtest_upload = None;
+        }
+        // No more input can confirm a destructive command
+        if self.cancel_confirmation(channel).await {
+            send_exec_output(session, channel, ExecOutput::error("No confirmation code received"))?;
+        }
+        Ok(())
+    }
+
+    async fn env_request(
+        &mut self,
+        _channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if variable_name == PORT_PROBE_ENV {
+            match PortProbeMode::parse(variable_value) {
+                Some(mode) => {
+                    info!("Client requested port probe mode {:?}", mode);
+                    self.shared_state.lock().await.port_probe = Some(mode);
+                }
+                None => warn!("Ignoring invalid {}='{}'", PORT_PROBE_ENV, variable_value),
+            }
+        } else if variable_name == OUTPUT_ENV {
+            match OutputMode::parse(variable_value) {
+                Some(mode) => {
+                    info!("Client requested output mode {:?}", mode);
+                    self.shared_state.lock().await.requested_output = Some(mode);
+                }
+                None => warn!("Ignoring invalid {}='{}'", OUTPUT_ENV, variable_value),
+            }
+        } else if variable_name == LIVE_VIEW_ENV {
+            let enabled = !matches!(variable_value.to_ascii_lowercase().as_str(), "off" | "0" | "false");
+            info!("Client set live request view {}", if enabled { "on" } else { "off" });
+            self.shared_state.lock().await.live_view_enabled = enabled;
+        } else if variable_name == SECRET_PATH_ENV {
+            if matches!(variable_value.to_ascii_lowercase().as_str(), "on" | "1" | "true") {
+                self.enable_secret_paths().await;
+            }
+        } else if variable_name == LANG_ENV {
+            // Most clients forward LANG, so an unknown locale is no error
+            match Lang::parse(variable_value) {
+                Some(lang) => {
+                    debug!("Client locale selects language {:?}", lang);
+                    self.shared_state.lock().await.locale_lang = Some(lang);
+                }
+                None => debug!("No translation for locale '{}'", variable_value),
+            }
+        } else {
+            debug!("Ignoring env request: {}", variable_name);
+        }
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data).to_string();
+        info!("Exec request on channel {:?}: {}", channel, command);
+        session.channel_success(channel)?;
+
+        let output = if ExecCommand::parse(&command) == Ok(ExecCommand::SpeedTest) {
+            match self.start_speedtest(channel).await {
+                Ok(()) => return Ok(()),
+                Err(output) => output,
+            }
+        } else {
+            self.open_channel = None;
+            // Destructive commands wait for their confirmation code in `data`
+            if let Some(prompt) = self.request_confirmation(channel, &command).await {
+                session.extended_data(channel, 1, prompt.into_bytes().into())?;
+                return Ok(());
+            }
+            let mut output = self.run_exec(&command).await;
+            // On a multiplexed connection (`ssh -S`) this may be the first
+            // session channel of a `-N` master: hand over what it missed
+            let mut missed = self.pending_notices(OutputMode::Plain).await;
+            if let VerificationStatus::Pending { code } = self.get_verification_status().await {
+                let url = self.device_flow_client.get_activation_url(&code);
+                missed.push_str(&SessionEvent::Activation { code: &code, url: &url }.render(OutputMode::Plain));
+            }
+            output.stderr.insert_str(0, &missed);
+            output
+        };
+        send_exec_output(session, channel, output)?;
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        info!("Subsystem request on channel {:?}: {}", channel, name);
+        if name != CONTROL_SUBSYSTEM || self.control.is_some() {
+            session.channel_failure(channel)?;
+            return Ok(());
+        }
+        session.channel_success(channel)?;
+        self.open_control_channel(channel).await;
         Ok(())
     }
 
@@ -332,18 +615,61 @@ impl Handler for SshHandler {
         &mut self,
         channel: ChannelId,
         _term: &str,
-        _col_width: u32,
-        _row_height: u32,
+        col_width: u32,
+        row_height: u32,
         _pix_width: u32,
         _pix_height: u32,
         _modes: &[(russh::Pty, u32)],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        info!("PTY request on channel {:?}", channel);
+        info!("PTY request on channel {:?} ({}x{})", channel, col_width, row_height);
+        {
+            let mut shared = self.shared_state.lock().await;
+            shared.pty_requested = true;
+            if col_width > 0 && row_height > 0 {
+                shared.terminal_size = Some((col_width, row_height));
+            }
+        }
         session.channel_success(channel)?;
         Ok(())
     }
 
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        debug!("Window change on channel {:?} ({}x{})", channel, col_width, row_height);
+        if col_width == 0 || row_height == 0 {
+            return Ok(());
+        }
+        let (output_mode, ui, registered) = {
+            let mut shared = self.shared_state.lock().await;
+            shared.terminal_size = Some((col_width, row_height));
+            if let Some(view) = &shared.live_view {
+                view.repaint();
+            }
+            (shared.output_mode(), shared.ui(), shared.registered_subdomains.clone())
+        };
+        // Later notices about the tunnels are sized for the new width
+        for subdomain in &registered {
+            self.state.set_output_mode(subdomain, output_mode, ui).await;
+        }
+
+        // Boxes drawn for the old width wrap (and the spinner's in-place
+        // updates land on the wrong line), so draw them again from scratch
+        if self.session_channel_id == Some(channel) {
+            if let Some(screen) = self.redrawn_screen().await {
+                session.data(channel, screen.into_bytes().into())?;
+            }
+        }
+        Ok(())
+    }
+
     async fn shell_request(
         &mut self,
         channel: ChannelId,
@@ -351,30 +677,39 @@ impl Handler for SshHandler {
     ) -> Result<(), Self::Error> {
         info!("Shell request on channel {:?}", channel);
         session.channel_success(channel)?;
+        self.open_channel = None;
 
-        // Check if there's a pending tunnel message
-        let pending_port = {
-            let mut state = self.shared_state.lock().await;
-            state.pending_tunnel_port.take()
+        // The PTY request (if any) came first, so the output mode is settled
+        let (message_pending, output_mode, registered) = {
+            let mut shared = self.shared_state.lock().await;
+            let pending = shared.take_tunnel_message_pending(std::time::Instant::now());
+            (pending, shared.output_mode(), shared.registered_subdomains.clone())
         };
+        let ui = self.ui().await;
+        for subdomain in &registered {
+            self.state.set_output_mode(subdomain, output_mode, ui).await;
+        }
 
-        if let Some(port) = pending_port {
-            let (display_name, tunnels) = {
-                let state = self.shared_state.lock().await;
-                let display_name = match &state.verification_status {
-                    VerificationStatus::Verified { display_name, .. } => display_name.clone(),
-                    _ => "unknown".to_string(),
-                };
-                let tunnels: Vec<(String, u32)> = state
-                    .registered_subdomains
-                    .iter()
-                    .map(|s| (s.clone(), port))
-                    .collect();
-                (display_name, tunnels)
-            };
+        // Refusals raised before the channel opened
+        let notices = self.pending_notices(output_mode).await;
+        if !notices.is_empty() {
+            let _ = session.data(channel, notices.into_bytes().into());
+        }
+
+        if message_pending {
+            let copy = self.auto_copy(&self.success_box_tunnels().await.1).await;
+            if !copy.is_empty() {
+                let _ = session.data(channel, copy.into_bytes().into());
+            }
+            if let Some(handle) = self.session_handle.clone() {
+                if self.show_live_view(&handle, channel).await {
+                    return Ok(());
+                }
+            }
+            let (display_name, tunnels) = self.success_box_tunnels().await;
 
             if !tunnels.is_empty() {
-                let message = terminal_ui::create_success_box(&display_name, &tunnels);
+                let message = self.tunnel_message(&display_name, &tunnels).await;
                 if let Err(e) = session.data(channel, message.into_bytes().into()) {
                     warn!("Failed to send tunnel message in shell_request: {:?}", e);
                 } else {
@@ -384,11 +719,37 @@ impl Handler for SshHandler {
             return Ok(());
         }
 
+        // Maintenance mode: a session without tunnels is turned away
+        if registered.is_empty() {
+            if let Some(notice) = self.state.maintenance_mode.current().await {
+                info!("Refusing new session: maintenance mode");
+                let message = match output_mode {
+                    OutputMode::Tty => terminal_ui::create_maintenance_box(ui, &notice.message),
+                    mode => SessionEvent::Error { reason: &notice.message, code: None }.render(mode),
+                };
+                let _ = session.data(channel, message.into_bytes().into());
+                let handle = self.session_handle.clone();
+                self.state.cleanup.spawn("maintenance_disconnect", async move {
+                    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+                    if let Some(h) = handle {
+                        let _ = h
+                            .disconnect(
+                                Disconnect::ByApplication,
+                                "Server under maintenance".to_string(),
+                                "en".to_string(),
+                            )
+                            .await;
+                    }
+                });
+                return Ok(());
+            }
+        }
+
         // Send the activation message if Device Flow is pending
         let status = self.get_verification_status().await;
         if let VerificationStatus::Pending { code } = status {
             let url = self.device_flow_client.get_activation_url(&code);
-            let message = terminal_ui::create_activation_box(&code, &url);
+            let message = self.activation_box(&code, &url).await;
             if let Err(e) = session.data(channel, message.into_bytes().into()) {
                 warn!("Failed to send activation message: {:?}", e);
             }
@@ -397,3 +758,17 @@ impl Handler for SshHandler {
         Ok(())
     }
 }
+
+/// Write an exec command's output and exit status, then close its channel
+fn send_exec_output(session: &mut Session, channel: ChannelId, output: ExecOutput) -> Result<(), russh::Error> {
+    if !output.stdout.is_empty() {
+        session.data(channel, output.stdout.into_bytes().into())?;
+    }
+    if !output.stderr.is_empty() {
+        // Extended data type 1 is stderr
+        session.extended_data(channel, 1, output.stderr.into_bytes().into())?;
+    }
+    session.exit_status_request(channel, output.exit_status)?;
+    session.eof(channel)?;
+    session.close(channel)
+}
@@ -0,0 +1,92 @@
+//! Periodic health checks of tunnels' local services.
+//!
+//! Every `HEALTH_CHECK_INTERVAL` each connected tunnel gets the same probe as
+//! at registration: a forwarded channel to its local port, closed right away.
+//! A client refusing the channel means nothing answers on the port. Tunnels
+//! failing in a row are marked degraded (see `state::local_health`); the
+//! session holding the tunnel is told when that happens and when the service
+//! answers again.
+
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use super::keepalive::{is_session_gone, mark_session_dead};
+use crate::state::{AppState, TunnelInfo};
+use crate::terminal_ui::{self, OutputMode, SessionEvent};
+
+/// How long a probe may wait for the client to accept or refuse the channel
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probe the local service of each connected tunnel once
+pub async fn check_local_services(state: &AppState) {
+    for tunnel in state.list_tunnels().await {
+        if !tunnel.is_connected {
+            continue;
+        }
+        let probe = tunnel.handle.channel_open_forwarded_tcpip(
+            &tunnel.requested_address,
+            tunnel.requested_port,
+            "127.0.0.1",
+            12345,
+        );
+        let answered = match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) if is_session_gone(&e) => {
+                mark_session_dead(state, &tunnel.session_id).await;
+                continue;
+            }
+            Ok(Err(_)) => false,
+            // A busy session says nothing about the local service
+            Err(_) => {
+                debug!("Health check of tunnel {} timed out", tunnel.subdomain);
+                continue;
+            }
+        };
+
+        if answered && tunnel.awaiting_local_service && state.mark_local_service_ready(&tunnel.subdomain).await {
+            info!("Local service of tunnel {} is up, tunnel is healthy", tunnel.subdomain);
+        }
+        if let Some(changed) = state.record_health_check(&tunnel.subdomain, answered).await {
+            notify_local_health(changed);
+        }
+    }
+}
+
+/// Tell the owner of `tunnel` its local service went down or came back
+pub fn notify_local_health(tunnel: TunnelInfo) {
+    let degraded = tunnel.degraded_since.is_some();
+    if degraded {
+        warn!(
+            "Local service of tunnel {} stopped answering on port {}",
+            tunnel.subdomain, tunnel.requested_port
+        );
+    } else {
+        info!(
+            "Local service of tunnel {} answers again on port {}",
+            tunnel.subdomain, tunnel.requested_port
+        );
+    }
+    tokio::spawn(async move {
+        let Some(channel_id) = tunnel.session_channel_id.filter(|_| tunnel.is_connected) else {
+            return;
+        };
+        let notice = match tunnel.output_mode {
+            OutputMode::Tty => {
+                terminal_ui::create_local_service_notice(
+                    tunnel.ui.lang,
+                    &tunnel.subdomain,
+                    tunnel.requested_port,
+                    degraded,
+                )
+            }
+            mode => SessionEvent::LocalService {
+                subdomain: &tunnel.subdomain,
+                port: tunnel.requested_port,
+                degraded,
+            }
+            .render(mode),
+        };
+        let _ = tunnel.handle.data(channel_id, notice.into_bytes().into()).await;
+    });
+}
@@ -0,0 +1,50 @@
+//! Disconnecting tunnels that carry no traffic.
+
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use russh::Disconnect;
+
+use crate::state::AppState;
+use crate::terminal_ui::{self, OutputMode, SessionEvent};
+
+/// Grace period between the idle notice and the disconnect
+pub(super) const NOTICE_GRACE: Duration = Duration::from_secs(2);
+
+/// Disconnect tunnels idle for longer than `timeout`, notifying the client first.
+pub async fn reap_idle_tunnels(state: &AppState, timeout: Duration) {
+    for tunnel in state.idle_tunnels(timeout).await {
+        let idle = tunnel.idle_for(SystemTime::now()).unwrap_or(timeout);
+        info!(
+            "Disconnecting idle tunnel {} (no traffic for {}s)",
+            tunnel.subdomain,
+            idle.as_secs()
+        );
+        state.mark_tunnel_disconnected(&tunnel.subdomain).await;
+
+        let handle = tunnel.handle.clone();
+        let channel_id = tunnel.session_channel_id;
+        let mode = tunnel.output_mode;
+        let ui = tunnel.ui;
+        state.cleanup.spawn("idle_disconnect", async move {
+            if let Some(channel_id) = channel_id {
+                let notice = match mode {
+                    OutputMode::Tty => terminal_ui::create_idle_disconnect_box(ui, idle),
+                    mode => SessionEvent::IdleDisconnect { idle }.render(mode),
+                };
+                let _ = handle.data(channel_id, notice.into_bytes().into()).await;
+                tokio::time::sleep(NOTICE_GRACE).await;
+            }
+            if let Err(e) = handle
+                .disconnect(
+                    Disconnect::ByApplication,
+                    "Tunnel closed after inactivity".to_string(),
+                    "en".to_string(),
+                )
+                .await
+            {
+                warn!("Failed to disconnect idle session: {:?}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,66 @@
+//! Detecting SSH sessions that died without closing.
+//!
+//! russh's keepalive (`SSH_KEEPALIVE_INTERVAL` / `SSH_KEEPALIVE_MAX`) drops a
+//! session whose client stopped answering. On top of that, each session with
+//! a session channel is pinged with an empty data packet: invisible to the
+//! client, but it fails once the session is gone. Tunnels of such a session,
+//! and of sessions whose handle errors while the proxy opens a channel, are
+//! marked disconnected right away so they stop being routed.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use log::{debug, warn};
+use russh::CryptoVec;
+
+use crate::state::AppState;
+
+/// How long a ping may wait on a busy session before it is skipped
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether a failed channel open means the session itself is gone, rather
+/// than the client refusing the channel
+pub fn is_session_gone(error: &russh::Error) -> bool {
+    !matches!(error, russh::Error::ChannelOpenFailure(_))
+}
+
+/// Mark every tunnel of a dead session disconnected
+pub async fn mark_session_dead(state: &AppState, session_id: &str) {
+    for subdomain in state.session_subdomains(session_id).await {
+        warn!("Session {} is gone, marking tunnel {} disconnected", session_id, subdomain);
+        state.mark_tunnel_disconnected(&subdomain).await;
+    }
+}
+
+/// Ping each session holding connected tunnels once
+pub async fn ping_sessions(state: &AppState) {
+    let mut pinged = HashSet::new();
+    for tunnel in state.list_tunnels().await {
+        let Some(channel) = tunnel.session_channel_id.filter(|_| tunnel.is_connected) else {
+            continue;
+        };
+        if !pinged.insert(tunnel.session_id.clone()) {
+            continue;
+        }
+        match tokio::time::timeout(PING_TIMEOUT, tunnel.handle.data(channel, CryptoVec::new())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => mark_session_dead(state, &tunnel.session_id).await,
+            // Busy is not dead; the SSH keepalive decides that
+            Err(_) => debug!("Ping to session {} timed out", tunnel.session_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_session_gone() {
+        assert!(!is_session_gone(&russh::Error::ChannelOpenFailure(
+            russh::ChannelOpenFailure::ConnectFailed
+        )));
+        assert!(is_session_gone(&russh::Error::SendError));
+        assert!(is_session_gone(&russh::Error::Disconnect));
+    }
+}
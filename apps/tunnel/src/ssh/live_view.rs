@@ -0,0 +1,259 @@
+//! Live request view below the success box.
+//!
+//! Once a session's tunnels are up, a task subscribes to the requests the
+//! proxy publishes for the session and redraws the success box with a panel
+//! of recent requests, byte counters and latency percentiles (see
+//! `state::stats`) underneath. A status line names the ports whose local
+//! service fails health checks; it is redrawn when one of the session's
+//! tunnels degrades or recovers. Redraws are batched so a
+//! burst of requests doesn't flood the terminal, and skipped while the ESC
+//! hint is on screen (it is cleared by moving the cursor up).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use russh::server::Handle;
+use russh::ChannelId;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+
+use crate::crash::{spawn_with_context, CrashContext};
+use crate::state::events::{TunnelEvent, TunnelEventKind};
+use crate::state::requests::RequestEvent;
+use crate::state::AppState;
+use crate::terminal_ui::{self, LiveTotals};
+
+use super::types::SharedHandlerState;
+
+/// Most request rows shown (fewer if the terminal is short)
+const MAX_ROWS: usize = 8;
+
+/// Minimum time between redraws
+const REPAINT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Recent requests and running totals for one session
+#[derive(Debug, Default)]
+struct LiveView {
+    /// Newest first
+    recent: Vec<RequestEvent>,
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl LiveView {
+    fn record(&mut self, event: RequestEvent) {
+        self.requests += 1;
+        self.bytes_in += event.bytes_in;
+        self.bytes_out += event.bytes_out;
+        self.recent.insert(0, event);
+        self.recent.truncate(MAX_ROWS);
+    }
+}
+
+/// A running live view
+pub struct LiveViewTask {
+    repaint: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl LiveViewTask {
+    /// Redraw now (e.g. after a tunnel was added)
+    pub fn repaint(&self) {
+        self.repaint.notify_one();
+    }
+
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+/// Request rows that fit below a `header_lines` tall header
+fn rows_for(header_lines: usize, terminal_size: Option<(u32, u32)>) -> usize {
+    match terminal_size {
+        Some((_, rows)) => (rows as usize)
+            .saturating_sub(header_lines + terminal_ui::LIVE_VIEW_FRAME_LINES + 1)
+            .min(MAX_ROWS),
+        None => MAX_ROWS,
+    }
+}
+
+/// Everything needed to redraw the session's screen
+struct Screen {
+    state: Arc<AppState>,
+    shared_state: Arc<Mutex<SharedHandlerState>>,
+    handle: Handle,
+    channel: ChannelId,
+    session_id: String,
+    display_name: String,
+    /// Operator message shown with the first box, kept across redraws
+    motd: String,
+}
+
+impl Screen {
+    /// Redraw the success box and the panel. Errors once the channel is gone.
+    async fn paint(&self, view: &LiveView) -> Result<(), ()> {
+        let ui = {
+            let shared = self.shared_state.lock().await;
+            if shared.esc_pressed {
+                return Ok(());
+            }
+            shared.ui()
+        };
+
+        // Read from the registry so renames and added ports show up
+        let mut tunnels = Vec::new();
+        let mut degraded = Vec::new();
+        for subdomain in self.state.session_subdomains(&self.session_id).await {
+            if let Some(tunnel) = self.state.get_tunnel(&subdomain).await {
+                if let Some(since) = tunnel.degraded_since {
+                    degraded.push((tunnel.requested_port, since));
+                }
+                tunnels.push((subdomain, tunnel.requested_port));
+            }
+        }
+        if tunnels.is_empty() {
+            return Ok(());
+        }
+        tunnels.sort_by_key(|(_, port)| *port);
+        degraded.sort_by_key(|(port, _)| *port);
+
+        let mut screen = terminal_ui::create_success_box(ui, &self.display_name, &tunnels);
+        screen.push_str(&self.motd);
+        if !degraded.is_empty() {
+            screen.push_str(&terminal_ui::create_degraded_status_line(ui.lang, &degraded));
+        }
+        let rows = rows_for(screen.matches("\r\n").count(), ui.terminal_size);
+        if rows > 0 {
+            let subdomains: Vec<String> = tunnels.iter().map(|(subdomain, _)| subdomain.clone()).collect();
+            let totals = LiveTotals {
+                requests: view.requests,
+                bytes_in: view.bytes_in,
+                bytes_out: view.bytes_out,
+                latency: self.state.latency.report(&subdomains).total,
+            };
+            screen.push_str(&terminal_ui::create_live_view(
+                ui,
+                &view.recent,
+                &totals,
+                rows,
+                tunnels.len() > 1,
+            ));
+        }
+        self.handle
+            .data(self.channel, screen.into_bytes().into())
+            .await
+            .map_err(|_| ())
+    }
+
+    /// Wait until one of the session's tunnels degrades or recovers
+    async fn health_changed(&self, events: &mut Receiver<TunnelEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(event) if matches!(event.kind, TunnelEventKind::Degraded | TunnelEventKind::Recovered) => {
+                    if self.state.session_subdomains(&self.session_id).await.contains(&event.subdomain) {
+                        return;
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Start the live view for a session; the first redraw happens immediately
+pub fn spawn_live_view(
+    state: Arc<AppState>,
+    shared_state: Arc<Mutex<SharedHandlerState>>,
+    handle: Handle,
+    channel: ChannelId,
+    session_id: String,
+    display_name: String,
+    motd: String,
+) -> LiveViewTask {
+    let repaint = Arc::new(Notify::new());
+    let mut feed = state.requests.subscribe(&session_id);
+    let (mut events, _) = state.events.subscribe();
+    let screen = Screen {
+        state,
+        shared_state,
+        handle,
+        channel,
+        session_id,
+        display_name,
+        motd,
+    };
+
+    let notified = repaint.clone();
+    let context = CrashContext::new("ssh::live_view").session(&screen.session_id);
+    let task = spawn_with_context(context, async move {
+        let mut view = LiveView::default();
+        loop {
+            if screen.paint(&view).await.is_err() {
+                debug!("Live view for session {} closed", screen.session_id);
+                break;
+            }
+            tokio::select! {
+                _ = notified.notified() => {}
+                _ = screen.health_changed(&mut events) => {}
+                event = feed.recv() => match event {
+                    Ok(event) => view.record(event),
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+            }
+            // Fold the rest of a burst into the same redraw
+            tokio::time::sleep(REPAINT_INTERVAL).await;
+            loop {
+                match feed.try_recv() {
+                    Ok(event) => view.record(event),
+                    Err(TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+    });
+
+    LiveViewTask { repaint, task }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(path: &str, bytes_out: u64) -> RequestEvent {
+        RequestEvent {
+            subdomain: "app".to_string(),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status: Some(200),
+            duration_ms: 5,
+            bytes_in: 100,
+            bytes_out,
+        }
+    }
+
+    #[test]
+    fn test_record_keeps_newest() {
+        let mut view = LiveView::default();
+        for i in 0..MAX_ROWS + 2 {
+            view.record(event(&format!("/{}", i), 10));
+        }
+        assert_eq!(view.recent.len(), MAX_ROWS);
+        assert_eq!(view.recent[0].path, format!("/{}", MAX_ROWS + 1));
+        assert_eq!(view.requests, MAX_ROWS as u64 + 2);
+        assert_eq!((view.bytes_in, view.bytes_out), (1000, 100));
+    }
+
+    #[test]
+    fn test_rows_for_terminal() {
+        assert_eq!(rows_for(14, None), MAX_ROWS);
+        assert_eq!(rows_for(14, Some((80, 50))), MAX_ROWS);
+        assert_eq!(rows_for(14, Some((80, 24))), 3);
+        assert_eq!(rows_for(14, Some((80, 16))), 0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Keyboard menu of the interactive session.
+//!
+//! Keys typed in the session's terminal (`SshHandler::data`) go through a
+//! small decoder that tells a lone ESC apart from the escape sequences of
+//! arrow and function keys. Double ESC (or `q`) disconnects; once the
+//! tunnels are up, `r` moves them to new random subdomains, `c` shows the
+//! URLs again and copies them to the clipboard (OSC 52) and `s` turns the
+//! live request view on or off.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use russh::server::Session;
+use russh::{ChannelId, Disconnect};
+
+use crate::terminal_ui::{self, OutputMode};
+
+use super::handler::SshHandler;
+use super::types::VerificationStatus;
+
+/// How soon a second ESC must follow the first to disconnect
+const DOUBLE_ESC_WINDOW: Duration = Duration::from_secs(2);
+
+/// A key the menu acts on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKey {
+    Esc,
+    /// `r`: new random subdomains
+    Rotate,
+    /// `c`: show the URLs again and copy them
+    CopyUrl,
+    /// `s`: live request view on/off
+    ToggleLiveView,
+    /// `q`: disconnect
+    Quit,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum KeyState {
+    #[default]
+    Ground,
+    /// After an ESC: a lone ESC unless a sequence follows in the same read
+    Escape,
+    /// Inside a CSI / SS3 sequence, until its final byte
+    Sequence,
+}
+
+/// Decodes terminal input into menu keys
+#[derive(Debug, Default)]
+pub struct KeyDecoder {
+    state: KeyState,
+}
+
+impl KeyDecoder {
+    /// Feed input as read from the channel; returns the keys it completes.
+    /// Terminals send an escape sequence in one write, so an ESC ending the
+    /// input is a key of its own.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<MenuKey> {
+        let mut keys = Vec::new();
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (KeyState::Ground, 0x1b) => KeyState::Escape,
+                (KeyState::Ground, byte) => {
+                    keys.extend(match byte.to_ascii_lowercase() {
+                        b'r' => Some(MenuKey::Rotate),
+                        b'c' => Some(MenuKey::CopyUrl),
+                        b's' => Some(MenuKey::ToggleLiveView),
+                        b'q' => Some(MenuKey::Quit),
+                        _ => None,
+                    });
+                    KeyState::Ground
+                }
+                (KeyState::Escape, 0x1b) => {
+                    keys.push(MenuKey::Esc);
+                    KeyState::Escape
+                }
+                (KeyState::Escape, b'[' | b'O') => KeyState::Sequence,
+                // Alt+key
+                (KeyState::Escape, _) => KeyState::Ground,
+                (KeyState::Sequence, 0x40..=0x7e) => KeyState::Ground,
+                (KeyState::Sequence, _) => KeyState::Sequence,
+            };
+        }
+        if self.state == KeyState::Escape {
+            keys.push(MenuKey::Esc);
+            self.state = KeyState::Ground;
+        }
+        keys
+    }
+}
+
+impl SshHandler {
+    /// Act on a key typed on `channel`. Besides ESC, keys only count in the
+    /// session's terminal once its tunnels are up.
+    pub(super) async fn menu_key(
+        &self,
+        key: MenuKey,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), russh::Error> {
+        if key == MenuKey::Esc {
+            return self.esc_key(channel, session).await;
+        }
+        if self.session_channel_id != Some(channel) || self.output_mode().await != OutputMode::Tty {
+            return Ok(());
+        }
+        let verified = matches!(self.get_verification_status().await, VerificationStatus::Verified { .. });
+        if !verified || self.success_box_tunnels().await.1.is_empty() {
+            return Ok(());
+        }
+
+        match key {
+            MenuKey::Rotate => {
+                self.rotate_subdomains().await;
+                self.redraw(channel, session).await
+            }
+            MenuKey::CopyUrl => {
+                let (_, tunnels) = self.success_box_tunnels().await;
+                let copy = terminal_ui::create_clipboard_copy(&terminal_ui::clipboard_urls(&tunnels));
+                session.data(channel, copy.into_bytes().into())?;
+                self.redraw(channel, session).await
+            }
+            MenuKey::ToggleLiveView => self.toggle_live_view(channel, session).await,
+            MenuKey::Quit => {
+                info!("Quit key pressed, disconnecting...");
+                self.disconnect_by_user().await;
+                Ok(())
+            }
+            MenuKey::Esc => Ok(()),
+        }
+    }
+
+    /// First ESC shows a hint for two seconds; a second one within that
+    /// time disconnects
+    async fn esc_key(&self, channel: ChannelId, session: &mut Session) -> Result<(), russh::Error> {
+        let mut state = self.shared_state.lock().await;
+        let now = Instant::now();
+        if state.esc_pressed && state.last_esc_time.is_some_and(|last| now.duration_since(last) < DOUBLE_ESC_WINDOW) {
+            drop(state);
+            info!("Double ESC detected, disconnecting...");
+            self.disconnect_by_user().await;
+            return Ok(());
+        }
+
+        state.esc_pressed = true;
+        state.last_esc_time = Some(now);
+        let lang = state.lang();
+        drop(state);
+
+        let hint = terminal_ui::create_esc_hint(lang);
+        session.data(channel, hint.into_bytes().into())?;
+
+        let shared_state = self.shared_state.clone();
+        let handle = self.session_handle.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(DOUBLE_ESC_WINDOW).await;
+            let mut state = shared_state.lock().await;
+            if state.esc_pressed {
+                state.esc_pressed = false;
+                state.last_esc_time = None;
+                if let Some(h) = handle {
+                    let clear = terminal_ui::clear_esc_hint();
+                    let _ = h.data(channel, clear.into_bytes().into()).await;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn disconnect_by_user(&self) {
+        if let Some(handle) = &self.session_handle {
+            let _ = handle
+                .disconnect(
+                    Disconnect::ByApplication,
+                    "Disconnected by user".to_string(),
+                    "en".to_string(),
+                )
+                .await;
+        }
+    }
+
+    /// Move the session's tunnels to fresh random subdomains
+    async fn rotate_subdomains(&self) {
+        let VerificationStatus::Verified { user_id, .. } = self.get_verification_status().await else {
+            return;
+        };
+        for current in self.state.session_subdomains(&self.session_id).await {
+            let new = self.generate_subdomain().await;
+            match self.move_tunnel(&user_id, &current, &new).await {
+                Ok(_) => {
+                    info!("Rotated tunnel {} to {}", current, new);
+                    self.shared_state.lock().await.rename_tunnel(&current, &new);
+                }
+                Err(e) => warn!("Failed to rotate tunnel {}: {}", current, e),
+            }
+        }
+    }
+
+    /// Draw the success box again (the live view is repainted instead)
+    async fn redraw(&self, channel: ChannelId, session: &mut Session) -> Result<(), russh::Error> {
+        if let Some(view) = &self.shared_state.lock().await.live_view {
+            view.repaint();
+            return Ok(());
+        }
+        if let Some(screen) = self.redrawn_screen().await {
+            session.data(channel, screen.into_bytes().into())?;
+        }
+        Ok(())
+    }
+
+    async fn toggle_live_view(&self, channel: ChannelId, session: &mut Session) -> Result<(), russh::Error> {
+        let (enabled, stopped) = {
+            let mut shared = self.shared_state.lock().await;
+            shared.live_view_enabled = !shared.live_view_enabled;
+            (shared.live_view_enabled, shared.live_view.take())
+        };
+        info!("Live request view turned {} by key", if enabled { "on" } else { "off" });
+        if let Some(view) = stopped {
+            view.stop();
+        }
+        if let Some(handle) = &self.session_handle {
+            if self.show_live_view(handle, channel).await {
+                return Ok(());
+            }
+        }
+        self.redraw(channel, session).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_decoder() {
+        let mut keys = KeyDecoder::default();
+        assert_eq!(keys.feed(b"\x1b"), vec![MenuKey::Esc]);
+        assert_eq!(keys.feed(b"\x1b\x1b"), vec![MenuKey::Esc, MenuKey::Esc]);
+        let letters = vec![MenuKey::Rotate, MenuKey::CopyUrl, MenuKey::ToggleLiveView, MenuKey::Quit];
+        assert_eq!(keys.feed(b"rCsQx"), letters);
+        // Arrow and function keys aren't an ESC, nor are their letters keys
+        assert!(keys.feed(b"\x1b[A\x1bOP\x1b[15~").is_empty());
+        assert!(keys.feed(b"\x1b[1;5").is_empty());
+        assert_eq!(keys.feed(b"Cq"), vec![MenuKey::Quit]);
+        // Alt+r
+        assert!(keys.feed(b"\x1br").is_empty());
+    }
+}
@@ -1,11 +1,32 @@
 //! SSH server module.
 
+mod certs;
+mod confirm;
+mod control;
+mod exec;
 mod handler;
 mod handler_impl;
+mod health_check;
+mod idle;
+mod keepalive;
+mod live_view;
+mod menu;
+mod port_forward;
+mod resume;
+mod secret_path;
 mod server;
+mod speedtest;
+mod status_alert;
+mod takeover;
 mod tunnel;
 mod types;
 mod verification;
 
+pub use certs::{parse_trusted_ca_keys, verify_user_certificate, CertifiedUser};
 pub use handler::SshHandler;
+pub use health_check::{check_local_services, notify_local_health};
+pub use idle::reap_idle_tunnels;
+pub use keepalive::{is_session_gone, mark_session_dead, ping_sessions};
 pub use server::TunnelServer;
+pub use status_alert::notify_status_alert;
+pub use types::{generate_secure_subdomain_id, is_valid_subdomain};
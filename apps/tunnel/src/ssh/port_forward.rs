@@ -0,0 +1,154 @@
+//! Dedicated TCP and UDP port forwards of a session.
+//!
+//! A forward with `tcp` or `udp` as its bind address gets a public port of
+//! its own (see `state::dedicated_ports`) instead of a subdomain. The port is
+//! bound when the forward is requested, so the reply can name it, and starts
+//! serving once the session is verified; until then it waits with the
+//! session's other pending forwards.
+
+use std::sync::Arc;
+
+use log::{info, warn};
+use russh::server::{Handle, Session};
+use tokio::sync::Mutex;
+
+use crate::acl::Capability;
+use crate::proxy::tcp::spawn_tcp_forward;
+use crate::proxy::udp::spawn_udp_forward;
+use crate::state::dedicated_ports::{BoundSocket, Transport};
+use crate::state::AppState;
+use crate::terminal_ui::{self, OutputMode, SessionEvent};
+
+use super::handler::SshHandler;
+use super::types::{PendingTunnel, SharedHandlerState, VerificationStatus};
+
+/// Serve a port bound for the session. False if it isn't bound (anymore).
+fn start_port_forward(
+    state: &Arc<AppState>,
+    handle: &Handle,
+    session_id: &str,
+    address: &str,
+    transport: Transport,
+    port: u32,
+    user_id: &str,
+) -> bool {
+    let Ok(port) = u16::try_from(port) else {
+        return false;
+    };
+    // Channels echo the bind address as the client sent it
+    let address = address.to_string();
+    let started = state.dedicated_ports.start(transport, session_id, port, user_id, |socket| match socket {
+        BoundSocket::Tcp(listener) => spawn_tcp_forward(state.clone(), listener, handle.clone(), address, port),
+        BoundSocket::Udp(socket) => spawn_udp_forward(state.clone(), socket, handle.clone(), address, port),
+    });
+    if started {
+        info!("{} port {} of user {} is served", transport.label(), port, user_id);
+    }
+    started
+}
+
+/// The notice for a port that is served
+fn port_notice(shared: &SharedHandlerState, transport: Transport, port: u32) -> String {
+    match shared.output_mode() {
+        OutputMode::Tty => terminal_ui::create_dedicated_port_notice(shared.ui().lang, transport, port),
+        mode => SessionEvent::DedicatedPort { transport, port }.render(mode),
+    }
+}
+
+/// Start the dedicated ports requested before verification. Returns the
+/// notices for the ports now served and the ports the user's tier doesn't
+/// allow (which are closed).
+pub(super) async fn start_pending_port_forwards(
+    pending: Vec<PendingTunnel>,
+    state: &Arc<AppState>,
+    shared_state: &Mutex<SharedHandlerState>,
+    handle: &Handle,
+    user_id: &str,
+) -> (String, Vec<(u32, Capability)>) {
+    let shared = shared_state.lock().await;
+    let mut notices = String::new();
+    let mut denied = Vec::new();
+    for forward in pending {
+        let Some(transport) = Transport::of_forward(&forward.address) else {
+            continue;
+        };
+        let capability = transport.capability();
+        if !shared.capabilities().allows(capability) {
+            warn!(
+                "Refusing {} port {}: missing capability {}",
+                transport.label(),
+                forward.port,
+                capability.as_str()
+            );
+            state.dedicated_ports.release(transport, &shared.session_id, forward.port as u16);
+            denied.push((forward.port, capability));
+        } else if start_port_forward(
+            state,
+            handle,
+            &shared.session_id,
+            &forward.address,
+            transport,
+            forward.port,
+            user_id,
+        ) {
+            notices.push_str(&port_notice(&shared, transport, forward.port));
+        }
+    }
+    (notices, denied)
+}
+
+impl SshHandler {
+    /// Bind the port of a `-R tcp:<port>:...` or `-R udp:<port>:...` forward,
+    /// replacing `port` with the one bound, and serve it right away if the
+    /// session is verified (otherwise it is kept pending by the caller).
+    /// Returns false if the forward is refused.
+    pub(super) async fn port_forward(
+        &self,
+        address: &str,
+        transport: Transport,
+        port: &mut u32,
+        session: &mut Session,
+    ) -> bool {
+        let verified = match self.get_verification_status().await {
+            VerificationStatus::Verified { user_id, .. } => Some(user_id),
+            _ => None,
+        };
+        let capability = transport.capability();
+        let allowed = self.shared_state.lock().await.capabilities().allows(capability);
+        if verified.is_some() && !allowed {
+            let reason = terminal_ui::capability_denied_reason(capability);
+            let notice = match self.output_mode().await {
+                OutputMode::Tty => terminal_ui::create_capability_denied_box(self.ui().await, capability, *port),
+                mode => SessionEvent::Refused { port: *port, reason: &reason }.render(mode),
+            };
+            self.send_notice(session, notice, &format!("port {} refused: {}", port, reason))
+                .await;
+            return false;
+        }
+
+        let bound = match self.state.dedicated_ports.allocate(transport, &self.session_id, *port).await {
+            Ok(bound) => bound,
+            Err(reason) => {
+                warn!("Refusing {} port {}: {}", transport.label(), port, reason);
+                let notice = match self.output_mode().await {
+                    OutputMode::Tty => terminal_ui::create_forward_refused_box(self.ui().await, *port, &reason),
+                    mode => SessionEvent::Refused { port: *port, reason: &reason }.render(mode),
+                };
+                self.send_notice(session, notice, &format!("port {} refused: {}", port, reason))
+                    .await;
+                return false;
+            }
+        };
+        *port = bound as u32;
+
+        if let (Some(user_id), Some(handle)) = (verified, &self.session_handle) {
+            let started =
+                start_port_forward(&self.state, handle, &self.session_id, address, transport, *port, &user_id);
+            if let Some(channel) = self.session_channel_id.filter(|_| started) {
+                let notice = port_notice(&*self.shared_state.lock().await, transport, *port);
+                let _ = session.data(channel, notice.into_bytes().into());
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,87 @@
+//! Session resumption: `resume-<token>` usernames and the tokens handed out
+//! once a session's tunnels are up (see `state::resume_tokens`).
+//!
+//! Tokens only go to scripted clients (`EXLO_OUTPUT=json` or `plain`), which
+//! can keep the latest one and present it when they reconnect; a terminal
+//! user reconnects with their verified key as before.
+
+use log::{info, warn};
+
+use crate::config;
+use crate::state::hooks::VerificationMethod;
+use crate::state::resume_tokens::ResumeGrant;
+use crate::state::AppState;
+use crate::terminal_ui::{OutputMode, SessionEvent};
+
+use super::handler::SshHandler;
+use super::types::{SharedHandlerState, VerificationStatus};
+
+impl SshHandler {
+    /// Verify the session with a resumption token presented with the key
+    /// `fingerprint`: same user, tier and profile, and its forwards get the
+    /// previous subdomains back. Returns false, after a strike, if the token
+    /// isn't valid for that key.
+    pub(super) async fn resume_session(&self, token: &str, fingerprint: &str) -> bool {
+        let Some(grant) = self.state.resume_tokens.redeem(token, fingerprint) else {
+            warn!("Rejected invalid resumption token for key {}", fingerprint);
+            self.report_verification(VerificationMethod::ResumeToken, Err("invalid resumption token".to_string()));
+            self.strike("invalid resumption token").await;
+            return false;
+        };
+        info!(
+            "Resuming session {} for user '{}', subdomains={:?}, skipping Device Flow",
+            grant.session_id, grant.user_id, grant.subdomains
+        );
+        self.report_verification(VerificationMethod::ResumeToken, Ok(grant.user_id.clone()));
+        let mut state = self.shared_state.lock().await;
+        state.verification_status = VerificationStatus::Verified {
+            user_id: grant.user_id,
+            display_name: grant.display_name,
+        };
+        state.tier = grant.tier;
+        state.last_subdomains = grant.subdomains;
+        state.perf_profile = state.perf_profile.or(grant.perf_profile);
+        state.resumed_session = Some(grant.session_id);
+        true
+    }
+
+    /// Append a fresh resumption token, if the session gets one
+    pub(super) async fn append_resume_token(&self, message: &mut String) {
+        let shared = self.shared_state.lock().await;
+        let fingerprint = self.public_key_fingerprint.as_deref();
+        if let Some(notice) = resume_token_notice(&self.state, &shared, fingerprint) {
+            message.push_str(&notice);
+        }
+    }
+}
+
+/// Issue a token for the session's current tunnels and render it. None in a
+/// terminal, with `RESUME_TOKEN_TTL=0`, or before the session is verified
+/// with a key.
+pub(super) fn resume_token_notice(
+    state: &AppState,
+    shared: &SharedHandlerState,
+    fingerprint: Option<&str>,
+) -> Option<String> {
+    let mode = shared.output_mode();
+    if mode == OutputMode::Tty {
+        return None;
+    }
+    let ttl = config::get().resume_token_ttl?;
+    let fingerprint = fingerprint?;
+    let VerificationStatus::Verified { user_id, display_name } = &shared.verification_status else {
+        return None;
+    };
+
+    let grant = ResumeGrant {
+        user_id: user_id.clone(),
+        display_name: display_name.clone(),
+        tier: shared.tier.clone(),
+        subdomains: shared.tunnel_ports.iter().map(|(subdomain, port)| (*port, subdomain.clone())).collect(),
+        perf_profile: shared.perf_profile,
+        fingerprint: fingerprint.to_string(),
+        session_id: shared.session_id.clone(),
+    };
+    let token = state.resume_tokens.issue(grant, ttl);
+    Some(SessionEvent::ResumeToken { token: &token, ttl }.render(mode))
+}
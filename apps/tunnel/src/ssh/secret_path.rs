@@ -0,0 +1,64 @@
+//! Secret paths (`EXLO_SECRET_PATH=on`): the session's tunnels only answer
+//! visitors who bring a random token (see `proxy::secret_path`).
+//!
+//! OpenSSH sends the variable after its forwards, so turning it on also
+//! gives the session's already registered tunnels a token; later ones get
+//! theirs when they register. The token is shown next to the tunnel URL.
+
+use log::{info, warn};
+
+use crate::proxy::secret_path::generate_path_token;
+use crate::state::AppState;
+use crate::terminal_ui::{self, Lang, OutputMode, SessionEvent};
+
+use super::handler::SshHandler;
+
+impl SshHandler {
+    /// Require secret path tokens for the session's tunnels
+    pub(super) async fn enable_secret_paths(&self) {
+        let session_id = {
+            let mut shared = self.shared_state.lock().await;
+            shared.secret_path = true;
+            shared.session_id.clone()
+        };
+        info!("Session {} requested secret paths", session_id);
+        for subdomain in self.state.session_subdomains(&session_id).await {
+            let has_token = self.state.get_tunnel(&subdomain).await.is_some_and(|t| t.secret_path.is_some());
+            if has_token {
+                continue;
+            }
+            if let Err(e) = self.state.set_secret_path(&subdomain, generate_path_token()).await {
+                warn!("Failed to set secret path of tunnel {}: {}", subdomain, e);
+            }
+        }
+    }
+
+    /// Append the secret URLs of `tunnels`
+    pub(super) async fn append_secret_paths(&self, message: &mut String, tunnels: &[(String, u32)]) {
+        let (mode, lang) = {
+            let shared = self.shared_state.lock().await;
+            (shared.output_mode(), shared.ui().lang)
+        };
+        message.push_str(&secret_path_notices(&self.state, tunnels, mode, lang).await);
+    }
+}
+
+/// Secret URL notices for those of `tunnels` that require a token
+pub(super) async fn secret_path_notices(
+    state: &AppState,
+    tunnels: &[(String, u32)],
+    mode: OutputMode,
+    lang: Lang,
+) -> String {
+    let mut notices = String::new();
+    for (subdomain, _) in tunnels {
+        let Some(token) = state.get_tunnel(subdomain).await.and_then(|t| t.secret_path.clone()) else {
+            continue;
+        };
+        match mode {
+            OutputMode::Tty => notices.push_str(&terminal_ui::create_secret_path_notice(lang, subdomain, &token)),
+            mode => notices.push_str(&SessionEvent::SecretPath { subdomain, token: &token }.render(mode)),
+        }
+    }
+    notices
+}
@@ -3,27 +3,85 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use log::{error, info};
-use russh::server::{Handler, Server};
+use log::{debug, error, info};
+use russh::server::{Config, Handler, Server};
 
 use super::SshHandler;
-use crate::device::DeviceFlowClient;
+use crate::accept::{bind_listener, ConnectionLimiter};
+use crate::config::get as get_config;
+use crate::device::AuthProvider;
 use crate::state::AppState;
 
 /// The main SSH server that creates handlers for each connection.
 #[derive(Clone)]
 pub struct TunnelServer {
     state: Arc<AppState>,
-    device_flow_client: Arc<DeviceFlowClient>,
+    device_flow_client: Arc<dyn AuthProvider>,
 }
 
 impl TunnelServer {
-    pub fn new(state: Arc<AppState>, device_flow_client: Arc<DeviceFlowClient>) -> Self {
+    pub fn new(state: Arc<AppState>, device_flow_client: Arc<dyn AuthProvider>) -> Self {
         Self {
             state,
             device_flow_client,
         }
     }
+
+    /// Accept SSH connections on `addr`, pausing accept while the
+    /// in-flight connection limit is reached. `config` builds the session
+    /// configuration for the host keys currently served.
+    pub async fn run(
+        mut self,
+        config: impl Fn(Vec<russh_keys::PrivateKey>) -> Config,
+        addr: &str,
+    ) -> anyhow::Result<()> {
+        let app_config = get_config();
+        let listener = bind_listener(addr, app_config.accept_backlog, app_config.reuse_port).await?;
+        let limiter = ConnectionLimiter::new("SSH", app_config.max_ssh_connections);
+        self.state.readiness.set_ssh_listening();
+        info!(
+            "SSH server listening on {} (max {} connections)",
+            addr, app_config.max_ssh_connections
+        );
+
+        // Configuration for the keys of a host key generation
+        let mut current: Option<(u64, Arc<Config>)> = None;
+        loop {
+            let (stream, peer_addr, permit) = limiter.accept(&listener).await;
+            if let Some(ban) = self.state.bans.is_banned(peer_addr.ip()).await {
+                debug!("Dropping SSH connection from banned IP {} ({})", peer_addr, ban.reason);
+                continue;
+            }
+            let _ = stream.set_nodelay(true);
+            let handler = self.new_client(Some(peer_addr));
+            let generation = self.state.host_keys.generation();
+            let config = match &current {
+                Some((served, config)) if *served == generation => config.clone(),
+                _ => {
+                    let built = Arc::new(config(self.state.host_keys.served()));
+                    current = Some((generation, built.clone()));
+                    built
+                }
+            };
+            let state = self.state.clone();
+
+            tokio::spawn(async move {
+                // Released when the session ends
+                let _permit = permit;
+                if !state.reputation.admit(peer_addr.ip(), "SSH").await {
+                    return;
+                }
+                let result = match russh::server::run_stream(config, stream, handler).await {
+                    Ok(session) => session.await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => debug!("SSH session from {} closed", peer_addr),
+                    Err(e) => error!("Session error: {:?}", e),
+                }
+            });
+        }
+    }
 }
 
 impl Server for TunnelServer {
@@ -0,0 +1,161 @@
+//! `speedtest` exec command: measure the link between client and server.
+//!
+//! The test runs on the exec channel it was started on, so it tells tunnel
+//! capacity apart from the local app. Round-trip time comes from session
+//! channels the server asks to open and the client refuses (one protocol round
+//! trip, no payload). Upload counts what the client pipes into ssh, from the
+//! first chunk until EOF or `TRANSFER_BYTES`; download streams `TRANSFER_BYTES`
+//! to stdout, paced by the client's channel window. The report goes to stderr:
+//!
+//! ```text
+//! head -c 8M /dev/zero | ssh -p 2222 server speedtest > /dev/null
+//! ```
+
+use std::time::{Duration, Instant};
+
+use log::info;
+use russh::server::{Handle, Msg};
+use russh::Channel;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use crate::terminal_ui::{self, Ui};
+
+/// Bytes moved in each direction
+pub const TRANSFER_BYTES: usize = 8 * 1024 * 1024;
+
+/// Round-trip samples taken (the median is reported)
+const RTT_PROBES: usize = 5;
+
+/// How long a single round-trip probe may take
+const RTT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the client to start piping upload data
+const UPLOAD_START_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Longest a transfer may take before it is reported as failed
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upload data as it arrives on the speed test channel: (arrival, bytes)
+pub type UploadSender = mpsc::UnboundedSender<(Instant, usize)>;
+pub type UploadReceiver = mpsc::UnboundedReceiver<(Instant, usize)>;
+
+/// Bytes moved in one direction and how long it took
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+/// Middle sample, or None without samples
+fn median(mut samples: Vec<Duration>) -> Option<Duration> {
+    samples.sort();
+    samples.get(samples.len() / 2).copied()
+}
+
+async fn measure_rtt(handle: &Handle) -> Option<Duration> {
+    let mut samples = Vec::new();
+    for _ in 0..RTT_PROBES {
+        let start = Instant::now();
+        match timeout(RTT_TIMEOUT, handle.channel_open_session()).await {
+            // A client that accepts still answered within one round trip
+            Ok(Ok(channel)) => {
+                samples.push(start.elapsed());
+                let _ = channel.close().await;
+            }
+            Ok(Err(russh::Error::ChannelOpenFailure(_))) => samples.push(start.elapsed()),
+            _ => break,
+        }
+    }
+    median(samples)
+}
+
+/// Time from the first upload chunk to EOF (or `TRANSFER_BYTES`). The first
+/// chunk only starts the clock since its transfer time is unknown.
+async fn measure_upload(mut upload: UploadReceiver) -> Option<Transfer> {
+    let (start, _) = timeout(UPLOAD_START_TIMEOUT, upload.recv()).await.ok()??;
+    let deadline = tokio::time::Instant::now() + TRANSFER_TIMEOUT;
+    let mut bytes = 0;
+    let mut end = start;
+    while bytes < TRANSFER_BYTES {
+        match tokio::time::timeout_at(deadline, upload.recv()).await {
+            Ok(Some((arrived, len))) => {
+                bytes += len;
+                end = arrived;
+            }
+            Ok(None) => break,
+            Err(_) => return None,
+        }
+    }
+    (bytes > 0).then(|| Transfer {
+        bytes: bytes as u64,
+        elapsed: end.duration_since(start),
+    })
+}
+
+async fn measure_download(channel: &Channel<Msg>) -> Option<Transfer> {
+    let payload = vec![0u8; TRANSFER_BYTES];
+    let start = Instant::now();
+    match timeout(TRANSFER_TIMEOUT, channel.data(&payload[..])).await {
+        Ok(Ok(())) => Some(Transfer {
+            bytes: TRANSFER_BYTES as u64,
+            elapsed: start.elapsed(),
+        }),
+        _ => None,
+    }
+}
+
+/// Run the test on `channel`, write the report and close the channel
+pub async fn run_speedtest(handle: Handle, channel: Channel<Msg>, upload: UploadReceiver, ui: Ui) {
+    let id = channel.id();
+    let rtt = measure_rtt(&handle).await;
+    let upload = measure_upload(upload).await;
+    let download = measure_download(&channel).await;
+    info!(
+        "Speed test on channel {:?}: rtt {:?}, upload {:?}, download {:?}",
+        id, rtt, upload, download
+    );
+
+    let report = terminal_ui::create_speedtest_box(
+        ui,
+        rtt,
+        upload.map(|t| (t.bytes, t.elapsed)),
+        download.map(|t| (t.bytes, t.elapsed)),
+    );
+    // Extended data type 1 is stderr, so the report survives `> /dev/null`
+    let _ = handle.extended_data(id, 1, report.into_bytes().into()).await;
+    let exit_status = if download.is_some() { 0 } else { 1 };
+    let _ = handle.exit_status_request(id, exit_status).await;
+    let _ = handle.eof(id).await;
+    let _ = handle.close(id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median() {
+        let ms = Duration::from_millis;
+        assert_eq!(median(vec![ms(30), ms(10), ms(20)]), Some(ms(20)));
+        assert_eq!(median(Vec::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_measure_upload() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let start = Instant::now();
+        tx.send((start, 1024)).unwrap();
+        tx.send((start + Duration::from_millis(500), 2048)).unwrap();
+        tx.send((start + Duration::from_secs(1), 2048)).unwrap();
+        drop(tx);
+        let transfer = measure_upload(rx).await.unwrap();
+        // The first chunk only starts the clock
+        assert_eq!(transfer.bytes, 4096);
+        assert_eq!(transfer.elapsed, Duration::from_secs(1));
+
+        let (tx, rx) = mpsc::unbounded_channel::<(Instant, usize)>();
+        drop(tx);
+        assert_eq!(measure_upload(rx).await, None);
+    }
+}
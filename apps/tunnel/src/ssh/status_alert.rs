@@ -0,0 +1,75 @@
+//! Telling tunnel owners about their 5xx alerts.
+//!
+//! A firing or resolved alert (see `state::status_alerts`) is written to the
+//! session holding the tunnel and, with `STATUS_ALERT_WEBHOOK_URL` set, posted
+//! as JSON so the web app can forward it.
+
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_json::json;
+
+use crate::config::{get as get_config, get_tunnel_url};
+use crate::state::status_alerts::AlertChange;
+use crate::state::TunnelInfo;
+use crate::terminal_ui::{self, OutputMode, SessionEvent};
+
+/// Timeout for posting an alert to the webhook
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Notify the owner of `tunnel` in the background
+pub fn notify_status_alert(tunnel: TunnelInfo, change: AlertChange) {
+    info!(
+        "Status alert of tunnel {} {}: {:.1}% 5xx in {} responses",
+        tunnel.subdomain,
+        if change.firing { "firing" } else { "resolved" },
+        change.error_percent,
+        change.requests
+    );
+    tokio::spawn(async move {
+        if let Some(channel_id) = tunnel.session_channel_id.filter(|_| tunnel.is_connected) {
+            let notice = match tunnel.output_mode {
+                OutputMode::Tty => terminal_ui::create_status_alert_box(
+                    tunnel.ui,
+                    &tunnel.subdomain,
+                    change.firing,
+                    change.error_percent,
+                    change.window,
+                ),
+                mode => SessionEvent::StatusAlert {
+                    subdomain: &tunnel.subdomain,
+                    firing: change.firing,
+                    error_percent: change.error_percent,
+                    window: change.window,
+                }
+                .render(mode),
+            };
+            let _ = tunnel.handle.data(channel_id, notice.into_bytes().into()).await;
+        }
+
+        let Some(url) = get_config().status_alert_webhook_url.as_deref() else {
+            return;
+        };
+        let payload = json!({
+            "event": "status_alert",
+            "state": if change.firing { "firing" } else { "resolved" },
+            "subdomain": tunnel.subdomain,
+            "url": get_tunnel_url(&tunnel.subdomain),
+            "user_id": tunnel.username,
+            "error_percent": change.error_percent,
+            "requests": change.requests,
+            "window_secs": change.window.as_secs(),
+            "threshold_percent": tunnel.status_alert.as_ref().map(|a| a.threshold_percent),
+        });
+        let result = reqwest::Client::new()
+            .post(url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            warn!("Failed to post status alert for {}: {}", tunnel.subdomain, e);
+        }
+    });
+}
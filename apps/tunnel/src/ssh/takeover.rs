@@ -0,0 +1,66 @@
+//! Session takeover: a key reconnecting while its earlier session still
+//! holds the subdomain.
+//!
+//! With `SESSION_TAKEOVER=takeover` (the default) the new session's tunnel
+//! replaces the old one in the registry in one step (see
+//! `AppState::take_over_tunnel`), the old session is told and disconnected,
+//! and the new one is told where the tunnel came from. With `reject` the new
+//! forward is refused as a taken subdomain until the old session ends.
+
+use std::sync::Arc;
+
+use log::{info, warn};
+use russh::Disconnect;
+
+use crate::state::{AppState, TunnelInfo};
+use crate::terminal_ui::{self, OutputMode, SessionEvent};
+
+use super::handler::SshHandler;
+use super::idle::NOTICE_GRACE;
+
+/// Tell the session that held `old` that a new connection from `by` took it
+/// over, then disconnect it
+pub(super) fn disconnect_taken_over(state: &AppState, old: Arc<TunnelInfo>, by: &str) {
+    info!(
+        "Disconnecting session {}: tunnel {} taken over by a connection from {}",
+        old.session_id, old.subdomain, by
+    );
+    let by = by.to_string();
+    state.cleanup.spawn("takeover_disconnect", async move {
+        if let Some(channel_id) = old.session_channel_id {
+            let notice = match old.output_mode {
+                OutputMode::Tty => terminal_ui::create_taken_over_box(old.ui, &old.subdomain, &by),
+                mode => SessionEvent::TakenOver { subdomain: &old.subdomain, by: &by }.render(mode),
+            };
+            let _ = old.handle.data(channel_id, notice.into_bytes().into()).await;
+            tokio::time::sleep(NOTICE_GRACE).await;
+        }
+        if let Err(e) = old
+            .handle
+            .disconnect(
+                Disconnect::ByApplication,
+                format!("Tunnel {} taken over by a new connection", old.subdomain),
+                "en".to_string(),
+            )
+            .await
+        {
+            warn!("Failed to disconnect taken-over session: {:?}", e);
+        }
+    });
+}
+
+impl SshHandler {
+    /// Append notices of tunnels taken over since the last message
+    pub(super) async fn append_takeovers(&self, message: &mut String) {
+        let (takeovers, mode, ui) = {
+            let mut shared = self.shared_state.lock().await;
+            (std::mem::take(&mut shared.takeovers), shared.output_mode(), shared.ui())
+        };
+        for (subdomain, from) in &takeovers {
+            match mode {
+                OutputMode::Tty => message.push_str(&terminal_ui::create_took_over_notice(ui.lang, subdomain, from)),
+                mode => message.push_str(&SessionEvent::TookOver { subdomain, from }.render(mode)),
+            }
+        }
+    }
+}
@@ -7,21 +7,41 @@ use log::{error, info, warn};
 use russh::server::Handle;
 use tokio::sync::Mutex;
 
-use crate::config::get_tunnel_url;
+use crate::acl::Capability;
+use crate::config::{get_tunnel_url, SessionTakeover};
 use crate::error::TunnelError;
-use crate::state::{AppState, TunnelInfo};
+use crate::proxy::secret_path::generate_path_token;
+use crate::state::header_rules::HeaderRules;
+use crate::state::{
+    generate_correlation_id, is_forward_label, AppState, NamedForward, SharedTraffic, TunnelInfo,
+};
 
-use super::types::{SharedHandlerState, VerificationStatus};
+use super::takeover::disconnect_taken_over;
+use super::types::{port_subdomain, SharedHandlerState, VerificationStatus};
 
 /// Result of tunnel creation
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct CreateTunnelResult {
     /// Whether the tunnel was created successfully
     pub success: bool,
-    /// The subdomain that caused the conflict (for subdomain taken errors)
-    pub conflicting_subdomain: Option<String>,
+    /// Why the subdomain couldn't be had (`SubdomainTaken` or `SubdomainReserved`)
+    pub conflict: Option<TunnelError>,
     /// Whether the conflict is from an explicit subdomain (should disconnect) or fallback (use random)
     pub is_explicit_conflict: bool,
+    /// The user's tier doesn't allow this tunnel
+    pub missing_capability: Option<Capability>,
+}
+
+/// Capability a further forward needs that the session's user lacks
+pub(super) fn missing_capability(state: &SharedHandlerState) -> Option<Capability> {
+    let capabilities = state.capabilities();
+    if state.requested_subdomain.is_some() && !capabilities.allows(Capability::CustomSubdomain) {
+        return Some(Capability::CustomSubdomain);
+    }
+    if !state.registered_subdomains.is_empty() && !capabilities.allows(Capability::MultipleTunnels) {
+        return Some(Capability::MultipleTunnels);
+    }
+    None
 }
 
 /// Create a tunnel after verification
@@ -42,18 +62,30 @@ pub async fn create_tunnel(
             error!("No session handle available!");
             return Ok(CreateTunnelResult {
                 success: false,
-                conflicting_subdomain: None,
+                conflict: None,
                 is_explicit_conflict: false,
+                missing_capability: None,
             });
         }
     };
 
+    let missing = missing_capability(&*shared_state.lock().await);
+    if let Some(capability) = missing {
+        warn!("Refusing forward for port {}: missing capability {}", port, capability.as_str());
+        return Ok(CreateTunnelResult {
+            success: false,
+            conflict: None,
+            is_explicit_conflict: false,
+            missing_capability: Some(capability),
+        });
+    }
+
     // Priority: 
     // 1. requested_subdomain (from username, strict - disconnect on conflict)
     //    - If username matches last_subdomain for this port, treat as reconnection
     // 2. last_subdomains (reconnection for "." username within 30min TTL)
     // 3. generate new random subdomain (when username is "." and no previous subdomain)
-    let (subdomain, is_reconnect) = {
+    let (mut subdomain, mut is_reconnect) = {
         let state = shared_state.lock().await;
         if let Some(ref requested) = state.requested_subdomain {
             // User explicitly specified a subdomain via username
@@ -84,13 +116,91 @@ pub async fn create_tunnel(
         state.requested_subdomain.is_some()
     };
 
-    // If reconnecting, remove the old tunnel first (stale from previous session)
+    // A further forward for a subdomain this session already holds becomes a
+    // named upstream of that tunnel (path-routing mode) if it carries a label,
+    // otherwise it gets a subdomain of its own
+    let held_by_session = shared_state
+        .lock()
+        .await
+        .registered_subdomains
+        .contains(&subdomain);
+    if held_by_session && !is_forward_label(address) {
+        let Some(own) = port_subdomain(&subdomain, port) else {
+            warn!("No valid subdomain for additional forward {} on {}", port, subdomain);
+            return Ok(CreateTunnelResult {
+                success: false,
+                conflict: None,
+                is_explicit_conflict: false,
+                missing_capability: None,
+            });
+        };
+        info!("Additional forward for port {} gets its own subdomain: {}", port, own);
+        is_reconnect = shared_state.lock().await.last_subdomains.get(&port) == Some(&own);
+        subdomain = own;
+    } else if held_by_session {
+        let forward = NamedForward::new(address, port);
+        return match app_state.add_forward(&subdomain, forward).await {
+            Ok(()) => Ok(CreateTunnelResult {
+                success: true,
+                conflict: None,
+                is_explicit_conflict: false,
+                missing_capability: None,
+            }),
+            Err(e) => {
+                warn!("Failed to add forward to {}: {}", subdomain, e);
+                Ok(CreateTunnelResult {
+                    success: false,
+                    conflict: None,
+                    is_explicit_conflict: false,
+                    missing_capability: None,
+                })
+            }
+        };
+    }
+
+    // If reconnecting, the old tunnel (from the previous session) is replaced
+    // on registration, keeping its share secret and secret path so shared
+    // links and passwords stay valid, and its correlation ID so the web
+    // backend record still joins
+    // (a profile picked with the exec command also stays unless the new
+    // username names one)
+    let mut share_secret = None;
+    let mut secret_path = None;
+    let mut correlation_id = None;
+    let mut preview_banner = false;
+    let mut response_cache = false;
+    let mut response_headers = HeaderRules::default();
+    let mut perf_profile = shared_state.lock().await.perf_profile;
+    let (own_session, resumed_session) = {
+        let state = shared_state.lock().await;
+        (state.session_id.clone(), state.resumed_session.clone())
+    };
     if is_reconnect {
-        if let Ok(old_info) = app_state.remove_tunnel(&subdomain).await {
+        if let Some(old_info) = app_state.get_tunnel(&subdomain).await {
             info!(
-                "Removed stale tunnel for reconnection: {} (was from {})",
-                subdomain, old_info.client_ip
+                "Reconnecting tunnel {} (was from {}, session {})",
+                subdomain, old_info.client_ip, old_info.session_id
             );
+            // The earlier session is still up: SESSION_TAKEOVER decides,
+            // unless this session resumed that one with its token
+            let still_held = old_info.is_connected && old_info.session_id != own_session;
+            let resumed = resumed_session.as_ref() == Some(&old_info.session_id);
+            if still_held && !resumed && crate::config::get().session_takeover == SessionTakeover::Reject {
+                warn!("Refusing tunnel {}: still held by session {}", subdomain, old_info.session_id);
+                return Ok(CreateTunnelResult {
+                    success: false,
+                    conflict: Some(TunnelError::SubdomainTaken(subdomain)),
+                    is_explicit_conflict: true,
+                    missing_capability: None,
+                });
+            }
+            share_secret = old_info.share_secret.clone();
+            secret_path = old_info.secret_path.clone();
+            correlation_id = Some(old_info.correlation_id.clone());
+            preview_banner = old_info.preview_banner;
+            response_cache = old_info.response_cache;
+            response_headers = old_info.response_headers.clone();
+            perf_profile = perf_profile.or(old_info.perf_profile);
         }
     }
 
@@ -105,6 +215,13 @@ pub async fn create_tunnel(
     let client_ip = peer_addr
         .map(|addr| addr.ip().to_string())
         .unwrap_or_else(|| "unknown".to_string());
+    let (session_channel_id, session_id) = {
+        let state = shared_state.lock().await;
+        (state.session_channel_id, state.session_id.clone())
+    };
+    if secret_path.is_none() && shared_state.lock().await.secret_path {
+        secret_path = Some(generate_path_token());
+    }
 
     let tunnel_info = TunnelInfo {
         subdomain: subdomain.clone(),
@@ -114,13 +231,46 @@ pub async fn create_tunnel(
         server_port: 80,
         created_at: SystemTime::now(),
         username: tunnel_username,
-        client_ip,
+        client_ip: client_ip.clone(),
         is_connected: true,
         disconnected_at: None,
+        node_id: crate::config::get().node_id.clone(),
+        forwards: Vec::new(),
+        awaiting_local_service: false,
+        degraded_since: None,
+        traffic: SharedTraffic::default(),
+        session_channel_id,
+        session_id,
+        share_secret,
+        secret_path,
+        correlation_id: correlation_id.unwrap_or_else(generate_correlation_id),
+        preview_banner,
+        response_cache,
+        response_headers,
+        capabilities: shared_state.lock().await.capabilities(),
+        tier: shared_state.lock().await.tier.clone(),
+        output_mode: shared_state.lock().await.output_mode(),
+        ui: shared_state.lock().await.ui(),
+        status_alert: None,
+        perf_profile,
+        control_channel_id: shared_state.lock().await.control_channel_id,
     };
 
-    match app_state.register_tunnel(tunnel_info).await {
-        Ok(()) => {
+    let registered = if is_reconnect {
+        app_state.take_over_tunnel(tunnel_info).await
+    } else {
+        app_state.register_tunnel(tunnel_info).await.map(|()| None)
+    };
+    match registered {
+        Ok(replaced) => {
+            if let Some(old) = replaced.filter(|old| old.is_connected && old.session_id != own_session) {
+                shared_state
+                    .lock()
+                    .await
+                    .takeovers
+                    .push((subdomain.clone(), old.client_ip.clone()));
+                disconnect_taken_over(app_state, old, &client_ip);
+            }
             let tunnel_url = get_tunnel_url(&subdomain);
             info!(
                 "✓ Tunnel registered!\n\
@@ -128,27 +278,17 @@ pub async fn create_tunnel(
                  URL: {}",
                 subdomain, tunnel_url
             );
-            shared_state
-                .lock()
-                .await
-                .registered_subdomains
-                .push(subdomain.clone());
-            // Store subdomain by port for future reconnections
-            shared_state
-                .lock()
-                .await
-                .last_subdomains
-                .insert(port, subdomain.clone());
+            shared_state.lock().await.record_tunnel(&subdomain, port);
             
             // Save to verified_key for persistence across sessions
             if let Some(fingerprint) = public_key_fingerprint {
-                let (user_id, display_name) = {
+                let (user_id, display_name, tier) = {
                     let state = shared_state.lock().await;
                     match &state.verification_status {
                         VerificationStatus::Verified { user_id, display_name } => {
-                            (user_id.clone(), Some(display_name.clone()))
+                            (user_id.clone(), Some(display_name.clone()), state.tier.clone())
                         }
-                        _ => (username.unwrap_or("anonymous").to_string(), None),
+                        _ => (username.unwrap_or("anonymous").to_string(), None, None),
                     }
                 };
                 app_state
@@ -156,6 +296,7 @@ pub async fn create_tunnel(
                         fingerprint,
                         &user_id,
                         display_name.as_deref(),
+                        tier.as_deref(),
                         port,
                         &subdomain,
                     )
@@ -164,16 +305,18 @@ pub async fn create_tunnel(
             
             Ok(CreateTunnelResult {
                 success: true,
-                conflicting_subdomain: None,
+                conflict: None,
                 is_explicit_conflict: false,
+                missing_capability: None,
             })
         }
-        Err(TunnelError::SubdomainTaken(s)) => {
-            warn!("Subdomain {} already taken (explicit={})", s, is_explicit);
+        Err(e @ (TunnelError::SubdomainTaken(_) | TunnelError::SubdomainReserved(_))) => {
+            warn!("{} (explicit={})", e, is_explicit);
             Ok(CreateTunnelResult {
                 success: false,
-                conflicting_subdomain: Some(s),
+                conflict: Some(e),
                 is_explicit_conflict: is_explicit,
+                missing_capability: None,
             })
         }
         Err(e) => {
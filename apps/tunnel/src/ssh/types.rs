@@ -1,14 +1,50 @@
 //! SSH handler types and shared state definitions.
 
+use std::time::{Duration, Instant};
+
 use russh::server::Handle;
 use russh::ChannelId;
 
+use crate::acl::Capabilities;
+use crate::config::PortProbeMode;
+use crate::grant::GRANT_USER_PREFIX;
+use crate::state::perf_profiles::PerfProfile;
+use crate::state::resume_tokens::RESUME_USER_PREFIX;
+use crate::terminal_ui::{self, Lang, OutputMode, Ui};
+
+use super::confirm::PendingConfirmation;
+use super::live_view::LiveViewTask;
+
+/// SSH environment variable (`ssh -o SetEnv=EXLO_PORT_PROBE=wait`) overriding the port probe mode
+pub const PORT_PROBE_ENV: &str = "EXLO_PORT_PROBE";
+
+/// SSH environment variable (`ssh -T -o SetEnv=EXLO_OUTPUT=json`) choosing `tty`, `plain` or `json` output
+pub const OUTPUT_ENV: &str = "EXLO_OUTPUT";
+
+/// SSH environment variable (`ssh -o SetEnv=EXLO_LIVE_VIEW=off`) turning off the live request view
+pub const LIVE_VIEW_ENV: &str = "EXLO_LIVE_VIEW";
+
+/// SSH environment variable (`ssh -o SetEnv=EXLO_SECRET_PATH=on`) hiding the session's tunnels behind a secret path
+pub const SECRET_PATH_ENV: &str = "EXLO_SECRET_PATH";
+
+/// Locale sent by most OpenSSH clients (`SendEnv LANG`), picking the language of boxes
+pub const LANG_ENV: &str = "LANG";
+
+/// SSH username prefix carrying an API token (`ssh -R ... token-XXXX@server`)
+pub const TOKEN_USER_PREFIX: &str = "token-";
+
 /// Maximum length for a subdomain (DNS label limit)
 pub const MAX_SUBDOMAIN_LENGTH: usize = 63;
 
 /// Minimum length for a subdomain
 pub const MIN_SUBDOMAIN_LENGTH: usize = 1;
 
+/// How long notices wait for a session channel before they are dropped
+pub const PENDING_NOTICE_TTL: Duration = Duration::from_secs(120);
+
+/// Notices kept for a session without a session channel
+const MAX_PENDING_NOTICES: usize = 16;
+
 /// Subdomain validation result
 #[derive(Debug, Clone, PartialEq)]
 pub enum SubdomainValidation {
@@ -56,6 +92,24 @@ pub fn is_valid_subdomain(subdomain: &str) -> bool {
     validate_subdomain(subdomain) == SubdomainValidation::Valid
 }
 
+/// The SSH username as it may be logged or stored: credentials carried after
+/// the `token-`, `resume-` or `grant-` prefix are replaced
+pub fn redact_username(user: &str) -> String {
+    for prefix in [TOKEN_USER_PREFIX, RESUME_USER_PREFIX, GRANT_USER_PREFIX] {
+        if user.starts_with(prefix) {
+            return format!("{}<redacted>", prefix);
+        }
+    }
+    user.to_string()
+}
+
+/// Subdomain for a further unnamed forward in a session that already holds
+/// `base` (e.g. `myapp` + port 8080 -> `myapp-8080`). None if it would be invalid.
+pub fn port_subdomain(base: &str, port: u32) -> Option<String> {
+    let subdomain = format!("{}-{}", base, port);
+    is_valid_subdomain(&subdomain).then_some(subdomain)
+}
+
 /// A pending tunnel request waiting for verification
 #[derive(Debug, Clone)]
 pub struct PendingTunnel {
@@ -65,9 +119,15 @@ pub struct PendingTunnel {
 
 /// Shared state that can be accessed from the polling task
 pub struct SharedHandlerState {
+    /// ID of the SSH session this state belongs to
+    pub session_id: String,
     pub verification_status: VerificationStatus,
+    /// Verified user's tier from the auth backend
+    pub tier: Option<String>,
     pub pending_tunnels: Vec<PendingTunnel>,
     pub registered_subdomains: Vec<String>,
+    /// Client port of each registered subdomain (for the success box)
+    pub tunnel_ports: std::collections::HashMap<String, u32>,
     pub subdomain_counter: u32,
     /// Session handle for sending data to client (set after auth succeeds)
     pub session_handle: Option<Handle>,
@@ -80,29 +140,169 @@ pub struct SharedHandlerState {
     /// Subdomains from previous session, keyed by client port (for reconnection)
     /// Maps client_port -> subdomain
     pub last_subdomains: std::collections::HashMap<u32, String>,
-    /// Pending tunnel port (set when tunnel created before session channel opens)
-    pub pending_tunnel_port: Option<u32>,
+    /// When the success box was deferred (tunnel created before the session
+    /// channel opened); dropped after `PENDING_NOTICE_TTL`
+    pub tunnel_message_pending: Option<Instant>,
+    /// Refusals and errors raised before the session channel opened, shown
+    /// once it does (or in an exec reply); dropped after `PENDING_NOTICE_TTL`
+    pub pending_notices: Vec<(Instant, String)>,
     /// User-requested subdomain from SSH username (strict - disconnect on conflict)
     /// None means use random subdomain (when username is ".")
     pub requested_subdomain: Option<String>,
+    /// Port probe mode requested by the client (None = server default)
+    pub port_probe: Option<PortProbeMode>,
+    /// Terminal size (cols, rows) from the PTY request or the last resize
+    pub terminal_size: Option<(u32, u32)>,
+    /// Whether the client asked for a PTY
+    pub pty_requested: bool,
+    /// Output mode the client asked for (None = decided by the PTY request)
+    pub requested_output: Option<OutputMode>,
+    /// Language from the `--lang=` username suffix
+    pub requested_lang: Option<Lang>,
+    /// Language of the client's `LANG`
+    pub locale_lang: Option<Lang>,
+    /// Height of the activation box last shown (error boxes clear this many lines)
+    pub activation_box_lines: usize,
+    /// Show recent requests below the success box
+    pub live_view_enabled: bool,
+    /// The running live view, once the tunnels are up
+    pub live_view: Option<LiveViewTask>,
+    /// Performance profile from the username suffix (e.g. `myapp+bulk`)
+    pub perf_profile: Option<PerfProfile>,
+    /// Channel running the `exlo-control` subsystem
+    pub control_channel_id: Option<ChannelId>,
+    /// Destructive exec command waiting for its confirmation code
+    pub pending_confirmation: Option<(ChannelId, PendingConfirmation)>,
+    /// New tunnels require a secret path token (`EXLO_SECRET_PATH`)
+    pub secret_path: bool,
+    /// Session this one resumed with a token (its tunnels are taken over even
+    /// with `SESSION_TAKEOVER=reject`)
+    pub resumed_session: Option<String>,
+    /// Subdomains taken over from a still-connected earlier session, with its
+    /// client IP, not announced yet
+    pub takeovers: Vec<(String, String)>,
 }
 
 impl SharedHandlerState {
     pub fn new() -> Self {
         Self {
+            session_id: String::new(),
             verification_status: VerificationStatus::NotStarted,
+            tier: None,
             pending_tunnels: Vec::new(),
             registered_subdomains: Vec::new(),
+            tunnel_ports: std::collections::HashMap::new(),
             subdomain_counter: 0,
             session_handle: None,
             session_channel_id: None,
             esc_pressed: false,
             last_esc_time: None,
             last_subdomains: std::collections::HashMap::new(),
-            pending_tunnel_port: None,
+            tunnel_message_pending: None,
+            pending_notices: Vec::new(),
             requested_subdomain: None,
+            port_probe: None,
+            terminal_size: None,
+            pty_requested: false,
+            requested_output: None,
+            requested_lang: None,
+            locale_lang: None,
+            activation_box_lines: terminal_ui::ACTIVATION_BOX_LINES,
+            live_view_enabled: true,
+            live_view: None,
+            perf_profile: None,
+            control_channel_id: None,
+            pending_confirmation: None,
+            secret_path: false,
+            resumed_session: None,
+            takeovers: Vec::new(),
+        }
+    }
+}
+
+impl SharedHandlerState {
+    /// How notices are written: boxes with a PTY, plain lines without
+    pub fn output_mode(&self) -> OutputMode {
+        self.requested_output.unwrap_or(if self.pty_requested {
+            OutputMode::Tty
+        } else {
+            OutputMode::Plain
+        })
+    }
+
+    /// Language of boxes: the username suffix, else the client's locale
+    pub fn lang(&self) -> Lang {
+        self.requested_lang.or(self.locale_lang).unwrap_or_default()
+    }
+
+    /// How boxes are drawn for this session's terminal
+    pub fn ui(&self) -> Ui {
+        Ui::new(self.lang(), self.terminal_size)
+    }
+
+    /// Features the verified user's tier allows
+    pub fn capabilities(&self) -> Capabilities {
+        crate::config::get().acl.capabilities(self.tier.as_deref())
+    }
+
+    /// Remember a newly registered tunnel and its client port
+    pub fn record_tunnel(&mut self, subdomain: &str, port: u32) {
+        self.registered_subdomains.push(subdomain.to_string());
+        self.tunnel_ports.insert(subdomain.to_string(), port);
+        // Store subdomain by port for future reconnections
+        self.last_subdomains.insert(port, subdomain.to_string());
+    }
+
+    /// Follow a tunnel of this session to its new subdomain
+    pub fn rename_tunnel(&mut self, subdomain: &str, new_subdomain: &str) {
+        for registered in self.registered_subdomains.iter_mut().filter(|s| *s == subdomain) {
+            *registered = new_subdomain.to_string();
+        }
+        if let Some(port) = self.tunnel_ports.remove(subdomain) {
+            self.tunnel_ports.insert(new_subdomain.to_string(), port);
+            self.last_subdomains.insert(port, new_subdomain.to_string());
         }
     }
+
+    /// Forget a tunnel whose forward was cancelled
+    pub fn forget_tunnel(&mut self, subdomain: &str) {
+        self.registered_subdomains.retain(|s| s != subdomain);
+        self.tunnel_ports.remove(subdomain);
+    }
+
+    /// Keep a notice for when a session channel opens
+    pub fn queue_notice(&mut self, reason: &str, now: Instant) {
+        self.pending_notices
+            .retain(|(queued, _)| now.duration_since(*queued) < PENDING_NOTICE_TTL);
+        if self.pending_notices.len() >= MAX_PENDING_NOTICES {
+            self.pending_notices.remove(0);
+        }
+        self.pending_notices.push((now, reason.to_string()));
+    }
+
+    /// Queued notices that haven't expired, oldest first
+    pub fn take_pending_notices(&mut self, now: Instant) -> Vec<String> {
+        std::mem::take(&mut self.pending_notices)
+            .into_iter()
+            .filter(|(queued, _)| now.duration_since(*queued) < PENDING_NOTICE_TTL)
+            .map(|(_, reason)| reason)
+            .collect()
+    }
+
+    /// Whether a deferred success box is still worth showing
+    pub fn take_tunnel_message_pending(&mut self, now: Instant) -> bool {
+        self.tunnel_message_pending
+            .take()
+            .is_some_and(|deferred| now.duration_since(deferred) < PENDING_NOTICE_TTL)
+    }
+
+    /// Registered tunnels with their client ports, in registration order
+    pub fn registered_tunnels(&self) -> Vec<(String, u32)> {
+        self.registered_subdomains
+            .iter()
+            .map(|s| (s.clone(), self.tunnel_ports.get(s).copied().unwrap_or_default()))
+            .collect()
+    }
 }
 
 impl Default for SharedHandlerState {
@@ -181,6 +381,16 @@ mod tests {
         assert_ne!(id1, id2);
     }
 
+    #[test]
+    fn test_redact_username() {
+        assert_eq!(redact_username("myapp"), "myapp");
+        assert_eq!(redact_username("."), ".");
+        assert_eq!(redact_username("token-abcdef"), "token-<redacted>");
+        assert_eq!(redact_username("resume-abcdef"), "resume-<redacted>");
+        assert_eq!(redact_username("grant-abc.def"), "grant-<redacted>");
+        assert_eq!(redact_username("tokens"), "tokens");
+    }
+
     #[test]
     fn test_shared_handler_state_default() {
         let state = SharedHandlerState::new();
@@ -211,6 +421,27 @@ mod tests {
         assert_eq!(tunnel.port, cloned.port);
     }
 
+    #[test]
+    fn test_registered_tunnels_keep_ports() {
+        let mut state = SharedHandlerState::new();
+        state.record_tunnel("myapp", 3000);
+        state.record_tunnel("myapp-8080", 8080);
+        assert_eq!(
+            state.registered_tunnels(),
+            vec![("myapp".to_string(), 3000), ("myapp-8080".to_string(), 8080)]
+        );
+        assert_eq!(state.last_subdomains.get(&8080), Some(&"myapp-8080".to_string()));
+
+        state.forget_tunnel("myapp");
+        assert_eq!(state.registered_tunnels(), vec![("myapp-8080".to_string(), 8080)]);
+    }
+
+    #[test]
+    fn test_port_subdomain() {
+        assert_eq!(port_subdomain("myapp", 8080), Some("myapp-8080".to_string()));
+        assert_eq!(port_subdomain(&"a".repeat(60), 8080), None);
+    }
+
     // Subdomain validation tests
     #[test]
     fn test_validate_subdomain_valid() {
@@ -261,4 +492,19 @@ mod tests {
         assert!(!is_valid_subdomain("app-"));
         assert!(!is_valid_subdomain("MY_APP"));
     }
+
+    #[test]
+    fn test_pending_notices_expire() {
+        let start = Instant::now();
+        let mut state = SharedHandlerState::new();
+        state.queue_notice("old", start);
+        state.queue_notice("new", start + PENDING_NOTICE_TTL / 2);
+        state.tunnel_message_pending = Some(start);
+
+        let later = start + PENDING_NOTICE_TTL;
+        assert_eq!(state.take_pending_notices(later), vec!["new".to_string()]);
+        assert!(state.take_pending_notices(later).is_empty());
+        assert!(!state.take_tunnel_message_pending(later));
+        assert_eq!(state.tunnel_message_pending, None);
+    }
 }
@@ -1,44 +1,145 @@
-//! Device Flow verification polling logic.
+//! Device Flow verification: waiting for the activation code to be approved
+//! (polling or callback, see `VerificationWaiter`) and creating the pending
+//! tunnels.
 
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use log::{error, info, warn};
 use russh::Disconnect;
 use tokio::sync::{oneshot, Mutex};
 
-use crate::device::{DeviceFlowClient, RegisterTunnelRequest, VerifiedUser};
-use crate::state::{AppState, TunnelInfo};
-use crate::terminal_ui;
+use crate::acl::Capability;
+use crate::config::{get as get_config, PortProbeMode, VerificationMode};
+use crate::crash::{spawn_with_context, CrashContext};
+use crate::device::{AuthProvider, RegisterTunnelRequest, VerifiedUser};
+use crate::error::TunnelError;
+use crate::proxy::secret_path::generate_path_token;
+use crate::state::audit::AuditEvent;
+use crate::state::dedicated_ports::Transport;
+use crate::state::header_rules::HeaderRules;
+use crate::state::hooks::{VerificationEvent, VerificationMethod};
+use crate::state::{
+    generate_correlation_id, is_forward_label, AppState, NamedForward, SharedTraffic, TunnelInfo,
+};
+use crate::terminal_ui::{self, OutputMode, SessionEvent, Ui};
+
+use super::control::{self, push_session_status, ServerMessage};
+use super::port_forward::start_pending_port_forwards;
+use super::resume::resume_token_notice;
+use super::secret_path::secret_path_notices;
+use super::tunnel::missing_capability;
+use super::types::{
+    port_subdomain, PendingTunnel, SharedHandlerState, VerificationStatus,
+};
+
+/// Error box sized to clear the activation box, or an error line without a
+/// terminal
+async fn error_notice(
+    shared_state: &Mutex<SharedHandlerState>,
+    reason: &str,
+    code: Option<&str>,
+    tty_box: impl FnOnce(Ui, usize) -> String,
+) -> String {
+    let (mode, ui, box_lines) = {
+        let state = shared_state.lock().await;
+        (state.output_mode(), state.ui(), state.activation_box_lines)
+    };
+    match mode {
+        OutputMode::Tty => tty_box(ui, box_lines),
+        mode => SessionEvent::Error { reason, code }.render(mode),
+    }
+}
+
+/// How a session waits for its activation code to be approved (`VERIFICATION_MODE`)
+pub enum VerificationWaiter {
+    /// Poll the auth provider until the code is approved or expires
+    Poll(Arc<dyn AuthProvider>),
+    /// Wait for the web app to POST the result to the management API
+    Callback {
+        app_state: Arc<AppState>,
+        timeout: Duration,
+    },
+}
+
+/// Stops waiting for a callback when the wait ends or is cancelled
+struct CallbackRegistration<'a> {
+    app_state: &'a AppState,
+    code: &'a str,
+}
+
+impl Drop for CallbackRegistration<'_> {
+    fn drop(&mut self) {
+        self.app_state.activations.cancel(self.code);
+    }
+}
 
-use super::types::{generate_secure_subdomain_id, PendingTunnel, SharedHandlerState, VerificationStatus};
+impl VerificationWaiter {
+    pub fn from_config(client: Arc<dyn AuthProvider>, app_state: Arc<AppState>) -> Self {
+        let config = get_config();
+        match config.verification_mode {
+            VerificationMode::Poll => Self::Poll(client),
+            VerificationMode::Callback => Self::Callback {
+                app_state,
+                timeout: config.code_expiry,
+            },
+        }
+    }
+
+    /// Wait until the code is approved (or fail when it expires)
+    pub async fn wait(&self, code: &str) -> Result<VerifiedUser, anyhow::Error> {
+        match self {
+            Self::Poll(client) => client.poll_until_verified(code).await,
+            Self::Callback { app_state, timeout } => {
+                let deadline = tokio::time::Instant::now() + *timeout;
+                let _registration = CallbackRegistration { app_state, code };
+                loop {
+                    let receiver = app_state.activations.register(code);
+                    match tokio::time::timeout_at(deadline, receiver).await {
+                        Ok(Ok(response)) => {
+                            // A "pending" callback keeps the session waiting
+                            if let Some(outcome) = response.outcome() {
+                                return outcome;
+                            }
+                        }
+                        Ok(Err(_)) => anyhow::bail!("Activation wait was replaced"),
+                        Err(_) => return Err(TunnelError::VerificationTimeout.into()),
+                    }
+                }
+            }
+        }
+    }
+}
 
-/// Spawn a background task to poll for Device Flow verification
+/// Spawn a background task waiting for Device Flow verification
 pub fn spawn_verification_polling(
     code: String,
     session_id: String,
     cancel_rx: oneshot::Receiver<()>,
-    client: Arc<DeviceFlowClient>,
+    client: Arc<dyn AuthProvider>,
     shared_state: Arc<Mutex<SharedHandlerState>>,
     app_state: Arc<AppState>,
     peer_addr: Option<SocketAddr>,
     public_key_fingerprint: Option<String>,
 ) {
-    tokio::spawn(async move {
+    let context = CrashContext::new("ssh::verification").session(&session_id);
+    let waiter = VerificationWaiter::from_config(client.clone(), app_state.clone());
+    spawn_with_context(context, async move {
         let mut frame_idx = 0;
 
         // Spawn a task to animate the spinner
         let shared_state_clone = shared_state.clone();
         let spinner_handle = tokio::spawn(async move {
             loop {
-                let (handle, channel_id) = {
+                let (handle, channel_id, mode, ui) = {
                     let state = shared_state_clone.lock().await;
-                    (state.session_handle.clone(), state.session_channel_id)
+                    (state.session_handle.clone(), state.session_channel_id, state.output_mode(), state.ui())
                 };
 
-                if let (Some(handle), Some(channel_id)) = (handle, channel_id) {
-                    let update = terminal_ui::create_spinner_update(frame_idx);
+                // Scripted clients only get the final result
+                if let (Some(handle), Some(channel_id), OutputMode::Tty) = (handle, channel_id, mode) {
+                    let update = terminal_ui::create_spinner_update(ui, frame_idx);
                     let _ = handle.data(channel_id, update.into_bytes().into()).await;
                 }
 
@@ -48,7 +149,7 @@ pub fn spawn_verification_polling(
         });
 
         tokio::select! {
-            result = client.poll_until_verified(&code) => {
+            result = waiter.wait(&code) => {
                 spinner_handle.abort();
                 handle_verification_result(
                     result,
@@ -72,14 +173,30 @@ async fn handle_verification_result(
     result: Result<VerifiedUser, anyhow::Error>,
     shared_state: Arc<Mutex<SharedHandlerState>>,
     app_state: Arc<AppState>,
-    client: Arc<DeviceFlowClient>,
+    client: Arc<dyn AuthProvider>,
     session_id: String,
     peer_addr: Option<SocketAddr>,
     public_key_fingerprint: Option<String>,
 ) {
+    let report = |result| VerificationEvent {
+        method: VerificationMethod::DeviceFlow,
+        session_id: session_id.clone(),
+        peer_addr,
+        key_fingerprint: public_key_fingerprint.clone(),
+        result,
+    };
     match result {
         Ok(verified_user) => {
             info!("Device Flow verified! User ID: {}", verified_user.user_id);
+            app_state.hooks.verification(&report(Ok(verified_user.user_id.clone())));
+            let mut event = AuditEvent::new(&verified_user.user_id, "verification.succeeded").detail(session_id.clone());
+            if let Some(fingerprint) = &public_key_fingerprint {
+                event = event.target(fingerprint);
+            }
+            if let Some(peer) = peer_addr {
+                event = event.remote(peer);
+            }
+            app_state.audit.record(event);
             handle_verification_success(
                 verified_user,
                 shared_state,
@@ -93,8 +210,18 @@ async fn handle_verification_result(
         }
         Err(e) => {
             let reason = format!("{}", e);
+            let code = e.downcast_ref::<TunnelError>().map(TunnelError::code);
             error!("Verification failed: {}", reason);
-            handle_verification_failure(reason, shared_state).await;
+            app_state.hooks.verification(&report(Err(reason.clone())));
+            let mut event = AuditEvent::new("anonymous", "verification.failed").detail(reason.clone());
+            if let Some(fingerprint) = &public_key_fingerprint {
+                event = event.target(fingerprint);
+            }
+            if let Some(peer) = peer_addr {
+                event = event.remote(peer);
+            }
+            app_state.audit.record(event);
+            handle_verification_failure(reason, code, shared_state).await;
         }
     }
 }
@@ -103,7 +230,7 @@ async fn handle_verification_success(
     verified_user: VerifiedUser,
     shared_state: Arc<Mutex<SharedHandlerState>>,
     app_state: Arc<AppState>,
-    client: Arc<DeviceFlowClient>,
+    client: Arc<dyn AuthProvider>,
     session_id: String,
     peer_addr: Option<SocketAddr>,
     public_key_fingerprint: Option<String>,
@@ -117,6 +244,7 @@ async fn handle_verification_success(
             user_id: user_id.clone(),
             display_name: display_name.clone(),
         };
+        state.tier = verified_user.tier.clone();
         (
             state.session_handle.clone(),
             state.session_channel_id,
@@ -136,7 +264,14 @@ async fn handle_verification_success(
         .map(|addr| addr.ip().to_string())
         .unwrap_or_else(|| "unknown".to_string());
 
-    let created_tunnels = create_pending_tunnels(
+    // Dedicated TCP / UDP ports were bound when requested and start serving now
+    let (port_forwards, pending_tunnels): (Vec<PendingTunnel>, Vec<PendingTunnel>) = pending_tunnels
+        .into_iter()
+        .partition(|pending| Transport::of_forward(&pending.address).is_some());
+    let (port_notices, port_denied) =
+        start_pending_port_forwards(port_forwards, &app_state, &shared_state, &handle, &user_id).await;
+
+    let (created_tunnels, mut denied) = create_pending_tunnels(
         pending_tunnels,
         &handle,
         &user_id,
@@ -150,10 +285,57 @@ async fn handle_verification_success(
         public_key_fingerprint.as_deref(),
     )
     .await;
+    denied.extend(port_denied);
 
     // Send success message to SSH client
     if let Some(channel_id) = session_channel_id {
-        let success_msg = terminal_ui::create_success_box(&display_name, &created_tunnels);
+        let (mode, ui) = {
+            let state = shared_state.lock().await;
+            (state.output_mode(), state.ui())
+        };
+        let tty = mode == OutputMode::Tty;
+        let mut success_msg = match mode {
+            OutputMode::Tty => terminal_ui::create_success_box(ui, &display_name, &created_tunnels),
+            mode => SessionEvent::Ready { tunnels: &created_tunnels }.render(mode),
+        };
+        for (subdomain, port) in &created_tunnels {
+            let waiting = app_state
+                .get_tunnel(subdomain)
+                .await
+                .is_some_and(|t| t.awaiting_local_service);
+            if waiting && tty {
+                success_msg.push_str(&terminal_ui::create_waiting_for_service_box(ui, *port));
+            } else if waiting {
+                success_msg.push_str(&SessionEvent::WaitingForService { port: *port }.render(mode));
+            }
+        }
+        success_msg.push_str(&port_notices);
+        success_msg.push_str(&secret_path_notices(&app_state, &created_tunnels, mode, ui.lang).await);
+        for (port, capability) in denied {
+            if tty {
+                success_msg.push_str(&terminal_ui::create_capability_denied_box(ui, capability, port));
+            } else {
+                let reason = terminal_ui::capability_denied_reason(capability);
+                success_msg.push_str(&SessionEvent::Refused { port, reason: &reason }.render(mode));
+            }
+        }
+        if let Some(motd) = app_state.motd.take_for_user(&user_id).await {
+            info!("Showing MOTD to user {}", user_id);
+            if tty {
+                success_msg.push_str(&terminal_ui::create_motd_box(ui, &motd));
+            } else {
+                success_msg.push_str(&SessionEvent::Message { text: &motd }.render(mode));
+            }
+        }
+        let shared = shared_state.lock().await;
+        if let Some(notice) = resume_token_notice(&app_state, &shared, public_key_fingerprint.as_deref()) {
+            success_msg.push_str(&notice);
+        }
+        drop(shared);
+        if tty && get_config().osc52_copy && !created_tunnels.is_empty() {
+            let urls = terminal_ui::clipboard_urls(&created_tunnels);
+            success_msg.push_str(&terminal_ui::create_clipboard_copy(&urls));
+        }
         if let Err(e) = handle
             .data(channel_id, success_msg.into_bytes().into())
             .await
@@ -161,25 +343,40 @@ async fn handle_verification_success(
             warn!("Failed to send tunnel success message: {:?}", e);
         }
     }
+    push_session_status(&app_state, &shared_state).await;
 }
 
-async fn handle_verification_failure(reason: String, shared_state: Arc<Mutex<SharedHandlerState>>) {
-    let (session_handle, session_channel_id) = {
+async fn handle_verification_failure(
+    reason: String,
+    code: Option<&str>,
+    shared_state: Arc<Mutex<SharedHandlerState>>,
+) {
+    let (session_handle, session_channel_id, control_channel_id) = {
         let mut state = shared_state.lock().await;
         state.verification_status = VerificationStatus::Failed {
             reason: reason.clone(),
         };
-        (state.session_handle.clone(), state.session_channel_id)
+        (state.session_handle.clone(), state.session_channel_id, state.control_channel_id)
     };
 
-    if let (Some(handle), Some(channel_id)) = (session_handle, session_channel_id) {
-        let error_msg = terminal_ui::create_error_box(&reason);
-        if let Err(e) = handle
-            .data(channel_id, error_msg.into_bytes().into())
-            .await
-        {
-            warn!("Failed to send error message: {:?}", e);
+    let Some(handle) = session_handle else { return };
+    if session_channel_id.is_some() || control_channel_id.is_some() {
+        if let Some(channel_id) = session_channel_id {
+            let error_msg = error_notice(&shared_state, &reason, code, |ui, lines| {
+                terminal_ui::create_error_box(ui, &reason, code, lines)
+            })
+            .await;
+            if let Err(e) = handle
+                .data(channel_id, error_msg.into_bytes().into())
+                .await
+            {
+                warn!("Failed to send error message: {:?}", e);
+            }
         }
+        let error = ServerMessage::Error {
+            message: reason.clone(),
+        };
+        control::push(&handle, control_channel_id, &error).await;
 
         tokio::time::sleep(std::time::Duration::from_secs(3)).await;
 
@@ -202,54 +399,130 @@ async fn create_pending_tunnels(
     session_channel_id: Option<russh::ChannelId>,
     shared_state: &Arc<Mutex<SharedHandlerState>>,
     app_state: &Arc<AppState>,
-    client: &Arc<DeviceFlowClient>,
+    client: &Arc<dyn AuthProvider>,
     session_id: &str,
     public_key_fingerprint: Option<&str>,
-) -> Vec<(String, u32)> {
+) -> (Vec<(String, u32)>, Vec<(u32, Capability)>) {
     let mut created_tunnels = Vec::new();
+    // Further forwards the user's tier doesn't allow
+    let mut denied = Vec::new();
+    let tier = shared_state.lock().await.tier.clone();
+    let probe_mode = shared_state
+        .lock()
+        .await
+        .port_probe
+        .unwrap_or(crate::config::get().port_probe);
 
     for pending in pending_tunnels {
+        let missing = missing_capability(&*shared_state.lock().await);
+        match missing {
+            Some(Capability::CustomSubdomain) => {
+                warn!("Refusing custom subdomain for user {}: not enabled for their tier", user_id);
+                let reason = terminal_ui::capability_denied_reason(Capability::CustomSubdomain);
+                if let Some(channel_id) = session_channel_id {
+                    let error_msg = error_notice(shared_state, &reason, None, |ui, lines| {
+                        terminal_ui::create_error_box(ui, &reason, None, lines)
+                    })
+                    .await;
+                    let _ = handle.data(channel_id, error_msg.into_bytes().into()).await;
+                }
+
+                tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+
+                let _ = handle
+                    .disconnect(Disconnect::ByApplication, reason, "en".to_string())
+                    .await;
+                return (created_tunnels, denied);
+            }
+            Some(capability) => {
+                warn!("Refusing forward for port {}: missing capability {}", pending.port, capability.as_str());
+                denied.push((pending.port, capability));
+                continue;
+            }
+            None => {}
+        }
+
         // Priority: 1. requested_subdomain (user-specified), 2. generate new
-        let subdomain = {
+        let mut subdomain = {
             let mut state = shared_state.lock().await;
             if let Some(ref requested) = state.requested_subdomain {
                 requested.clone()
             } else {
                 state.subdomain_counter += 1;
-                let random_id = generate_secure_subdomain_id();
-                format!("tunnel-{}-{}", random_id, state.subdomain_counter)
+                app_state.subdomain_pool.take()
             }
         };
 
+        // A further forward for a subdomain this session already holds becomes
+        // a named upstream of that tunnel (path-routing mode) if it carries a
+        // label, otherwise it gets a subdomain of its own
+        let held_by_session = shared_state
+            .lock()
+            .await
+            .registered_subdomains
+            .contains(&subdomain);
+        if held_by_session && !is_forward_label(&pending.address) {
+            match port_subdomain(&subdomain, pending.port) {
+                Some(own) => subdomain = own,
+                None => {
+                    warn!("No valid subdomain for additional forward {} on {}", pending.port, subdomain);
+                    continue;
+                }
+            }
+        } else if held_by_session {
+            let forward = NamedForward::new(&pending.address, pending.port);
+            if let Err(e) = app_state.add_forward(&subdomain, forward).await {
+                warn!("Failed to add forward to {}: {}", subdomain, e);
+            }
+            continue;
+        }
+
         // Check if subdomain is already taken
         if app_state.is_subdomain_taken(&subdomain).await {
             warn!("Subdomain '{}' is already taken by another user", subdomain);
+            let error = TunnelError::SubdomainTaken(subdomain.clone());
+            let reason = error.to_string();
             if let Some(channel_id) = session_channel_id {
-                let error_msg = terminal_ui::create_subdomain_taken_error_box(&subdomain, pending.port);
+                let error_msg = error_notice(shared_state, &reason, Some(error.code()), |ui, _| {
+                    terminal_ui::create_subdomain_taken_error_box(ui, &error, pending.port)
+                })
+                .await;
                 let _ = handle.data(channel_id, error_msg.into_bytes().into()).await;
             }
 
             tokio::time::sleep(std::time::Duration::from_secs(3)).await;
 
-            let reason = format!("Subdomain '{}' is already in use", subdomain);
             let _ = handle
                 .disconnect(Disconnect::ByApplication, reason, "en".to_string())
                 .await;
-            return created_tunnels;
+            return (created_tunnels, denied);
         }
 
         // Probe the local port before registering the tunnel
-        let probe_result = handle
-            .channel_open_forwarded_tcpip(&pending.address, pending.port, "127.0.0.1", 12345)
-            .await;
+        let probe_result = match probe_mode {
+            PortProbeMode::Off => Ok(()),
+            _ => handle
+                .channel_open_forwarded_tcpip(&pending.address, pending.port, "127.0.0.1", 12345)
+                .await
+                .map(drop),
+        };
 
+        let mut awaiting_local_service = false;
         match probe_result {
-            Ok(channel) => {
-                drop(channel);
+            Ok(()) => {
+                if probe_mode != PortProbeMode::Off {
+                    info!(
+                        "Port probe succeeded for {}:{}",
+                        pending.address, pending.port
+                    );
+                }
+            }
+            Err(e) if probe_mode == PortProbeMode::Wait => {
                 info!(
-                    "Port probe succeeded for {}:{}",
-                    pending.address, pending.port
+                    "Port probe failed for {}:{} ({:?}), registering as waiting for local service",
+                    pending.address, pending.port, e
                 );
+                awaiting_local_service = true;
             }
             Err(e) => {
                 warn!(
@@ -257,9 +530,16 @@ async fn create_pending_tunnels(
                     pending.address, pending.port, e
                 );
 
+                let error = TunnelError::UpstreamUnreachable(format!(
+                    "local service not available on {}:{}",
+                    pending.address, pending.port
+                ));
+                let reason = error.to_string();
                 if let Some(channel_id) = session_channel_id {
-                    let error_msg =
-                        terminal_ui::create_port_error_box(pending.port, &pending.address);
+                    let error_msg = error_notice(shared_state, &reason, Some(error.code()), |ui, lines| {
+                        terminal_ui::create_port_error_box(ui, pending.port, &pending.address, error.code(), lines)
+                    })
+                    .await;
                     let _ = handle
                         .data(channel_id, error_msg.into_bytes().into())
                         .await;
@@ -267,14 +547,10 @@ async fn create_pending_tunnels(
 
                 tokio::time::sleep(std::time::Duration::from_secs(3)).await;
 
-                let reason = format!(
-                    "Local service not available on {}:{}",
-                    pending.address, pending.port
-                );
                 let _ = handle
                     .disconnect(Disconnect::ByApplication, reason, "en".to_string())
                     .await;
-                return created_tunnels;
+                return (created_tunnels, denied);
             }
         }
 
@@ -289,8 +565,29 @@ async fn create_pending_tunnels(
             client_ip: client_ip.to_string(),
             is_connected: true,
             disconnected_at: None,
+            node_id: crate::config::get().node_id.clone(),
+            forwards: Vec::new(),
+            awaiting_local_service,
+            degraded_since: None,
+            traffic: SharedTraffic::default(),
+            session_channel_id,
+            session_id: session_id.to_string(),
+            share_secret: None,
+            secret_path: shared_state.lock().await.secret_path.then(generate_path_token),
+            correlation_id: generate_correlation_id(),
+            preview_banner: false,
+            response_cache: false,
+            response_headers: HeaderRules::default(),
+            capabilities: shared_state.lock().await.capabilities(),
+            tier: tier.clone(),
+            output_mode: shared_state.lock().await.output_mode(),
+            ui: shared_state.lock().await.ui(),
+            status_alert: None,
+            perf_profile: shared_state.lock().await.perf_profile,
+            control_channel_id: shared_state.lock().await.control_channel_id,
         };
 
+        let correlation_id = tunnel_info.correlation_id.clone();
         match app_state.register_tunnel(tunnel_info).await {
             Ok(()) => {
                 let tunnel_url = crate::config::get_tunnel_url(&subdomain);
@@ -300,24 +597,27 @@ async fn create_pending_tunnels(
                      URL: {}",
                     subdomain, tunnel_url
                 );
-                {
-                    let mut state = shared_state.lock().await;
-                    state.registered_subdomains.push(subdomain.clone());
-                    // Store subdomain by port for future reconnections
-                    state.last_subdomains.insert(pending.port, subdomain.clone());
-                }
+                shared_state.lock().await.record_tunnel(&subdomain, pending.port);
                 created_tunnels.push((subdomain.clone(), pending.port));
 
                 // Save verified key with subdomain for reconnection
                 if let Some(fingerprint) = public_key_fingerprint {
                     app_state
-                        .save_verified_key(fingerprint, user_id, Some(display_name), pending.port, &subdomain)
+                        .save_verified_key(
+                            fingerprint,
+                            user_id,
+                            Some(display_name),
+                            tier.as_deref(),
+                            pending.port,
+                            &subdomain,
+                        )
                         .await;
                 }
 
                 // Register tunnel with web server for tracking
                 let register_req = RegisterTunnelRequest {
                     subdomain: subdomain.clone(),
+                    correlation_id,
                     user_id: user_id.to_string(),
                     session_id: session_id.to_string(),
                     requested_address: pending.address.clone(),
@@ -335,5 +635,5 @@ async fn create_pending_tunnels(
         }
     }
 
-    created_tunnels
+    (created_tunnels, denied)
 }
@@ -0,0 +1,92 @@
+//! Sessions waiting for an activation callback.
+//!
+//! With `VERIFICATION_MODE=callback` a session registers its activation code
+//! here instead of polling the web app; the web app POSTs the result to the
+//! management API once the user approves (or the code expires), which hands
+//! it to the waiting session.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::debug;
+use tokio::sync::oneshot;
+
+use crate::device::CheckCodeResponse;
+
+/// Activation codes of sessions waiting for a callback
+#[derive(Debug, Default)]
+pub struct PendingActivations {
+    waiters: Mutex<HashMap<String, oneshot::Sender<CheckCodeResponse>>>,
+}
+
+impl PendingActivations {
+    /// Wait for the result for `code` (replaces an earlier waiter for it)
+    pub fn register(&self, code: &str) -> oneshot::Receiver<CheckCodeResponse> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.lock().unwrap().insert(code.to_string(), sender);
+        receiver
+    }
+
+    /// Hand a result to the session waiting for `code`.
+    /// Returns false if no session is waiting for it.
+    pub fn resolve(&self, code: &str, result: CheckCodeResponse) -> bool {
+        let Some(sender) = self.waiters.lock().unwrap().remove(code) else {
+            debug!("No session waiting for activation code {}", code);
+            return false;
+        };
+        sender.send(result).is_ok()
+    }
+
+    /// Stop waiting for `code` (the session gave up or went away)
+    pub fn cancel(&self, code: &str) {
+        self.waiters.lock().unwrap().remove(code);
+    }
+
+    /// Number of sessions waiting
+    pub fn len(&self) -> usize {
+        self.waiters.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: &str, user_id: Option<&str>) -> CheckCodeResponse {
+        CheckCodeResponse {
+            status: status.to_string(),
+            user_id: user_id.map(str::to_string),
+            user_name: None,
+            tier: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_reaches_waiting_session() {
+        let activations = PendingActivations::default();
+        let receiver = activations.register("ABCD-1234");
+        assert!(!activations.resolve("OTHER-0000", response("verified", Some("user1"))));
+        assert!(activations.resolve("ABCD-1234", response("verified", Some("user1"))));
+        assert!(activations.is_empty());
+
+        let user = receiver.await.unwrap().outcome().unwrap().unwrap();
+        assert_eq!(user.user_id, "user1");
+    }
+
+    #[test]
+    fn test_cancel_and_outcomes() {
+        let activations = PendingActivations::default();
+        let _receiver = activations.register("ABCD-1234");
+        activations.cancel("ABCD-1234");
+        assert!(!activations.resolve("ABCD-1234", response("verified", Some("user1"))));
+
+        assert!(response("pending", None).outcome().is_none());
+        assert!(response("verified", Some("")).outcome().is_none());
+        assert!(response("expired", None).outcome().unwrap().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Append-only audit trail.
+//!
+//! Tunnel creation and removal, kicks, Device Flow outcomes and every
+//! state-changing management API call are recorded with who did it. Events
+//! are appended as JSON lines to `AUDIT_LOG_PATH` and never rewritten;
+//! `GET /audit` reads them back, filtered by time and actor. Without a path
+//! (tests) only the most recent events are kept in memory.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+
+/// Events kept in memory when the trail isn't persisted
+const MEMORY_EVENTS: usize = 1024;
+
+/// Actor of events the server causes itself (expiry, cleanup)
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// One audited action
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub at: DateTime<Utc>,
+    /// user_id, `admin`, `system` or the `X-Actor` a management caller gave
+    pub actor: String,
+    /// Dotted action name, e.g. `tunnel.created` or `api.call`
+    pub action: String,
+    /// What was acted on (subdomain, fingerprint, request path)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Caller's address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(actor: &str, action: &str) -> Self {
+        Self {
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: None,
+            remote: None,
+            detail: None,
+        }
+    }
+
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn remote(mut self, remote: impl ToString) -> Self {
+        self.remote = Some(remote.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Which events a query returns
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// Only events at or after this time
+    pub since: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    /// Most recent events returned
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.since.is_none_or(|since| event.at >= since)
+            && self.actor.as_deref().is_none_or(|actor| event.actor == actor)
+    }
+}
+
+/// The audit trail
+#[derive(Debug)]
+pub struct AuditLog {
+    /// Where events are appended (None = memory only)
+    path: Option<PathBuf>,
+    /// Append handle, opened on first use
+    file: Mutex<Option<File>>,
+    recent: Mutex<VecDeque<AuditEvent>>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        if config_loaded() {
+            Self::new(Some(PathBuf::from(&get_config().audit_log_path)))
+        } else {
+            Self::new(None)
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            file: Mutex::new(None),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Append an event
+    pub fn record(&self, event: AuditEvent) {
+        let Some(path) = &self.path else {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() >= MEMORY_EVENTS {
+                recent.pop_front();
+            }
+            recent.push_back(event);
+            return;
+        };
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize audit event: {}", e);
+                return;
+            }
+        };
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(opened) => *file = Some(opened),
+                Err(e) => {
+                    warn!("Failed to open audit log {}: {} (dropped: {})", path.display(), e, line);
+                    return;
+                }
+            }
+        }
+        if let Some(handle) = file.as_mut() {
+            if let Err(e) = writeln!(handle, "{}", line) {
+                warn!("Failed to write audit log {}: {} (dropped: {})", path.display(), e, line);
+                // Reopen next time, in case the file was rotated away
+                *file = None;
+            }
+        }
+    }
+
+    /// The most recent events matching `query`, oldest first
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEvent> {
+        let mut matched = VecDeque::new();
+        let keep = |event: AuditEvent| {
+            if query.matches(&event) {
+                if matched.len() >= query.limit {
+                    matched.pop_front();
+                }
+                matched.push_back(event);
+            }
+        };
+        match &self.path {
+            None => self.recent.lock().unwrap().iter().cloned().for_each(keep),
+            Some(path) => match File::open(path) {
+                Ok(file) => BufReader::new(file)
+                    .lines()
+                    .map_while(Result::ok)
+                    .filter_map(|line| serde_json::from_str(&line).ok())
+                    .for_each(keep),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to read audit log {}: {}", path.display(), e),
+            },
+        }
+        matched.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_query() {
+        let path = std::env::temp_dir().join(format!("exlo-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::new(Some(path.clone()));
+        log.record(AuditEvent::new("user1", "tunnel.created").target("app"));
+        log.record(AuditEvent::new("admin", "tunnel.kicked").target("app").remote("127.0.0.1:5000"));
+        log.record(AuditEvent::new("user2", "tunnel.created").target("api"));
+
+        let all = |actor: Option<&str>, limit| AuditQuery {
+            since: None,
+            actor: actor.map(str::to_string),
+            limit,
+        };
+        // Read back from disk, by a fresh log as after a restart
+        let reopened = AuditLog::new(Some(path.clone()));
+        assert_eq!(reopened.query(&all(None, 10)).len(), 3);
+        let kicks = reopened.query(&all(Some("admin"), 10));
+        assert_eq!(kicks.len(), 1);
+        assert_eq!(kicks[0].remote.as_deref(), Some("127.0.0.1:5000"));
+        let latest = reopened.query(&all(None, 1));
+        assert_eq!(latest[0].actor, "user2");
+
+        let future = AuditQuery {
+            since: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..all(None, 10)
+        };
+        assert!(reopened.query(&future).is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
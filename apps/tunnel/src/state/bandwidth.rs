@@ -0,0 +1,355 @@
+//! Bandwidth shaping for proxied traffic.
+//!
+//! Each tunnel has a byte budget per second in each direction:
+//! `BANDWIDTH_LIMIT` by default, or an override set through the management
+//! API. `BANDWIDTH_TIERS` adds a budget per user, by tier, shared by all of the
+//! user's tunnels. Budgets are token buckets holding one second of traffic;
+//! a transfer may overdraw them, and the proxy then pauses that direction
+//! until the debt is paid off, so throughput averages out at the limit.
+//! Throughput is measured whether or not a limit applies, for tunnel stats.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::{is_loaded as config_loaded, reloadable};
+
+/// How long throughput is averaged over for stats
+const METER_WINDOW: Duration = Duration::from_secs(1);
+
+/// Parse `tier=bytes;tier=bytes` (bytes per second, 0 = unlimited)
+pub fn parse_tier_limits(value: &str) -> Result<HashMap<String, u64>, String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (tier, bytes) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not a tier=bytes_per_second entry", entry))?;
+            let bytes = bytes
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not a number of bytes per second", bytes.trim()))?;
+            match tier.trim() {
+                "" => Err(format!("'{}' names no tier", entry)),
+                tier => Ok((tier.to_string(), bytes)),
+            }
+        })
+        .collect()
+}
+
+/// Token bucket on bytes that can be overdrawn
+#[derive(Debug)]
+struct ByteBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ByteBucket {
+    fn new(now: Instant) -> Self {
+        Self { tokens: 0.0, updated: now }
+    }
+
+    /// Take `bytes` at `rate` bytes/s; returns how long to pause before
+    /// moving more
+    fn take(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        if rate == 0 {
+            self.tokens = 0.0;
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        // Holds at most a second of traffic
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Bytes moved in the current and the last complete window
+#[derive(Debug)]
+struct Meter {
+    window_start: Instant,
+    bytes: u64,
+    last: u64,
+}
+
+impl Meter {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            bytes: 0,
+            last: 0,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < METER_WINDOW {
+            return;
+        }
+        // Nothing was measured in a window that passed without traffic
+        self.last = if elapsed < METER_WINDOW * 2 { self.bytes } else { 0 };
+        self.bytes = 0;
+        self.window_start = now;
+    }
+
+    fn add(&mut self, bytes: u64, now: Instant) {
+        self.roll(now);
+        self.bytes += bytes;
+    }
+
+    fn per_second(&mut self, now: Instant) -> u64 {
+        self.roll(now);
+        (self.last as f64 / METER_WINDOW.as_secs_f64()) as u64
+    }
+}
+
+#[derive(Debug)]
+struct Direction {
+    bucket: ByteBucket,
+    meter: Meter,
+}
+
+impl Direction {
+    fn new(now: Instant) -> Self {
+        Self {
+            bucket: ByteBucket::new(now),
+            meter: Meter::new(now),
+        }
+    }
+}
+
+/// Budget and throughput of a tunnel or user, in both directions
+#[derive(Debug)]
+pub struct Shaper {
+    /// Bytes per second in each direction (0 = unlimited)
+    rate: AtomicU64,
+    inbound: Mutex<Direction>,
+    outbound: Mutex<Direction>,
+}
+
+impl Shaper {
+    fn new(rate: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            rate: AtomicU64::new(rate),
+            inbound: Mutex::new(Direction::new(now)),
+            outbound: Mutex::new(Direction::new(now)),
+        }
+    }
+
+    fn direction(&self, inbound: bool) -> &Mutex<Direction> {
+        if inbound {
+            &self.inbound
+        } else {
+            &self.outbound
+        }
+    }
+
+    fn take(&self, inbound: bool, bytes: u64, now: Instant) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let mut direction = self.direction(inbound).lock().unwrap();
+        direction.meter.add(bytes, now);
+        direction.bucket.take(rate, bytes, now)
+    }
+
+    fn usage(&self) -> BandwidthUsage {
+        let now = Instant::now();
+        BandwidthUsage {
+            limit: self.rate.load(Ordering::Relaxed),
+            bytes_in_per_sec: self.inbound.lock().unwrap().meter.per_second(now),
+            bytes_out_per_sec: self.outbound.lock().unwrap().meter.per_second(now),
+        }
+    }
+}
+
+/// Current throughput of a tunnel and the limit it runs under
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BandwidthUsage {
+    /// Bytes per second in each direction (0 = unlimited)
+    pub limit: u64,
+    /// Visitors to the tunnel, over the last second
+    pub bytes_in_per_sec: u64,
+    /// The tunnel back to visitors, over the last second
+    pub bytes_out_per_sec: u64,
+}
+
+/// The budgets a proxied connection draws from
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    tunnel: Arc<Shaper>,
+    user: Option<Arc<Shaper>>,
+}
+
+impl Throttle {
+    /// Count bytes moved towards the tunnel (`inbound`) or back to the
+    /// visitor; returns how long that direction has to pause
+    pub fn take(&self, inbound: bool, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let tunnel = self.tunnel.take(inbound, bytes as u64, now);
+        let user = self
+            .user
+            .as_ref()
+            .map_or(Duration::ZERO, |user| user.take(inbound, bytes as u64, now));
+        tunnel.max(user)
+    }
+}
+
+/// Bandwidth overrides and budgets by subdomain and user
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    overrides: Mutex<HashMap<String, u64>>,
+    tunnels: Mutex<HashMap<String, Arc<Shaper>>>,
+    users: Mutex<HashMap<String, Arc<Shaper>>>,
+}
+
+/// Configured per-tunnel default
+fn default_limit() -> u64 {
+    if !config_loaded() {
+        return 0;
+    }
+    reloadable().bandwidth_limit
+}
+
+/// Configured budget for users of `tier` (None = no per-user budget)
+fn tier_limit(tier: Option<&str>) -> Option<u64> {
+    if !config_loaded() {
+        return None;
+    }
+    tier.and_then(|tier| reloadable().bandwidth_tiers.get(tier).copied())
+        .filter(|bytes| *bytes > 0)
+}
+
+impl Bandwidth {
+    /// The per-tunnel limit in effect and whether it was set through the API
+    pub fn limit_for(&self, subdomain: &str) -> (u64, bool) {
+        match self.overrides.lock().unwrap().get(subdomain) {
+            Some(limit) => (*limit, true),
+            None => (default_limit(), false),
+        }
+    }
+
+    pub fn set(&self, subdomain: &str, bytes_per_second: u64) {
+        self.overrides.lock().unwrap().insert(subdomain.to_string(), bytes_per_second);
+        if let Some(shaper) = self.tunnels.lock().unwrap().get(subdomain) {
+            shaper.rate.store(bytes_per_second, Ordering::Relaxed);
+        }
+    }
+
+    /// Drop a tunnel's override; returns whether there was one
+    pub fn clear(&self, subdomain: &str) -> bool {
+        let removed = self.overrides.lock().unwrap().remove(subdomain).is_some();
+        if let Some(shaper) = self.tunnels.lock().unwrap().get(subdomain) {
+            shaper.rate.store(default_limit(), Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Keep a renamed tunnel's override and budget
+    pub fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut overrides = self.overrides.lock().unwrap();
+        if let Some(limit) = overrides.remove(subdomain) {
+            overrides.insert(new_subdomain.to_string(), limit);
+        }
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if let Some(shaper) = tunnels.remove(subdomain) {
+            tunnels.insert(new_subdomain.to_string(), shaper);
+        }
+    }
+
+    /// Budgets for a new proxied connection to a tunnel of `user_id`
+    pub fn throttle(&self, subdomain: &str, user_id: &str, tier: Option<&str>) -> Throttle {
+        let (limit, _) = self.limit_for(subdomain);
+        Throttle {
+            tunnel: shaper(&self.tunnels, subdomain, limit),
+            user: tier_limit(tier).map(|limit| shaper(&self.users, user_id, limit)),
+        }
+    }
+
+    /// Throughput of a tunnel (zero until it has carried traffic)
+    pub fn usage(&self, subdomain: &str) -> BandwidthUsage {
+        let tunnel = self.tunnels.lock().unwrap().get(subdomain).cloned();
+        match tunnel {
+            Some(shaper) => shaper.usage(),
+            None => BandwidthUsage {
+                limit: self.limit_for(subdomain).0,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Forget budgets nobody draws from that belong to tunnels and users
+    /// without a registered tunnel
+    pub fn prune(&self, subdomains: &[String], users: &[String]) {
+        self.tunnels
+            .lock()
+            .unwrap()
+            .retain(|subdomain, shaper| subdomains.contains(subdomain) || Arc::strong_count(shaper) > 1);
+        self.users
+            .lock()
+            .unwrap()
+            .retain(|user_id, shaper| users.contains(user_id) || Arc::strong_count(shaper) > 1);
+    }
+}
+
+/// The shaper for `key`, created with `limit` or updated to it
+fn shaper(shapers: &Mutex<HashMap<String, Arc<Shaper>>>, key: &str, limit: u64) -> Arc<Shaper> {
+    let mut shapers = shapers.lock().unwrap();
+    let shaper = shapers
+        .entry(key.to_string())
+        .or_insert_with(|| Arc::new(Shaper::new(limit)));
+    shaper.rate.store(limit, Ordering::Relaxed);
+    shaper.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_bucket() {
+        let start = Instant::now();
+        let mut bucket = ByteBucket::new(start);
+        // Unlimited never pauses
+        assert_eq!(bucket.take(0, 1 << 20, start), Duration::ZERO);
+
+        // A full second of budget after a second, then debt
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(1000, 600, later), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 900, later), Duration::from_millis(500));
+        // The debt is paid after the pause
+        let paid = later + Duration::from_millis(500);
+        assert_eq!(bucket.take(1000, 0, paid), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_meter() {
+        let start = Instant::now();
+        let mut meter = Meter::new(start);
+        meter.add(300, start);
+        meter.add(200, start + Duration::from_millis(500));
+        assert_eq!(meter.per_second(start + Duration::from_millis(1200)), 500);
+        // A quiet window reads as nothing
+        assert_eq!(meter.per_second(start + Duration::from_secs(4)), 0);
+    }
+
+    #[test]
+    fn test_parse_tier_limits() {
+        let tiers = parse_tier_limits("free=131072; pro=0").unwrap();
+        assert_eq!(tiers.get("free"), Some(&131072));
+        assert_eq!(tiers.get("pro"), Some(&0));
+        assert!(parse_tier_limits("").unwrap().is_empty());
+        assert!(parse_tier_limits("free").is_err());
+        assert!(parse_tier_limits("free=1MB").is_err());
+        assert!(parse_tier_limits("=10").is_err());
+    }
+}
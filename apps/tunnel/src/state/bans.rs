@@ -0,0 +1,195 @@
+//! IP ban list and automatic abuse lockout.
+//!
+//! Operators ban addresses through the management API. Abuse (Device Flow
+//! rate-limit hits, rejected SSH auth attempts) counts as strikes against an
+//! IP; enough strikes within the window earn a temporary ban. Banned IPs are
+//! dropped by the SSH listener and refused by the HTTP proxy.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use tokio::sync::RwLock;
+
+use crate::config::{is_loaded as config_loaded, reloadable};
+
+/// Window in which strikes are counted towards an automatic ban
+const STRIKE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Defaults when configuration isn't loaded
+const DEFAULT_AUTO_BAN_THRESHOLD: u32 = 10;
+const DEFAULT_AUTO_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// A banned address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub created_at: SystemTime,
+    /// None = until removed
+    pub expires_at: Option<SystemTime>,
+    /// Issued by the abuse lockout rather than an operator
+    pub automatic: bool,
+}
+
+impl Ban {
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.expires_at.is_none_or(|expires| expires > now)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Strikes {
+    count: u32,
+    window_start: SystemTime,
+}
+
+/// Banned IPs and strike counters
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: RwLock<HashMap<IpAddr, Ban>>,
+    strikes: RwLock<HashMap<IpAddr, Strikes>>,
+}
+
+impl BanList {
+    /// Ban an IP, replacing any existing ban
+    pub async fn ban(&self, ip: IpAddr, reason: &str, duration: Option<Duration>) -> Ban {
+        let now = SystemTime::now();
+        let ban = Ban {
+            ip,
+            reason: reason.to_string(),
+            created_at: now,
+            expires_at: duration.map(|d| now + d),
+            automatic: false,
+        };
+        self.bans.write().await.insert(ip, ban.clone());
+        self.strikes.write().await.remove(&ip);
+        ban
+    }
+
+    /// Lift a ban; returns whether the IP was banned
+    pub async fn unban(&self, ip: IpAddr) -> bool {
+        self.strikes.write().await.remove(&ip);
+        self.bans.write().await.remove(&ip).is_some()
+    }
+
+    /// The active ban on an IP, if any
+    pub async fn is_banned(&self, ip: IpAddr) -> Option<Ban> {
+        let bans = self.bans.read().await;
+        bans.get(&ip).filter(|b| b.is_active(SystemTime::now())).cloned()
+    }
+
+    /// Active bans, sorted by IP
+    pub async fn list(&self) -> Vec<Ban> {
+        let now = SystemTime::now();
+        let bans = self.bans.read().await;
+        let mut list: Vec<_> = bans.values().filter(|b| b.is_active(now)).cloned().collect();
+        list.sort_by_key(|b| b.ip);
+        list
+    }
+
+    /// Record abuse from an IP; returns true if it triggered a ban
+    pub async fn strike(&self, ip: IpAddr, reason: &str) -> bool {
+        let (threshold, duration) = if config_loaded() {
+            let settings = reloadable();
+            (settings.auto_ban_threshold, settings.auto_ban_duration)
+        } else {
+            (DEFAULT_AUTO_BAN_THRESHOLD, DEFAULT_AUTO_BAN_DURATION)
+        };
+        self.strike_with(ip, reason, threshold, duration).await
+    }
+
+    async fn strike_with(&self, ip: IpAddr, reason: &str, threshold: u32, duration: Duration) -> bool {
+        if threshold == 0 || self.is_banned(ip).await.is_some() {
+            return false;
+        }
+
+        let now = SystemTime::now();
+        let count = {
+            let mut strikes = self.strikes.write().await;
+            let entry = strikes.entry(ip).or_insert(Strikes {
+                count: 0,
+                window_start: now,
+            });
+            if now.duration_since(entry.window_start).unwrap_or_default() >= STRIKE_WINDOW {
+                entry.count = 0;
+                entry.window_start = now;
+            }
+            entry.count += 1;
+            entry.count
+        };
+        if count < threshold {
+            return false;
+        }
+
+        warn!("Banning {} for {:?} after {} strikes ({})", ip, duration, count, reason);
+        let ban = Ban {
+            ip,
+            reason: format!("automatic: {}", reason),
+            created_at: now,
+            expires_at: Some(now + duration),
+            automatic: true,
+        };
+        self.bans.write().await.insert(ip, ban);
+        self.strikes.write().await.remove(&ip);
+        true
+    }
+
+    /// Drop expired bans and stale strike counters
+    pub async fn cleanup(&self) {
+        let now = SystemTime::now();
+        self.bans.write().await.retain(|_, ban| ban.is_active(now));
+        self.strikes.write().await.retain(|_, strikes| {
+            now.duration_since(strikes.window_start).unwrap_or_default() < STRIKE_WINDOW
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_manual_ban() {
+        let bans = BanList::default();
+        bans.ban(ip("203.0.113.5"), "scanning", None).await;
+        assert!(bans.is_banned(ip("203.0.113.5")).await.is_some());
+        assert!(bans.is_banned(ip("203.0.113.6")).await.is_none());
+        assert_eq!(bans.list().await.len(), 1);
+
+        assert!(bans.unban(ip("203.0.113.5")).await);
+        assert!(!bans.unban(ip("203.0.113.5")).await);
+        assert!(bans.is_banned(ip("203.0.113.5")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_ban() {
+        let bans = BanList::default();
+        bans.ban(ip("2001:db8::1"), "test", Some(Duration::ZERO)).await;
+        assert!(bans.is_banned(ip("2001:db8::1")).await.is_none());
+        bans.cleanup().await;
+        assert!(bans.bans.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_automatic_ban_after_strikes() {
+        let bans = BanList::default();
+        let offender = ip("198.51.100.7");
+        let hour = Duration::from_secs(3600);
+        assert!(!bans.strike_with(offender, "auth", 3, hour).await);
+        assert!(!bans.strike_with(offender, "auth", 3, hour).await);
+        assert!(bans.strike_with(offender, "auth", 3, hour).await);
+
+        let ban = bans.is_banned(offender).await.unwrap();
+        assert!(ban.automatic);
+        assert!(ban.expires_at.is_some());
+
+        // A zero threshold disables the lockout
+        assert!(!bans.strike_with(ip("198.51.100.8"), "auth", 0, hour).await);
+    }
+}
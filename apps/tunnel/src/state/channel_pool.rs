@@ -0,0 +1,147 @@
+//! Idle forwarded channels kept for reuse by the HTTP proxy.
+//!
+//! Opening a `forwarded-tcpip` channel costs a round trip to the SSH client
+//! and a fresh connection to the local service. With `CHANNEL_POOL_SIZE` set,
+//! a channel whose last exchange ended cleanly on a persistent HTTP/1.1
+//! connection is parked here and handed to the next visitor of the same
+//! forward. Channels are keyed by SSH session, so a reconnect never reuses a
+//! channel of the previous session, and expire after
+//! `CHANNEL_POOL_IDLE_TIMEOUT`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use russh::server::Msg;
+use russh::ChannelStream;
+use tokio::sync::Mutex;
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+
+/// The forward a pooled channel leads to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub session_id: String,
+    pub address: String,
+    pub port: u32,
+}
+
+/// Idle channels per forward
+pub struct ChannelPool<S = ChannelStream<Msg>> {
+    idle: Mutex<HashMap<PoolKey, Vec<(Instant, S)>>>,
+}
+
+impl<S> std::fmt::Debug for ChannelPool<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelPool").finish_non_exhaustive()
+    }
+}
+
+impl<S> Default for ChannelPool<S> {
+    fn default() -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Idle channels kept per forward and how long they stay (0 = pooling off)
+fn limits() -> (usize, Duration) {
+    if !config_loaded() {
+        return (0, Duration::ZERO);
+    }
+    let config = get_config();
+    (config.channel_pool_size, config.channel_pool_idle_timeout)
+}
+
+impl<S> ChannelPool<S> {
+    pub fn is_enabled(&self) -> bool {
+        limits().0 > 0
+    }
+
+    /// Take the most recently parked channel for the forward, if any
+    pub async fn checkout(&self, key: &PoolKey) -> Option<S> {
+        self.take(key, limits().1, Instant::now()).await
+    }
+
+    /// Park a channel that is ready for another exchange
+    pub async fn checkin(&self, key: PoolKey, channel: S) {
+        self.put(key, channel, limits().0, Instant::now()).await;
+    }
+
+    async fn take(&self, key: &PoolKey, idle_timeout: Duration, now: Instant) -> Option<S> {
+        let mut idle = self.idle.lock().await;
+        let channels = idle.get_mut(key)?;
+        channels.retain(|(parked, _)| now.duration_since(*parked) < idle_timeout);
+        let channel = channels.pop().map(|(_, channel)| channel);
+        if channels.is_empty() {
+            idle.remove(key);
+        }
+        channel
+    }
+
+    async fn put(&self, key: PoolKey, channel: S, max_idle: usize, now: Instant) {
+        if max_idle == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().await;
+        let channels = idle.entry(key).or_default();
+        if channels.len() >= max_idle {
+            // Keep the most recently used channels
+            channels.remove(0);
+        }
+        channels.push((now, channel));
+    }
+
+    /// Drop expired channels and those of sessions that are gone
+    pub async fn prune(&self, live_sessions: &[String]) {
+        let idle_timeout = limits().1;
+        let now = Instant::now();
+        let mut idle = self.idle.lock().await;
+        idle.retain(|key, channels| {
+            channels.retain(|(parked, _)| now.duration_since(*parked) < idle_timeout);
+            !channels.is_empty() && live_sessions.contains(&key.session_id)
+        });
+    }
+
+    /// Idle channels across all forwards
+    pub async fn idle_count(&self) -> usize {
+        self.idle.lock().await.values().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(session_id: &str) -> PoolKey {
+        PoolKey {
+            session_id: session_id.to_string(),
+            address: "localhost".to_string(),
+            port: 3000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checkout_and_expiry() {
+        let pool: ChannelPool<u32> = ChannelPool::default();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(30);
+
+        pool.put(key("a"), 1, 2, now).await;
+        pool.put(key("a"), 2, 2, now).await;
+        pool.put(key("a"), 3, 2, now).await;
+        assert_eq!(pool.idle_count().await, 2);
+
+        // Most recent first; other sessions never get them
+        assert_eq!(pool.take(&key("b"), timeout, now).await, None);
+        assert_eq!(pool.take(&key("a"), timeout, now).await, Some(3));
+
+        // Expired channels are dropped
+        assert_eq!(pool.take(&key("a"), timeout, now + timeout).await, None);
+        assert_eq!(pool.idle_count().await, 0);
+
+        // Pooling off keeps nothing
+        pool.put(key("a"), 4, 0, now).await;
+        assert_eq!(pool.idle_count().await, 0);
+    }
+}
@@ -0,0 +1,129 @@
+//! Cap on concurrent forwarded channels per tunnel.
+//!
+//! Every proxied exchange holds one of its tunnel's slots while it uses a
+//! forwarded channel, so a popular tunnel never has more than
+//! `MAX_CHANNELS_PER_TUNNEL` channels busy at once and can't swamp the SSH
+//! client. A request finding every slot taken waits in line for up to
+//! `CHANNEL_QUEUE_TIMEOUT` and gets a 503 if none frees up. Idle channels
+//! parked in the channel pool don't hold a slot.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+
+/// A busy channel's place; the slot frees up when it is dropped
+pub type ChannelSlot = OwnedSemaphorePermit;
+
+/// No slot freed up within the queue timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueTimeout;
+
+/// Busy channel slots by subdomain
+#[derive(Debug, Default)]
+pub struct ChannelSlots {
+    slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Channels per tunnel and how long a request waits for one (0 = no cap)
+fn limits() -> (usize, Duration) {
+    if !config_loaded() {
+        return (0, Duration::ZERO);
+    }
+    let config = get_config();
+    (config.max_channels_per_tunnel, config.channel_queue_timeout)
+}
+
+impl ChannelSlots {
+    /// Wait for a free channel slot of the tunnel (None when channels aren't capped)
+    pub async fn acquire(&self, subdomain: &str) -> Result<Option<ChannelSlot>, QueueTimeout> {
+        let (max, wait) = limits();
+        self.acquire_with(subdomain, max, wait).await
+    }
+
+    async fn acquire_with(
+        &self,
+        subdomain: &str,
+        max: usize,
+        wait: Duration,
+    ) -> Result<Option<ChannelSlot>, QueueTimeout> {
+        if max == 0 {
+            return Ok(None);
+        }
+        let semaphore = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(subdomain.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone();
+        match tokio::time::timeout(wait, semaphore.acquire_owned()).await {
+            Ok(Ok(slot)) => Ok(Some(slot)),
+            // The semaphore is never closed; treat it like a full queue anyway
+            Ok(Err(_)) | Err(_) => Err(QueueTimeout),
+        }
+    }
+
+    /// Channels of the tunnel busy right now
+    pub fn in_use(&self, subdomain: &str) -> usize {
+        let (max, _) = limits();
+        self.in_use_of(subdomain, max)
+    }
+
+    fn in_use_of(&self, subdomain: &str, max: usize) -> usize {
+        self.slots
+            .lock()
+            .unwrap()
+            .get(subdomain)
+            .map_or(0, |semaphore| max.saturating_sub(semaphore.available_permits()))
+    }
+
+    /// Keep a renamed tunnel's slots (busy channels release them as before)
+    pub fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(semaphore) = slots.remove(subdomain) {
+            slots.insert(new_subdomain.to_string(), semaphore);
+        }
+    }
+
+    /// Forget tunnels that are gone and have no busy or waiting channel
+    pub fn prune(&self, subdomains: &[String]) {
+        self.slots
+            .lock()
+            .unwrap()
+            .retain(|subdomain, semaphore| subdomains.contains(subdomain) || Arc::strong_count(semaphore) > 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_channel_slots() {
+        let slots = ChannelSlots::default();
+        let wait = Duration::from_millis(20);
+        assert!(slots.acquire_with("app", 0, wait).await.unwrap().is_none());
+
+        let first = slots.acquire_with("app", 2, wait).await.unwrap();
+        let _second = slots.acquire_with("app", 2, wait).await.unwrap();
+        assert_eq!(slots.in_use_of("app", 2), 2);
+        assert_eq!(slots.acquire_with("app", 2, wait).await.unwrap_err(), QueueTimeout);
+        // Other tunnels have their own slots
+        assert!(slots.acquire_with("other", 2, wait).await.is_ok());
+
+        // A queued request gets the slot as soon as it frees up
+        let (queued, _) = tokio::join!(slots.acquire_with("app", 2, Duration::from_secs(5)), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(first);
+        });
+        assert!(queued.unwrap().is_some());
+
+        // "app" still has busy channels
+        slots.prune(&[]);
+        assert_eq!(slots.slots.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+//! Subdomains reserved for a user account.
+//!
+//! A verified user can claim a subdomain, for a while or permanently. While
+//! the claim holds, only tunnels of that user_id may register under (or be
+//! renamed to) the subdomain, whichever key they connect with. Claims are
+//! written to `SUBDOMAIN_CLAIMS_PATH` on every change and loaded at startup.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+
+/// A subdomain reserved for one user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubdomainClaim {
+    pub subdomain: String,
+    pub user_id: String,
+    pub claimed_at: DateTime<Utc>,
+    /// None = until released
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl SubdomainClaim {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|expires| expires > now)
+    }
+}
+
+/// Claims by subdomain
+#[derive(Debug)]
+pub struct SubdomainClaims {
+    claims: RwLock<HashMap<String, SubdomainClaim>>,
+    /// Where claims are persisted (None = memory only)
+    path: Option<PathBuf>,
+}
+
+impl Default for SubdomainClaims {
+    fn default() -> Self {
+        if config_loaded() {
+            Self::load(Some(PathBuf::from(&get_config().subdomain_claims_path)))
+        } else {
+            Self::load(None)
+        }
+    }
+}
+
+impl SubdomainClaims {
+    /// Claims saved at `path` (a missing or unreadable file starts empty)
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let claims = path
+            .as_deref()
+            .and_then(|path| match std::fs::read_to_string(path) {
+                Ok(contents) => match serde_json::from_str::<Vec<SubdomainClaim>>(&contents) {
+                    Ok(claims) => Some(claims),
+                    Err(e) => {
+                        warn!("Ignoring unreadable subdomain claims in {}: {}", path.display(), e);
+                        None
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!("Failed to read subdomain claims from {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        if !claims.is_empty() {
+            info!("Loaded {} subdomain claim(s)", claims.len());
+        }
+        Self {
+            claims: RwLock::new(claims.into_iter().map(|c| (c.subdomain.clone(), c)).collect()),
+            path,
+        }
+    }
+
+    /// Write all claims to disk (replacing the file atomically)
+    async fn save(&self, claims: &HashMap<String, SubdomainClaim>) {
+        let Some(path) = &self.path else { return };
+        let mut list: Vec<_> = claims.values().collect();
+        list.sort_by(|a, b| a.subdomain.cmp(&b.subdomain));
+        if let Err(e) = write_atomically(path, &serde_json::to_vec_pretty(&list).unwrap_or_default()).await {
+            warn!("Failed to save subdomain claims to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Reserve a subdomain for `user_id` (None = permanently). Renewing one's
+    /// own claim is allowed; another user's active claim is not replaced.
+    pub async fn claim(&self, subdomain: &str, user_id: &str, ttl: Option<Duration>) -> Result<SubdomainClaim, String> {
+        let now = Utc::now();
+        let mut claims = self.claims.write().await;
+        if let Some(existing) = claims.get(subdomain) {
+            if existing.user_id != user_id && existing.is_active(now) {
+                return Err(format!("Subdomain '{}' is claimed by another user", subdomain));
+            }
+        }
+        let expires_at = match ttl {
+            Some(ttl) => Some(now + chrono::Duration::from_std(ttl).map_err(|_| "Claim period is too long".to_string())?),
+            None => None,
+        };
+        let claim = SubdomainClaim {
+            subdomain: subdomain.to_string(),
+            user_id: user_id.to_string(),
+            claimed_at: now,
+            expires_at,
+        };
+        claims.insert(subdomain.to_string(), claim.clone());
+        self.save(&claims).await;
+        info!("Subdomain '{}' claimed by {} (expires {:?})", subdomain, user_id, expires_at);
+        Ok(claim)
+    }
+
+    /// Drop a claim
+    pub async fn release(&self, subdomain: &str) -> Option<SubdomainClaim> {
+        let mut claims = self.claims.write().await;
+        let released = claims.remove(subdomain)?;
+        self.save(&claims).await;
+        info!("Subdomain '{}' released by {}", subdomain, released.user_id);
+        Some(released)
+    }
+
+    /// Whether `user_id` may register the subdomain
+    pub async fn allows(&self, subdomain: &str, user_id: &str) -> bool {
+        let claims = self.claims.read().await;
+        claims
+            .get(subdomain)
+            .is_none_or(|claim| claim.user_id == user_id || !claim.is_active(Utc::now()))
+    }
+
+    /// Active claims, sorted by subdomain
+    pub async fn list(&self) -> Vec<SubdomainClaim> {
+        let now = Utc::now();
+        let mut list: Vec<_> = self
+            .claims
+            .read()
+            .await
+            .values()
+            .filter(|claim| claim.is_active(now))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.subdomain.cmp(&b.subdomain));
+        list
+    }
+
+    /// Forget expired claims
+    pub async fn cleanup(&self) {
+        let now = Utc::now();
+        let mut claims = self.claims.write().await;
+        let before = claims.len();
+        claims.retain(|_, claim| claim.is_active(now));
+        if claims.len() != before {
+            info!("Removed {} expired subdomain claim(s)", before - claims.len());
+            self.save(&claims).await;
+        }
+    }
+}
+
+pub(super) async fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_claim_and_release() {
+        let claims = SubdomainClaims::load(None);
+        claims.claim("myapp", "user1", None).await.unwrap();
+        assert!(claims.allows("myapp", "user1").await);
+        assert!(!claims.allows("myapp", "user2").await);
+        assert!(claims.allows("other", "user2").await);
+        assert!(claims.claim("myapp", "user2", None).await.is_err());
+        // The owner may renew
+        assert!(claims.claim("myapp", "user1", Some(Duration::from_secs(60))).await.is_ok());
+
+        assert_eq!(claims.release("myapp").await.map(|c| c.user_id), Some("user1".to_string()));
+        assert!(claims.allows("myapp", "user2").await);
+    }
+
+    #[tokio::test]
+    async fn test_expired_claim_is_ignored() {
+        let claims = SubdomainClaims::load(None);
+        claims.claim("myapp", "user1", Some(Duration::ZERO)).await.unwrap();
+        assert!(claims.allows("myapp", "user2").await);
+        assert!(claims.list().await.is_empty());
+        claims.claim("myapp", "user2", None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_claims_are_persisted() {
+        let path = std::env::temp_dir().join(format!("exlo-claims-{}.json", std::process::id()));
+        let claims = SubdomainClaims::load(Some(path.clone()));
+        claims.claim("myapp", "user1", None).await.unwrap();
+
+        let reloaded = SubdomainClaims::load(Some(path.clone()));
+        assert_eq!(reloaded.list().await, claims.list().await);
+        assert!(!reloaded.allows("myapp", "user2").await);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,158 @@
+//! Bounded background cleanup.
+//!
+//! Dropped handlers, kicks, closes and idle disconnects clean up in spawned
+//! tasks because `Drop` and some callers can't await. A mass disconnect would
+//! otherwise run thousands of them at once against the state locks and the
+//! web API, so they wait for a semaphore permit and are counted by kind.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Semaphore;
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+use crate::crash::{spawn_with_context, CrashContext};
+
+/// Cleanup tasks allowed to run at once when not configured
+pub const DEFAULT_CLEANUP_CONCURRENCY: usize = 32;
+
+/// Snapshot of the cleanup backlog
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CleanupStats {
+    pub limit: usize,
+    /// Spawned and waiting for a permit
+    pub queued: usize,
+    pub running: usize,
+    pub completed: u64,
+    /// Highest queued + running seen
+    pub peak: usize,
+    /// Queued + running by kind
+    pub by_kind: HashMap<&'static str, usize>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    permits: Semaphore,
+    limit: usize,
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    completed: AtomicU64,
+    peak: AtomicUsize,
+    by_kind: Mutex<HashMap<&'static str, usize>>,
+}
+
+/// Spawner for cleanup tasks with a concurrency cap and gauges
+#[derive(Debug, Clone)]
+pub struct CleanupTasks {
+    inner: Arc<Inner>,
+}
+
+impl Default for CleanupTasks {
+    fn default() -> Self {
+        let limit = if config_loaded() {
+            get_config().cleanup_concurrency
+        } else {
+            DEFAULT_CLEANUP_CONCURRENCY
+        };
+        Self::new(limit)
+    }
+}
+
+impl CleanupTasks {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                permits: Semaphore::new(limit),
+                limit,
+                queued: AtomicUsize::new(0),
+                running: AtomicUsize::new(0),
+                completed: AtomicU64::new(0),
+                peak: AtomicUsize::new(0),
+                by_kind: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Run a cleanup future once a permit is free
+    pub fn spawn<F>(&self, kind: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let queued = inner.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        inner
+            .peak
+            .fetch_max(queued + inner.running.load(Ordering::SeqCst), Ordering::SeqCst);
+        *inner.by_kind.lock().unwrap().entry(kind).or_default() += 1;
+
+        spawn_with_context(CrashContext::new("cleanup"), async move {
+            let permit = inner.permits.acquire().await;
+            inner.queued.fetch_sub(1, Ordering::SeqCst);
+            inner.running.fetch_add(1, Ordering::SeqCst);
+
+            task.await;
+
+            drop(permit);
+            inner.running.fetch_sub(1, Ordering::SeqCst);
+            inner.completed.fetch_add(1, Ordering::SeqCst);
+            let mut by_kind = inner.by_kind.lock().unwrap();
+            if let Some(count) = by_kind.get_mut(kind) {
+                *count -= 1;
+                if *count == 0 {
+                    by_kind.remove(kind);
+                }
+            }
+        });
+    }
+
+    pub fn stats(&self) -> CleanupStats {
+        let inner = &self.inner;
+        CleanupStats {
+            limit: inner.limit,
+            queued: inner.queued.load(Ordering::SeqCst),
+            running: inner.running.load(Ordering::SeqCst),
+            completed: inner.completed.load(Ordering::SeqCst),
+            peak: inner.peak.load(Ordering::SeqCst),
+            by_kind: inner.by_kind.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_concurrency_cap_and_gauges() {
+        let cleanup = CleanupTasks::new(1);
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+
+        cleanup.spawn("kick", async move {
+            let _ = release_rx.await;
+        });
+        cleanup.spawn("handler_drop", async move {
+            let _ = done_tx.send(());
+        });
+        tokio::task::yield_now().await;
+
+        let stats = cleanup.stats();
+        assert_eq!(stats.running, 1);
+        assert_eq!(stats.queued, 1);
+        assert_eq!(stats.peak, 2);
+        assert_eq!(stats.by_kind.get("handler_drop"), Some(&1));
+
+        release_tx.send(()).unwrap();
+        done_rx.await.unwrap();
+        tokio::task::yield_now().await;
+
+        let stats = cleanup.stats();
+        assert_eq!(stats.running + stats.queued, 0);
+        assert_eq!(stats.completed, 2);
+        assert!(stats.by_kind.is_empty());
+    }
+}
@@ -0,0 +1,374 @@
+//! Shared tunnel registry for multi-node deployments.
+//!
+//! Every node periodically pulls the list of connected tunnels from its peers'
+//! management APIs. When the HTTP proxy receives a request for a subdomain it
+//! doesn't own, it consults this registry to find the node holding the SSH
+//! session and either relays the connection or redirects the visitor.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use sha2::Sha256;
+use tokio::sync::RwLock;
+
+use crate::config::get as get_config;
+
+use super::AppState;
+
+/// How often peers are polled for their tunnel lists
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Remote entries not refreshed within this window are considered gone
+const REMOTE_ENTRY_TTL: Duration = Duration::from_secs(20);
+
+/// Prefix of the line a relaying node sends before the visitor's bytes.
+/// Receiving nodes never relay such connections again, preventing loops
+/// between nodes that briefly disagree about ownership.
+pub const RELAY_MARKER: &[u8] = b"EXLO-RELAY ";
+
+/// Maximum length of the relay marker line (prefix + node id + client address + signature + CRLF)
+const MAX_RELAY_LINE: usize = 192;
+
+/// Bytes of the HMAC kept in a relay marker's signature
+const RELAY_SIGNATURE_LEN: usize = 16;
+
+/// A tunnel held by another node in the cluster
+#[derive(Debug, Clone)]
+pub struct RemoteTunnel {
+    pub subdomain: String,
+    pub node_id: String,
+    /// Address of the owning node's HTTP proxy
+    pub proxy_addr: String,
+    /// Owning node's redirect base domain (if configured)
+    pub redirect_base: Option<String>,
+    /// When this entry was last confirmed by the owning node
+    pub seen_at: SystemTime,
+}
+
+impl RemoteTunnel {
+    fn is_stale(&self) -> bool {
+        SystemTime::now()
+            .duration_since(self.seen_at)
+            .map(|elapsed| elapsed > REMOTE_ENTRY_TTL)
+            .unwrap_or(true)
+    }
+}
+
+/// Node description and tunnel list exchanged between peers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClusterTunnelsResponse {
+    #[serde(rename = "nodeId")]
+    pub node_id: String,
+    #[serde(rename = "proxyAddr")]
+    pub proxy_addr: String,
+    #[serde(rename = "redirectBase")]
+    pub redirect_base: Option<String>,
+    /// Subdomains with a connected SSH session on this node
+    pub tunnels: Vec<String>,
+}
+
+/// Registry of tunnels owned by other nodes (subdomain -> RemoteTunnel)
+#[derive(Debug, Default)]
+pub struct ClusterRegistry {
+    remote: RwLock<HashMap<String, RemoteTunnel>>,
+}
+
+impl ClusterRegistry {
+    /// Look up a non-stale remote tunnel by subdomain
+    pub async fn lookup(&self, subdomain: &str) -> Option<RemoteTunnel> {
+        let remote = self.remote.read().await;
+        remote.get(subdomain).filter(|t| !t.is_stale()).cloned()
+    }
+
+    /// Check whether another node currently holds the subdomain
+    pub async fn is_owned_elsewhere(&self, subdomain: &str) -> bool {
+        self.lookup(subdomain).await.is_some()
+    }
+
+    /// List all non-stale remote tunnels
+    pub async fn list(&self) -> Vec<RemoteTunnel> {
+        let remote = self.remote.read().await;
+        remote.values().filter(|t| !t.is_stale()).cloned().collect()
+    }
+
+    /// Replace the entries belonging to a peer with its latest report
+    pub async fn apply_peer_report(&self, report: ClusterTunnelsResponse) {
+        let now = SystemTime::now();
+        let mut remote = self.remote.write().await;
+        remote.retain(|_, t| t.node_id != report.node_id);
+        for subdomain in report.tunnels {
+            remote.insert(
+                subdomain.clone(),
+                RemoteTunnel {
+                    subdomain,
+                    node_id: report.node_id.clone(),
+                    proxy_addr: report.proxy_addr.clone(),
+                    redirect_base: report.redirect_base.clone(),
+                    seen_at: now,
+                },
+            );
+        }
+    }
+
+    /// Drop entries that haven't been refreshed recently
+    pub async fn cleanup_stale(&self) {
+        let mut remote = self.remote.write().await;
+        remote.retain(|_, t| !t.is_stale());
+    }
+}
+
+/// Build this node's report for peers
+pub async fn local_report(state: &AppState) -> ClusterTunnelsResponse {
+    let config = get_config();
+    let tunnels = state
+        .list_tunnels()
+        .await
+        .into_iter()
+        .filter(|t| t.is_connected)
+        .map(|t| t.subdomain.clone())
+        .collect();
+
+    ClusterTunnelsResponse {
+        node_id: config.node_id.clone(),
+        proxy_addr: config.cluster_proxy_addr.clone(),
+        redirect_base: config.cluster_redirect_base.clone(),
+        tunnels,
+    }
+}
+
+/// Fetch a single peer's tunnel list from its management API
+async fn fetch_peer(
+    client: &reqwest::Client,
+    peer: &str,
+) -> Result<ClusterTunnelsResponse, anyhow::Error> {
+    let url = format!("{}/cluster/tunnels", peer.trim_end_matches('/'));
+    let response = client
+        .get(&url)
+        .header("X-Internal-Secret", &get_config().internal_api_secret)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!("Peer {} returned {}", peer, response.status());
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Periodically pull tunnel lists from all configured peers.
+pub async fn run_cluster_sync(state: Arc<AppState>) {
+    let config = get_config();
+    info!(
+        "Cluster sync enabled: node_id={}, peers={:?}, mode={:?}",
+        config.node_id, config.cluster_peers, config.cluster_mode
+    );
+
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .expect("Failed to build HTTP client");
+
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for peer in &config.cluster_peers {
+            match fetch_peer(&client, peer).await {
+                Ok(report) if report.node_id == config.node_id => {
+                    warn!("Peer {} reports our own node id '{}', ignoring", peer, report.node_id);
+                }
+                Ok(report) => {
+                    debug!(
+                        "Cluster peer {} ({}) reports {} tunnel(s)",
+                        peer,
+                        report.node_id,
+                        report.tunnels.len()
+                    );
+                    state.cluster.apply_peer_report(report).await;
+                }
+                Err(e) => {
+                    debug!("Cluster sync with {} failed: {}", peer, e);
+                }
+            }
+        }
+
+        state.cluster.cleanup_stale().await;
+    }
+}
+
+/// Origin of a connection relayed by another node
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayedFrom {
+    pub node_id: String,
+    /// The visitor's address as seen by the relaying node
+    pub client_addr: SocketAddr,
+}
+
+/// HMAC of a relay marker, keyed with the shared internal secret so visitors
+/// can't pass for another node by sending a marker themselves
+fn relay_mac(secret: &str, node_id: &str, client_addr: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(node_id.as_bytes());
+    mac.update(b" ");
+    mac.update(client_addr.as_bytes());
+    mac
+}
+
+/// Sign a relay marker
+fn relay_signature(secret: &str, node_id: &str, client_addr: &str) -> String {
+    hex::encode(&relay_mac(secret, node_id, client_addr).finalize().into_bytes()[..RELAY_SIGNATURE_LEN])
+}
+
+/// Parse the contents of a relay marker line (after the prefix, without CRLF).
+/// None unless the signature matches (compared in constant time).
+fn parse_relay_line(line: &str, secret: &str) -> Option<RelayedFrom> {
+    let mut parts = line.split(' ');
+    let (node_id, addr, signature) = (parts.next()?, parts.next()?, parts.next()?);
+    let signature = hex::decode(signature).ok().filter(|s| s.len() == RELAY_SIGNATURE_LEN)?;
+    relay_mac(secret, node_id, addr).verify_truncated_left(&signature).ok()?;
+    Some(RelayedFrom {
+        node_id: node_id.to_string(),
+        client_addr: addr.parse().ok()?,
+    })
+}
+
+/// Whether a connection starts with a relay marker, without consuming it.
+/// Peers relay straight to `CLUSTER_PROXY_ADDR`, so with `PROXY_PROTOCOL`
+/// on the marker stands in for the PROXY header (its signature is checked
+/// by `consume_relay_marker`).
+pub async fn starts_with_relay_marker(stream: &TcpStream) -> std::io::Result<bool> {
+    let mut peek_buf = [0u8; RELAY_MARKER.len()];
+    let n = stream.peek(&mut peek_buf).await?;
+    Ok(peek_buf[..n] == *RELAY_MARKER)
+}
+
+/// Detect and consume a relay marker line at the start of a connection.
+/// Returns the relay origin if the connection was relayed from another node;
+/// a marker that isn't validly signed is an error (the connection is dropped).
+pub async fn consume_relay_marker(stream: &mut TcpStream) -> std::io::Result<Option<RelayedFrom>> {
+    let mut peek_buf = [0u8; MAX_RELAY_LINE];
+    let n = stream.peek(&mut peek_buf).await?;
+    if n < RELAY_MARKER.len() || &peek_buf[..RELAY_MARKER.len()] != RELAY_MARKER {
+        return Ok(None);
+    }
+
+    let line_len = match peek_buf[..n].windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos + 2,
+        None => return Ok(None),
+    };
+
+    let mut line = vec![0u8; line_len];
+    stream.read_exact(&mut line).await?;
+    let relayed = parse_relay_line(
+        &String::from_utf8_lossy(&line[RELAY_MARKER.len()..line_len - 2]),
+        &get_config().internal_api_secret,
+    )
+    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "relay marker with an invalid signature"))?;
+    debug!("Accepted relayed connection from node {}", relayed.node_id);
+    Ok(Some(relayed))
+}
+
+/// Connect to the proxy of the node that owns the tunnel, announcing the
+/// visitor `client_addr` with a signed relay marker
+pub async fn connect_to_node(remote: &RemoteTunnel, client_addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let mut upstream = TcpStream::connect(&remote.proxy_addr).await?;
+    let config = get_config();
+    let client_addr = client_addr.to_string();
+    let marker = format!(
+        "{}{} {} {}\r\n",
+        String::from_utf8_lossy(RELAY_MARKER),
+        config.node_id,
+        client_addr,
+        relay_signature(&config.internal_api_secret, &config.node_id, &client_addr)
+    );
+    upstream.write_all(marker.as_bytes()).await?;
+    Ok(upstream)
+}
+
+/// Relay a visitor connection to the node that owns the tunnel.
+/// `buffered` is what was already read from the visitor for routing; it is
+/// sent ahead of the rest of the stream so nothing is lost.
+pub async fn relay_to_node(
+    stream: &mut TcpStream,
+    remote: &RemoteTunnel,
+    client_addr: SocketAddr,
+    buffered: &[u8],
+) -> std::io::Result<(u64, u64)> {
+    let mut upstream = connect_to_node(remote, client_addr).await?;
+    upstream.write_all(buffered).await?;
+    let (to_node, to_client) = tokio::io::copy_bidirectional(stream, &mut upstream).await?;
+    Ok((buffered.len() as u64 + to_node, to_client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(node_id: &str, tunnels: &[&str]) -> ClusterTunnelsResponse {
+        ClusterTunnelsResponse {
+            node_id: node_id.to_string(),
+            proxy_addr: format!("{}:8080", node_id),
+            redirect_base: None,
+            tunnels: tunnels.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_peer_report_and_lookup() {
+        let registry = ClusterRegistry::default();
+        registry.apply_peer_report(report("node-b", &["app", "api"])).await;
+
+        let tunnel = registry.lookup("app").await.unwrap();
+        assert_eq!(tunnel.node_id, "node-b");
+        assert_eq!(tunnel.proxy_addr, "node-b:8080");
+        assert!(registry.is_owned_elsewhere("api").await);
+        assert!(!registry.is_owned_elsewhere("missing").await);
+    }
+
+    #[test]
+    fn test_parse_relay_line() {
+        let secret = "test-secret";
+        let signature = relay_signature(secret, "node-a", "203.0.113.9:51000");
+        let line = format!("node-a 203.0.113.9:51000 {}", signature);
+
+        let relayed = parse_relay_line(&line, secret).unwrap();
+        assert_eq!(relayed.node_id, "node-a");
+        assert_eq!(relayed.client_addr, "203.0.113.9:51000".parse().unwrap());
+        assert_eq!(parse_relay_line("node-a", secret), None);
+        assert_eq!(parse_relay_line("node-a 203.0.113.9:51000", secret), None);
+    }
+
+    #[test]
+    fn test_parse_relay_line_rejects_bad_signature() {
+        let secret = "test-secret";
+        assert_eq!(parse_relay_line("node-a 203.0.113.9:51000 deadbeef", secret), None);
+
+        // Signed for another address or node, or with a truncated signature
+        let signature = relay_signature(secret, "node-a", "203.0.113.9:51000");
+        assert_eq!(parse_relay_line(&format!("node-a 198.51.100.1:51000 {}", signature), secret), None);
+        assert_eq!(parse_relay_line(&format!("node-b 203.0.113.9:51000 {}", signature), secret), None);
+        assert_eq!(parse_relay_line(&format!("node-a 203.0.113.9:51000 {}", &signature[..2]), secret), None);
+        assert_eq!(parse_relay_line(&format!("node-a 203.0.113.9:51000 {}", signature), "other"), None);
+    }
+
+    #[tokio::test]
+    async fn test_peer_report_replaces_previous_entries() {
+        let registry = ClusterRegistry::default();
+        registry.apply_peer_report(report("node-b", &["app", "api"])).await;
+        registry.apply_peer_report(report("node-b", &["api"])).await;
+        registry.apply_peer_report(report("node-c", &["web"])).await;
+
+        assert!(registry.lookup("app").await.is_none());
+        assert!(registry.lookup("api").await.is_some());
+        assert_eq!(registry.list().await.len(), 2);
+    }
+}
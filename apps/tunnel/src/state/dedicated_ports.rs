@@ -0,0 +1,307 @@
+//! Public TCP and UDP ports bound for raw forwards.
+//!
+//! HTTP tunnels share the proxy's port and are told apart by Host header, so
+//! the port in `-R <port>:...` only identifies the forward. Clients needing a
+//! plain endpoint (databases, game servers, DNS) ask for a dedicated port by
+//! using `tcp` or `udp` as the bind address: `ssh -R tcp:5432:localhost:5432`
+//! binds public TCP port 5432, `ssh -R udp:0:localhost:7000` takes a free UDP
+//! port and reports it in the forward reply. Only ports in `TCP_PORT_RANGE` /
+//! `UDP_PORT_RANGE` are given out. A port is bound as soon as it is requested,
+//! so the reply can name it, but only served (see `proxy::tcp` and
+//! `proxy::udp`) once the session is verified; it is released when the
+//! forward is cancelled or the session ends.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::info;
+use serde::Serialize;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::AbortHandle;
+
+use crate::acl::Capability;
+use crate::config::{get as get_config, is_loaded as config_loaded};
+
+/// Where dedicated ports listen
+const BIND_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+/// Protocol of a dedicated port, named by the forward's bind address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    /// The transport a forward asks for (`-R tcp:...` / `-R udp:...`), if any
+    pub fn of_forward(address: &str) -> Option<Self> {
+        [Self::Tcp, Self::Udp]
+            .into_iter()
+            .find(|transport| address.eq_ignore_ascii_case(transport.as_str()))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+
+    /// For log lines and refusals ("TCP port 5432 ...")
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Tcp => "TCP",
+            Self::Udp => "UDP",
+        }
+    }
+
+    pub fn capability(&self) -> Capability {
+        match self {
+            Self::Tcp => Capability::TcpPorts,
+            Self::Udp => Capability::UdpPorts,
+        }
+    }
+
+    fn configured_range(&self) -> Option<RangeInclusive<u16>> {
+        if !config_loaded() {
+            return None;
+        }
+        match self {
+            Self::Tcp => get_config().tcp_port_range.clone(),
+            Self::Udp => get_config().udp_port_range.clone(),
+        }
+    }
+}
+
+/// A bound socket waiting to be served
+#[derive(Debug)]
+pub enum BoundSocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl BoundSocket {
+    async fn bind(transport: Transport, ip: IpAddr, port: u16) -> std::io::Result<Self> {
+        Ok(match transport {
+            Transport::Tcp => Self::Tcp(TcpListener::bind((ip, port)).await?),
+            Transport::Udp => Self::Udp(UdpSocket::bind((ip, port)).await?),
+        })
+    }
+}
+
+/// A bound port and the session holding it
+#[derive(Debug)]
+struct DedicatedPort {
+    session_id: String,
+    /// Set once the port is served
+    username: Option<String>,
+    bound_at: SystemTime,
+    /// Waiting for the session's verification
+    socket: Option<BoundSocket>,
+    task: Option<AbortHandle>,
+}
+
+/// A dedicated port as listed by the management API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortInfo {
+    pub port: u16,
+    pub session_id: String,
+    pub username: Option<String>,
+    /// False while the session waits for verification
+    pub serving: bool,
+    pub bound_at: u64,
+}
+
+/// Dedicated ports by transport and port number
+#[derive(Debug, Default)]
+pub struct DedicatedPorts {
+    ports: Mutex<HashMap<(Transport, u16), DedicatedPort>>,
+}
+
+impl DedicatedPorts {
+    /// Bind `requested` (or a free port for 0) for a session. Returns the
+    /// bound port, or why none could be given out.
+    pub async fn allocate(&self, transport: Transport, session_id: &str, requested: u32) -> Result<u16, String> {
+        let Some(range) = transport.configured_range() else {
+            return Err(format!("dedicated {} ports are not enabled on this server", transport.label()));
+        };
+        self.allocate_in(transport, session_id, requested, BIND_IP, range).await
+    }
+
+    async fn allocate_in(
+        &self,
+        transport: Transport,
+        session_id: &str,
+        requested: u32,
+        ip: IpAddr,
+        range: RangeInclusive<u16>,
+    ) -> Result<u16, String> {
+        let label = transport.label();
+        let candidates: Vec<u16> = if requested == 0 {
+            let taken = self.ports.lock().unwrap();
+            range.clone().filter(|port| !taken.contains_key(&(transport, *port))).collect()
+        } else {
+            match u16::try_from(requested).ok().filter(|port| range.contains(port)) {
+                Some(port) if self.ports.lock().unwrap().contains_key(&(transport, port)) => {
+                    return Err(format!("{} port {} is already in use", label, port));
+                }
+                Some(port) => vec![port],
+                None => {
+                    return Err(format!(
+                        "{} port {} is outside the range {}-{} of this server",
+                        label,
+                        requested,
+                        range.start(),
+                        range.end()
+                    ));
+                }
+            }
+        };
+
+        for port in candidates {
+            // Ports another process holds are skipped (or refused if asked for)
+            let socket = match BoundSocket::bind(transport, ip, port).await {
+                Ok(socket) => socket,
+                Err(e) if requested != 0 => return Err(format!("{} port {} is unavailable: {}", label, port, e)),
+                Err(_) => continue,
+            };
+            let mut ports = self.ports.lock().unwrap();
+            if ports.contains_key(&(transport, port)) {
+                continue;
+            }
+            ports.insert(
+                (transport, port),
+                DedicatedPort {
+                    session_id: session_id.to_string(),
+                    username: None,
+                    bound_at: SystemTime::now(),
+                    socket: Some(socket),
+                    task: None,
+                },
+            );
+            info!("Bound {} port {} for session {}", label, port, session_id);
+            return Ok(port);
+        }
+        Err(format!("no {} port is free", label))
+    }
+
+    /// Start serving a session's bound port; `serve` gets its socket and
+    /// returns the task handling it. False if the port isn't waiting.
+    pub fn start(
+        &self,
+        transport: Transport,
+        session_id: &str,
+        port: u16,
+        username: &str,
+        serve: impl FnOnce(BoundSocket) -> AbortHandle,
+    ) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        let Some(forward) = ports.get_mut(&(transport, port)).filter(|f| f.session_id == session_id) else {
+            return false;
+        };
+        let Some(socket) = forward.socket.take() else {
+            return false;
+        };
+        forward.username = Some(username.to_string());
+        forward.task = Some(serve(socket));
+        true
+    }
+
+    /// Close a session's port. Returns false if the session doesn't hold it.
+    pub fn release(&self, transport: Transport, session_id: &str, port: u16) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        if ports.get(&(transport, port)).is_none_or(|f| f.session_id != session_id) {
+            return false;
+        }
+        if let Some(task) = ports.remove(&(transport, port)).and_then(|f| f.task) {
+            task.abort();
+        }
+        info!("Released {} port {} of session {}", transport.label(), port, session_id);
+        true
+    }
+
+    /// Close every port of a session
+    pub fn release_session(&self, session_id: &str) -> Vec<(Transport, u16)> {
+        let held: Vec<(Transport, u16)> = self
+            .ports
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, forward)| forward.session_id == session_id)
+            .map(|(key, _)| *key)
+            .collect();
+        held.into_iter()
+            .filter(|(transport, port)| self.release(*transport, session_id, *port))
+            .collect()
+    }
+
+    /// Bound ports of a transport, lowest first
+    pub fn list(&self, transport: Transport) -> Vec<PortInfo> {
+        let mut ports: Vec<PortInfo> = self
+            .ports
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((bound, _), _)| *bound == transport)
+            .map(|((_, port), forward)| PortInfo {
+                port: *port,
+                session_id: forward.session_id.clone(),
+                username: forward.username.clone(),
+                serving: forward.task.is_some(),
+                bound_at: forward
+                    .bound_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            })
+            .collect();
+        ports.sort_by_key(|info| info.port);
+        ports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_allocate_and_release() {
+        // A free port from the OS, so the test doesn't depend on a fixed one
+        let free = std::net::TcpListener::bind((LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let ports = DedicatedPorts::default();
+        let tcp = Transport::Tcp;
+        assert_eq!(ports.allocate_in(tcp, "s1", free as u32, LOCALHOST, free..=free).await, Ok(free));
+        assert!(ports.allocate_in(tcp, "s2", free as u32, LOCALHOST, free..=free).await.is_err());
+        assert_eq!(
+            ports.allocate_in(tcp, "s2", 0, LOCALHOST, free..=free).await,
+            Err("no TCP port is free".to_string())
+        );
+        let outside = ports.allocate_in(tcp, "s2", 1, LOCALHOST, free..=free).await.unwrap_err();
+        assert!(outside.contains("outside the range"));
+        assert!(!ports.list(tcp)[0].serving);
+
+        assert!(!ports.release(tcp, "s2", free));
+        assert_eq!(ports.release_session("s1"), vec![(tcp, free)]);
+        assert!(ports.list(tcp).is_empty());
+        assert_eq!(ports.allocate_in(tcp, "s2", 0, LOCALHOST, free..=free).await, Ok(free));
+    }
+
+    #[tokio::test]
+    async fn test_transports_are_separate() {
+        let free = std::net::UdpSocket::bind((LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let ports = DedicatedPorts::default();
+        assert_eq!(ports.allocate_in(Transport::Udp, "s1", 0, LOCALHOST, free..=free).await, Ok(free));
+        assert!(ports.list(Transport::Tcp).is_empty());
+        assert!(!ports.release(Transport::Tcp, "s1", free));
+        assert_eq!(ports.list(Transport::Udp)[0].port, free);
+
+        assert_eq!(Transport::of_forward("UDP"), Some(Transport::Udp));
+        assert_eq!(Transport::of_forward("api"), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! Custom domains (CNAMEs) attached to tunnels.
+//!
+//! A user can point their own host (e.g. `demo.mycompany.com`) at the server
+//! and attach it to one of their tunnels. The mapping is keyed by subdomain and
+//! owner, so it follows the tunnel across reconnects but never routes to a
+//! tunnel that someone else registered under the same name later.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use tokio::sync::RwLock;
+
+/// A custom domain attached to a tunnel
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomDomain {
+    pub domain: String,
+    pub subdomain: String,
+    pub user_id: String,
+    pub created_at: SystemTime,
+}
+
+/// Lowercase a Host value and strip the port and trailing dot
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = host.rsplit_once(':').map_or(host, |(name, port)| {
+        if port.chars().all(|c| c.is_ascii_digit()) {
+            name
+        } else {
+            host
+        }
+    });
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// Check that a custom domain is a plausible public host outside the tunnel base domain
+pub fn validate_custom_domain(domain: &str, base_domain: &str) -> Result<(), String> {
+    let labels: Vec<&str> = domain.split('.').collect();
+    if domain.len() > 253 || labels.len() < 2 {
+        return Err(format!("'{}' is not a fully qualified domain name", domain));
+    }
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+    if !labels.iter().all(valid_label) {
+        return Err(format!("'{}' is not a valid domain name", domain));
+    }
+    let base = normalize_host(base_domain);
+    if domain == base || domain.ends_with(&format!(".{}", base)) {
+        return Err(format!("'{}' is under the tunnel domain; use a subdomain instead", domain));
+    }
+    Ok(())
+}
+
+/// Registry of custom domains by host
+#[derive(Debug, Default)]
+pub struct CustomDomains {
+    domains: RwLock<HashMap<String, CustomDomain>>,
+}
+
+impl CustomDomains {
+    /// Attach a domain to a tunnel. Fails if another user already holds it.
+    pub async fn attach(&self, domain: &str, subdomain: &str, user_id: &str) -> Result<CustomDomain, String> {
+        let mut domains = self.domains.write().await;
+        if let Some(existing) = domains.get(domain) {
+            if existing.user_id != user_id {
+                return Err(format!("Domain '{}' is attached by another user", domain));
+            }
+        }
+        let entry = CustomDomain {
+            domain: domain.to_string(),
+            subdomain: subdomain.to_string(),
+            user_id: user_id.to_string(),
+            created_at: SystemTime::now(),
+        };
+        domains.insert(domain.to_string(), entry.clone());
+        Ok(entry)
+    }
+
+    pub async fn detach(&self, domain: &str) -> Option<CustomDomain> {
+        self.domains.write().await.remove(domain)
+    }
+
+    /// The custom domain matching a request's Host header, if any
+    pub async fn resolve(&self, host: &str) -> Option<CustomDomain> {
+        let domains = self.domains.read().await;
+        if domains.is_empty() {
+            return None;
+        }
+        domains.get(&normalize_host(host)).cloned()
+    }
+
+    /// Follow a tunnel that was renamed
+    pub async fn rename_subdomain(&self, subdomain: &str, new_subdomain: &str) {
+        let mut domains = self.domains.write().await;
+        for entry in domains.values_mut().filter(|d| d.subdomain == subdomain) {
+            entry.subdomain = new_subdomain.to_string();
+        }
+    }
+
+    /// All custom domains, sorted by domain
+    pub async fn list(&self) -> Vec<CustomDomain> {
+        let domains = self.domains.read().await;
+        let mut list: Vec<_> = domains.values().cloned().collect();
+        list.sort_by(|a, b| a.domain.cmp(&b.domain));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Demo.MyCompany.com:443"), "demo.mycompany.com");
+        assert_eq!(normalize_host("demo.mycompany.com."), "demo.mycompany.com");
+        assert_eq!(normalize_host("demo.mycompany.com"), "demo.mycompany.com");
+    }
+
+    #[test]
+    fn test_validate_custom_domain() {
+        assert!(validate_custom_domain("demo.mycompany.com", "tunnel.example.com").is_ok());
+        assert!(validate_custom_domain("localhost", "tunnel.example.com").is_err());
+        assert!(validate_custom_domain("bad_name.example.org", "tunnel.example.com").is_err());
+        assert!(validate_custom_domain("app.tunnel.example.com", "tunnel.example.com").is_err());
+        assert!(validate_custom_domain("tunnel.example.com", "tunnel.example.com:8080").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attach_and_resolve() {
+        let domains = CustomDomains::default();
+        domains.attach("demo.mycompany.com", "myapp", "user1").await.unwrap();
+
+        let resolved = domains.resolve("DEMO.mycompany.com:8080").await.unwrap();
+        assert_eq!(resolved.subdomain, "myapp");
+        assert!(domains.resolve("other.mycompany.com").await.is_none());
+
+        // Another user can't take it over, the owner can re-point it
+        assert!(domains.attach("demo.mycompany.com", "theirs", "user2").await.is_err());
+        domains.attach("demo.mycompany.com", "myapp2", "user1").await.unwrap();
+        domains.rename_subdomain("myapp2", "myapp3").await;
+        assert_eq!(domains.resolve("demo.mycompany.com").await.unwrap().subdomain, "myapp3");
+
+        assert!(domains.detach("demo.mycompany.com").await.is_some());
+        assert!(domains.list().await.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//! Tunnel lifecycle events for the management event stream.
+//!
+//! Every event gets a sequence number and is kept in a bounded buffer, so a
+//! dashboard that reconnects with its last resume token can replay what it
+//! missed. Tokens embed a per-process epoch; after a restart (or once the
+//! buffer has moved past the token) the subscriber is told to resync instead.
+//!
+//! Subscribers authenticate with either the internal API secret (global scope)
+//! or a user token `<user_id>.<signature>` minted by the web server with the
+//! same secret (that user's tunnels only).
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::broadcast;
+
+use crate::proxy::share_secret::secrets_match;
+
+/// Number of recent events kept for resuming subscribers
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// Bytes of the HMAC kept in a user token's signature
+const SIGNATURE_LEN: usize = 16;
+
+/// What happened to a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelEventKind {
+    Connected,
+    Disconnected,
+    Renamed,
+    Removed,
+    /// The local service stopped answering health checks
+    Degraded,
+    /// The local service answers again
+    Recovered,
+}
+
+impl TunnelEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connected => "connected",
+            Self::Disconnected => "disconnected",
+            Self::Renamed => "renamed",
+            Self::Removed => "removed",
+            Self::Degraded => "degraded",
+            Self::Recovered => "recovered",
+        }
+    }
+}
+
+/// A tunnel lifecycle event
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelEvent {
+    pub seq: u64,
+    pub kind: TunnelEventKind,
+    pub subdomain: String,
+    /// Previous subdomain of a renamed tunnel
+    pub previous_subdomain: Option<String>,
+    pub user_id: String,
+    pub at: SystemTime,
+}
+
+/// Who a subscriber may see events for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventScope {
+    /// All tunnels (operators)
+    Global,
+    /// Only tunnels owned by this user
+    User(String),
+}
+
+fn user_token_mac(secret: &str, user_id: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"events:");
+    mac.update(user_id.as_bytes());
+    mac
+}
+
+/// Signature of a per-user event stream token
+pub fn user_token_signature(secret: &str, user_id: &str) -> String {
+    hex::encode(&user_token_mac(secret, user_id).finalize().into_bytes()[..SIGNATURE_LEN])
+}
+
+impl EventScope {
+    /// Scope granted by a subscriber's token, if it is valid (both secrets
+    /// are compared in constant time)
+    pub fn from_token(token: &str, secret: &str) -> Option<Self> {
+        if secrets_match(token, secret) {
+            return Some(Self::Global);
+        }
+        let (user_id, signature) = token.rsplit_once('.')?;
+        if user_id.is_empty() || user_id == "anonymous" {
+            return None;
+        }
+        let signature = hex::decode(signature).ok().filter(|s| s.len() == SIGNATURE_LEN)?;
+        user_token_mac(secret, user_id).verify_truncated_left(&signature).ok()?;
+        Some(Self::User(user_id.to_string()))
+    }
+
+    pub fn allows(&self, event: &TunnelEvent) -> bool {
+        match self {
+            Self::Global => true,
+            Self::User(user_id) => event.user_id == *user_id,
+        }
+    }
+}
+
+/// Result of resuming from a token
+#[derive(Debug, PartialEq)]
+pub enum Replay {
+    /// Events after the token, oldest first
+    Events(Vec<TunnelEvent>),
+    /// The token is from another process or too old; the client must resync
+    ResyncRequired,
+}
+
+#[derive(Debug)]
+struct Buffer {
+    next_seq: u64,
+    events: VecDeque<TunnelEvent>,
+}
+
+/// Sequenced, replayable tunnel event log
+#[derive(Debug)]
+pub struct EventLog {
+    /// Random per-process value so tokens don't survive a restart
+    epoch: u64,
+    buffer: Mutex<Buffer>,
+    sender: broadcast::Sender<TunnelEvent>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        Self {
+            epoch: rand::random(),
+            buffer: Mutex::new(Buffer {
+                next_seq: 1,
+                events: VecDeque::new(),
+            }),
+            sender,
+        }
+    }
+}
+
+impl EventLog {
+    /// Record an event and notify live subscribers
+    pub fn publish(&self, kind: TunnelEventKind, subdomain: &str, previous_subdomain: Option<&str>, user_id: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let event = TunnelEvent {
+            seq: buffer.next_seq,
+            kind,
+            subdomain: subdomain.to_string(),
+            previous_subdomain: previous_subdomain.map(|s| s.to_string()),
+            user_id: user_id.to_string(),
+            at: SystemTime::now(),
+        };
+        buffer.next_seq += 1;
+        buffer.events.push_back(event.clone());
+        if buffer.events.len() > EVENT_BUFFER_SIZE {
+            buffer.events.pop_front();
+        }
+        // Sent under the lock so live delivery keeps sequence order
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribe to live events.
+    /// Returns the receiver together with the token of the latest event, so
+    /// nothing is lost between a replay and the live stream.
+    pub fn subscribe(&self) -> (broadcast::Receiver<TunnelEvent>, String) {
+        let buffer = self.buffer.lock().unwrap();
+        (self.sender.subscribe(), self.token(buffer.next_seq - 1))
+    }
+
+    /// Resume token for an event sequence number
+    pub fn token(&self, seq: u64) -> String {
+        format!("{:x}-{}", self.epoch, seq)
+    }
+
+    /// Events after a resume token, or `ResyncRequired` if they can't all be replayed
+    pub fn replay(&self, token: &str) -> Replay {
+        let Some(seq) = self.parse_token(token) else {
+            return Replay::ResyncRequired;
+        };
+        let buffer = self.buffer.lock().unwrap();
+        if seq >= buffer.next_seq {
+            return Replay::ResyncRequired;
+        }
+        // The event right after the token must still be buffered
+        let oldest = buffer.events.front().map(|e| e.seq).unwrap_or(buffer.next_seq);
+        if seq + 1 < oldest {
+            return Replay::ResyncRequired;
+        }
+        Replay::Events(buffer.events.iter().filter(|e| e.seq > seq).cloned().collect())
+    }
+
+    fn parse_token(&self, token: &str) -> Option<u64> {
+        let (epoch, seq) = token.split_once('-')?;
+        if u64::from_str_radix(epoch, 16).ok()? != self.epoch {
+            return None;
+        }
+        seq.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_after_token() {
+        let log = EventLog::default();
+        let (_rx, start) = log.subscribe();
+        log.publish(TunnelEventKind::Connected, "app", None, "user1");
+        log.publish(TunnelEventKind::Renamed, "web", Some("app"), "user1");
+
+        let Replay::Events(events) = log.replay(&start) else {
+            panic!("expected events");
+        };
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].previous_subdomain.as_deref(), Some("app"));
+
+        let Replay::Events(events) = log.replay(&log.token(events[0].seq)) else {
+            panic!("expected events");
+        };
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, TunnelEventKind::Renamed);
+    }
+
+    #[test]
+    fn test_replay_requires_resync() {
+        let log = EventLog::default();
+        let (_rx, start) = log.subscribe();
+        for _ in 0..(EVENT_BUFFER_SIZE + 1) {
+            log.publish(TunnelEventKind::Connected, "app", None, "user1");
+        }
+        // The first event has been dropped from the buffer
+        assert_eq!(log.replay(&start), Replay::ResyncRequired);
+        // Tokens from another process
+        assert_eq!(EventLog::default().replay(&start), Replay::ResyncRequired);
+        assert_eq!(log.replay("garbage"), Replay::ResyncRequired);
+    }
+
+    #[test]
+    fn test_user_scope() {
+        let log = EventLog::default();
+        let (mut rx, _) = log.subscribe();
+        log.publish(TunnelEventKind::Connected, "app", None, "user1");
+        let event = rx.try_recv().unwrap();
+        assert!(EventScope::Global.allows(&event));
+        assert!(EventScope::User("user1".to_string()).allows(&event));
+        assert!(!EventScope::User("user2".to_string()).allows(&event));
+    }
+
+    #[test]
+    fn test_scope_from_token() {
+        let secret = "test-secret";
+        assert_eq!(EventScope::from_token(secret, secret), Some(EventScope::Global));
+
+        let token = format!("user.1.{}", user_token_signature(secret, "user.1"));
+        assert_eq!(
+            EventScope::from_token(&token, secret),
+            Some(EventScope::User("user.1".to_string()))
+        );
+
+        let forged = format!("user2.{}", user_token_signature(secret, "user.1"));
+        assert_eq!(EventScope::from_token(&forged, secret), None);
+        // A prefix of the signature doesn't pass
+        assert_eq!(EventScope::from_token(&token[..token.len() - 2], secret), None);
+        assert_eq!(EventScope::from_token("", secret), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Validation of the addresses clients ask to forward.
+//!
+//! With `ssh -R myhost.internal:80:localhost:3000` the bind address is a
+//! hostname that only means something on the client side. The server never
+//! connects to it, but echoes it back as the connected address of every
+//! forwarded channel and writes it to logs, so anything that isn't a
+//! well-formed hostname or IP is refused. The echo stays byte-for-byte what
+//! the client sent (OpenSSH matches channels to forwards by comparing it);
+//! policy checks and logs use the normalized form (lowercase, no trailing dot
+//! or IPv6 brackets). `FORWARD_ADDRESS_POLICY` narrows what is accepted: `any`
+//! hostname, only hostnames resolving to `private` addresses (looked up here,
+//! with results cached for `FORWARD_DNS_CACHE_TTL`), or only `labels`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::RwLock;
+
+use crate::config::{get as get_config, is_loaded as config_loaded, ForwardAddressPolicy};
+
+use super::is_forward_label;
+
+/// Longest hostname accepted (DNS limit)
+const MAX_HOSTNAME_LEN: usize = 253;
+
+/// How long a lookup may take before the address counts as unresolvable
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Lookups kept before expired entries are dropped
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// Used when the configuration isn't loaded
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Canonical form of a requested bind address, or why it is unacceptable
+pub fn normalize_forward_address(address: &str) -> Result<String, String> {
+    let trimmed = address.trim();
+    let unbracketed = trimmed
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .unwrap_or(trimmed);
+    if unbracketed.is_empty() || unbracketed == "*" {
+        return Ok(unbracketed.to_string());
+    }
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+    let host = unbracketed.strip_suffix('.').unwrap_or(unbracketed).to_lowercase();
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+    if host.len() > MAX_HOSTNAME_LEN || !host.split('.').all(valid_label) {
+        return Err("not a valid hostname or IP address".to_string());
+    }
+    Ok(host)
+}
+
+/// Whether a normalized address is a hostname (not a label, IP or wildcard)
+fn is_hostname(address: &str) -> bool {
+    !address.is_empty()
+        && address != "*"
+        && address != "localhost"
+        && address.parse::<IpAddr>().is_err()
+        && !is_forward_label(address)
+}
+
+/// Loopback, private, link-local and unique local addresses
+fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || (v6.segments()[0] & 0xfe00) == 0xfc00
+                || (v6.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Addresses a hostname resolved to (None = didn't resolve) and when
+type Lookup = (Option<Vec<IpAddr>>, Instant);
+
+/// Requested address checks and their DNS cache
+#[derive(Debug, Default)]
+pub struct ForwardAddresses {
+    /// Lookups by hostname
+    cache: RwLock<HashMap<String, Lookup>>,
+}
+
+impl ForwardAddresses {
+    /// Apply the configured policy to a requested bind address; returns its
+    /// normalized form
+    pub async fn check(&self, address: &str) -> Result<String, String> {
+        let address = normalize_forward_address(address)?;
+        let policy = if config_loaded() {
+            get_config().forward_address_policy
+        } else {
+            ForwardAddressPolicy::Any
+        };
+        match policy {
+            ForwardAddressPolicy::Any => Ok(address),
+            ForwardAddressPolicy::Labels if is_hostname(&address) => {
+                Err("hostnames are not accepted as bind addresses".to_string())
+            }
+            ForwardAddressPolicy::Labels => Ok(address),
+            ForwardAddressPolicy::Private => {
+                let private = match address.parse::<IpAddr>() {
+                    Ok(ip) => is_private_address(ip),
+                    Err(_) if !is_hostname(&address) => true,
+                    Err(_) => self
+                        .resolve(&address)
+                        .await
+                        .is_some_and(|ips| !ips.is_empty() && ips.into_iter().all(is_private_address)),
+                };
+                if private {
+                    Ok(address)
+                } else {
+                    Err("only private addresses are accepted as bind addresses".to_string())
+                }
+            }
+        }
+    }
+
+    /// Addresses a hostname resolves to, from the cache when fresh
+    pub async fn resolve(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let ttl = if config_loaded() {
+            get_config().forward_dns_cache_ttl
+        } else {
+            DEFAULT_CACHE_TTL
+        };
+        if let Some((ips, at)) = self.cache.read().await.get(host) {
+            if at.elapsed() < ttl {
+                return ips.clone();
+            }
+        }
+
+        let ips = match tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((host, 0))).await {
+            Ok(Ok(addrs)) => Some(addrs.map(|addr| addr.ip()).collect::<Vec<_>>()),
+            Ok(Err(e)) => {
+                debug!("Forward address '{}' did not resolve: {}", host, e);
+                None
+            }
+            Err(_) => {
+                debug!("Lookup of forward address '{}' timed out", host);
+                None
+            }
+        };
+
+        let mut cache = self.cache.write().await;
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, (_, at)| at.elapsed() < ttl);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        cache.insert(host.to_string(), (ips.clone(), Instant::now()));
+        ips
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_forward_address() {
+        assert_eq!(normalize_forward_address(""), Ok(String::new()));
+        assert_eq!(normalize_forward_address("*"), Ok("*".to_string()));
+        assert_eq!(normalize_forward_address("MyHost.Internal."), Ok("myhost.internal".to_string()));
+        assert_eq!(normalize_forward_address("[::1]"), Ok("::1".to_string()));
+        assert_eq!(normalize_forward_address("api"), Ok("api".to_string()));
+        assert!(normalize_forward_address("my host").is_err());
+        assert!(normalize_forward_address("evil\nlog line").is_err());
+        assert!(normalize_forward_address("-bad.internal").is_err());
+        assert!(normalize_forward_address(&"a.".repeat(130)).is_err());
+    }
+
+    #[test]
+    fn test_address_kinds() {
+        assert!(is_hostname("myhost.internal"));
+        assert!(!is_hostname("api"));
+        assert!(!is_hostname("localhost"));
+        assert!(!is_hostname("10.0.0.1"));
+        assert!(is_private_address("10.1.2.3".parse().unwrap()));
+        assert!(is_private_address("fd00::1".parse().unwrap()));
+        assert!(!is_private_address("203.0.113.5".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_is_cached() {
+        let addresses = ForwardAddresses::default();
+        let ips = addresses.resolve("localhost").await;
+        assert_eq!(addresses.cache.read().await.get("localhost").map(|(cached, _)| cached.clone()), Some(ips));
+    }
+}
@@ -0,0 +1,317 @@
+//! HAR capture of proxied exchanges.
+//!
+//! While capture is on for a subdomain, each HTTP/1.1 exchange the proxy
+//! forwards to it is kept as a HAR 1.2 entry, so webhook deliveries and other
+//! requests can be inspected and replayed offline with standard tooling.
+//! Messages are kept up to `MAX_MESSAGE_BYTES` each; a capture holds up to
+//! `MAX_CAPTURE_BYTES` and drops its oldest exchanges beyond that. Captures
+//! live in memory until discarded or their tunnel is gone.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Largest request or response (head and body) kept per exchange
+pub const MAX_MESSAGE_BYTES: usize = 1 << 20;
+
+/// Largest capture per subdomain, counting heads and bodies
+const MAX_CAPTURE_BYTES: usize = 16 << 20;
+
+/// A header, query parameter or cookie
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HarPair {
+    pub name: String,
+    pub value: String,
+}
+
+/// Body of a captured request
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    pub mime_type: String,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub cookies: Vec<HarPair>,
+    pub headers: Vec<HarPair>,
+    pub query_string: Vec<HarPair>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+/// Body of a captured response
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    /// Length of the payload as sent (before truncation)
+    pub size: i64,
+    pub mime_type: String,
+    pub text: String,
+    /// `base64` for bodies that aren't UTF-8 text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    pub cookies: Vec<HarPair>,
+    pub headers: Vec<HarPair>,
+    pub content: HarContent,
+    #[serde(rename = "redirectURL")]
+    pub redirect_url: String,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+/// Milliseconds spent on each phase of an exchange
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct HarTimings {
+    /// Forwarding the request to the tunnel
+    pub send: f64,
+    /// Until the first byte of the response
+    pub wait: f64,
+    /// Reading the rest of the response
+    pub receive: f64,
+}
+
+/// Cache details of an exchange (the proxy records none)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HarCache {}
+
+/// One captured exchange
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    /// RFC 3339 time the request arrived
+    pub started_date_time: String,
+    /// Total milliseconds
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    pub cache: HarCache,
+    pub timings: HarTimings,
+    /// The visitor connection, as in the access log
+    pub connection: String,
+}
+
+impl HarEntry {
+    /// Approximate memory held by the entry
+    fn weight(&self) -> usize {
+        let post_data = self.request.post_data.as_ref().map_or(0, |data| data.text.len());
+        let response = self.response.headers_size.max(0) as usize + self.response.content.text.len();
+        self.request.url.len() + self.request.headers_size.max(0) as usize + post_data + response
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarCreator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarLog {
+    pub version: String,
+    pub creator: HarCreator,
+    pub entries: Vec<HarEntry>,
+}
+
+/// A HAR file
+#[derive(Debug, Clone, Serialize)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+/// Exchanges captured for a subdomain
+#[derive(Debug, Default)]
+struct Capture {
+    recording: bool,
+    entries: VecDeque<HarEntry>,
+    bytes: usize,
+}
+
+/// HAR captures by subdomain
+#[derive(Debug, Default)]
+pub struct HarCaptures {
+    captures: Mutex<HashMap<String, Capture>>,
+}
+
+impl HarCaptures {
+    /// Start or pause recording for a subdomain; a paused capture keeps its
+    /// exchanges for download
+    pub fn set_recording(&self, subdomain: &str, recording: bool) {
+        let mut captures = self.captures.lock().unwrap();
+        match captures.get_mut(subdomain) {
+            Some(capture) => capture.recording = recording,
+            None if recording => {
+                let capture = Capture {
+                    recording,
+                    ..Default::default()
+                };
+                captures.insert(subdomain.to_string(), capture);
+            }
+            None => {}
+        }
+    }
+
+    /// Whether exchanges with the subdomain are being captured
+    pub fn is_recording(&self, subdomain: &str) -> bool {
+        self.captures
+            .lock()
+            .unwrap()
+            .get(subdomain)
+            .is_some_and(|capture| capture.recording)
+    }
+
+    pub fn record(&self, subdomain: &str, entry: HarEntry) {
+        let mut captures = self.captures.lock().unwrap();
+        let Some(capture) = captures.get_mut(subdomain).filter(|capture| capture.recording) else {
+            return;
+        };
+        capture.bytes += entry.weight();
+        capture.entries.push_back(entry);
+        while capture.bytes > MAX_CAPTURE_BYTES {
+            let Some(oldest) = capture.entries.pop_front() else {
+                break;
+            };
+            capture.bytes = capture.bytes.saturating_sub(oldest.weight());
+        }
+    }
+
+    /// The capture as a HAR file (None if there is none)
+    pub fn export(&self, subdomain: &str) -> Option<Har> {
+        let captures = self.captures.lock().unwrap();
+        let capture = captures.get(subdomain)?;
+        Some(Har {
+            log: HarLog {
+                version: "1.2".to_string(),
+                creator: HarCreator {
+                    name: env!("CARGO_PKG_NAME").to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+                entries: capture.entries.iter().cloned().collect(),
+            },
+        })
+    }
+
+    /// Stop capturing and drop what was captured; returns the number of
+    /// exchanges dropped (None if there was no capture)
+    pub fn discard(&self, subdomain: &str) -> Option<usize> {
+        let capture = self.captures.lock().unwrap().remove(subdomain)?;
+        Some(capture.entries.len())
+    }
+
+    /// Keep a renamed tunnel's capture
+    pub fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut captures = self.captures.lock().unwrap();
+        if let Some(capture) = captures.remove(subdomain) {
+            captures.insert(new_subdomain.to_string(), capture);
+        }
+    }
+
+    /// Drop captures of subdomains without a registered tunnel
+    pub fn prune(&self, subdomains: &[String]) {
+        self.captures
+            .lock()
+            .unwrap()
+            .retain(|subdomain, _| subdomains.contains(subdomain));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(url: &str, body: usize) -> HarEntry {
+        HarEntry {
+            started_date_time: "2024-01-01T00:00:00+00:00".to_string(),
+            time: 1.0,
+            request: HarRequest {
+                method: "POST".to_string(),
+                url: url.to_string(),
+                http_version: "HTTP/1.1".to_string(),
+                cookies: Vec::new(),
+                headers: Vec::new(),
+                query_string: Vec::new(),
+                post_data: None,
+                headers_size: 0,
+                body_size: 0,
+            },
+            response: HarResponse {
+                status: 200,
+                status_text: "OK".to_string(),
+                http_version: "HTTP/1.1".to_string(),
+                cookies: Vec::new(),
+                headers: Vec::new(),
+                content: HarContent {
+                    size: body as i64,
+                    mime_type: String::new(),
+                    text: "x".repeat(body),
+                    encoding: None,
+                    comment: None,
+                },
+                redirect_url: String::new(),
+                headers_size: 0,
+                body_size: body as i64,
+            },
+            cache: HarCache::default(),
+            timings: HarTimings::default(),
+            connection: "0000beef".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_capture_lifecycle() {
+        let captures = HarCaptures::default();
+        captures.record("app", entry("http://app/ignored", 0));
+        assert!(captures.export("app").is_none());
+
+        captures.set_recording("app", true);
+        captures.record("app", entry("http://app/hook", 0));
+        captures.set_recording("app", false);
+        captures.record("app", entry("http://app/paused", 0));
+        let har = captures.export("app").unwrap();
+        assert_eq!(har.log.version, "1.2");
+        assert_eq!(har.log.entries.len(), 1);
+        assert_eq!(har.log.entries[0].request.url, "http://app/hook");
+
+        let json = serde_json::to_value(&har).unwrap();
+        assert_eq!(json["log"]["entries"][0]["response"]["redirectURL"], "");
+        assert_eq!(json["log"]["entries"][0]["startedDateTime"], "2024-01-01T00:00:00+00:00");
+
+        assert_eq!(captures.discard("app"), Some(1));
+        assert!(!captures.is_recording("app"));
+    }
+
+    #[test]
+    fn test_capture_drops_oldest() {
+        let captures = HarCaptures::default();
+        captures.set_recording("app", true);
+        for i in 0..20 {
+            captures.record("app", entry(&format!("http://app/{}", i), MAX_MESSAGE_BYTES));
+        }
+        let entries = captures.export("app").unwrap().log.entries;
+        assert!(entries.len() < 20);
+        assert_eq!(entries.last().unwrap().request.url, "http://app/19");
+
+        captures.prune(&[]);
+        assert!(captures.export("app").is_none());
+    }
+}
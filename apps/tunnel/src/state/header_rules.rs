@@ -0,0 +1,152 @@
+//! Per-tunnel response header rules.
+//!
+//! A tunnel can have headers added to (or replaced in) every response, e.g.
+//! `X-Robots-Tag: noindex` or CORS headers, and headers stripped, e.g.
+//! `Server`. Operators set them through the management API, owners with the
+//! `headers` exec command. Like the preview banner, the proxy then forwards one
+//! request per visitor connection so it sees each response head.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Most headers a tunnel can set or remove
+pub const MAX_HEADER_RULES: usize = 32;
+
+/// Headers that frame the response; rewriting them would corrupt the stream
+const PROTECTED_HEADERS: &[&str] = &["content-length", "transfer-encoding", "connection", "keep-alive", "upgrade"];
+
+/// Headers added to and removed from a tunnel's responses
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderRules {
+    /// Added to every response, replacing any value the service sent
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// Removed from every response (case-insensitive names)
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.remove.is_empty()
+    }
+
+    /// Reject invalid names and values, framing headers and too many rules
+    pub fn validate(&self) -> Result<(), String> {
+        if self.set.len() + self.remove.len() > MAX_HEADER_RULES {
+            return Err(format!("At most {} header rules are allowed", MAX_HEADER_RULES));
+        }
+        for name in self.set.keys().chain(&self.remove) {
+            validate_name(name)?;
+        }
+        if let Some(name) = self.set.iter().find(|(_, value)| !is_valid_value(value)).map(|(name, _)| name) {
+            return Err(format!("Invalid value for header '{}'", name));
+        }
+        Ok(())
+    }
+
+    /// Add (or replace) a header
+    pub fn set_header(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let mut next = self.clone();
+        next.forget(name);
+        next.set.insert(name.to_string(), value.to_string());
+        next.validate()?;
+        *self = next;
+        Ok(())
+    }
+
+    /// Strip a header from responses
+    pub fn remove_header(&mut self, name: &str) -> Result<(), String> {
+        let mut next = self.clone();
+        next.forget(name);
+        next.remove.push(name.to_string());
+        next.validate()?;
+        *self = next;
+        Ok(())
+    }
+
+    /// Drop any rule for `name`
+    fn forget(&mut self, name: &str) {
+        self.set.retain(|n, _| !n.eq_ignore_ascii_case(name));
+        self.remove.retain(|n| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Apply the rules to a complete response head
+    pub fn apply(&self, head: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(head);
+        let mut lines = text.split("\r\n").filter(|line| !line.is_empty());
+        let mut rewritten = String::with_capacity(head.len() + 64);
+        if let Some(status_line) = lines.next() {
+            rewritten.push_str(status_line);
+            rewritten.push_str("\r\n");
+        }
+        for line in lines {
+            let name = line.split(':').next().unwrap_or("").trim();
+            let replaced = self
+                .set
+                .keys()
+                .chain(&self.remove)
+                .any(|rule| rule.eq_ignore_ascii_case(name));
+            if !replaced {
+                rewritten.push_str(line);
+                rewritten.push_str("\r\n");
+            }
+        }
+        for (name, value) in &self.set {
+            rewritten.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        rewritten.push_str("\r\n");
+        rewritten.into_bytes()
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    let is_token = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if !is_token {
+        return Err(format!("Invalid header name '{}'", name));
+    }
+    if PROTECTED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+        return Err(format!("Header '{}' can't be changed", name));
+    }
+    Ok(())
+}
+
+fn is_valid_value(value: &str) -> bool {
+    !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut rules = HeaderRules::default();
+        rules.set_header("X-Robots-Tag", "noindex").unwrap();
+        rules.remove_header("server").unwrap();
+        let head = b"HTTP/1.1 200 OK\r\nServer: nginx\r\nx-robots-tag: all\r\nContent-Length: 2\r\n\r\n";
+        assert_eq!(
+            String::from_utf8(rules.apply(head)).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Robots-Tag: noindex\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rules_are_validated() {
+        let mut rules = HeaderRules::default();
+        assert!(rules.set_header("Content-Length", "0").is_err());
+        assert!(rules.set_header("X-Bad Name", "1").is_err());
+        assert!(rules.set_header("X-Injected", "a\r\nSet-Cookie: x").is_err());
+        assert!(rules.remove_header("Transfer-Encoding").is_err());
+
+        // A later rule for the same header replaces the earlier one
+        rules.set_header("Server", "exlo").unwrap();
+        rules.remove_header("SERVER").unwrap();
+        assert!(rules.set.is_empty());
+        assert_eq!(rules.remove, vec!["SERVER".to_string()]);
+    }
+}
@@ -0,0 +1,30 @@
+//! Listener readiness flags for health probes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Tracks which public listeners have been bound
+#[derive(Debug, Default)]
+pub struct Readiness {
+    ssh_listener: AtomicBool,
+    http_listener: AtomicBool,
+}
+
+impl Readiness {
+    /// Mark the SSH listener as bound and accepting
+    pub fn set_ssh_listening(&self) {
+        self.ssh_listener.store(true, Ordering::Relaxed);
+    }
+
+    /// Mark the HTTP proxy listener as bound and accepting
+    pub fn set_http_listening(&self) {
+        self.http_listener.store(true, Ordering::Relaxed);
+    }
+
+    pub fn ssh_listening(&self) -> bool {
+        self.ssh_listener.load(Ordering::Relaxed)
+    }
+
+    pub fn http_listening(&self) -> bool {
+        self.http_listener.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,108 @@
+//! Per-user history of ended tunnels.
+//!
+//! Lets users recall the URLs of earlier sessions (`ssh server -- history`).
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::SystemTime;
+
+use tokio::sync::RwLock;
+
+use super::TunnelInfo;
+
+/// Number of past tunnels kept per user
+const MAX_ENTRIES_PER_USER: usize = 20;
+
+/// A tunnel that has ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub subdomain: String,
+    pub started_at: SystemTime,
+    pub ended_at: SystemTime,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// When the tunnel last carried a request (None if it never did)
+    pub last_active: Option<SystemTime>,
+    /// Its proxied connections per close reason
+    pub close_reasons: BTreeMap<String, u64>,
+}
+
+impl HistoryEntry {
+    pub fn from_tunnel(tunnel: &TunnelInfo, ended_at: SystemTime) -> Self {
+        let traffic = tunnel.traffic.snapshot();
+        Self {
+            subdomain: tunnel.subdomain.clone(),
+            started_at: tunnel.created_at,
+            ended_at,
+            bytes_in: traffic.bytes_in,
+            bytes_out: traffic.bytes_out,
+            last_active: traffic.last_activity,
+            close_reasons: traffic.close_reasons,
+        }
+    }
+}
+
+/// Recent tunnels per user ID, newest first
+#[derive(Debug, Default)]
+pub struct TunnelHistory {
+    entries: RwLock<HashMap<String, VecDeque<HistoryEntry>>>,
+}
+
+impl TunnelHistory {
+    /// Record an ended tunnel for a user
+    pub async fn record(&self, user_id: &str, entry: HistoryEntry) {
+        if user_id.is_empty() || user_id == "anonymous" {
+            return;
+        }
+        let mut entries = self.entries.write().await;
+        let user_entries = entries.entry(user_id.to_string()).or_default();
+        user_entries.push_front(entry);
+        user_entries.truncate(MAX_ENTRIES_PER_USER);
+    }
+
+    /// A user's recent tunnels, newest first
+    pub async fn for_user(&self, user_id: &str) -> Vec<HistoryEntry> {
+        let entries = self.entries.read().await;
+        entries
+            .get(user_id)
+            .map(|e| e.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(subdomain: &str) -> HistoryEntry {
+        HistoryEntry {
+            subdomain: subdomain.to_string(),
+            started_at: SystemTime::UNIX_EPOCH,
+            ended_at: SystemTime::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            last_active: None,
+            close_reasons: BTreeMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_newest_first_and_capped() {
+        let history = TunnelHistory::default();
+        for i in 0..(MAX_ENTRIES_PER_USER + 5) {
+            history.record("user1", entry(&format!("app{}", i))).await;
+        }
+
+        let entries = history.for_user("user1").await;
+        assert_eq!(entries.len(), MAX_ENTRIES_PER_USER);
+        assert_eq!(entries[0].subdomain, format!("app{}", MAX_ENTRIES_PER_USER + 4));
+        assert!(history.for_user("user2").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_history_ignores_anonymous() {
+        let history = TunnelHistory::default();
+        history.record("anonymous", entry("app")).await;
+        history.record("", entry("app")).await;
+        assert!(history.for_user("anonymous").await.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+//! Lifecycle callbacks for embedding programs.
+//!
+//! An embedder implements [`TunnelHooks`] and registers it with
+//! `TunnlServiceBuilder::hooks` to push tunnel, verification and request
+//! events into its own systems. Verifications and requests are reported
+//! inline from the SSH handler and the proxy, so hooks should return quickly
+//! (spawn a task for anything slow); tunnel creation and removal are
+//! delivered from the event log by a background task.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::proxy::access_log::AccessLogEntry;
+use crate::state::events::TunnelEvent;
+
+/// How a session proved who it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationMethod {
+    /// Browser activation
+    DeviceFlow,
+    /// A key verified earlier, within its cache lifetime
+    VerifiedKey,
+    /// API token as the SSH password or username
+    ApiToken,
+    /// OpenSSH user certificate from a trusted CA
+    Certificate,
+    /// Resumption token of a dropped session
+    ResumeToken,
+}
+
+impl VerificationMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DeviceFlow => "device_flow",
+            Self::VerifiedKey => "verified_key",
+            Self::ApiToken => "api_token",
+            Self::Certificate => "certificate",
+            Self::ResumeToken => "resume_token",
+        }
+    }
+}
+
+/// A finished verification attempt
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationEvent {
+    pub method: VerificationMethod,
+    pub session_id: String,
+    pub peer_addr: Option<SocketAddr>,
+    /// SHA256 fingerprint of the key the session authenticated with
+    pub key_fingerprint: Option<String>,
+    /// The verified user_id, or why verification failed
+    pub result: Result<String, String>,
+}
+
+/// Callbacks for tunnel lifecycle events; every method defaults to doing nothing
+pub trait TunnelHooks: Send + Sync {
+    /// A tunnel went live (also after its session reconnected)
+    fn on_tunnel_created(&self, _event: &TunnelEvent) {}
+
+    /// A tunnel is gone for good: closed, kicked or not reconnected in time
+    fn on_tunnel_closed(&self, _event: &TunnelEvent) {}
+
+    /// A session was verified, or failed to be
+    fn on_verification(&self, _event: &VerificationEvent) {}
+
+    /// The proxy finished a request to a tunnel
+    fn on_request(&self, _entry: &AccessLogEntry) {}
+}
+
+/// Registered hooks
+#[derive(Default)]
+pub struct Hooks {
+    hooks: RwLock<Vec<Arc<dyn TunnelHooks>>>,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks").field("registered", &self.len()).finish()
+    }
+}
+
+impl Hooks {
+    pub fn add(&self, hooks: Arc<dyn TunnelHooks>) {
+        self.hooks.write().unwrap().push(hooks);
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn each(&self, f: impl Fn(&dyn TunnelHooks)) {
+        // Cloned so a hook may register another without deadlocking
+        let hooks = self.hooks.read().unwrap().clone();
+        hooks.iter().for_each(|hooks| f(hooks.as_ref()));
+    }
+
+    pub fn verification(&self, event: &VerificationEvent) {
+        self.each(|hooks| hooks.on_verification(event));
+    }
+
+    pub fn request(&self, entry: &AccessLogEntry) {
+        self.each(|hooks| hooks.on_request(entry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl TunnelHooks for Recorder {
+        fn on_verification(&self, event: &VerificationEvent) {
+            let outcome = event.result.clone().unwrap_or_else(|e| e);
+            self.0.lock().unwrap().push(format!("{} {}", event.method.as_str(), outcome));
+        }
+    }
+
+    #[test]
+    fn test_hooks_dispatch() {
+        let hooks = Hooks::default();
+        let recorder = Arc::new(Recorder::default());
+        hooks.add(recorder.clone());
+        hooks.verification(&VerificationEvent {
+            method: VerificationMethod::ApiToken,
+            session_id: "s1".to_string(),
+            peer_addr: None,
+            key_fingerprint: None,
+            result: Ok("user_1".to_string()),
+        });
+        // Unimplemented callbacks are no-ops
+        hooks.request(&AccessLogEntry::new("127.0.0.1:1".parse().unwrap(), b"GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["api_token user_1".to_string()]);
+    }
+}
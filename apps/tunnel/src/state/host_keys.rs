@@ -0,0 +1,278 @@
+//! Host keys served to SSH clients, and their rotation.
+//!
+//! A rotation generates a replacement for every file-backed host key but
+//! keeps presenting the current ones for a grace period
+//! (`HOST_KEY_ROTATION_GRACE`). Both fingerprints are published on
+//! `GET /host-keys` meanwhile, so clients and `known_hosts` files can be
+//! updated before the switch instead of every client seeing a changed key at
+//! once. A maintenance pass makes the replacements current when the grace
+//! period is over. A rotation interrupted by a restart resumes from the
+//! `.next` files, timed from when they were written.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use russh_keys::HashAlg;
+use serde::Serialize;
+
+use crate::key::{load_rotated, next_key_path, promote, rotate, HostKeyAlgorithm};
+
+fn after(at: DateTime<Utc>, grace: Duration) -> DateTime<Utc> {
+    at + chrono::Duration::from_std(grace).unwrap_or(chrono::TimeDelta::MAX)
+}
+
+/// A served host key
+#[derive(Debug, Clone)]
+pub struct HostKey {
+    pub key: russh_keys::PrivateKey,
+    /// File the key is kept in (None = supplied by an embedding program,
+    /// can't be rotated)
+    pub path: Option<PathBuf>,
+}
+
+impl HostKey {
+    pub fn new(key: russh_keys::PrivateKey, path: Option<PathBuf>) -> Self {
+        Self { key, path }
+    }
+
+    fn info(&self, status: HostKeyStatus) -> HostKeyInfo {
+        HostKeyInfo {
+            algorithm: self.key.algorithm().to_string(),
+            fingerprint: self.key.public_key().fingerprint(HashAlg::Sha256).to_string(),
+            status,
+        }
+    }
+}
+
+/// Where a published key is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyStatus {
+    /// Presented to clients
+    Active,
+    /// Generated by a rotation, presented once the grace period is over
+    Next,
+    /// Replaced by the last rotation
+    Retired,
+}
+
+/// A published host key fingerprint
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostKeyInfo {
+    pub algorithm: String,
+    /// SHA256 fingerprint as `ssh-keygen -l` prints it
+    pub fingerprint: String,
+    pub status: HostKeyStatus,
+}
+
+/// A rotation waiting for its grace period to end
+#[derive(Debug, Clone, Serialize)]
+pub struct RotationInfo {
+    pub started_at: DateTime<Utc>,
+    /// When the new keys replace the current ones
+    pub promote_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct Rotation {
+    /// Replacements, in the same order as the current keys (None = not rotated)
+    next: Vec<Option<HostKey>>,
+    info: RotationInfo,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    keys: Vec<HostKey>,
+    rotation: Option<Rotation>,
+    retired: Vec<HostKeyInfo>,
+}
+
+/// The served host keys
+#[derive(Debug, Default)]
+pub struct HostKeys {
+    inner: RwLock<Inner>,
+    /// Bumped whenever the served keys change
+    generation: AtomicU64,
+}
+
+impl HostKeys {
+    /// Serve `keys`, resuming a rotation left by a previous run
+    pub fn install(&self, keys: Vec<HostKey>, grace: Duration) {
+        let mut rotation: Option<Rotation> = None;
+        let mut next = Vec::with_capacity(keys.len());
+        for host_key in &keys {
+            let resumed = host_key.path.as_ref().and_then(|path| {
+                let algorithm = HostKeyAlgorithm::of(&host_key.key)?;
+                let key = match load_rotated(path, algorithm) {
+                    Ok(key) => key?,
+                    Err(e) => {
+                        warn!("Ignoring rotated key for {}: {}", path.display(), e);
+                        return None;
+                    }
+                };
+                let written: DateTime<Utc> = std::fs::metadata(next_key_path(path))
+                    .and_then(|meta| meta.modified())
+                    .map(Into::into)
+                    .unwrap_or_else(|_| Utc::now());
+                Some((HostKey::new(key, Some(path.clone())), written))
+            });
+            if let Some((_, written)) = &resumed {
+                let started_at = rotation.as_ref().map_or(*written, |r| r.info.started_at.min(*written));
+                rotation = Some(Rotation {
+                    next: Vec::new(),
+                    info: RotationInfo {
+                        started_at,
+                        promote_at: after(started_at, grace),
+                    },
+                });
+            }
+            next.push(resumed.map(|(key, _)| key));
+        }
+        if let Some(rotation) = rotation.as_mut() {
+            info!("Resuming host key rotation, keys switch at {}", rotation.info.promote_at);
+            rotation.next = next;
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        *inner = Inner {
+            keys,
+            rotation,
+            retired: Vec::new(),
+        };
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Changes whenever [`served`](Self::served) would return different keys
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Keys presented to clients, in order of preference
+    pub fn served(&self) -> Vec<russh_keys::PrivateKey> {
+        self.inner.read().unwrap().keys.iter().map(|k| k.key.clone()).collect()
+    }
+
+    /// Every published fingerprint and the rotation in progress, if any
+    pub fn published(&self) -> (Vec<HostKeyInfo>, Option<RotationInfo>) {
+        let inner = self.inner.read().unwrap();
+        let mut keys: Vec<_> = inner.keys.iter().map(|k| k.info(HostKeyStatus::Active)).collect();
+        if let Some(rotation) = &inner.rotation {
+            keys.extend(rotation.next.iter().flatten().map(|k| k.info(HostKeyStatus::Next)));
+        }
+        keys.extend(inner.retired.iter().cloned());
+        (keys, inner.rotation.as_ref().map(|r| r.info.clone()))
+    }
+
+    /// Generate replacements for the file-backed keys, switched to after
+    /// `grace`. Generating RSA keys takes a while, so call this off the
+    /// async runtime.
+    pub fn rotate(&self, grace: Duration) -> anyhow::Result<RotationInfo> {
+        let keys = {
+            let inner = self.inner.read().unwrap();
+            if inner.rotation.is_some() {
+                anyhow::bail!("a host key rotation is already in progress");
+            }
+            inner.keys.clone()
+        };
+        if keys.iter().all(|k| k.path.is_none()) {
+            anyhow::bail!("the host keys were supplied by the embedding program and can't be rotated");
+        }
+
+        let mut next = Vec::with_capacity(keys.len());
+        for host_key in &keys {
+            let replacement = match (&host_key.path, HostKeyAlgorithm::of(&host_key.key)) {
+                (Some(path), Some(algorithm)) => Some(HostKey::new(rotate(path, algorithm)?, Some(path.clone()))),
+                _ => None,
+            };
+            next.push(replacement);
+        }
+
+        let started_at = Utc::now();
+        let info = RotationInfo {
+            started_at,
+            promote_at: after(started_at, grace),
+        };
+        let mut inner = self.inner.write().unwrap();
+        if inner.rotation.is_some() {
+            anyhow::bail!("a host key rotation is already in progress");
+        }
+        inner.rotation = Some(Rotation {
+            next,
+            info: info.clone(),
+        });
+        info!("Host key rotation started, keys switch at {}", info.promote_at);
+        Ok(info)
+    }
+
+    /// Switch to the rotated keys if the grace period is over; returns
+    /// whether the keys changed
+    pub fn finish_due_rotation(&self, now: DateTime<Utc>) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        if inner.rotation.as_ref().is_none_or(|rotation| rotation.info.promote_at > now) {
+            return false;
+        }
+        let Some(rotation) = inner.rotation.take() else {
+            return false;
+        };
+        let mut retired = Vec::new();
+        for (current, next) in inner.keys.iter_mut().zip(rotation.next) {
+            let Some(next) = next else {
+                continue;
+            };
+            if let Some(path) = &current.path {
+                if let Err(e) = promote(path) {
+                    warn!("Host key {} not rotated: {:#}", path.display(), e);
+                    continue;
+                }
+            }
+            retired.push(current.info(HostKeyStatus::Retired));
+            *current = next;
+        }
+        inner.retired = retired;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_lifecycle() {
+        let path = std::env::temp_dir().join(format!("exlo-host-keys-{}.pem", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = crate::key::load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, None).unwrap();
+        let host_keys = HostKeys::default();
+        host_keys.install(vec![HostKey::new(key.clone(), Some(path.clone()))], Duration::ZERO);
+        let generation = host_keys.generation();
+
+        let info = host_keys.rotate(Duration::from_secs(60)).unwrap();
+        assert!(host_keys.rotate(Duration::from_secs(60)).is_err());
+        let (published, rotation) = host_keys.published();
+        let statuses: Vec<_> = published.iter().map(|k| k.status).collect();
+        assert_eq!(statuses, vec![HostKeyStatus::Active, HostKeyStatus::Next]);
+        assert!(rotation.is_some());
+        // The old key is still the one presented
+        assert!(!host_keys.finish_due_rotation(Utc::now()));
+        assert_eq!(host_keys.served()[0].public_key(), key.public_key());
+
+        assert!(host_keys.finish_due_rotation(info.promote_at));
+        assert_ne!(host_keys.generation(), generation);
+        let on_disk = crate::key::load_or_generate_key(&path, HostKeyAlgorithm::Ed25519, None).unwrap();
+        assert_eq!(host_keys.served()[0].public_key(), on_disk.public_key());
+        assert_ne!(on_disk.public_key(), key.public_key());
+        let (published, rotation) = host_keys.published();
+        assert_eq!(published[1].status, HostKeyStatus::Retired);
+        assert!(rotation.is_none());
+
+        let mut old = path.as_os_str().to_owned();
+        old.push(".old");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(old);
+    }
+}
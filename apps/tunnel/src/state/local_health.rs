@@ -0,0 +1,68 @@
+//! Failed health checks of tunnels' local services.
+//!
+//! The port probe at registration runs once; `ssh::health_check` probes each
+//! tunnel's local port again every `HEALTH_CHECK_INTERVAL`. A single failed
+//! probe can be a restart in progress, so a tunnel only counts as degraded
+//! after `FAILURES_BEFORE_DEGRADED` failures in a row, and as healthy again
+//! after the first probe that succeeds.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Failed probes in a row before a tunnel is marked degraded
+pub const FAILURES_BEFORE_DEGRADED: u32 = 2;
+
+/// Consecutive failed probes by subdomain
+#[derive(Debug, Default)]
+pub struct HealthChecks {
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+impl HealthChecks {
+    /// Count a probe; returns whether the local service now counts as down
+    pub fn record(&self, subdomain: &str, answered: bool) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        if answered {
+            failures.remove(subdomain);
+            return false;
+        }
+        let count = failures.entry(subdomain.to_string()).or_default();
+        *count += 1;
+        *count >= FAILURES_BEFORE_DEGRADED
+    }
+
+    /// Keep a renamed tunnel's failure count
+    pub fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(count) = failures.remove(subdomain) {
+            failures.insert(new_subdomain.to_string(), count);
+        }
+    }
+
+    /// Forget subdomains without a registered tunnel
+    pub fn prune(&self, subdomains: &[String]) {
+        self.failures
+            .lock()
+            .unwrap()
+            .retain(|subdomain, _| subdomains.contains(subdomain));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consecutive_failures() {
+        let checks = HealthChecks::default();
+        assert!(!checks.record("app", false));
+        assert!(checks.record("app", false));
+        assert!(checks.record("app", false));
+        // One answer resets the count
+        assert!(!checks.record("app", true));
+        assert!(!checks.record("app", false));
+
+        checks.prune(&[]);
+        assert!(!checks.record("app", false));
+    }
+}
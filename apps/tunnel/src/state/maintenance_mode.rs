@@ -0,0 +1,76 @@
+//! Server-wide maintenance switch.
+//!
+//! While it is on, tunnels that are already registered keep serving (and
+//! sessions may reconnect to them within the reconnection window), but new
+//! SSH sessions and new tunnels are refused with a notice. Operators turn it
+//! on with `MAINTENANCE_MODE` at startup or through the management API, e.g.
+//! before draining a node for a rollout.
+
+use std::time::SystemTime;
+
+use tokio::sync::RwLock;
+
+/// Shown when no operator message was given
+pub const DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "The server is under maintenance and not accepting new tunnels. Existing tunnels keep working; please try again later.";
+
+/// An active maintenance period
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceNotice {
+    pub message: String,
+    pub since: SystemTime,
+}
+
+/// Whether the server is in maintenance mode
+#[derive(Debug, Default)]
+pub struct MaintenanceMode {
+    current: RwLock<Option<MaintenanceNotice>>,
+}
+
+impl MaintenanceMode {
+    /// Turn maintenance mode on, or replace its message
+    pub async fn enable(&self, message: Option<String>) {
+        let message = message
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_string());
+        let mut current = self.current.write().await;
+        let since = current.as_ref().map_or_else(SystemTime::now, |notice| notice.since);
+        *current = Some(MaintenanceNotice { message, since });
+    }
+
+    /// Turn maintenance mode off; returns whether it was on
+    pub async fn disable(&self) -> bool {
+        self.current.write().await.take().is_some()
+    }
+
+    /// The active maintenance period, if any
+    pub async fn current(&self) -> Option<MaintenanceNotice> {
+        self.current.read().await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_enable_and_disable() {
+        let mode = MaintenanceMode::default();
+        assert_eq!(mode.current().await, None);
+
+        mode.enable(Some("  ".to_string())).await;
+        let notice = mode.current().await.unwrap();
+        assert_eq!(notice.message, DEFAULT_MAINTENANCE_MESSAGE);
+
+        // A new message keeps the start of the period
+        mode.enable(Some("Upgrading".to_string())).await;
+        let updated = mode.current().await.unwrap();
+        assert_eq!(updated.message, "Upgrading");
+        assert_eq!(updated.since, notice.since);
+
+        assert!(mode.disable().await);
+        assert!(!mode.disable().await);
+        assert_eq!(mode.current().await, None);
+    }
+}
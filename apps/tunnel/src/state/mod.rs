@@ -0,0 +1,1712 @@
+//! State management for tunnel registry.
+
+pub mod activations;
+pub mod audit;
+pub mod bandwidth;
+pub mod bans;
+pub mod channel_pool;
+pub mod channel_slots;
+pub mod claims;
+pub mod cleanup;
+pub mod cluster;
+pub mod dedicated_ports;
+pub mod domains;
+pub mod events;
+pub mod forward_addresses;
+pub mod har;
+pub mod header_rules;
+pub mod health;
+pub mod history;
+pub mod hooks;
+pub mod host_keys;
+pub mod local_health;
+pub mod maintenance_mode;
+pub mod motd;
+pub mod oauth;
+pub mod perf_profiles;
+pub mod reconcile;
+pub mod requests;
+pub mod response_cache;
+pub mod resume_tokens;
+pub mod static_routes;
+pub mod stats;
+pub mod status_alerts;
+pub mod subdomain_pool;
+pub mod tunnel_limits;
+pub mod verified_keys;
+pub mod webhooks;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::info;
+use russh::server::Handle;
+use russh::ChannelId;
+use tokio::sync::RwLock;
+
+use crate::acl::Capabilities;
+use crate::config::{get as get_config, is_loaded as config_loaded, reloadable};
+use crate::error::TunnelError;
+use crate::maintenance::MaintenanceStats;
+use crate::proxy::access_log::AccessLogEntry;
+use crate::proxy::close_reason::CloseReason;
+use crate::reputation::IpReputation;
+use crate::terminal_ui::{OutputMode, Ui};
+
+use self::activations::PendingActivations;
+use self::audit::{AuditEvent, AuditLog, SYSTEM_ACTOR};
+use self::bandwidth::Bandwidth;
+use self::bans::BanList;
+use self::channel_pool::ChannelPool;
+use self::channel_slots::ChannelSlots;
+use self::claims::SubdomainClaims;
+use self::cleanup::CleanupTasks;
+use self::cluster::ClusterRegistry;
+use self::dedicated_ports::DedicatedPorts;
+use self::domains::CustomDomains;
+use self::events::{EventLog, TunnelEventKind};
+use self::forward_addresses::ForwardAddresses;
+use self::har::HarCaptures;
+use self::header_rules::HeaderRules;
+use self::health::Readiness;
+use self::history::{HistoryEntry, TunnelHistory};
+use self::hooks::Hooks;
+use self::host_keys::HostKeys;
+use self::local_health::HealthChecks;
+use self::maintenance_mode::MaintenanceMode;
+use self::motd::MotdBoard;
+use self::oauth::OAuthPolicies;
+use self::perf_profiles::PerfProfile;
+use self::reconcile::Reconciler;
+use self::requests::RequestFeeds;
+use self::response_cache::ResponseCache;
+use self::resume_tokens::ResumeTokens;
+use self::static_routes::StaticRoutes;
+use self::stats::LatencyStats;
+use self::status_alerts::{AlertChange, StatusAlert, StatusCounts};
+use self::subdomain_pool::SubdomainPool;
+use self::tunnel_limits::{LimitExceeded, TunnelLimits};
+use self::verified_keys::VerifiedKeyStore;
+use self::webhooks::WebhookRules;
+
+/// How long a verified key remains valid (30 minutes)
+const VERIFIED_KEY_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How long a disconnected tunnel remains in the list (same as verified key TTL)
+const DISCONNECTED_TUNNEL_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Minimum interval between Device Flow requests per IP (10 seconds)
+const DEVICE_FLOW_RATE_LIMIT: Duration = Duration::from_secs(10);
+
+/// Maximum Device Flow attempts per IP within the rate limit window (5 attempts per minute)
+const DEVICE_FLOW_MAX_ATTEMPTS: u32 = 5;
+
+/// Window for counting Device Flow attempts (1 minute)
+const DEVICE_FLOW_WINDOW: Duration = Duration::from_secs(60);
+
+/// Maximum Device Flow attempts per IP within the window across all keys, so a
+/// team behind one NAT isn't limited like a single client (30 per minute)
+const DEVICE_FLOW_IP_MAX_ATTEMPTS: u32 = 30;
+
+/// Device Flow budget of each key (or keyless session) behind an IP
+pub const DEVICE_FLOW_KEY_BUDGET: RateBudget = RateBudget {
+    min_interval: DEVICE_FLOW_RATE_LIMIT,
+    max_attempts: DEVICE_FLOW_MAX_ATTEMPTS,
+};
+
+/// Device Flow budget of an IP, shared by its keys
+pub const DEVICE_FLOW_IP_BUDGET: RateBudget = RateBudget {
+    min_interval: Duration::ZERO,
+    max_attempts: DEVICE_FLOW_IP_MAX_ATTEMPTS,
+};
+
+/// An additional upstream sharing a tunnel's subdomain (path-routing mode).
+///
+/// Created when a session forwards several ports under one subdomain, e.g.
+/// `ssh -R web:80:localhost:5173 -R api:80:localhost:3000 myapp@server`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedForward {
+    /// Name used for routing (bind address label or port number)
+    pub name: String,
+    /// The address the client requested to forward
+    pub address: String,
+    /// The port the client requested
+    pub port: u32,
+}
+
+impl NamedForward {
+    pub fn new(address: &str, port: u32) -> Self {
+        Self {
+            name: forward_name(address, port),
+            address: address.to_string(),
+            port,
+        }
+    }
+}
+
+/// Whether a forward's bind address is a plain label (`-R api:80:...`)
+pub fn is_forward_label(address: &str) -> bool {
+    !address.is_empty()
+        && address.parse::<IpAddr>().is_err()
+        && !matches!(address, "localhost" | "*")
+        && address
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Derive a routing name for a forward.
+/// A bind address that is a plain label (`-R api:80:...`) names the forward;
+/// wildcard, loopback, and IP bind addresses fall back to the port number.
+pub fn forward_name(address: &str, port: u32) -> String {
+    if is_forward_label(address) {
+        address.to_lowercase()
+    } else {
+        port.to_string()
+    }
+}
+
+/// Generate the ID that joins a tunnel's web backend record with its proxy logs
+pub fn generate_correlation_id() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 8];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Whether `RESERVED_SUBDOMAINS` keeps everyone from registering the subdomain
+fn is_reserved(subdomain: &str) -> bool {
+    config_loaded() && reloadable().is_reserved(subdomain)
+}
+
+/// Traffic carried by a tunnel through the HTTP proxy
+#[derive(Debug, Clone, Default)]
+pub struct TunnelTraffic {
+    /// Bytes sent from visitors to the tunnel
+    pub bytes_in: u64,
+    /// Bytes sent from the tunnel back to visitors
+    pub bytes_out: u64,
+    /// When the tunnel last carried a proxied connection
+    pub last_activity: Option<SystemTime>,
+    /// Proxied connections currently open
+    pub active_connections: u32,
+    /// Responses of the tunneled service per status class
+    pub statuses: StatusCounts,
+    /// Closed proxied connections per close reason (`client_eof`, `error:...`)
+    pub close_reasons: BTreeMap<String, u64>,
+}
+
+impl TunnelTraffic {
+    fn count_close(&mut self, reason: &CloseReason) {
+        *self.close_reasons.entry(reason.to_string()).or_default() += 1;
+    }
+}
+
+/// A tunnel's traffic counters, shared by every copy of its `TunnelInfo` so
+/// the proxy can count without taking the registry's write lock
+#[derive(Debug, Clone, Default)]
+pub struct SharedTraffic(Arc<Mutex<TunnelTraffic>>);
+
+impl SharedTraffic {
+    /// The counters as they are now
+    pub fn snapshot(&self) -> TunnelTraffic {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut TunnelTraffic) -> R) -> R {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+/// Information about a registered tunnel.
+#[derive(Debug, Clone)]
+pub struct TunnelInfo {
+    /// The assigned subdomain (e.g., "abc123")
+    pub subdomain: String,
+    /// SSH session handle for opening forwarded channels
+    pub handle: Handle,
+    /// The address the client requested to forward
+    pub requested_address: String,
+    /// The port the client requested (client's localhost port)
+    pub requested_port: u32,
+    /// Server port that was "virtually" bound
+    pub server_port: u32,
+    /// When this tunnel was created (wall-clock time for persistence)
+    pub created_at: SystemTime,
+    /// The client's username
+    pub username: String,
+    /// The client's IP address
+    pub client_ip: String,
+    /// Whether the SSH connection is still active
+    pub is_connected: bool,
+    /// When the tunnel was disconnected (None if still connected)
+    pub disconnected_at: Option<SystemTime>,
+    /// Cluster node holding the SSH session for this tunnel
+    pub node_id: String,
+    /// Additional named upstreams sharing this subdomain
+    pub forwards: Vec<NamedForward>,
+    /// Registered although the local service didn't answer the probe;
+    /// cleared by the first successful proxied connection
+    pub awaiting_local_service: bool,
+    /// Since when the local service has failed health checks (None = answering)
+    pub degraded_since: Option<SystemTime>,
+    /// Traffic counters updated by the proxy
+    pub traffic: SharedTraffic,
+    /// Session channel for terminal notices (None until the client opens it)
+    pub session_channel_id: Option<ChannelId>,
+    /// ID of the SSH session holding this tunnel
+    pub session_id: String,
+    /// Password / share URL secret; requests must carry it when set
+    pub share_secret: Option<String>,
+    /// Secret path token; visitors without it get a 404 when set
+    pub secret_path: Option<String>,
+    /// Sent to the web backend on registration and stamped on proxy logs
+    pub correlation_id: String,
+    /// Inject the preview banner into HTML responses
+    pub preview_banner: bool,
+    /// Answer cacheable requests from the response cache
+    pub response_cache: bool,
+    /// Headers added to and removed from responses
+    pub response_headers: HeaderRules,
+    /// Features the owner's tier allows
+    pub capabilities: Capabilities,
+    /// Owner's tier from the auth backend (picks their bandwidth budget)
+    pub tier: Option<String>,
+    /// How notices are written to the session holding the tunnel
+    pub output_mode: OutputMode,
+    /// Language and terminal size of the session's boxes
+    pub ui: Ui,
+    /// Owner's alert on the share of 5xx responses
+    pub status_alert: Option<StatusAlert>,
+    /// Tuning of proxied connections (None = global settings)
+    pub perf_profile: Option<PerfProfile>,
+    /// Control channel of the session holding the tunnel (see `ssh::control`)
+    pub control_channel_id: Option<ChannelId>,
+}
+
+impl TunnelInfo {
+    /// How long the tunnel has gone without proxied traffic
+    /// (None while a connection is open)
+    pub fn idle_for(&self, now: SystemTime) -> Option<Duration> {
+        let traffic = self.traffic.snapshot();
+        if traffic.active_connections > 0 {
+            return None;
+        }
+        let since = traffic.last_activity.unwrap_or(self.created_at);
+        Some(now.duration_since(since).unwrap_or_default())
+    }
+
+    /// The forward the tunnel was created with
+    pub fn primary_forward(&self) -> NamedForward {
+        NamedForward::new(&self.requested_address, self.requested_port)
+    }
+
+    /// Pick the upstream forward for a request (see `select_forward`)
+    pub fn select_upstream(&self, upstream: Option<&str>, path: Option<&str>) -> Option<NamedForward> {
+        select_forward(&self.primary_forward(), &self.forwards, upstream, path).cloned()
+    }
+}
+
+/// Pick the upstream forward for a request.
+///
+/// An explicit upstream name (from the `X-EXLO-Upstream` header) must match a
+/// forward. Otherwise the first path segment selects a named forward, falling
+/// back to the primary forward.
+pub fn select_forward<'a>(
+    primary: &'a NamedForward,
+    forwards: &'a [NamedForward],
+    upstream: Option<&str>,
+    path: Option<&str>,
+) -> Option<&'a NamedForward> {
+    let find = |name: &str| {
+        std::iter::once(primary)
+            .chain(forwards.iter())
+            .find(|f| f.name.eq_ignore_ascii_case(name))
+    };
+
+    if let Some(name) = upstream {
+        return find(name.trim());
+    }
+
+    if !forwards.is_empty() {
+        let segment = path
+            .and_then(|p| p.trim_start_matches('/').split(['/', '?']).next())
+            .filter(|s| !s.is_empty());
+        if let Some(found) = segment.and_then(find) {
+            return Some(found);
+        }
+    }
+
+    Some(primary)
+}
+
+/// Time until which a verification made at `verified_at` may be renewed
+/// (`VERIFIED_KEY_MAX_AGE`)
+fn renewal_limit(verified_at: SystemTime) -> Option<SystemTime> {
+    let max_age = if config_loaded() {
+        get_config().verified_key_max_age
+    } else {
+        None
+    };
+    max_age.map(|max_age| verified_at + max_age)
+}
+
+/// Renew unexpired keys owning one of the `connected` tunnels (subdomain ->
+/// user_id); returns how many were renewed
+fn renew_active_keys(
+    keys: &mut HashMap<String, VerifiedKey>,
+    connected: &HashMap<String, String>,
+    now: SystemTime,
+) -> usize {
+    let mut renewed = 0;
+    for key in keys.values_mut().filter(|key| !key.is_expired()) {
+        let active = key
+            .subdomains
+            .values()
+            .any(|subdomain| connected.get(subdomain) == Some(&key.user_id));
+        if active {
+            key.renew(now);
+            renewed += 1;
+        }
+    }
+    renewed
+}
+
+/// Put `tunnel` in the slot of `subdomain` in place of a tunnel of the same
+/// owner, under the shard's lock; returns the replaced tunnel (None if the
+/// slot was free). A tunnel of another owner is `SubdomainTaken`.
+fn replace_own<T>(
+    tunnels: &DashMap<String, Arc<T>>,
+    subdomain: &str,
+    tunnel: T,
+    owner: fn(&T) -> &str,
+) -> Result<Option<Arc<T>>, TunnelError> {
+    match tunnels.entry(subdomain.to_string()) {
+        Entry::Occupied(slot) if owner(slot.get()) != owner(&tunnel) => {
+            Err(TunnelError::SubdomainTaken(subdomain.to_string()))
+        }
+        Entry::Occupied(mut slot) => Ok(Some(slot.insert(Arc::new(tunnel)))),
+        Entry::Vacant(slot) => {
+            slot.insert(Arc::new(tunnel));
+            Ok(None)
+        }
+    }
+}
+
+/// A verified public key with expiration.
+///
+/// The verification lasts `VERIFIED_KEY_TTL` from `verified_at`, which is
+/// moved forward by reconnects and while the key's tunnels are connected,
+/// but not past `renewable_until`.
+#[derive(Debug, Clone)]
+pub struct VerifiedKey {
+    pub user_id: String,
+    /// User's display name (nickname)
+    pub display_name: Option<String>,
+    /// Last verification or renewal
+    pub verified_at: SystemTime,
+    /// Renewals don't extend the verification past this time (None = no limit)
+    pub renewable_until: Option<SystemTime>,
+    /// Subdomains for this key, keyed by client port (to preserve on reconnect)
+    /// Maps client_port -> subdomain
+    pub subdomains: HashMap<u32, String>,
+    /// User's tier from the auth backend (decides their capabilities)
+    pub tier: Option<String>,
+}
+
+impl VerifiedKey {
+    pub fn new(user_id: String, display_name: Option<String>, tier: Option<String>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            user_id,
+            display_name,
+            verified_at: now,
+            renewable_until: renewal_limit(now),
+            subdomains: HashMap::new(),
+            tier,
+        }
+    }
+
+    /// When the verification stops skipping the Device Flow
+    pub fn expires_at(&self) -> SystemTime {
+        let expires_at = self.verified_at + VERIFIED_KEY_TTL;
+        match self.renewable_until {
+            Some(limit) => expires_at.min(limit),
+            None => expires_at,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now();
+        now.duration_since(self.verified_at).is_err() || now > self.expires_at()
+    }
+
+    /// Extend the verification by activity
+    pub fn renew(&mut self, now: SystemTime) {
+        self.verified_at = now;
+    }
+
+    /// Get display name (falls back to truncated user_id if not set)
+    pub fn get_display_name(&self) -> String {
+        self.display_name
+            .clone()
+            .unwrap_or_else(|| crate::device::truncate_user_id(&self.user_id))
+    }
+}
+
+/// How often Device Flow requests may be made within `DEVICE_FLOW_WINDOW`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateBudget {
+    /// Minimum time between requests
+    pub min_interval: Duration,
+    pub max_attempts: u32,
+}
+
+/// Rate limit tracking for Device Flow requests
+#[derive(Debug, Clone)]
+pub struct RateLimitEntry {
+    pub last_request: SystemTime,
+    pub attempts: u32,
+    pub window_start: SystemTime,
+}
+
+impl RateLimitEntry {
+    pub fn new() -> Self {
+        let now = SystemTime::now();
+        Self {
+            last_request: now,
+            attempts: 1,
+            window_start: now,
+        }
+    }
+
+    /// Whether another request would exceed a single key's budget
+    pub fn is_rate_limited(&self) -> bool {
+        self.exceeds(&DEVICE_FLOW_KEY_BUDGET)
+    }
+
+    /// Whether another request would exceed `budget`
+    pub fn exceeds(&self, budget: &RateBudget) -> bool {
+        let now = SystemTime::now();
+        
+        // Check minimum interval since last request
+        if let Ok(since_last) = now.duration_since(self.last_request) {
+            if since_last < budget.min_interval {
+                return true;
+            }
+        }
+        
+        // Check max attempts in window
+        if let Ok(since_window_start) = now.duration_since(self.window_start) {
+            if since_window_start < DEVICE_FLOW_WINDOW && self.attempts >= budget.max_attempts {
+                return true;
+            }
+        }
+        
+        false
+    }
+
+    pub fn record_attempt(&mut self) {
+        let now = SystemTime::now();
+        
+        // Reset window if expired
+        if let Ok(since_window_start) = now.duration_since(self.window_start) {
+            if since_window_start >= DEVICE_FLOW_WINDOW {
+                self.attempts = 0;
+                self.window_start = now;
+            }
+        }
+        
+        self.last_request = now;
+        self.attempts += 1;
+    }
+}
+
+impl Default for RateLimitEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Thread-safe global state for the tunnel registry.
+#[derive(Debug, Default)]
+pub struct AppState {
+    /// Map from subdomain -> TunnelInfo, sharded so lookups by the proxy
+    /// don't contend with each other or with updates to other tunnels.
+    /// Entries are replaced copy-on-write; readers get a cheap `Arc`.
+    pub tunnels: DashMap<String, Arc<TunnelInfo>>,
+    /// Map from public key fingerprint -> VerifiedKey
+    pub verified_keys: RwLock<HashMap<String, VerifiedKey>>,
+    /// Where verified keys are persisted across restarts
+    key_store: VerifiedKeyStore,
+    /// Rate limiting for Device Flow requests (IP -> RateLimitEntry)
+    rate_limits: RwLock<HashMap<IpAddr, RateLimitEntry>>,
+    /// Device Flow rate limits of each key behind an IP ((IP, fingerprint) ->
+    /// RateLimitEntry; an empty fingerprint for sessions without a key)
+    key_rate_limits: RwLock<HashMap<(IpAddr, String), RateLimitEntry>>,
+    /// Tunnels owned by other nodes in the cluster
+    pub cluster: ClusterRegistry,
+    /// Operator message shown once to each user on their next connect
+    pub motd: MotdBoard,
+    /// Listener state reported by the readiness probe
+    pub readiness: Readiness,
+    /// Ended tunnels per user
+    pub history: TunnelHistory,
+    /// Pass statistics of the periodic maintenance tasks
+    pub maintenance: MaintenanceStats,
+    /// Server-wide switch refusing new sessions and tunnels
+    pub maintenance_mode: MaintenanceMode,
+    /// Tunnel lifecycle events for the management event stream
+    pub events: EventLog,
+    /// IP reputation checks for incoming connections
+    pub reputation: IpReputation,
+    /// Custom domains attached to tunnels
+    pub domains: CustomDomains,
+    /// Subdomains reserved for a user account
+    pub claims: SubdomainClaims,
+    /// Pre-generated random subdomains for activation
+    pub subdomain_pool: SubdomainPool,
+    /// Per-tunnel request rate and connection limits for the HTTP proxy
+    pub tunnel_limits: TunnelLimits,
+    /// Bandwidth budgets per tunnel and user, and measured throughput
+    pub bandwidth: Bandwidth,
+    /// Banned client IPs (manual and automatic)
+    pub bans: BanList,
+    /// Background cleanup tasks (handler drops, kicks, idle disconnects)
+    pub cleanup: CleanupTasks,
+    /// Orphaned backend registration cleanup and its counters
+    pub reconcile: Reconciler,
+    /// Finished requests for the sessions showing a live view
+    pub requests: RequestFeeds,
+    /// Sessions waiting for an activation callback from the web app
+    pub activations: PendingActivations,
+    /// Idle forwarded channels kept for the next HTTP request
+    pub channel_pool: ChannelPool,
+    /// Forwarded channels busy per tunnel, capped by `MAX_CHANNELS_PER_TUNNEL`
+    pub channel_slots: ChannelSlots,
+    /// Static responses kept for tunnels with the response cache on
+    pub response_cache: ResponseCache,
+    /// Exchanges recorded as HAR for subdomains being captured
+    pub har: HarCaptures,
+    /// Bind address checks and their DNS cache
+    pub forward_addresses: ForwardAddresses,
+    /// Append-only record of tunnel, verification and admin actions
+    pub audit: AuditLog,
+    /// Subdomains the operator routes to fixed upstreams
+    pub static_routes: StaticRoutes,
+    /// SSH host keys being served and any rotation in progress
+    pub host_keys: HostKeys,
+    /// Tunnels whose visitors must sign in with an identity provider
+    pub oauth: OAuthPolicies,
+    /// Signature checks for webhook deliveries to tunnels
+    pub webhooks: WebhookRules,
+    /// Failed health checks of tunnels' local services in a row
+    pub health_checks: HealthChecks,
+    /// Embedder callbacks for verifications and proxied requests
+    pub hooks: Hooks,
+    /// Latency histograms of the requests each tunnel answered
+    pub latency: LatencyStats,
+    /// Public ports bound for `-R tcp:<port>:...` / `-R udp:<port>:...` forwards
+    pub dedicated_ports: DedicatedPorts,
+    /// Tokens resuming a dropped session (`resume-<token>` username)
+    pub resume_tokens: ResumeTokens,
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report a request the proxy finished for a session's tunnel to its
+    /// live view and the registered hooks
+    pub fn request_finished(&self, session_id: &str, entry: &AccessLogEntry) {
+        if let (Some(subdomain), Some(first_byte_ms)) = (&entry.subdomain, entry.first_byte_ms) {
+            self.latency.record(subdomain, first_byte_ms, entry.duration_ms);
+        }
+        self.requests.publish(session_id, entry);
+        self.hooks.request(entry);
+    }
+
+    /// State persisting subdomain claims, the audit trail and verified keys
+    /// to the given files instead of the configured ones (None = memory only).
+    /// Claims and unexpired verified keys saved before are loaded.
+    pub fn with_storage(
+        claims_path: Option<PathBuf>,
+        audit_path: Option<PathBuf>,
+        verified_keys_path: Option<PathBuf>,
+    ) -> Self {
+        let key_store = VerifiedKeyStore::new(verified_keys_path);
+        Self {
+            claims: SubdomainClaims::load(claims_path),
+            audit: AuditLog::new(audit_path),
+            verified_keys: RwLock::new(key_store.load()),
+            key_store,
+            ..Self::default()
+        }
+    }
+
+    /// Check if a key behind an IP is rate-limited for Device Flow requests
+    /// and record the request atomically to prevent race conditions. Each key
+    /// has its own budget; the IP's budget is shared by its keys and larger,
+    /// so clients behind one NAT don't block each other.
+    /// Returns true if rate-limited (request should be rejected).
+    pub async fn check_and_record_device_flow(&self, ip: IpAddr, fingerprint: Option<&str>) -> bool {
+        let mut limits = self.rate_limits.write().await;
+        let mut key_limits = self.key_rate_limits.write().await;
+        let key = (ip, fingerprint.unwrap_or_default().to_string());
+
+        let limited = key_limits.get(&key).is_some_and(|entry| entry.exceeds(&DEVICE_FLOW_KEY_BUDGET))
+            || limits.get(&ip).is_some_and(|entry| entry.exceeds(&DEVICE_FLOW_IP_BUDGET));
+        if limited {
+            return true;
+        }
+        // A first request is not rate limited, but recorded
+        limits.entry(ip).and_modify(RateLimitEntry::record_attempt).or_default();
+        key_limits.entry(key).and_modify(RateLimitEntry::record_attempt).or_default();
+        false
+    }
+
+    /// Device Flow rate limit state of an IP and of each key behind it
+    /// (None if it made no recent requests)
+    pub async fn device_flow_rate_limits(&self, ip: IpAddr) -> Option<(RateLimitEntry, Vec<(String, RateLimitEntry)>)> {
+        let entry = self.rate_limits.read().await.get(&ip)?.clone();
+        let mut keys: Vec<_> = self
+            .key_rate_limits
+            .read()
+            .await
+            .iter()
+            .filter(|((key_ip, _), _)| *key_ip == ip)
+            .map(|((_, fingerprint), entry)| (fingerprint.clone(), entry.clone()))
+            .collect();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        Some((entry, keys))
+    }
+
+    /// Forget an IP's Device Flow rate limits; returns whether it had any
+    pub async fn clear_device_flow_rate_limits(&self, ip: IpAddr) -> bool {
+        let mut limits = self.rate_limits.write().await;
+        let mut key_limits = self.key_rate_limits.write().await;
+        key_limits.retain(|(key_ip, _), _| *key_ip != ip);
+        limits.remove(&ip).is_some()
+    }
+
+    /// Check if an IP is rate-limited for Device Flow requests (read-only check)
+    #[deprecated(note = "Use check_and_record_device_flow for atomic operation")]
+    pub async fn is_device_flow_rate_limited(&self, ip: IpAddr) -> bool {
+        let limits = self.rate_limits.read().await;
+        if let Some(entry) = limits.get(&ip) {
+            entry.is_rate_limited()
+        } else {
+            false
+        }
+    }
+
+    /// Record a Device Flow request from an IP
+    #[deprecated(note = "Use check_and_record_device_flow for atomic operation")]
+    pub async fn record_device_flow_request(&self, ip: IpAddr) {
+        let mut limits = self.rate_limits.write().await;
+        if let Some(entry) = limits.get_mut(&ip) {
+            entry.record_attempt();
+        } else {
+            limits.insert(ip, RateLimitEntry::new());
+        }
+    }
+
+    /// Clean up old rate limit entries
+    pub async fn cleanup_rate_limits(&self) {
+        {
+            let mut limits = self.rate_limits.write().await;
+            let mut key_limits = self.key_rate_limits.write().await;
+            let now = SystemTime::now();
+            let recent = |entry: &RateLimitEntry| {
+                now.duration_since(entry.window_start)
+                    .map(|elapsed| elapsed < DEVICE_FLOW_WINDOW * 2)
+                    .unwrap_or(false)
+            };
+            limits.retain(|_, entry| recent(entry));
+            key_limits.retain(|_, entry| recent(entry));
+        }
+        self.tunnel_limits.prune().await;
+    }
+
+    /// Whether `username` may hold `subdomain` at all
+    async fn check_registrable(&self, subdomain: &str, username: &str) -> Result<(), TunnelError> {
+        if is_reserved(subdomain)
+            || self.static_routes.contains(subdomain)
+            || !self.claims.allows(subdomain, username).await
+        {
+            return Err(TunnelError::SubdomainReserved(subdomain.to_string()));
+        }
+        if self.cluster.is_owned_elsewhere(subdomain).await {
+            return Err(TunnelError::SubdomainTaken(subdomain.to_string()));
+        }
+        Ok(())
+    }
+
+    pub async fn register_tunnel(&self, info: TunnelInfo) -> Result<(), TunnelError> {
+        self.check_registrable(&info.subdomain, &info.username).await?;
+        let (subdomain, username, port) = (info.subdomain.clone(), info.username.clone(), info.requested_port);
+        // Checked and inserted under the shard's lock, so only one session gets a free subdomain
+        match self.tunnels.entry(subdomain.clone()) {
+            Entry::Occupied(_) => return Err(TunnelError::SubdomainTaken(subdomain)),
+            Entry::Vacant(slot) => {
+                slot.insert(Arc::new(info));
+            }
+        }
+        info!("Registered tunnel: {} -> localhost:{}", subdomain, port);
+        self.events
+            .publish(TunnelEventKind::Connected, &subdomain, None, &username);
+        self.audit
+            .record(AuditEvent::new(&username, "tunnel.created").target(&subdomain));
+        Ok(())
+    }
+
+    /// Register a reconnecting session's tunnel in place of the same user's
+    /// earlier one, in one step so no other session can grab the subdomain in
+    /// between. Returns the replaced tunnel (None if the subdomain was free);
+    /// a tunnel of another user is `SubdomainTaken`.
+    pub async fn take_over_tunnel(&self, info: TunnelInfo) -> Result<Option<Arc<TunnelInfo>>, TunnelError> {
+        self.check_registrable(&info.subdomain, &info.username).await?;
+        let (subdomain, username, port) = (info.subdomain.clone(), info.username.clone(), info.requested_port);
+        let replaced = replace_own(&self.tunnels, &subdomain, info, |t| t.username.as_str())?;
+        if let Some(old) = &replaced {
+            info!(
+                "Tunnel {} moved from session {} ({}) to a new session",
+                subdomain, old.session_id, old.client_ip
+            );
+            self.record_removed(old).await;
+        }
+        info!("Registered tunnel: {} -> localhost:{}", subdomain, port);
+        self.events
+            .publish(TunnelEventKind::Connected, &subdomain, None, &username);
+        self.audit
+            .record(AuditEvent::new(&username, "tunnel.created").target(&subdomain));
+        Ok(replaced)
+    }
+
+    /// Attach an additional named forward to an existing tunnel
+    pub async fn add_forward(&self, subdomain: &str, forward: NamedForward) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        let tunnel = Arc::make_mut(&mut entry);
+
+        if forward.name == tunnel.primary_forward().name
+            || tunnel.forwards.iter().any(|f| f.name == forward.name)
+        {
+            return Err(TunnelError::ForwardNameTaken(forward.name));
+        }
+
+        info!(
+            "Added forward '{}' to tunnel {} -> {}:{}",
+            forward.name, subdomain, forward.address, forward.port
+        );
+        tunnel.forwards.push(forward);
+        Ok(())
+    }
+
+    /// Mark a waiting tunnel's local service as up.
+    /// Returns true if the tunnel was waiting.
+    pub async fn mark_local_service_ready(&self, subdomain: &str) -> bool {
+        match self.tunnels.get_mut(subdomain) {
+            Some(mut tunnel) if tunnel.awaiting_local_service => {
+                Arc::make_mut(&mut tunnel).awaiting_local_service = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record a health check of a tunnel's local service. Returns the tunnel
+    /// if it became degraded or recovered.
+    pub async fn record_health_check(&self, subdomain: &str, answered: bool) -> Option<TunnelInfo> {
+        let down = self.health_checks.record(subdomain, answered);
+        let tunnel = {
+            let mut entry = self.tunnels.get_mut(subdomain)?;
+            if entry.degraded_since.is_some() == down {
+                return None;
+            }
+            let tunnel = Arc::make_mut(&mut entry);
+            tunnel.degraded_since = down.then(SystemTime::now);
+            tunnel.clone()
+        };
+        let kind = if down {
+            TunnelEventKind::Degraded
+        } else {
+            TunnelEventKind::Recovered
+        };
+        self.events.publish(kind, subdomain, None, &tunnel.username);
+        Some(tunnel)
+    }
+
+    /// Record the start of a proxied connection if the tunnel's limits allow it,
+    /// taking a token from its request rate bucket (HTTP/1.1 connections of a
+    /// rate-limited tunnel carry a single request, HTTP/2 calls this per stream).
+    /// An admitted connection must be ended with `record_traffic`.
+    pub async fn connection_opened(&self, subdomain: &str) -> Result<(), LimitExceeded> {
+        let (limit, _) = self.tunnel_limits.limit_for(subdomain).await;
+        let Some(traffic) = self.tunnels.get(subdomain).map(|t| t.traffic.clone()) else {
+            return Ok(());
+        };
+        traffic.update(|traffic| {
+            let admitted = if limit.max_connections > 0 && traffic.active_connections >= limit.max_connections {
+                Err(LimitExceeded::Connections)
+            } else {
+                self.tunnel_limits.check_rate(subdomain, &limit)
+            };
+            if let Err(exceeded) = admitted {
+                traffic.count_close(&CloseReason::LimitExceeded);
+                return Err(exceeded);
+            }
+            traffic.active_connections += 1;
+            traffic.last_activity = Some(SystemTime::now());
+            Ok(())
+        })
+    }
+
+    /// Add a finished proxied connection's traffic and close reason to a
+    /// tunnel's counters
+    pub async fn record_traffic(&self, subdomain: &str, bytes_in: u64, bytes_out: u64, reason: &CloseReason) {
+        if let Some(tunnel) = self.tunnels.get(subdomain) {
+            tunnel.traffic.update(|traffic| {
+                traffic.count_close(reason);
+                traffic.bytes_in += bytes_in;
+                traffic.bytes_out += bytes_out;
+                traffic.active_connections = traffic.active_connections.saturating_sub(1);
+                traffic.last_activity = Some(SystemTime::now());
+            });
+        }
+    }
+
+    /// Count a response of the tunneled service. Returns the tunnel if its
+    /// status alert fired or resolved.
+    pub async fn record_status(&self, subdomain: &str, status: u16) -> Option<(TunnelInfo, AlertChange)> {
+        {
+            let tunnel = self.tunnels.get(subdomain)?;
+            tunnel.traffic.update(|traffic| traffic.statuses.record(status));
+            tunnel.status_alert.as_ref()?;
+        }
+        let mut entry = self.tunnels.get_mut(subdomain)?;
+        let change = Arc::make_mut(&mut entry).status_alert.as_mut()?.observe(Instant::now(), status)?;
+        Some((TunnelInfo::clone(&entry), change))
+    }
+
+    /// Set or clear a tunnel's status alert
+    pub async fn set_status_alert(&self, subdomain: &str, alert: Option<StatusAlert>) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        let tunnel = Arc::make_mut(&mut entry);
+        match &alert {
+            Some(alert) => info!(
+                "Status alert of tunnel {} set to {}% 5xx over {}s",
+                subdomain,
+                alert.threshold_percent,
+                alert.window.as_secs()
+            ),
+            None => info!("Status alert of tunnel {} cleared", subdomain),
+        }
+        tunnel.status_alert = alert;
+        Ok(())
+    }
+
+    /// Change how a tunnel's new proxied connections are tuned
+    pub async fn set_perf_profile(&self, subdomain: &str, profile: Option<PerfProfile>) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        let tunnel = Arc::make_mut(&mut entry);
+        match profile {
+            Some(profile) => info!("Tunnel {} uses the {} profile", subdomain, profile),
+            None => info!("Tunnel {} uses the default profile", subdomain),
+        }
+        tunnel.perf_profile = profile;
+        Ok(())
+    }
+
+    /// Connected tunnels held by an SSH session
+    pub async fn session_subdomains(&self, session_id: &str) -> Vec<String> {
+        self.tunnels
+            .iter()
+            .filter(|t| t.is_connected && t.session_id == session_id)
+            .map(|t| t.subdomain.clone())
+            .collect()
+    }
+
+    /// Tunnels owned by a user across all sessions, sorted by subdomain
+    pub async fn user_tunnels(&self, user_id: &str) -> Vec<Arc<TunnelInfo>> {
+        let mut owned: Vec<_> = self
+            .tunnels
+            .iter()
+            .filter(|t| t.username == user_id)
+            .map(|t| Arc::clone(&t))
+            .collect();
+        owned.sort_by(|a, b| a.subdomain.cmp(&b.subdomain));
+        owned
+    }
+
+    /// Move a tunnel to a new subdomain, keeping its session and counters.
+    /// A stale disconnected entry under the new name is replaced.
+    pub async fn rename_tunnel(&self, subdomain: &str, new_subdomain: &str) -> Result<Arc<TunnelInfo>, TunnelError> {
+        let not_found = || TunnelError::TunnelNotFound(subdomain.to_string());
+        let taken = || TunnelError::SubdomainTaken(new_subdomain.to_string());
+        let owner = self.tunnels.get(subdomain).map(|t| t.username.clone()).ok_or_else(not_found)?;
+        if self.tunnels.get(new_subdomain).is_some_and(|t| t.is_connected) {
+            return Err(taken());
+        }
+        self.check_registrable(new_subdomain, &owner).await?;
+        // The new name is taken before the old one is released, so the
+        // tunnel stays reachable throughout. The old entry is only released
+        // if it is still the one copied; if it changed meanwhile (e.g. a new
+        // share secret), it is copied again.
+        let mut copied: Option<Arc<TunnelInfo>> = None;
+        let tunnel = loop {
+            let Some(original) = self.get_tunnel(subdomain).await else {
+                if let Some(copy) = copied {
+                    self.tunnels.remove_if(new_subdomain, |_, t| Arc::ptr_eq(t, &copy));
+                }
+                return Err(not_found());
+            };
+            let mut moved = TunnelInfo::clone(&original);
+            moved.subdomain = new_subdomain.to_string();
+            let copy = Arc::new(moved);
+            match self.tunnels.entry(new_subdomain.to_string()) {
+                Entry::Occupied(slot)
+                    if slot.get().is_connected && !copied.as_ref().is_some_and(|c| Arc::ptr_eq(c, slot.get())) =>
+                {
+                    return Err(taken());
+                }
+                slot => {
+                    slot.insert(Arc::clone(&copy));
+                }
+            }
+            if self.tunnels.remove_if(subdomain, |_, current| Arc::ptr_eq(current, &original)).is_some() {
+                break copy;
+            }
+            copied = Some(copy);
+        };
+        self.move_subdomain_state(subdomain, new_subdomain).await;
+        info!("Renamed tunnel: {} -> {}", subdomain, new_subdomain);
+        self.events
+            .publish(TunnelEventKind::Renamed, new_subdomain, Some(subdomain), &tunnel.username);
+        Ok(tunnel)
+    }
+
+    /// Move what is kept by subdomain outside the registry to a renamed
+    /// tunnel's new name. Policies follow the tunnel, so it is never exposed
+    /// under its new name without the sign-in, signature checks and limits
+    /// set for it.
+    async fn move_subdomain_state(&self, subdomain: &str, new_subdomain: &str) {
+        self.domains.rename_subdomain(subdomain, new_subdomain).await;
+        self.oauth.rename(subdomain, new_subdomain).await;
+        self.webhooks.rename(subdomain, new_subdomain).await;
+        self.tunnel_limits.rename(subdomain, new_subdomain).await;
+        self.bandwidth.rename(subdomain, new_subdomain);
+        self.channel_slots.rename(subdomain, new_subdomain);
+        self.har.rename(subdomain, new_subdomain);
+        self.health_checks.rename(subdomain, new_subdomain);
+        self.latency.rename(subdomain, new_subdomain);
+        self.resume_tokens.rename(subdomain, new_subdomain);
+        // Cached bodies live under the old name's directory
+        self.response_cache.purge(subdomain).await;
+    }
+
+    /// Replace a tunnel's share secret, invalidating the previous one
+    pub async fn set_share_secret(&self, subdomain: &str, secret: String) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        let tunnel = Arc::make_mut(&mut entry);
+        tunnel.share_secret = Some(secret);
+        info!("Rotated share secret of tunnel {}", subdomain);
+        Ok(())
+    }
+
+    /// Require a secret path token for a tunnel (see `proxy::secret_path`)
+    pub async fn set_secret_path(&self, subdomain: &str, token: String) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        Arc::make_mut(&mut entry).secret_path = Some(token);
+        info!("Tunnel {} now requires its secret path", subdomain);
+        Ok(())
+    }
+
+    /// Turn the preview banner on or off for a tunnel
+    pub async fn set_preview_banner(&self, subdomain: &str, enabled: bool) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        let tunnel = Arc::make_mut(&mut entry);
+        tunnel.preview_banner = enabled;
+        info!("Preview banner of tunnel {} {}", subdomain, if enabled { "enabled" } else { "disabled" });
+        Ok(())
+    }
+
+    /// Turn the response cache on or off for a tunnel
+    pub async fn set_response_cache(&self, subdomain: &str, enabled: bool) -> Result<(), TunnelError> {
+        {
+            let mut entry = self
+                .tunnels
+                .get_mut(subdomain)
+                .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+            Arc::make_mut(&mut entry).response_cache = enabled;
+        }
+        if !enabled {
+            self.response_cache.purge(subdomain).await;
+        }
+        info!("Response cache of tunnel {} {}", subdomain, if enabled { "enabled" } else { "disabled" });
+        Ok(())
+    }
+
+    /// Replace the response header rules of a tunnel
+    pub async fn set_response_headers(&self, subdomain: &str, rules: HeaderRules) -> Result<(), TunnelError> {
+        let mut entry = self
+            .tunnels
+            .get_mut(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        let tunnel = Arc::make_mut(&mut entry);
+        info!(
+            "Response headers of tunnel {}: {} set, {} removed",
+            subdomain,
+            rules.set.len(),
+            rules.remove.len()
+        );
+        tunnel.response_headers = rules;
+        Ok(())
+    }
+
+    /// Remember the session channel of a tunnel's SSH session
+    pub async fn set_session_channel(&self, subdomain: &str, channel_id: ChannelId) {
+        if let Some(mut entry) = self.tunnels.get_mut(subdomain) {
+            let tunnel = Arc::make_mut(&mut entry);
+            tunnel.session_channel_id = Some(channel_id);
+        }
+    }
+
+    /// Attach (or, with None, detach) the control channel of the tunnel's
+    /// session. Terminal notices no longer go to that channel.
+    pub async fn set_control_channel(&self, subdomain: &str, channel_id: Option<ChannelId>) {
+        if let Some(mut entry) = self.tunnels.get_mut(subdomain) {
+            let tunnel = Arc::make_mut(&mut entry);
+            if channel_id.is_some() && tunnel.session_channel_id == channel_id {
+                tunnel.session_channel_id = None;
+            }
+            tunnel.control_channel_id = channel_id;
+        }
+    }
+
+    /// Remember how, in which language and for which terminal size the
+    /// tunnel's session wants notices written
+    pub async fn set_output_mode(&self, subdomain: &str, mode: OutputMode, ui: Ui) {
+        if let Some(mut entry) = self.tunnels.get_mut(subdomain) {
+            let tunnel = Arc::make_mut(&mut entry);
+            tunnel.output_mode = mode;
+            tunnel.ui = ui;
+        }
+    }
+
+    /// Connected tunnels without proxied traffic for longer than `timeout`
+    pub async fn idle_tunnels(&self, timeout: Duration) -> Vec<Arc<TunnelInfo>> {
+        let now = SystemTime::now();
+        self.tunnels
+            .iter()
+            .filter(|t| t.is_connected && t.idle_for(now).is_some_and(|idle| idle > timeout))
+            .map(|t| Arc::clone(&t))
+            .collect()
+    }
+
+    pub async fn remove_tunnel(&self, subdomain: &str) -> Result<Arc<TunnelInfo>, TunnelError> {
+        let (_, removed) = self
+            .tunnels
+            .remove(subdomain)
+            .ok_or_else(|| TunnelError::TunnelNotFound(subdomain.to_string()))?;
+        self.record_removed(&removed).await;
+        Ok(removed)
+    }
+
+    /// Events, audit and history of a tunnel that left the registry
+    async fn record_removed(&self, removed: &TunnelInfo) {
+        let subdomain = &removed.subdomain;
+        self.events
+            .publish(TunnelEventKind::Removed, subdomain, None, &removed.username);
+        self.audit
+            .record(AuditEvent::new(&removed.username, "tunnel.removed").target(subdomain));
+        // Disconnected tunnels were already recorded when their session ended
+        if removed.is_connected {
+            self.history
+                .record(&removed.username, HistoryEntry::from_tunnel(removed, SystemTime::now()))
+                .await;
+        }
+    }
+
+    /// The tunnel as it is now; later changes replace the registry's entry
+    /// and don't show in the returned one (apart from its traffic counters)
+    pub async fn get_tunnel(&self, subdomain: &str) -> Option<Arc<TunnelInfo>> {
+        self.tunnels.get(subdomain).map(|t| Arc::clone(&t))
+    }
+
+    /// Check if a subdomain is already taken (only considers connected tunnels,
+    /// including those held by other cluster nodes)
+    pub async fn is_subdomain_taken(&self, subdomain: &str) -> bool {
+        if self.static_routes.contains(subdomain) {
+            return true;
+        }
+        if let Some(connected) = self.tunnels.get(subdomain).map(|t| t.is_connected) {
+            return connected;
+        }
+        self.cluster.is_owned_elsewhere(subdomain).await
+    }
+
+    pub async fn list_tunnels(&self) -> Vec<Arc<TunnelInfo>> {
+        self.tunnels.iter().map(|t| Arc::clone(&t)).collect()
+    }
+
+    /// Save a verified public key fingerprint
+    pub async fn save_verified_key(
+        &self,
+        fingerprint: &str,
+        user_id: &str,
+        display_name: Option<&str>,
+        tier: Option<&str>,
+        client_port: u32,
+        subdomain: &str,
+    ) {
+        let mut keys = self.verified_keys.write().await;
+        info!(
+            "Saving verified key: fingerprint={}, user_id={}, display_name={:?}, port={}, subdomain={}",
+            fingerprint, user_id, display_name, client_port, subdomain
+        );
+        
+        if let Some(existing) = keys.get_mut(fingerprint) {
+            // An expired key got here through a new verification: its renewal limit restarts
+            if existing.is_expired() {
+                existing.renewable_until = renewal_limit(SystemTime::now());
+            }
+            existing.subdomains.insert(client_port, subdomain.to_string());
+            existing.renew(SystemTime::now());
+            if display_name.is_some() {
+                existing.display_name = display_name.map(|s| s.to_string());
+            }
+            if tier.is_some() {
+                existing.tier = tier.map(|s| s.to_string());
+            }
+        } else {
+            let mut key = VerifiedKey::new(
+                user_id.to_string(),
+                display_name.map(|s| s.to_string()),
+                tier.map(|s| s.to_string()),
+            );
+            key.subdomains.insert(client_port, subdomain.to_string());
+            keys.insert(fingerprint.to_string(), key);
+        }
+        self.key_store.save(&keys).await;
+    }
+
+    /// Update/add a subdomain for a verified key by client port
+    pub async fn update_verified_key_subdomain(&self, fingerprint: &str, client_port: u32, subdomain: &str) {
+        let mut keys = self.verified_keys.write().await;
+        if let Some(key) = keys.get_mut(fingerprint) {
+            key.subdomains.insert(client_port, subdomain.to_string());
+            info!("Updated verified key subdomain: fingerprint={}, port={}, subdomain={}", fingerprint, client_port, subdomain);
+            self.key_store.save(&keys).await;
+        }
+    }
+
+    /// Point verified keys' reconnection entries at a renamed subdomain
+    pub async fn rename_verified_key_subdomain(&self, subdomain: &str, new_subdomain: &str) {
+        let mut keys = self.verified_keys.write().await;
+        let mut renamed = false;
+        for key in keys.values_mut() {
+            for entry in key.subdomains.values_mut() {
+                if entry == subdomain {
+                    *entry = new_subdomain.to_string();
+                    renamed = true;
+                }
+            }
+        }
+        if renamed {
+            self.key_store.save(&keys).await;
+        }
+    }
+
+    /// Get a verified key if it exists and is not expired
+    pub async fn get_verified_key(&self, fingerprint: &str) -> Option<VerifiedKey> {
+        let keys = self.verified_keys.read().await;
+        keys.get(fingerprint).and_then(|key| {
+            if key.is_expired() {
+                None
+            } else {
+                Some(key.clone())
+            }
+        })
+    }
+
+    /// Unexpired verified keys by fingerprint, sorted by fingerprint
+    pub async fn list_verified_keys(&self) -> Vec<(String, VerifiedKey)> {
+        let keys = self.verified_keys.read().await;
+        let mut listed: Vec<_> = keys
+            .iter()
+            .filter(|(_, key)| !key.is_expired())
+            .map(|(fingerprint, key)| (fingerprint.clone(), key.clone()))
+            .collect();
+        listed.sort_by(|a, b| a.0.cmp(&b.0));
+        listed
+    }
+
+    /// Forget a verified key so its next connection goes through the Device
+    /// Flow (resume tokens issued to the key are revoked too)
+    pub async fn revoke_verified_key(&self, fingerprint: &str) -> Option<VerifiedKey> {
+        self.resume_tokens.revoke_fingerprint(fingerprint);
+        let mut keys = self.verified_keys.write().await;
+        let removed = keys.remove(fingerprint);
+        if removed.is_some() {
+            info!("Revoked verified key: fingerprint={}", fingerprint);
+            self.key_store.save(&keys).await;
+        }
+        removed
+    }
+
+    /// Renew verified keys with a connected tunnel, so a key doesn't expire
+    /// while it is serving traffic
+    pub async fn renew_verified_keys(&self) {
+        let connected: HashMap<String, String> = self
+            .tunnels
+            .iter()
+            .filter(|t| t.is_connected)
+            .map(|t| (t.subdomain.clone(), t.username.clone()))
+            .collect();
+        let mut keys = self.verified_keys.write().await;
+        if renew_active_keys(&mut keys, &connected, SystemTime::now()) > 0 {
+            self.key_store.save(&keys).await;
+        }
+    }
+
+    /// Clean up expired verified keys
+    pub async fn cleanup_expired_keys(&self) {
+        let mut keys = self.verified_keys.write().await;
+        let before = keys.len();
+        keys.retain(|_, key| !key.is_expired());
+        if keys.len() != before {
+            self.key_store.save(&keys).await;
+        }
+    }
+
+    /// Mark a tunnel as disconnected (but keep it for reconnection window)
+    pub async fn mark_tunnel_disconnected(&self, subdomain: &str) {
+        let ended = {
+            match self.tunnels.get_mut(subdomain) {
+                Some(mut entry) if entry.is_connected => {
+                    let tunnel = Arc::make_mut(&mut entry);
+                    let now = SystemTime::now();
+                    tunnel.is_connected = false;
+                    tunnel.disconnected_at = Some(now);
+                    info!("Marked tunnel as disconnected: {}", subdomain);
+                    self.events
+                        .publish(TunnelEventKind::Disconnected, subdomain, None, &tunnel.username);
+                    Some((tunnel.username.clone(), HistoryEntry::from_tunnel(tunnel, now)))
+                }
+                _ => None,
+            }
+        };
+        if let Some((user_id, entry)) = ended {
+            self.history.record(&user_id, entry).await;
+        }
+    }
+
+    /// Clean up tunnels that have been disconnected for too long
+    pub async fn cleanup_expired_tunnels(&self) {
+        let now = SystemTime::now();
+        self.tunnels.retain(|subdomain, tunnel| {
+            if let Some(disconnected_at) = tunnel.disconnected_at {
+                if let Ok(elapsed) = now.duration_since(disconnected_at) {
+                    if elapsed > DISCONNECTED_TUNNEL_TTL {
+                        info!("Removing expired disconnected tunnel: {}", subdomain);
+                        self.events
+                            .publish(TunnelEventKind::Removed, subdomain, None, &tunnel.username);
+                        self.audit.record(
+                            AuditEvent::new(SYSTEM_ACTOR, "tunnel.removed")
+                                .target(subdomain)
+                                .detail(format!("reconnect window of {} expired", tunnel.username)),
+                        );
+                        return false;
+                    }
+                }
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn create_test_state() -> AppState {
+        AppState::new()
+    }
+
+     #[test]
+     fn test_verified_key_expiration() {
+         let key = VerifiedKey::new("user123".to_string(), None, None);
+         assert!(!key.is_expired());
+     }
+
+    #[test]
+    fn test_renew_active_keys() {
+        let now = SystemTime::now();
+        let mut key = VerifiedKey::new("user1".to_string(), None, None);
+        key.verified_at = now - Duration::from_secs(20 * 60);
+        key.subdomains.insert(3000, "myapp".to_string());
+        let capped = VerifiedKey {
+            renewable_until: Some(now + Duration::from_secs(60)),
+            ..key.clone()
+        };
+        let mut keys = HashMap::from([("SHA256:a".to_string(), key), ("SHA256:b".to_string(), capped)]);
+
+        // Another user's tunnel on the subdomain doesn't count
+        let connected = HashMap::from([("myapp".to_string(), "user2".to_string())]);
+        assert_eq!(renew_active_keys(&mut keys, &connected, now), 0);
+
+        let connected = HashMap::from([("myapp".to_string(), "user1".to_string())]);
+        assert_eq!(renew_active_keys(&mut keys, &connected, now), 2);
+        assert_eq!(keys["SHA256:a"].expires_at(), now + VERIFIED_KEY_TTL);
+        // Renewals stop at the limit
+        assert_eq!(keys["SHA256:b"].expires_at(), now + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_rate_limit_entry_new() {
+        let entry = RateLimitEntry::new();
+        assert_eq!(entry.attempts, 1);
+    }
+
+    #[test]
+    fn test_rate_limit_entry_is_rate_limited_on_first_request() {
+        let entry = RateLimitEntry::new();
+        // Should be rate limited because last_request is just now (< 10s ago)
+        assert!(entry.is_rate_limited());
+    }
+
+    #[test]
+    fn test_rate_limit_entry_max_attempts() {
+        let mut entry = RateLimitEntry::new();
+        // Record more attempts to exceed limit
+        for _ in 0..DEVICE_FLOW_MAX_ATTEMPTS {
+            entry.record_attempt();
+        }
+        assert!(entry.is_rate_limited());
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_rate_limiting() {
+        let state = create_test_state();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+
+        // First request should not be rate limited
+        assert!(!state.is_device_flow_rate_limited(ip).await);
+
+        // Record the request
+        state.record_device_flow_request(ip).await;
+
+        // Now should be rate limited (too soon)
+        assert!(state.is_device_flow_rate_limited(ip).await);
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_limits_per_key() {
+        let state = create_test_state();
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+        // Colleagues behind one NAT each get their own budget
+        assert!(!state.check_and_record_device_flow(ip, Some("SHA256:a")).await);
+        assert!(state.check_and_record_device_flow(ip, Some("SHA256:a")).await);
+        assert!(!state.check_and_record_device_flow(ip, Some("SHA256:b")).await);
+        assert!(!state.check_and_record_device_flow(ip, None).await);
+
+        let (entry, keys) = state.device_flow_rate_limits(ip).await.unwrap();
+        assert_eq!(entry.attempts, 3);
+        assert_eq!(keys.len(), 3);
+
+        // The IP's shared budget still caps them
+        for i in 0..DEVICE_FLOW_IP_MAX_ATTEMPTS {
+            state.check_and_record_device_flow(ip, Some(&format!("SHA256:{}", i))).await;
+        }
+        assert!(state.check_and_record_device_flow(ip, Some("SHA256:new")).await);
+
+        assert!(state.clear_device_flow_rate_limits(ip).await);
+        assert!(state.device_flow_rate_limits(ip).await.is_none());
+        assert!(!state.check_and_record_device_flow(ip, Some("SHA256:a")).await);
+    }
+
+    #[tokio::test]
+    async fn test_verified_key_save_and_get() {
+        let state = create_test_state();
+        let fingerprint = "SHA256:abc123";
+        let user_id = "user1";
+
+        state.save_verified_key(fingerprint, user_id, Some("User One"), None, 8000, "test-subdomain").await;
+
+        let key = state.get_verified_key(fingerprint).await;
+        assert!(key.is_some());
+        let key = key.unwrap();
+        assert_eq!(key.user_id, user_id);
+        assert_eq!(key.display_name, Some("User One".to_string()));
+        assert_eq!(key.subdomains.get(&8000), Some(&"test-subdomain".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_verified_key_not_found() {
+        let state = create_test_state();
+        let key = state.get_verified_key("nonexistent").await;
+        assert!(key.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_update_verified_key_subdomain() {
+        let state = create_test_state();
+        let fingerprint = "SHA256:xyz789";
+
+        state.save_verified_key(fingerprint, "user", None, None, 3000, "old-subdomain").await;
+        state.update_verified_key_subdomain(fingerprint, 3000, "new-subdomain").await;
+
+        let key = state.get_verified_key(fingerprint).await.unwrap();
+        assert_eq!(key.subdomains.get(&3000), Some(&"new-subdomain".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_verified_key_multiple_ports() {
+        let state = create_test_state();
+        let fingerprint = "SHA256:multiport";
+        
+        state.save_verified_key(fingerprint, "user", Some("Test User"), None, 8000, "subdomain-8000").await;
+        state.save_verified_key(fingerprint, "user", Some("Test User"), None, 3000, "subdomain-3000").await;
+        
+        let key = state.get_verified_key(fingerprint).await.unwrap();
+        assert_eq!(key.subdomains.len(), 2);
+        assert_eq!(key.subdomains.get(&8000), Some(&"subdomain-8000".to_string()));
+        assert_eq!(key.subdomains.get(&3000), Some(&"subdomain-3000".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_list_and_revoke_verified_keys() {
+        let state = create_test_state();
+        state.save_verified_key("SHA256:b", "user2", None, None, 3000, "two").await;
+        state.save_verified_key("SHA256:a", "user1", None, None, 3000, "one").await;
+        state.verified_keys.write().await.insert(
+            "SHA256:expired".to_string(),
+            VerifiedKey {
+                verified_at: SystemTime::now() - VERIFIED_KEY_TTL - Duration::from_secs(1),
+                ..VerifiedKey::new("user3".to_string(), None, None)
+            },
+        );
+
+        let listed: Vec<String> = state.list_verified_keys().await.into_iter().map(|(f, _)| f).collect();
+        assert_eq!(listed, vec!["SHA256:a", "SHA256:b"]);
+        let grant = resume_tokens::ResumeGrant {
+            user_id: "user1".to_string(),
+            display_name: "user1".to_string(),
+            tier: None,
+            subdomains: HashMap::from([(3000, "one".to_string())]),
+            perf_profile: None,
+            fingerprint: "SHA256:a".to_string(),
+            session_id: "s1".to_string(),
+        };
+        let token = state.resume_tokens.issue(grant, Duration::from_secs(60));
+
+        assert_eq!(state.revoke_verified_key("SHA256:a").await.map(|k| k.user_id), Some("user1".to_string()));
+        assert!(state.revoke_verified_key("SHA256:a").await.is_none());
+        assert!(state.get_verified_key("SHA256:a").await.is_none());
+        // Nor can the key resume its session
+        assert!(state.resume_tokens.redeem(&token, "SHA256:a").is_none());
+    }
+
+    #[test]
+    fn test_forward_name() {
+        assert_eq!(forward_name("api", 80), "api");
+        assert_eq!(forward_name("Web-1", 80), "web-1");
+        assert_eq!(forward_name("localhost", 3000), "3000");
+        assert_eq!(forward_name("", 3000), "3000");
+        assert_eq!(forward_name("0.0.0.0", 8080), "8080");
+        assert_eq!(forward_name("::1", 8080), "8080");
+    }
+
+    #[test]
+    fn test_select_forward() {
+        let primary = NamedForward::new("web", 80);
+        let forwards = vec![NamedForward::new("api", 80), NamedForward::new("localhost", 9000)];
+
+        let pick = |upstream: Option<&str>, path: Option<&str>| {
+            select_forward(&primary, &forwards, upstream, path).map(|f| f.name.clone())
+        };
+
+        // Header override wins over path
+        assert_eq!(pick(Some("api"), Some("/web/index.html")), Some("api".to_string()));
+        assert_eq!(pick(Some("WEB"), None), Some("web".to_string()));
+        assert_eq!(pick(Some("missing"), None), None);
+        // Path routing by first segment
+        assert_eq!(pick(None, Some("/api/users")), Some("api".to_string()));
+        assert_eq!(pick(None, Some("/9000?x=1")), Some("9000".to_string()));
+        // Fallback to primary
+        assert_eq!(pick(None, Some("/other")), Some("web".to_string()));
+        assert_eq!(pick(None, None), Some("web".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_rate_limits() {
+        let state = create_test_state();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        state.record_device_flow_request(ip).await;
+        
+        // Should have entry
+        {
+            let limits = state.rate_limits.read().await;
+            assert!(limits.contains_key(&ip));
+        }
+
+        // Cleanup should not remove recent entries
+        state.cleanup_rate_limits().await;
+        
+        {
+            let limits = state.rate_limits.read().await;
+            assert!(limits.contains_key(&ip));
+        }
+    }
+
+    #[test]
+    fn test_shared_traffic() {
+        let traffic = SharedTraffic::default();
+        let copy = traffic.clone();
+        copy.update(|t| {
+            t.bytes_in += 10;
+            t.count_close(&CloseReason::ClientEof);
+        });
+        let snapshot = traffic.snapshot();
+        assert_eq!(snapshot.bytes_in, 10);
+        assert_eq!(snapshot.close_reasons.get("client_eof"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_rename_moves_policies() {
+        let state = create_test_state();
+        let policy = oauth::OAuthPolicy {
+            provider: oauth::OAuthProvider::Github,
+            allow: vec!["octocat".to_string()],
+        };
+        state.oauth.set("app", policy.clone()).await;
+        let rule: webhooks::WebhookRule =
+            serde_json::from_str(r#"{"path": "/hooks", "provider": "github", "secret": "whsec"}"#).unwrap();
+        state.webhooks.set("app", vec![rule.clone()]).await;
+        let limit = tunnel_limits::TunnelRateLimit {
+            requests_per_second: 5,
+            burst: 0,
+            max_connections: 2,
+        };
+        state.tunnel_limits.set("app", limit).await;
+        state.bandwidth.set("app", 1024);
+        state.har.set_recording("app", true);
+        state.domains.attach("app.example.com", "app", "user1").await.unwrap();
+
+        state.move_subdomain_state("app", "renamed").await;
+
+        // The protected tunnel stays protected under its new name
+        assert_eq!(state.oauth.get("renamed").await, Some(policy));
+        assert!(state.oauth.get("app").await.is_none());
+        assert_eq!(state.webhooks.get("renamed").await, vec![rule]);
+        assert!(!state.webhooks.has_rules("app").await);
+        assert_eq!(state.tunnel_limits.limit_for("renamed").await, (limit, true));
+        assert!(!state.tunnel_limits.limit_for("app").await.1);
+        assert_eq!(state.bandwidth.limit_for("renamed"), (1024, true));
+        assert!(!state.bandwidth.limit_for("app").1);
+        assert!(state.har.is_recording("renamed") && !state.har.is_recording("app"));
+        let domain = state.domains.resolve("app.example.com").await.unwrap();
+        assert_eq!(domain.subdomain, "renamed");
+    }
+
+    #[tokio::test]
+    async fn test_static_route_not_registrable() {
+        // Registering and renaming onto a static route's subdomain are refused alike
+        let mut state = create_test_state();
+        state.static_routes = StaticRoutes::new(static_routes::parse_static_routes("docs=127.0.0.1:8080").unwrap());
+        let refused = state.check_registrable("docs", "user1").await;
+        assert!(matches!(refused, Err(TunnelError::SubdomainReserved(s)) if s == "docs"));
+        assert!(state.check_registrable("app", "user1").await.is_ok());
+    }
+
+    /// Stand-in for a `TunnelInfo` (which needs a live SSH handle)
+    #[derive(Debug)]
+    struct Held {
+        username: &'static str,
+        session_id: &'static str,
+        forwards: Vec<&'static str>,
+    }
+
+    fn held_by(username: &'static str, session_id: &'static str, forwards: Vec<&'static str>) -> Held {
+        Held { username, session_id, forwards }
+    }
+
+    #[test]
+    fn test_take_over_own_tunnel() {
+        let tunnels = DashMap::new();
+        let owner: fn(&Held) -> &str = |t| t.username;
+        let free = replace_own(&tunnels, "app", held_by("user1", "s1", vec!["api"]), owner).unwrap();
+        assert!(free.is_none());
+
+        // The same user's new session replaces the old one, forwards and all
+        let old = replace_own(&tunnels, "app", held_by("user1", "s2", Vec::new()), owner)
+            .unwrap()
+            .unwrap();
+        assert_eq!((old.session_id, old.forwards.clone()), ("s1", vec!["api"]));
+        let current = tunnels.get("app").unwrap();
+        assert_eq!(current.session_id, "s2");
+        assert!(current.forwards.is_empty());
+    }
+
+    #[test]
+    fn test_take_over_other_users_tunnel() {
+        let tunnels = DashMap::new();
+        let owner: fn(&Held) -> &str = |t| t.username;
+        replace_own(&tunnels, "app", held_by("user1", "s1", vec!["api"]), owner).unwrap();
+
+        let taken = replace_own(&tunnels, "app", held_by("user2", "s2", Vec::new()), owner);
+        assert!(matches!(taken, Err(TunnelError::SubdomainTaken(s)) if s == "app"));
+        let current = tunnels.get("app").unwrap();
+        assert_eq!((current.session_id, current.forwards.clone()), ("s1", vec!["api"]));
+    }
+
+    #[test]
+    fn test_reconnect_takeover_decisions() {
+        // What create_tunnel does with the result: a replaced tunnel of
+        // another session is taken over and that session disconnected
+        let tunnels = DashMap::new();
+        let owner: fn(&Held) -> &str = |t| t.username;
+        let taken_over = |replaced: Option<Arc<Held>>, own: &str| replaced.filter(|old| old.session_id != own);
+        replace_own(&tunnels, "app", held_by("user1", "s1", Vec::new()), owner).unwrap();
+
+        // The same session registering again (another forward) takes nothing over
+        let replaced = replace_own(&tunnels, "app", held_by("user1", "s1", Vec::new()), owner).unwrap();
+        assert!(replaced.is_some());
+        assert!(taken_over(replaced, "s1").is_none());
+
+        // A new session of the same user takes the tunnel from the one holding it
+        let replaced = replace_own(&tunnels, "app", held_by("user1", "s2", Vec::new()), owner).unwrap();
+        assert_eq!(taken_over(replaced, "s2").unwrap().session_id, "s1");
+
+        // Another user's session is refused and the holder keeps the tunnel
+        let refused = replace_own(&tunnels, "app", held_by("user2", "s3", Vec::new()), owner);
+        assert!(matches!(refused, Err(TunnelError::SubdomainTaken(s)) if s == "app"));
+        assert_eq!(tunnels.get("app").unwrap().session_id, "s2");
+    }
+}
@@ -0,0 +1,101 @@
+//! Operator message-of-the-day shown once per user.
+//!
+//! The message is delivered on a user's next successful activation or
+//! reconnect rather than broadcast to idle sessions.
+
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use tokio::sync::RwLock;
+
+/// The currently configured message
+#[derive(Debug, Clone)]
+pub struct Motd {
+    pub message: String,
+    pub set_at: SystemTime,
+}
+
+#[derive(Debug, Default)]
+struct MotdInner {
+    current: Option<Motd>,
+    /// User IDs that have already seen the current message
+    seen_by: HashSet<String>,
+}
+
+/// Holds the active MOTD and tracks which users have seen it
+#[derive(Debug, Default)]
+pub struct MotdBoard {
+    inner: RwLock<MotdInner>,
+}
+
+impl MotdBoard {
+    /// Set a new message; every user will see it once
+    pub async fn set(&self, message: String) {
+        let mut inner = self.inner.write().await;
+        inner.current = Some(Motd {
+            message,
+            set_at: SystemTime::now(),
+        });
+        inner.seen_by.clear();
+    }
+
+    /// Remove the current message
+    pub async fn clear(&self) {
+        let mut inner = self.inner.write().await;
+        inner.current = None;
+        inner.seen_by.clear();
+    }
+
+    /// Get the current message and how many users have seen it
+    pub async fn current(&self) -> Option<(Motd, usize)> {
+        let inner = self.inner.read().await;
+        inner
+            .current
+            .clone()
+            .map(|motd| (motd, inner.seen_by.len()))
+    }
+
+    /// Return the message if this user hasn't seen it yet, marking it as seen
+    pub async fn take_for_user(&self, user_id: &str) -> Option<String> {
+        let mut inner = self.inner.write().await;
+        let message = inner.current.as_ref()?.message.clone();
+        if inner.seen_by.insert(user_id.to_string()) {
+            Some(message)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_motd_shown_once_per_user() {
+        let board = MotdBoard::default();
+        assert_eq!(board.take_for_user("user1").await, None);
+
+        board.set("Migrating to new domain".to_string()).await;
+        assert_eq!(
+            board.take_for_user("user1").await,
+            Some("Migrating to new domain".to_string())
+        );
+        assert_eq!(board.take_for_user("user1").await, None);
+        assert!(board.take_for_user("user2").await.is_some());
+        assert_eq!(board.current().await.map(|(_, seen)| seen), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_new_motd_resets_seen_users() {
+        let board = MotdBoard::default();
+        board.set("first".to_string()).await;
+        assert!(board.take_for_user("user1").await.is_some());
+
+        board.set("second".to_string()).await;
+        assert_eq!(board.take_for_user("user1").await, Some("second".to_string()));
+
+        board.clear().await;
+        assert!(board.take_for_user("user2").await.is_none());
+    }
+}
@@ -0,0 +1,147 @@
+//! Per-tunnel OAuth protection policies.
+//!
+//! The management API can require visitors of a subdomain to sign in with
+//! GitHub or Google before the proxy forwards anything (see
+//! `proxy::oauth`). A policy names the provider and who may pass: exact
+//! emails or GitHub logins, or whole email domains (`@example.com`); an empty
+//! list lets any account of the provider in. Policies are keyed by
+//! subdomain, so they survive reconnects, and are checked on every request,
+//! so changes apply to visitors who are already signed in.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+/// An identity provider visitors can sign in with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthProvider {
+    Github,
+    Google,
+}
+
+impl OAuthProvider {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "github" => Some(Self::Github),
+            "google" => Some(Self::Google),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Github => "github",
+            Self::Google => "google",
+        }
+    }
+}
+
+/// OAuth application credentials registered with a provider
+#[derive(Debug, Clone)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Who may visit a protected tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OAuthPolicy {
+    pub provider: OAuthProvider,
+    /// Emails, GitHub logins or `@domain` suffixes (empty = any account)
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+impl OAuthPolicy {
+    /// Whether a visitor known by `identities` (login and verified emails)
+    /// at `provider` may pass
+    pub fn allows(&self, provider: OAuthProvider, identities: &[String]) -> bool {
+        if provider != self.provider || identities.is_empty() {
+            return false;
+        }
+        if self.allow.is_empty() {
+            return true;
+        }
+        self.allow.iter().any(|entry| {
+            identities.iter().any(|identity| match entry.strip_prefix('@') {
+                Some(domain) => identity
+                    .rsplit_once('@')
+                    .is_some_and(|(_, host)| host.eq_ignore_ascii_case(domain)),
+                None => identity.eq_ignore_ascii_case(entry),
+            })
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.allow.iter().find(|entry| {
+            let entry = entry.trim();
+            entry.is_empty() || entry == "@" || entry.contains(char::is_whitespace)
+        }) {
+            Some(entry) => Err(format!("'{}' is not an email, login or @domain", entry)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// OAuth policies by subdomain
+#[derive(Debug, Default)]
+pub struct OAuthPolicies {
+    policies: RwLock<HashMap<String, OAuthPolicy>>,
+}
+
+impl OAuthPolicies {
+    pub async fn get(&self, subdomain: &str) -> Option<OAuthPolicy> {
+        self.policies.read().await.get(subdomain).cloned()
+    }
+
+    pub async fn set(&self, subdomain: &str, policy: OAuthPolicy) {
+        self.policies.write().await.insert(subdomain.to_string(), policy);
+    }
+
+    /// Remove a tunnel's policy; returns whether it had one
+    pub async fn clear(&self, subdomain: &str) -> bool {
+        self.policies.write().await.remove(subdomain).is_some()
+    }
+
+    /// Keep a renamed tunnel's policy
+    pub async fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut policies = self.policies.write().await;
+        if let Some(policy) = policies.remove(subdomain) {
+            policies.insert(new_subdomain.to_string(), policy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_allows() {
+        let ids = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let policy = OAuthPolicy {
+            provider: OAuthProvider::Github,
+            allow: vec!["octocat".to_string(), "@Example.com".to_string()],
+        };
+        assert!(policy.allows(OAuthProvider::Github, &ids(&["octocat"])));
+        assert!(policy.allows(OAuthProvider::Github, &ids(&["someone", "dev@example.com"])));
+        assert!(!policy.allows(OAuthProvider::Github, &ids(&["dev@example.com.evil.io"])));
+        assert!(!policy.allows(OAuthProvider::Google, &ids(&["octocat"])));
+        assert!(!policy.allows(OAuthProvider::Github, &[]));
+
+        let anyone = OAuthPolicy {
+            provider: OAuthProvider::Google,
+            allow: Vec::new(),
+        };
+        assert!(anyone.allows(OAuthProvider::Google, &ids(&["a@b.c"])));
+        assert!(anyone.validate().is_ok());
+        assert!(OAuthPolicy {
+            allow: vec!["@".to_string()],
+            ..anyone
+        }
+        .validate()
+        .is_err());
+    }
+}
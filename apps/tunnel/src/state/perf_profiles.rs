@@ -0,0 +1,118 @@
+//! Named performance profiles for a tunnel's proxied connections.
+//!
+//! By default every connection is relayed with the global settings
+//! (`PROXY_IDLE_TIMEOUT`, 16 KiB copy buffers, Nagle on). A client can pick a
+//! profile with a username suffix (`myapp+streaming@server`) or later with the
+//! `profile` exec command, tuning only that tunnel:
+//!
+//! - `interactive`: small buffers and `TCP_NODELAY` for low latency
+//! - `streaming`: long idle timeout for SSE / WebSockets, `TCP_NODELAY`, and no
+//!   compression so compressing middleware doesn't hold back events
+//! - `bulk`: large buffers with Nagle left on, for downloads and uploads
+
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Idle timeout of streaming tunnels (long-lived event streams go quiet for a while)
+const STREAMING_IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Copy buffer size without a profile
+pub const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
+/// A tunnel's performance profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PerfProfile {
+    Interactive,
+    Streaming,
+    Bulk,
+}
+
+impl PerfProfile {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "interactive" => Some(Self::Interactive),
+            "streaming" => Some(Self::Streaming),
+            "bulk" => Some(Self::Bulk),
+            _ => None,
+        }
+    }
+
+    /// Close a connection after this long without traffic (`global` = `PROXY_IDLE_TIMEOUT`)
+    pub fn idle_timeout(self, global: Duration) -> Duration {
+        match self {
+            Self::Streaming => global.max(STREAMING_IDLE_TIMEOUT),
+            Self::Interactive | Self::Bulk => global,
+        }
+    }
+
+    /// Bytes copied per read in each direction
+    pub fn buffer_size(self) -> usize {
+        match self {
+            Self::Interactive => 4 * 1024,
+            Self::Streaming => DEFAULT_BUFFER_SIZE,
+            Self::Bulk => 256 * 1024,
+        }
+    }
+
+    /// Set `TCP_NODELAY` on the visitor's connection
+    pub fn nodelay(self) -> bool {
+        !matches!(self, Self::Bulk)
+    }
+
+    /// Let the visitor negotiate a compressed response (`Accept-Encoding` is
+    /// dropped otherwise)
+    pub fn compression(self) -> bool {
+        !matches!(self, Self::Streaming)
+    }
+}
+
+impl fmt::Display for PerfProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Interactive => "interactive",
+            Self::Streaming => "streaming",
+            Self::Bulk => "bulk",
+        })
+    }
+}
+
+/// Split a profile suffix off an SSH username (`myapp+bulk` -> `myapp`, bulk).
+/// An unknown profile is an error.
+pub fn split_username(user: &str) -> Result<(&str, Option<PerfProfile>), String> {
+    match user.split_once('+') {
+        Some((name, profile)) => match PerfProfile::parse(profile) {
+            Some(profile) => Ok((name, Some(profile))),
+            None => Err(format!(
+                "Unknown profile '{}': use interactive, streaming or bulk",
+                profile
+            )),
+        },
+        None => Ok((user, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_username() {
+        assert_eq!(split_username("myapp"), Ok(("myapp", None)));
+        assert_eq!(split_username("myapp+Bulk"), Ok(("myapp", Some(PerfProfile::Bulk))));
+        assert_eq!(split_username(".+streaming"), Ok((".", Some(PerfProfile::Streaming))));
+        assert!(split_username("myapp+fast").is_err());
+    }
+
+    #[test]
+    fn test_profile_settings() {
+        let global = Duration::from_secs(300);
+        assert_eq!(PerfProfile::Streaming.idle_timeout(global), STREAMING_IDLE_TIMEOUT);
+        assert_eq!(PerfProfile::Bulk.idle_timeout(global), global);
+        assert!(PerfProfile::Interactive.buffer_size() < PerfProfile::Bulk.buffer_size());
+        assert!(!PerfProfile::Bulk.nodelay());
+        assert!(!PerfProfile::Streaming.compression());
+    }
+}
@@ -0,0 +1,205 @@
+//! Garbage collection of orphaned backend tunnel registrations.
+//!
+//! A tunnel is registered with the web backend when it is created and
+//! unregistered when its session drops. A crash or a failed unregister call
+//! leaves the row behind and the dashboard shows a tunnel that doesn't exist.
+//! Reconciliation periodically lists the backend's registrations and removes
+//! those that no node holds: not in the local registry, not reported by a
+//! cluster peer, and older than a grace period (so registrations racing the
+//! listing, or not yet synced from peers, are left alone).
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::DateTime;
+use log::{info, warn};
+
+use crate::config::ReconcileMode;
+use crate::device::{AuthProvider, BackendTunnel};
+
+use super::AppState;
+
+/// Registrations younger than this are never treated as orphans
+pub const ORPHAN_GRACE: Duration = Duration::from_secs(2 * 60);
+
+/// Corrections made so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconcileStats {
+    pub runs: u64,
+    pub failed_runs: u64,
+    /// Orphans found over all runs (including dry runs)
+    pub orphans_found: u64,
+    pub unregistered: u64,
+    pub unregister_failures: u64,
+    pub last_run: Option<SystemTime>,
+    /// Orphans found by the last run
+    pub last_orphans: Vec<String>,
+}
+
+/// Reconciliation counters, exposed through the management API
+#[derive(Debug, Default)]
+pub struct Reconciler {
+    stats: Mutex<ReconcileStats>,
+}
+
+/// Registrations that no node holds and that are past the grace period
+pub fn find_orphans(
+    backend: &[BackendTunnel],
+    held: &HashSet<String>,
+    now: SystemTime,
+    grace: Duration,
+) -> Vec<String> {
+    backend
+        .iter()
+        .filter(|t| !held.contains(&t.subdomain))
+        .filter(|t| {
+            // Unknown age counts as old enough
+            let created = t
+                .created_at
+                .as_deref()
+                .and_then(|c| DateTime::parse_from_rfc3339(c).ok())
+                .map(SystemTime::from);
+            created.is_none_or(|created| created + grace <= now)
+        })
+        .map(|t| t.subdomain.clone())
+        .collect()
+}
+
+impl Reconciler {
+    pub fn stats(&self) -> ReconcileStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// One reconciliation pass
+    pub async fn run(&self, state: &AppState, auth: &dyn AuthProvider, mode: ReconcileMode) {
+        if mode == ReconcileMode::Off {
+            return;
+        }
+
+        // List the backend first: a tunnel created after the listing can't be
+        // mistaken for an orphan by the local snapshot taken below
+        let backend = match auth.list_tunnels().await {
+            Ok(tunnels) => tunnels,
+            Err(e) => {
+                warn!("Backend reconciliation: failed to list tunnels: {}", e);
+                let mut stats = self.stats.lock().unwrap();
+                stats.runs += 1;
+                stats.failed_runs += 1;
+                stats.last_run = Some(SystemTime::now());
+                return;
+            }
+        };
+
+        let mut held: HashSet<String> = state.tunnels.iter().map(|t| t.key().clone()).collect();
+        held.extend(state.cluster.list().await.into_iter().map(|t| t.subdomain));
+        let orphans = find_orphans(&backend, &held, SystemTime::now(), ORPHAN_GRACE);
+
+        let mut unregistered = 0;
+        let mut failures = 0;
+        for subdomain in &orphans {
+            if mode == ReconcileMode::DryRun {
+                info!("Backend reconciliation (dry run): '{}' is orphaned", subdomain);
+                continue;
+            }
+            match auth.unregister_tunnel(subdomain).await {
+                Ok(()) => {
+                    info!("Backend reconciliation: unregistered orphaned tunnel '{}'", subdomain);
+                    unregistered += 1;
+                }
+                Err(e) => {
+                    warn!("Backend reconciliation: failed to unregister '{}': {}", subdomain, e);
+                    failures += 1;
+                }
+            }
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.runs += 1;
+        stats.orphans_found += orphans.len() as u64;
+        stats.unregistered += unregistered;
+        stats.unregister_failures += failures;
+        stats.last_run = Some(SystemTime::now());
+        stats.last_orphans = orphans;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use chrono::Utc;
+
+    fn backend_tunnel(subdomain: &str, age: Duration) -> BackendTunnel {
+        BackendTunnel {
+            subdomain: subdomain.to_string(),
+            session_id: "session".to_string(),
+            user_id: "user1".to_string(),
+            created_at: Some((Utc::now() - chrono::Duration::from_std(age).unwrap()).to_rfc3339()),
+        }
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let backend = vec![
+            backend_tunnel("live", Duration::from_secs(600)),
+            backend_tunnel("orphan", Duration::from_secs(600)),
+            backend_tunnel("fresh", Duration::from_secs(5)),
+            BackendTunnel {
+                created_at: None,
+                ..backend_tunnel("undated", Duration::ZERO)
+            },
+        ];
+        let held = HashSet::from(["live".to_string()]);
+        let orphans = find_orphans(&backend, &held, SystemTime::now(), ORPHAN_GRACE);
+        assert_eq!(orphans, vec!["orphan".to_string(), "undated".to_string()]);
+    }
+
+    struct FakeBackend {
+        tunnels: Vec<BackendTunnel>,
+        unregistered: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl AuthProvider for FakeBackend {
+        async fn register_code(&self, _code: &str, _session_id: &str) -> Result<(), anyhow::Error> {
+            Ok(())
+        }
+        async fn poll_until_verified(&self, _code: &str) -> Result<crate::device::VerifiedUser, anyhow::Error> {
+            anyhow::bail!("not used")
+        }
+        fn get_activation_url(&self, code: &str) -> String {
+            code.to_string()
+        }
+        async fn register_tunnel(&self, _tunnel: &crate::device::RegisterTunnelRequest) -> Result<(), anyhow::Error> {
+            Ok(())
+        }
+        async fn unregister_tunnel(&self, subdomain: &str) -> Result<(), anyhow::Error> {
+            self.unregistered.lock().unwrap().push(subdomain.to_string());
+            Ok(())
+        }
+        async fn list_tunnels(&self) -> Result<Vec<BackendTunnel>, anyhow::Error> {
+            Ok(self.tunnels.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_and_enforce() {
+        let state = AppState::new();
+        let backend = FakeBackend {
+            tunnels: vec![backend_tunnel("orphan", Duration::from_secs(600))],
+            unregistered: Mutex::new(Vec::new()),
+        };
+        let reconciler = Reconciler::default();
+
+        reconciler.run(&state, &backend, ReconcileMode::DryRun).await;
+        assert!(backend.unregistered.lock().unwrap().is_empty());
+        assert_eq!(reconciler.stats().orphans_found, 1);
+
+        reconciler.run(&state, &backend, ReconcileMode::On).await;
+        assert_eq!(*backend.unregistered.lock().unwrap(), vec!["orphan".to_string()]);
+        let stats = reconciler.stats();
+        assert_eq!((stats.runs, stats.orphans_found, stats.unregistered), (2, 2, 1));
+        assert_eq!(stats.last_orphans, vec!["orphan".to_string()]);
+    }
+}
@@ -0,0 +1,113 @@
+//! Per-session feed of proxied requests for the terminal live view.
+//!
+//! The proxy publishes each finished request to the SSH session holding the
+//! tunnel; the session's live view subscribes and repaints. Feeds are keyed by
+//! session ID rather than subdomain so they follow renames and cover every
+//! tunnel of a multi-port session. Nothing is kept when nobody is watching.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use tokio::sync::broadcast;
+
+use crate::proxy::access_log::AccessLogEntry;
+
+/// Requests buffered per subscriber before it starts skipping
+const FEED_CAPACITY: usize = 64;
+
+/// A finished request, as shown in the live view
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestEvent {
+    pub subdomain: String,
+    pub method: String,
+    pub path: String,
+    pub status: Option<u16>,
+    pub duration_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl From<&AccessLogEntry> for RequestEvent {
+    fn from(entry: &AccessLogEntry) -> Self {
+        Self {
+            subdomain: entry.subdomain.clone().unwrap_or_default(),
+            method: entry.method.clone(),
+            path: entry.path.clone(),
+            status: entry.status,
+            duration_ms: entry.duration_ms,
+            bytes_in: entry.bytes_in,
+            bytes_out: entry.bytes_out,
+        }
+    }
+}
+
+/// Request feeds of the sessions currently watching
+#[derive(Debug, Default)]
+pub struct RequestFeeds {
+    feeds: RwLock<HashMap<String, broadcast::Sender<RequestEvent>>>,
+}
+
+impl RequestFeeds {
+    /// Receive the requests proxied to a session's tunnels
+    pub fn subscribe(&self, session_id: &str) -> broadcast::Receiver<RequestEvent> {
+        self.feeds
+            .write()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_insert_with(|| broadcast::channel(FEED_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Deliver a finished request to the session's subscribers, if any
+    pub fn publish(&self, session_id: &str, entry: &AccessLogEntry) {
+        let delivered = match self.feeds.read().unwrap().get(session_id) {
+            Some(sender) => sender.send(RequestEvent::from(entry)).is_ok(),
+            None => return,
+        };
+        if !delivered {
+            // Every subscriber is gone
+            let mut feeds = self.feeds.write().unwrap();
+            if feeds.get(session_id).is_some_and(|s| s.receiver_count() == 0) {
+                feeds.remove(session_id);
+            }
+        }
+    }
+
+    /// Number of sessions with a live view open
+    pub fn watched_sessions(&self) -> usize {
+        self.feeds.read().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> AccessLogEntry {
+        let request = format!("GET {} HTTP/1.1\r\nHost: app.localhost\r\n\r\n", path);
+        let mut entry = AccessLogEntry::new("203.0.113.5:40000".parse().unwrap(), request.as_bytes());
+        entry.subdomain = Some("app".to_string());
+        entry.status = Some(200);
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_session() {
+        let feeds = RequestFeeds::default();
+        // Nobody watching: nothing is kept
+        feeds.publish("session1", &entry("/ignored"));
+        assert_eq!(feeds.watched_sessions(), 0);
+
+        let mut receiver = feeds.subscribe("session1");
+        feeds.publish("session2", &entry("/other"));
+        feeds.publish("session1", &entry("/api"));
+        let event = receiver.recv().await.unwrap();
+        assert_eq!((event.subdomain.as_str(), event.path.as_str()), ("app", "/api"));
+        assert_eq!(event.status, Some(200));
+
+        // The feed goes away with its last subscriber
+        drop(receiver);
+        feeds.publish("session1", &entry("/late"));
+        assert_eq!(feeds.watched_sessions(), 0);
+    }
+}
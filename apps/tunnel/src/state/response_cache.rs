@@ -0,0 +1,457 @@
+//! Per-tunnel cache of static responses.
+//!
+//! Tunnels with the cache turned on keep fresh copies of cacheable responses
+//! (see `proxy::caching` for what qualifies), so repeated hits on static
+//! assets are answered at the edge instead of crossing the SSH channel to the
+//! developer's machine. Each tunnel gets `RESPONSE_CACHE_MEMORY` bytes in
+//! memory; with `RESPONSE_CACHE_DIR` set, entries pushed out of memory move to
+//! disk (up to `RESPONSE_CACHE_DISK` bytes per tunnel) before being dropped.
+//! A cache belongs to one tunnel by correlation ID, so it survives reconnects
+//! but is never served to whoever takes over the subdomain next.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+
+/// Largest response (head and body) that gets cached
+pub const MAX_ENTRY_BYTES: usize = 8 * 1024 * 1024;
+
+/// A response to keep, as parsed by the proxy
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    /// Response head as the origin sent it
+    pub head: Vec<u8>,
+    pub body: Bytes,
+    /// Request header values the response varies on (lowercase names)
+    pub vary: Vec<(String, Option<String>)>,
+    /// Freshness lifetime the origin gave the response
+    pub fresh_for: Duration,
+    /// Age the response already had when it arrived
+    pub age: Duration,
+}
+
+/// A stored response found for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheHit {
+    pub head: Vec<u8>,
+    pub body: Bytes,
+    /// Current age of the response, for the `Age` header
+    pub age: Duration,
+}
+
+/// Entries and counters of a tunnel's cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub memory_bytes: u64,
+    pub disk_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+enum Location {
+    Memory(Bytes),
+    Disk(PathBuf),
+}
+
+#[derive(Debug)]
+struct Stored {
+    head: Vec<u8>,
+    body: Location,
+    len: u64,
+    vary: Vec<(String, Option<String>)>,
+    stored_at: Instant,
+    expires: Instant,
+    age: Duration,
+    last_used: u64,
+}
+
+impl Stored {
+    fn matches(&self, header: &impl Fn(&str) -> Option<String>) -> bool {
+        self.vary.iter().all(|(name, value)| header(name) == *value)
+    }
+}
+
+/// Memory and disk budget of each tunnel's cache
+#[derive(Debug, Clone)]
+struct Limits {
+    memory: u64,
+    dir: Option<PathBuf>,
+    disk: u64,
+}
+
+fn limits() -> Limits {
+    if !config_loaded() {
+        return Limits {
+            memory: 16 * 1024 * 1024,
+            dir: None,
+            disk: 0,
+        };
+    }
+    let config = get_config();
+    Limits {
+        memory: config.response_cache_memory,
+        dir: config.response_cache_dir.as_ref().map(PathBuf::from),
+        disk: config.response_cache_disk,
+    }
+}
+
+/// Where a tunnel's entries go on disk
+fn tunnel_dir(limits: &Limits, subdomain: &str) -> Option<PathBuf> {
+    limits.dir.as_ref().map(|dir| dir.join(subdomain))
+}
+
+async fn remove_dir(dir: Option<PathBuf>) {
+    if let Some(dir) = dir {
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove response cache directory {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TunnelCache {
+    correlation_id: String,
+    /// Variants by method and target
+    entries: HashMap<String, Vec<Stored>>,
+    stats: CacheStats,
+    /// Ticks on every use, for least-recently-used eviction
+    clock: u64,
+    next_file: u64,
+}
+
+impl TunnelCache {
+    fn new(correlation_id: &str) -> Self {
+        Self {
+            correlation_id: correlation_id.to_string(),
+            entries: HashMap::new(),
+            stats: CacheStats::default(),
+            clock: 0,
+            next_file: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Least recently used entry in memory or on disk
+    fn least_recent(&self, on_disk: bool) -> Option<(String, usize)> {
+        self.entries
+            .iter()
+            .flat_map(|(key, variants)| variants.iter().enumerate().map(move |(i, stored)| (key, i, stored)))
+            .filter(|(_, _, stored)| matches!(stored.body, Location::Disk(_)) == on_disk)
+            .min_by_key(|(_, _, stored)| stored.last_used)
+            .map(|(key, i, _)| (key.clone(), i))
+    }
+
+    /// Take an entry out, updating the byte counts
+    fn take(&mut self, key: &str, index: usize) -> Option<Stored> {
+        let variants = self.entries.get_mut(key)?;
+        let stored = variants.remove(index);
+        if variants.is_empty() {
+            self.entries.remove(key);
+        }
+        match stored.body {
+            Location::Memory(_) => self.stats.memory_bytes -= stored.len,
+            Location::Disk(_) => self.stats.disk_bytes -= stored.len,
+        }
+        self.stats.entries -= 1;
+        Some(stored)
+    }
+
+    fn remove_expired(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let expired: Vec<(String, usize)> = self
+            .entries
+            .iter()
+            .flat_map(|(key, variants)| variants.iter().enumerate().map(move |(i, stored)| (key, i, stored)))
+            .filter(|(_, _, stored)| stored.expires <= now)
+            .map(|(key, i, _)| (key.clone(), i))
+            .collect();
+        // Highest index first so the others stay valid
+        for (key, index) in expired.into_iter().rev() {
+            if let Some(Stored {
+                body: Location::Disk(path),
+                ..
+            }) = self.take(&key, index)
+            {
+                files.push(path);
+            }
+        }
+        files
+    }
+}
+
+/// Response caches by subdomain
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    tunnels: Mutex<HashMap<String, TunnelCache>>,
+}
+
+impl ResponseCache {
+    /// A fresh stored response for `key` whose varying request headers
+    /// (looked up through `header`) match the request
+    pub async fn lookup(
+        &self,
+        subdomain: &str,
+        correlation_id: &str,
+        key: &str,
+        header: impl Fn(&str) -> Option<String>,
+    ) -> Option<CacheHit> {
+        let (head, body, age) = {
+            let mut tunnels = self.tunnels.lock().await;
+            let cache = tunnels.get_mut(subdomain).filter(|c| c.correlation_id == correlation_id)?;
+            let now = Instant::now();
+            let tick = cache.tick();
+            let found = cache
+                .entries
+                .get_mut(key)
+                .and_then(|variants| variants.iter_mut().find(|s| s.expires > now && s.matches(&header)));
+            let Some(stored) = found else {
+                cache.stats.misses += 1;
+                return None;
+            };
+            stored.last_used = tick;
+            let body = match &stored.body {
+                Location::Memory(body) => Ok(body.clone()),
+                Location::Disk(path) => Err(path.clone()),
+            };
+            let age = stored.age + now.duration_since(stored.stored_at);
+            let head = stored.head.clone();
+            cache.stats.hits += 1;
+            (head, body, age)
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(path) => match tokio::fs::read(&path).await {
+                Ok(body) => Bytes::from(body),
+                Err(e) => {
+                    warn!("Failed to read cached response {}: {}", path.display(), e);
+                    return None;
+                }
+            },
+        };
+        Some(CacheHit { head, body, age })
+    }
+
+    /// Keep a response, replacing a stored one for the same request
+    pub async fn store(&self, subdomain: &str, correlation_id: &str, key: String, entry: CacheEntry) {
+        self.store_with(subdomain, correlation_id, key, entry, &limits()).await
+    }
+
+    async fn store_with(&self, subdomain: &str, correlation_id: &str, key: String, entry: CacheEntry, limits: &Limits) {
+        let len = (entry.head.len() + entry.body.len()) as u64;
+        if len > MAX_ENTRY_BYTES as u64 || len > limits.memory {
+            return;
+        }
+        let mut tunnels = self.tunnels.lock().await;
+        if tunnels.get(subdomain).is_none_or(|cache| cache.correlation_id != correlation_id) {
+            // New tunnel on the subdomain: start over
+            remove_dir(tunnel_dir(limits, subdomain)).await;
+            tunnels.insert(subdomain.to_string(), TunnelCache::new(correlation_id));
+        }
+        let cache = tunnels.get_mut(subdomain).expect("just inserted");
+
+        let now = Instant::now();
+        let mut stale = cache.remove_expired(now);
+        let replaced = cache
+            .entries
+            .get(&key)
+            .and_then(|variants| variants.iter().position(|s| s.vary == entry.vary));
+        if let Some(index) = replaced {
+            if let Some(Stored {
+                body: Location::Disk(path),
+                ..
+            }) = cache.take(&key, index)
+            {
+                stale.push(path);
+            }
+        }
+
+        let last_used = cache.tick();
+        cache.entries.entry(key).or_default().push(Stored {
+            head: entry.head,
+            body: Location::Memory(entry.body),
+            len,
+            vary: entry.vary,
+            stored_at: now,
+            expires: now + entry.fresh_for.saturating_sub(entry.age),
+            age: entry.age,
+            last_used,
+        });
+        cache.stats.entries += 1;
+        cache.stats.memory_bytes += len;
+
+        // Over the memory budget: move the least recently used entries to disk
+        while cache.stats.memory_bytes > limits.memory {
+            let Some((key, index)) = cache.least_recent(false) else {
+                break;
+            };
+            let Some(mut stored) = cache.take(&key, index) else {
+                break;
+            };
+            let Some(dir) = tunnel_dir(limits, subdomain).filter(|_| stored.len <= limits.disk) else {
+                continue;
+            };
+            let Location::Memory(body) = &stored.body else {
+                continue;
+            };
+            let path = dir.join(cache.next_file.to_string());
+            cache.next_file += 1;
+            let written = match tokio::fs::create_dir_all(&dir).await {
+                Ok(()) => tokio::fs::write(&path, body).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                warn!("Failed to write cached response {}: {}", path.display(), e);
+                continue;
+            }
+            stored.body = Location::Disk(path);
+            cache.stats.disk_bytes += stored.len;
+            cache.stats.entries += 1;
+            cache.entries.entry(key).or_default().push(stored);
+            while cache.stats.disk_bytes > limits.disk {
+                let Some((key, index)) = cache.least_recent(true) else {
+                    break;
+                };
+                if let Some(Stored {
+                    body: Location::Disk(path),
+                    ..
+                }) = cache.take(&key, index)
+                {
+                    stale.push(path);
+                }
+            }
+        }
+        for path in stale {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    /// Counters of a tunnel's cache
+    pub async fn stats(&self, subdomain: &str) -> CacheStats {
+        let tunnels = self.tunnels.lock().await;
+        tunnels.get(subdomain).map(|cache| cache.stats).unwrap_or_default()
+    }
+
+    /// Drop everything a tunnel has cached; returns how many entries that was
+    pub async fn purge(&self, subdomain: &str) -> usize {
+        let removed = self.tunnels.lock().await.remove(subdomain);
+        remove_dir(tunnel_dir(&limits(), subdomain)).await;
+        removed.map_or(0, |cache| cache.stats.entries)
+    }
+
+    /// Drop expired entries, and the caches of tunnels that are gone or no
+    /// longer cache (`keep` lists subdomains and correlation IDs that do)
+    pub async fn prune(&self, keep: &[(String, String)]) {
+        let limits = limits();
+        let now = Instant::now();
+        let mut stale = Vec::new();
+        let mut dirs = Vec::new();
+        {
+            let mut tunnels = self.tunnels.lock().await;
+            tunnels.retain(|subdomain, cache| {
+                let kept = keep
+                    .iter()
+                    .any(|(s, correlation_id)| s == subdomain && *correlation_id == cache.correlation_id);
+                if !kept {
+                    debug!("Dropping response cache of {}", subdomain);
+                    dirs.push(tunnel_dir(&limits, subdomain));
+                }
+                kept
+            });
+            for cache in tunnels.values_mut() {
+                stale.extend(cache.remove_expired(now));
+            }
+        }
+        for path in stale {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        for dir in dirs {
+            remove_dir(dir).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &str, vary: Option<&str>) -> CacheEntry {
+        CacheEntry {
+            head: b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            body: Bytes::from(body.to_string()),
+            vary: vary
+                .map(|value| vec![("accept-language".to_string(), Some(value.to_string()))])
+                .unwrap_or_default(),
+            fresh_for: Duration::from_secs(60),
+            age: Duration::ZERO,
+        }
+    }
+
+    fn language(value: &'static str) -> impl Fn(&str) -> Option<String> {
+        move |name| (name == "accept-language").then(|| value.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_and_vary() {
+        let cache = ResponseCache::default();
+        let limits = limits();
+        let key = "GET /app.js".to_string();
+        cache.store_with("app", "cid1", key.clone(), entry("en", Some("en")), &limits).await;
+        cache.store_with("app", "cid1", key.clone(), entry("de", Some("de")), &limits).await;
+
+        let hit = cache.lookup("app", "cid1", &key, language("de")).await.unwrap();
+        assert_eq!(hit.body, Bytes::from("de"));
+        assert!(cache.lookup("app", "cid1", &key, language("fr")).await.is_none());
+        // Another tunnel on the subdomain never sees the entries
+        assert!(cache.lookup("app", "cid2", &key, language("en")).await.is_none());
+
+        let stats = cache.stats("app").await;
+        assert_eq!((stats.entries, stats.hits, stats.misses), (2, 1, 1));
+
+        // Expired responses are not served
+        let mut stale = entry("old", None);
+        stale.age = Duration::from_secs(60);
+        cache.store_with("app", "cid1", "GET /old.css".to_string(), stale, &limits).await;
+        assert!(cache.lookup("app", "cid1", "GET /old.css", language("en")).await.is_none());
+
+        cache.prune(&[]).await;
+        assert_eq!(cache.stats("app").await, CacheStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_spill_to_disk() {
+        let dir = std::env::temp_dir().join(format!("exlo-cache-test-{}", std::process::id()));
+        let limits = Limits {
+            memory: 64,
+            dir: Some(dir.clone()),
+            disk: 1024,
+        };
+        let cache = ResponseCache::default();
+        let body = "x".repeat(30);
+        cache.store_with("app", "cid", "GET /a".to_string(), entry(&body, None), &limits).await;
+        cache.store_with("app", "cid", "GET /b".to_string(), entry(&body, None), &limits).await;
+
+        // The older entry moved to disk and is still served from there
+        let stats = cache.stats("app").await;
+        assert_eq!(stats.entries, 2);
+        assert!(stats.disk_bytes > 0 && stats.memory_bytes <= limits.memory);
+        let hit = cache.lookup("app", "cid", "GET /a", |_| None).await.unwrap();
+        assert_eq!(hit.body, Bytes::from(body));
+
+        let _ = tokio::fs::remove_dir_all(dir).await;
+    }
+}
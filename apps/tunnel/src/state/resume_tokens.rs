@@ -0,0 +1,177 @@
+//! Short-lived tokens that resume a session after a dropped connection.
+//!
+//! Once a session's tunnels are up, the client gets a token (a
+//! `resume_token` event on the session channel). Connecting again with
+//! `resume-<token>` as the SSH username, with the same key, skips the
+//! verified-key lookup and the Device Flow: the session is verified as the
+//! same user right away and its forwards get the previous subdomains back,
+//! taking them over from the old connection if the server hasn't noticed it
+//! dropped yet. Tokens are single-use (the resumed session gets a new one)
+//! and expire after `RESUME_TOKEN_TTL`; a session only ever has one.
+//! Revoking the key or kicking the session or its user revokes its tokens.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+
+use super::perf_profiles::PerfProfile;
+
+/// SSH username prefix presenting a resumption token
+pub const RESUME_USER_PREFIX: &str = "resume-";
+
+/// What a token restores
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeGrant {
+    pub user_id: String,
+    pub display_name: String,
+    pub tier: Option<String>,
+    /// Subdomains by client port
+    pub subdomains: HashMap<u32, String>,
+    pub perf_profile: Option<PerfProfile>,
+    /// Key the token was issued to
+    pub fingerprint: String,
+    /// Session the token was issued to
+    pub session_id: String,
+}
+
+#[derive(Debug)]
+struct IssuedToken {
+    grant: ResumeGrant,
+    expires_at: Instant,
+}
+
+/// Live tokens by token
+#[derive(Debug, Default)]
+pub struct ResumeTokens {
+    tokens: Mutex<HashMap<String, IssuedToken>>,
+}
+
+impl ResumeTokens {
+    /// Issue a token for a session, replacing the one it had
+    pub fn issue(&self, grant: ResumeGrant, ttl: Duration) -> String {
+        let mut bytes = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, issued| issued.grant.session_id != grant.session_id);
+        let expires_at = Instant::now() + ttl;
+        tokens.insert(token.clone(), IssuedToken { grant, expires_at });
+        token
+    }
+
+    /// Use up a token presented with the key `fingerprint`. None if it is
+    /// unknown, expired or was issued to another key (clients offering
+    /// several keys don't use it up with the wrong one).
+    pub fn redeem(&self, token: &str, fingerprint: &str) -> Option<ResumeGrant> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.get(token)?.grant.fingerprint != fingerprint {
+            return None;
+        }
+        let issued = tokens.remove(token)?;
+        (issued.expires_at > Instant::now()).then_some(issued.grant)
+    }
+
+    /// Keep a renamed tunnel's subdomain in the tokens naming it
+    pub fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        for issued in self.tokens.lock().unwrap().values_mut() {
+            for held in issued.grant.subdomains.values_mut().filter(|held| *held == subdomain) {
+                *held = new_subdomain.to_string();
+            }
+        }
+    }
+
+    /// Revoke the tokens issued to the key `fingerprint`
+    pub fn revoke_fingerprint(&self, fingerprint: &str) {
+        self.revoke_where(|grant| grant.fingerprint == fingerprint);
+    }
+
+    /// Revoke the tokens of a user's sessions
+    pub fn revoke_user(&self, user_id: &str) {
+        self.revoke_where(|grant| grant.user_id == user_id);
+    }
+
+    /// Revoke the token of a session
+    pub fn revoke_session(&self, session_id: &str) {
+        self.revoke_where(|grant| grant.session_id == session_id);
+    }
+
+    /// Revoke every token
+    pub fn revoke_all(&self) {
+        self.tokens.lock().unwrap().clear();
+    }
+
+    fn revoke_where(&self, revoked: impl Fn(&ResumeGrant) -> bool) {
+        self.tokens.lock().unwrap().retain(|_, issued| !revoked(&issued.grant));
+    }
+
+    /// Forget expired tokens
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.tokens.lock().unwrap().retain(|_, issued| issued.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(session_id: &str) -> ResumeGrant {
+        ResumeGrant {
+            user_id: "user_1".to_string(),
+            display_name: "Alice".to_string(),
+            tier: None,
+            subdomains: HashMap::from([(3000, "myapp".to_string())]),
+            perf_profile: None,
+            fingerprint: "SHA256:key".to_string(),
+            session_id: session_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_issue_and_redeem() {
+        let tokens = ResumeTokens::default();
+        let first = tokens.issue(grant("s1"), Duration::from_secs(60));
+        let second = tokens.issue(grant("s1"), Duration::from_secs(60));
+        // A session only has its latest token
+        assert_eq!(tokens.redeem(&first, "SHA256:key"), None);
+
+        tokens.rename("myapp", "web");
+        let redeemed = tokens.redeem(&second, "SHA256:key").unwrap();
+        assert_eq!(redeemed.subdomains[&3000], "web");
+        // Single use
+        assert_eq!(tokens.redeem(&second, "SHA256:key"), None);
+
+        let other_key = tokens.issue(grant("s2"), Duration::from_secs(60));
+        assert_eq!(tokens.redeem(&other_key, "SHA256:other"), None);
+        assert!(tokens.redeem(&other_key, "SHA256:key").is_some());
+        let expired = tokens.issue(grant("s3"), Duration::ZERO);
+        assert_eq!(tokens.redeem(&expired, "SHA256:key"), None);
+    }
+
+    #[test]
+    fn test_revoked_tokens_fail() {
+        let tokens = ResumeTokens::default();
+        let ttl = Duration::from_secs(60);
+        let by_key = tokens.issue(grant("s1"), ttl);
+        tokens.revoke_fingerprint("SHA256:other");
+        tokens.revoke_fingerprint("SHA256:key");
+        assert_eq!(tokens.redeem(&by_key, "SHA256:key"), None);
+
+        let by_user = tokens.issue(grant("s1"), ttl);
+        tokens.revoke_user("user_1");
+        assert_eq!(tokens.redeem(&by_user, "SHA256:key"), None);
+
+        let by_session = tokens.issue(grant("s1"), ttl);
+        let kept = tokens.issue(grant("s2"), ttl);
+        tokens.revoke_session("s1");
+        assert_eq!(tokens.redeem(&by_session, "SHA256:key"), None);
+        assert!(tokens.redeem(&kept, "SHA256:key").is_some());
+
+        let all = tokens.issue(grant("s3"), ttl);
+        tokens.revoke_all();
+        assert_eq!(tokens.redeem(&all, "SHA256:key"), None);
+    }
+}
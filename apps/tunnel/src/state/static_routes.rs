@@ -0,0 +1,116 @@
+//! Operator-defined static routes.
+//!
+//! `STATIC_ROUTES=docs=127.0.0.1:4000,dash=web:3000` sends `docs.<domain>` to
+//! a service the server itself can reach, without any SSH session: the
+//! dashboard or docs can live on a subdomain of the same base domain behind
+//! the same proxy. Routes are read once at startup, sit in the same routing
+//! namespace as tunnels (a route's subdomain can't be registered by a client)
+//! and are served over plain HTTP/1.x.
+
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::config::{get as get_config, is_loaded as config_loaded};
+use crate::ssh::is_valid_subdomain;
+
+/// A subdomain served by a fixed upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticRoute {
+    pub subdomain: String,
+    /// `host:port` connected to for every request
+    pub target: String,
+}
+
+/// Parse `subdomain=host:port` pairs separated by commas
+pub fn parse_static_routes(value: &str) -> Result<Vec<StaticRoute>, String> {
+    let mut routes: Vec<StaticRoute> = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (subdomain, target) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not subdomain=host:port", entry))?;
+        let subdomain = subdomain.trim().to_lowercase();
+        let target = target.trim();
+        if !is_valid_subdomain(&subdomain) {
+            return Err(format!("'{}' is not a valid subdomain", subdomain));
+        }
+        let valid_target = target
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port > 0));
+        if !valid_target {
+            return Err(format!("'{}' is not a host:port target", target));
+        }
+        if routes.iter().any(|route| route.subdomain == subdomain) {
+            return Err(format!("'{}' is routed twice", subdomain));
+        }
+        routes.push(StaticRoute {
+            subdomain,
+            target: target.to_string(),
+        });
+    }
+    Ok(routes)
+}
+
+/// Static routes by subdomain
+#[derive(Debug)]
+pub struct StaticRoutes {
+    routes: HashMap<String, StaticRoute>,
+}
+
+impl Default for StaticRoutes {
+    fn default() -> Self {
+        if config_loaded() {
+            Self::new(get_config().static_routes.clone())
+        } else {
+            Self::new(Vec::new())
+        }
+    }
+}
+
+impl StaticRoutes {
+    pub fn new(routes: Vec<StaticRoute>) -> Self {
+        for route in &routes {
+            info!("Static route: {} -> {}", route.subdomain, route.target);
+        }
+        Self {
+            routes: routes.into_iter().map(|route| (route.subdomain.clone(), route)).collect(),
+        }
+    }
+
+    pub fn get(&self, subdomain: &str) -> Option<&StaticRoute> {
+        self.routes.get(subdomain)
+    }
+
+    pub fn contains(&self, subdomain: &str) -> bool {
+        self.routes.contains_key(subdomain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_static_routes() {
+        let routes = parse_static_routes(" docs=127.0.0.1:4000, Dash=web:3000 ,").unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                StaticRoute {
+                    subdomain: "docs".to_string(),
+                    target: "127.0.0.1:4000".to_string(),
+                },
+                StaticRoute {
+                    subdomain: "dash".to_string(),
+                    target: "web:3000".to_string(),
+                },
+            ]
+        );
+        assert_eq!(parse_static_routes("").unwrap(), Vec::new());
+        assert!(parse_static_routes("docs").is_err());
+        assert!(parse_static_routes("docs=web").is_err());
+        assert!(parse_static_routes("docs=web:0").is_err());
+        assert!(parse_static_routes("-bad-=web:80").is_err());
+        assert!(parse_static_routes("docs=a:1,docs=b:2").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Latency histograms of the requests proxied to each tunnel.
+//!
+//! Every request the tunnel answered adds two samples: the time to first
+//! byte (from accepting the visitor's connection to the first response bytes
+//! from the SSH channel) and the total duration. Samples go into fixed
+//! buckets, so memory stays the same however many requests pass; a
+//! percentile is the upper bound of the bucket it falls in (capped at the
+//! slowest sample). The management API reports them per tunnel, the live
+//! view for all tunnels of a session.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Upper bounds of the histogram buckets, in milliseconds; slower samples
+/// land in a last, open-ended bucket
+const BUCKET_BOUNDS_MS: [u64; 16] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 300_000,
+];
+
+/// Latency samples counted per bucket
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    max_ms: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, ms: u64) {
+        let bucket = BUCKET_BOUNDS_MS.partition_point(|&bound| bound < ms);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.max_ms = self.max_ms.max(other.max_ms);
+    }
+
+    /// Latency below which `quantile` (0.0 - 1.0) of the samples fall
+    pub fn percentile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = BUCKET_BOUNDS_MS.get(bucket).copied().unwrap_or(u64::MAX);
+                return Some(bound.min(self.max_ms));
+            }
+        }
+        Some(self.max_ms)
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            p50_ms: self.percentile(0.50),
+            p95_ms: self.percentile(0.95),
+            p99_ms: self.percentile(0.99),
+            max_ms: (self.count > 0).then_some(self.max_ms),
+        }
+    }
+}
+
+/// Percentiles of a histogram (None without samples)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+    pub max_ms: Option<u64>,
+}
+
+/// Both histograms of a tunnel
+#[derive(Debug, Clone, Default)]
+struct TunnelLatency {
+    first_byte: Histogram,
+    total: Histogram,
+}
+
+/// Latency percentiles of one or more tunnels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencyReport {
+    /// From accepting the connection to the tunnel's first response bytes
+    pub first_byte: LatencySummary,
+    /// From accepting the connection until it was done
+    pub total: LatencySummary,
+}
+
+/// Latency histograms by subdomain
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    tunnels: Mutex<HashMap<String, TunnelLatency>>,
+}
+
+impl LatencyStats {
+    /// Count a request the tunnel answered
+    pub fn record(&self, subdomain: &str, first_byte_ms: u64, total_ms: u64) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let latency = tunnels.entry(subdomain.to_string()).or_default();
+        latency.first_byte.record(first_byte_ms);
+        latency.total.record(total_ms);
+    }
+
+    /// Percentiles over all requests to the given subdomains
+    pub fn report(&self, subdomains: &[String]) -> LatencyReport {
+        let tunnels = self.tunnels.lock().unwrap();
+        let mut merged = TunnelLatency::default();
+        for latency in subdomains.iter().filter_map(|subdomain| tunnels.get(subdomain)) {
+            merged.first_byte.merge(&latency.first_byte);
+            merged.total.merge(&latency.total);
+        }
+        LatencyReport {
+            first_byte: merged.first_byte.summary(),
+            total: merged.total.summary(),
+        }
+    }
+
+    /// Keep a renamed tunnel's histograms
+    pub fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if let Some(latency) = tunnels.remove(subdomain) {
+            tunnels.insert(new_subdomain.to_string(), latency);
+        }
+    }
+
+    /// Forget subdomains without a registered tunnel
+    pub fn prune(&self, subdomains: &[String]) {
+        self.tunnels
+            .lock()
+            .unwrap()
+            .retain(|subdomain, _| subdomains.contains(subdomain));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.summary(), LatencySummary::default());
+        for ms in 1..=100 {
+            histogram.record(ms);
+        }
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 100);
+        // Upper bounds of the buckets holding the 50th, 95th and 99th sample
+        assert_eq!((summary.p50_ms, summary.p95_ms, summary.p99_ms), (Some(50), Some(100), Some(100)));
+        assert_eq!(summary.max_ms, Some(100));
+
+        // Capped at the slowest sample, also past the last bound
+        histogram.record(400_000);
+        assert_eq!(histogram.percentile(1.0), Some(400_000));
+        histogram.record(0);
+        assert_eq!(histogram.percentile(0.0), Some(1));
+    }
+
+    #[test]
+    fn test_report_merges_and_follows_renames() {
+        let stats = LatencyStats::default();
+        stats.record("app", 3, 20);
+        stats.record("api", 40, 600);
+        stats.rename("app", "web");
+        let report = stats.report(&["web".to_string(), "api".to_string()]);
+        assert_eq!(report.total.count, 2);
+        assert_eq!(report.first_byte.p50_ms, Some(5));
+        assert_eq!(report.total.p99_ms, Some(600));
+        assert_eq!(stats.report(&["app".to_string()]).total.count, 0);
+
+        stats.prune(&["api".to_string()]);
+        assert_eq!(stats.report(&["web".to_string()]).total.count, 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Response status statistics and 5xx alerts per tunnel.
+//!
+//! The proxy reports the status of every response the tunneled service sends
+//! (and a 502 when it can't be reached). Each tunnel keeps running counts per
+//! status class; owners can add an alert that fires once the share of 5xx
+//! responses within a sliding window reaches a threshold, and resolves when it
+//! drops below again.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Responses needed within the window before an alert is evaluated
+pub const MIN_ALERT_REQUESTS: u64 = 5;
+
+/// Longest alert window
+pub const MAX_ALERT_WINDOW: Duration = Duration::from_secs(24 * 3600);
+
+/// Responses per status class since the tunnel was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct StatusCounts {
+    /// 1xx and 2xx
+    pub success: u64,
+    pub redirect: u64,
+    pub client_error: u64,
+    pub server_error: u64,
+}
+
+impl StatusCounts {
+    pub fn record(&mut self, status: u16) {
+        match status {
+            300..=399 => self.redirect += 1,
+            400..=499 => self.client_error += 1,
+            500..=599 => self.server_error += 1,
+            _ => self.success += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.success + self.redirect + self.client_error + self.server_error
+    }
+}
+
+/// An alert firing or resolving
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlertChange {
+    pub firing: bool,
+    /// Share of 5xx responses in the window, in percent
+    pub error_percent: f64,
+    /// Responses in the window
+    pub requests: u64,
+    pub window: Duration,
+}
+
+/// Alert on the share of 5xx responses over a sliding window
+#[derive(Debug, Clone)]
+pub struct StatusAlert {
+    /// Fire at or above this share of 5xx responses (percent)
+    pub threshold_percent: f64,
+    pub window: Duration,
+    pub firing: bool,
+    /// Per-second buckets of (second, responses, 5xx responses), oldest first
+    buckets: VecDeque<(u64, u64, u64)>,
+    started: Instant,
+}
+
+impl StatusAlert {
+    pub fn new(threshold_percent: f64, window: Duration) -> Self {
+        Self {
+            threshold_percent,
+            window,
+            firing: false,
+            buckets: VecDeque::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// (responses, 5xx responses) within the window
+    fn totals(&self) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .fold((0, 0), |(total, errors), (_, t, e)| (total + t, errors + e))
+    }
+
+    /// Count a response; returns the change if the alert fired or resolved
+    pub fn observe(&mut self, now: Instant, status: u16) -> Option<AlertChange> {
+        let second = now.saturating_duration_since(self.started).as_secs();
+        let error = u64::from((500..=599).contains(&status));
+        match self.buckets.back_mut() {
+            Some((s, total, errors)) if *s == second => {
+                *total += 1;
+                *errors += error;
+            }
+            _ => self.buckets.push_back((second, 1, error)),
+        }
+        let oldest = second.saturating_sub(self.window.as_secs().saturating_sub(1));
+        while self.buckets.front().is_some_and(|(s, _, _)| *s < oldest) {
+            self.buckets.pop_front();
+        }
+
+        let (requests, errors) = self.totals();
+        if requests < MIN_ALERT_REQUESTS {
+            return None;
+        }
+        let error_percent = errors as f64 * 100.0 / requests as f64;
+        let firing = error_percent >= self.threshold_percent;
+        if firing == self.firing {
+            return None;
+        }
+        self.firing = firing;
+        Some(AlertChange {
+            firing,
+            error_percent,
+            requests,
+            window: self.window,
+        })
+    }
+}
+
+/// Parse an alert threshold ("10" or "10%", in (0, 100])
+pub fn parse_threshold(value: &str) -> Option<f64> {
+    let percent: f64 = value.strip_suffix('%').unwrap_or(value).parse().ok()?;
+    (percent > 0.0 && percent <= 100.0).then_some(percent)
+}
+
+/// Parse an alert window ("300", "30s", "5m", "1h"), up to `MAX_ALERT_WINDOW`
+pub fn parse_window(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 's') => (&value[..i], 1),
+        (i, 'm') => (&value[..i], 60),
+        (i, 'h') => (&value[..i], 3600),
+        _ => (value, 1),
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(unit)?;
+    let window = Duration::from_secs(secs);
+    (secs > 0 && window <= MAX_ALERT_WINDOW).then_some(window)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_counts() {
+        let mut counts = StatusCounts::default();
+        for status in [200, 204, 301, 404, 500, 503] {
+            counts.record(status);
+        }
+        assert_eq!(
+            counts,
+            StatusCounts {
+                success: 2,
+                redirect: 1,
+                client_error: 1,
+                server_error: 2,
+            }
+        );
+        assert_eq!(counts.total(), 6);
+    }
+
+    #[test]
+    fn test_alert_fires_and_resolves() {
+        let mut alert = StatusAlert::new(50.0, Duration::from_secs(60));
+        let start = alert.started;
+        // Too few responses to judge
+        for _ in 0..4 {
+            assert_eq!(alert.observe(start, 500), None);
+        }
+        let fired = alert.observe(start, 200).unwrap();
+        assert!(fired.firing);
+        assert_eq!((fired.requests, fired.error_percent), (5, 80.0));
+        assert_eq!(alert.observe(start, 500), None);
+
+        // The errors leave the window; healthy responses resolve it
+        let later = start + Duration::from_secs(120);
+        for _ in 0..4 {
+            assert_eq!(alert.observe(later, 200), None);
+        }
+        let resolved = alert.observe(later, 200).unwrap();
+        assert!(!resolved.firing);
+        assert_eq!(resolved.error_percent, 0.0);
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(parse_threshold("10"), Some(10.0));
+        assert_eq!(parse_threshold("2.5%"), Some(2.5));
+        assert_eq!(parse_threshold("0"), None);
+        assert_eq!(parse_threshold("150"), None);
+        assert_eq!(parse_threshold("ten"), None);
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("300"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_window("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_window("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_window("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_window("0"), None);
+        assert_eq!(parse_window("2d"), None);
+        assert_eq!(parse_window("25h"), None);
+    }
+}
@@ -0,0 +1,115 @@
+//! Pre-generated random subdomains.
+//!
+//! Activation draws a subdomain from the pool instead of generating one and
+//! checking it for collisions on the hot path. A maintenance task keeps the
+//! pool topped up; if it ever runs dry, a subdomain is generated inline.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use log::debug;
+
+use super::AppState;
+use crate::ssh::generate_secure_subdomain_id;
+
+/// Number of subdomains kept ready
+pub const DEFAULT_POOL_SIZE: usize = 64;
+
+/// Format a random ID as a tunnel subdomain
+fn random_subdomain() -> String {
+    format!("tunnel-{}", generate_secure_subdomain_id())
+}
+
+/// Pool of unused random subdomains
+#[derive(Debug)]
+pub struct SubdomainPool {
+    ready: Mutex<VecDeque<String>>,
+    size: usize,
+}
+
+impl Default for SubdomainPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_SIZE)
+    }
+}
+
+impl SubdomainPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            ready: Mutex::new(VecDeque::with_capacity(size)),
+            size,
+        }
+    }
+
+    /// Take a subdomain; each pooled entry is handed out exactly once
+    pub fn take(&self) -> String {
+        let pooled = self.ready.lock().unwrap().pop_front();
+        pooled.unwrap_or_else(|| {
+            debug!("Subdomain pool empty, generating inline");
+            random_subdomain()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ready.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Top the pool up with fresh subdomains that aren't in use
+    pub async fn refill(&self, state: &AppState) {
+        let missing = self.size.saturating_sub(self.len());
+        if missing == 0 {
+            return;
+        }
+
+        let mut fresh = Vec::with_capacity(missing);
+        for _ in 0..missing {
+            let subdomain = random_subdomain();
+            if !state.is_subdomain_taken(&subdomain).await && state.get_tunnel(&subdomain).await.is_none() {
+                fresh.push(subdomain);
+            }
+        }
+
+        let mut ready = self.ready.lock().unwrap();
+        for subdomain in fresh {
+            if ready.len() >= self.size {
+                break;
+            }
+            ready.push_back(subdomain);
+        }
+        debug!("Subdomain pool refilled to {}", ready.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refill_and_take() {
+        let state = AppState::new();
+        let pool = SubdomainPool::new(8);
+        assert!(pool.is_empty());
+
+        pool.refill(&state).await;
+        assert_eq!(pool.len(), 8);
+
+        let a = pool.take();
+        let b = pool.take();
+        assert_ne!(a, b);
+        assert!(a.starts_with("tunnel-"));
+        assert_eq!(pool.len(), 6);
+
+        pool.refill(&state).await;
+        assert_eq!(pool.len(), 8);
+    }
+
+    #[test]
+    fn test_take_from_empty_pool() {
+        let pool = SubdomainPool::new(0);
+        assert!(pool.take().starts_with("tunnel-"));
+    }
+}
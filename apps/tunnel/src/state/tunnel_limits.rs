@@ -0,0 +1,194 @@
+//! Per-tunnel limits on proxied HTTP requests.
+//!
+//! Each subdomain gets a token bucket (requests per second with a burst) and a
+//! cap on concurrent proxied connections. Defaults come from the configuration;
+//! the management API can override them per tunnel. Overrides are keyed by
+//! subdomain, so they survive reconnects.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::config::{is_loaded as config_loaded, reloadable};
+
+/// Limits for one tunnel (0 = unlimited)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelRateLimit {
+    pub requests_per_second: u32,
+    /// Requests allowed in a burst (0 = same as requests_per_second)
+    #[serde(default)]
+    pub burst: u32,
+    #[serde(default)]
+    pub max_connections: u32,
+}
+
+impl TunnelRateLimit {
+    fn capacity(&self) -> f64 {
+        self.burst.max(self.requests_per_second) as f64
+    }
+}
+
+/// Token bucket refilled at a fixed rate
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(capacity: f64) -> Self {
+        Self {
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &TunnelRateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second as f64).min(limit.capacity());
+        self.updated = now;
+    }
+
+    /// Take a token, or return how long until one is available
+    fn take(&mut self, limit: &TunnelRateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(missing / limit.requests_per_second as f64))
+        }
+    }
+}
+
+/// Why a proxied request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Rate { retry_after: Duration },
+    Connections,
+}
+
+/// Limit overrides and token buckets by subdomain
+#[derive(Debug, Default)]
+pub struct TunnelLimits {
+    overrides: RwLock<HashMap<String, TunnelRateLimit>>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl TunnelLimits {
+    /// Configured default for tunnels without an override
+    fn default_limit() -> TunnelRateLimit {
+        if !config_loaded() {
+            return TunnelRateLimit::default();
+        }
+        reloadable().tunnel_rate_limit
+    }
+
+    /// The limit in effect for a tunnel and whether it was set through the API
+    pub async fn limit_for(&self, subdomain: &str) -> (TunnelRateLimit, bool) {
+        match self.overrides.read().await.get(subdomain) {
+            Some(limit) => (*limit, true),
+            None => (Self::default_limit(), false),
+        }
+    }
+
+    pub async fn set(&self, subdomain: &str, limit: TunnelRateLimit) {
+        self.overrides.write().await.insert(subdomain.to_string(), limit);
+        self.buckets.lock().unwrap().remove(subdomain);
+    }
+
+    /// Drop a tunnel's override; returns whether there was one
+    pub async fn clear(&self, subdomain: &str) -> bool {
+        self.buckets.lock().unwrap().remove(subdomain);
+        self.overrides.write().await.remove(subdomain).is_some()
+    }
+
+    /// Keep a renamed tunnel's override and bucket
+    pub async fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut overrides = self.overrides.write().await;
+        if let Some(limit) = overrides.remove(subdomain) {
+            overrides.insert(new_subdomain.to_string(), limit);
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.remove(subdomain) {
+            buckets.insert(new_subdomain.to_string(), bucket);
+        }
+    }
+
+    /// Take a request token from the tunnel's bucket
+    pub fn check_rate(&self, subdomain: &str, limit: &TunnelRateLimit) -> Result<(), LimitExceeded> {
+        if limit.requests_per_second == 0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry(subdomain.to_string())
+            .or_insert_with(|| TokenBucket::full(limit.capacity()))
+            .take(limit, Instant::now())
+            .map_err(|retry_after| LimitExceeded::Rate { retry_after })
+    }
+
+    /// Forget buckets that have refilled completely (they behave like new ones)
+    pub async fn prune(&self) {
+        let overrides = self.overrides.read().await;
+        let default = Self::default_limit();
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|subdomain, bucket| {
+            let limit = overrides.get(subdomain).unwrap_or(&default);
+            bucket.refill(limit, now);
+            limit.requests_per_second > 0 && bucket.tokens < limit.capacity()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = TunnelRateLimit {
+            requests_per_second: 2,
+            burst: 3,
+            max_connections: 0,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(limit.capacity());
+        for _ in 0..3 {
+            assert!(bucket.take(&limit, start).is_ok());
+        }
+        let retry_after = bucket.take(&limit, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+
+        // Half a second later one token is back
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(&limit, later).is_ok());
+        assert!(bucket.take(&limit, later).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_overrides() {
+        let limits = TunnelLimits::default();
+        assert_eq!(limits.limit_for("app").await, (TunnelRateLimit::default(), false));
+
+        let limit = TunnelRateLimit {
+            requests_per_second: 1,
+            burst: 0,
+            max_connections: 4,
+        };
+        limits.set("app", limit).await;
+        assert_eq!(limits.limit_for("app").await, (limit, true));
+
+        assert!(limits.check_rate("app", &limit).is_ok());
+        assert!(matches!(limits.check_rate("app", &limit), Err(LimitExceeded::Rate { .. })));
+        assert!(limits.check_rate("other", &TunnelRateLimit::default()).is_ok());
+
+        limits.prune().await;
+        assert!(limits.clear("app").await);
+        assert!(!limits.clear("app").await);
+    }
+}
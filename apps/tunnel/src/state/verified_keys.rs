@@ -0,0 +1,135 @@
+//! Persistence of verified keys across restarts.
+//!
+//! Verified keys let a returning client skip the Device Flow and get its
+//! subdomains back. They are written to `VERIFIED_KEYS_PATH` on every change
+//! and loaded when the state is created, so a restart doesn't send everyone
+//! through the browser again. Keys whose verification expired while the
+//! server was down are dropped on load.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::claims::write_atomically;
+use super::VerifiedKey;
+
+/// A verified key as saved to disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct StoredKey {
+    fingerprint: String,
+    user_id: String,
+    display_name: Option<String>,
+    tier: Option<String>,
+    verified_at: DateTime<Utc>,
+    #[serde(default)]
+    renewable_until: Option<DateTime<Utc>>,
+    /// Subdomains by client port
+    subdomains: HashMap<u32, String>,
+}
+
+/// Where verified keys are persisted
+#[derive(Debug, Default)]
+pub struct VerifiedKeyStore {
+    /// None = memory only
+    path: Option<PathBuf>,
+}
+
+impl VerifiedKeyStore {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path }
+    }
+
+    /// Unexpired keys saved by fingerprint (a missing or unreadable file
+    /// starts empty)
+    pub fn load(&self) -> HashMap<String, VerifiedKey> {
+        let Some(path) = &self.path else {
+            return HashMap::new();
+        };
+        let stored = match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Vec<StoredKey>>(&contents) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    warn!("Ignoring unreadable verified keys in {}: {}", path.display(), e);
+                    return HashMap::new();
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read verified keys from {}: {}", path.display(), e);
+                return HashMap::new();
+            }
+        };
+        let keys: HashMap<String, VerifiedKey> = stored
+            .into_iter()
+            .map(|key| {
+                let verified = VerifiedKey {
+                    user_id: key.user_id,
+                    display_name: key.display_name,
+                    verified_at: key.verified_at.into(),
+                    renewable_until: key.renewable_until.map(Into::into),
+                    subdomains: key.subdomains,
+                    tier: key.tier,
+                };
+                (key.fingerprint, verified)
+            })
+            .filter(|(_, key)| !key.is_expired())
+            .collect();
+        if !keys.is_empty() {
+            info!("Loaded {} verified key(s)", keys.len());
+        }
+        keys
+    }
+
+    /// Write all keys to disk (replacing the file atomically)
+    pub async fn save(&self, keys: &HashMap<String, VerifiedKey>) {
+        let Some(path) = &self.path else { return };
+        let mut stored: Vec<StoredKey> = keys
+            .iter()
+            .map(|(fingerprint, key)| StoredKey {
+                fingerprint: fingerprint.clone(),
+                user_id: key.user_id.clone(),
+                display_name: key.display_name.clone(),
+                tier: key.tier.clone(),
+                verified_at: key.verified_at.into(),
+                renewable_until: key.renewable_until.map(Into::into),
+                subdomains: key.subdomains.clone(),
+            })
+            .collect();
+        stored.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        if let Err(e) = write_atomically(path, &serde_json::to_vec_pretty(&stored).unwrap_or_default()).await {
+            warn!("Failed to save verified keys to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn test_keys_survive_reload() {
+        let path = std::env::temp_dir().join(format!("exlo-verified-keys-{}.json", std::process::id()));
+        let store = VerifiedKeyStore::new(Some(path.clone()));
+        let mut key = VerifiedKey::new("user1".to_string(), Some("Ada".to_string()), None);
+        key.subdomains.insert(3000, "myapp".to_string());
+        let expired = VerifiedKey {
+            verified_at: SystemTime::now() - Duration::from_secs(24 * 60 * 60),
+            ..key.clone()
+        };
+        let keys = HashMap::from([("SHA256:a".to_string(), key), ("SHA256:b".to_string(), expired)]);
+        store.save(&keys).await;
+
+        let loaded = VerifiedKeyStore::new(Some(path.clone())).load();
+        assert_eq!(loaded.len(), 1);
+        let key = &loaded["SHA256:a"];
+        assert_eq!((key.user_id.as_str(), key.display_name.as_deref()), ("user1", Some("Ada")));
+        assert_eq!(key.subdomains.get(&3000).map(String::as_str), Some("myapp"));
+        let _ = std::fs::remove_file(path);
+
+        assert!(VerifiedKeyStore::default().load().is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+//! Per-tunnel webhook signature rules.
+//!
+//! A rule names a path prefix of a tunnel, the provider signing deliveries
+//! to it and the shared secret; requests under that path without a valid
+//! signature are refused by the proxy (see `proxy::webhooks`) and never reach
+//! the developer's service. GitHub (`X-Hub-Signature-256`) and Stripe
+//! (`Stripe-Signature`) are built in; `hmac` covers other providers signing
+//! the body with HMAC-SHA256 in a header of their choice. Rules are keyed by
+//! subdomain, so they survive reconnects. Secrets are never returned by the
+//! management API.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+/// Default age limit of Stripe signature timestamps (seconds)
+pub const DEFAULT_TOLERANCE_SECS: u64 = 300;
+
+/// Who signs the deliveries, and so how the signature is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookProvider {
+    /// `X-Hub-Signature-256: sha256=<hex>` over the body
+    Github,
+    /// `Stripe-Signature: t=<unix>,v1=<hex>` over `<t>.<body>`
+    Stripe,
+    /// A configured header holding HMAC-SHA256 of the body
+    Hmac,
+}
+
+/// How a generic `hmac` signature is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/// Signature check for deliveries to a path of a tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookRule {
+    /// Path prefix of the deliveries, e.g. `/webhooks/stripe`
+    pub path: String,
+    pub provider: WebhookProvider,
+    /// Shared secret (write-only)
+    #[serde(default, skip_serializing)]
+    pub secret: String,
+    /// Header carrying the signature (`hmac` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// Text before the signature in the header, e.g. `sha256=` (`hmac` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    /// Oldest signature timestamp accepted (`stripe` only, seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tolerance_secs: Option<u64>,
+}
+
+impl WebhookRule {
+    /// Whether a request for `path` (without the query) is a delivery to this rule
+    pub fn matches(&self, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path.starts_with('/') {
+            return Err(format!("Path '{}' must start with '/'", self.path));
+        }
+        if self.secret.is_empty() {
+            return Err(format!("Rule for '{}' has no secret", self.path));
+        }
+        let header = self.header.as_deref().map(str::trim);
+        match self.provider {
+            WebhookProvider::Hmac if header.is_none_or(|header| header.is_empty() || header.contains(' ')) => {
+                Err(format!("Rule for '{}' needs the header carrying the signature", self.path))
+            }
+            WebhookProvider::Github | WebhookProvider::Stripe if header.is_some() || self.prefix.is_some() => {
+                Err(format!("Rule for '{}' can't override the provider's header", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Validate a tunnel's rule set
+pub fn validate_rules(rules: &[WebhookRule]) -> Result<(), String> {
+    for (i, rule) in rules.iter().enumerate() {
+        rule.validate()?;
+        if rules[..i].iter().any(|other| other.path == rule.path) {
+            return Err(format!("More than one rule for '{}'", rule.path));
+        }
+    }
+    Ok(())
+}
+
+/// Webhook rules by subdomain
+#[derive(Debug, Default)]
+pub struct WebhookRules {
+    rules: RwLock<HashMap<String, Vec<WebhookRule>>>,
+}
+
+impl WebhookRules {
+    pub async fn get(&self, subdomain: &str) -> Vec<WebhookRule> {
+        self.rules.read().await.get(subdomain).cloned().unwrap_or_default()
+    }
+
+    /// Whether a tunnel has any rules
+    pub async fn has_rules(&self, subdomain: &str) -> bool {
+        self.rules.read().await.contains_key(subdomain)
+    }
+
+    /// The rule for a request to `path`; the longest matching prefix wins
+    pub async fn matching(&self, subdomain: &str, path: &str) -> Option<WebhookRule> {
+        let rules = self.rules.read().await;
+        rules
+            .get(subdomain)?
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .max_by_key(|rule| rule.path.len())
+            .cloned()
+    }
+
+    /// Replace a tunnel's rules (an empty list removes them)
+    pub async fn set(&self, subdomain: &str, rules: Vec<WebhookRule>) {
+        let mut all = self.rules.write().await;
+        if rules.is_empty() {
+            all.remove(subdomain);
+        } else {
+            all.insert(subdomain.to_string(), rules);
+        }
+    }
+
+    /// Remove a tunnel's rules; returns how many it had
+    pub async fn clear(&self, subdomain: &str) -> usize {
+        self.rules.write().await.remove(subdomain).map_or(0, |rules| rules.len())
+    }
+
+    /// Keep a renamed tunnel's rules
+    pub async fn rename(&self, subdomain: &str, new_subdomain: &str) {
+        let mut all = self.rules.write().await;
+        if let Some(rules) = all.remove(subdomain) {
+            all.insert(new_subdomain.to_string(), rules);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str, provider: WebhookProvider) -> WebhookRule {
+        WebhookRule {
+            path: path.to_string(),
+            provider,
+            secret: "whsec".to_string(),
+            header: None,
+            prefix: None,
+            encoding: SignatureEncoding::Hex,
+            tolerance_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matching_rules() {
+        let rules = WebhookRules::default();
+        let github = rule("/hooks", WebhookProvider::Github);
+        let stripe = rule("/hooks/stripe/", WebhookProvider::Stripe);
+        rules.set("app", vec![github, stripe]).await;
+
+        let found = rules.matching("app", "/hooks/stripe").await.unwrap();
+        assert_eq!(found.provider, WebhookProvider::Stripe);
+        let found = rules.matching("app", "/hooks/github/push").await.unwrap();
+        assert_eq!(found.provider, WebhookProvider::Github);
+        assert!(rules.matching("app", "/hooksy").await.is_none());
+        assert!(rules.matching("other", "/hooks").await.is_none());
+        assert!(rules.has_rules("app").await && !rules.has_rules("other").await);
+
+        // Secrets stay write-only
+        let listed = serde_json::to_value(rules.get("app").await).unwrap();
+        assert!(listed[0].get("secret").is_none());
+        assert_eq!(rules.clear("app").await, 2);
+        assert!(!rules.has_rules("app").await);
+    }
+
+    #[test]
+    fn test_validate_rules() {
+        assert!(validate_rules(&[rule("/hooks", WebhookProvider::Github)]).is_ok());
+        assert!(validate_rules(&[rule("hooks", WebhookProvider::Github)]).is_err());
+        assert!(validate_rules(&[rule("/a", WebhookProvider::Hmac)]).is_err());
+        let shopify = WebhookRule {
+            header: Some("X-Shopify-Hmac-Sha256".to_string()),
+            encoding: SignatureEncoding::Base64,
+            ..rule("/a", WebhookProvider::Hmac)
+        };
+        assert!(validate_rules(std::slice::from_ref(&shopify)).is_ok());
+        assert!(validate_rules(&[shopify.clone(), shopify]).is_err());
+        let no_secret = WebhookRule {
+            secret: String::new(),
+            ..rule("/a", WebhookProvider::Stripe)
+        };
+        assert!(validate_rules(&[no_secret]).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! systemd service notifications.
+//!
+//! Under a `Type=notify` unit, systemd passes `NOTIFY_SOCKET` and (with
+//! `WatchdogSec=`) `WATCHDOG_USEC`. Once the SSH and HTTP listeners are bound
+//! the server sends `READY=1`, then keeps `STATUS=` current with the tunnel
+//! counts and pets the watchdog at half its interval. A ping is skipped when
+//! the tunnel registry can't be read in time, so a wedged runtime or a stuck
+//! lock gets the service restarted. `SYSTEMD_NOTIFY=false` turns this off.
+
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::config::get as get_config;
+use crate::state::AppState;
+
+/// Status refresh interval without a watchdog
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often readiness is checked before READY=1
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where notifications go
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    socket: PathBuf,
+}
+
+impl Notifier {
+    /// The notify socket systemd passed, if any
+    pub fn from_env() -> Option<Self> {
+        let socket = std::env::var_os("NOTIFY_SOCKET")?;
+        Some(Self { socket: socket.into() })
+    }
+
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self { socket: socket.into() }
+    }
+
+    /// Send newline-separated `KEY=value` assignments
+    pub fn notify(&self, state: &str) -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        // '@' names a socket in the abstract namespace
+        #[cfg(target_os = "linux")]
+        if let Some(name) = self.socket.to_string_lossy().strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        socket.send_to(state.as_bytes(), &self.socket)?;
+        Ok(())
+    }
+}
+
+/// Watchdog ping interval: half of `WATCHDOG_USEC`, if it is meant for us
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// `STATUS=` line from the tunnel counts
+fn status_line(tunnels: usize, connected: usize) -> String {
+    format!("STATUS={} tunnels ({} connected)", tunnels, connected)
+}
+
+/// Start notifying systemd, if it supervises this process
+pub fn spawn_systemd_notifier(state: Arc<AppState>) -> Option<JoinHandle<()>> {
+    if !get_config().systemd_notify {
+        return None;
+    }
+    let notifier = Notifier::from_env()?;
+    let watchdog = watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+    );
+    info!("Notifying systemd (watchdog: {:?})", watchdog);
+    Some(tokio::spawn(run_notifier(state, notifier, watchdog)))
+}
+
+/// Tell systemd the service is shutting down
+pub fn notify_stopping() {
+    if get_config().systemd_notify {
+        if let Some(notifier) = Notifier::from_env() {
+            let _ = notifier.notify("STOPPING=1");
+        }
+    }
+}
+
+async fn run_notifier(state: Arc<AppState>, notifier: Notifier, watchdog: Option<Duration>) {
+    while !(state.readiness.ssh_listening() && state.readiness.http_listening()) {
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+    if let Err(e) = notifier.notify("READY=1\nSTATUS=Listening") {
+        warn!("Failed to notify systemd: {}", e);
+    }
+
+    let interval = watchdog.unwrap_or(STATUS_INTERVAL);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        // A registry that can't be read in time means the server is stuck
+        let tunnels = match tokio::time::timeout(interval, state.list_tunnels()).await {
+            Ok(tunnels) => tunnels,
+            Err(_) => {
+                warn!("Tunnel registry unavailable for {:?}, skipping watchdog ping", interval);
+                continue;
+            }
+        };
+        let connected = tunnels.iter().filter(|t| t.is_connected).count();
+        let mut message = status_line(tunnels.len(), connected);
+        if watchdog.is_some() {
+            message.push_str("\nWATCHDOG=1");
+        }
+        if let Err(e) = notifier.notify(&message) {
+            debug!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_interval(Some("30000000"), None), Some(Duration::from_secs(15)));
+        let pid = std::process::id().to_string();
+        assert_eq!(watchdog_interval(Some("2000000"), Some(&pid)), Some(Duration::from_secs(1)));
+        assert_eq!(watchdog_interval(Some("2000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("exlo-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        Notifier::new(&path).notify(&status_line(3, 2)).unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=3 tunnels (2 connected)");
+        let _ = std::fs::remove_file(&path);
+    }
+}
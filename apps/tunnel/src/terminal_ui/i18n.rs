@@ -0,0 +1,341 @@
+//! Message catalogs of the terminal UI.
+//!
+//! Every string a box or notice shows lives in a [`Catalog`], one per
+//! language. A session's language comes from a `--lang=<code>` username
+//! suffix (`myapp--lang=zh@server`) or, failing that, the client's `LANG`
+//! env request (`zh_CN.UTF-8`); anything else gets English. Plain and JSON
+//! output stays English, since scripts match on it.
+//!
+//! Templates name their values in braces (`{port}`), filled by [`fill`], so
+//! translations can put them in any order.
+
+use std::fmt::Display;
+
+/// Username suffix picking the session's language
+pub const LANG_SUFFIX: &str = "--lang=";
+
+/// Language of a session's terminal UI
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Lang {
+    /// Parse a language code or locale (`zh`, `zh-TW`, `zh_CN.UTF-8`, `C`)
+    pub fn parse(value: &str) -> Option<Self> {
+        let language = value
+            .split(['_', '-', '.', '@'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match language.as_str() {
+            "en" | "c" | "posix" => Some(Self::En),
+            "zh" => Some(Self::Zh),
+            _ => None,
+        }
+    }
+
+    pub fn catalog(self) -> &'static Catalog {
+        match self {
+            Self::En => &EN,
+            Self::Zh => &ZH,
+        }
+    }
+}
+
+/// Split a `--lang=<code>` suffix off an SSH username (`myapp--lang=zh` ->
+/// `myapp`, Chinese). An unknown language is an error.
+pub fn split_lang(user: &str) -> Result<(&str, Option<Lang>), String> {
+    match user.rsplit_once(LANG_SUFFIX) {
+        Some((name, code)) => match Lang::parse(code) {
+            Some(lang) => Ok((name, Some(lang))),
+            None => Err(format!("Unknown language '{}': use en or zh", code)),
+        },
+        None => Ok((user, None)),
+    }
+}
+
+/// Fill the `{name}` placeholders of `template`
+pub fn fill(template: &str, values: &[(&str, &dyn Display)]) -> String {
+    values.iter().fold(template.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), &value.to_string())
+    })
+}
+
+/// The strings of the terminal UI in one language
+#[derive(Debug)]
+pub struct Catalog {
+    pub activation_title: &'static str,
+    /// `{code}`
+    pub your_code: &'static str,
+    pub open_url: &'static str,
+    pub waiting_for_authorization: &'static str,
+    pub activated_title: &'static str,
+    /// `{user}`
+    pub welcome_back: &'static str,
+    pub tunnel_ready: &'static str,
+    pub tunnels_ready: &'static str,
+    pub disconnect_hint: &'static str,
+    pub menu_keys: &'static str,
+    pub activation_failed_title: &'static str,
+    pub error_code: &'static str,
+    pub reconnect_to_retry: &'static str,
+    pub closing_soon: &'static str,
+    pub connection_failed_title: &'static str,
+    /// `{address}`, `{port}`
+    pub cannot_connect: &'static str,
+    pub start_local_service: &'static str,
+    pub port_mismatch_title: &'static str,
+    pub ports_must_match: &'static str,
+    pub correct_usage: &'static str,
+    pub operator_message_title: &'static str,
+    pub maintenance_title: &'static str,
+    pub active_tunnels_title: &'static str,
+    pub this_session: &'static str,
+    pub not_available_title: &'static str,
+    /// `{feature}`
+    pub not_enabled: &'static str,
+    /// `{port}`
+    pub forward_refused: &'static str,
+    pub address_not_allowed_title: &'static str,
+    pub bind_address_refused: &'static str,
+    pub waiting_for_service_title: &'static str,
+    /// `{port}`
+    pub nothing_listening: &'static str,
+    pub live_once_started: &'static str,
+    pub speed_test_title: &'static str,
+    pub round_trip: &'static str,
+    pub upload: &'static str,
+    pub download: &'static str,
+    pub not_measured: &'static str,
+    pub nothing_piped: &'static str,
+    pub failed: &'static str,
+    pub idle_title: &'static str,
+    /// `{duration}`
+    pub idle_for: &'static str,
+    pub closing_idle: &'static str,
+    pub reconnect_same_url: &'static str,
+    pub server_errors_title: &'static str,
+    pub server_errors_resolved_title: &'static str,
+    /// `{percent}`, `{subdomain}`
+    pub error_share: &'static str,
+    /// `{window}`
+    pub over_window: &'static str,
+    pub check_logs: &'static str,
+    /// `{port}`, `{url}`
+    pub service_degraded: &'static str,
+    /// `{port}`, `{url}`
+    pub service_recovered: &'static str,
+    /// `{endpoint}`
+    pub tcp_port_open: &'static str,
+    /// `{endpoint}`
+    pub udp_port_open: &'static str,
+    /// `{url}`, `{ip}`
+    pub took_over: &'static str,
+    pub taken_over_title: &'static str,
+    /// `{url}`, `{ip}`
+    pub taken_over_by: &'static str,
+    pub closing_session: &'static str,
+    /// `{url}`, `{header}`
+    pub secret_path: &'static str,
+    /// `{ports}`
+    pub not_answering: &'static str,
+    /// `{port}`, `{duration}`
+    pub port_down_for: &'static str,
+    pub list_separator: &'static str,
+    /// `{old}`, `{url}`
+    pub renamed: &'static str,
+    /// `{requests}`
+    pub live_requests: &'static str,
+    /// `{p50}`, `{p95}`, `{p99}`
+    pub live_latency: &'static str,
+    pub waiting_for_requests: &'static str,
+    pub esc_again: &'static str,
+    pub subdomain_taken_title: &'static str,
+    pub subdomain_reserved_title: &'static str,
+    pub subdomain_unavailable_title: &'static str,
+    /// `{subdomain}`
+    pub subdomain_taken: &'static str,
+    /// `{subdomain}`
+    pub subdomain_reserved: &'static str,
+    /// `{subdomain}`
+    pub subdomain_unavailable: &'static str,
+    pub try_other_subdomain: &'static str,
+}
+
+pub static EN: Catalog = Catalog {
+    activation_title: "DEVICE ACTIVATION",
+    your_code: "Your code: {code}",
+    open_url: "Open this URL in your browser:",
+    waiting_for_authorization: "Waiting for authorization...",
+    activated_title: "TUNNEL ACTIVATED",
+    welcome_back: "Welcome back, {user}!",
+    tunnel_ready: "Your tunnel is ready:",
+    tunnels_ready: "Your tunnels are ready:",
+    disconnect_hint: "Press Esc double to disconnect",
+    menu_keys: "r new URL · c copy URL · s live view · q quit",
+    activation_failed_title: "ACTIVATION FAILED",
+    error_code: "Error code:",
+    reconnect_to_retry: "Please reconnect to try again.",
+    closing_soon: "Connection will close in 3 seconds...",
+    connection_failed_title: "CONNECTION FAILED",
+    cannot_connect: "Cannot connect to {address}:{port}",
+    start_local_service: "Make sure your local service is running:",
+    port_mismatch_title: "PORT MISMATCH",
+    ports_must_match: "Remote and local ports must match",
+    correct_usage: "Correct usage:",
+    operator_message_title: "MESSAGE FROM OPERATOR",
+    maintenance_title: "SERVER UNDER MAINTENANCE",
+    active_tunnels_title: "YOUR ACTIVE TUNNELS",
+    this_session: "this session",
+    not_available_title: "NOT AVAILABLE",
+    not_enabled: "Not enabled for your account: {feature}",
+    forward_refused: "The forward for port {port} was refused.",
+    address_not_allowed_title: "ADDRESS NOT ALLOWED",
+    bind_address_refused: "Bind address refused:",
+    waiting_for_service_title: "WAITING FOR LOCAL SERVICE",
+    nothing_listening: "Nothing is listening on port {port} yet.",
+    live_once_started: "The tunnel goes live once your app starts.",
+    speed_test_title: "SPEED TEST",
+    round_trip: "Round trip:",
+    upload: "Upload:",
+    download: "Download:",
+    not_measured: "not measured",
+    nothing_piped: "not measured (nothing piped into ssh)",
+    failed: "failed",
+    idle_title: "TUNNEL IDLE",
+    idle_for: "No requests reached your tunnel for {duration}.",
+    closing_idle: "Closing it to free up resources.",
+    reconnect_same_url: "Reconnect any time to get the same URL back.",
+    server_errors_title: "SERVER ERRORS",
+    server_errors_resolved_title: "SERVER ERRORS RESOLVED",
+    error_share: "{percent}% of responses from {subdomain} were 5xx",
+    over_window: "over the last {window}.",
+    check_logs: "Check your local service's logs.",
+    service_degraded: "Nothing answers on port {port} anymore; visitors of {url} get errors",
+    service_recovered: "Port {port} answers again, {url} is back",
+    tcp_port_open: "TCP port open: {endpoint}",
+    udp_port_open: "UDP port open: {endpoint} (datagrams relayed to your UDP agent)",
+    took_over: "Took over {url} from your earlier connection ({ip}), which is being closed",
+    taken_over_title: "TUNNEL TAKEN OVER",
+    taken_over_by: "{url} is now served by a new connection from {ip}.",
+    closing_session: "Closing this session.",
+    secret_path: "Secret URL: {url} (anyone else gets a 404; scripts can send the token in {header})",
+    not_answering: "Not answering: {ports}",
+    port_down_for: "port {port} ({duration})",
+    list_separator: ", ",
+    renamed: "Tunnel {old} renamed, now serving at {url}",
+    live_requests: "{requests} requests",
+    live_latency: "Latency  p50 {p50}  p95 {p95}  p99 {p99}",
+    waiting_for_requests: "Waiting for requests…",
+    esc_again: "Press ESC again to disconnect...",
+    subdomain_taken_title: "SUBDOMAIN TAKEN",
+    subdomain_reserved_title: "SUBDOMAIN RESERVED",
+    subdomain_unavailable_title: "SUBDOMAIN UNAVAILABLE",
+    subdomain_taken: "Subdomain '{subdomain}' is already in use",
+    subdomain_reserved: "Subdomain '{subdomain}' is reserved",
+    subdomain_unavailable: "Subdomain '{subdomain}' is not available",
+    try_other_subdomain: "Try a different subdomain:",
+};
+
+pub static ZH: Catalog = Catalog {
+    activation_title: "设备激活",
+    your_code: "您的验证码：{code}",
+    open_url: "请在浏览器中打开以下链接：",
+    waiting_for_authorization: "等待授权中...",
+    activated_title: "隧道已激活",
+    welcome_back: "欢迎回来，{user}！",
+    tunnel_ready: "您的隧道已就绪：",
+    tunnels_ready: "您的隧道均已就绪：",
+    disconnect_hint: "连按两次 Esc 断开连接",
+    menu_keys: "r 换新地址 · c 复制地址 · s 实时视图 · q 退出",
+    activation_failed_title: "激活失败",
+    error_code: "错误码：",
+    reconnect_to_retry: "请重新连接后再试。",
+    closing_soon: "连接将在 3 秒后关闭...",
+    connection_failed_title: "连接失败",
+    cannot_connect: "无法连接到 {address}:{port}",
+    start_local_service: "请确认本地服务正在运行：",
+    port_mismatch_title: "端口不匹配",
+    ports_must_match: "远程端口与本地端口必须一致",
+    correct_usage: "正确用法：",
+    operator_message_title: "来自运营方的消息",
+    maintenance_title: "服务器维护中",
+    active_tunnels_title: "您的活动隧道",
+    this_session: "当前会话",
+    not_available_title: "不可用",
+    not_enabled: "您的账户未开通：{feature}",
+    forward_refused: "端口 {port} 的转发已被拒绝。",
+    address_not_allowed_title: "地址不被允许",
+    bind_address_refused: "绑定地址被拒绝：",
+    waiting_for_service_title: "等待本地服务",
+    nothing_listening: "端口 {port} 上还没有服务在监听。",
+    live_once_started: "应用启动后隧道即可访问。",
+    speed_test_title: "网速测试",
+    round_trip: "往返延迟：",
+    upload: "上传：",
+    download: "下载：",
+    not_measured: "未测量",
+    nothing_piped: "未测量（没有数据通过管道传给 ssh）",
+    failed: "失败",
+    idle_title: "隧道空闲",
+    idle_for: "您的隧道已有 {duration} 没有收到请求。",
+    closing_idle: "将关闭隧道以释放资源。",
+    reconnect_same_url: "随时重新连接即可找回相同的地址。",
+    server_errors_title: "服务器错误",
+    server_errors_resolved_title: "服务器错误已恢复",
+    error_share: "{subdomain} 的响应中有 {percent}% 为 5xx",
+    over_window: "（统计范围：最近 {window}）",
+    check_logs: "请检查本地服务的日志。",
+    service_degraded: "端口 {port} 已无响应，{url} 的访客会看到错误",
+    service_recovered: "端口 {port} 已恢复响应，{url} 重新可用",
+    tcp_port_open: "TCP 端口已开放：{endpoint}",
+    udp_port_open: "UDP 端口已开放：{endpoint}（数据报转发至本地 UDP 代理）",
+    took_over: "已从您之前的连接（{ip}）接管 {url}，旧连接将被关闭",
+    taken_over_title: "隧道已被接管",
+    taken_over_by: "{url} 现由来自 {ip} 的新连接提供服务。",
+    closing_session: "将关闭此会话。",
+    secret_path: "秘密地址：{url}（其他访问者只会看到 404；脚本可通过 {header} 请求头携带令牌）",
+    not_answering: "无响应：{ports}",
+    port_down_for: "端口 {port}（{duration}）",
+    list_separator: "，",
+    renamed: "隧道 {old} 已重命名，新地址为 {url}",
+    live_requests: "{requests} 个请求",
+    live_latency: "延迟  p50 {p50}  p95 {p95}  p99 {p99}",
+    waiting_for_requests: "等待请求中…",
+    esc_again: "再按一次 ESC 断开连接...",
+    subdomain_taken_title: "子域名已被占用",
+    subdomain_reserved_title: "子域名已被保留",
+    subdomain_unavailable_title: "子域名不可用",
+    subdomain_taken: "子域名 '{subdomain}' 已被使用",
+    subdomain_reserved: "子域名 '{subdomain}' 已被保留",
+    subdomain_unavailable: "子域名 '{subdomain}' 不可用",
+    try_other_subdomain: "请换一个子域名：",
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lang_selection() {
+        assert_eq!(Lang::parse("zh_CN.UTF-8"), Some(Lang::Zh));
+        assert_eq!(Lang::parse("zh-TW"), Some(Lang::Zh));
+        assert_eq!(Lang::parse("C.UTF-8"), Some(Lang::En));
+        assert_eq!(Lang::parse("de_DE.UTF-8"), None);
+
+        assert_eq!(split_lang("myapp--lang=zh"), Ok(("myapp", Some(Lang::Zh))));
+        assert_eq!(split_lang("myapp+bulk--lang=en"), Ok(("myapp+bulk", Some(Lang::En))));
+        assert_eq!(split_lang("my--app"), Ok(("my--app", None)));
+        assert!(split_lang("myapp--lang=xx").is_err());
+    }
+
+    #[test]
+    fn test_fill() {
+        let line = fill(ZH.cannot_connect, &[("address", &"localhost"), ("port", &3000)]);
+        assert_eq!(line, "无法连接到 localhost:3000");
+        assert_eq!(fill(EN.welcome_back, &[("user", &"Ada")]), "Welcome back, Ada!");
+    }
+}
@@ -0,0 +1,1451 @@
+//! Terminal UI helpers for SSH client output.
+//!
+//! Uses the `console` crate for proper text styling and width calculation.
+//! Sessions without a PTY (scripted clients, CI) get the same notices as
+//! plain-text or JSON lines instead of boxes, see [`SessionEvent`]. Boxes
+//! are written in the session's language, see [`i18n`].
+
+pub mod i18n;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use console::{measure_text_width, pad_str, style, truncate_str, Alignment};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::{EcLevel, QrCode};
+
+use crate::acl::Capability;
+use crate::config::{get_port_endpoint, get_tunnel_url};
+use crate::error::TunnelError;
+use crate::proxy::secret_path::TOKEN_HEADER;
+use crate::state::dedicated_ports::Transport;
+use crate::state::history::HistoryEntry;
+use crate::state::requests::RequestEvent;
+use crate::state::stats::LatencySummary;
+
+pub use self::i18n::Lang;
+use self::i18n::fill;
+
+/// How notices are written to a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Boxes and escape sequences for an interactive terminal
+    #[default]
+    Tty,
+    /// One `key: value` line per notice
+    Plain,
+    /// One JSON object per line
+    Json,
+}
+
+impl OutputMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "tty" => Some(Self::Tty),
+            "plain" | "text" => Some(Self::Plain),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A notice for sessions without a terminal
+#[derive(Debug, Clone, Copy)]
+pub enum SessionEvent<'a> {
+    /// Device Flow started; the user must open `url`
+    Activation { code: &'a str, url: &'a str },
+    /// Tunnels are live (subdomain, client port)
+    Ready { tunnels: &'a [(String, u32)] },
+    /// Registered, but nothing listens on the client port yet
+    WaitingForService { port: u32 },
+    /// A forward was refused; the session continues
+    Refused { port: u32, reason: &'a str },
+    /// The session failed and is about to be closed
+    Error { reason: &'a str, code: Option<&'a str> },
+    Renamed { from: &'a str, to: &'a str },
+    /// Operator message
+    Message { text: &'a str },
+    IdleDisconnect { idle: Duration },
+    /// The tunnel's 5xx alert fired or resolved
+    StatusAlert {
+        subdomain: &'a str,
+        firing: bool,
+        error_percent: f64,
+        window: Duration,
+    },
+    /// The local service stopped or resumed answering health checks
+    LocalService {
+        subdomain: &'a str,
+        port: u32,
+        degraded: bool,
+    },
+    /// A dedicated TCP or UDP port is served
+    DedicatedPort { transport: Transport, port: u32 },
+    /// Token resuming the session after a dropped connection
+    ResumeToken { token: &'a str, ttl: Duration },
+    /// This session took a tunnel over from the user's earlier connection
+    TookOver { subdomain: &'a str, from: &'a str },
+    /// A new connection took this session's tunnel over; the session is about to be closed
+    TakenOver { subdomain: &'a str, by: &'a str },
+    /// The tunnel only answers requests carrying its secret path token
+    SecretPath { subdomain: &'a str, token: &'a str },
+}
+
+impl SessionEvent<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Activation { .. } => "activation",
+            Self::Ready { .. } => "ready",
+            Self::WaitingForService { .. } => "waiting_for_service",
+            Self::Refused { .. } => "refused",
+            Self::Error { .. } => "error",
+            Self::Renamed { .. } => "renamed",
+            Self::Message { .. } => "message",
+            Self::IdleDisconnect { .. } => "idle_disconnect",
+            Self::StatusAlert { .. } => "status_alert",
+            Self::LocalService { .. } => "local_service",
+            Self::DedicatedPort { transport: Transport::Tcp, .. } => "tcp_port",
+            Self::DedicatedPort { transport: Transport::Udp, .. } => "udp_port",
+            Self::ResumeToken { .. } => "resume_token",
+            Self::TookOver { .. } => "took_over",
+            Self::TakenOver { .. } => "taken_over",
+            Self::SecretPath { .. } => "secret_path",
+        }
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        let mut value = match self {
+            Self::Activation { code, url } => serde_json::json!({ "code": code, "url": url }),
+            Self::Ready { tunnels } => serde_json::json!({
+                "tunnels": tunnels
+                    .iter()
+                    .map(|(subdomain, port)| serde_json::json!({
+                        "subdomain": subdomain,
+                        "url": get_tunnel_url(subdomain),
+                        "port": port,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            Self::WaitingForService { port } => serde_json::json!({ "port": port }),
+            Self::Refused { port, reason } => serde_json::json!({ "port": port, "reason": reason }),
+            Self::Error { reason, code } => serde_json::json!({ "reason": reason, "code": code }),
+            Self::Renamed { from, to } => {
+                serde_json::json!({ "from": from, "to": to, "url": get_tunnel_url(to) })
+            }
+            Self::Message { text } => serde_json::json!({ "text": text }),
+            Self::IdleDisconnect { idle } => serde_json::json!({ "idle_secs": idle.as_secs() }),
+            Self::StatusAlert { subdomain, firing, error_percent, window } => serde_json::json!({
+                "subdomain": subdomain,
+                "state": if firing { "firing" } else { "resolved" },
+                "error_percent": error_percent,
+                "window_secs": window.as_secs(),
+            }),
+            Self::LocalService { subdomain, port, degraded } => serde_json::json!({
+                "subdomain": subdomain,
+                "port": port,
+                "state": if degraded { "degraded" } else { "healthy" },
+            }),
+            Self::DedicatedPort { port, .. } => {
+                serde_json::json!({ "port": port, "endpoint": get_port_endpoint(port) })
+            }
+            Self::ResumeToken { token, ttl } => {
+                serde_json::json!({ "token": token, "expires_in_secs": ttl.as_secs() })
+            }
+            Self::TookOver { subdomain, from } => {
+                serde_json::json!({ "subdomain": subdomain, "url": get_tunnel_url(subdomain), "from": from })
+            }
+            Self::TakenOver { subdomain, by } => {
+                serde_json::json!({ "subdomain": subdomain, "url": get_tunnel_url(subdomain), "by": by })
+            }
+            Self::SecretPath { subdomain, token } => serde_json::json!({
+                "subdomain": subdomain,
+                "url": secret_url(subdomain, token),
+                "token": token,
+                "header": TOKEN_HEADER,
+            }),
+        };
+        value["event"] = self.name().into();
+        value
+    }
+
+    fn to_plain(self) -> String {
+        match self {
+            Self::Activation { code, url } => format!("activate: {} (code {})\n", url, code),
+            Self::Ready { tunnels } => tunnels
+                .iter()
+                .map(|(subdomain, port)| format!("ready: {} -> localhost:{}\n", get_tunnel_url(subdomain), port))
+                .collect(),
+            Self::WaitingForService { port } => format!("waiting: nothing listening on port {} yet\n", port),
+            Self::Refused { port, reason } => format!("refused: port {}: {}\n", port, reason),
+            Self::Error { reason, code: Some(code) } => format!("error: {} [{}]\n", reason, code),
+            Self::Error { reason, code: None } => format!("error: {}\n", reason),
+            Self::Renamed { from, to } => format!("renamed: {} -> {}\n", from, get_tunnel_url(to)),
+            Self::Message { text } => format!("message: {}\n", text.replace('\n', " ")),
+            Self::IdleDisconnect { idle } => format!("closing: no requests for {}\n", format_duration(idle)),
+            Self::StatusAlert { subdomain, firing, error_percent, window } => format!(
+                "alert: {} {}: {:.1}% 5xx over {}\n",
+                subdomain,
+                if firing { "firing" } else { "resolved" },
+                error_percent,
+                format_duration(window)
+            ),
+            Self::LocalService { subdomain, port, degraded: true } => {
+                format!("degraded: {}: nothing answering on port {}\n", subdomain, port)
+            }
+            Self::LocalService { subdomain, port, degraded: false } => {
+                format!("recovered: {}: port {} answers again\n", subdomain, port)
+            }
+            Self::DedicatedPort { transport, port } => format!("{}: {}\n", transport.as_str(), get_port_endpoint(port)),
+            Self::ResumeToken { token, ttl } => {
+                format!("resume: {} (valid for {})\n", token, format_duration(ttl))
+            }
+            Self::TookOver { subdomain, from } => format!("took_over: {} from {}\n", get_tunnel_url(subdomain), from),
+            Self::TakenOver { subdomain, by } => format!("taken_over: {} by {}\n", get_tunnel_url(subdomain), by),
+            Self::SecretPath { subdomain, token } => {
+                format!("secret: {} (or header {}: {})\n", secret_url(subdomain, token), TOKEN_HEADER, token)
+            }
+        }
+    }
+
+    /// Render for a session without a terminal (empty in TTY mode)
+    pub fn render(self, mode: OutputMode) -> String {
+        match mode {
+            OutputMode::Tty => String::new(),
+            OutputMode::Plain => self.to_plain(),
+            OutputMode::Json => format!("{}\n", self.to_json()),
+        }
+    }
+}
+
+/// Box width (inner content width, excluding borders)
+const BOX_WIDTH: usize = 58;
+
+/// Terminals narrower than this get the minimal layout: boxes without side
+/// borders, as wide as the terminal
+const MIN_BOX_COLUMNS: usize = 60;
+
+/// Spinner animation frames
+const SPINNER_FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Get a spinner frame by index (wraps around)
+pub fn spinner_frame(index: usize) -> &'static str {
+    SPINNER_FRAMES[index % SPINNER_FRAMES.len()]
+}
+
+/// How a session's boxes are drawn: in its language, sized to its terminal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ui {
+    pub lang: Lang,
+    /// Terminal size (cols, rows) from the PTY request or the last resize
+    pub terminal_size: Option<(u32, u32)>,
+}
+
+impl Ui {
+    pub fn new(lang: Lang, terminal_size: Option<(u32, u32)>) -> Self {
+        Self { lang, terminal_size }
+    }
+
+    /// Whether boxes use the minimal layout (narrow terminal)
+    fn minimal(self) -> bool {
+        self.terminal_size.is_some_and(|(cols, _)| (cols as usize) < MIN_BOX_COLUMNS)
+    }
+
+    /// Width of a box's content: `BOX_WIDTH`, less on narrower terminals
+    fn width(self) -> usize {
+        match self.terminal_size {
+            // The last column stays free so lines never wrap
+            Some((cols, _)) if self.minimal() => (cols as usize).saturating_sub(1).max(1),
+            Some((cols, _)) => (cols as usize - 4).min(BOX_WIDTH),
+            None => BOX_WIDTH,
+        }
+    }
+
+    fn border(self, left: char, right: char) -> String {
+        if self.minimal() {
+            format!("{}\r\n", "─".repeat(self.width()))
+        } else {
+            format!("{}{}{}\r\n", left, "═".repeat(self.width() + 2), right)
+        }
+    }
+
+    /// Create a horizontal border line
+    fn top_border(self) -> String {
+        self.border('╔', '╗')
+    }
+
+    fn middle_border(self) -> String {
+        self.border('╠', '╣')
+    }
+
+    fn bottom_border(self) -> String {
+        self.border('╚', '╝')
+    }
+
+    /// Pad (by display width, so wide characters line up) or cut `text` to
+    /// the box width; a cut line never wraps and breaks the box
+    fn line(self, text: &str, alignment: Alignment) -> String {
+        let padded = pad_str(text, self.width(), alignment, Some("…"));
+        if self.minimal() {
+            format!("{}\r\n", padded)
+        } else {
+            format!("║ {} ║\r\n", padded)
+        }
+    }
+
+    /// Create a content line
+    fn content_line(self, text: &str) -> String {
+        self.line(text, Alignment::Left)
+    }
+
+    /// Create a centered content line
+    fn centered_line(self, text: &str) -> String {
+        self.line(text, Alignment::Center)
+    }
+
+    /// Create an empty line
+    fn empty_line(self) -> String {
+        self.content_line("")
+    }
+
+    /// Line with the stable code of an error (see `TunnelError::code`)
+    fn error_code_line(self, code: &str) -> String {
+        self.content_line(&format!("{} {}", style(self.lang.catalog().error_code).dim(), code))
+    }
+}
+
+/// Word-wrap plain text to fit inside the box
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut current = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate_width = if current.is_empty() {
+                measure_text_width(word)
+            } else {
+                measure_text_width(&current) + 1 + measure_text_width(word)
+            };
+            if candidate_width > width && !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        lines.push(current);
+    }
+    lines
+}
+
+/// Render a URL as a QR code, two modules per character cell, light on dark
+/// (with a light quiet zone) so it scans from a terminal with a dark background
+fn qr_code_lines(url: &str, width: usize) -> Option<Vec<String>> {
+    let code = QrCode::with_error_correction_level(url, EcLevel::L).ok()?;
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    let lines: Vec<String> = image.lines().map(str::to_string).collect();
+    lines
+        .first()
+        .is_some_and(|line| measure_text_width(line) <= width)
+        .then_some(lines)
+}
+
+/// The activation QR code, if the terminal is known and large enough to show it
+fn activation_qr(ui: Ui, url: &str) -> Option<Vec<String>> {
+    let (_, rows) = ui.terminal_size?;
+    let lines = qr_code_lines(url, ui.width())?;
+    (rows as usize > ACTIVATION_BOX_LINES + lines.len()).then_some(lines)
+}
+
+/// Number of lines the activation box takes for this URL and terminal size
+pub fn activation_box_lines(ui: Ui, url: &str) -> usize {
+    ACTIVATION_BOX_LINES + activation_qr(ui, url).map_or(0, |lines| lines.len() + 1)
+}
+
+/// Create the device activation box shown when waiting for user verification.
+/// With a known terminal size that fits it, the URL is also shown as a QR code.
+pub fn create_activation_box(ui: Ui, code: &str, url: &str) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("🔐").yellow(), text.activation_title);
+
+    let code_styled = format!("{}", style(code).yellow().bold());
+    let code_line = fill(text.your_code, &[("code", &code_styled)]);
+
+    // Truncate URL if too long
+    let url_display = if measure_text_width(url) > ui.width().saturating_sub(2) {
+        let truncated: String = url.chars().take(ui.width().saturating_sub(5)).collect();
+        format!("{}...", truncated)
+    } else {
+        url.to_string()
+    };
+    let url_styled = format!("{}", style(&url_display).cyan().underlined());
+
+    let spinner_line = format!("{} {}", spinner_frame(0), text.waiting_for_authorization);
+
+    let mut output = String::new();
+    // Clear entire screen and move cursor to top
+    output.push_str("\x1B[2J\x1B[H");
+    output.push_str("\r\n");
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&code_line));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.open_url));
+    output.push_str(&ui.content_line(&url_styled));
+    output.push_str(&ui.empty_line());
+    if let Some(qr) = activation_qr(ui, url) {
+        for line in &qr {
+            output.push_str(&ui.centered_line(line));
+        }
+        output.push_str(&ui.empty_line());
+    }
+    output.push_str(&ui.content_line(&spinner_line));
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the ANSI escape sequence to update the spinner line in-place
+pub fn create_spinner_update(ui: Ui, frame_index: usize) -> String {
+    let spinner = spinner_frame(frame_index);
+    let line_content = format!("{} {}", spinner, ui.lang.catalog().waiting_for_authorization);
+    let line = ui.content_line(&line_content);
+
+    // Save cursor, move up 3 lines, write the line, restore cursor
+    format!("\x1B[s\x1B[3A\r{}\x1B[u", line.trim_end_matches("\r\n"))
+}
+
+/// Number of lines in the activation box without a QR code (for clearing)
+pub const ACTIVATION_BOX_LINES: usize = 14;
+
+/// Create the success box shown after tunnel activation
+pub fn create_success_box(ui: Ui, username: &str, tunnel_urls: &[(String, u32)]) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("✓").green(), text.activated_title);
+
+    // Truncate username if too long
+    let display_user = if username.len() > 30 {
+        format!("{}...", &username[..27])
+    } else {
+        username.to_string()
+    };
+    let welcome_styled = fill(text.welcome_back, &[("user", &style(&display_user).bold())]);
+
+    let disconnect_hint = format!("{}", style(text.disconnect_hint).dim());
+
+    let mut output = String::new();
+
+    // Clear entire screen and move cursor to top
+    output.push_str("\x1B[2J\x1B[H");
+
+    output.push_str("\r\n");
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&welcome_styled));
+    output.push_str(&ui.empty_line());
+    if tunnel_urls.len() > 1 {
+        output.push_str(&ui.content_line(text.tunnels_ready));
+    } else {
+        output.push_str(&ui.content_line(text.tunnel_ready));
+    }
+
+    for (subdomain, port) in tunnel_urls {
+        let full_url = get_tunnel_url(subdomain);
+        let mut url_line = format!(
+            "{} {}",
+            style("➜").cyan(),
+            style(&full_url).cyan().underlined()
+        );
+        // With several forwards, show which local port each URL serves
+        if tunnel_urls.len() > 1 {
+            url_line.push_str(&format!(" {}", style(format!("→ :{}", port)).dim()));
+        }
+        output.push_str(&ui.content_line(&url_line));
+    }
+
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&disconnect_hint));
+    output.push_str(&ui.content_line(&style(text.menu_keys).dim().to_string()));
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the error box shown when activation fails, replacing the
+/// `clear_lines` tall activation box above the cursor
+pub fn create_error_box(ui: Ui, reason: &str, code: Option<&str>, clear_lines: usize) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("✗").red(), text.activation_failed_title);
+
+    // Truncate reason if too long
+    let display_reason = truncate_str(reason, ui.width().saturating_sub(4), "...");
+    let error_line = format!("{} {}", style("✗").red(), display_reason);
+
+    let mut output = String::new();
+
+    // Move up and clear the old box
+    output.push_str(&format!("\x1B[{}A\x1B[0J", clear_lines));
+
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&error_line));
+    if let Some(code) = code {
+        output.push_str(&ui.error_code_line(code));
+    }
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.reconnect_to_retry));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.closing_soon));
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create an error box for port connection failure, replacing the
+/// `clear_lines` tall activation box above the cursor
+pub fn create_port_error_box(ui: Ui, port: u32, address: &str, code: &str, clear_lines: usize) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("✗").red(), text.connection_failed_title);
+
+    let error_line = format!(
+        "{} {}",
+        style("✗").red(),
+        fill(text.cannot_connect, &[("address", &address), ("port", &port)])
+    );
+
+    let mut output = String::new();
+
+    // Move up and clear the old box
+    output.push_str(&format!("\x1B[{}A\x1B[0J", clear_lines));
+
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&error_line));
+    output.push_str(&ui.error_code_line(code));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.start_local_service));
+    let hint = format!("  {} your-app --port {}", style("$").dim(), port);
+    output.push_str(&ui.content_line(&hint));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.closing_soon));
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create an error box for port mismatch (remote port != local port)
+pub fn create_port_mismatch_error_box(ui: Ui, remote_port: u32) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("✗").red(), text.port_mismatch_title);
+
+    let error_line = format!("{} {}", style("✗").red(), text.ports_must_match);
+
+    let mut output = String::new();
+
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&error_line));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.correct_usage));
+    let hint = format!(
+        "  {} ssh -R {}:localhost:{} ...",
+        style("$").dim(),
+        remote_port,
+        remote_port
+    );
+    output.push_str(&ui.content_line(&hint));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.closing_soon));
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the reconnect success box shown when a verified user reconnects
+pub fn create_reconnect_box(ui: Ui, username: &str, tunnel_urls: &[(String, u32)]) -> String {
+    create_success_box(ui, username, tunnel_urls)
+}
+
+/// Create the connected box shown when an already-verified user adds a new port
+pub fn create_connected_box(ui: Ui, username: &str, tunnel_urls: &[(String, u32)]) -> String {
+    create_success_box(ui, username, tunnel_urls)
+}
+
+/// Create the operator message box appended below the success box
+pub fn create_motd_box(ui: Ui, message: &str) -> String {
+    let title = format!("{} {}", style("📢").yellow(), ui.lang.catalog().operator_message_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    for line in wrap_text(message, ui.width()) {
+        output.push_str(&ui.content_line(&line));
+    }
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the box shown to sessions turned away in maintenance mode
+pub fn create_maintenance_box(ui: Ui, message: &str) -> String {
+    let title = format!("{} {}", style("🛠").yellow(), ui.lang.catalog().maintenance_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    for line in wrap_text(message, ui.width()) {
+        output.push_str(&ui.content_line(&line));
+    }
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// One of the user's connected tunnels, for the summary box
+#[derive(Debug, Clone)]
+pub struct ActiveTunnelSummary {
+    pub url: String,
+    /// Held by the session the box is shown in
+    pub this_session: bool,
+    /// Address the holding session connected from
+    pub client_ip: String,
+    pub connected_for: Duration,
+}
+
+/// Create the box listing the user's connected tunnels across all sessions,
+/// appended below the success box on reconnect
+pub fn create_active_tunnels_box(ui: Ui, tunnels: &[ActiveTunnelSummary]) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("⇄").cyan(), text.active_tunnels_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    for tunnel in tunnels {
+        let location = if tunnel.this_session {
+            text.this_session.to_string()
+        } else {
+            format!("{}, {}", tunnel.client_ip, format_duration(tunnel.connected_for))
+        };
+        let location = format!(" ({})", location);
+        let url_width = ui.width().saturating_sub(measure_text_width(&location) + 2);
+        let url = truncate_str(&tunnel.url, url_width, "…");
+        output.push_str(&ui.content_line(&format!(
+            "{} {}{}",
+            style("➜").cyan(),
+            url,
+            style(location).dim()
+        )));
+    }
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Why a forward needing `capability` was refused
+pub fn capability_denied_reason(capability: Capability) -> String {
+    fill(i18n::EN.not_enabled, &[("feature", &capability.description())])
+}
+
+/// Create the notice shown when a forward needs a capability the user's tier lacks
+pub fn create_capability_denied_box(ui: Ui, capability: Capability, port: u32) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("✗").red(), text.not_available_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&format!(
+        "{} {}",
+        style("✗").red(),
+        fill(text.not_enabled, &[("feature", &capability.description())])
+    )));
+    output.push_str(&ui.content_line(&fill(text.forward_refused, &[("port", &port)])));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the notice shown when a forward's bind address is refused
+pub fn create_forward_refused_box(ui: Ui, port: u32, reason: &str) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("✗").red(), text.address_not_allowed_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&format!("{} {}", style("✗").red(), text.bind_address_refused)));
+    for line in wrap_text(reason, ui.width()) {
+        output.push_str(&ui.content_line(&line));
+    }
+    output.push_str(&ui.content_line(&fill(text.forward_refused, &[("port", &port)])));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the notice shown when a tunnel was registered before its local service is up
+pub fn create_waiting_for_service_box(ui: Ui, port: u32) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("⏳").yellow(), text.waiting_for_service_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&fill(text.nothing_listening, &[("port", &port)])));
+    output.push_str(&ui.content_line(text.live_once_started));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Throughput of a transfer, e.g. "94.2 Mbit/s (8.0 MiB in 0.7s)"
+fn format_throughput(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64().max(0.001);
+    format!(
+        "{:.1} Mbit/s ({} in {:.1}s)",
+        bytes as f64 * 8.0 / secs / 1_000_000.0,
+        format_bytes(bytes),
+        secs
+    )
+}
+
+/// Create the `speedtest` report (transfers are bytes and elapsed time)
+pub fn create_speedtest_box(
+    ui: Ui,
+    rtt: Option<Duration>,
+    upload: Option<(u64, Duration)>,
+    download: Option<(u64, Duration)>,
+) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("⇅").cyan(), text.speed_test_title);
+    let rtt = rtt
+        .map(|rtt| format!("{:.1} ms", rtt.as_secs_f64() * 1000.0))
+        .unwrap_or_else(|| text.not_measured.to_string());
+    let upload = upload
+        .map(|(bytes, elapsed)| format_throughput(bytes, elapsed))
+        .unwrap_or_else(|| text.nothing_piped.to_string());
+    let download = download
+        .map(|(bytes, elapsed)| format_throughput(bytes, elapsed))
+        .unwrap_or_else(|| text.failed.to_string());
+    // Values line up after the longest label
+    let row = |label: &str, value: &str| format!("{}{}", pad_str(label, 13, Alignment::Left, None), value);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&row(text.round_trip, &rtt)));
+    output.push_str(&ui.content_line(&row(text.upload, &upload)));
+    output.push_str(&ui.content_line(&row(text.download, &download)));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Format a duration compactly (e.g. "45s", "12m", "3h 5m", "2d 4h")
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, (secs % 3600) / 60),
+        _ => format!("{}d {}h", secs / 86400, (secs % 86400) / 3600),
+    }
+}
+
+/// Format a latency percentile (e.g. "85ms", "1.2s"; "-" without samples)
+fn format_latency(ms: Option<u64>) -> String {
+    match ms {
+        None => "-".to_string(),
+        Some(ms @ 0..=999) => format!("{}ms", ms),
+        Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+    }
+}
+
+/// Format a byte count with binary units (e.g. "512 B", "1.5 KiB")
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format how long ago a point in time was (e.g. "3h 5m ago")
+fn format_ago(time: SystemTime) -> String {
+    let elapsed = SystemTime::now().duration_since(time).unwrap_or_default();
+    format!("{} ago", format_duration(elapsed))
+}
+
+/// Connection count, with the reasons other than a normal close (e.g. `12 (idle_timeout 2)`)
+fn format_close_reasons(reasons: &BTreeMap<String, u64>) -> String {
+    let total: u64 = reasons.values().sum();
+    let unusual: Vec<String> = reasons
+        .iter()
+        .filter(|(reason, _)| !matches!(reason.as_str(), "client_eof" | "upstream_eof"))
+        .map(|(reason, count)| format!("{} {}", reason, count))
+        .collect();
+    if unusual.is_empty() {
+        total.to_string()
+    } else {
+        format!("{} ({})", total, unusual.join(", "))
+    }
+}
+
+/// Render a user's past tunnels as a plain-text table (exec `history` output)
+pub fn create_history_table(entries: &[HistoryEntry]) -> String {
+    if entries.is_empty() {
+        return "No past tunnels.\n".to_string();
+    }
+
+    let rows: Vec<[String; 5]> = entries
+        .iter()
+        .map(|entry| {
+            let duration = entry
+                .ended_at
+                .duration_since(entry.started_at)
+                .unwrap_or_default();
+            [
+                entry.subdomain.clone(),
+                format_duration(duration),
+                format_bytes(entry.bytes_in + entry.bytes_out),
+                format_close_reasons(&entry.close_reasons),
+                entry
+                    .last_active
+                    .map(format_ago)
+                    .unwrap_or_else(|| "never".to_string()),
+            ]
+        })
+        .collect();
+
+    let headers = ["SUBDOMAIN", "DURATION", "TRAFFIC", "CONNECTIONS", "LAST ACTIVE"];
+    let mut widths = headers.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(measure_text_width(cell));
+        }
+    }
+
+    let format_row = |cells: [&str; 5]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| pad_str(cell, width, Alignment::Left, None).to_string())
+            .collect();
+        format!("{}\n", line.join("  ").trim_end())
+    };
+
+    let mut output = format_row(headers);
+    for row in &rows {
+        output.push_str(&format_row([&row[0], &row[1], &row[2], &row[3], &row[4]]));
+    }
+    output
+}
+
+/// Create the notice sent before an idle tunnel is disconnected
+pub fn create_idle_disconnect_box(ui: Ui, idle: Duration) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("⏸").yellow(), text.idle_title);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&fill(text.idle_for, &[("duration", &format_duration(idle))])));
+    output.push_str(&ui.content_line(text.closing_idle));
+    output.push_str(&ui.content_line(text.reconnect_same_url));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the box shown to a session whose tunnel a new connection took over
+pub fn create_taken_over_box(ui: Ui, subdomain: &str, by: &str) -> String {
+    let text = ui.lang.catalog();
+    let title = format!("{} {}", style("↻").yellow(), text.taken_over_title);
+    let url = get_tunnel_url(subdomain);
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    for line in wrap_text(&fill(text.taken_over_by, &[("url", &url), ("ip", &by)]), ui.width()) {
+        output.push_str(&ui.content_line(&line));
+    }
+    output.push_str(&ui.content_line(text.closing_session));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the notice shown when a session took a tunnel over from the
+/// user's earlier connection
+pub fn create_took_over_notice(lang: Lang, subdomain: &str, from: &str) -> String {
+    let url = style(get_tunnel_url(subdomain)).cyan();
+    let notice = fill(lang.catalog().took_over, &[("url", &url), ("ip", &from)]);
+    format!("\r\n{} {}\r\n", style("↻").yellow(), notice)
+}
+
+/// URL entering a secret-path tunnel
+fn secret_url(subdomain: &str, token: &str) -> String {
+    format!("{}/{}/", get_tunnel_url(subdomain), token)
+}
+
+/// Create the notice giving a tunnel's secret URL
+pub fn create_secret_path_notice(lang: Lang, subdomain: &str, token: &str) -> String {
+    let url = style(secret_url(subdomain, token)).cyan();
+    let notice = fill(lang.catalog().secret_path, &[("url", &url), ("header", &TOKEN_HEADER)]);
+    format!("\r\n{} {}\r\n", style("🔒").yellow(), notice)
+}
+
+/// Create the box shown when a tunnel's 5xx alert fires or resolves
+pub fn create_status_alert_box(
+    ui: Ui,
+    subdomain: &str,
+    firing: bool,
+    error_percent: f64,
+    window: Duration,
+) -> String {
+    let text = ui.lang.catalog();
+    let title = if firing {
+        format!("{} {}", style("⚠").red(), text.server_errors_title)
+    } else {
+        format!("{} {}", style("✓").green(), text.server_errors_resolved_title)
+    };
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&fill(
+        text.error_share,
+        &[("percent", &format!("{:.1}", error_percent)), ("subdomain", &subdomain)],
+    )));
+    output.push_str(&ui.content_line(&fill(text.over_window, &[("window", &format_duration(window))])));
+    if firing {
+        output.push_str(&ui.content_line(text.check_logs));
+    }
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the notice shown when a tunnel's local service stops or resumes
+/// answering health checks
+pub fn create_local_service_notice(lang: Lang, subdomain: &str, port: u32, degraded: bool) -> String {
+    let text = lang.catalog();
+    let url = style(get_tunnel_url(subdomain)).cyan();
+    let (icon, template) = if degraded {
+        (style("⚠").yellow(), text.service_degraded)
+    } else {
+        (style("✓").green(), text.service_recovered)
+    };
+    format!("\r\n{} {}\r\n", icon, fill(template, &[("port", &port), ("url", &url)]))
+}
+
+/// Create the notice shown when a dedicated TCP or UDP port is served
+pub fn create_dedicated_port_notice(lang: Lang, transport: Transport, port: u32) -> String {
+    let endpoint = style(get_port_endpoint(port)).cyan();
+    let template = match transport {
+        Transport::Tcp => lang.catalog().tcp_port_open,
+        Transport::Udp => lang.catalog().udp_port_open,
+    };
+    format!("\r\n{} {}\r\n", style("✓").green(), fill(template, &[("endpoint", &endpoint)]))
+}
+
+/// Create the status line shown below the success box while local services
+/// of the session's tunnels don't answer (ports and since when)
+pub fn create_degraded_status_line(lang: Lang, degraded: &[(u32, SystemTime)]) -> String {
+    let text = lang.catalog();
+    let now = SystemTime::now();
+    let ports: Vec<String> = degraded
+        .iter()
+        .map(|(port, since)| {
+            let down_for = now.duration_since(*since).unwrap_or_default();
+            fill(text.port_down_for, &[("port", port), ("duration", &format_duration(down_for))])
+        })
+        .collect();
+    format!(
+        "{} {}\r\n",
+        style("⚠").yellow(),
+        fill(text.not_answering, &[("ports", &ports.join(text.list_separator))])
+    )
+}
+
+/// Create the notice shown in a session whose tunnel was renamed via exec
+pub fn create_renamed_notice(lang: Lang, old_subdomain: &str, new_subdomain: &str) -> String {
+    let url = style(get_tunnel_url(new_subdomain)).cyan().underlined();
+    format!(
+        "\r\n{} {}\r\n",
+        style("✓").green(),
+        fill(lang.catalog().renamed, &[("old", &old_subdomain), ("url", &url)])
+    )
+}
+
+/// Lines of the live view besides its request rows
+pub const LIVE_VIEW_FRAME_LINES: usize = 6;
+
+/// Running totals in the live view's header
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LiveTotals {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Request durations of the session's tunnels
+    pub latency: LatencySummary,
+}
+
+/// One request row of the live view
+fn live_request_line(ui: Ui, event: &RequestEvent, show_subdomain: bool) -> String {
+    let status = match event.status {
+        Some(code @ 200..=299) => style(code.to_string()).green(),
+        Some(code @ 300..=399) => style(code.to_string()).cyan(),
+        Some(code @ 400..=499) => style(code.to_string()).yellow(),
+        Some(code) => style(code.to_string()).red(),
+        None => style("-".to_string()).dim(),
+    };
+    let latency = format!("{:>6}ms", event.duration_ms);
+    let target = if show_subdomain {
+        format!("{}:{}", event.subdomain, event.path)
+    } else {
+        event.path.clone()
+    };
+    // method (7) + status (3) + latency (8) + gaps (5)
+    let target = truncate_str(&target, ui.width().saturating_sub(23), "…");
+    let line = format!(
+        "{} {}  {}  {}",
+        pad_str(&event.method, 7, Alignment::Left, Some("…")),
+        pad_str(&status.to_string(), 3, Alignment::Right, None),
+        style(latency).dim(),
+        target
+    );
+    ui.content_line(&line)
+}
+
+/// Create the live request panel shown below the success box. `recent` is
+/// newest first; `rows` request lines are always drawn so the height is fixed.
+pub fn create_live_view(
+    ui: Ui,
+    recent: &[RequestEvent],
+    totals: &LiveTotals,
+    rows: usize,
+    show_subdomain: bool,
+) -> String {
+    let text = ui.lang.catalog();
+    let title = format!(
+        "{} LIVE  {}  ↓ {}  ↑ {}",
+        style("●").red(),
+        fill(text.live_requests, &[("requests", &totals.requests)]),
+        format_bytes(totals.bytes_in),
+        format_bytes(totals.bytes_out)
+    );
+    let latency = fill(
+        text.live_latency,
+        &[
+            ("p50", &format_latency(totals.latency.p50_ms)),
+            ("p95", &format_latency(totals.latency.p95_ms)),
+            ("p99", &format_latency(totals.latency.p99_ms)),
+        ],
+    );
+
+    let mut output = String::new();
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.content_line(&title));
+    output.push_str(&ui.content_line(&style(latency).dim().to_string()));
+    output.push_str(&ui.middle_border());
+    for row in 0..rows {
+        match recent.get(row) {
+            Some(event) => output.push_str(&live_request_line(ui, event, show_subdomain)),
+            None if row == 0 => output.push_str(&ui.content_line(&style(text.waiting_for_requests).dim().to_string())),
+            None => output.push_str(&ui.empty_line()),
+        }
+    }
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+/// Create the OSC 52 sequence putting `text` on the clipboard of terminals
+/// that support it; others ignore it
+pub fn create_clipboard_copy(text: &str) -> String {
+    format!("\x1B]52;c;{}\x07", STANDARD.encode(text))
+}
+
+/// The tunnels' URLs as copied to the clipboard, one per line
+pub fn clipboard_urls(tunnel_urls: &[(String, u32)]) -> String {
+    let urls: Vec<String> = tunnel_urls.iter().map(|(subdomain, _)| get_tunnel_url(subdomain)).collect();
+    urls.join("\n")
+}
+
+/// Create a hint message for ESC key press
+pub fn create_esc_hint(lang: Lang) -> String {
+    format!("\r\n{} {}\r\n", style("⚠").yellow(), lang.catalog().esc_again)
+}
+
+/// Clear the ESC hint (move up and clear line)
+pub fn clear_esc_hint() -> String {
+    "\x1B[2A\x1B[0J".to_string()
+}
+
+/// Create an error box for a subdomain that is taken or reserved
+pub fn create_subdomain_taken_error_box(ui: Ui, error: &TunnelError, port: u32) -> String {
+    let text = ui.lang.catalog();
+    let (heading, subdomain, problem) = match error {
+        TunnelError::SubdomainReserved(subdomain) => {
+            (text.subdomain_reserved_title, subdomain.as_str(), text.subdomain_reserved)
+        }
+        TunnelError::SubdomainTaken(subdomain) => {
+            (text.subdomain_taken_title, subdomain.as_str(), text.subdomain_taken)
+        }
+        _ => (text.subdomain_unavailable_title, "", text.subdomain_unavailable),
+    };
+    let title = format!("{} {}", style("✗").red(), heading);
+
+    let error_line = format!(
+        "{} {}",
+        style("✗").red(),
+        fill(problem, &[("subdomain", &style(subdomain).yellow().bold())])
+    );
+
+    let mut output = String::new();
+
+    // Clear entire screen and move cursor to top
+    output.push_str("\x1B[2J\x1B[H");
+    output.push_str("\r\n");
+
+    output.push_str(&ui.top_border());
+    output.push_str(&ui.centered_line(&title));
+    output.push_str(&ui.middle_border());
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(&error_line));
+    output.push_str(&ui.error_code_line(error.code()));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.try_other_subdomain));
+    let hint = format!(
+        "  {} ssh -R {}:localhost:{} {}@...",
+        style("$").dim(),
+        port,
+        port,
+        style("<your-subdomain>").cyan()
+    );
+    output.push_str(&ui.content_line(&hint));
+    output.push_str(&ui.empty_line());
+    output.push_str(&ui.content_line(text.closing_soon));
+    output.push_str(&ui.bottom_border());
+    output.push_str("\r\n");
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spinner_frame() {
+        assert_eq!(spinner_frame(0), "⠋");
+        assert_eq!(spinner_frame(10), "⠋"); // wraps around
+    }
+
+    #[test]
+    fn test_session_event_render() {
+        let activation = SessionEvent::Activation { code: "ABC123", url: "http://example.com/activate" };
+        assert_eq!(activation.render(OutputMode::Tty), "");
+        assert_eq!(
+            activation.render(OutputMode::Plain),
+            "activate: http://example.com/activate (code ABC123)\n"
+        );
+
+        let json = SessionEvent::Refused { port: 3000, reason: "no" }.render(OutputMode::Json);
+        assert!(json.ends_with('\n') && !json.trim_end().contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["event"], "refused");
+        assert_eq!(value["port"], 3000);
+
+        let error = SessionEvent::Error { reason: "Activation code expired", code: Some("verification_timeout") };
+        assert_eq!(error.render(OutputMode::Plain), "error: Activation code expired [verification_timeout]\n");
+
+        let idle = SessionEvent::IdleDisconnect { idle: Duration::from_secs(600) };
+        assert_eq!(idle.render(OutputMode::Plain), "closing: no requests for 10m\n");
+        let alert = SessionEvent::StatusAlert {
+            subdomain: "myapp",
+            firing: true,
+            error_percent: 12.5,
+            window: Duration::from_secs(300),
+        };
+        assert_eq!(alert.render(OutputMode::Plain), "alert: myapp firing: 12.5% 5xx over 5m\n");
+        let health = SessionEvent::LocalService { subdomain: "myapp", port: 3000, degraded: true };
+        let value: serde_json::Value = serde_json::from_str(&health.render(OutputMode::Json)).unwrap();
+        assert_eq!((value["event"].as_str(), value["state"].as_str()), (Some("local_service"), Some("degraded")));
+        assert_eq!(OutputMode::parse("JSON"), Some(OutputMode::Json));
+        assert_eq!(OutputMode::parse("xml"), None);
+    }
+
+    #[test]
+    fn test_speedtest_box() {
+        let report = create_speedtest_box(
+            Ui::default(),
+            Some(Duration::from_micros(12_340)),
+            None,
+            Some((8 * 1024 * 1024, Duration::from_secs(2))),
+        );
+        assert!(report.contains("12.3 ms"));
+        assert!(report.contains("nothing piped into ssh"));
+        assert!(report.contains("33.6 Mbit/s (8.0 MiB in 2.0s)"));
+    }
+
+    #[test]
+    fn test_activation_box_contains_code() {
+        let box_output = create_activation_box(Ui::default(), "ABC123", "http://example.com/activate");
+        assert!(box_output.contains("ABC123"));
+        assert!(box_output.contains("example.com"));
+    }
+
+    #[test]
+    fn test_activation_box_qr_code() {
+        let url = "https://exlo.example.com/activate?code=ABC123";
+        let plain = create_activation_box(Ui::default(), "ABC123", url);
+        assert!(!plain.contains('█'));
+        assert_eq!(activation_box_lines(Ui::default(), url), ACTIVATION_BOX_LINES);
+
+        // Too small a terminal keeps the plain box
+        assert_eq!(create_activation_box(Ui::new(Lang::En, Some((80, 24))), "ABC123", url), plain);
+
+        let with_qr = create_activation_box(Ui::new(Lang::En, Some((80, 60))), "ABC123", url);
+        assert!(with_qr.contains('█'));
+        let extra = activation_box_lines(Ui::new(Lang::En, Some((80, 60))), url) - ACTIVATION_BOX_LINES;
+        assert_eq!(with_qr.matches("\r\n").count() - plain.matches("\r\n").count(), extra);
+        // The spinner stays three lines above the end for in-place updates
+        assert!(with_qr.ends_with(&format!(
+            "{}{}\r\n",
+            Ui::default().content_line(&format!("{} Waiting for authorization...", spinner_frame(0))),
+            Ui::default().bottom_border()
+        )));
+    }
+
+    #[test]
+    fn test_clipboard_copy() {
+        let copy = create_clipboard_copy("https://myapp.example.com");
+        assert_eq!(copy, "\x1B]52;c;aHR0cHM6Ly9teWFwcC5leGFtcGxlLmNvbQ==\x07");
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        let lines = wrap_text("one two three four", 9);
+        assert_eq!(lines, vec!["one two", "three", "four"]);
+        assert_eq!(wrap_text("first\nsecond", 20), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_waiting_for_service_box() {
+        let box_output = create_waiting_for_service_box(Ui::default(), 5173);
+        assert!(box_output.contains("WAITING FOR LOCAL SERVICE"));
+        assert!(box_output.contains("port 5173"));
+
+        // Wide characters are padded by display width, so the box stays square
+        let box_output = create_waiting_for_service_box(Ui::new(Lang::Zh, None), 5173);
+        assert!(box_output.contains("等待本地服务"));
+        for line in box_output.split("\r\n").filter(|l| l.starts_with('║')) {
+            assert_eq!(measure_text_width(line), BOX_WIDTH + 4);
+        }
+    }
+
+    #[test]
+    fn test_format_helpers() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 5 * 60)), "3h 5m");
+        assert_eq!(format_duration(Duration::from_secs(2 * 86400 + 4 * 3600)), "2d 4h");
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+    }
+
+    #[test]
+    fn test_history_table() {
+        assert_eq!(create_history_table(&[]), "No past tunnels.\n");
+
+        let entry = HistoryEntry {
+            subdomain: "myapp".to_string(),
+            started_at: SystemTime::UNIX_EPOCH,
+            ended_at: SystemTime::UNIX_EPOCH + Duration::from_secs(600),
+            bytes_in: 1024,
+            bytes_out: 1024,
+            last_active: None,
+            close_reasons: BTreeMap::from([("upstream_eof".to_string(), 3), ("idle_timeout".to_string(), 1)]),
+        };
+        let table = create_history_table(&[entry]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("SUBDOMAIN"));
+        assert!(lines[1].starts_with("myapp "));
+        assert!(lines[1].contains("10m"));
+        assert!(lines[1].contains("2.0 KiB"));
+        assert!(lines[1].contains("4 (idle_timeout 1)"));
+        assert!(lines[1].ends_with("never"));
+    }
+
+    #[test]
+    fn test_live_view() {
+        let event = RequestEvent {
+            subdomain: "app".to_string(),
+            method: "POST".to_string(),
+            path: format!("/api/{}", "x".repeat(80)),
+            status: Some(201),
+            duration_ms: 42,
+            bytes_in: 300,
+            bytes_out: 2048,
+        };
+        let totals = LiveTotals {
+            requests: 1,
+            bytes_in: 300,
+            bytes_out: 2048,
+            latency: LatencySummary {
+                count: 1,
+                p50_ms: Some(50),
+                p95_ms: Some(50),
+                p99_ms: Some(2500),
+                max_ms: Some(2500),
+            },
+        };
+        let view = create_live_view(Ui::default(), &[event], &totals, 4, true);
+        assert!(view.contains("1 requests"));
+        assert!(view.contains("2.0 KiB"));
+        assert!(view.contains("p95 50ms  p99 2.5s"));
+        assert!(view.contains("app:/api/"));
+        assert!(view.contains("42ms"));
+        assert_eq!(view.matches("\r\n").count(), LIVE_VIEW_FRAME_LINES + 4);
+        // Long paths are cut to keep every row inside the box
+        for line in view.split("\r\n").filter(|l| l.starts_with('║')) {
+            assert_eq!(measure_text_width(line), BOX_WIDTH + 4);
+        }
+
+        let empty = create_live_view(Ui::default(), &[], &LiveTotals::default(), 4, false);
+        assert!(empty.contains("Waiting for requests"));
+        assert!(empty.contains("p50 -"));
+        assert_eq!(empty.matches("\r\n").count(), LIVE_VIEW_FRAME_LINES + 4);
+    }
+
+    #[test]
+    fn test_active_tunnels_box() {
+        let tunnels = [
+            ActiveTunnelSummary {
+                url: "myapp.localhost".to_string(),
+                this_session: true,
+                client_ip: "203.0.113.5".to_string(),
+                connected_for: Duration::from_secs(30),
+            },
+            ActiveTunnelSummary {
+                url: format!("{}.localhost", "a".repeat(80)),
+                this_session: false,
+                client_ip: "198.51.100.7".to_string(),
+                connected_for: Duration::from_secs(7200),
+            },
+        ];
+        let summary = create_active_tunnels_box(Ui::default(), &tunnels);
+        assert!(summary.contains("myapp.localhost"));
+        assert!(summary.contains("this session"));
+        assert!(summary.contains("198.51.100.7, 2h"));
+        for line in summary.split("\r\n").filter(|l| l.starts_with('║')) {
+            assert_eq!(measure_text_width(line), BOX_WIDTH + 4);
+        }
+    }
+
+    #[test]
+    fn test_box_width_consistency() {
+        // All border lines should have the same length
+        let ui = Ui::default();
+        let top = ui.top_border();
+        let mid = ui.middle_border();
+        let bot = ui.bottom_border();
+
+        // Remove \r\n for comparison
+        let top_len = measure_text_width(top.trim());
+        let mid_len = measure_text_width(mid.trim());
+        let bot_len = measure_text_width(bot.trim());
+
+        assert_eq!(top_len, mid_len);
+        assert_eq!(mid_len, bot_len);
+    }
+
+    #[test]
+    fn test_box_fits_terminal() {
+        let lines_of = |ui: Ui| {
+            let output = create_motd_box(ui, &"Scheduled maintenance tonight. ".repeat(4));
+            let lines: Vec<usize> = output.split("\r\n").map(measure_text_width).collect();
+            lines[..lines.len() - 2].to_vec()
+        };
+
+        // Narrower than the default box: every line is as wide as the terminal
+        assert!(lines_of(Ui::new(Lang::En, Some((60, 24)))).iter().all(|&width| width == 60));
+        // Wide terminals keep the default box
+        assert_eq!(lines_of(Ui::new(Lang::En, Some((200, 24)))), lines_of(Ui::default()));
+
+        // Under 60 columns the box loses its side borders and leaves the last column free
+        let ui = Ui::new(Lang::En, Some((40, 24)));
+        let minimal = create_activation_box(ui, "ABC123", "https://exlo.example.com/activate?code=ABC123");
+        assert!(!minimal.contains('║'));
+        assert!(minimal.split("\r\n").all(|line| measure_text_width(line) <= 39));
+        assert!(create_spinner_update(ui, 1).contains(&format!("{} Waiting", spinner_frame(1))));
+    }
+}
@@ -0,0 +1,80 @@
+import { createHash, randomBytes } from 'node:crypto'
+import { and, db, eq } from '@exlo/db'
+import { apiTokens } from '@exlo/db/schema/api-token'
+import { createServerFn } from '@tanstack/react-start'
+import { authMiddleware } from '@/middleware/auth'
+
+// Hash stored for a token (see /api/internal/verify-token)
+function hashToken(token: string): string {
+  return createHash('sha256').update(token).digest('hex')
+}
+
+// Create an API token for SSH clients; the token is only returned here
+export const createApiToken = createServerFn({ method: 'POST' })
+  .middleware([authMiddleware])
+  .inputValidator((data: { name: string; expiresInDays?: number }) => data)
+  .handler(async ({ context, data }) => {
+    const { name, expiresInDays } = data
+
+    if (!context.session?.user) {
+      throw new Error('Not authenticated')
+    }
+
+    const token = randomBytes(32).toString('hex')
+    let expiresAt: Date | null = null
+    if (expiresInDays) {
+      expiresAt = new Date()
+      expiresAt.setDate(expiresAt.getDate() + expiresInDays)
+    }
+
+    await db.insert(apiTokens).values({
+      userId: context.session.user.id,
+      name,
+      tokenHash: hashToken(token),
+      expiresAt
+    })
+
+    return {
+      success: true,
+      token,
+      expiresAt: expiresAt?.toISOString() ?? null
+    }
+  })
+
+// List the current user's API tokens (without the tokens)
+export const listApiTokens = createServerFn({ method: 'GET' })
+  .middleware([authMiddleware])
+  .handler(async ({ context }) => {
+    const userId = context.session?.user?.id
+    if (!userId) {
+      throw new Error('Not authenticated')
+    }
+
+    const tokens = await db.query.apiTokens.findMany({
+      where: eq(apiTokens.userId, userId),
+      orderBy: (apiTokens, { desc }) => [desc(apiTokens.createdAt)]
+    })
+
+    return tokens.map((token) => ({
+      id: token.id,
+      name: token.name,
+      expiresAt: token.expiresAt?.toISOString() ?? null,
+      lastUsedAt: token.lastUsedAt?.toISOString() ?? null,
+      createdAt: token.createdAt.toISOString()
+    }))
+  })
+
+// Revoke one of the current user's API tokens
+export const deleteApiToken = createServerFn({ method: 'POST' })
+  .middleware([authMiddleware])
+  .inputValidator((data: { id: string }) => data)
+  .handler(async ({ context, data }) => {
+    const userId = context.session?.user?.id
+    if (!userId) {
+      throw new Error('Not authenticated')
+    }
+
+    await db.delete(apiTokens).where(and(eq(apiTokens.id, data.id), eq(apiTokens.userId, userId)))
+
+    return { success: true }
+  })
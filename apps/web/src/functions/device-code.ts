@@ -1,8 +1,45 @@
 import { db, eq } from '@exlo/db'
 import { activationCodes } from '@exlo/db/schema/index'
 import { createServerFn } from '@tanstack/react-start'
+import { env } from '@/lib/env'
 import { authMiddleware } from '@/middleware/auth'
 
+// Report a code's status to the tunnel server, waking the waiting SSH session
+// when it runs with VERIFICATION_MODE=callback (in poll mode nobody waits: 404)
+async function notifyTunnelServer(code: string) {
+  const result = await db.query.activationCodes.findFirst({
+    where: eq(activationCodes.code, code),
+    with: {
+      user: true
+    }
+  })
+  if (!result) {
+    return
+  }
+
+  try {
+    const response = await fetch(`${env.TUNNEL_MANAGEMENT_API_URL}/activations`, {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+        'X-Internal-Secret': env.INTERNAL_API_SECRET
+      },
+      body: JSON.stringify({
+        code,
+        status: result.status,
+        userId: result.userId,
+        userName: result.user?.name,
+        tier: result.user?.role
+      })
+    })
+    if (!response.ok && response.status !== 404) {
+      console.error(`Failed to notify tunnel server: ${response.statusText}`)
+    }
+  } catch (error) {
+    console.error('Failed to notify tunnel server:', error)
+  }
+}
+
 // Server function to authorize a device code
 export const authorizeCode = createServerFn({ method: 'POST' })
   .middleware([authMiddleware])
@@ -30,6 +67,7 @@ export const authorizeCode = createServerFn({ method: 'POST' })
     if (new Date() > existing.expiresAt) {
       // Update to expired
       await db.update(activationCodes).set({ status: 'expired' }).where(eq(activationCodes.code, code))
+      await notifyTunnelServer(code)
       throw new Error('Code has expired')
     }
 
@@ -41,6 +79,7 @@ export const authorizeCode = createServerFn({ method: 'POST' })
         userId: context.session.user.id
       })
       .where(eq(activationCodes.code, code))
+    await notifyTunnelServer(code)
 
     return { success: true }
   })
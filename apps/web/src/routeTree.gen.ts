@@ -16,8 +16,10 @@ import { Route as LoginRouteImport } from './routes/login'
 import { Route as JoinRouteImport } from './routes/join'
 import { Route as ActivateRouteImport } from './routes/activate'
 import { Route as IndexRouteImport } from './routes/index'
+import { Route as ApiInternalVerifyTokenRouteImport } from './routes/api/internal/verify-token'
 import { Route as ApiInternalUnregisterTunnelRouteImport } from './routes/api/internal/unregister-tunnel'
 import { Route as ApiInternalRegisterTunnelRouteImport } from './routes/api/internal/register-tunnel'
+import { Route as ApiInternalListTunnelsRouteImport } from './routes/api/internal/list-tunnels'
 import { Route as ApiInternalGenerateCodeRouteImport } from './routes/api/internal/generate-code'
 import { Route as ApiInternalCheckCodeRouteImport } from './routes/api/internal/check-code'
 import { Route as ApiAuthSplatRouteImport } from './routes/api/auth/$'
@@ -57,6 +59,11 @@ const IndexRoute = IndexRouteImport.update({
   path: '/',
   getParentRoute: () => rootRouteImport,
 } as any)
+const ApiInternalVerifyTokenRoute = ApiInternalVerifyTokenRouteImport.update({
+  id: '/api/internal/verify-token',
+  path: '/api/internal/verify-token',
+  getParentRoute: () => rootRouteImport,
+} as any)
 const ApiInternalUnregisterTunnelRoute =
   ApiInternalUnregisterTunnelRouteImport.update({
     id: '/api/internal/unregister-tunnel',
@@ -69,6 +76,11 @@ const ApiInternalRegisterTunnelRoute =
     path: '/api/internal/register-tunnel',
     getParentRoute: () => rootRouteImport,
   } as any)
+const ApiInternalListTunnelsRoute = ApiInternalListTunnelsRouteImport.update({
+  id: '/api/internal/list-tunnels',
+  path: '/api/internal/list-tunnels',
+  getParentRoute: () => rootRouteImport,
+} as any)
 const ApiInternalGenerateCodeRoute = ApiInternalGenerateCodeRouteImport.update({
   id: '/api/internal/generate-code',
   path: '/api/internal/generate-code',
@@ -96,8 +108,10 @@ export interface FileRoutesByFullPath {
   '/api/auth/$': typeof ApiAuthSplatRoute
   '/api/internal/check-code': typeof ApiInternalCheckCodeRoute
   '/api/internal/generate-code': typeof ApiInternalGenerateCodeRoute
+  '/api/internal/list-tunnels': typeof ApiInternalListTunnelsRoute
   '/api/internal/register-tunnel': typeof ApiInternalRegisterTunnelRoute
   '/api/internal/unregister-tunnel': typeof ApiInternalUnregisterTunnelRoute
+  '/api/internal/verify-token': typeof ApiInternalVerifyTokenRoute
 }
 export interface FileRoutesByTo {
   '/': typeof IndexRoute
@@ -110,8 +124,10 @@ export interface FileRoutesByTo {
   '/api/auth/$': typeof ApiAuthSplatRoute
   '/api/internal/check-code': typeof ApiInternalCheckCodeRoute
   '/api/internal/generate-code': typeof ApiInternalGenerateCodeRoute
+  '/api/internal/list-tunnels': typeof ApiInternalListTunnelsRoute
   '/api/internal/register-tunnel': typeof ApiInternalRegisterTunnelRoute
   '/api/internal/unregister-tunnel': typeof ApiInternalUnregisterTunnelRoute
+  '/api/internal/verify-token': typeof ApiInternalVerifyTokenRoute
 }
 export interface FileRoutesById {
   __root__: typeof rootRouteImport
@@ -125,8 +141,10 @@ export interface FileRoutesById {
   '/api/auth/$': typeof ApiAuthSplatRoute
   '/api/internal/check-code': typeof ApiInternalCheckCodeRoute
   '/api/internal/generate-code': typeof ApiInternalGenerateCodeRoute
+  '/api/internal/list-tunnels': typeof ApiInternalListTunnelsRoute
   '/api/internal/register-tunnel': typeof ApiInternalRegisterTunnelRoute
   '/api/internal/unregister-tunnel': typeof ApiInternalUnregisterTunnelRoute
+  '/api/internal/verify-token': typeof ApiInternalVerifyTokenRoute
 }
 export interface FileRouteTypes {
   fileRoutesByFullPath: FileRoutesByFullPath
@@ -141,8 +159,10 @@ export interface FileRouteTypes {
     | '/api/auth/$'
     | '/api/internal/check-code'
     | '/api/internal/generate-code'
+    | '/api/internal/list-tunnels'
     | '/api/internal/register-tunnel'
     | '/api/internal/unregister-tunnel'
+    | '/api/internal/verify-token'
   fileRoutesByTo: FileRoutesByTo
   to:
     | '/'
@@ -155,8 +175,10 @@ export interface FileRouteTypes {
     | '/api/auth/$'
     | '/api/internal/check-code'
     | '/api/internal/generate-code'
+    | '/api/internal/list-tunnels'
     | '/api/internal/register-tunnel'
     | '/api/internal/unregister-tunnel'
+    | '/api/internal/verify-token'
   id:
     | '__root__'
     | '/'
@@ -169,8 +191,10 @@ export interface FileRouteTypes {
     | '/api/auth/$'
     | '/api/internal/check-code'
     | '/api/internal/generate-code'
+    | '/api/internal/list-tunnels'
     | '/api/internal/register-tunnel'
     | '/api/internal/unregister-tunnel'
+    | '/api/internal/verify-token'
   fileRoutesById: FileRoutesById
 }
 export interface RootRouteChildren {
@@ -184,8 +208,10 @@ export interface RootRouteChildren {
   ApiAuthSplatRoute: typeof ApiAuthSplatRoute
   ApiInternalCheckCodeRoute: typeof ApiInternalCheckCodeRoute
   ApiInternalGenerateCodeRoute: typeof ApiInternalGenerateCodeRoute
+  ApiInternalListTunnelsRoute: typeof ApiInternalListTunnelsRoute
   ApiInternalRegisterTunnelRoute: typeof ApiInternalRegisterTunnelRoute
   ApiInternalUnregisterTunnelRoute: typeof ApiInternalUnregisterTunnelRoute
+  ApiInternalVerifyTokenRoute: typeof ApiInternalVerifyTokenRoute
 }
 
 declare module '@tanstack/react-router' {
@@ -239,6 +265,13 @@ declare module '@tanstack/react-router' {
       preLoaderRoute: typeof IndexRouteImport
       parentRoute: typeof rootRouteImport
     }
+    '/api/internal/verify-token': {
+      id: '/api/internal/verify-token'
+      path: '/api/internal/verify-token'
+      fullPath: '/api/internal/verify-token'
+      preLoaderRoute: typeof ApiInternalVerifyTokenRouteImport
+      parentRoute: typeof rootRouteImport
+    }
     '/api/internal/unregister-tunnel': {
       id: '/api/internal/unregister-tunnel'
       path: '/api/internal/unregister-tunnel'
@@ -253,6 +286,13 @@ declare module '@tanstack/react-router' {
       preLoaderRoute: typeof ApiInternalRegisterTunnelRouteImport
       parentRoute: typeof rootRouteImport
     }
+    '/api/internal/list-tunnels': {
+      id: '/api/internal/list-tunnels'
+      path: '/api/internal/list-tunnels'
+      fullPath: '/api/internal/list-tunnels'
+      preLoaderRoute: typeof ApiInternalListTunnelsRouteImport
+      parentRoute: typeof rootRouteImport
+    }
     '/api/internal/generate-code': {
       id: '/api/internal/generate-code'
       path: '/api/internal/generate-code'
@@ -288,8 +328,10 @@ const rootRouteChildren: RootRouteChildren = {
   ApiAuthSplatRoute: ApiAuthSplatRoute,
   ApiInternalCheckCodeRoute: ApiInternalCheckCodeRoute,
   ApiInternalGenerateCodeRoute: ApiInternalGenerateCodeRoute,
+  ApiInternalListTunnelsRoute: ApiInternalListTunnelsRoute,
   ApiInternalRegisterTunnelRoute: ApiInternalRegisterTunnelRoute,
   ApiInternalUnregisterTunnelRoute: ApiInternalUnregisterTunnelRoute,
+  ApiInternalVerifyTokenRoute: ApiInternalVerifyTokenRoute,
 }
 export const routeTree = rootRouteImport
   ._addFileChildren(rootRouteChildren)
@@ -51,6 +51,7 @@ export const Route = createFileRoute('/api/internal/check-code')({
               status: result.status,
               userId: result.userId,
               userName: result.user?.name,
+              tier: result.user?.role,
               sessionId: result.sessionId
             }),
             {
@@ -0,0 +1,47 @@
+import { db } from '@exlo/db'
+import { tunnels } from '@exlo/db/schema/index'
+import { createFileRoute } from '@tanstack/react-router'
+import { env } from '@/lib/env'
+
+export const Route = createFileRoute('/api/internal/list-tunnels')({
+  server: {
+    handlers: {
+      GET: async ({ request }) => {
+        const secret = request.headers.get('X-Internal-Secret')
+        if (secret !== env.INTERNAL_API_SECRET) {
+          return new Response(JSON.stringify({ error: 'Unauthorized' }), {
+            status: 401,
+            headers: { 'Content-Type': 'application/json' }
+          })
+        }
+
+        try {
+          const result = await db
+            .select({
+              subdomain: tunnels.subdomain,
+              sessionId: tunnels.sessionId,
+              userId: tunnels.userId,
+              createdAt: tunnels.createdAt
+            })
+            .from(tunnels)
+
+          return new Response(JSON.stringify({ tunnels: result }), {
+            headers: { 'Content-Type': 'application/json' }
+          })
+        } catch (error) {
+          console.error('Failed to list tunnels:', error)
+          return new Response(
+            JSON.stringify({
+              error: 'Failed to list tunnels',
+              details: error instanceof Error ? error.message : String(error)
+            }),
+            {
+              status: 500,
+              headers: { 'Content-Type': 'application/json' }
+            }
+          )
+        }
+      }
+    }
+  }
+})
@@ -0,0 +1,85 @@
+import { createHash } from 'node:crypto'
+import { db, eq } from '@exlo/db'
+import { apiTokens } from '@exlo/db/schema/index'
+import { createFileRoute } from '@tanstack/react-router'
+import { env } from '@/lib/env'
+
+export const Route = createFileRoute('/api/internal/verify-token')({
+  server: {
+    handlers: {
+      GET: async () =>
+        new Response(
+          JSON.stringify({
+            endpoint: '/api/internal/verify-token',
+            method: 'POST required',
+            headers: { 'X-Internal-Secret': 'required' }
+          }),
+          {
+            headers: { 'Content-Type': 'application/json' }
+          }
+        ),
+      POST: async ({ request }) => {
+        const secret = request.headers.get('X-Internal-Secret')
+        if (secret !== env.INTERNAL_API_SECRET) {
+          return new Response(JSON.stringify({ error: 'Unauthorized' }), {
+            status: 401,
+            headers: { 'Content-Type': 'application/json' }
+          })
+        }
+
+        try {
+          const body = await request.json()
+          const { token } = body as { token: string }
+
+          if (!token) {
+            return new Response(JSON.stringify({ error: 'Missing required fields' }), {
+              status: 400,
+              headers: { 'Content-Type': 'application/json' }
+            })
+          }
+
+          // Only the hash is stored, so a leaked table doesn't leak tokens
+          const tokenHash = createHash('sha256').update(token).digest('hex')
+          const result = await db.query.apiTokens.findFirst({
+            where: eq(apiTokens.tokenHash, tokenHash),
+            with: {
+              user: true
+            }
+          })
+
+          if (!result) {
+            return new Response(JSON.stringify({ valid: false, error: 'Unknown token' }), {
+              headers: { 'Content-Type': 'application/json' }
+            })
+          }
+
+          if (result.expiresAt && new Date() > result.expiresAt) {
+            return new Response(JSON.stringify({ valid: false, error: 'Token has expired' }), {
+              headers: { 'Content-Type': 'application/json' }
+            })
+          }
+
+          await db.update(apiTokens).set({ lastUsedAt: new Date() }).where(eq(apiTokens.id, result.id))
+
+          return new Response(
+            JSON.stringify({
+              valid: true,
+              userId: result.userId,
+              userName: result.user?.name,
+              tier: result.user?.role
+            }),
+            {
+              headers: { 'Content-Type': 'application/json' }
+            }
+          )
+        } catch (error) {
+          console.error('Failed to verify token:', error)
+          return new Response(JSON.stringify({ error: 'Failed to verify token' }), {
+            status: 500,
+            headers: { 'Content-Type': 'application/json' }
+          })
+        }
+      }
+    }
+  }
+})
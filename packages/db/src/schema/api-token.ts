@@ -0,0 +1,34 @@
+import { relations } from 'drizzle-orm'
+import { index, pgTable, text, timestamp, uuid } from 'drizzle-orm/pg-core'
+import { user } from './auth'
+
+// Stores API tokens headless SSH clients use instead of the Device Flow
+export const apiTokens = pgTable(
+  'api_tokens',
+  {
+    id: uuid('id').primaryKey().defaultRandom(),
+    // User the token signs in as
+    userId: text('user_id')
+      .notNull()
+      .references(() => user.id, { onDelete: 'cascade' }),
+    // Label chosen by the user (e.g. "CI")
+    name: text('name').notNull(),
+    // SHA-256 of the token (hex); the token itself is only shown once
+    tokenHash: text('token_hash').notNull().unique(),
+    // When this token stops working (null = never)
+    expiresAt: timestamp('expires_at'),
+    // Last successful use by the tunnel server
+    lastUsedAt: timestamp('last_used_at'),
+    // When created
+    createdAt: timestamp('created_at').defaultNow().notNull()
+  },
+  (table) => [index('api_tokens_user_idx').on(table.userId)]
+)
+
+// Relations
+export const apiTokenRelations = relations(apiTokens, ({ one }) => ({
+  user: one(user, {
+    fields: [apiTokens.userId],
+    references: [user.id]
+  })
+}))